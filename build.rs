@@ -0,0 +1,15 @@
+fn main() {
+    // the WinFSP dll is not on the PATH, it's loaded by `winfsp::winfsp_init` from where WinFSP is installed, so
+    // it needs to be delay loaded, like `winfsp::build::winfsp_link_delayload` does
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows")
+        && std::env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc")
+    {
+        let arch = match std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() {
+            Ok("x86_64") => "x64",
+            Ok("aarch64") => "a64",
+            _ => "x86",
+        };
+        println!("cargo:rustc-link-lib=dylib=delayimp");
+        println!("cargo:rustc-link-arg=/DELAYLOAD:winfsp-{arch}.dll");
+    }
+}
@@ -10,6 +10,7 @@ use tracing::info;
 use rencfs::crypto::Cipher;
 use rencfs::encryptedfs::PasswordProvider;
 use rencfs::mount::create_mount_point;
+use rencfs::mount::MountOptions;
 use rencfs::mount::MountPoint;
 
 /// This will mount and expose the mount point until you press `Enter`, then it will umount and close the program.
@@ -27,7 +28,7 @@ async fn main() -> Result<()> {
     impl PasswordProvider for PasswordProviderImpl {
         fn get_password(&self) -> Option<SecretString> {
             // dummy password, use some secure way to get the password like with [keyring](https://crates.io/crates/keyring) crate
-            Some(SecretString::from_str("a").unwrap())
+            Some(SecretString::from_str("super-secret-42").unwrap())
         }
     }
     let mount_point = create_mount_point(
@@ -38,6 +39,7 @@ async fn main() -> Result<()> {
         false,
         false,
         false,
+        MountOptions::default().with_create_mount_point_dir(0o755),
     );
     let handle = mount_point.mount().await?;
     let mut buffer = String::new();
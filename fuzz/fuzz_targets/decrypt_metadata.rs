@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rencfs::bincode_util::{deserialize_from, METADATA_LIMIT};
+use rencfs::crypto::{self, Cipher};
+use rencfs::encryptedfs::FileAttr;
+use shush_rs::SecretVec;
+
+// corrupted encrypted files, the AEAD tag should reject them before we deserialize anything
+fuzz_target!(|data: &[u8]| {
+    for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        let key = SecretVec::new(Box::new(vec![42; cipher.key_len()]));
+        let reader = crypto::create_read(data, cipher, &key);
+        let _ = deserialize_from::<_, FileAttr>(reader, METADATA_LIMIT);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rencfs::bincode_util::{deserialize_from, METADATA_LIMIT, SMALL_LIMIT};
+use rencfs::encryptedfs::{FileAttr, FileType};
+
+// what we keep in inodes and directory entries, after it's decrypted
+fuzz_target!(|data: &[u8]| {
+    let _ = deserialize_from::<_, FileAttr>(data, METADATA_LIMIT);
+    let _ = deserialize_from::<_, (u64, FileType)>(data, METADATA_LIMIT);
+    let _ = deserialize_from::<_, (u64, FileType, String)>(data, METADATA_LIMIT);
+    let _ = deserialize_from::<_, u8>(data, SMALL_LIMIT);
+});
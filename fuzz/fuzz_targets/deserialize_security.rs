@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rencfs::bincode_util::{deserialize_from, KEY_LIMIT, SALT_LIMIT, SMALL_LIMIT};
+use rencfs::crypto::{Cipher, KdfParams};
+
+// the files in `security` dir, the salt and the KDF params are read before the password is checked
+fuzz_target!(|data: &[u8]| {
+    let _ = deserialize_from::<_, Vec<u8>>(data, SALT_LIMIT);
+    let _ = deserialize_from::<_, Vec<u8>>(data, KEY_LIMIT);
+    let _ = deserialize_from::<_, Cipher>(data, SMALL_LIMIT);
+    if let Ok(params) = deserialize_from::<_, KdfParams>(data, SMALL_LIMIT) {
+        let _ = params.validate();
+    }
+});
@@ -12,7 +12,7 @@ use jni::JNIEnv;
 use rencfs::crypto::Cipher;
 use rencfs::encryptedfs::PasswordProvider;
 use rencfs::log::log_init;
-use rencfs::mount::{create_mount_point, umount, MountHandle};
+use rencfs::mount::{create_mount_point, umount, MountHandle, MountOptions};
 use shush_rs::SecretString;
 use std::collections::BTreeMap;
 use std::ops::Add;
@@ -21,7 +21,7 @@ use std::sync::LazyLock;
 use std::{io, process};
 use tokio::runtime::Runtime;
 use tokio::sync::Mutex;
-use tracing::{error, info, warn, Level};
+use tracing::{error, info, Level};
 use tracing_appender::non_blocking::WorkerGuard;
 
 #[derive(Debug, Default)]
@@ -164,13 +164,6 @@ pub extern "system" fn Java_RustLibrary_mount(
         });
     }
 
-    if umount_first == 1 {
-        let _ = umount(mount_path.as_str()).map_err(|err| {
-            warn!("Cannot umount, maybe it was not mounted: {err}");
-            err
-        });
-    }
-
     struct PasswordProviderImpl(SecretString); // use secretvec instead of string
     impl PasswordProvider for PasswordProviderImpl {
         fn get_password(&self) -> Option<SecretString> {
@@ -186,6 +179,9 @@ pub extern "system" fn Java_RustLibrary_mount(
         false,
         false,
         false,
+        MountOptions::default()
+            .with_umount_first(umount_first == 1)
+            .with_create_mount_point_dir(0o755),
     );
 
     let handle = match RT.block_on(async {
@@ -0,0 +1,62 @@
+use std::io::Read;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+
+/// Max length (in bytes) of the salt file, it's read before we can check the password.
+pub const SALT_LIMIT: u64 = 1024;
+/// Max length (in bytes) of small files with a fixed size, like the saved [`KdfParams`](crate::crypto::KdfParams)
+/// or [`Cipher`](crate::crypto::Cipher).
+pub const SMALL_LIMIT: u64 = 1024;
+/// Max length (in bytes) of the encryption key.
+pub const KEY_LIMIT: u64 = 1024;
+/// Max length (in bytes) of an inode and of a directory entry, which keeps the encrypted name.
+pub const METADATA_LIMIT: u64 = 64 * 1024;
+/// Max length (in bytes) of all the extended attributes of an inode.
+pub const XATTR_LIMIT: u64 = 1024 * 1024;
+/// Max length (in bytes) of the holes of a sparse file, see [`HoleMap`](crate::crypto::holes::HoleMap).
+pub const HOLES_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Like [`bincode::deserialize_from`], but fails if more than `limit` bytes would be read.
+///
+/// We use it for all we read from the data dir, so a corrupted or crafted file can't make us allocate
+/// a huge length prefix, it returns an error instead.
+#[allow(clippy::missing_errors_doc)]
+pub fn deserialize_from<R: Read, T: DeserializeOwned>(reader: R, limit: u64) -> bincode::Result<T> {
+    // same encoding as `bincode::deserialize_from`
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+        .deserialize_from(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::encryptedfs::{FileAttr, FileType};
+
+    #[test]
+    fn test_deserialize_from() {
+        let salt = vec![42_u8; 16];
+        let bytes = bincode::serialize(&salt).unwrap();
+        let salt2: Vec<u8> = deserialize_from(&bytes[..], SALT_LIMIT).unwrap();
+        assert_eq!(salt, salt2);
+
+        // length prefix of u64::MAX
+        let res: bincode::Result<Vec<u8>> = deserialize_from(&[0xff; 8][..], SALT_LIMIT);
+        assert!(matches!(*res.unwrap_err(), bincode::ErrorKind::SizeLimit));
+        let res: bincode::Result<(u64, FileType, String)> =
+            deserialize_from(&[[0; 8], [0; 8], [0xff; 8]].concat()[..], METADATA_LIMIT);
+        assert!(res.is_err());
+        let too_long = bincode::serialize(&vec![0_u8; SALT_LIMIT as usize]).unwrap();
+        assert!(deserialize_from::<_, Vec<u8>>(&too_long[..], SALT_LIMIT).is_err());
+
+        // truncated and invalid enum variant
+        assert!(deserialize_from::<_, Vec<u8>>(&bytes[..10], SALT_LIMIT).is_err());
+        assert!(deserialize_from::<_, FileType>(&[9, 0, 0, 0][..], SMALL_LIMIT).is_err());
+        assert!(deserialize_from::<_, FileAttr>(&[0; 10][..], METADATA_LIMIT).is_err());
+        assert!(deserialize_from::<_, FileAttr>(&[0xff; 512][..], METADATA_LIMIT).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lru::LruCache;
+use rand_core::RngCore;
+use tracing::{error, warn};
+
+use crate::crypto;
+use crate::crypto::write::BLOCK_SIZE;
+
+#[cfg(target_os = "linux")]
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+#[cfg(target_os = "linux")]
+const RAMFS_MAGIC: i64 = 0x8584_58f6;
+
+/// Hits and misses of a [`BlockCache`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Inner {
+    // (ino, block index) -> generation of the inode when the block was read
+    lru: LruCache<(u64, u64), u64>,
+    blocks: HashMap<u64, HashSet<u64>>,
+    generations: HashMap<u64, u64>,
+    stats: BlockCacheStats,
+}
+
+/// Keeps decrypted blocks of file contents in a RAM backed filesystem (`tmpfs` or `ramfs`),
+/// so repeated reads of the same blocks don't need to decrypt them again.
+///
+/// Blocks are keyed by `(ino, block index, generation)`, the generation is changed on each
+/// [`BlockCache::invalidate`] so blocks read before a write are never served after it.
+///
+/// The data is stored unencrypted in a private directory, only accessible by the current user, inside `dir`.
+/// Evicted blocks and all of them on drop are overwritten with zeros before being deleted.
+/// Keep in mind `tmpfs` can be swapped to disk, use `ramfs` or encrypted swap if that's a concern.
+pub struct BlockCache {
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+impl BlockCache {
+    /// Create a cache in `dir` holding at most `max_size` bytes, rounded down to whole blocks.
+    ///
+    /// Fails if `dir` is not on a RAM backed filesystem.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn new(dir: &Path, max_size: usize) -> io::Result<Self> {
+        if !is_ram_backed(dir)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block cache dir must be on tmpfs or ramfs",
+            ));
+        }
+        let capacity = NonZeroUsize::new(max_size / BLOCK_SIZE).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block cache max size must be at least one block",
+            )
+        })?;
+        let dir = dir.join(format!("rencfs-{:016x}", crypto::create_rng().next_u64()));
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&dir)?;
+        Ok(Self {
+            dir,
+            inner: Mutex::new(Inner {
+                lru: LruCache::new(capacity),
+                blocks: HashMap::new(),
+                generations: HashMap::new(),
+                stats: BlockCacheStats::default(),
+            }),
+        })
+    }
+
+    /// Current generation of the inode, pass it to [`BlockCache::get`] and [`BlockCache::put`].
+    #[allow(clippy::missing_panics_doc)]
+    pub fn generation(&self, ino: u64) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.generations.get(&ino).copied().unwrap_or_default()
+    }
+
+    /// Get the plaintext of a block if we have it for this generation.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get(&self, ino: u64, block: u64, generation: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.lru.get(&(ino, block)) != Some(&generation) {
+            inner.stats.misses += 1;
+            return Ok(None);
+        }
+        let mut data = Vec::with_capacity(BLOCK_SIZE);
+        File::open(self.block_path(ino, block))?.read_to_end(&mut data)?;
+        inner.stats.hits += 1;
+        Ok(Some(data))
+    }
+
+    /// Add the plaintext of a block, ignored if the inode was invalidated after `generation` was taken.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn put(&self, ino: u64, block: u64, generation: u64, data: &[u8]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generations.get(&ino).copied().unwrap_or_default() != generation {
+            return Ok(());
+        }
+        let mut opts = OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        opts.open(self.block_path(ino, block))?.write_all(data)?;
+        if let Some(((evicted_ino, evicted_block), _)) = inner.lru.push((ino, block), generation) {
+            if (evicted_ino, evicted_block) != (ino, block) {
+                Self::forget_block(&mut inner, evicted_ino, evicted_block);
+                self.remove_block(evicted_ino, evicted_block);
+            }
+        }
+        inner.blocks.entry(ino).or_default().insert(block);
+        Ok(())
+    }
+
+    /// Drop all blocks of the inode, call it when the content changes.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn invalidate(&self, ino: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.generations.entry(ino).or_default() += 1;
+        if let Some(blocks) = inner.blocks.remove(&ino) {
+            for block in blocks {
+                inner.lru.pop(&(ino, block));
+                self.remove_block(ino, block);
+            }
+        }
+    }
+
+    /// Drop all blocks.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn clear(&self) {
+        let inos: Vec<u64> = self.inner.lock().unwrap().blocks.keys().copied().collect();
+        for ino in inos {
+            self.invalidate(ino);
+        }
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub fn stats(&self) -> BlockCacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    fn forget_block(inner: &mut Inner, ino: u64, block: u64) {
+        if let Some(blocks) = inner.blocks.get_mut(&ino) {
+            blocks.remove(&block);
+            if blocks.is_empty() {
+                inner.blocks.remove(&ino);
+            }
+        }
+    }
+
+    fn block_path(&self, ino: u64, block: u64) -> PathBuf {
+        self.dir.join(format!("{ino}-{block}"))
+    }
+
+    /// Overwrite with zeros and delete.
+    fn remove_block(&self, ino: u64, block: u64) {
+        let path = self.block_path(ino, block);
+        let res = (|| {
+            let len = fs::metadata(&path)?.len();
+            let zeros = vec![0_u8; usize::try_from(len).unwrap_or_default()];
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .write_all(&zeros)?;
+            fs::remove_file(&path)
+        })();
+        if let Err(err) = res {
+            error!(err = %err, "cannot remove cached block");
+        }
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        self.clear();
+        if let Err(err) = fs::remove_dir(&self.dir) {
+            warn!(err = %err, "cannot remove block cache dir");
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_ram_backed(dir: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    let f_type = stat.f_type as i64;
+    Ok(f_type == TMPFS_MAGIC || f_type == RAMFS_MAGIC)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_ram_backed(_dir: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+
+    const SHM: &str = "/dev/shm";
+
+    #[test]
+    fn test_not_ram_backed() {
+        let tmp = tempfile::tempdir_in(env!("CARGO_MANIFEST_DIR")).unwrap();
+        if is_ram_backed(tmp.path()).unwrap() {
+            return;
+        }
+        assert!(BlockCache::new(tmp.path(), BLOCK_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_get_put_invalidate() {
+        if !Path::new(SHM).is_dir() {
+            return;
+        }
+        let cache = BlockCache::new(Path::new(SHM), 2 * BLOCK_SIZE).unwrap();
+        let generation = cache.generation(1);
+        assert_eq!(cache.get(1, 0, generation).unwrap(), None);
+        cache.put(1, 0, generation, b"test-42").unwrap();
+        assert_eq!(
+            cache.get(1, 0, generation).unwrap().as_deref(),
+            Some(&b"test-42"[..])
+        );
+        assert_eq!(cache.stats(), BlockCacheStats { hits: 1, misses: 1 });
+
+        // blocks read before invalidation are not kept
+        cache.invalidate(1);
+        assert_eq!(cache.get(1, 0, generation).unwrap(), None);
+        cache.put(1, 0, generation, b"test-42").unwrap();
+        assert_eq!(cache.get(1, 0, cache.generation(1)).unwrap(), None);
+
+        // bounded
+        let generation = cache.generation(1);
+        for block in 0..3 {
+            cache.put(1, block, generation, b"test-42").unwrap();
+        }
+        assert_eq!(cache.get(1, 0, generation).unwrap(), None);
+        assert!(cache.get(1, 2, generation).unwrap().is_some());
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), 2);
+
+        let dir = cache.dir.clone();
+        drop(cache);
+        assert!(!dir.exists());
+    }
+}
@@ -1,11 +1,15 @@
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{Read, Seek, Write};
-use std::num::ParseIntError;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::num::{NonZeroUsize, ParseIntError};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 
-use argon2::Argon2;
+use aes_gcm_siv::aead::{AeadInPlace, KeyInit};
+use aes_gcm_siv::Aes256GcmSiv;
+use argon2::{Argon2, Params, Version};
+use atomic_write_file::AtomicWriteFile;
 use base64::alphabet::STANDARD;
 use base64::engine::general_purpose::NO_PAD;
 use base64::engine::GeneralPurpose;
@@ -14,21 +18,32 @@ use hex::FromHexError;
 use num_format::{Locale, ToFormattedString};
 use rand_chacha::rand_core::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
+use ring::aead::{
+    Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN,
+};
+use ring::error::Unspecified;
 use serde::{Deserialize, Serialize};
-use shush_rs::{ExposeSecret, SecretString, SecretVec};
+use shush_rs::{ExposeSecret, SecretString, SecretVec, Zeroize};
 use strum_macros::{Display, EnumIter, EnumString};
 use thiserror::Error;
 use tracing::{debug, error, instrument};
 use write::CryptoInnerWriter;
 
+use crate::crypto::holes::HoleMap;
+use crate::crypto::nonce::NonceCounter;
 use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
-use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite};
+use crate::crypto::transform::{ContentTransform, FRAME_HEADER_LEN};
+use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite, BLOCK_SIZE};
 use crate::encryptedfs::FsResult;
 use crate::{fs_util, stream_util};
 
 pub mod buf_mut;
+pub mod escrow;
+pub mod holes;
+pub mod nonce;
 pub mod read;
+pub mod recovery;
+pub mod transform;
 pub mod write;
 
 pub static BASE64: GeneralPurpose = GeneralPurpose::new(&STANDARD, NO_PAD);
@@ -37,10 +52,25 @@ pub static BASE64: GeneralPurpose = GeneralPurpose::new(&STANDARD, NO_PAD);
     Debug, Clone, Copy, EnumIter, EnumString, Display, Serialize, Deserialize, PartialEq, Eq,
 )]
 pub enum Cipher {
+    /// ChaCha20-Poly1305, 256 bits key, 96 bits nonce and 128 bits tag.
     ChaCha20Poly1305,
+    /// AES-256 in GCM mode, 256 bits key, 96 bits nonce and 128 bits tag, fast on CPUs with AES-NI.
+    ///
+    /// Reusing a nonce leaks the authentication key, if you expect to write a lot over the lifetime of the volume
+    /// use [`NonceStrategy::Counter`](nonce::NonceStrategy::Counter).
     Aes256Gcm,
+    /// AES-256 in GCM-SIV mode, 256 bits key, 96 bits nonce and 128 bits tag, nonce-misuse resistant.
+    ///
+    /// A repeated nonce only reveals that the same block index was written with the same content, it doesn't leak
+    /// the key, so it's safe also on the paths which rewrite the content, like `set_len`. It's slower than
+    /// [`Cipher::Aes256Gcm`] as it's not implemented by `ring`.
+    Aes256GcmSiv,
 }
 
+/// Key and tag length of [`Cipher::Aes256GcmSiv`], in bytes.
+const AES_256_GCM_SIV_KEY_LEN: usize = 32;
+const AES_256_GCM_SIV_TAG_LEN: usize = 16;
+
 impl Cipher {
     /// In bytes.
     #[must_use]
@@ -49,16 +79,204 @@ impl Cipher {
         match self {
             Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.key_len(),
             Cipher::Aes256Gcm => AES_256_GCM.key_len(),
+            Cipher::Aes256GcmSiv => AES_256_GCM_SIV_KEY_LEN,
         }
     }
 
-    /// Max length (in bytes) of the plaintext that can be encrypted before becoming unsafe.
+    /// Length (in bytes) of the authentication tag added to each encrypted block.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn tag_len(&self) -> usize {
+        match self {
+            Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+            Cipher::Aes256Gcm => AES_256_GCM.tag_len(),
+            Cipher::Aes256GcmSiv => AES_256_GCM_SIV_TAG_LEN,
+        }
+    }
+
+    /// Bytes added on disk to each block of a file, the nonce and the authentication tag.
+    ///
+    /// Files written with a [`ContentTransform`] have [`FRAME_HEADER_LEN`] more bytes in each block.
+    #[must_use]
+    pub fn per_block_overhead(&self) -> usize {
+        NONCE_LEN + self.tag_len()
+    }
+
+    /// Length (in bytes) of the plaintext encrypted in each block, reads and writes of multiples of it
+    /// are the most efficient.
+    #[must_use]
+    pub const fn block_len(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    /// Size (in bytes) of the buffers to read and write files with, like in copy loops. Each call then reads or
+    /// writes whole blocks, so no block is decrypted or encrypted more than once, it's [`Cipher::block_len`].
+    #[must_use]
+    pub const fn recommended_io_size(&self) -> usize {
+        self.block_len()
+    }
+
+    /// Length (in bytes) on disk of a file with `plaintext_len` bytes of content,
+    /// each block of [`BLOCK_SIZE`] has [`Cipher::per_block_overhead`] bytes more.
+    #[must_use]
+    pub fn ciphertext_len(&self, plaintext_len: u64) -> u64 {
+        plaintext_len + plaintext_len.div_ceil(BLOCK_SIZE as u64) * self.per_block_overhead() as u64
+    }
+
+    /// Max length (in bytes) of the plaintext that can be encrypted with one key before becoming unsafe, it's the max
+    /// size of a file. Reads and writes past it are truncated, it's not related to the size of the buffers, see
+    /// [`Cipher::recommended_io_size`] for those.
     #[must_use]
     #[allow(clippy::use_self)]
     pub const fn max_plaintext_len(&self) -> usize {
         match self {
             Cipher::ChaCha20Poly1305 => (2_usize.pow(32) - 1) * 64,
             Cipher::Aes256Gcm => (2_usize.pow(39) - 256) / 8,
+            Cipher::Aes256GcmSiv => 2_usize.pow(36),
+        }
+    }
+}
+
+/// Key used to seal and open the blocks, `ring` doesn't have AES-GCM-SIV so that one is from `aes-gcm-siv`.
+///
+/// The index of the block is the additional data, the nonce is stored before each block and the tag after it.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum BlockKey {
+    Ring(LessSafeKey),
+    Aes256GcmSiv(Aes256GcmSiv),
+}
+
+impl BlockKey {
+    pub(crate) fn new(cipher: Cipher, key: &SecretVec<u8>) -> io::Result<Self> {
+        match cipher {
+            Cipher::ChaCha20Poly1305 => Self::ring(&CHACHA20_POLY1305, key),
+            Cipher::Aes256Gcm => Self::ring(&AES_256_GCM, key),
+            Cipher::Aes256GcmSiv => Aes256GcmSiv::new_from_slice(key.expose_secret())
+                .map(Self::Aes256GcmSiv)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key")),
+        }
+    }
+
+    pub(crate) fn ring(algorithm: &'static Algorithm, key: &SecretVec<u8>) -> io::Result<Self> {
+        UnboundKey::new(algorithm, key.expose_secret())
+            .map(|key| Self::Ring(LessSafeKey::new(key)))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
+    }
+
+    pub(crate) fn tag_len(&self) -> usize {
+        match self {
+            Self::Ring(key) => key.algorithm().tag_len(),
+            Self::Aes256GcmSiv(_) => AES_256_GCM_SIV_TAG_LEN,
+        }
+    }
+
+    /// Encrypt `data` in place and return the tag.
+    pub(crate) fn seal_in_place_separate_tag(
+        &self,
+        nonce: &[u8],
+        block_index: u64,
+        data: &mut [u8],
+    ) -> std::result::Result<Vec<u8>, Unspecified> {
+        let aad = block_index.to_le_bytes();
+        match self {
+            Self::Ring(key) => key
+                .seal_in_place_separate_tag(
+                    Nonce::try_assume_unique_for_key(nonce)?,
+                    Aad::from(aad),
+                    data,
+                )
+                .map(|tag| tag.as_ref().to_vec()),
+            Self::Aes256GcmSiv(key) => key
+                .encrypt_in_place_detached(aes_gcm_siv::Nonce::from_slice(nonce), &aad, data)
+                .map(|tag| tag.to_vec())
+                .map_err(|_| Unspecified),
+        }
+    }
+
+    /// Decrypt in place `data`, which has the tag at the end, and return the plaintext.
+    pub(crate) fn open_in_place<'a>(
+        &self,
+        nonce: &[u8],
+        block_index: u64,
+        data: &'a mut [u8],
+    ) -> std::result::Result<&'a mut [u8], Unspecified> {
+        let aad = block_index.to_le_bytes();
+        match self {
+            Self::Ring(key) => key.open_in_place(
+                Nonce::try_assume_unique_for_key(nonce)?,
+                Aad::from(aad),
+                data,
+            ),
+            Self::Aes256GcmSiv(key) => {
+                let len = data
+                    .len()
+                    .checked_sub(AES_256_GCM_SIV_TAG_LEN)
+                    .ok_or(Unspecified)?;
+                let (plaintext, tag) = data.split_at_mut(len);
+                key.decrypt_in_place_detached(
+                    aes_gcm_siv::Nonce::from_slice(nonce),
+                    &aad,
+                    plaintext,
+                    aes_gcm_siv::Tag::from_slice(tag),
+                )
+                .map_err(|_| Unspecified)?;
+                Ok(plaintext)
+            }
+        }
+    }
+}
+
+/// Argon2id params used to derive the key from the password.
+///
+/// They are saved in the data dir when the volume is created and always read from there, so a volume
+/// stays openable even if the defaults of the `argon2` crate change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory size in KiB
+    pub m_cost: u32,
+    /// Number of iterations
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// Params of volumes created before they were saved in the data dir, the defaults of `argon2` 0.5.
+    pub const LEGACY: Self = Self {
+        m_cost: 19 * 1024,
+        t_cost: 2,
+        p_cost: 1,
+    };
+
+    /// Max memory (in KiB) we accept, 4 GiB.
+    const MAX_M_COST: u32 = 4 * 1024 * 1024;
+    const MAX_T_COST: u32 = 1024;
+    const MAX_P_COST: u32 = 256;
+
+    /// Check the params are within what `argon2` accepts and not so big to exhaust the memory or CPU,
+    /// like when read from a corrupted file.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn validate(&self) -> Result<()> {
+        if self.m_cost > Self::MAX_M_COST
+            || self.t_cost > Self::MAX_T_COST
+            || self.p_cost > Self::MAX_P_COST
+        {
+            return Err(Error::Generic("KDF params are too big"));
+        }
+        Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|err| Error::GenericString(err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// The defaults of the `argon2` crate, used only for new volumes.
+impl Default for KdfParams {
+    fn default() -> Self {
+        let params = Params::default();
+        Self {
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
         }
     }
 }
@@ -132,11 +350,7 @@ fn create_ring_write<W: CryptoInnerWriter + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> RingCryptoWrite<W> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoWrite::new(writer, false, algorithm, key)
+    RingCryptoWrite::with_cipher(writer, false, cipher, key)
 }
 
 fn create_ring_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync>(
@@ -144,11 +358,7 @@ fn create_ring_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> RingCryptoWrite<W> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoWrite::new(writer, true, algorithm, key)
+    RingCryptoWrite::with_cipher(writer, true, cipher, key)
 }
 
 fn create_ring_read<R: Read + Send + Sync>(
@@ -156,11 +366,7 @@ fn create_ring_read<R: Read + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> RingCryptoRead<R> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoRead::new(reader, algorithm, key)
+    RingCryptoRead::with_cipher(reader, cipher, key)
 }
 
 fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
@@ -168,11 +374,7 @@ fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> RingCryptoRead<R> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoRead::new_seek(reader, algorithm, key)
+    RingCryptoRead::with_cipher(reader, cipher, key)
 }
 
 /// Creates an encrypted reader
@@ -193,6 +395,85 @@ pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     create_ring_read_seek(reader, cipher, key)
 }
 
+/// Like [`create_write`], but applies `transform`, if any, on each block before encrypting it, see [`ContentTransform`].
+/// Nonces are taken from `nonce_counter` if set, see [`NonceStrategy`](nonce::NonceStrategy).
+pub fn create_write_with_transform<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    transform: Option<Arc<dyn ContentTransform>>,
+    nonce_counter: Option<Arc<NonceCounter>>,
+) -> impl CryptoWrite<W> {
+    let mut writer = create_ring_write(writer, cipher, key);
+    if let Some(counter) = nonce_counter {
+        writer = writer.with_nonce_counter(counter);
+    }
+    match transform {
+        Some(transform) => writer.with_transform(transform),
+        None => writer,
+    }
+}
+
+/// Like [`create_write_seek`], but applies `transform`, if any, on each block before encrypting it, see [`ContentTransform`].
+/// Nonces are taken from `nonce_counter` if set, see [`NonceStrategy`](nonce::NonceStrategy).
+/// Blocks in `holes` are read as holes and with `sparse` new holes are added to it, see [`HoleMap`].
+pub fn create_write_seek_with_transform<
+    W: CryptoInnerWriter + Seek + Read + Send + Sync + 'static,
+>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    transform: Option<Arc<dyn ContentTransform>>,
+    nonce_counter: Option<Arc<NonceCounter>>,
+    holes: Option<Arc<HoleMap>>,
+    sparse: bool,
+) -> impl CryptoWriteSeek<W> {
+    let mut writer = create_ring_write_seek(writer, cipher, key);
+    if let Some(counter) = nonce_counter {
+        writer = writer.with_nonce_counter(counter);
+    }
+    if let Some(holes) = holes {
+        writer = writer.with_holes(holes, sparse);
+    }
+    match transform {
+        Some(transform) => writer.with_transform(transform),
+        None => writer,
+    }
+}
+
+/// Like [`create_read`], but reverts `transform`, if any, on each block after decrypting it, see [`ContentTransform`]
+pub fn create_read_with_transform<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    transform: Option<Arc<dyn ContentTransform>>,
+) -> impl CryptoRead<R> {
+    let reader = create_ring_read(reader, cipher, key);
+    match transform {
+        Some(transform) => reader.with_transform(transform),
+        None => reader,
+    }
+}
+
+/// Like [`create_read_seek`], but reverts `transform`, if any, on each block after decrypting it, see [`ContentTransform`].
+/// Blocks in `holes` are read as holes, see [`HoleMap`].
+pub fn create_read_seek_with_transform<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    transform: Option<Arc<dyn ContentTransform>>,
+    holes: Option<Arc<HoleMap>>,
+) -> impl CryptoReadSeek<R> {
+    let mut reader = create_ring_read_seek(reader, cipher, key);
+    if let Some(holes) = holes {
+        reader = reader.with_holes(holes);
+    }
+    match transform {
+        Some(transform) => reader.with_transform(transform),
+        None => reader,
+    }
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt(s: &SecretString, cipher: Cipher, key: &SecretVec<u8>) -> Result<String> {
     let mut cursor = io::Cursor::new(vec![]);
@@ -215,29 +496,45 @@ pub fn decrypt(s: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretStr
     Ok(SecretString::new(Box::new(decrypted)))
 }
 
+/// Strips the padding added by [`encrypt_file_name`], names without padding are returned as they are.
 #[allow(clippy::missing_errors_doc)]
 pub fn decrypt_file_name(name: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretString> {
     let name = String::from(name).replace('|', "/");
-    decrypt(&name, cipher, key)
+    let mut decrypted = decrypt(&name, cipher, key)?;
+    let len = decrypted.expose_secret().trim_end_matches('\0').len();
+    decrypted.expose_secret_mut().truncate(len);
+    Ok(decrypted)
 }
 
+/// Derive a key from the password with Argon2id, `params` should be the ones saved for the volume.
 #[instrument(skip(password, salt))]
 #[allow(clippy::missing_errors_doc)]
-pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Result<SecretVec<u8>> {
+pub fn derive_key(
+    password: &SecretString,
+    cipher: Cipher,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<SecretVec<u8>> {
     let mut dk = vec![];
     let key_len = cipher.key_len();
     dk.resize(key_len, 0);
-    Argon2::default()
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+        .map_err(|err| Error::GenericString(err.to_string()))?;
+    Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params)
         .hash_password_into(password.expose_secret().as_bytes(), salt, &mut dk)
         .map_err(|err| Error::GenericString(err.to_string()))?;
     Ok(SecretVec::new(Box::new(dk)))
 }
 
+/// With `padding` the name is filled with `\0` up to the next multiple of `padding` bytes before encrypting,
+/// so names of lengths in the same bucket have the same encrypted length.
+/// File names can't contain `\0`, so [`decrypt_file_name`] strips it.
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt_file_name(
     name: &SecretString,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    padding: Option<NonZeroUsize>,
 ) -> FsResult<String> {
     let secret_string = name.expose_secret();
 
@@ -245,8 +542,15 @@ pub fn encrypt_file_name(
         "$." | "$.." => Ok(secret_string.clone()),
         "." | ".." => Ok(format!("${secret_string}")),
         _ => {
-            let secret = SecretString::from_str(&secret_string)
+            let mut secret = SecretString::from_str(&secret_string)
                 .map_err(|err| Error::GenericString(err.to_string()))?;
+            if let Some(padding) = padding {
+                let len = secret.expose_secret().len();
+                let padded_len = len.div_ceil(padding.get()) * padding.get();
+                secret
+                    .expose_secret_mut()
+                    .extend(std::iter::repeat_n('\0', padded_len - len));
+            }
             let mut encrypted = encrypt(&secret, cipher, key)?;
             encrypted = encrypted.replace('/', "|");
 
@@ -282,6 +586,169 @@ pub fn hash_reader<R: Read + ?Sized>(r: &mut R) -> io::Result<[u8; 32]> {
     Ok(hasher.finalize().into())
 }
 
+/// Encrypt the file at `src` into `dst`, in the same format as [`create_write`], so it can be read back with
+/// [`create_read`] or [`decrypt_file`].
+///
+/// `dst` is written to a temp file which replaces it and is synced when it's done, on error it's left as it was.
+/// Returns the length and the hash, like [`hash_reader`], of the plaintext.
+#[allow(clippy::missing_errors_doc)]
+pub fn encrypt_file(
+    src: &Path,
+    dst: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> Result<(u64, [u8; 32])> {
+    let mut writer = create_write(fs_util::open_atomic_write(dst)?, cipher, key);
+    let res = copy_hashed(&mut File::open(src)?, &mut writer)?;
+    commit_atomic(writer.finish()?, dst)?;
+    Ok(res)
+}
+
+/// Decrypt the file at `src`, written with [`encrypt_file`] or [`create_write`], into `dst`.
+///
+/// `dst` is written to a temp file which replaces it and is synced when it's done, on error, like when `src` was
+/// changed, it's left as it was. Returns the length and the hash, like [`hash_reader`], of the plaintext.
+#[allow(clippy::missing_errors_doc)]
+pub fn decrypt_file(
+    src: &Path,
+    dst: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> Result<(u64, [u8; 32])> {
+    let mut file = fs_util::open_atomic_write(dst)?;
+    let res = copy_hashed(&mut create_read(File::open(src)?, cipher, key), &mut file)?;
+    commit_atomic(file, dst)?;
+    Ok(res)
+}
+
+/// Like [`encrypt_file`], with a key derived from `password` with [`derive_key`].
+///
+/// The same `salt` and `params` are needed to decrypt it, they are not saved in `dst`.
+#[allow(clippy::missing_errors_doc)]
+pub fn encrypt_file_with_password(
+    src: &Path,
+    dst: &Path,
+    cipher: Cipher,
+    password: &SecretString,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<(u64, [u8; 32])> {
+    encrypt_file(
+        src,
+        dst,
+        cipher,
+        &derive_key(password, cipher, salt, params)?,
+    )
+}
+
+/// Like [`decrypt_file`], with a key derived from `password` with [`derive_key`], see
+/// [`encrypt_file_with_password`].
+#[allow(clippy::missing_errors_doc)]
+pub fn decrypt_file_with_password(
+    src: &Path,
+    dst: &Path,
+    cipher: Cipher,
+    password: &SecretString,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<(u64, [u8; 32])> {
+    decrypt_file(
+        src,
+        dst,
+        cipher,
+        &derive_key(password, cipher, salt, params)?,
+    )
+}
+
+/// Copy all of `reader` to `writer`, returns the length and the hash of what was copied.
+fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<(u64, [u8; 32])> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; BLOCK_SIZE];
+    let mut len = 0;
+    let res = (|| loop {
+        let read = stream_util::read(&mut *reader, &mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..read]);
+        writer.write_all(&buf[..read])?;
+        len += read as u64;
+    })();
+    // it might be the plaintext
+    buf.zeroize();
+    res?;
+    Ok((len, hasher.finalize().into()))
+}
+
+fn commit_atomic(file: AtomicWriteFile, path: &Path) -> io::Result<()> {
+    file.commit()?;
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(parent)?.sync_all()
+}
+
+/// MAC of the content of a file, a keyed hash of its inode, its size and the authentication tags of all the blocks
+/// of `block_len` bytes in `reader`, in order.
+///
+/// Each block is authenticated with its index, but not with the file it belongs to, so the MAC changes if blocks
+/// are removed from the end or replaced with the ones from another file.
+#[allow(clippy::missing_errors_doc)]
+pub fn content_mac<R: Read + Seek + ?Sized>(
+    reader: &mut R,
+    ino: u64,
+    size: u64,
+    block_len: u64,
+    tag_len: usize,
+    key: &SecretVec<u8>,
+) -> io::Result<[u8; 32]> {
+    let mut mac_key = [0_u8; 32];
+    blake3::derive_key("rencfs content mac", key.expose_secret(), &mut mac_key);
+    let mut hasher = blake3::Hasher::new_keyed(&mac_key);
+    mac_key.zeroize();
+    hasher.update(&ino.to_le_bytes());
+    hasher.update(&size.to_le_bytes());
+    let len = reader.seek(SeekFrom::End(0))?;
+    hasher.update(&len.to_le_bytes());
+    let mut tag = vec![0; tag_len];
+    let mut start = 0;
+    while start < len {
+        let end = (start + block_len).min(len);
+        // a truncated block can be shorter than the tag
+        let tag_start = end.saturating_sub(tag_len as u64).max(start);
+        #[allow(clippy::cast_possible_truncation)]
+        let tag = &mut tag[..(end - tag_start) as usize];
+        reader.seek(SeekFrom::Start(tag_start))?;
+        reader.read_exact(tag)?;
+        hasher.update(tag);
+        start = end;
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Name of the shared content of the files with the plaintext `hash`, from [`hash_reader`], encrypted with
+/// `cipher` and the content transform `transform_id`.
+///
+/// It's a keyed hash, so it doesn't tell which files have a known content. Files with the same content but
+/// stored differently get different names, so only identical ciphertext framing is shared.
+#[must_use]
+pub fn content_ref_name(
+    hash: &[u8; 32],
+    cipher: Cipher,
+    transform_id: Option<u8>,
+    key: &SecretVec<u8>,
+) -> String {
+    let mut ref_key = [0_u8; 32];
+    blake3::derive_key("rencfs content ref", key.expose_secret(), &mut ref_key);
+    let mut hasher = blake3::Hasher::new_keyed(&ref_key);
+    ref_key.zeroize();
+    hasher.update(hash);
+    hasher.update(cipher.to_string().as_bytes());
+    hasher.update(&transform_id.map_or([0, 0], |id| [1, id]));
+    hex::encode(hasher.finalize().as_bytes())
+}
+
 #[must_use]
 pub fn hash_secret_string(data: &SecretString) -> [u8; 32] {
     hash(data.expose_secret().as_bytes())
@@ -342,6 +809,94 @@ pub fn copy_from_file(
     Ok(len)
 }
 
+/// Re-encrypt data written with `from` cipher to `to` cipher, block by block.
+///
+/// The layout stays the same, each block gets a new nonce and keeps its index as additional data.
+/// Set `framed` for content written with a [`ContentTransform`], the frames are kept as they are.
+/// The same `key` is used for both ciphers, so they need to have the same key length.
+/// Blocks which are all zeros are holes of sparse files, see [`HoleMap`], they are kept as they are.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::missing_panics_doc)]
+pub fn reencrypt(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    from: Cipher,
+    to: Cipher,
+    key: &SecretVec<u8>,
+    framed: bool,
+) -> io::Result<()> {
+    reencrypt_with(input, output, (from, key), (to, key), framed)
+}
+
+/// Re-encrypt data written with `old_key` with `new_key`, block by block, like [`reencrypt`] does for ciphers.
+///
+/// If the data can't be decrypted with `old_key` it fails with [`io::ErrorKind::InvalidData`].
+#[allow(clippy::missing_errors_doc)]
+pub fn rekey(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    cipher: Cipher,
+    old_key: &SecretVec<u8>,
+    new_key: &SecretVec<u8>,
+    framed: bool,
+) -> io::Result<()> {
+    reencrypt_with(input, output, (cipher, old_key), (cipher, new_key), framed)
+}
+
+fn reencrypt_with(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    (from, from_key): (Cipher, &SecretVec<u8>),
+    (to, to_key): (Cipher, &SecretVec<u8>),
+    framed: bool,
+) -> io::Result<()> {
+    let opening_key = BlockKey::new(from, from_key)?;
+    let sealing_key = BlockKey::new(to, to_key)?;
+    let plaintext_len = BLOCK_SIZE + if framed { FRAME_HEADER_LEN } else { 0 };
+    let mut buf = vec![0; plaintext_len + from.per_block_overhead()];
+    let mut rng = create_rng();
+    let mut block_index = 0_u64;
+    let res = (|| loop {
+        let len = stream_util::read(&mut *input, &mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if len < from.per_block_overhead() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted block too short",
+            ));
+        }
+        if len == buf.len() && buf.iter().all(|b| *b == 0) {
+            output.write_all(&buf)?;
+            block_index += 1;
+            continue;
+        }
+        let (nonce, data) = buf[..len].split_at_mut(NONCE_LEN);
+        let data = opening_key
+            .open_in_place(nonce, block_index, data)
+            .map_err(|err| {
+                error!("error opening block: {}", err);
+                io::Error::new(io::ErrorKind::InvalidData, "error opening block")
+            })?;
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        // sealed in place, so the buffer holds no plaintext after this
+        let tag = sealing_key
+            .seal_in_place_separate_tag(&nonce, block_index, data)
+            .map_err(|err| {
+                error!("error sealing block: {}", err);
+                io::Error::new(io::ErrorKind::Other, "error sealing block")
+            })?;
+        output.write_all(&nonce)?;
+        output.write_all(data)?;
+        output.write_all(&tag)?;
+        block_index += 1;
+    })();
+    buf.zeroize();
+    res
+}
+
 #[must_use]
 pub fn create_rng() -> impl RngCore + CryptoRng {
     ChaCha20Rng::from_entropy()
@@ -391,6 +946,7 @@ mod tests {
         io::{self, Write},
         path::{Path, PathBuf},
     };
+    use strum::IntoEnumIterator;
     use tempfile::{tempdir, TempDir};
 
     fn create_encrypted_file(
@@ -432,7 +988,7 @@ mod tests {
     fn test_simple_encrypt_and_decrypt() {
         let secret = SecretString::from_str("Test secret").unwrap();
 
-        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        for cipher in Cipher::iter() {
             let key = secret_key(cipher);
 
             let encrypted = encrypt(&secret, cipher, &key).unwrap();
@@ -447,7 +1003,7 @@ mod tests {
 
         for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
             let key = secret_key(cipher);
-            let encrypted = encrypt_file_name(&secret_name, cipher, &key).unwrap();
+            let encrypted = encrypt_file_name(&secret_name, cipher, &key, None).unwrap();
             let decrypted = decrypt_file_name(&encrypted, cipher, &key).unwrap();
             assert_eq!(decrypted.expose_secret(), secret_name.expose_secret());
         }
@@ -456,18 +1012,49 @@ mod tests {
 
         for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
             let key = secret_key(cipher);
-            let encrypted = encrypt_file_name(&secret_name, cipher, &key).unwrap();
+            let encrypted = encrypt_file_name(&secret_name, cipher, &key, None).unwrap();
             let decrypted = decrypt_file_name(&encrypted, cipher, &key).unwrap();
             assert_eq!(decrypted.expose_secret(), secret_name.expose_secret());
         }
     }
 
+    #[test]
+    fn test_encrypt_file_name_padding() {
+        let padding = NonZeroUsize::new(16);
+        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let key = secret_key(cipher);
+            let short = SecretString::from_str("a.txt").unwrap();
+            let long = SecretString::from_str("longer-name.txt").unwrap();
+            let other_bucket = SecretString::from_str("even-longer-name.txt").unwrap();
+
+            let short_enc = encrypt_file_name(&short, cipher, &key, padding).unwrap();
+            let long_enc = encrypt_file_name(&long, cipher, &key, padding).unwrap();
+            let other_bucket_enc = encrypt_file_name(&other_bucket, cipher, &key, padding).unwrap();
+            assert_eq!(short_enc.len(), long_enc.len());
+            assert!(other_bucket_enc.len() > long_enc.len());
+            assert_ne!(
+                encrypt_file_name(&short, cipher, &key, None).unwrap().len(),
+                encrypt_file_name(&long, cipher, &key, None).unwrap().len()
+            );
+
+            for (name, encrypted) in [
+                (&short, short_enc),
+                (&long, long_enc),
+                (&other_bucket, other_bucket_enc),
+            ] {
+                let decrypted = decrypt_file_name(&encrypted, cipher, &key).unwrap();
+                assert_eq!(decrypted.expose_secret(), name.expose_secret());
+            }
+        }
+    }
+
     #[test]
     fn test_encrypt_and_decrypt_file_name_invalid_cipher() {
         let key = secret_key(Cipher::ChaCha20Poly1305);
         let secret_name = SecretString::from_str("testfile.txt").unwrap();
 
-        let encrypted = encrypt_file_name(&secret_name, Cipher::ChaCha20Poly1305, &key).unwrap();
+        let encrypted =
+            encrypt_file_name(&secret_name, Cipher::ChaCha20Poly1305, &key, None).unwrap();
         let result = decrypt_file_name(&encrypted, Cipher::Aes256Gcm, &key);
         assert!(result.is_err());
     }
@@ -478,7 +1065,7 @@ mod tests {
         let salt = b"salt_of_pass";
 
         for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
-            let derived_key = derive_key(&password, cipher, salt).unwrap();
+            let derived_key = derive_key(&password, cipher, salt, &KdfParams::default()).unwrap();
             assert_eq!(derived_key.expose_secret().len(), cipher.key_len());
         }
     }
@@ -488,18 +1075,83 @@ mod tests {
         let password = SecretString::from_str("password").unwrap();
         let salt = b"random_salt";
 
-        let derived_key_1 = derive_key(&password, Cipher::ChaCha20Poly1305, salt).unwrap();
-        let derived_key_2 = derive_key(&password, Cipher::ChaCha20Poly1305, salt).unwrap();
+        let derived_key_1 = derive_key(
+            &password,
+            Cipher::ChaCha20Poly1305,
+            salt,
+            &KdfParams::default(),
+        )
+        .unwrap();
+        let derived_key_2 = derive_key(
+            &password,
+            Cipher::ChaCha20Poly1305,
+            salt,
+            &KdfParams::default(),
+        )
+        .unwrap();
 
         assert_eq!(derived_key_1.expose_secret(), derived_key_2.expose_secret());
     }
 
+    #[test]
+    fn test_derive_key_params() {
+        let password = SecretString::from_str("password").unwrap();
+        let salt = b"random_salt";
+        let params = KdfParams {
+            m_cost: 8 * 1024,
+            ..KdfParams::LEGACY
+        };
+
+        let key = derive_key(&password, Cipher::ChaCha20Poly1305, salt, &params).unwrap();
+        let legacy_key = derive_key(
+            &password,
+            Cipher::ChaCha20Poly1305,
+            salt,
+            &KdfParams::LEGACY,
+        )
+        .unwrap();
+        assert_ne!(key.expose_secret(), legacy_key.expose_secret());
+
+        let invalid = KdfParams {
+            t_cost: 0,
+            ..KdfParams::LEGACY
+        };
+        assert!(derive_key(&password, Cipher::ChaCha20Poly1305, salt, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_kdf_params_validate() {
+        KdfParams::LEGACY.validate().unwrap();
+        KdfParams::default().validate().unwrap();
+        for params in [
+            KdfParams {
+                m_cost: u32::MAX,
+                ..KdfParams::LEGACY
+            },
+            KdfParams {
+                t_cost: u32::MAX,
+                ..KdfParams::LEGACY
+            },
+            KdfParams {
+                p_cost: 0,
+                ..KdfParams::LEGACY
+            },
+        ] {
+            assert!(params.validate().is_err());
+        }
+    }
+
     #[test]
     fn test_derive_key_empty_salt() {
         let empty_password = SecretString::from_str("password").unwrap();
         let empty_salt = b"";
 
-        let result = derive_key(&empty_password, Cipher::ChaCha20Poly1305, empty_salt);
+        let result = derive_key(
+            &empty_password,
+            Cipher::ChaCha20Poly1305,
+            empty_salt,
+            &KdfParams::default(),
+        );
 
         // Salt is too small
         assert!(result.is_err());
@@ -512,7 +1164,13 @@ mod tests {
 
         let mut derived_keys = std::collections::HashSet::new();
         for salt in salts.clone() {
-            let derived_key = derive_key(&password, Cipher::ChaCha20Poly1305, salt).unwrap();
+            let derived_key = derive_key(
+                &password,
+                Cipher::ChaCha20Poly1305,
+                salt,
+                &KdfParams::default(),
+            )
+            .unwrap();
             derived_keys.insert(derived_key.expose_secret().clone());
         }
 
@@ -650,4 +1308,280 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_per_block_overhead() {
+        for cipher in Cipher::iter() {
+            let key = secret_key(cipher);
+            for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE + 1, 3 * BLOCK_SIZE - 1] {
+                let mut writer = create_write(io::Cursor::new(vec![]), cipher, &key);
+                writer.write_all(&vec![42; len]).unwrap();
+                let encrypted = writer.finish().unwrap().into_inner();
+                assert_eq!(encrypted.len() as u64, cipher.ciphertext_len(len as u64));
+            }
+            assert_eq!(cipher.per_block_overhead(), NONCE_LEN + cipher.tag_len());
+        }
+    }
+
+    #[test]
+    fn test_nonce_strategies() {
+        let key = secret_key(Cipher::ChaCha20Poly1305);
+        let data = "test-42".repeat(BLOCK_SIZE / 3);
+        let dir = tempdir().unwrap();
+        let counter = Arc::new(NonceCounter::open(&dir.path().join("nonce_counter")).unwrap());
+
+        for nonce_counter in [None, Some(counter)] {
+            let mut writer = create_write_seek_with_transform(
+                io::Cursor::new(vec![]),
+                Cipher::ChaCha20Poly1305,
+                &key,
+                None,
+                nonce_counter.clone(),
+                None,
+                false,
+            );
+            writer.write_all(data.as_bytes()).unwrap();
+            let encrypted = writer.finish().unwrap().into_inner();
+
+            let mut reader = create_read(encrypted.as_slice(), Cipher::ChaCha20Poly1305, &key);
+            let mut decrypted = String::new();
+            reader.read_to_string(&mut decrypted).unwrap();
+            assert_eq!(data, decrypted);
+
+            let nonces: Vec<_> = encrypted
+                .chunks(NONCE_LEN + BLOCK_SIZE + Cipher::ChaCha20Poly1305.tag_len())
+                .map(|block| &block[..NONCE_LEN])
+                .collect();
+            assert_eq!(nonces.len(), data.len().div_ceil(BLOCK_SIZE));
+            if nonce_counter.is_some() {
+                // increasing, one for each block
+                for (i, nonce) in nonces.iter().enumerate() {
+                    assert_eq!(nonce[NONCE_LEN - 1] as usize, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparse_write() {
+        let key = secret_key(Cipher::ChaCha20Poly1305);
+        let holes = Arc::new(HoleMap::default());
+        let mut writer = create_write_seek_with_transform(
+            io::Cursor::new(vec![]),
+            Cipher::ChaCha20Poly1305,
+            &key,
+            None,
+            None,
+            Some(holes.clone()),
+            true,
+        );
+        writer.write_all(b"start").unwrap();
+        writer
+            .seek(SeekFrom::Start(10 * BLOCK_SIZE as u64 + 5))
+            .unwrap();
+        writer.write_all(b"end").unwrap();
+        let encrypted = writer.finish().unwrap().into_inner();
+        // the first block is completed with zeros, the whole blocks after it are left as holes
+        assert_eq!(holes.ranges(), vec![(1, 10)]);
+        #[allow(clippy::cast_possible_truncation)]
+        let block_len = Cipher::ChaCha20Poly1305.ciphertext_len(BLOCK_SIZE as u64) as usize;
+        assert!(encrypted[block_len..10 * block_len].iter().all(|b| *b == 0));
+        assert_eq!(
+            encrypted.len() as u64,
+            Cipher::ChaCha20Poly1305.ciphertext_len(10 * BLOCK_SIZE as u64 + 8)
+        );
+
+        let mut expected = vec![0; 10 * BLOCK_SIZE + 8];
+        expected[..5].copy_from_slice(b"start");
+        expected[10 * BLOCK_SIZE + 5..].copy_from_slice(b"end");
+        let read = |encrypted: &[u8], cipher, holes: Option<Arc<HoleMap>>| {
+            let mut reader = create_read_seek_with_transform(
+                io::Cursor::new(encrypted.to_vec()),
+                cipher,
+                &key,
+                None,
+                holes,
+            );
+            let mut decrypted = vec![];
+            reader.read_to_end(&mut decrypted).map(|_| decrypted)
+        };
+        assert_eq!(
+            read(&encrypted, Cipher::ChaCha20Poly1305, Some(holes.clone())).unwrap(),
+            expected
+        );
+        // zeros are not accepted outside the holes
+        assert!(read(&encrypted, Cipher::ChaCha20Poly1305, None).is_err());
+
+        // holes are kept when re-encrypting
+        let mut reencrypted = vec![];
+        reencrypt(
+            &mut encrypted.as_slice(),
+            &mut reencrypted,
+            Cipher::ChaCha20Poly1305,
+            Cipher::Aes256Gcm,
+            &key,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            read(&reencrypted, Cipher::Aes256Gcm, Some(holes)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_aes256gcmsiv_rewrite() {
+        let cipher = Cipher::Aes256GcmSiv;
+        let key = secret_key(cipher);
+        let len = BLOCK_SIZE * 3 + 42;
+        let mut expected = vec![0; len];
+        create_rng().fill_bytes(&mut expected);
+
+        let mut writer = create_write_seek(io::Cursor::new(vec![]), cipher, &key);
+        writer.write_all(&expected).unwrap();
+        let mut encrypted = writer.finish().unwrap();
+        for i in 0..20_u8 {
+            // the same region, across two blocks
+            let offset = BLOCK_SIZE / 2 + usize::from(i % 3);
+            let mut writer = create_write_seek(encrypted, cipher, &key);
+            writer.seek(SeekFrom::Start(offset as u64)).unwrap();
+            let data = vec![i; BLOCK_SIZE];
+            writer.write_all(&data).unwrap();
+            expected[offset..offset + BLOCK_SIZE].copy_from_slice(&data);
+            encrypted = writer.finish().unwrap();
+
+            encrypted.seek(SeekFrom::Start(0)).unwrap();
+            let mut reader = create_read(&mut encrypted, cipher, &key);
+            let mut decrypted = vec![];
+            reader.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(expected, decrypted);
+        }
+        assert_eq!(
+            encrypted.get_ref().len() as u64,
+            cipher.ciphertext_len(len as u64)
+        );
+
+        let mut reencrypted = vec![];
+        reencrypt(
+            &mut encrypted.get_ref().as_slice(),
+            &mut reencrypted,
+            cipher,
+            Cipher::Aes256Gcm,
+            &key,
+            false,
+        )
+        .unwrap();
+        let mut decrypted = vec![];
+        create_read(reencrypted.as_slice(), Cipher::Aes256Gcm, &key)
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(expected, decrypted);
+    }
+
+    #[test]
+    fn test_reencrypt() {
+        let key = secret_key(Cipher::ChaCha20Poly1305);
+        let data = "test-42".repeat(BLOCK_SIZE / 3);
+
+        let transforms: [Option<Arc<dyn ContentTransform>>; 2] =
+            [None, Some(Arc::new(transform::XorTransform))];
+        for transform in transforms {
+            let framed = transform.is_some();
+            let mut writer = create_write_with_transform(
+                io::Cursor::new(vec![]),
+                Cipher::ChaCha20Poly1305,
+                &key,
+                transform.clone(),
+                None,
+            );
+            writer.write_all(data.as_bytes()).unwrap();
+            let encrypted = writer.finish().unwrap().into_inner();
+
+            let mut reencrypted = vec![];
+            reencrypt(
+                &mut encrypted.as_slice(),
+                &mut reencrypted,
+                Cipher::ChaCha20Poly1305,
+                Cipher::Aes256Gcm,
+                &key,
+                framed,
+            )
+            .unwrap();
+            assert_eq!(encrypted.len(), reencrypted.len());
+
+            let mut decrypted = String::new();
+            create_read_with_transform(
+                reencrypted.as_slice(),
+                Cipher::Aes256Gcm,
+                &key,
+                transform.clone(),
+            )
+            .read_to_string(&mut decrypted)
+            .unwrap();
+            assert_eq!(data, decrypted);
+            // not readable with the old cipher anymore
+            let mut decrypted = String::new();
+            assert!(create_read_with_transform(
+                reencrypted.as_slice(),
+                Cipher::ChaCha20Poly1305,
+                &key,
+                transform,
+            )
+            .read_to_string(&mut decrypted)
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_encrypt_file() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = secret_key(cipher);
+        let data = "test-42".repeat(BLOCK_SIZE / 3);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().join("test-file");
+        let encrypted = temp_dir.path().join("test-file.enc");
+        let decrypted = temp_dir.path().join("test-file.dec");
+        std::fs::write(&src, &data).unwrap();
+
+        let (len, hash) = encrypt_file(&src, &encrypted, cipher, &key).unwrap();
+        assert_eq!(data.len() as u64, len);
+        assert_eq!(hash_reader(&mut data.as_bytes()).unwrap(), hash);
+        assert_ne!(data.as_bytes(), std::fs::read(&encrypted).unwrap());
+        assert_eq!(
+            (len, hash),
+            decrypt_file(&encrypted, &decrypted, cipher, &key).unwrap()
+        );
+        assert_eq!(data, std::fs::read_to_string(&decrypted).unwrap());
+
+        // nothing is left behind on error
+        std::fs::write(&decrypted, "existing").unwrap();
+        assert!(decrypt_file(&encrypted, &decrypted, cipher, &secret_key(cipher)).is_err());
+        assert_eq!("existing", std::fs::read_to_string(&decrypted).unwrap());
+        assert_eq!(3, std::fs::read_dir(temp_dir.path()).unwrap().count());
+
+        let password = SecretString::from_str("password").unwrap();
+        let salt = b"random_salt";
+        encrypt_file_with_password(
+            &src,
+            &encrypted,
+            cipher,
+            &password,
+            salt,
+            &KdfParams::LEGACY,
+        )
+        .unwrap();
+        assert_eq!(
+            (len, hash),
+            decrypt_file_with_password(
+                &encrypted,
+                &decrypted,
+                cipher,
+                &password,
+                salt,
+                &KdfParams::LEGACY
+            )
+            .unwrap()
+        );
+        assert_eq!(data, std::fs::read_to_string(&decrypted).unwrap());
+    }
 }
@@ -0,0 +1,136 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use shush_rs::{ExposeSecret, SecretVec};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::crypto::{create_rng, Error, Result};
+
+/// Length (in bytes) of the X25519 keys used for escrow.
+pub const ESCROW_KEY_LEN: usize = 32;
+
+const VERSION: u8 = 1;
+const INFO: &[u8] = b"rencfs key escrow";
+const HEADER_LEN: usize = 1 + ESCROW_KEY_LEN;
+
+/// Create a new X25519 keypair for escrow, returns the secret and the public key.
+///
+/// The public key is used by [`wrap`] and the secret one, which the recovery service keeps, by [`unwrap`].
+#[must_use]
+pub fn generate_keypair() -> (SecretVec<u8>, [u8; ESCROW_KEY_LEN]) {
+    let secret = StaticSecret::random_from_rng(create_rng());
+    let public = PublicKey::from(&secret);
+    (
+        SecretVec::new(Box::new(secret.to_bytes().to_vec())),
+        public.to_bytes(),
+    )
+}
+
+/// Encrypt `key` for the owner of `escrow_public_key`.
+///
+/// It uses an ephemeral X25519 key agreement, with the shared secret passed through HKDF-SHA256,
+/// and encrypts with ChaCha20Poly1305. The result is `[version][ephemeral public key][ciphertext][tag]`.
+#[allow(clippy::missing_errors_doc)]
+pub fn wrap(key: &SecretVec<u8>, escrow_public_key: &[u8]) -> Result<Vec<u8>> {
+    let recipient = PublicKey::from(to_key_bytes(escrow_public_key)?);
+    let ephemeral = EphemeralSecret::random_from_rng(create_rng());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient);
+    if !shared.was_contributory() {
+        return Err(Error::Generic("invalid escrow public key"));
+    }
+    let sealing_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, &recipient)?;
+
+    let mut out =
+        Vec::with_capacity(HEADER_LEN + key.expose_secret().len() + CHACHA20_POLY1305.tag_len());
+    out.push(VERSION);
+    out.extend_from_slice(ephemeral_public.as_bytes());
+    let mut in_out = key.expose_secret().to_vec();
+    // the key is used only once, for this ephemeral secret, so a fixed nonce is safe
+    sealing_key
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key([0; NONCE_LEN]),
+            Aad::from([VERSION]),
+            &mut in_out,
+        )
+        .map_err(|_| Error::Generic("cannot wrap key"))?;
+    out.extend_from_slice(&in_out);
+    Ok(out)
+}
+
+/// Decrypt a key wrapped by [`wrap`] with the escrow secret key.
+#[allow(clippy::missing_errors_doc)]
+pub fn unwrap(wrapped: &[u8], escrow_secret_key: &SecretVec<u8>) -> Result<SecretVec<u8>> {
+    if wrapped.len() < HEADER_LEN + CHACHA20_POLY1305.tag_len() {
+        return Err(Error::Generic("invalid escrow data"));
+    }
+    if wrapped[0] != VERSION {
+        return Err(Error::Generic("unsupported escrow version"));
+    }
+    let secret = StaticSecret::from(to_key_bytes(escrow_secret_key.expose_secret().as_slice())?);
+    let recipient = PublicKey::from(&secret);
+    let ephemeral_public = PublicKey::from(to_key_bytes(&wrapped[1..HEADER_LEN])?);
+    let shared = secret.diffie_hellman(&ephemeral_public);
+    if !shared.was_contributory() {
+        return Err(Error::Generic("invalid escrow data"));
+    }
+    let opening_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, &recipient)?;
+
+    let mut in_out = wrapped[HEADER_LEN..].to_vec();
+    let len = opening_key
+        .open_in_place(
+            Nonce::assume_unique_for_key([0; NONCE_LEN]),
+            Aad::from([VERSION]),
+            &mut in_out,
+        )
+        .map_err(|_| Error::Generic("invalid escrow key"))?
+        .len();
+    in_out.truncate(len);
+    Ok(SecretVec::new(Box::new(in_out)))
+}
+
+fn derive_wrapping_key(
+    shared: &[u8],
+    ephemeral_public: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<LessSafeKey> {
+    let mut salt = [0; 2 * ESCROW_KEY_LEN];
+    salt[..ESCROW_KEY_LEN].copy_from_slice(ephemeral_public.as_bytes());
+    salt[ESCROW_KEY_LEN..].copy_from_slice(recipient.as_bytes());
+    let okm = Salt::new(HKDF_SHA256, &salt)
+        .extract(shared)
+        .expand(&[INFO], &CHACHA20_POLY1305)
+        .map_err(|_| Error::Generic("cannot derive escrow key"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn to_key_bytes(key: &[u8]) -> Result<[u8; ESCROW_KEY_LEN]> {
+    key.try_into()
+        .map_err(|_| Error::Generic("invalid escrow key length"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let (secret, public) = generate_keypair();
+        let key = SecretVec::new(Box::new(vec![42; 32]));
+        let wrapped = wrap(&key, &public).unwrap();
+        assert_eq!(wrapped.len(), HEADER_LEN + 32 + CHACHA20_POLY1305.tag_len());
+        assert!(!wrapped.windows(32).any(|w| w == [42; 32]));
+        let unwrapped = unwrap(&wrapped, &secret).unwrap();
+        assert_eq!(*unwrapped.expose_secret(), *key.expose_secret());
+
+        // each wrap uses a new ephemeral key
+        assert_ne!(wrap(&key, &public).unwrap(), wrapped);
+
+        let (other, _) = generate_keypair();
+        assert!(unwrap(&wrapped, &other).is_err());
+        let mut tampered = wrapped.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(unwrap(&tampered, &secret).is_err());
+        assert!(wrap(&key, &public[..16]).is_err());
+        assert!(unwrap(&wrapped[..HEADER_LEN], &secret).is_err());
+    }
+}
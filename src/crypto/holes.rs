@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// Blocks of the content of a sparse file which were never written, they are left as holes on disk and read as zeros.
+///
+/// A hole is all zeros on disk, which doesn't decrypt, so an all zeros block is read as zeros only if its index is
+/// in the map, else zeroing blocks on disk would go undetected. Writers remove the blocks they write, so a block is
+/// read as a hole only while it was never written after it was skipped.
+///
+/// It's shared by all the readers and writers of a file, writers add the blocks they skip and the
+/// [`EncryptedFs`](crate::encryptedfs::EncryptedFs) saves it before the content is committed, together with its
+/// MAC in the inode, so a map from another file or an older one is refused.
+#[derive(Debug, Default)]
+pub struct HoleMap {
+    // start -> end (exclusive) block index, disjoint and not adjacent
+    ranges: RwLock<BTreeMap<u64, u64>>,
+    dirty: AtomicBool,
+}
+
+impl HoleMap {
+    /// Map with `ranges` of block indexes, `(start, end)` with `end` exclusive, like the ones from [`HoleMap::ranges`].
+    #[must_use]
+    pub fn from_ranges(ranges: &[(u64, u64)]) -> Self {
+        let holes = Self::default();
+        for (start, end) in ranges {
+            holes.insert(*start, *end);
+        }
+        holes.dirty.store(false, Ordering::SeqCst);
+        holes
+    }
+
+    /// The ranges of block indexes in the map, ordered.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        self.ranges
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(start, end)| (*start, *end))
+            .collect()
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.read().unwrap().is_empty()
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub fn contains(&self, block_index: u64) -> bool {
+        self.ranges
+            .read()
+            .unwrap()
+            .range(..=block_index)
+            .next_back()
+            .is_some_and(|(_, end)| block_index < *end)
+    }
+
+    /// Add the blocks from `start` until `end`, exclusive.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn insert(&self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let mut ranges = self.ranges.write().unwrap();
+        let (mut start, mut end) = (start, end);
+        // merge with the overlapping and adjacent ranges
+        let merged = ranges
+            .range(..=end)
+            .rev()
+            .take_while(|(_, range_end)| **range_end >= start)
+            .map(|(range_start, range_end)| (*range_start, *range_end))
+            .collect::<Vec<_>>();
+        for (range_start, range_end) in merged {
+            ranges.remove(&range_start);
+            start = start.min(range_start);
+            end = end.max(range_end);
+        }
+        ranges.insert(start, end);
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Remove one block, like when it's written.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn remove(&self, block_index: u64) {
+        if !self.contains(block_index) {
+            return;
+        }
+        let mut ranges = self.ranges.write().unwrap();
+        let Some((start, end)) = ranges
+            .range(..=block_index)
+            .next_back()
+            .map(|(start, end)| (*start, *end))
+            .filter(|(_, end)| block_index < *end)
+        else {
+            // removed meanwhile
+            return;
+        };
+        ranges.remove(&start);
+        if start < block_index {
+            ranges.insert(start, block_index);
+        }
+        if block_index + 1 < end {
+            ranges.insert(block_index + 1, end);
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// If blocks were added or removed since the last call, so it needs to be saved.
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::SeqCst)
+    }
+
+    /// Mark it as changed again, like when saving it failed.
+    pub fn set_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let holes = HoleMap::default();
+        assert!(holes.is_empty());
+        assert!(!holes.take_dirty());
+
+        holes.insert(2, 5);
+        assert!(holes.take_dirty());
+        assert!(!holes.contains(1));
+        assert!(holes.contains(2));
+        assert!(holes.contains(4));
+        assert!(!holes.contains(5));
+
+        holes.insert(10, 12);
+        // adjacent to the first one
+        holes.insert(5, 7);
+        assert_eq!(holes.ranges(), vec![(2, 7), (10, 12)]);
+        // overlaps both
+        holes.insert(6, 11);
+        assert_eq!(holes.ranges(), vec![(2, 12)]);
+        holes.insert(3, 3);
+        assert_eq!(holes.ranges(), vec![(2, 12)]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let holes = HoleMap::from_ranges(&[(2, 6), (8, 9)]);
+        holes.remove(0);
+        assert!(!holes.take_dirty());
+        holes.remove(3);
+        assert!(holes.take_dirty());
+        assert_eq!(holes.ranges(), vec![(2, 3), (4, 6), (8, 9)]);
+        holes.remove(2);
+        holes.remove(5);
+        holes.remove(8);
+        assert_eq!(holes.ranges(), vec![(4, 5)]);
+        assert!(!holes.contains(8));
+    }
+
+    #[test]
+    fn test_from_ranges() {
+        let holes = HoleMap::from_ranges(&[(0, 1), (3, 4)]);
+        assert!(!holes.take_dirty());
+        assert!(holes.contains(0));
+        assert!(!holes.contains(2));
+        assert!(holes.contains(3));
+        assert_eq!(holes.ranges(), vec![(0, 1), (3, 4)]);
+    }
+}
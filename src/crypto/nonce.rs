@@ -0,0 +1,143 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use ring::aead::NONCE_LEN;
+use serde::{Deserialize, Serialize};
+
+use crate::encryptedfs::storage::{FileStorage, Storage};
+
+/// How we generate the nonce of each encrypted block.
+///
+/// All blocks are encrypted with the same key, so a nonce must never repeat, else the content of both
+/// blocks can be recovered.
+///
+/// - [`NonceStrategy::Random`] needs no state, but the nonces are random 96 bits, and after `2^32` blocks the chance
+///   of a collision reaches `2^-32`, the limit NIST recommends. With 256 KiB blocks that is about **1 PiB** written
+///   over the lifetime of the volume, counting rewrites.
+/// - [`NonceStrategy::Counter`] uses a counter kept in the data dir, so nonces can't repeat until `2^96` blocks.
+///   The counter needs to be written to disk from time to time, and it must not be reverted, like restoring
+///   an older copy of the data dir and writing to it, as that would reuse nonces.
+///
+/// Random is the default, as volumes are expected to be way below the limit.
+/// It applies to the content of files, metadata and names always use random nonces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NonceStrategy {
+    #[default]
+    Random,
+    Counter,
+}
+
+/// How many nonces we reserve at once, so we don't write the counter for each block.
+const RESERVE: u128 = 1 << 16;
+const MAX: u128 = 1 << (NONCE_LEN * 8);
+
+/// Counter used for [`NonceStrategy::Counter`], it's shared by all writers of a volume.
+///
+/// We persist the end of a range of reserved values before using them, so after a crash we continue after it
+/// and the values handed out are always increasing, even if some are skipped.
+pub struct NonceCounter {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    // (next, reserved)
+    state: Mutex<(u128, u128)>,
+}
+
+impl NonceCounter {
+    /// Open the counter kept in `path`, it's created on the first [`NonceCounter::next`] if it doesn't exist.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_in(Arc::new(FileStorage), path)
+    }
+
+    /// Like [`NonceCounter::open`], with the counter kept in `storage`.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn open_in(storage: Arc<dyn Storage>, path: &Path) -> io::Result<Self> {
+        let reserved = match storage.read(path) {
+            Ok(bytes) => u128::from_le_bytes(bytes.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid nonce counter")
+            })?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            storage,
+            path: path.to_path_buf(),
+            state: Mutex::new((reserved, reserved)),
+        })
+    }
+
+    /// Next nonce, as a 96 bits big-endian number.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn next(&self) -> io::Result<[u8; NONCE_LEN]> {
+        let mut state = self.state.lock().unwrap();
+        let (next, reserved) = *state;
+        if next == reserved {
+            let reserved = reserved + RESERVE;
+            if reserved > MAX {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "nonce counter exhausted",
+                ));
+            }
+            self.storage
+                .write_atomic(&self.path, &reserved.to_le_bytes())?;
+            state.1 = reserved;
+        }
+        state.0 = next + 1;
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&next.to_be_bytes()[16 - NONCE_LEN..]);
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_counter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nonce_counter");
+        let counter = NonceCounter::open(&path).unwrap();
+        assert_eq!(counter.next().unwrap(), [0; NONCE_LEN]);
+        let mut expected = [0; NONCE_LEN];
+        expected[NONCE_LEN - 1] = 1;
+        assert_eq!(counter.next().unwrap(), expected);
+        assert!(path.exists());
+
+        let mut last = counter.next().unwrap();
+        for _ in 0..RESERVE * 2 {
+            let nonce = counter.next().unwrap();
+            assert!(nonce > last);
+            last = nonce;
+        }
+    }
+
+    #[test]
+    fn test_counter_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nonce_counter");
+        let counter = NonceCounter::open(&path).unwrap();
+        let mut last = [0; NONCE_LEN];
+        for _ in 0..10 {
+            last = counter.next().unwrap();
+        }
+        // nothing is written on drop, like when the process is killed
+        drop(counter);
+
+        let counter = NonceCounter::open(&path).unwrap();
+        let nonce = counter.next().unwrap();
+        assert!(nonce > last);
+        // reopen while the reserved range is not used yet
+        drop(counter);
+        let counter = NonceCounter::open(&path).unwrap();
+        assert!(counter.next().unwrap() > nonce);
+
+        fs::write(&path, [0; 3]).unwrap();
+        assert!(NonceCounter::open(&path).is_err());
+    }
+}
@@ -1,16 +1,16 @@
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use ring::aead::{
-    Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, UnboundKey, NONCE_LEN,
-};
-use ring::error;
-use shush_rs::{ExposeSecret, SecretVec};
+use ring::aead::{Algorithm, NONCE_LEN};
+use shush_rs::SecretVec;
 use tracing::{error, instrument, warn};
 
 use crate::crypto::buf_mut::BufMut;
+use crate::crypto::holes::HoleMap;
+use crate::crypto::transform::{ContentTransform, FRAME_HEADER_LEN};
 use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{BlockKey, Cipher};
 use crate::stream_util;
 
 mod test;
@@ -25,7 +25,7 @@ pub trait CryptoRead<R: Read + Send + Sync>: Read + Send + Sync {
 /// ring
 #[macro_export]
 macro_rules! decrypt_block {
-    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr) => {{
+    ($block_index:expr, $buf:expr, $input:expr, $opening_key:expr, $transform:expr, $holes:expr) => {{
         let len = {
             $buf.clear();
             let buffer = $buf.as_mut_remaining();
@@ -44,20 +44,40 @@ macro_rules! decrypt_block {
                 }
                 pos
             };
-            if len != 0 {
-                let data = &mut buffer[..len];
-                let aad = Aad::from(($block_index).to_le_bytes());
-                // extract nonce
-                $last_nonce
-                    .lock()
-                    .unwrap()
-                    .replace(data[..NONCE_LEN].to_vec());
-                let data = &mut data[NONCE_LEN..];
-                let plaintext = $opening_key.open_within(aad, data, 0..).map_err(|err| {
-                    error!("error opening within: {}", err);
-                    io::Error::new(io::ErrorKind::Other, "error opening within")
-                })?;
+            if len == buffer.len()
+                && $holes
+                    .as_ref()
+                    .is_some_and(|holes| holes.contains($block_index))
+                && buffer.iter().all(|b| *b == 0)
+            {
+                // a hole, see [`HoleMap`](crate::crypto::holes::HoleMap)
+                len = BLOCK_SIZE;
+            } else if len != 0 {
+                if len < NONCE_LEN {
+                    // truncated or corrupted, don't panic on slicing
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "block too short",
+                    ));
+                }
+                let (nonce, data) = buffer[..len].split_at_mut(NONCE_LEN);
+                let plaintext = $opening_key
+                    .open_in_place(nonce, $block_index, data)
+                    .map_err(|err| {
+                        error!("error opening within: {}", err);
+                        io::Error::new(io::ErrorKind::InvalidData, "error opening within")
+                    })?;
                 len = plaintext.len();
+                if let Some(transform) = $transform.as_ref() {
+                    let mut decoded = $crate::crypto::transform::decode_block(
+                        transform.as_ref(),
+                        $block_index,
+                        &plaintext[..len],
+                    )?;
+                    len = decoded.len();
+                    plaintext[..len].copy_from_slice(&decoded);
+                    shush_rs::Zeroize::zeroize(&mut decoded);
+                }
             }
             len
         };
@@ -76,33 +96,57 @@ pub(crate) use decrypt_block;
 #[allow(clippy::module_name_repetitions)]
 pub struct RingCryptoRead<R: Read> {
     input: Option<R>,
-    opening_key: OpeningKey<ExistingNonceSequence>,
+    opening_key: BlockKey,
     buf: BufMut,
-    last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    transform: Option<Arc<dyn ContentTransform>>,
+    holes: Option<Arc<HoleMap>>,
 }
 
 impl<R: Read> RingCryptoRead<R> {
     #[allow(clippy::missing_panics_doc)]
     pub fn new(reader: R, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
-        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
+        Self::with_key(reader, BlockKey::ring(algorithm, key).unwrap())
+    }
+
+    /// Like [`RingCryptoRead::new`], but with any [`Cipher`], also the ones not implemented by `ring`.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn with_cipher(reader: R, cipher: Cipher, key: &SecretVec<u8>) -> Self {
+        Self::with_key(reader, BlockKey::new(cipher, key).unwrap())
+    }
+
+    fn with_key(reader: R, opening_key: BlockKey) -> Self {
+        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + opening_key.tag_len();
         let buf = BufMut::new(vec![0; ciphertext_block_size]);
-        let last_nonce = Arc::new(Mutex::new(None));
-        let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).unwrap();
-        let nonce_sequence = ExistingNonceSequence::new(last_nonce.clone());
-        let opening_key = OpeningKey::new(unbound_key, nonce_sequence);
         Self {
             input: Some(reader),
             opening_key,
             buf,
-            last_nonce,
             ciphertext_block_size,
             plaintext_block_size: BLOCK_SIZE,
             block_index: 0,
+            transform: None,
+            holes: None,
         }
     }
+
+    /// Reverts `transform` on each block after decrypting it, see [`ContentTransform`] for the framing.
+    #[must_use]
+    pub fn with_transform(mut self, transform: Arc<dyn ContentTransform>) -> Self {
+        self.ciphertext_block_size += FRAME_HEADER_LEN;
+        self.buf = BufMut::new(vec![0; self.ciphertext_block_size]);
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Read the all zeros blocks in `holes` as zeros, see [`HoleMap`].
+    #[must_use]
+    pub fn with_holes(mut self, holes: Arc<HoleMap>) -> Self {
+        self.holes = Some(holes);
+        self
+    }
 }
 
 impl<R: Read> Read for RingCryptoRead<R> {
@@ -118,30 +162,15 @@ impl<R: Read> Read for RingCryptoRead<R> {
             self.block_index,
             self.buf,
             self.input.as_mut().unwrap(),
-            self.last_nonce,
-            self.opening_key
+            self.opening_key,
+            self.transform,
+            self.holes
         );
         let len = self.buf.read(buf)?;
         Ok(len)
     }
 }
 
-pub(crate) struct ExistingNonceSequence {
-    last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
-}
-
-impl ExistingNonceSequence {
-    pub const fn new(last_nonce: Arc<Mutex<Option<Vec<u8>>>>) -> Self {
-        Self { last_nonce }
-    }
-}
-
-impl NonceSequence for ExistingNonceSequence {
-    fn advance(&mut self) -> Result<Nonce, error::Unspecified> {
-        Nonce::try_assume_unique_for_key(self.last_nonce.lock().unwrap().as_mut().unwrap())
-    }
-}
-
 impl<R: Read + Send + Sync> CryptoRead<R> for RingCryptoRead<R> {
     fn into_inner(&mut self) -> R {
         self.input.take().unwrap()
@@ -233,8 +262,9 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
                     self.block_index,
                     self.buf,
                     self.input.as_mut().unwrap(),
-                    self.last_nonce,
-                    self.opening_key
+                    self.opening_key,
+                    self.transform,
+                    self.holes
                 );
             }
             // seek inside new block
@@ -638,3 +638,60 @@ fn reader_with_seeks() {
     reader.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(reader.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn test_seek_back_decrypts_only_target_block() {
+    use std::io::{Cursor, Read, SeekFrom};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use crate::crypto;
+    use crate::crypto::read::BLOCK_SIZE;
+    use crate::crypto::Cipher;
+
+    struct CountingReader {
+        inner: Cursor<Vec<u8>>,
+        read: Arc<AtomicU64>,
+    }
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.inner.read(buf)?;
+            self.read.fetch_add(len as u64, Ordering::SeqCst);
+            Ok(len)
+        }
+    }
+    impl Seek for CountingReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let blocks = 100;
+    #[allow(clippy::cast_possible_truncation)]
+    let data = (0..BLOCK_SIZE * blocks)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let read = Arc::new(AtomicU64::new(0));
+    let mut reader = crypto::create_read_seek(
+        CountingReader {
+            inner: Cursor::new(create_encrypted_data(&data, &key)),
+            read: read.clone(),
+        },
+        cipher,
+        &key,
+    );
+
+    let mut buf = vec![0; 42];
+    // from the end backward and at random, only the block we read from is read from the input
+    for block in [blocks - 1, 50, 1, 73, 0, 72] {
+        let offset = block * BLOCK_SIZE + 7;
+        read.store(0, Ordering::SeqCst);
+        reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &data[offset..offset + buf.len()]);
+        assert!(read.load(Ordering::SeqCst) <= cipher.ciphertext_len(BLOCK_SIZE as u64));
+    }
+}
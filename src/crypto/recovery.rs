@@ -0,0 +1,73 @@
+use bip39::Mnemonic;
+use shush_rs::{ExposeSecret, SecretString, SecretVec, Zeroize};
+
+use crate::crypto::{Error, Result};
+
+/// Encode `key` as a [BIP39](https://github.com/bitcoin/bips/blob/master/bip-0039.mediawiki) mnemonic
+/// in English, 24 words for a 256 bits key.
+///
+/// The last word includes a checksum of the key, so typos are caught by [`from_phrase`].
+#[allow(clippy::missing_errors_doc)]
+pub fn to_phrase(key: &SecretVec<u8>) -> Result<SecretString> {
+    let mnemonic = Mnemonic::from_entropy(key.expose_secret().as_slice())
+        .map_err(|_| Error::Generic("invalid key length for recovery phrase"))?;
+    Ok(SecretString::new(Box::new(mnemonic.to_string())))
+}
+
+/// Decode the key from a phrase created by [`to_phrase`].
+///
+/// Words can be separated by any whitespace and are case-insensitive.
+#[allow(clippy::missing_errors_doc)]
+pub fn from_phrase(phrase: &SecretString) -> Result<SecretVec<u8>> {
+    let mut normalized = phrase
+        .expose_secret()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let mnemonic = Mnemonic::parse_normalized(&normalized);
+    normalized.zeroize();
+    let mnemonic = mnemonic.map_err(|err| match err {
+        bip39::Error::InvalidChecksum => Error::Generic("invalid recovery phrase checksum"),
+        _ => Error::Generic("invalid recovery phrase"),
+    })?;
+    Ok(SecretVec::new(Box::new(mnemonic.to_entropy())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phrase() {
+        let key = SecretVec::new(Box::new(vec![42; 32]));
+        let phrase = to_phrase(&key).unwrap();
+        let words: Vec<String> = phrase
+            .expose_secret()
+            .split(' ')
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(words.len(), 24);
+        let key2 = from_phrase(&phrase).unwrap();
+        assert_eq!(*key.expose_secret(), *key2.expose_secret());
+
+        // extra whitespace and uppercase
+        let messy = SecretString::new(Box::new(format!(" {}\n", words.join("  ").to_uppercase())));
+        assert_eq!(
+            *key.expose_secret(),
+            *from_phrase(&messy).unwrap().expose_secret()
+        );
+
+        // the checksum catches swapped words
+        let mut swapped = words.clone();
+        swapped.swap(0, 1);
+        assert_ne!(swapped, words);
+        assert!(matches!(
+            from_phrase(&SecretString::new(Box::new(swapped.join(" ")))),
+            Err(Error::Generic("invalid recovery phrase checksum"))
+        ));
+        assert!(from_phrase(&SecretString::new(Box::new(words[..23].join(" ")))).is_err());
+        assert!(from_phrase(&SecretString::new(Box::new("not a phrase".to_string()))).is_err());
+        assert!(to_phrase(&SecretVec::new(Box::new(vec![42; 7]))).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::io;
+
+use shush_rs::Zeroize;
+
+/// Id used in the block header when the block is stored as-is.
+pub const IDENTITY_TRANSFORM_ID: u8 = 0;
+
+/// Size of the header we add in front of each block when a transform is used, `id: u8` + `len: u32`.
+pub const FRAME_HEADER_LEN: usize = 5;
+
+/// Transforms the plaintext of each block before it's encrypted and after it's decrypted.
+///
+/// It can be used to plug in things like compression, deduplication, erasure coding or secret splitting.
+///
+/// # Framing
+///
+/// Each plaintext block (max [`BLOCK_SIZE`](crate::crypto::write::BLOCK_SIZE) bytes, only the last one can be smaller)
+/// is passed to [`ContentTransform::transform_write`] and the result is put in a frame which is then encrypted:
+///
+/// `[id: u8][len: u32 LE][transformed data: len bytes][zero padding]`
+///
+/// - the frame always has [`FRAME_HEADER_LEN`] + plaintext block length bytes, so the encrypted file
+///   keeps fixed size blocks and we can still seek and compute the plaintext size from the file length, which
+///   also means a transform which makes the data smaller, like compression, doesn't save space on disk
+/// - if the transformed data doesn't fit in the plaintext length, the block is stored as-is with [`IDENTITY_TRANSFORM_ID`]
+/// - on read [`ContentTransform::transform_read`] receives the transformed data and must return exactly
+///   the original plaintext block
+#[allow(clippy::module_name_repetitions)]
+pub trait ContentTransform: Send + Sync {
+    /// Stored in the header of each block and in the file metadata, so we know how to read it back.
+    /// [`IDENTITY_TRANSFORM_ID`] is reserved.
+    fn id(&self) -> u8;
+
+    #[allow(clippy::missing_errors_doc)]
+    fn transform_write(&self, block_index: u64, plaintext: &[u8]) -> io::Result<Vec<u8>>;
+
+    #[allow(clippy::missing_errors_doc)]
+    fn transform_read(&self, block_index: u64, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Keeps the data as it is, this is the default.
+pub struct IdentityTransform;
+
+impl ContentTransform for IdentityTransform {
+    fn id(&self) -> u8 {
+        IDENTITY_TRANSFORM_ID
+    }
+
+    fn transform_write(&self, _block_index: u64, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(plaintext.to_vec())
+    }
+
+    fn transform_read(&self, _block_index: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// Id of [`XorTransform`].
+#[cfg(test)]
+pub(crate) const XOR_TRANSFORM_ID: u8 = 1;
+
+/// Flips the bits of each byte, so tests can check the data goes through the transform.
+#[cfg(test)]
+pub(crate) struct XorTransform;
+
+#[cfg(test)]
+impl ContentTransform for XorTransform {
+    fn id(&self) -> u8 {
+        XOR_TRANSFORM_ID
+    }
+
+    fn transform_write(&self, _block_index: u64, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(plaintext.iter().map(|b| !b).collect())
+    }
+
+    fn transform_read(&self, _block_index: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(data.iter().map(|b| !b).collect())
+    }
+}
+
+/// Creates the frame for a plaintext block.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn encode_block(
+    transform: &dyn ContentTransform,
+    block_index: u64,
+    plaintext: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut transformed = transform.transform_write(block_index, plaintext)?;
+    let (id, data) =
+        if transform.id() != IDENTITY_TRANSFORM_ID && transformed.len() <= plaintext.len() {
+            (transform.id(), transformed.as_slice())
+        } else {
+            (IDENTITY_TRANSFORM_ID, plaintext)
+        };
+    let mut frame = vec![0; FRAME_HEADER_LEN + plaintext.len()];
+    frame[0] = id;
+    frame[1..FRAME_HEADER_LEN].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    frame[FRAME_HEADER_LEN..FRAME_HEADER_LEN + data.len()].copy_from_slice(data);
+    transformed.zeroize();
+    Ok(frame)
+}
+
+/// Extracts the plaintext block from a frame created by [`encode_block`].
+pub(crate) fn decode_block(
+    transform: &dyn ContentTransform,
+    block_index: u64,
+    frame: &[u8],
+) -> io::Result<Vec<u8>> {
+    if frame.len() < FRAME_HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block too small for frame header",
+        ));
+    }
+    let plaintext_len = frame.len() - FRAME_HEADER_LEN;
+    let id = frame[0];
+    let len = u32::from_le_bytes(frame[1..FRAME_HEADER_LEN].try_into().unwrap()) as usize;
+    if len > plaintext_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid length in frame header",
+        ));
+    }
+    let data = &frame[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len];
+    let plaintext = if id == IDENTITY_TRANSFORM_ID {
+        data.to_vec()
+    } else if id == transform.id() {
+        transform.transform_read(block_index, data)?
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("block was written with unknown transform {id}"),
+        ));
+    };
+    if plaintext.len() != plaintext_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "transformed block has a different size than the original one",
+        ));
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    use rand_core::RngCore;
+    use ring::aead::CHACHA20_POLY1305;
+    use shush_rs::SecretVec;
+
+    use super::*;
+    use crate::crypto;
+    use crate::crypto::read::RingCryptoRead;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+
+    fn key() -> SecretVec<u8> {
+        let mut key = vec![0; CHACHA20_POLY1305.key_len()];
+        crypto::create_rng().fill_bytes(&mut key);
+        SecretVec::new(Box::new(key))
+    }
+
+    /// Makes the data longer, so it doesn't fit in the frame.
+    struct GrowTransform;
+
+    impl ContentTransform for GrowTransform {
+        fn id(&self) -> u8 {
+            2
+        }
+
+        fn transform_write(&self, _block_index: u64, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+            let mut data = plaintext.to_vec();
+            data.push(0);
+            Ok(data)
+        }
+
+        fn transform_read(&self, _block_index: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(data[..data.len() - 1].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_block() {
+        let transform = XorTransform;
+        let plaintext = vec![42_u8; BLOCK_SIZE];
+        let frame = encode_block(&transform, 0, &plaintext).unwrap();
+        assert_eq!(frame.len(), FRAME_HEADER_LEN + plaintext.len());
+        assert_eq!(frame[0], XOR_TRANSFORM_ID);
+        assert_eq!(frame[FRAME_HEADER_LEN], !42);
+        assert_eq!(decode_block(&transform, 0, &frame).unwrap(), plaintext);
+
+        // what doesn't fit is stored as-is
+        let mut plaintext = vec![0_u8; BLOCK_SIZE];
+        crypto::create_rng().fill_bytes(&mut plaintext);
+        let frame = encode_block(&GrowTransform, 0, &plaintext).unwrap();
+        assert_eq!(frame[0], IDENTITY_TRANSFORM_ID);
+        assert_eq!(decode_block(&GrowTransform, 0, &frame).unwrap(), plaintext);
+
+        let frame = encode_block(&IdentityTransform, 0, b"test-42").unwrap();
+        assert_eq!(frame[0], IDENTITY_TRANSFORM_ID);
+        assert_eq!(decode_block(&transform, 0, &frame).unwrap(), b"test-42");
+    }
+
+    #[test]
+    fn test_decode_block_unknown_transform() {
+        let frame = encode_block(&XorTransform, 0, &[42_u8; BLOCK_SIZE]).unwrap();
+        assert!(decode_block(&IdentityTransform, 0, &frame).is_err());
+    }
+
+    #[test]
+    fn test_write_read_with_transform() {
+        let key = key();
+        let transforms: Vec<Arc<dyn ContentTransform>> =
+            vec![Arc::new(IdentityTransform), Arc::new(XorTransform)];
+        for transform in transforms {
+            let data = b"test-42".repeat(BLOCK_SIZE);
+            let mut writer =
+                RingCryptoWrite::new(Cursor::new(vec![]), true, &CHACHA20_POLY1305, &key)
+                    .with_transform(transform.clone());
+            writer.write_all(&data).unwrap();
+            let cursor = writer.finish().unwrap();
+
+            let mut reader = RingCryptoRead::new_seek(cursor, &CHACHA20_POLY1305, &key)
+                .with_transform(transform.clone());
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, data);
+
+            // seek in the middle of a block
+            let pos = reader.seek(SeekFrom::Start(BLOCK_SIZE as u64 + 3)).unwrap();
+            assert_eq!(pos, BLOCK_SIZE as u64 + 3);
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, &data[BLOCK_SIZE + 3..BLOCK_SIZE + 7]);
+            assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+        }
+    }
+}
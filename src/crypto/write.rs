@@ -5,15 +5,16 @@ use std::sync::{Arc, Mutex};
 
 use bytes::Buf;
 use rand_chacha::rand_core::RngCore;
-use ring::aead::{
-    Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, NONCE_LEN,
-};
+use ring::aead::{Algorithm, NONCE_LEN};
 use ring::error::Unspecified;
-use shush_rs::{ExposeSecret, SecretVec};
+use shush_rs::SecretVec;
 use tracing::error;
 
 use crate::crypto::buf_mut::BufMut;
-use crate::crypto::read::ExistingNonceSequence;
+use crate::crypto::holes::HoleMap;
+use crate::crypto::nonce::NonceCounter;
+use crate::crypto::transform::{ContentTransform, FRAME_HEADER_LEN};
+use crate::crypto::{BlockKey, Cipher};
 use crate::{crypto, decrypt_block, stream_util};
 
 mod bench;
@@ -69,65 +70,122 @@ pub trait CryptoWriteSeek<W: CryptoInnerWriter + Send + Sync>: CryptoWrite<W> +
 pub struct RingCryptoWrite<W: CryptoInnerWriter + Send + Sync> {
     writer: Option<W>,
     seek: bool,
-    sealing_key: SealingKey<RandomNonceSequenceWrapper>,
+    sealing_key: BlockKey,
     buf: BufMut,
-    nonce_sequence: Arc<Mutex<RandomNonceSequence>>,
+    nonce_sequence: BlockNonceSequence,
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
-    opening_key: Option<OpeningKey<ExistingNonceSequence>>,
-    last_nonce: Option<Arc<Mutex<Option<Vec<u8>>>>>,
+    opening_key: Option<BlockKey>,
     decrypt_buf: Option<BufMut>,
+    transform: Option<Arc<dyn ContentTransform>>,
+    holes: Option<Arc<HoleMap>>,
+    sparse: bool,
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::needless_pass_by_value)]
-    pub fn new(
-        mut writer: W,
-        seek: bool,
-        algorithm: &'static Algorithm,
-        key: &SecretVec<u8>,
-    ) -> Self {
-        let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).expect("unbound key");
-        let nonce_sequence = Arc::new(Mutex::new(RandomNonceSequence::default()));
-        let wrapping_nonce_sequence = RandomNonceSequenceWrapper::new(nonce_sequence.clone());
-        let sealing_key = SealingKey::new(unbound_key, wrapping_nonce_sequence);
+    pub fn new(writer: W, seek: bool, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
+        Self::with_keys(writer, seek, || {
+            BlockKey::ring(algorithm, key).expect("unbound key")
+        })
+    }
+
+    /// Like [`RingCryptoWrite::new`], but with any [`Cipher`], also the ones not implemented by `ring`.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn with_cipher(writer: W, seek: bool, cipher: Cipher, key: &SecretVec<u8>) -> Self {
+        Self::with_keys(writer, seek, || {
+            BlockKey::new(cipher, key).expect("unbound key")
+        })
+    }
+
+    fn with_keys(mut writer: W, seek: bool, new_key: impl Fn() -> BlockKey) -> Self {
+        let sealing_key = new_key();
         let buf = BufMut::new(vec![0; BLOCK_SIZE]);
+        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + sealing_key.tag_len();
 
-        let (last_nonce, opening_key, decrypt_buf) = if writer.as_write_seek_read().is_some() {
-            let last_nonce = Arc::new(Mutex::new(None));
-            let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).unwrap();
-            let nonce_sequence2 = ExistingNonceSequence::new(last_nonce.clone());
-            let opening_key = OpeningKey::new(unbound_key, nonce_sequence2);
-            let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
+        let (opening_key, decrypt_buf) = if writer.as_write_seek_read().is_some() {
             let decrypt_buf = BufMut::new(vec![0; ciphertext_block_size]);
 
-            (Some(last_nonce), Some(opening_key), Some(decrypt_buf))
+            (Some(new_key()), Some(decrypt_buf))
         } else {
-            (None, None, None)
+            (None, None)
         };
         Self {
             writer: Some(writer),
             seek,
             sealing_key,
             buf,
-            nonce_sequence,
-            ciphertext_block_size: NONCE_LEN + BLOCK_SIZE + algorithm.tag_len(),
+            nonce_sequence: BlockNonceSequence::default(),
+            ciphertext_block_size,
             plaintext_block_size: BLOCK_SIZE,
             block_index: 0,
             opening_key,
-            last_nonce,
             decrypt_buf,
+            transform: None,
+            holes: None,
+            sparse: false,
         }
     }
 
+    /// Applies `transform` on each block before encrypting it, see [`ContentTransform`] for the framing.
+    #[must_use]
+    pub fn with_transform(mut self, transform: Arc<dyn ContentTransform>) -> Self {
+        self.ciphertext_block_size += FRAME_HEADER_LEN;
+        if self.decrypt_buf.is_some() {
+            self.decrypt_buf = Some(BufMut::new(vec![0; self.ciphertext_block_size]));
+        }
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Read the all zeros blocks in `holes` as zeros, see [`HoleMap`].
+    ///
+    /// With `sparse`, seeking after the end leaves the whole blocks in between as holes and adds them to `holes`,
+    /// instead of writing zeros.
+    #[must_use]
+    pub fn with_holes(mut self, holes: Arc<HoleMap>, sparse: bool) -> Self {
+        self.holes = Some(holes);
+        self.sparse = sparse;
+        self
+    }
+
+    /// Take the nonces from `counter` instead of generating random ones, see [`NonceStrategy`](crate::crypto::nonce::NonceStrategy).
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn with_nonce_counter(mut self, counter: Arc<NonceCounter>) -> Self {
+        self.nonce_sequence.counter = Some(counter);
+        self
+    }
+
     fn encrypt_and_write(&mut self) -> io::Result<()> {
-        let data = self.buf.as_mut();
-        let aad = Aad::from(self.block_index.to_le_bytes());
+        // frame is encrypted in place, so it holds no plaintext after sealing
+        let mut frame = self
+            .transform
+            .as_ref()
+            .map(|transform| {
+                crypto::transform::encode_block(
+                    transform.as_ref(),
+                    self.block_index,
+                    self.buf.as_ref(),
+                )
+            })
+            .transpose()?;
+        let data = match frame.as_mut() {
+            Some(frame) => frame.as_mut_slice(),
+            None => self.buf.as_mut(),
+        };
+        let nonce = self.nonce_sequence.advance().map_err(|err| {
+            error!("error getting next nonce: {}", err);
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("error getting next nonce: {err}"),
+            )
+        })?;
         let tag = self
             .sealing_key
-            .seal_in_place_separate_tag(aad, data)
+            .seal_in_place_separate_tag(nonce, self.block_index, data)
             .map_err(|err| {
                 error!("error sealing in place: {}", err);
                 io::Error::new(
@@ -135,8 +193,6 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
                     format!("error sealing in place: {err}"),
                 )
             })?;
-        let nonce_sequence = self.nonce_sequence.lock().unwrap();
-        let nonce = &nonce_sequence.last_nonce;
         let writer = self
             .writer
             .as_mut()
@@ -144,8 +200,12 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
         writer.write_all(nonce)?;
         writer.write_all(data)?;
         self.buf.clear();
-        writer.write_all(tag.as_ref())?;
+        writer.write_all(&tag)?;
         writer.flush()?;
+        if let Some(holes) = &self.holes {
+            // not a hole anymore, its zeros on disk must not be accepted
+            holes.remove(self.block_index);
+        }
         self.block_index += 1;
         Ok(())
     }
@@ -169,8 +229,9 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             self.block_index,
             self.decrypt_buf.as_mut().unwrap(),
             writer,
-            self.last_nonce.as_ref().unwrap(),
-            self.opening_key.as_mut().unwrap()
+            self.opening_key.as_ref().unwrap(),
+            self.transform,
+            self.holes
         );
         if old_block_index == self.block_index {
             // no decryption happened
@@ -207,6 +268,42 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             Ok(true)
         }
     }
+
+    /// Skip the whole blocks after the end until `new_pos`, leaving them as holes, see [`HoleMap`].
+    fn skip_holes(&mut self, new_pos: u64) -> io::Result<()> {
+        let Some(holes) = self.holes.clone() else {
+            return Ok(());
+        };
+        let block_len = self.plaintext_block_size as u64;
+        let start = self.pos().div_ceil(block_len);
+        // the block with the last zero is written, so the file has the new length
+        let end = (new_pos - 1) / block_len;
+        if start >= end {
+            return Ok(());
+        }
+        // complete the current block
+        let len = start * block_len - self.pos();
+        if len > 0 {
+            stream_util::fill_zeros(self, len)?;
+        }
+        if self.buf.is_dirty() {
+            self.encrypt_and_write()?;
+        }
+        self.buf.clear();
+        holes.insert(start, end);
+        self.block_index = end;
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+            .as_write_seek_read()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?;
+        writer.seek(SeekFrom::Start(end * self.ciphertext_block_size as u64))?;
+        Ok(())
+    }
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
@@ -288,41 +385,36 @@ impl<W: CryptoInnerWriter + Send + Sync> CryptoWrite<W> for RingCryptoWrite<W> {
     }
 }
 
-struct RandomNonceSequence {
+struct BlockNonceSequence {
     rng: Mutex<Box<dyn RngCore + Send + Sync>>,
+    counter: Option<Arc<NonceCounter>>,
     last_nonce: Vec<u8>,
 }
 
-impl Default for RandomNonceSequence {
+impl Default for BlockNonceSequence {
     fn default() -> Self {
         Self {
             rng: Mutex::new(Box::new(crypto::create_rng())),
+            counter: None,
             last_nonce: vec![0; NONCE_LEN],
         }
     }
 }
 
-impl NonceSequence for RandomNonceSequence {
+impl BlockNonceSequence {
     // called once for each seal operation
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        self.rng.lock().unwrap().fill_bytes(&mut self.last_nonce);
-        Nonce::try_assume_unique_for_key(&self.last_nonce)
-    }
-}
-
-struct RandomNonceSequenceWrapper {
-    inner: Arc<Mutex<RandomNonceSequence>>,
-}
-
-impl RandomNonceSequenceWrapper {
-    pub const fn new(inner: Arc<Mutex<RandomNonceSequence>>) -> Self {
-        Self { inner }
-    }
-}
-
-impl NonceSequence for RandomNonceSequenceWrapper {
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        self.inner.lock().unwrap().advance()
+    fn advance(&mut self) -> Result<&[u8], Unspecified> {
+        match &self.counter {
+            Some(counter) => {
+                let nonce = counter.next().map_err(|err| {
+                    error!(err = %err, "getting next nonce");
+                    Unspecified
+                })?;
+                self.last_nonce.copy_from_slice(&nonce);
+            }
+            None => self.rng.lock().unwrap().fill_bytes(&mut self.last_nonce),
+        }
+        Ok(&self.last_nonce)
     }
 }
 
@@ -448,6 +540,9 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
         }
         // if we couldn't seek until new pos, write zeros until new position
         if self.pos() < new_pos {
+            if self.sparse {
+                self.skip_holes(new_pos)?;
+            }
             let len = new_pos - self.pos();
             stream_util::fill_zeros(self, len)?;
         }
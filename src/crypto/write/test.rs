@@ -1,13 +1,12 @@
 use std::io::{self, Seek, SeekFrom};
 
-use ring::aead::{Aad, Algorithm, BoundKey, OpeningKey, UnboundKey, NONCE_LEN};
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
 use shush_rs::{ExposeSecret, SecretVec};
-use std::sync::{Arc, Mutex};
 #[allow(unused_imports)]
 use tracing_test::traced_test;
 
 use crate::crypto;
-use crate::crypto::read::{CryptoRead, ExistingNonceSequence};
+use crate::crypto::read::CryptoRead;
 use crate::crypto::Cipher;
 
 #[allow(dead_code)]
@@ -33,14 +32,14 @@ fn verify_encryption(
 
     let key_bytes = &key.expose_secret();
     let unbound_key = UnboundKey::new(algorithm, key_bytes).unwrap();
-    let nonce_sequence = ExistingNonceSequence::new(Arc::new(Mutex::new(Some(nonce.to_vec()))));
-    let mut opening_key = OpeningKey::new(unbound_key, nonce_sequence);
+    let opening_key = LessSafeKey::new(unbound_key);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
 
     let mut decrypted = encrypted[NONCE_LEN..].to_vec();
 
     let block_index: u64 = 0;
     let aad = Aad::from(block_index.to_le_bytes());
-    matches!(opening_key.open_in_place(aad, &mut decrypted), Ok(decrypted_data) if decrypted_data == plaintext)
+    matches!(opening_key.open_in_place(nonce, aad, &mut decrypted), Ok(decrypted_data) if decrypted_data == plaintext)
 }
 
 #[test]
@@ -1,52 +1,131 @@
 use argon2::password_hash::rand_core::RngCore;
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use bytes::Bytes;
+use futures_util::stream::{self, Stream};
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
 use serde::{Deserialize, Serialize};
-use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
+use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec, Zeroize};
 use std::backtrace::Backtrace;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::fs::{DirEntry, File, OpenOptions, ReadDir};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroUsize, ParseIntError};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, LazyLock, Weak};
-use std::time::{Duration, SystemTime};
-use std::{fs, io};
+use std::time::{Duration, Instant, SystemTime};
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumString};
 use thiserror::Error;
-use tokio::runtime::Runtime;
-use tokio::sync::{Mutex, RwLock};
-use tokio::task::{JoinError, JoinSet};
-use tokio_stream::wrappers::ReadDirStream;
+use tokio::runtime::{Runtime, RuntimeFlavor};
+use tokio::sync::{Mutex, Notify, RwLock, Semaphore};
+use tokio::task::{self, JoinError, JoinSet};
 use tracing::{debug, error, info, instrument, warn, Level};
 
 use crate::arc_hashmap::ArcHashMap;
+use crate::block_cache::BlockCache;
+use crate::crypto::escrow;
+use crate::crypto::holes::HoleMap;
+use crate::crypto::nonce::{NonceCounter, NonceStrategy};
 use crate::crypto::read::{CryptoRead, CryptoReadSeek};
-use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek};
-use crate::crypto::Cipher;
+use crate::crypto::transform::{ContentTransform, FRAME_HEADER_LEN};
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
+use crate::crypto::{Cipher, KdfParams};
+use crate::encryptedfs::async_io::{FileReader, FileWriter};
+use crate::encryptedfs::cipher_tags::CipherTags;
+use crate::encryptedfs::ino_counter::InoCounter;
+use crate::encryptedfs::inode_store::InodeStore;
+use crate::encryptedfs::quota::Quota;
+use crate::encryptedfs::storage::{FileStorage, Storage, StorageFile};
+use crate::encryptedfs::wal::{Journal, JournaledFile};
 use crate::expire_value::{ExpireValue, ValueProvider};
-use crate::{crypto, fs_util, stream_util};
+use crate::{bincode_util, crypto, stream_util};
 use bon::bon;
 
+pub mod async_io;
 mod bench;
+mod cipher_tags;
+mod ino_counter;
+mod inode_store;
+mod quota;
+pub mod storage;
 #[cfg(test)]
 mod test;
+mod wal;
 
 pub(crate) const INODES_DIR: &str = "inodes";
 pub(crate) const CONTENTS_DIR: &str = "contents";
+/// Suffix of the file next to the content file which keeps the [`ContentTransform`] id.
+pub(crate) const CONTENT_TRANSFORM_SUFFIX: &str = ".transform";
+/// Suffix of the file next to the content file which keeps the [`HoleMap`] of sparse files.
+pub(crate) const CONTENT_HOLES_SUFFIX: &str = ".holes";
 pub(crate) const SECURITY_DIR: &str = "security";
+/// Optional, created with the first snapshot.
+pub(crate) const SNAPSHOTS_DIR: &str = "snapshots";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+/// Keeps the [`KdfParams`] the key is derived with, missing for volumes created before they were saved.
+pub(crate) const KDF_PARAMS_FILENAME: &str = "kdf_params";
+/// Keeps the cipher of the volume.
+pub(crate) const CIPHER_FILENAME: &str = "cipher";
+/// Keeps the cipher we migrate to while [`EncryptedFs::migrate_cipher`] is in progress.
+pub(crate) const CIPHER_MIGRATION_FILENAME: &str = "cipher_migration";
+/// Files already migrated to the new cipher.
+pub(crate) const CIPHER_MIGRATION_JOURNAL_FILENAME: &str = "cipher_migration.journal";
+/// Keeps the new key, encrypted like `key.enc`, while [`EncryptedFs::rotate_data_key`] is in progress.
+pub(crate) const KEY_ROTATION_FILENAME: &str = "key_rotation.enc";
+/// Keeps the state of [`NonceStrategy::Counter`].
+pub(crate) const NONCE_COUNTER_FILENAME: &str = "nonce_counter";
+/// Keeps the [`VolumeFormat`], missing for volumes created before it was kept.
+pub(crate) const FORMAT_FILENAME: &str = "format";
+/// Keeps the state of the numbers of new inodes, missing for volumes which didn't create any since it was added.
+pub(crate) const INO_COUNTER_FILENAME: &str = "ino_counter";
 
 pub(crate) const LS_DIR: &str = "ls";
 pub(crate) const HASH_DIR: &str = "hash";
+/// Keeps the extended attributes of each inode which has any, missing for volumes created before we supported them.
+pub(crate) const XATTR_DIR: &str = "xattr";
+/// Keeps the journals of the files with changes not yet committed, see [`wal`].
+pub(crate) const WAL_DIR: &str = "wal";
+/// Keeps the contents shared by files with the same content, see [`FsConfig::dedup`].
+/// Created with the first shared content.
+pub(crate) const CONTENTS_REFS_DIR: &str = "contents-refs";
+/// Suffix of the file next to a shared content which keeps the [`FileKey`] it's encrypted with, missing for the ones
+/// shared before files had their own key.
+pub(crate) const CONTENT_REF_KEY_SUFFIX: &str = ".key";
+
+/// Max length (in bytes) of the name of an extended attribute, the same as `XATTR_NAME_MAX` on Linux.
+pub const XATTR_NAME_MAX_LEN: usize = 255;
+/// Max length (in bytes) of the value of an extended attribute, the same as `XATTR_SIZE_MAX` on Linux.
+pub const XATTR_VALUE_MAX_LEN: usize = 64 * 1024;
+/// The extended attributes of an inode are padded to a multiple of this, so the size of the file
+/// doesn't show how many there are.
+const XATTR_PADDING: usize = 4096;
+/// Page size used to zero the tail of a read which ends at the end of the file, see [`EncryptedFs::read`].
+const PAGE_SIZE: u64 = 4096;
 
 pub(crate) const ROOT_INODE: u64 = 1;
 
+/// How much a stored time can be in the future before we consider the system clock jumped backward.
+pub(crate) const CLOCK_SKEW_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// The file cannot be changed, removed or renamed, like `chattr +i`. Same value as in `linux/fs.h`.
+///
+/// The flags are set with [`EncryptedFs::set_attr`] and on macOS with `chflags`. On Linux `chattr` and `lsattr` don't
+/// work on the mount, as `fuse3` doesn't pass `FUSE_IOCTL` to the filesystem, so we can't handle `FS_IOC_SETFLAGS`.
+pub const FS_IMMUTABLE_FL: u32 = 0x0000_0010;
+/// The file can only be appended and cannot be truncated, removed or renamed, like `chattr +a`.
+/// Same value as in `linux/fs.h`.
+pub const FS_APPEND_FL: u32 = 0x0000_0020;
+
+/// Allocate space without changing the size of the file, see [`EncryptedFs::fallocate`].
+/// Same value as in `linux/falloc.h`.
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+
 fn spawn_runtime() -> Runtime {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -65,7 +144,8 @@ pub struct FileAttr {
     pub ino: u64,
     /// Size in bytes
     pub size: u64,
-    /// Size in blocks
+    /// Size on disk in 512 bytes units, like `st_blocks`, estimated with [`Cipher::ciphertext_len`], for files with
+    /// holes it's what the content takes on disk, see [`FsConfig::sparse`]
     pub blocks: u64,
     /// Time of last access
     pub atime: SystemTime,
@@ -87,10 +167,157 @@ pub struct FileAttr {
     pub gid: u32,
     /// Rdev
     pub rdev: u32,
-    /// Block size
+    /// Preferred size for I/O, like `st_blksize`, it's [`Cipher::recommended_io_size`]
     pub blksize: u32,
-    /// Flags (macOS only, see chflags(2))
+    /// Flags, see chflags(2) on macOS. On Linux we support [`FS_IMMUTABLE_FL`] and [`FS_APPEND_FL`]
     pub flags: u32,
+    /// MAC of the content of a regular file, updated on each flush, see [`EncryptedFs::verify_file`].
+    /// Missing for files from before it was kept which weren't changed since, those fail the verification
+    pub content_mac: Option<[u8; 32]>,
+    /// Key the content of a regular file or symlink is encrypted with, the inode is encrypted with the volume key.
+    /// Missing for files created before each file had its own key, they use the volume key
+    pub file_key: Option<FileKey>,
+    /// Hash of the [`HoleMap`] of a sparse file, saved with it, so the map is used only with the file and the
+    /// version it was saved for. Missing for files without holes, which includes all the files from before the inode
+    /// had a version, as there were no sparse files then
+    pub holes_mac: Option<[u8; 32]>,
+}
+
+impl FileAttr {
+    #[must_use]
+    pub const fn is_immutable(&self) -> bool {
+        self.flags & FS_IMMUTABLE_FL != 0
+    }
+
+    #[must_use]
+    pub const fn is_append_only(&self) -> bool {
+        self.flags & FS_APPEND_FL != 0
+    }
+}
+
+/// Random key of the content of a file, see [`FileAttr::file_key`].
+///
+/// As it's kept in the inode, changing the password or rotating the volume key doesn't need to re-encrypt the contents.
+/// It's not shown by `Debug`, so it doesn't end up in logs.
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileKey([u8; 32]);
+
+impl FileKey {
+    fn generate() -> Self {
+        let mut key = [0; 32];
+        crypto::create_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+}
+
+impl Debug for FileKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FileKey(..)")
+    }
+}
+
+/// Hash of the ranges of the [`HoleMap`] of the file `ino`, see [`FileAttr::holes_mac`]. It's kept in the inode,
+/// which is encrypted and authenticated, so it doesn't need a key.
+fn holes_mac(ino: u64, ranges: &[(u64, u64)]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&ino.to_le_bytes());
+    for (start, end) in ranges {
+        hasher.update(&start.to_le_bytes());
+        hasher.update(&end.to_le_bytes());
+    }
+    *hasher.finalize().as_bytes()
+}
+
+/// Zero `buf` after the `len` bytes read from `offset`, up to the end of the page holding the end of the file, if the
+/// read ended at the end of the file which is `size` long.
+fn zero_last_page(buf: &mut [u8], offset: u64, len: usize, size: u64) {
+    if offset >= size || offset + len as u64 != size {
+        return;
+    }
+    let page_end = size.next_multiple_of(PAGE_SIZE);
+    let end = buf
+        .len()
+        .min(usize::try_from(page_end - offset).unwrap_or(usize::MAX));
+    if len < end {
+        buf[len..end].fill(0);
+    }
+}
+
+/// The key the content of a file is encrypted with, its own one or the volume `key` for files created before they
+/// had one.
+fn content_key(file_key: Option<FileKey>, key: &SecretVec<u8>) -> SecretVec<u8> {
+    let key = file_key.map_or_else(
+        || key.expose_secret().clone(),
+        |file_key| file_key.0.to_vec(),
+    );
+    SecretVec::new(Box::new(key))
+}
+
+/// Unix permission check of a user with `uid` and `gid` against the mode, owner and group of a file, like
+/// `access(2)`, see [`EncryptedFs::check_access`].
+///
+/// `mask` is a combination of 4 read, 2 write and 1 execute, 0 only checks that it exists. Root can read and write
+/// anything, and execute only if one of the execute bits is set. Supplementary groups are not checked.
+#[must_use]
+#[allow(clippy::similar_names)]
+pub const fn access_allowed(
+    file_uid: u32,
+    file_gid: u32,
+    file_mode: u16,
+    uid: u32,
+    gid: u32,
+    mask: u32,
+) -> bool {
+    let mode = file_mode as u32;
+    if uid == 0 {
+        return mask & 0o1 == 0 || mode & 0o111 != 0;
+    }
+    let allowed = if uid == file_uid {
+        mode >> 6
+    } else if gid == file_gid {
+        mode >> 3
+    } else {
+        mode
+    };
+    mask & 0o7 & !allowed == 0
+}
+
+/// Version of the layout of file contents, a sequence of `[nonce][ciphertext][tag]` blocks.
+/// When a [`ContentTransform`] is used the plaintext of each block is framed as described there.
+/// Each block is sealed on its own, so a write anywhere in the file re-encrypts only the blocks it touches.
+pub const CONTENT_FORMAT_VERSION: u8 = 1;
+
+/// How [`EncryptedFs::set_xattr`] handles an existing attribute, like the `flags` of `setxattr(2)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum XattrMode {
+    /// Create it or replace the value if it exists.
+    #[default]
+    Set,
+    /// Fail with [`FsError::AlreadyExists`] if it exists, like `XATTR_CREATE`.
+    Create,
+    /// Fail with [`FsError::XattrNotFound`] if it doesn't exist, like `XATTR_REPLACE`.
+    Replace,
+}
+
+/// How a file is stored, see [`EncryptedFs::file_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Inode number
+    pub ino: u64,
+    /// Cipher used to encrypt the content
+    pub cipher: Cipher,
+    /// Layout of the content, see [`CONTENT_FORMAT_VERSION`]
+    pub format_version: u8,
+    /// Id of the [`ContentTransform`] the file was created with, `None` if blocks are stored as they are
+    pub content_transform: Option<u8>,
+    /// Size of the plaintext in bytes
+    pub size: u64,
+    /// Size of the encrypted content on disk in bytes
+    pub disk_size: u64,
+    /// Number of encrypted blocks
+    pub blocks: u64,
+    /// The encrypted content has holes on disk
+    pub sparse: bool,
 }
 
 /// File types.
@@ -106,8 +333,8 @@ pub enum FileType {
     Directory,
     /// Regular file (`S_IFREG`)
     RegularFile,
-    // /// Symbolic link (S_IFLNK)
-    // Symlink,
+    /// Symbolic link (`S_IFLNK`), the target is kept encrypted in the content
+    Symlink,
     // /// Unix domain socket (S_IFSOCK)
     // Socket,
 }
@@ -132,7 +359,7 @@ pub struct SetFileAttr {
     pub gid: Option<u32>,
     /// Rdev
     pub rdev: Option<u32>,
-    /// Flags (macOS only, see chflags(2))
+    /// Flags, see [`FileAttr::flags`]
     pub flags: Option<u32>,
 }
 
@@ -193,7 +420,7 @@ impl SetFileAttr {
 
     #[must_use]
     pub const fn with_flags(mut self, flags: u32) -> Self {
-        self.rdev = Some(flags);
+        self.flags = Some(flags);
         self
     }
 }
@@ -235,8 +462,12 @@ impl From<CreateFileAttr> for FileAttr {
             uid: value.uid,
             gid: value.gid,
             rdev: value.rdev,
-            blksize: 0,
+            #[allow(clippy::cast_possible_truncation)]
+            blksize: BLOCK_SIZE as u32,
             flags: value.flags,
+            content_mac: None,
+            file_key: None,
+            holes_mac: None,
         }
     }
 }
@@ -245,13 +476,11 @@ impl From<CreateFileAttr> for FileAttr {
 pub enum FsError {
     #[error("IO error: {source}")]
     Io {
-        #[from]
         source: io::Error,
         backtrace: Backtrace,
     },
     #[error("serialize error: {source}")]
     SerializeError {
-        #[from]
         source: bincode::Error,
         backtrace: Backtrace,
     },
@@ -275,11 +504,26 @@ pub enum FsError {
     Other(&'static str),
     #[error("invalid password")]
     InvalidPassword,
+    #[error("extended attribute not found")]
+    XattrNotFound,
+    #[error("extended attribute is too big")]
+    XattrTooBig,
+    #[error("invalid recovery phrase")]
+    InvalidRecoveryPhrase,
+    #[error("password is too weak, it needs at least {min_len} characters")]
+    WeakPassword { min_len: usize },
+    #[error("volume is encrypted with {volume}, not {requested}")]
+    CipherMismatch { volume: Cipher, requested: Cipher },
     #[error("invalid structure of data directory")]
     InvalidDataDirStructure,
+    /// The filesystem of the data dir doesn't allow the names of our files, checked when it's opened read-write.
+    #[error(
+        "the filesystem of the data directory doesn't allow '|' in file names, like FAT, exFAT or NTFS, use one like \
+         ext4, btrfs, XFS or APFS, existing data can still be opened read-only to copy it"
+    )]
+    UnsupportedDataDirFs,
     #[error("crypto error: {source}")]
     Crypto {
-        #[from]
         source: crypto::Error,
         backtrace: Backtrace,
     },
@@ -305,6 +549,100 @@ pub enum FsError {
     MaxFilesizeExceeded(usize),
     #[error("Read only mode is active.")]
     ReadOnly,
+    #[error("operation not permitted, file is immutable or append-only")]
+    NotPermitted,
+    /// The mode of the file doesn't allow it to the user, see [`EncryptedFs::check_access`].
+    #[error("permission denied")]
+    AccessDenied,
+    #[error("mount point is already mounted")]
+    AlreadyMounted,
+    /// The disk of the data dir is full, or the user's quota on it is exceeded.
+    #[error("no space left on device")]
+    NoSpace,
+    /// The files would use more than the limit set with [`FsConfig::quota`].
+    #[error("disk quota exceeded")]
+    QuotaExceeded,
+    #[error("not supported: {0}")]
+    NotSupported(&'static str),
+    /// The content of a file doesn't match the MAC in its inode, blocks were removed or replaced.
+    #[error("integrity check failed")]
+    IntegrityCheckFailed,
+    /// What [`EncryptedFs::self_test`] read back doesn't match what it wrote.
+    #[error("self-test failed: {0}")]
+    SelfTestFailed(String),
+    /// The data is re-encrypted by [`EncryptedFs::rotate_data_key`], or it was interrupted and the volume needs
+    /// to be opened read-write to finish it.
+    #[error("key rotation in progress")]
+    KeyRotationInProgress,
+}
+
+impl FsError {
+    /// If the content could not be decrypted, or is shorter than the file size, like when it was changed outside
+    /// the filesystem, see [`FsObserver::on_decrypt_failure`].
+    fn is_decrypt_failure(&self) -> bool {
+        match self {
+            Self::IntegrityCheckFailed => true,
+            Self::Io { source, .. }
+            | Self::Crypto {
+                source: crypto::Error::Io { source },
+                ..
+            } => source.kind() == io::ErrorKind::InvalidData,
+            _ => false,
+        }
+    }
+}
+
+// IO errors when the disk is full are mapped to `NoSpace`, wherever they come from,
+// so they can be reported to the user as such
+
+impl From<io::Error> for FsError {
+    fn from(source: io::Error) -> Self {
+        if is_no_space(&source) {
+            return Self::NoSpace;
+        }
+        Self::Io {
+            source,
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
+
+impl From<bincode::Error> for FsError {
+    fn from(source: bincode::Error) -> Self {
+        if let bincode::ErrorKind::Io(err) = &*source {
+            if is_no_space(err) {
+                return Self::NoSpace;
+            }
+        }
+        Self::SerializeError {
+            source,
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
+
+impl From<crypto::Error> for FsError {
+    fn from(source: crypto::Error) -> Self {
+        if let crypto::Error::Io { source: err } = &source {
+            if is_no_space(err) {
+                return Self::NoSpace;
+            }
+        }
+        Self::Crypto {
+            source,
+            backtrace: Backtrace::capture(),
+        }
+    }
+}
+
+fn is_no_space(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    ) || err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<FsError>())
+        .is_some_and(|err| matches!(err, FsError::NoSpace))
 }
 
 #[derive(Debug, Clone)]
@@ -463,7 +801,90 @@ impl Iterator for DirectoryEntryPlusIterator {
 struct ReadHandleContext {
     ino: u64,
     attr: TimesFileAttr,
-    reader: Option<Box<dyn CryptoReadSeek<File>>>,
+    reader: Option<Box<dyn CryptoReadSeek<Box<dyn StorageFile>>>>,
+    readahead: Readahead,
+}
+
+impl ReadHandleContext {
+    /// Replace the reader, what was read ahead with the old one might be stale so it's dropped.
+    fn set_reader(&mut self, reader: Box<dyn CryptoReadSeek<Box<dyn StorageFile>>>) {
+        self.reader = Some(reader);
+        self.readahead.buf = ReadaheadBuf::default();
+    }
+}
+
+type ReadaheadTask = task::JoinHandle<(
+    Box<dyn CryptoReadSeek<Box<dyn StorageFile>>>,
+    io::Result<ReadaheadBuf>,
+)>;
+
+/// Content decrypted ahead of sequential reads, see [`FsConfig::readahead_blocks`].
+#[derive(Default)]
+struct Readahead {
+    // where the last read ended, a read starting here is sequential
+    next_offset: u64,
+    buf: ReadaheadBuf,
+    // the read in flight, it has the reader of the handle until it's collected
+    task: Option<ReadaheadTask>,
+}
+
+/// Decrypted content from `start`, zeroized on drop.
+#[derive(Default)]
+struct ReadaheadBuf {
+    start: u64,
+    data: Vec<u8>,
+}
+
+impl ReadaheadBuf {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Copy what we have from `offset` to `buf`, returns how many bytes were copied.
+    #[allow(clippy::cast_possible_truncation)]
+    fn copy_to(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset < self.start || offset >= self.end() {
+            return 0;
+        }
+        let from = (offset - self.start) as usize;
+        let len = (self.data.len() - from).min(buf.len());
+        buf[..len].copy_from_slice(&self.data[from..from + len]);
+        len
+    }
+
+    /// Drop the content before `offset`, it was already read.
+    #[allow(clippy::cast_possible_truncation)]
+    fn consume(&mut self, offset: u64) {
+        if offset >= self.end() {
+            self.data.zeroize();
+        } else if offset > self.start {
+            self.data.drain(..(offset - self.start) as usize);
+        } else {
+            return;
+        }
+        self.start = offset;
+    }
+
+    /// Add `other` if it continues our content, else it's dropped.
+    fn append(&mut self, other: Self) {
+        if other.start != self.end() {
+            return;
+        }
+        if self.data.capacity() - self.data.len() < other.data.len() {
+            // don't leave a copy of the content in the old allocation
+            let mut data = Vec::with_capacity(self.data.len() + other.data.len());
+            data.extend_from_slice(&self.data);
+            self.data.zeroize();
+            self.data = data;
+        }
+        self.data.extend_from_slice(&other.data);
+    }
+}
+
+impl Drop for ReadaheadBuf {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
 }
 
 enum ReadHandleContextOperation {
@@ -493,14 +914,108 @@ impl WriteHandleContextOperation {
 struct WriteHandleContext {
     ino: u64,
     attr: TimesAndSizeFileAttr,
-    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    writer: Option<Box<dyn CryptoWriteSeek<JournaledFile>>>,
+    // the writer might keep written data in its buffer, not yet in the file
+    dirty: bool,
 }
 
 struct KeyProvider {
     key_path: PathBuf,
     salt_path: PathBuf,
+    kdf_params_path: PathBuf,
     password_provider: Box<dyn PasswordProvider>,
-    cipher: Cipher,
+    ciphers: Arc<CipherTags>,
+}
+
+impl KeyProvider {
+    /// Re-encrypt the key file with the cipher we migrate to, the key itself doesn't change.
+    fn migrate(&self) -> FsResult<()> {
+        let Some(to) = self.ciphers.migrating_to() else {
+            return Ok(());
+        };
+        let (mut file, from) = self.ciphers.open(&self.key_path)?;
+        if from == to {
+            return Ok(());
+        }
+        let password = self
+            .password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        let storage = self.ciphers.storage();
+        let salt = read_salt(&**storage, &self.salt_path)?;
+        let kdf_params = read_kdf_params(&**storage, &self.kdf_params_path)?;
+        let derived_key = crypto::derive_key(&password, from, &salt, &kdf_params)?;
+        let tmp = self.ciphers.tmp_path();
+        let mut tmp_file = storage.create(&tmp)?;
+        crypto::reencrypt(&mut file, &mut tmp_file, from, to, &derived_key, false)?;
+        tmp_file.sync_all()?;
+        self.ciphers.replace(&self.key_path, &tmp)?;
+        Ok(())
+    }
+
+    /// Where the new key is kept while [`EncryptedFs::rotate_data_key`] is in progress.
+    fn rotation_path(&self) -> PathBuf {
+        self.key_path.with_file_name(KEY_ROTATION_FILENAME)
+    }
+
+    /// Decrypt a key saved with [`KeyProvider::wrap`].
+    fn unwrap(&self, path: &Path) -> FsResult<SecretVec<u8>> {
+        let password = self
+            .password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        let storage = self.ciphers.storage();
+        let salt = read_salt(&**storage, &self.salt_path)?;
+        let kdf_params = read_kdf_params(&**storage, &self.kdf_params_path)?;
+        Ok(unwrap_key(path, &password, &salt, &kdf_params, &self.ciphers)?.0)
+    }
+
+    /// Encrypt `key` with the key derived from the password, like `key.enc`, and save it in `path`.
+    fn wrap(&self, path: &Path, key: &SecretVec<u8>) -> FsResult<()> {
+        let password = self
+            .password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        let storage = self.ciphers.storage();
+        let salt = read_salt(&**storage, &self.salt_path)?;
+        let kdf_params = read_kdf_params(&**storage, &self.kdf_params_path)?;
+        wrap_key(
+            &**storage,
+            path,
+            key,
+            &password,
+            &salt,
+            &kdf_params,
+            self.ciphers.for_write(path)?,
+        )
+    }
+}
+
+type BoxedKeyProvider = Box<dyn ValueProvider<SecretVec<u8>, FsError>>;
+
+/// Checks the length of the keys from a provider given to [`EncryptedFs::new_with_key_provider`],
+/// a wrong one would fail deep in the crypto code.
+struct ExternalKeyProvider {
+    provider: BoxedKeyProvider,
+    key_len: usize,
+}
+
+#[async_trait]
+impl ValueProvider<SecretVec<u8>, FsError> for ExternalKeyProvider {
+    async fn provide(&self) -> Result<SecretVec<u8>, FsError> {
+        let key = self.provider.provide().await?;
+        if key.expose_secret().len() != self.key_len {
+            return Err(FsError::InvalidInput("key length doesn't match the cipher"));
+        }
+        Ok(key)
+    }
+}
+
+/// Where the key of the volume comes from.
+enum KeySource {
+    /// `key.enc` in the data dir, encrypted with a key derived from the password.
+    Password(Box<dyn PasswordProvider>),
+    External(BoxedKeyProvider),
 }
 
 #[async_trait]
@@ -510,35 +1025,353 @@ impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
             .password_provider
             .get_password()
             .ok_or(FsError::InvalidPassword)?;
-        read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)
+        read_or_create_key(
+            &self.key_path,
+            &self.salt_path,
+            &self.kdf_params_path,
+            &password,
+            &self.ciphers,
+            KdfParams::default(),
+            self.password_provider.min_password_len(),
+        )
     }
 }
 
+/// Min length of the password of new volumes, see [`PasswordProvider::min_password_len`].
+pub const DEFAULT_MIN_PASSWORD_LEN: usize = 8;
+
 pub trait PasswordProvider: Send + Sync + 'static {
     fn get_password(&self) -> Option<SecretString>;
+
+    /// Min number of characters of the password when a volume is created, else it fails with [`FsError::WeakPassword`].
+    ///
+    /// Existing volumes are opened with any password. Override it only if you know what you're doing, like in tests,
+    /// an empty password is always rejected.
+    fn min_password_len(&self) -> usize {
+        DEFAULT_MIN_PASSWORD_LEN
+    }
+}
+
+/// Gives the password we already have, like in [`EncryptedFs::change_cipher`].
+struct FixedPasswordProvider(SecretString);
+
+impl PasswordProvider for FixedPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(self.0.clone())
+    }
+}
+
+/// The caches reported to [`FsObserver::on_cache_hit`] and [`FsObserver::on_cache_miss`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum CacheKind {
+    /// Inodes attributes, see [`CacheConfig::attr_capacity`].
+    Attr,
+    /// Decrypted names of directory entries, see [`CacheConfig::dir_entries_name_capacity`].
+    DirEntryName,
+    /// Inode and type of directory entries, see [`CacheConfig::dir_entries_meta_capacity`].
+    DirEntryMeta,
+    /// Decrypted blocks, see [`FsConfig::block_cache`].
+    Block,
+}
+
+/// Gets notified of what the filesystem does, to collect metrics, see [`FsConfig::observer`].
+///
+/// It's called inline on the hot paths, so implementations should be quick, like incrementing some counters.
+/// All methods do nothing by default.
+#[allow(unused_variables)]
+pub trait FsObserver: Send + Sync {
+    /// A successful [`EncryptedFs::read`] of `len` bytes which took `duration`.
+    fn on_read(&self, ino: u64, len: usize, duration: Duration) {}
+
+    /// A successful [`EncryptedFs::write`] of `len` bytes which took `duration`.
+    fn on_write(&self, ino: u64, len: usize, duration: Duration) {}
+
+    fn on_cache_hit(&self, kind: CacheKind) {}
+
+    fn on_cache_miss(&self, kind: CacheKind) {}
+
+    /// Content of the file could not be decrypted, it was changed or corrupted outside the filesystem.
+    fn on_decrypt_failure(&self, ino: u64) {}
+
+    /// A handle was opened for the file, with [`EncryptedFs::open`] or [`EncryptedFs::open_append`].
+    fn on_handle_opened(&self, ino: u64) {}
+
+    /// A handle of the file was released with [`EncryptedFs::release`].
+    fn on_handle_released(&self, ino: u64) {}
+}
+
+/// Sizes and TTLs of the in-memory metadata caches, see [`FsConfig::cache`].
+///
+/// A capacity of `0` disables that cache, every lookup then goes to storage.
+/// After the TTL passes since a cache was created it's dropped and starts empty again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Max number of inodes attributes kept.
+    pub attr_capacity: usize,
+    pub attr_ttl: Duration,
+    /// Max number of decrypted names of directory entries kept.
+    pub dir_entries_name_capacity: usize,
+    pub dir_entries_name_ttl: Duration,
+    /// Max number of directory entries kept with their inode and type.
+    pub dir_entries_meta_capacity: usize,
+    pub dir_entries_meta_ttl: Duration,
+}
+
+impl CacheConfig {
+    /// All caches disabled.
+    #[must_use]
+    pub const fn disabled() -> Self {
+        Self {
+            attr_capacity: 0,
+            attr_ttl: DEFAULT_CACHE_TTL,
+            dir_entries_name_capacity: 0,
+            dir_entries_name_ttl: DEFAULT_CACHE_TTL,
+            dir_entries_meta_capacity: 0,
+            dir_entries_meta_ttl: DEFAULT_CACHE_TTL,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            attr_capacity: DEFAULT_CACHE_CAPACITY,
+            attr_ttl: DEFAULT_CACHE_TTL,
+            dir_entries_name_capacity: DEFAULT_CACHE_CAPACITY,
+            dir_entries_name_ttl: DEFAULT_CACHE_TTL,
+            dir_entries_meta_capacity: DEFAULT_CACHE_CAPACITY,
+            dir_entries_meta_ttl: DEFAULT_CACHE_TTL,
+        }
+    }
+}
+
+const DEFAULT_CACHE_CAPACITY: usize = 2000;
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How the inodes are stored in the data dir, chosen when it's created, see [`FsConfig::inode_backend`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum InodeBackend {
+    /// One file per inode in `inodes/`.
+    #[default]
+    Files,
+    /// All inodes in one file, `inodes/db`, with an index in memory. Much faster and uses less space with millions
+    /// of files, but the index takes memory and the file is read when the filesystem is opened.
+    /// Cipher migration is not supported.
+    Db,
+}
+
+/// When reading a file or listing a directory updates its `atime`, like the `atime` mount options on Linux,
+/// see [`FsConfig::atime_policy`].
+///
+/// Each update re-encrypts the inode, so with [`AtimePolicy::Always`] most reads also write.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum AtimePolicy {
+    /// On every access, like `strictatime`.
+    Always,
+    /// Only if `atime` is not newer than `mtime` or `ctime`, or it's older than a day, like `relatime`.
+    /// Enough for tools which check if a file was read since it was changed.
+    #[default]
+    Relatime,
+    /// Never, reads don't touch the inode at all, like `noatime`.
+    Never,
+}
+
+impl AtimePolicy {
+    /// With [`AtimePolicy::Relatime`] `atime` is updated at least this often.
+    const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn should_update(
+        self,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        now: SystemTime,
+    ) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Relatime => {
+                atime <= mtime
+                    || atime <= ctime
+                    || now
+                        .duration_since(atime)
+                        .is_ok_and(|elapsed| elapsed >= Self::RELATIME_INTERVAL)
+            }
+            Self::Never => false,
+        }
+    }
+}
+
+/// Settings which change how the data is stored, they are chosen when the data dir is created and kept in
+/// `security/format`, see [`FsConfig::format`].
+///
+/// Data dirs created before it was kept take the one from [`FsConfig::format`], or the default one, and keep it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeFormat {
+    /// Pad the names of directory entries to the next multiple of this many bytes before encrypting them,
+    /// so the names in the data dir don't show how long the original ones are.
+    ///
+    /// Names are read with any padding. `None` doesn't pad, this is the default.
+    pub name_padding: Option<NonZeroUsize>,
+    /// How the nonces of the blocks written in files are generated, see [`NonceStrategy`] for the tradeoffs.
+    ///
+    /// [`NonceStrategy::Random`] is the default.
+    pub nonce_strategy: NonceStrategy,
+    /// Id of the [`ContentTransform`] applied on the content of new files, it must be one of
+    /// [`FsConfig::content_transforms`]. `None` means files are stored as they are, this is the default.
+    pub content_transform: Option<u8>,
+    /// Names which differ only by case are the same entry, like `File.txt` and `file.txt`, as on Windows.
+    ///
+    /// Names are lowercased with [`str::to_lowercase`] before they are hashed, the case they were created with is
+    /// kept and listed. Disabled by default.
+    pub case_insensitive: bool,
+}
+
+/// Settings of an [`EncryptedFs`], see [`EncryptedFs::new_with_config`]. They are fixed for the lifetime of the
+/// instance, the ones which change how the data is stored are in [`FsConfig::format`].
+#[derive(Clone, Default)]
+pub struct FsConfig {
+    /// Sizes and TTLs of the metadata caches.
+    pub cache: CacheConfig,
+    /// Backend of the inodes of new data dirs, existing ones keep the backend they were created with.
+    ///
+    /// If it's set and the data dir uses the other one, it fails with [`FsError::InvalidInput`]. `None` detects it,
+    /// new data dirs then use [`InodeBackend::Files`].
+    pub inode_backend: Option<InodeBackend>,
+    /// Format of new data dirs, existing ones keep the one they were created with.
+    ///
+    /// If it's set and the data dir has another one, it fails with [`FsError::InvalidInput`]. `None` uses the one of
+    /// the data dir, new data dirs then use the default one.
+    pub format: Option<VolumeFormat>,
+    /// The [`ContentTransform`]s files can be stored with. Each file is read with the one it was created with, its id
+    /// is kept next to the content, so all the ones used in the data dir need to be here.
+    pub content_transforms: Vec<Arc<dyn ContentTransform>>,
+    /// Run encryption and decryption from [`EncryptedFs::read`] and [`EncryptedFs::write`] on tokio's blocking pool,
+    /// with at most this many operations in parallel, so they don't block the async workers.
+    ///
+    /// `None` runs them inline on the async worker, this is the default.
+    pub crypto_threads: Option<NonZeroUsize>,
+    /// When opening for write a file which is already opened for write, wait up to this long for it to be released,
+    /// instead of failing right away with [`FsError::AlreadyOpenForWrite`].
+    ///
+    /// `None` fails right away, this is the default.
+    pub open_write_timeout: Option<Duration>,
+    /// Store the content of files with the same content only once.
+    ///
+    /// When a file opened for write is released, or after [`EncryptedFs::copy_file`], if another file has the same
+    /// plaintext, encrypted with the same cipher and [`ContentTransform`], the file becomes a hard link to the
+    /// encrypted content of that one, kept in `contents-refs`. Writing to a shared content makes a copy of it first.
+    /// The number of links is the reference count, the shared content is removed with the last file using it.
+    ///
+    /// Only on Unix, elsewhere it's ignored. Disabled by default, already shared contents stay shared.
+    pub dedup: bool,
+    /// Overwrite the inode of a file with zeros before it's removed, with the last link to it.
+    ///
+    /// The inode keeps the [`FileKey`] of the content, so the content can't be decrypted anymore, even if the volume
+    /// key leaks later. Contents shared with [`FsConfig::dedup`] and snapshots have their own copy of the key,
+    /// they can still be decrypted until they are removed too.
+    ///
+    /// Disabled by default. With the inode db backend it fails with [`FsError::NotSupported`], it keeps the old
+    /// records until it's compacted.
+    pub shred: bool,
+    /// Sort the entries by name in [`EncryptedFs::read_dir_paged`] and [`EncryptedFs::read_dir_plus`], with
+    /// `.` and `..` first, so the order is the same each time, like for `ls`.
+    ///
+    /// All the names of the directory are decrypted for each page, which is slower on big directories.
+    /// Disabled by default, [`EncryptedFs::read_dir`] is never sorted, see [`EncryptedFs::read_dir_sorted`].
+    pub sorted_dirs: bool,
+    /// Leave holes instead of zeros when a file is extended, by writing after its end or with
+    /// [`EncryptedFs::set_len`].
+    ///
+    /// The whole blocks in between are left as holes in the content file, so they take no space on disk, and are
+    /// read as zeros. The blocks which are holes are kept in a [`HoleMap`] next to the content, so zeroed blocks
+    /// are still detected everywhere else. Files with holes are not deduplicated, see [`FsConfig::dedup`].
+    ///
+    /// Keep in mind someone with access to the data dir can see which parts of a file were never written.
+    /// Disabled by default, existing holes are read either way.
+    pub sparse: bool,
+    /// Batch the updates which change only the times of an inode, like `atime` after reading a file or a directory,
+    /// and write them to the inode at most once in this interval, instead of rewriting the encrypted inode on each
+    /// one.
+    ///
+    /// Pending times are visible in [`EncryptedFs::get_attr`] and are written right away with any other change
+    /// of the inode, like size or permissions, on [`EncryptedFs::flush`] and [`EncryptedFs::flush_times`].
+    /// If the process stops before they are written only the times are lost.
+    ///
+    /// `None` writes them right away, this is the default.
+    pub times_write_back: Option<Duration>,
+    /// Flush the files opened for write at this interval, so a crash loses at most what was written since the last
+    /// flush, even if a program keeps the file opened for a long time.
+    ///
+    /// `None` disables it, data is then durable only after [`EncryptedFs::flush`], this is the default.
+    /// The task stops when the last reference to the filesystem is dropped.
+    pub auto_flush: Option<Duration>,
+    /// When reading a file or listing a directory updates its `atime`.
+    pub atime_policy: AtimePolicy,
+    /// Keep decrypted blocks in a [`BlockCache`], so repeated reads of the same blocks are not decrypted again.
+    ///
+    /// `None` disables it, this is the default.
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// While a handle is read sequentially, decrypt up to this many blocks ahead on a background task, so reading
+    /// a streamed file doesn't wait for the disk and the decryption of each block.
+    ///
+    /// A read at another offset than where the previous one ended drops what was read ahead, and nothing is read
+    /// ahead until the reads are sequential again. It's not used with the [`BlockCache`], nor with
+    /// [`EncryptedFs::new_serialized`]. `None` disables it, this is the default.
+    pub readahead_blocks: Option<NonZeroUsize>,
+    /// Report reads, writes, cache hits and misses and opened handles to an [`FsObserver`], like to export metrics.
+    ///
+    /// `None` disables it, this is the default, then nothing is measured.
+    pub observer: Option<Arc<dyn FsObserver>>,
+    /// Limit the total size of the regular files to this many bytes, like for a tenant on a shared host.
+    ///
+    /// Writes, [`EncryptedFs::set_len`] and other changes which would make the files bigger than that fail with
+    /// [`FsError::QuotaExceeded`], removing or truncating files gives the space back. The size counted is the one
+    /// of the plaintext, like `du --apparent-size`, the space taken in the data dir is bigger, see
+    /// [`EncryptedFs::storage_overhead_ratio`]. Files shared with [`FsConfig::dedup`] count once for each
+    /// inode, hard links only once.
+    ///
+    /// The size used so far is the sum of the sizes of all the inodes, so opening the volume reads all of them.
+    /// Changes made meanwhile by another instance on the same data dir are not counted.
+    /// `None` has no limit, this is the default. A quota smaller than the files already use is allowed, then
+    /// they can only shrink.
+    pub quota: Option<u64>,
+    /// Where the files of the data dir are kept, all of them are read and written through it, see [`Storage`].
+    ///
+    /// `None` keeps them on the local filesystem with [`FileStorage`], this is the default. With
+    /// [`MemStorage`](storage::MemStorage) nothing reaches a disk, like for scratch space, it's kept until the
+    /// storage is dropped. The same storage needs to be given each time the data dir is opened.
+    pub storage: Option<Arc<dyn Storage>>,
 }
 
-struct DirEntryNameCacheProvider {}
+struct DirEntryNameCacheProvider {
+    capacity: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryNameCacheProvider {
     async fn provide(&self) -> Result<Mutex<LruCache<String, SecretString>>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(Mutex::new(LruCache::new(self.capacity)))
     }
 }
 
-struct DirEntryMetaCacheProvider {}
+struct DirEntryMetaCacheProvider {
+    capacity: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<Mutex<DirEntryMetaCache>, FsError> for DirEntryMetaCacheProvider {
     async fn provide(&self) -> Result<Mutex<DirEntryMetaCache>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(Mutex::new(LruCache::new(self.capacity)))
     }
 }
 
-struct AttrCacheProvider {}
+struct AttrCacheProvider {
+    capacity: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<RwLock<LruCache<u64, FileAttr>>, FsError> for AttrCacheProvider {
     async fn provide(&self) -> Result<RwLock<LruCache<u64, FileAttr>>, FsError> {
-        Ok(RwLock::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(RwLock::new(LruCache::new(self.capacity)))
     }
 }
 
@@ -547,10 +1380,16 @@ type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
 /// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
 pub struct EncryptedFs {
     pub(crate) data_dir: PathBuf,
-    write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
-    read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
+    // all the files of the data dir are read and written through it
+    storage: Arc<dyn Storage>,
+    // the maps are locked only to get the context, which is locked after for the duration of the op,
+    // so opening or releasing a handle doesn't wait for ops on other files
+    write_handles: RwLock<HashMap<u64, Arc<Mutex<WriteHandleContext>>>>,
+    read_handles: RwLock<HashMap<u64, Arc<Mutex<ReadHandleContext>>>>,
     current_handle: AtomicU64,
-    cipher: Cipher,
+    ciphers: Arc<CipherTags>,
+    // held by `migrate_cipher` and `rotate_data_key`, so snapshots are not taken meanwhile
+    cipher_migration_lock: RwLock<()>,
     // (ino, fh)
     opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
     opened_files_for_write: RwLock<HashMap<u64, u64>>,
@@ -559,21 +1398,60 @@ pub struct EncryptedFs {
     serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
     // used for the update op
     serialize_update_inode_locks: ArcHashMap<u64, Mutex<bool>>,
+    // used for the read-modify-write of extended attributes
+    serialize_xattr_locks: ArcHashMap<u64, Mutex<bool>>,
     // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
     serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     read_write_locks: ArcHashMap<u64, RwLock<bool>>,
-    key: ExpireValue<SecretVec<u8>, FsError, KeyProvider>,
-    self_weak: std::sync::Mutex<Option<Weak<Self>>>,
-    attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
-    dir_entries_name_cache:
+    key: ExpireValue<SecretVec<u8>, FsError, BoxedKeyProvider>,
+    // set while `rotate_data_key` runs, then getting the key fails
+    key_rotation: RwLock<bool>,
+    // `None` when the key comes from an external provider
+    key_file: Option<Arc<KeyProvider>>,
+    // set once in the constructor, so getting it doesn't need a lock
+    self_weak: std::sync::OnceLock<Weak<Self>>,
+    // `None` when disabled in `CacheConfig`
+    attr_cache: Option<ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>>,
+    dir_entries_name_cache: Option<
         ExpireValue<Mutex<LruCache<String, SecretString>>, FsError, DirEntryNameCacheProvider>,
+    >,
     dir_entries_meta_cache:
-        ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
-    sizes_write: Mutex<HashMap<u64, AtomicU64>>,
-    sizes_read: Mutex<HashMap<u64, AtomicU64>>,
-    requested_read: Mutex<HashMap<u64, AtomicU64>>,
+        Option<ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>>,
     read_only: bool,
+    format: VolumeFormat,
+    content_transforms: Vec<Arc<dyn ContentTransform>>,
+    // limits how many crypto operations run in parallel on the blocking pool, `None` runs them inline
+    crypto_pool: Option<Arc<Semaphore>>,
+    // timestamp-only updates not yet written to the inode, merged on get_attr
+    pending_times: Mutex<HashMap<u64, SetFileAttr>>,
+    times_write_back: Option<Duration>,
+    atime_policy: AtimePolicy,
+    open_write_timeout: Option<Duration>,
+    dedup: bool,
+    shred: bool,
+    quota: Quota,
+    sorted_dirs: bool,
+    sparse: bool,
+    // serializes sharing contents with unsharing them, so a shared content is never written in place
+    content_refs_lock: Mutex<()>,
+    // notified when a file opened for write is released
+    write_slot_released: Notify,
+    block_cache: Option<Arc<BlockCache>>,
+    readahead_blocks: Option<NonZeroUsize>,
+    observer: Option<Arc<dyn FsObserver>>,
+    // `None` uses random nonces for the blocks of the content
+    nonce_counter: Option<Arc<NonceCounter>>,
+    ino_counter: InoCounter,
+    // run everything on the caller's runtime, see [`EncryptedFs::new_serialized`]
+    serialized: bool,
+    // replaced on rename while still opened, removed on the last release
+    orphans: Mutex<HashSet<u64>>,
+    // handles from `open_append` to their inode, they don't hold the write slot
+    append_handles: RwLock<HashMap<u64, u64>>,
+    journals: std::sync::Mutex<HashMap<u64, Arc<Journal>>>,
+    holes: std::sync::Mutex<HashMap<u64, Arc<HoleMap>>>,
+    inodes: Arc<InodeStore>,
 }
 
 impl EncryptedFs {
@@ -585,82 +1463,658 @@ impl EncryptedFs {
         cipher: Cipher,
         read_only: bool,
     ) -> FsResult<Arc<Self>> {
-        let key_provider = KeyProvider {
-            key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+        Self::new_with_config(
+            data_dir,
             password_provider,
             cipher,
-        };
-        let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
-
-        ensure_structure_created(&data_dir.clone()).await?;
-        key.get().await?; // this will check the password
+            read_only,
+            FsConfig::default(),
+        )
+        .await
+    }
 
-        let fs = Self {
+    /// Like [`EncryptedFs::new`], with the settings from `config`.
+    ///
+    /// Fails with [`FsError::InvalidInput`] if the data dir was created with another [`FsConfig::inode_backend`] or
+    /// [`FsConfig::format`] than the ones set, or if its [`ContentTransform`] is not in
+    /// [`FsConfig::content_transforms`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_with_config(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        read_only: bool,
+        config: FsConfig,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_with(
             data_dir,
-            write_handles: RwLock::new(HashMap::new()),
-            read_handles: RwLock::new(HashMap::new()),
-            current_handle: AtomicU64::new(1),
+            KeySource::Password(password_provider),
             cipher,
-            opened_files_for_read: RwLock::new(HashMap::new()),
-            opened_files_for_write: RwLock::new(HashMap::new()),
-            serialize_inode_locks: Arc::new(ArcHashMap::default()),
-            serialize_update_inode_locks: ArcHashMap::default(),
-            serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
-            serialize_dir_entries_hash_locks: Arc::new(ArcHashMap::default()),
-            key,
-            self_weak: std::sync::Mutex::new(None),
-            read_write_locks: ArcHashMap::default(),
-            // todo: take duration from param
-            attr_cache: ExpireValue::new(AttrCacheProvider {}, Duration::from_secs(10 * 60)),
-            // todo: take duration from param
-            dir_entries_name_cache: ExpireValue::new(
-                DirEntryNameCacheProvider {},
-                Duration::from_secs(10 * 60),
-            ),
-            // todo: take duration from param
-            dir_entries_meta_cache: ExpireValue::new(
-                DirEntryMetaCacheProvider {},
-                Duration::from_secs(10 * 60),
-            ),
-            sizes_write: Mutex::default(),
-            sizes_read: Mutex::default(),
-            requested_read: Mutex::default(),
             read_only,
-        };
-
-        let arc = Arc::new(fs);
-        arc.self_weak
-            .lock()
-            .expect("cannot obtain lock")
-            .replace(Arc::downgrade(&arc));
-
-        arc.ensure_root_exists().await?;
-
-        Ok(arc)
-    }
-
-    pub fn exists(&self, ino: u64) -> bool {
-        self.ino_file(ino).is_file()
-    }
-
-    pub fn is_dir(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_dir()
+            config,
+            false,
+            None,
+        )
+        .await
+    }
+
+    /// Like [`EncryptedFs::new_with_config`], but the key comes from `key_provider` instead of from `key.enc` in the
+    /// data dir, like from a KMS, so it never needs to be stored on the host.
+    ///
+    /// The key must have the length of `cipher`'s keys and must always be the same for the data dir.
+    /// It's asked again after it expires from memory, if that fails the operation fails and the next one
+    /// asks again.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_with_key_provider(
+        data_dir: PathBuf,
+        key_provider: Box<dyn ValueProvider<SecretVec<u8>, FsError>>,
+        cipher: Cipher,
+        read_only: bool,
+        config: FsConfig,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_with(
+            data_dir,
+            KeySource::External(key_provider),
+            cipher,
+            read_only,
+            config,
+            false,
+            None,
+        )
+        .await
+    }
+
+    /// **For debugging only**, this is much slower than [`EncryptedFs::new`].
+    ///
+    /// Runs all operations on the caller's task, instead of on our dedicated runtimes and threads, so everything
+    /// happens on one thread. Concurrent operations can still interleave, but only at `.await` points.
+    /// If a bug still reproduces like this it's a logic error in the order of operations, if not it's likely
+    /// a data race between threads.
+    ///
+    /// Must be called from a current-thread runtime, like the default one of `#[tokio::test]`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_serialized(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        read_only: bool,
+    ) -> FsResult<Arc<Self>> {
+        if tokio::runtime::Handle::current().runtime_flavor() != RuntimeFlavor::CurrentThread {
+            return Err(FsError::InvalidInput(
+                "serialized mode needs a current-thread runtime",
+            ));
+        }
+        warn!("running serialized, this is only meant for debugging");
+        Self::new_with(
+            data_dir,
+            KeySource::Password(password_provider),
+            cipher,
+            read_only,
+            FsConfig::default(),
+            true,
+            None,
+        )
+        .await
+    }
+
+    /// Open a snapshot taken with [`EncryptedFs::snapshot`], it shows the files and directories as they were when
+    /// it was taken. It's always read-only, the password and `cipher` are the ones of `data_dir`, `config` is like
+    /// for [`EncryptedFs::new_with_config`].
+    ///
+    /// Fails with [`FsError::NotFound`] if there is no snapshot with `snapshot_id`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_readonly_at(
+        data_dir: PathBuf,
+        snapshot_id: u64,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        config: FsConfig,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_with(
+            data_dir,
+            KeySource::Password(password_provider),
+            cipher,
+            true,
+            config,
+            false,
+            Some(snapshot_id),
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn new_with(
+        data_dir: PathBuf,
+        key_source: KeySource,
+        cipher: Cipher,
+        read_only: bool,
+        config: FsConfig,
+        serialized: bool,
+        snapshot: Option<u64>,
+    ) -> FsResult<Arc<Self>> {
+        let key_file = matches!(key_source, KeySource::Password(_));
+        let storage = config.storage.unwrap_or_else(|| Arc::new(FileStorage));
+        ensure_structure_created(&*storage, &data_dir, key_file)?;
+        let ciphers = Arc::new(CipherTags::load_with(storage.clone(), &data_dir, cipher)?);
+        // a snapshot has the same layout as the data dir, only the key and the ciphers are from the data dir
+        let root = match snapshot {
+            Some(id) => {
+                let root = data_dir.join(SNAPSHOTS_DIR).join(id.to_string());
+                if !storage.is_dir(&root) {
+                    return Err(FsError::NotFound("snapshot not found"));
+                }
+                root
+            }
+            None => data_dir.clone(),
+        };
+        let read_only = read_only || snapshot.is_some();
+        if !read_only {
+            // fail before anything is written, else only the names which have it fail later
+            check_names_supported(&*storage, &data_dir)?;
+        }
+        let inodes = Arc::new(InodeStore::open(
+            &root,
+            config.inode_backend,
+            ciphers.clone(),
+            read_only,
+        )?);
+        if config.shred && inodes.backend() == InodeBackend::Db {
+            return Err(FsError::NotSupported("shred with the inode db backend"));
+        }
+        let format_path = data_dir.join(SECURITY_DIR).join(FORMAT_FILENAME);
+        let saved_format = read_format(&*storage, &format_path)?;
+        if let (Some(saved), Some(requested)) = (saved_format, config.format) {
+            if saved != requested {
+                return Err(FsError::InvalidInput("the data dir uses another format"));
+            }
+        }
+        let format = saved_format.or(config.format).unwrap_or_default();
+        if let Some(id) = format.content_transform {
+            if !config.content_transforms.iter().any(|t| t.id() == id) {
+                return Err(FsError::InvalidInput(
+                    "the content transform of the data dir is not set",
+                ));
+            }
+        }
+        let (key_provider, key_file): (BoxedKeyProvider, _) = match key_source {
+            KeySource::Password(password_provider) => {
+                let key_file = Arc::new(KeyProvider {
+                    key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
+                    salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+                    kdf_params_path: data_dir.join(SECURITY_DIR).join(KDF_PARAMS_FILENAME),
+                    password_provider,
+                    ciphers: ciphers.clone(),
+                });
+                (Box::new(key_file.clone()), Some(key_file))
+            }
+            KeySource::External(provider) => (
+                Box::new(ExternalKeyProvider {
+                    provider,
+                    key_len: cipher.key_len(),
+                }),
+                None,
+            ),
+        };
+        let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
+        key.get().await?; // this will check the password
+        let ino_counter = InoCounter::open(
+            storage.clone(),
+            &data_dir.join(SECURITY_DIR).join(INO_COUNTER_FILENAME),
+        )?;
+        if !read_only {
+            ciphers.save()?;
+            if saved_format.is_none() {
+                save_format(&*storage, &format_path, format)?;
+            }
+        }
+        let nonce_counter = match format.nonce_strategy {
+            NonceStrategy::Random => None,
+            NonceStrategy::Counter => Some(Arc::new(NonceCounter::open_in(
+                storage.clone(),
+                &data_dir.join(SECURITY_DIR).join(NONCE_COUNTER_FILENAME),
+            )?)),
+        };
+        let cache_config = config.cache;
+
+        let attr_cache = NonZeroUsize::new(cache_config.attr_capacity).map(|capacity| {
+            ExpireValue::new(AttrCacheProvider { capacity }, cache_config.attr_ttl)
+        });
+        let dir_entries_name_cache =
+            NonZeroUsize::new(cache_config.dir_entries_name_capacity).map(|capacity| {
+                ExpireValue::new(
+                    DirEntryNameCacheProvider { capacity },
+                    cache_config.dir_entries_name_ttl,
+                )
+            });
+        let dir_entries_meta_cache =
+            NonZeroUsize::new(cache_config.dir_entries_meta_capacity).map(|capacity| {
+                ExpireValue::new(
+                    DirEntryMetaCacheProvider { capacity },
+                    cache_config.dir_entries_meta_ttl,
+                )
+            });
+        let fs = Self {
+            data_dir: root,
+            storage,
+            write_handles: RwLock::new(HashMap::new()),
+            read_handles: RwLock::new(HashMap::new()),
+            current_handle: AtomicU64::new(1),
+            ciphers,
+            cipher_migration_lock: RwLock::new(()),
+            opened_files_for_read: RwLock::new(HashMap::new()),
+            opened_files_for_write: RwLock::new(HashMap::new()),
+            serialize_inode_locks: Arc::new(ArcHashMap::default()),
+            serialize_update_inode_locks: ArcHashMap::default(),
+            serialize_xattr_locks: ArcHashMap::default(),
+            serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
+            serialize_dir_entries_hash_locks: Arc::new(ArcHashMap::default()),
+            key,
+            key_rotation: RwLock::new(false),
+            key_file,
+            self_weak: std::sync::OnceLock::new(),
+            read_write_locks: ArcHashMap::default(),
+            attr_cache,
+            dir_entries_name_cache,
+            dir_entries_meta_cache,
+            read_only,
+            format,
+            content_transforms: config.content_transforms,
+            crypto_pool: config
+                .crypto_threads
+                .map(|threads| Arc::new(Semaphore::new(threads.get()))),
+            pending_times: Mutex::default(),
+            times_write_back: config.times_write_back,
+            atime_policy: config.atime_policy,
+            open_write_timeout: config.open_write_timeout,
+            dedup: config.dedup,
+            shred: config.shred,
+            quota: Quota::new(),
+            sorted_dirs: config.sorted_dirs,
+            sparse: config.sparse,
+            content_refs_lock: Mutex::default(),
+            write_slot_released: Notify::new(),
+            block_cache: config.block_cache,
+            readahead_blocks: config.readahead_blocks,
+            observer: config.observer,
+            nonce_counter,
+            ino_counter,
+            serialized,
+            orphans: Mutex::default(),
+            append_handles: RwLock::default(),
+            journals: std::sync::Mutex::default(),
+            holes: std::sync::Mutex::default(),
+            inodes,
+        };
+
+        let arc = Arc::new(fs);
+        let _ = arc.self_weak.set(Arc::downgrade(&arc));
+
+        if arc
+            .key_file
+            .as_ref()
+            .is_some_and(|key_file| arc.storage.exists(&key_file.rotation_path()))
+        {
+            // some files use the new key, some the old one
+            if arc.read_only {
+                return Err(FsError::KeyRotationInProgress);
+            }
+            warn!("key rotation was interrupted, finishing it");
+            arc.rotate_data_key().await?;
+        }
+        arc.rollback_journals().await?;
+        if !arc.read_only {
+            arc.gc_content_refs().await?;
+        }
+        arc.ensure_root_exists().await?;
+        if let Some(quota) = config.quota {
+            // before it's shared, so all the changes are counted
+            arc.quota.set(Some(quota), arc.quota_used().await?);
+        }
+        if let Some(interval) = config.times_write_back {
+            arc.spawn_times_write_back(interval);
+        }
+        if let Some(interval) = config.auto_flush {
+            arc.spawn_auto_flush(interval);
+        }
+
+        Ok(arc)
+    }
+
+    pub fn exists(&self, ino: u64) -> bool {
+        self.inodes.exists(ino)
+    }
+
+    pub fn is_dir(&self, ino: u64) -> bool {
+        self.storage.is_dir(&self.contents_path(ino))
     }
 
     pub fn is_file(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_file()
+        self.storage.is_file(&self.contents_path(ino))
     }
 
-    #[allow(dead_code)]
-    async fn is_read_only(&self) -> bool {
+    /// Number of inodes in the filesystem, including the root.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn count_inodes(&self) -> FsResult<u64> {
+        Ok(self.inodes.inos()?.len() as u64)
+    }
+
+    /// Attributes of all the inodes as they are in storage, sorted by `ino`. Only the inodes are read,
+    /// not the contents.
+    ///
+    /// Meant for backups of the data dir, comparing `mtime` and `size` with the ones from a previous call tells
+    /// which `contents/<ino>` changed. Like the data dir, it doesn't include what opened files didn't flush yet
+    /// or times not written yet, see [`FsConfig::times_write_back`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn metadata_snapshot(&self) -> FsResult<Vec<(u64, FileAttr)>> {
+        let mut inos = self.inodes.inos()?;
+        inos.sort_unstable();
+        let mut attrs = Vec::with_capacity(inos.len());
+        for ino in inos {
+            match self.get_inode_from_storage(ino).await {
+                Ok(attr) => attrs.push((ino, attr)),
+                // removed meanwhile
+                Err(FsError::InodeNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(attrs)
+    }
+
+    #[must_use]
+    pub const fn is_read_only(&self) -> bool {
         self.read_only
     }
 
+    /// The [`VolumeFormat`] of the data dir.
+    #[must_use]
+    pub const fn volume_format(&self) -> VolumeFormat {
+        self.format
+    }
+
+    /// The [`ContentTransform`] new files are created with, see [`VolumeFormat::content_transform`].
+    fn content_transform(&self) -> Option<Arc<dyn ContentTransform>> {
+        let id = self.format.content_transform?;
+        self.content_transforms
+            .iter()
+            .find(|transform| transform.id() == id)
+            .cloned()
+    }
+
+    fn name_padding(&self) -> Option<NonZeroUsize> {
+        self.format.name_padding
+    }
+
+    /// Name of the entry in the hash dir, the name is lowercased first if the volume is case-insensitive,
+    /// see [`VolumeFormat::case_insensitive`].
+    fn hash_file_name(&self, name: &SecretString) -> String {
+        if self.format.case_insensitive {
+            crypto::hash_file_name(&SecretString::new(Box::new(
+                name.expose_secret().to_lowercase(),
+            )))
+        } else {
+            crypto::hash_file_name(name)
+        }
+    }
+
+    /// If both names are the same entry in a directory.
+    fn same_name(&self, name: &SecretString, other: &SecretString) -> bool {
+        if self.format.case_insensitive {
+            name.expose_secret().to_lowercase() == other.expose_secret().to_lowercase()
+        } else {
+            name.expose_secret() == other.expose_secret()
+        }
+    }
+
+    /// The sum of the sizes of the regular files, what counts for [`FsConfig::quota`].
+    async fn quota_used(&self) -> FsResult<u64> {
+        let mut used = 0_u64;
+        for ino in self.inodes.inos()? {
+            match self.get_attr(ino).await {
+                Ok(attr) if attr.kind == FileType::RegularFile => {
+                    used = used.saturating_add(attr.size);
+                }
+                // directories and symlinks are not counted, or it was removed meanwhile
+                Ok(_) | Err(FsError::InodeNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(used)
+    }
+
+    /// The limit set with [`FsConfig::quota`] and how many bytes of it the files use, `None` without a limit.
+    #[must_use]
+    pub fn quota_usage(&self) -> Option<(u64, u64)> {
+        self.quota.usage()
+    }
+
+    /// Write the pending times at `interval`, see [`FsConfig::times_write_back`].
+    fn spawn_times_write_back(&self, interval: Duration) {
+        let Some(weak) = self.self_weak.get().cloned() else {
+            return;
+        };
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // don't keep it alive while sleeping
+                let Some(fs) = weak.upgrade() else {
+                    break;
+                };
+                if let Err(err) = fs.flush_times().await {
+                    error!(err = %err, "writing pending times");
+                }
+            }
+        });
+    }
+
+    /// Flush the files opened for write at `interval`, see [`FsConfig::auto_flush`].
+    fn spawn_auto_flush(&self, interval: Duration) {
+        let Some(weak) = self.self_weak.get().cloned() else {
+            return;
+        };
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // don't keep it alive while sleeping
+                let Some(fs) = weak.upgrade() else {
+                    break;
+                };
+                if let Err(err) = fs.flush_writers().await {
+                    error!(err = %err, "auto flush");
+                }
+            }
+        });
+    }
+
+    fn atime_policy(&self) -> AtimePolicy {
+        self.atime_policy
+    }
+
+    /// Update `atime` after a file or directory was accessed, if [`FsConfig::atime_policy`] allows it.
+    async fn touch_atime(&self, ino: u64) -> FsResult<()> {
+        let policy = self.atime_policy();
+        if self.read_only || policy == AtimePolicy::Never {
+            return Ok(());
+        }
+        let attr = self.get_attr(ino).await?;
+        let now = SystemTime::now();
+        if !policy.should_update(attr.atime, attr.mtime, attr.ctime, now) {
+            return Ok(());
+        }
+        self.set_attr(ino, SetFileAttr::default().with_atime(now))
+            .await
+    }
+
+    /// Like [`EncryptedFs::touch_atime`] for the times a read handle keeps in memory until it's released.
+    fn touch_handle_atime(&self, attr: &mut TimesFileAttr) {
+        let now = SystemTime::now();
+        if self
+            .atime_policy()
+            .should_update(attr.atime, attr.mtime, attr.ctime, now)
+        {
+            attr.atime = now;
+        }
+    }
+
+    /// Write the `atime` of a read handle, only if it's newer than the one in the inode, so handles which
+    /// didn't change it don't re-encrypt the inode.
+    async fn save_handle_atime(&self, ino: u64, atime: SystemTime) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if atime <= attr.atime {
+            return Ok(());
+        }
+        self.set_attr(ino, SetFileAttr::default().with_atime(atime))
+            .await
+    }
+
+    /// Write all pending times batched by [`FsConfig::times_write_back`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn flush_times(&self) -> FsResult<()> {
+        let inodes: Vec<u64> = self.pending_times.lock().await.keys().copied().collect();
+        for ino in inodes {
+            self.flush_inode_times(ino).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush_inode_times(&self, ino: u64) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let Some(pending) = self.pending_times.lock().await.remove(&ino) else {
+            return Ok(());
+        };
+        if !self.exists(ino) {
+            // it was deleted meanwhile
+            return Ok(());
+        }
+        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+        merge_attr(&mut attr, &pending, false, false);
+        self.write_inode_to_storage(&attr).await
+    }
+
+    /// Keep the times in memory if [`FsConfig::times_write_back`] is enabled and they are the only change.
+    async fn try_defer_times(&self, ino: u64, set_attr: &SetFileAttr) -> FsResult<bool> {
+        if self.times_write_back.is_none() || !is_times_only(set_attr) {
+            return Ok(false);
+        }
+        let attr = self.get_attr(ino).await?;
+        let now = SystemTime::now();
+        warn_on_clock_skew(&attr, set_attr, now);
+        // same as in `set_attr2`, atime is always updated and ctime unless it's only an access
+        let set_attr = SetFileAttr {
+            atime: Some(now),
+            ctime: if is_atime_only(set_attr) {
+                set_attr.ctime
+            } else {
+                Some(now)
+            },
+            ..*set_attr
+        };
+        let mut pending = self.pending_times.lock().await;
+        let pending = pending.entry(ino).or_default();
+        pending.atime = pending.atime.max(set_attr.atime);
+        pending.mtime = pending.mtime.max(set_attr.mtime);
+        pending.ctime = pending.ctime.max(set_attr.ctime);
+        pending.crtime = pending.crtime.max(set_attr.crtime);
+        Ok(true)
+    }
+
+    fn block_cache(&self) -> Option<Arc<BlockCache>> {
+        self.block_cache.clone()
+    }
+
+    fn readahead_blocks(&self) -> Option<NonZeroUsize> {
+        self.readahead_blocks.filter(|_| !self.serialized)
+    }
+
+    fn observe(&self, f: impl FnOnce(&dyn FsObserver)) {
+        if let Some(observer) = &self.observer {
+            f(&**observer);
+        }
+    }
+
+    fn observe_cache(&self, kind: CacheKind, hit: bool) {
+        self.observe(|observer| {
+            if hit {
+                observer.on_cache_hit(kind);
+            } else {
+                observer.on_cache_miss(kind);
+            }
+        });
+    }
+
+    /// Size (in bytes) of the buffers to read and write files with, see [`Cipher::recommended_io_size`].
+    ///
+    /// It's also the `st_blksize` of files and the block size `statfs` reports for the mount, which programs like `cp`
+    /// use as their buffer size.
+    #[must_use]
+    pub fn io_block_size(&self) -> usize {
+        self.ciphers.cipher().recommended_io_size()
+    }
+
+    /// How many bytes are stored on disk for each byte of content, for new files with the current cipher
+    /// and [`ContentTransform`].
+    ///
+    /// Useful to estimate the space needed, it doesn't include the metadata and the partial last block of files.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn storage_overhead_ratio(&self) -> f64 {
+        let mut block_len = BLOCK_SIZE + self.ciphers.cipher().per_block_overhead();
+        if self.content_transform().is_some() {
+            block_len += FRAME_HEADER_LEN;
+        }
+        block_len as f64 / BLOCK_SIZE as f64
+    }
+
+    fn nonce_counter(&self) -> Option<Arc<NonceCounter>> {
+        self.nonce_counter.clone()
+    }
+
+    /// Runs a crypto operation based on [`FsConfig::crypto_threads`].
+    ///
+    /// `f` must not take any of our locks, so we cannot deadlock while waiting for the blocking pool.
+    async fn run_crypto<T, F>(&self, f: F) -> FsResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let pool = self.crypto_pool.clone();
+        match pool.filter(|_| !self.serialized) {
+            None => Ok(f()),
+            Some(pool) => {
+                let _permit = pool
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| FsError::Other("crypto pool closed"))?;
+                Ok(task::spawn_blocking(f).await?)
+            }
+        }
+    }
+
+    /// Runs `f` on the dedicated runtime `rt`, or on the caller's task with [`EncryptedFs::new_serialized`].
+    async fn spawn_on<T, F>(&self, rt: &Runtime, f: F) -> FsResult<T>
+    where
+        T: Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+    {
+        if self.serialized {
+            Ok(f.await)
+        } else {
+            Ok(rt.spawn(f).await?)
+        }
+    }
+
     fn validate_filename(&self, secret_filename: &SecretBox<String>) -> FsResult<()> {
         let filename = secret_filename.expose_secret().to_string();
         if filename.contains('/') {
             Err(FsError::InvalidInput("'/' not allowed in the filename"))
+        } else if filename.contains('\0') {
+            Err(FsError::InvalidInput("'\\0' not allowed in the filename"))
         } else if filename.contains('\\') {
             Err(FsError::InvalidInput("'\\' not allowed in the filename"))
         } else {
@@ -695,139 +2149,350 @@ impl EncryptedFs {
         self.validate_filename(name)?;
 
         // spawn on a dedicated runtime to not interfere with other higher priority tasks
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
+        let self_clone = self.self_arc();
         let name_clone = name.clone();
-        NOD_RT
-            .spawn(async move {
-                let mut attr: FileAttr = create_attr.into();
-                attr.ino = self_clone.generate_next_inode();
-
-                let fs = self_clone;
-                let mut join_set = JoinSet::new();
-
-                // write inode
-                let self_clone = fs.clone();
-                self_clone.write_inode_to_storage(&attr).await?;
-
-                match attr.kind {
-                    FileType::RegularFile => {
-                        let self_clone = fs.clone();
-                        join_set.spawn(async move {
-                            // create in contents directory
-                            let file = File::create(self_clone.contents_path(attr.ino))?;
-                            // sync_all file and parent
-                            // these operations are a bit slow, but are necessary to make sure the file is correctly created
-                            // i.e. creating 100 files takes 0.965 sec with sync_all and 0.130 sec without
-                            file.sync_all()?;
-                            File::open(
-                                self_clone
-                                    .contents_path(attr.ino)
-                                    .parent()
-                                    .expect("oops, we don't have a parent"),
-                            )?
-                            .sync_all()?;
-                            Ok::<(), FsError>(())
-                        });
-                    }
-                    FileType::Directory => {
-                        let self_clone = fs.clone();
-                        let attr_clone = attr;
-                        join_set.spawn(async move {
-                            // create in contents directory
-                            let contents_dir = self_clone.contents_path(attr.ino);
-                            fs::create_dir(contents_dir.clone())?;
-                            // used to keep encrypted file names used by [`read_dir`] and [`read_dir_plus`]
-                            fs::create_dir(contents_dir.join(LS_DIR))?;
-                            // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
-                            // this optimizes the search process as we don't need to decrypt all file names and search
-                            fs::create_dir(contents_dir.join(HASH_DIR))?;
-
-                            // add "." and ".." entries
-                            self_clone
-                                .insert_directory_entry(
-                                    attr_clone.ino,
-                                    &DirectoryEntry {
-                                        ino: attr_clone.ino,
-                                        name: SecretString::new(Box::new("$.".into())),
-                                        kind: FileType::Directory,
-                                    },
-                                )
-                                .await?;
+        self.spawn_on(&NOD_RT, async move {
+            let mut attr: FileAttr = create_attr.into();
+            attr.ino = self_clone.generate_next_inode()?;
+            if matches!(attr.kind, FileType::RegularFile | FileType::Symlink) {
+                attr.file_key = Some(FileKey::generate());
+            }
+            if attr.kind == FileType::RegularFile {
+                // the content is empty, there are no blocks
+                attr.content_mac = Some(crypto::content_mac(
+                    &mut io::empty(),
+                    attr.ino,
+                    0,
+                    0,
+                    0,
+                    &content_key(attr.file_key, &*self_clone.key().await?),
+                )?);
+            }
+
+            let fs = self_clone;
+            let mut join_set = JoinSet::new();
+
+            // write inode
+            let self_clone = fs.clone();
+            self_clone.write_inode_to_storage(&attr).await?;
+
+            match attr.kind {
+                // the target of a symlink is written by `create_symlink`
+                FileType::RegularFile | FileType::Symlink => {
+                    let self_clone = fs.clone();
+                    join_set.spawn(async move {
+                        // create in contents directory
+                        let path = self_clone.contents_path(attr.ino);
+                        self_clone.ciphers.for_write(&path)?;
+                        let file = self_clone.storage.create(&path)?;
+                        // sync_all file and parent
+                        // these operations are a bit slow, but are necessary to make sure the file is correctly created
+                        // i.e. creating 100 files takes 0.965 sec with sync_all and 0.130 sec without
+                        file.sync_all()?;
+                        if let Some(transform) = self_clone.content_transform() {
+                            // keep the transform in the file metadata, so we know how to read it
+                            let path = self_clone.content_transform_path(attr.ino);
+                            atomic_serialize_encrypt_into(
+                                &*self_clone.storage,
+                                &path,
+                                &transform.id(),
+                                self_clone.ciphers.for_write(&path)?,
+                                &*self_clone.key().await?,
+                            )?;
+                        }
+                        self_clone.storage.sync_dir(
                             self_clone
-                                .insert_directory_entry(
-                                    attr_clone.ino,
-                                    &DirectoryEntry {
-                                        ino: parent,
-                                        name: SecretString::new(Box::new("$..".into())),
-                                        kind: FileType::Directory,
-                                    },
-                                )
-                                .await?;
-                            Ok::<(), FsError>(())
-                        });
-                    }
+                                .contents_path(attr.ino)
+                                .parent()
+                                .expect("oops, we don't have a parent"),
+                        )?;
+                        Ok::<(), FsError>(())
+                    });
+                }
+                FileType::Directory => {
+                    let self_clone = fs.clone();
+                    let attr_clone = attr;
+                    join_set.spawn(async move {
+                        // create in contents directory
+                        let contents_dir = self_clone.contents_path(attr.ino);
+                        self_clone.storage.create_dir(&contents_dir)?;
+                        // used to keep encrypted file names used by [`read_dir`] and [`read_dir_plus`]
+                        self_clone.storage.create_dir(&contents_dir.join(LS_DIR))?;
+                        // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
+                        // this optimizes the search process as we don't need to decrypt all file names and search
+                        self_clone
+                            .storage
+                            .create_dir(&contents_dir.join(HASH_DIR))?;
+
+                        // add "." and ".." entries
+                        self_clone
+                            .insert_directory_entry(
+                                attr_clone.ino,
+                                &DirectoryEntry {
+                                    ino: attr_clone.ino,
+                                    name: SecretString::new(Box::new("$.".into())),
+                                    kind: FileType::Directory,
+                                },
+                            )
+                            .await?;
+                        self_clone
+                            .insert_directory_entry(
+                                attr_clone.ino,
+                                &DirectoryEntry {
+                                    ino: parent,
+                                    name: SecretString::new(Box::new("$..".into())),
+                                    kind: FileType::Directory,
+                                },
+                            )
+                            .await?;
+                        Ok::<(), FsError>(())
+                    });
                 }
+            }
 
-                // edd entry in parent directory, used for listing
-                let self_clone = fs.clone();
-                let attr_clone = attr;
-                join_set.spawn(async move {
-                    self_clone
-                        .insert_directory_entry(
-                            parent,
-                            &DirectoryEntry {
-                                ino: attr_clone.ino,
-                                name: name_clone,
-                                kind: attr_clone.kind,
-                            },
-                        )
-                        .await?;
-                    Ok::<(), FsError>(())
-                });
+            // edd entry in parent directory, used for listing
+            let self_clone = fs.clone();
+            let attr_clone = attr;
+            join_set.spawn(async move {
+                self_clone
+                    .insert_directory_entry(
+                        parent,
+                        &DirectoryEntry {
+                            ino: attr_clone.ino,
+                            name: name_clone,
+                            kind: attr_clone.kind,
+                        },
+                    )
+                    .await?;
+                Ok::<(), FsError>(())
+            });
 
-                let self_clone = fs.clone();
-                join_set.spawn(async move {
-                    let now = SystemTime::now();
-                    self_clone
-                        .set_attr(
-                            parent,
-                            SetFileAttr::default()
-                                .with_mtime(now)
-                                .with_ctime(now)
-                                .with_atime(now),
-                        )
-                        .await?;
-                    Ok::<(), FsError>(())
-                });
+            let self_clone = fs.clone();
+            join_set.spawn(async move {
+                let now = SystemTime::now();
+                self_clone
+                    .set_attr(
+                        parent,
+                        SetFileAttr::default()
+                            .with_mtime(now)
+                            .with_ctime(now)
+                            .with_atime(now),
+                    )
+                    .await?;
+                Ok::<(), FsError>(())
+            });
 
-                // wait for all tasks to finish
-                while let Some(res) = join_set.join_next().await {
-                    res??;
-                }
+            // wait for all tasks to finish
+            while let Some(res) = join_set.join_next().await {
+                res??;
+            }
+            if attr.kind == FileType::Directory {
+                // the `..` of the new directory links to the parent
+                fs.change_nlink(parent, 1).await?;
+            }
 
-                let self_clone = fs.clone();
-                let handle = if attr.kind == FileType::RegularFile {
-                    if read || write {
-                        self_clone.open(attr.ino, read, write).await?
-                    } else {
-                        // we don't create a handle for files that are not opened
-                        0
-                    }
+            let self_clone = fs.clone();
+            let handle = if attr.kind == FileType::RegularFile {
+                if read || write {
+                    self_clone.open(attr.ino, read, write).await?
                 } else {
-                    // we don't use a handle for directories
+                    // we don't create a handle for files that are not opened
                     0
-                };
+                }
+            } else {
+                // we don't use a handle for directories
+                0
+            };
 
-                Ok((handle, attr))
-            })
-            .await?
+            Ok((handle, attr))
+        })
+        .await?
+    }
+
+    /// Create a symbolic link to `target`, it's encrypted like the content of files.
+    ///
+    /// The target is kept as it is, relative or absolute, and it's not checked, so it can point to something
+    /// which doesn't exist.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_symlink(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        target: &SecretString,
+    ) -> FsResult<FileAttr> {
+        if target.expose_secret().is_empty() {
+            return Err(FsError::InvalidInput("symlink target cannot be empty"));
+        }
+        let create_attr = CreateFileAttr {
+            kind: FileType::Symlink,
+            perm: 0o777,
+            uid: *crate::UID,
+            gid: *crate::GID,
+            rdev: 0,
+            flags: 0,
+        };
+        let (_, mut attr) = self.create(parent, name, create_attr, false, false).await?;
+
+        let file = self.storage.open_write(&self.contents_path(attr.ino))?;
+        let mut writer = self.create_content_write(attr.ino, file).await?;
+        writer.write_all(target.expose_secret().as_bytes())?;
+        let file = writer.finish()?;
+        file.sync_all()?;
+        attr.size = target.expose_secret().len() as u64;
+        self.write_inode_to_storage(&attr).await?;
+        Ok(attr)
+    }
+
+    /// Create many regular files with their content in `parent`, like when extracting an archive.
+    ///
+    /// Much faster than [`EncryptedFs::create`] and [`EncryptedFs::write`] for each file, as nothing is synced
+    /// to disk until all of them are written, then everything is synced once, and the parent is updated once.
+    /// The tradeoff is durability: if we crash before the end any of the files might be missing, and
+    /// [`EncryptedFs::verify`] could find leftovers of the ones not finished. A file is visible in `parent` only
+    /// after its content and inode are written.
+    ///
+    /// All names are checked before anything is created, if one is invalid or already exists nothing is created.
+    /// Returns the attributes of the files in the same order.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn create_many(
+        &self,
+        parent: u64,
+        entries: Vec<(SecretString, CreateFileAttr, Vec<u8>)>,
+    ) -> FsResult<Vec<FileAttr>> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.is_dir(parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let mut names = HashSet::new();
+        for (name, create_attr, _) in &entries {
+            if create_attr.kind != FileType::RegularFile {
+                return Err(FsError::InvalidInput(
+                    "only regular files can be created in a batch",
+                ));
+            }
+            if *name.expose_secret() == "." || *name.expose_secret() == ".." {
+                return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+            }
+            self.validate_filename(name)?;
+            if !names.insert(name.expose_secret().to_string())
+                || self.exists_by_name(parent, name)?
+            {
+                return Err(FsError::AlreadyExists);
+            }
+        }
+        drop(names);
+        let reservation = self
+            .quota
+            .reserve(entries.iter().map(|(_, _, data)| data.len() as u64).sum())?;
+
+        let key = self.key().await?;
+        let parent_path = self.contents_path(parent);
+        let mut written = vec![];
+        let mut attrs = Vec::with_capacity(entries.len());
+        for (name, create_attr, mut data) in entries {
+            let mut attr: FileAttr = create_attr.into();
+            attr.ino = self.generate_next_inode()?;
+            attr.size = data.len() as u64;
+            attr.file_key = Some(FileKey::generate());
+
+            // content
+            let transform = self.content_transform();
+            if let Some(transform) = &transform {
+                let path = self.content_transform_path(attr.ino);
+                crypto::serialize_encrypt_into(
+                    self.storage.create_new(&path)?,
+                    &transform.id(),
+                    self.ciphers.for_write(&path)?,
+                    &key,
+                )?;
+                written.push(path);
+            }
+            let path = self.contents_path(attr.ino);
+            let mut writer = crypto::create_write_with_transform(
+                self.storage.create_new(&path)?,
+                self.ciphers.for_write(&path)?,
+                &content_key(attr.file_key, &key),
+                transform,
+                self.nonce_counter(),
+            );
+            let res = writer.write_all(&data);
+            data.zeroize();
+            res?;
+            writer.finish()?;
+            written.push(path);
+            attr.content_mac = Some(self.content_mac(&self.data_dir, &attr, &key)?);
+
+            // inode
+            if let Some(path) = self.inodes.write_new_unsynced(&attr, &key)? {
+                written.push(path);
+            }
+            if let Some(lock) = self.attr_cache().await? {
+                lock.write().await.put(attr.ino, attr);
+            }
+
+            // entry in parent, the listing last so it's not listed without the rest
+            let (ls_path, ls_cipher) = self.ciphers.for_new_path(|cipher| {
+                let name = crypto::encrypt_file_name(&name, cipher, &key, self.name_padding())?;
+                Ok(parent_path.join(LS_DIR).join(name))
+            })?;
+            let encrypted_name = ls_path.file_name().unwrap().to_str().unwrap().to_owned();
+            let hash_path = parent_path.join(HASH_DIR).join(self.hash_file_name(&name));
+            crypto::serialize_encrypt_into(
+                self.storage.create_new(&hash_path)?,
+                &(attr.ino, attr.kind, encrypted_name),
+                self.ciphers.for_write(&hash_path)?,
+                &key,
+            )?;
+            written.push(hash_path);
+            crypto::serialize_encrypt_into(
+                self.storage.create_new(&ls_path)?,
+                &(attr.ino, attr.kind),
+                ls_cipher,
+                &key,
+            )?;
+            if let Some(cache) = self.dir_entries_meta_cache().await? {
+                cache
+                    .lock()
+                    .await
+                    .put(ls_path.to_str().unwrap().to_owned(), (attr.ino, attr.kind));
+            }
+            written.push(ls_path);
+            attrs.push(attr);
+        }
+        self.inodes.sync()?;
+        self.storage.sync_paths(&self.data_dir, &written)?;
+        reservation.keep(attrs.iter().map(|attr| attr.size).sum());
+
+        let now = SystemTime::now();
+        self.set_attr(
+            parent,
+            SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now),
+        )
+        .await?;
+        Ok(attrs)
+    }
+
+    /// Target of a symbolic link created with [`EncryptedFs::create_symlink`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_link(&self, ino: u64) -> FsResult<SecretString> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind != FileType::Symlink {
+            return Err(FsError::InvalidInodeType);
+        }
+        let mut reader = self.create_content_read(ino).await?;
+        let mut target = vec![];
+        reader.read_to_end(&mut target)?;
+        let target = String::from_utf8(target).map_err(|err| {
+            err.into_bytes().zeroize();
+            FsError::Other("symlink target is not valid UTF-8")
+        })?;
+        Ok(SecretString::new(Box::new(target)))
     }
 
     #[allow(clippy::missing_panics_doc)]
@@ -843,9 +2508,17 @@ impl EncryptedFs {
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
+        if parent == ROOT_INODE && *name.expose_secret() == ".." {
+            // root has no parent inside the fs, like `/..` it resolves to itself, so lookups
+            // of `..` from the mount root don't end up in an internal inode or error
+            return self
+                .get_inode_from_cache_or_storage(ROOT_INODE)
+                .await
+                .map(Some);
+        }
+        let hash = self.hash_file_name(name);
         let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        if !hash_path.is_file() {
+        if !self.storage.is_file(&hash_path) {
             return Ok(None);
         }
         let lock = self
@@ -854,1308 +2527,4859 @@ impl EncryptedFs {
                 RwLock::new(false)
             });
         let guard = lock.read().await;
-        let (ino, _, _): (u64, FileType, String) = bincode::deserialize_from(crypto::create_read(
-            File::open(hash_path)?,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?;
+        let (file, cipher) = self.ciphers.open(&hash_path)?;
+        let (ino, _, _): (u64, FileType, String) = bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &*self.key().await?),
+            bincode_util::METADATA_LIMIT,
+        )?;
         drop(guard);
         self.get_inode_from_cache_or_storage(ino).await.map(Some)
     }
 
-    /// Count children of a directory. This **EXCLUDES** "." and "..".
+    /// Resolve a `/` separated path starting from root, like `/dir/file`.
+    ///
+    /// The leading `/` is optional, empty components, `.` and trailing slashes are ignored and `..` goes to the parent.
+    /// Symlinks are not followed. Fails with [`FsError::NotFound`] if a component doesn't exist.
     #[allow(clippy::missing_errors_doc)]
-    pub fn len(&self, ino: u64) -> FsResult<usize> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        let mut count = fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count();
-        if ino == ROOT_INODE {
-            // we don't count "."
-            count -= 1;
-        } else {
-            // we don't count "." and ".."
-            count -= 2;
+    pub async fn lookup_path(&self, path: &str) -> FsResult<FileAttr> {
+        let mut attr = self.get_inode_from_cache_or_storage(ROOT_INODE).await?;
+        for name in path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            if attr.kind != FileType::Directory {
+                return Err(FsError::InvalidInodeType);
+            }
+            attr = self
+                .find_by_name(attr.ino, &SecretString::from_str(name).unwrap())
+                .await?
+                .ok_or(FsError::NotFound("path not found"))?;
         }
-        Ok(count)
+        Ok(attr)
     }
 
-    /// Delete a directory
-    #[allow(clippy::missing_panics_doc)]
+    /// Resolve the parent of a `/` separated path like [`EncryptedFs::lookup_path`], returning its inode and the
+    /// last component, which doesn't need to exist. Useful for [`EncryptedFs::create`] and alike.
+    ///
+    /// Fails with [`FsError::InvalidInput`] if the path doesn't end with a name, like `/` or `dir/..`.
     #[allow(clippy::missing_errors_doc)]
-    pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn lookup_path_parent(&self, path: &str) -> FsResult<(u64, SecretString)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(FsError::InvalidInput("path must end with a name"));
         }
-        if !self.is_dir(parent) {
+        let parent = self.lookup_path(parent).await?;
+        if parent.kind != FileType::Directory {
             return Err(FsError::InvalidInodeType);
         }
+        Ok((parent.ino, SecretString::from_str(name).unwrap()))
+    }
 
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
+    /// Verify the directory tree and repair what's wrong.
+    ///
+    /// Every directory must have a `$.` entry pointing to itself and, except root, a `$..` entry pointing to
+    /// the directory which lists it. Wrong or missing entries are rewritten, in read-only mode they are only reported.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn check(&self) -> FsResult<CheckReport> {
+        let mut report = CheckReport::default();
+        let mut visited = HashSet::new();
+        // (dir, parent)
+        let mut queue = VecDeque::from([(ROOT_INODE, None)]);
+        while let Some((ino, parent)) = queue.pop_front() {
+            if !visited.insert(ino) {
+                continue;
+            }
+            if !self.is_special_entry_valid(ino, "$.", ino).await? {
+                report.wrong_self_entries.push(ino);
+                if !self.read_only {
+                    self.insert_special_entry(ino, "$.", ino).await?;
+                }
+            }
+            if let Some(parent) = parent {
+                if !self.is_special_entry_valid(ino, "$..", parent).await? {
+                    report.wrong_parent_entries.push(ino);
+                    if !self.read_only {
+                        self.insert_special_entry(ino, "$..", parent).await?;
+                    }
+                }
+            }
+            let ls_dir = self.contents_path(ino).join(LS_DIR);
+            for name in self.storage.read_dir(&ls_dir)? {
+                if name == "$." || name == "$.." {
+                    continue;
+                }
+                let (file, cipher) = self.ciphers.open(&ls_dir.join(name))?;
+                let (child, kind): (u64, FileType) = bincode_util::deserialize_from(
+                    crypto::create_read(file, cipher, &*self.key().await?),
+                    bincode_util::METADATA_LIMIT,
+                )?;
+                if kind == FileType::Directory {
+                    queue.push_back((child, Some(ino)));
+                }
+            }
         }
+        report.repaired = !self.read_only && !report.is_clean();
+        Ok(report)
+    }
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::Directory) {
-            return Err(FsError::InvalidInodeType);
-        }
-        // check if it's empty
-        if self.len(attr.ino)? > 0 {
-            return Err(FsError::NotEmpty);
+    /// Checks both the `ls` and `hash` files of a `$.` or `$..` entry point to `expected_ino`.
+    async fn is_special_entry_valid(
+        &self,
+        ino: u64,
+        name: &str,
+        expected_ino: u64,
+    ) -> FsResult<bool> {
+        let ls_path = self.contents_path(ino).join(LS_DIR).join(name);
+        let hash_path = self.contents_path(ino).join(HASH_DIR).join(name);
+        if !self.storage.is_file(&ls_path) || !self.storage.is_file(&hash_path) {
+            return Ok(false);
         }
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
-        let name_clone = name.clone();
-        NOD_RT
-            .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
-
-                // remove contents directory
-                fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
-                // remove from parent directory
-                self_clone
-                    .remove_directory_entry(parent, &name_clone)
-                    .await?;
-                // remove from cache
-                self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
-
-                let now = SystemTime::now();
-                self_clone
-                    .set_attr(
-                        parent,
-                        SetFileAttr::default()
-                            .with_mtime(now)
-                            .with_ctime(now)
-                            .with_atime(now),
-                    )
-                    .await?;
+        let key = self.key().await?;
+        let (file, cipher) = self.ciphers.open(&ls_path)?;
+        let ls: bincode::Result<(u64, FileType)> = bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &key),
+            bincode_util::METADATA_LIMIT,
+        );
+        let (file, cipher) = self.ciphers.open(&hash_path)?;
+        let hash: bincode::Result<(u64, FileType, String)> = bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &key),
+            bincode_util::METADATA_LIMIT,
+        );
+        Ok(
+            matches!(ls, Ok((ls_ino, FileType::Directory)) if ls_ino == expected_ino)
+                && matches!(hash, Ok((hash_ino, FileType::Directory, _)) if hash_ino == expected_ino),
+        )
+    }
 
-                Ok(())
-            })
-            .await?
+    async fn insert_special_entry(&self, ino: u64, name: &str, target_ino: u64) -> FsResult<()> {
+        warn!(ino, name, target_ino, "repairing directory entry");
+        self.insert_directory_entry(
+            ino,
+            &DirectoryEntry {
+                ino: target_ino,
+                name: SecretString::from_str(name).unwrap(),
+                kind: FileType::Directory,
+            },
+        )
+        .await
     }
 
-    /// Delete a file
+    /// Check the cipher and the key work before trusting the volume with real data.
+    ///
+    /// A file is created in root with a random hidden name, written at a few offsets, across blocks and after a gap,
+    /// then opened again and read back. It fails with [`FsError::SelfTestFailed`] if the content doesn't match.
+    /// The file is removed after, also when the test fails.
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+    pub async fn self_test(&self) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
-        }
+        let root = self.get_attr(ROOT_INODE).await?;
+        let name =
+            SecretString::from_str(&format!(".rencfs-self-test-{:016x}", rand::random::<u64>()))
+                .unwrap();
+        let create_attr = CreateFileAttr {
+            kind: FileType::RegularFile,
+            perm: 0o600,
+            uid: root.uid,
+            gid: root.gid,
+            rdev: 0,
+            flags: 0,
+        };
+        let (ino, _) = self
+            .create(ROOT_INODE, &name, create_attr, false, false)
+            .await?;
+        let res = self.self_test_file(ino).await;
+        let removed = self.remove_file(ROOT_INODE, &name).await;
+        res?;
+        removed
+    }
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
-            return Err(FsError::InvalidInodeType);
+    async fn self_test_file(&self, ino: u64) -> FsResult<()> {
+        let block = BLOCK_SIZE as u64;
+        // after a gap, at the start, across two blocks and over what was written before
+        let writes = [
+            (2 * block + 7, BLOCK_SIZE),
+            (0, 100),
+            (block - 10, 20),
+            (50, 100),
+        ];
+        let mut expected = vec![0; 3 * BLOCK_SIZE + 7];
+        let mut rng = crypto::create_rng();
+        let handle = self.open(ino, false, true).await?;
+        let written = async {
+            for (offset, len) in writes {
+                let data = &mut expected[offset as usize..offset as usize + len];
+                rng.fill_bytes(data);
+                if self.write(ino, offset, data, handle).await? != len {
+                    return Err(FsError::SelfTestFailed(format!(
+                        "short write of {len} bytes at offset {offset}"
+                    )));
+                }
+            }
+            self.flush(handle).await
         }
-        // todo move to method
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
-        let name_clone = name.clone();
-        NOD_RT
-            .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
+        .await;
+        let released = self.release(handle).await;
+        written?;
+        released?;
+
+        // one more byte, to check it ends where it should
+        let mut buf = vec![0; expected.len() + 1];
+        let handle = self.open(ino, true, false).await?;
+        let read = async {
+            let mut len = 0;
+            while len < buf.len() {
+                let n = self.read(ino, len as u64, &mut buf[len..], handle).await?;
+                if n == 0 {
+                    break;
                 }
-
-                // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
-                // remove from parent directory
-                self_clone
-                    .remove_directory_entry(parent, &name_clone)
-                    .await?;
-                // remove from cache
-                self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
-
-                let now = SystemTime::now();
-                self_clone
-                    .set_attr(
-                        parent,
-                        SetFileAttr::default()
-                            .with_mtime(now)
-                            .with_ctime(now)
-                            .with_atime(now),
-                    )
-                    .await?;
-
-                Ok(())
-            })
-            .await?
-    }
-
-    #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::missing_errors_doc)]
-    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+                len += n;
+            }
+            Ok::<_, FsError>(len)
         }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+        .await;
+        let released = self.release(handle).await;
+        let len = read?;
+        released?;
+
+        if len != expected.len() {
+            return Err(FsError::SelfTestFailed(format!(
+                "read {len} bytes instead of {}",
+                expected.len()
+            )));
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        Ok(hash_path.is_file())
+        if let Some(offset) = buf.iter().zip(&expected).position(|(a, b)| a != b) {
+            return Err(FsError::SelfTestFailed(format!(
+                "content read differs from the one written at offset {offset}"
+            )));
+        }
+        Ok(())
     }
 
+    /// Walk all inodes and directory entries and report what's inconsistent, nothing is changed.
+    ///
+    /// Every inode must decrypt and have `contents/<ino>` matching its kind, every `ls` entry must have its `hash`
+    /// counterpart and the other way around, entries must point to existing inodes, `$.` and `$..` of directories
+    /// must point to themselves and to the directory which lists them, and every inode must be reachable from root.
+    /// Inodes removed but still opened are not reported as orphaned, other changes made meanwhile might be, so
+    /// it's best to run this when the filesystem is idle. Use [`EncryptedFs::repair`] to fix what was found.
     #[allow(clippy::missing_errors_doc)]
-    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
-            return Err(FsError::InvalidInodeType);
+    pub async fn verify(&self) -> FsResult<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let key = self.key().await?;
+
+        let mut inodes = HashSet::new();
+        let mut undecryptable_inodes = HashSet::new();
+        for ino in self.inodes.inos()? {
+            inodes.insert(ino);
+            let attr = match self.get_inode_from_storage(ino).await {
+                Ok(attr) => attr,
+                Err(err) => {
+                    warn!(ino, err = %err, "undecryptable inode");
+                    report.undecryptable.push(self.ino_file(ino));
+                    undecryptable_inodes.insert(ino);
+                    continue;
+                }
+            };
+            let contents = self.contents_path(ino);
+            let valid = match attr.kind {
+                FileType::Directory => {
+                    self.storage.is_dir(&contents.join(LS_DIR))
+                        && self.storage.is_dir(&contents.join(HASH_DIR))
+                }
+                FileType::RegularFile | FileType::Symlink => self.storage.is_file(&contents),
+            };
+            if !valid {
+                report.invalid_contents.push(ino);
+            }
         }
-
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_iterator(iter).await)
-    }
-
-    /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
-    pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
-            return Err(FsError::InvalidInodeType);
+        let mut orphaned_contents = BTreeSet::new();
+        for name in self.storage.read_dir(&self.data_dir.join(CONTENTS_DIR))? {
+            let name = name.to_string_lossy().to_string();
+            let name = name
+                .strip_suffix(CONTENT_TRANSFORM_SUFFIX)
+                .or_else(|| name.strip_suffix(CONTENT_HOLES_SUFFIX))
+                .unwrap_or(&name);
+            // skip temp files
+            if let Ok(ino) = name.parse::<u64>() {
+                if !inodes.contains(&ino) {
+                    orphaned_contents.insert(ino);
+                }
+            }
         }
-
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_plus_iterator(iter).await)
-    }
-
-    async fn create_directory_entry_plus(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntryPlus> {
-        let entry = self.create_directory_entry(entry).await?;
-        let lock = self.serialize_inode_locks.clone();
-        let lock_ino = lock.get_or_insert_with(entry.ino, || RwLock::new(false));
-        let _ino_guard = lock_ino.read();
-        let attr = self.get_inode_from_cache_or_storage(entry.ino).await?;
-        Ok(DirectoryEntryPlus {
-            ino: entry.ino,
-            name: entry.name,
-            kind: entry.kind,
-            attr,
-        })
-    }
-
-    async fn create_directory_entry_plus_iterator(
-        &self,
-        read_dir: ReadDir,
-    ) -> DirectoryEntryPlusIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
-            .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
+        report.orphaned_contents = orphaned_contents.into_iter().collect();
+
+        let mut reachable = HashSet::from([ROOT_INODE]);
+        // (dir, parent)
+        let mut queue = VecDeque::from([(ROOT_INODE, None)]);
+        while let Some((ino, parent)) = queue.pop_front() {
+            let ls_dir = self.contents_path(ino).join(LS_DIR);
+            let hash_dir = self.contents_path(ino).join(HASH_DIR);
+            if !self.storage.is_dir(&ls_dir) || !self.storage.is_dir(&hash_dir) {
+                // already reported as invalid contents
+                continue;
+            }
+            if !self.is_special_entry_valid(ino, "$.", ino).await? {
+                report.wrong_self_entries.push(ino);
+            }
+            if let Some(parent) = parent {
+                if !self.is_special_entry_valid(ino, "$..", parent).await? {
+                    report.wrong_parent_entries.push((ino, parent));
+                }
+            }
+            let mut subdirs = 0;
+            for file_name in self.storage.read_dir(&ls_dir)? {
+                if file_name == "$." || file_name == "$.." {
+                    continue;
+                }
+                let path = ls_dir.join(file_name);
+                let (Ok((child, kind)), Ok(name)) = (
+                    self.read_ls_entry(&path, &key),
+                    self.ls_entry_name(&path, &key),
+                ) else {
+                    warn!(ino, "undecryptable directory entry");
+                    report.undecryptable.push(path);
+                    continue;
                 };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry_plus(entry).await })
-            })
-            .collect();
+                let reason = if !inodes.contains(&child) {
+                    Some(DanglingReason::MissingInode)
+                } else if !self
+                    .storage
+                    .is_file(&hash_dir.join(self.hash_file_name(&name)))
+                {
+                    Some(DanglingReason::MissingHash)
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    report.dangling_entries.push(DanglingEntry {
+                        dir: ino,
+                        path,
+                        reason,
+                    });
+                }
+                if !inodes.contains(&child) {
+                    continue;
+                }
+                if kind == FileType::Directory {
+                    subdirs += 1;
+                }
+                // a directory can be listed only once, a file can have more hard links
+                if reachable.insert(child) && kind == FileType::Directory {
+                    queue.push_back((child, Some(ino)));
+                }
+            }
+            for file_name in self.storage.read_dir(&hash_dir)? {
+                if file_name == "$." || file_name == "$.." {
+                    continue;
+                }
+                let path = hash_dir.join(file_name);
+                let Ok((_, _, ls_name)) = self.read_hash_entry(&path, &key) else {
+                    warn!(ino, "undecryptable directory entry");
+                    report.undecryptable.push(path);
+                    continue;
+                };
+                if !self.storage.is_file(&ls_dir.join(ls_name)) {
+                    report.dangling_entries.push(DanglingEntry {
+                        dir: ino,
+                        path,
+                        reason: DanglingReason::MissingLs,
+                    });
+                }
+            }
+            // an undecryptable inode is already reported
+            if let Ok(attr) = self.get_inode_from_storage(ino).await {
+                if attr.nlink != subdirs + 2 {
+                    report.wrong_nlinks.push((ino, subdirs + 2));
+                }
+            }
+        }
 
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
+        for ino in inodes {
+            // undecryptable ones are left for manual recovery, don't have them removed by `repair`
+            if !reachable.contains(&ino)
+                && !undecryptable_inodes.contains(&ino)
+                && !self.is_opened(ino).await
+            {
+                report.orphaned_inodes.push(ino);
+            }
         }
-        DirectoryEntryPlusIterator(res)
+        report.orphaned_inodes.sort_unstable();
+        report.invalid_contents.sort_unstable();
+        report.wrong_nlinks.sort_unstable();
+        Ok(report)
     }
 
-    async fn create_directory_entry(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntry> {
-        if entry.is_err() {
-            return Err(entry.err().unwrap().into());
+    /// Fix what [`EncryptedFs::verify`] found.
+    ///
+    /// Wrong `$.` and `$..` entries are rewritten and an `ls` entry missing its `hash` counterpart gets it back.
+    /// The links of directories are set to match their subdirectories.
+    /// Entries pointing to missing inodes, orphaned `hash` entries, orphaned inodes and orphaned contents are
+    /// removed, as there is no way to reach them anymore. Undecryptable files and inodes with invalid contents
+    /// are left as they are, they need to be recovered manually.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn repair(&self, report: &VerifyReport) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
         }
-        if let Err(e) = entry {
-            error!(err = %e, "reading directory entry");
-            return Err(e.into());
+        let key = self.key().await?;
+        for ino in &report.wrong_self_entries {
+            self.insert_special_entry(*ino, "$.", *ino).await?;
         }
-        let entry = entry.unwrap();
-        let name = entry.file_name().to_string_lossy().to_string();
-        let name = {
-            if name == "$." {
-                SecretString::new(Box::new(".".into()))
-            } else if name == "$.." {
-                SecretString::from_str("..").unwrap()
-            } else {
-                // try from cache
-                let lock = self.get_dir_entries_name_cache().await?;
-                let mut cache = lock.lock().await;
-                if let Some(name_cached) = cache.get(&name).cloned() {
-                    name_cached
-                } else {
-                    drop(cache);
-                    if let Ok(decrypted_name) =
-                        crypto::decrypt_file_name(&name, self.cipher, &*self.key.get().await?)
-                            .map_err(|err| {
-                                error!(err = %err, "decrypting file name");
-                                err
-                            })
-                    {
-                        lock.lock().await.put(name.clone(), decrypted_name.clone());
-                        decrypted_name
+        for (ino, parent) in &report.wrong_parent_entries {
+            self.insert_special_entry(*ino, "$..", *parent).await?;
+        }
+        for (ino, nlink) in &report.wrong_nlinks {
+            let lock = self
+                .serialize_update_inode_locks
+                .get_or_insert_with(*ino, || Mutex::new(false));
+            let _guard = lock.lock().await;
+            let mut attr = match self.get_inode_from_storage(*ino).await {
+                Ok(attr) => attr,
+                // removed meanwhile
+                Err(FsError::InodeNotFound) => continue,
+                Err(err) => return Err(err),
+            };
+            warn!(ino, nlink, "repairing links of directory");
+            attr.nlink = *nlink;
+            self.write_inode_to_storage(&attr).await?;
+        }
+        for entry in &report.dangling_entries {
+            if !self.storage.is_file(&entry.path) {
+                // fixed meanwhile
+                continue;
+            }
+            match entry.reason {
+                DanglingReason::MissingInode => {
+                    let name = self.ls_entry_name(&entry.path, &key)?;
+                    let hash_path = self
+                        .contents_path(entry.dir)
+                        .join(HASH_DIR)
+                        .join(self.hash_file_name(&name));
+                    warn!(dir = entry.dir, "removing dangling directory entry");
+                    if self.storage.is_file(&hash_path) {
+                        self.remove_directory_entry(entry.dir, &name).await?;
                     } else {
-                        return Err(FsError::InvalidInput("invalid file name"));
+                        self.storage.remove_file(&entry.path)?;
                     }
                 }
+                DanglingReason::MissingHash => {
+                    let (ino, kind) = self.read_ls_entry(&entry.path, &key)?;
+                    let name = self.ls_entry_name(&entry.path, &key)?;
+                    warn!(dir = entry.dir, ino, "repairing directory entry");
+                    self.insert_directory_entry(entry.dir, &DirectoryEntry { ino, name, kind })
+                        .await?;
+                }
+                DanglingReason::MissingLs => {
+                    warn!(dir = entry.dir, "removing dangling directory entry");
+                    self.storage.remove_file(&entry.path)?;
+                }
             }
-        };
-
-        self.validate_filename(&name)?;
-
-        let file_path = entry.path().to_str().unwrap().to_owned();
-        // try from cache
-        let lock = self.dir_entries_meta_cache.get().await?;
-        let mut cache = lock.lock().await;
-        if let Some((ino, kind)) = cache.get(&file_path) {
-            return Ok(DirectoryEntry {
-                ino: *ino,
-                name,
-                kind: *kind,
-            });
         }
-        drop(cache);
-        let lock = self
-            .serialize_dir_entries_ls_locks
-            .get_or_insert_with(file_path.clone(), || RwLock::new(false));
-        let guard = lock.read().await;
-        let file = File::open(entry.path())?;
-        let res: bincode::Result<(u64, FileType)> = bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ));
-        drop(guard);
-        if let Err(e) = res {
-            error!(err = %e, "deserializing directory entry");
-            return Err(e.into());
+        for ino in &report.orphaned_inodes {
+            if self.is_opened(*ino).await {
+                continue;
+            }
+            warn!(ino, "removing orphaned inode");
+            self.remove_inode_storage(*ino).await?;
         }
-        let (ino, kind): (u64, FileType) = res.unwrap();
-        // add to cache
-        self.dir_entries_meta_cache
-            .get()
-            .await?
-            .lock()
-            .await
-            .put(file_path, (ino, kind));
-        Ok(DirectoryEntry { ino, name, kind })
+        for ino in &report.orphaned_contents {
+            if self.exists(*ino) {
+                continue;
+            }
+            warn!(ino, "removing orphaned contents");
+            let contents_path = self.contents_path(*ino);
+            if self.storage.is_dir(&contents_path) {
+                self.storage.remove_dir_all(&contents_path)?;
+            } else if self.storage.exists(&contents_path) {
+                self.storage.remove_file(&contents_path)?;
+            }
+            let transform_path = self.content_transform_path(*ino);
+            if self.storage.exists(&transform_path) {
+                self.storage.remove_file(&transform_path)?;
+            }
+            let holes_path = self.content_holes_path(*ino);
+            if self.storage.exists(&holes_path) {
+                self.storage.remove_file(&holes_path)?;
+            }
+        }
+        Ok(())
     }
 
-    async fn get_dir_entries_name_cache(
-        &self,
-    ) -> FsResult<Arc<Mutex<LruCache<String, SecretString>>>> {
-        self.dir_entries_name_cache.get().await
+    fn read_ls_entry(&self, path: &Path, key: &SecretVec<u8>) -> FsResult<(u64, FileType)> {
+        let (file, cipher) = self.ciphers.open(path)?;
+        Ok(bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, key),
+            bincode_util::METADATA_LIMIT,
+        )?)
     }
 
-    async fn create_directory_entry_iterator(&self, read_dir: ReadDir) -> DirectoryEntryIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
-            .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
-                };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry(entry).await })
-            })
-            .collect();
+    fn read_hash_entry(
+        &self,
+        path: &Path,
+        key: &SecretVec<u8>,
+    ) -> FsResult<(u64, FileType, String)> {
+        let (file, cipher) = self.ciphers.open(path)?;
+        Ok(bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, key),
+            bincode_util::METADATA_LIMIT,
+        )?)
+    }
 
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
-        }
-        DirectoryEntryIterator(res)
+    /// Decrypt the name of an `ls` entry, it's encrypted with the same cipher as the entry.
+    fn ls_entry_name(&self, path: &Path, key: &SecretVec<u8>) -> FsResult<SecretString> {
+        let name = path.file_name().unwrap().to_string_lossy();
+        Ok(crypto::decrypt_file_name(
+            &name,
+            self.ciphers.cipher_for(path),
+            key,
+        )?)
     }
 
+    /// Count children of a directory. This **EXCLUDES** "." and "..".
     #[allow(clippy::missing_errors_doc)]
-    async fn get_inode_from_storage(&self, ino: u64) -> FsResult<FileAttr> {
-        let lock = self
-            .serialize_inode_locks
-            .get_or_insert_with(ino, || RwLock::new(false));
-        let _guard = lock.read();
-
-        let path = self.ino_file(ino);
-        if !path.is_file() {
-            return Err(FsError::InodeNotFound);
+    pub fn len(&self, ino: u64) -> FsResult<usize> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
         }
-        let file = OpenOptions::new().read(true).open(path).map_err(|err| {
-            error!(err = %err, "opening file");
-            FsError::InodeNotFound
-        })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?)
-    }
-
-    async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
-        let lock = self.attr_cache.get().await?;
-        let mut guard = lock.write().await;
-        let attr = guard.get(&ino);
-        if let Some(attr) = attr {
-            Ok(*attr)
+        let mut count = self
+            .storage
+            .read_dir(&self.contents_path(ino).join(LS_DIR))?
+            .len();
+        if ino == ROOT_INODE {
+            // we don't count "."
+            count -= 1;
         } else {
-            drop(guard);
-            let attr = self.get_inode_from_storage(ino).await?;
-            let mut guard = lock.write().await;
-            guard.put(ino, attr);
-            Ok(attr)
+            // we don't count "." and ".."
+            count -= 2;
         }
+        Ok(count)
     }
 
-    /// Get metadata
+    /// If a directory has no children besides "." and "..". Unlike [`EncryptedFs::len`] it stops at the first one,
+    /// so it's fast also on big directories.
     #[allow(clippy::missing_errors_doc)]
-    pub async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
-        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
-
-        // merge time info with any open read handles
-        let open_reads = { self.opened_files_for_read.read().await.contains_key(&ino) };
-        if open_reads {
-            let fhs = self.opened_files_for_read.read().await.get(&ino).cloned();
-            if let Some(fhs) = fhs {
-                for fh in fhs {
-                    let lock = self.read_handles.read().await;
-                    if let Some(ctx) = lock.get(&fh) {
-                        let set_atr: SetFileAttr = ctx.lock().await.attr.clone().into();
-                        merge_attr(&mut attr, &set_atr, false);
-                    }
-                }
+    pub fn is_empty(&self, ino: u64) -> FsResult<bool> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        for name in self
+            .storage
+            .read_dir(&self.contents_path(ino).join(LS_DIR))?
+        {
+            if name != "$." && name != "$.." {
+                return Ok(false);
             }
         }
+        Ok(true)
+    }
 
-        // merge time info and size with any open write handles
-        let open_writes = { self.opened_files_for_write.read().await.contains_key(&ino) };
-        if open_writes {
-            let fh = self.opened_files_for_write.read().await.get(&ino).copied();
-            if let Some(fh) = fh {
-                let lock = self.write_handles.read().await;
-                if let Some(ctx) = lock.get(&fh) {
-                    let ctx = ctx.lock().await;
-                    merge_attr(&mut attr, &ctx.attr.clone().into(), false);
+    /// Size of the files under a directory, recursively, as `(logical, physical)`, like for a folder size in a UI.
+    ///
+    /// Logical is the sum of the sizes of the files, physical the size of their encrypted content in the data dir.
+    /// Hard links are counted once. The entries of each directory are read in parallel with the attributes from the
+    /// cache, like [`EncryptedFs::read_dir_plus`], but the access times don't change. For a file it's only its size.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn disk_usage(&self, ino: u64) -> FsResult<(u64, u64)> {
+        let (mut logical, mut physical) = (0, 0);
+        let mut seen = HashSet::new();
+        let mut pending = vec![self.get_attr(ino).await?];
+        while let Some(attr) = pending.pop() {
+            match attr.kind {
+                FileType::RegularFile if seen.insert(attr.ino) => {
+                    logical += attr.size;
+                    physical += match self.storage.metadata(&self.contents_path(attr.ino)) {
+                        Ok(metadata) => metadata.len,
+                        // removed after we listed it
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+                        Err(err) => return Err(err.into()),
+                    };
+                }
+                FileType::Directory => {
+                    // going into "." and ".." would never end
+                    let ls_dir = self.contents_path(attr.ino).join(LS_DIR);
+                    let entries = self
+                        .storage
+                        .read_dir(&ls_dir)?
+                        .into_iter()
+                        .filter(|name| name != "$." && name != "$..")
+                        .map(|name| ls_dir.join(name));
+                    for entry in self.create_directory_entry_plus_iterator(entries).await {
+                        match entry {
+                            Ok(entry) => pending.push(entry.attr),
+                            Err(FsError::Io { source, .. })
+                                if source.kind() == io::ErrorKind::NotFound => {}
+                            Err(err) => return Err(err),
+                        }
+                    }
                 }
+                _ => {}
             }
         }
-
-        Ok(attr)
+        Ok((logical, physical))
     }
 
-    /// Set metadata
-    pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+    /// Delete a directory
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        self.set_attr2(ino, set_attr, false).await
-    }
+        if !self.is_dir(parent) {
+            return Err(FsError::InvalidInodeType);
+        }
 
-    async fn set_attr2(
-        &self,
-        ino: u64,
-        set_attr: SetFileAttr,
-        overwrite_size: bool,
-    ) -> FsResult<()> {
-        let serialize_update_lock = self
-            .serialize_update_inode_locks
-            .get_or_insert_with(ino, || Mutex::new(false));
-        let _serialize_update_guard = serialize_update_lock.lock().await;
+        if !self.exists_by_name(parent, name)? {
+            return Err(FsError::NotFound("name not found"));
+        }
 
-        let mut attr = self.get_attr(ino).await?;
-        merge_attr(&mut attr, &set_attr, overwrite_size);
-        let now = SystemTime::now();
-        attr.ctime = now;
-        attr.atime = now;
+        let attr = self
+            .find_by_name(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        if !matches!(attr.kind, FileType::Directory) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if attr.is_immutable() || attr.is_append_only() {
+            return Err(FsError::NotPermitted);
+        }
+        // check if it's empty
+        if !self.is_empty(attr.ino)? {
+            return Err(FsError::NotEmpty);
+        }
+        let self_clone = self.self_arc();
+        let name_clone = name.clone();
+        self.spawn_on(&NOD_RT, async move {
+            // remove inode file
+            {
+                let lock = self_clone
+                    .serialize_inode_locks
+                    .get_or_insert_with(attr.ino, || RwLock::new(false));
+                let _guard = lock.write();
+                self_clone.inodes.remove(attr.ino)?;
+            }
 
-        self.write_inode_to_storage(&attr).await?;
+            // remove contents directory
+            self_clone
+                .storage
+                .remove_dir_all(&self_clone.contents_path(attr.ino))?;
+            // remove from parent directory
+            self_clone
+                .remove_directory_entry(parent, &name_clone)
+                .await?;
+            self_clone.change_nlink(parent, -1).await?;
+            // remove from cache
+            if let Some(cache) = self_clone.attr_cache().await? {
+                cache.write().await.demote(&attr.ino);
+            }
 
-        Ok(())
-    }
+            let now = SystemTime::now();
+            self_clone
+                .set_attr(
+                    parent,
+                    SetFileAttr::default()
+                        .with_mtime(now)
+                        .with_ctime(now)
+                        .with_atime(now),
+                )
+                .await?;
 
-    async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
-        let lock = self
-            .serialize_inode_locks
-            .get_or_insert_with(attr.ino, || RwLock::new(false));
-        let guard = lock.write().await;
-        crypto::atomic_serialize_encrypt_into(
-            &self.ino_file(attr.ino),
-            attr,
-            self.cipher,
-            &*self.key.get().await?,
-        )?;
-        drop(guard);
-        // update cache also
-        {
-            let lock = self.attr_cache.get().await?;
-            let mut guard = lock.write().await;
-            guard.put(attr.ino, *attr);
-        }
-        Ok(())
+            Ok(())
+        })
+        .await?
     }
 
-    /// Read the contents from an `offset`.
-    ///
-    /// If we try to read outside of file size, we return zero bytes.
-    /// If the file is not opened for read, it will return an error of type [FsError::InvalidFileHandle].
-    #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
+    /// Delete a file, with the last link its inode is shredded if [`FsConfig::shred`] is on.
+    #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    #[allow(clippy::cast_possible_truncation)]
-    pub async fn read(
-        &self,
-        ino: u64,
-        offset: u64,
-        buf: &mut [u8],
-        handle: u64,
-    ) -> FsResult<usize> {
-        if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
+    pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
         }
-        if !self.is_file(ino) {
+        if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.read_handles.read().await.contains_key(&handle) {
-            return Err(FsError::InvalidFileHandle);
+        if !self.exists_by_name(parent, name)? {
+            return Err(FsError::NotFound("name not found"));
         }
 
-        let _size = self.get_attr(ino).await?.size;
+        let attr = self
+            .find_by_name(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        if !matches!(attr.kind, FileType::RegularFile | FileType::Symlink) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if attr.is_immutable() || attr.is_append_only() {
+            return Err(FsError::NotPermitted);
+        }
+        // todo move to method
+        let self_clone = self.self_arc();
+        let name_clone = name.clone();
+        self.spawn_on(&NOD_RT, async move {
+            // remove from parent directory
+            self_clone
+                .remove_directory_entry(parent, &name_clone)
+                .await?;
+            // the inode and content are kept while other hard links remain
+            if self_clone.change_nlink(attr.ino, -1).await? == 0 {
+                self_clone.remove_inode_storage(attr.ino).await?;
+            }
 
-        let lock = self
-            .read_write_locks
-            .get_or_insert_with(ino, || RwLock::new(false));
-        let _read_guard = lock.read().await;
+            let now = SystemTime::now();
+            self_clone
+                .set_attr(
+                    parent,
+                    SetFileAttr::default()
+                        .with_mtime(now)
+                        .with_ctime(now)
+                        .with_atime(now),
+                )
+                .await?;
 
-        let guard = self.read_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+            Ok(())
+        })
+        .await?
+    }
 
-        if ctx.ino != ino {
-            return Err(FsError::InvalidFileHandle);
+    /// Create a hard link, another name `new_name` in `new_parent` for the existing file `ino`.
+    ///
+    /// The names share the inode and content, which are removed only when the last name is removed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn link(
+        &self,
+        ino: u64,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<FileAttr> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
         }
-        if self.is_dir(ino) {
+        if *new_name.expose_secret() == "." || *new_name.expose_secret() == ".." {
+            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+        }
+        if !self.exists(ino) || !self.exists(new_parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(new_parent) {
             return Err(FsError::InvalidInodeType);
         }
-        if buf.is_empty() {
-            // no-op
-            return Ok(0);
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind == FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        if attr.is_immutable() || attr.is_append_only() {
+            return Err(FsError::NotPermitted);
+        }
+        if self.exists_by_name(new_parent, new_name)? {
+            return Err(FsError::AlreadyExists);
         }
+        self.validate_filename(new_name)?;
 
-        // read data
-        let (_buf, len) = {
-            let reader = ctx.reader.as_mut().unwrap();
+        self.insert_directory_entry(
+            new_parent,
+            &DirectoryEntry {
+                ino,
+                name: new_name.clone(),
+                kind: attr.kind,
+            },
+        )
+        .await?;
+        self.change_nlink(ino, 1).await?;
 
-            reader.seek(SeekFrom::Start(offset)).map_err(|err| {
-                error!(err = %err, "seeking");
-                err
-            })?;
-            let pos = reader.stream_position().map_err(|err| {
-                error!(err = %err, "getting position");
-                err
-            })?;
-            if pos != offset {
-                // we would need to seek after filesize
-                return Ok(0);
-            }
-            // keep block size to max the cipher can handle
-            #[allow(clippy::cast_possible_truncation)]
-            let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
-                warn!("reading more than max block size, truncating");
-                buf.split_at_mut(self.cipher.max_plaintext_len() - offset as usize)
-                    .0
-            } else {
-                buf
-            };
-            let len = stream_util::read(reader, buf).map_err(|err| {
-                error!(err = %err, "reading");
-                err
-            })?;
-            (buf, len)
-        };
+        let now = SystemTime::now();
+        self.set_attr(
+            new_parent,
+            SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now),
+        )
+        .await?;
 
-        ctx.attr.atime = SystemTime::now();
-        drop(ctx);
+        self.get_attr(ino).await
+    }
 
-        // self.sizes_read
-        //     .lock()
-        //     .await
-        //     .get_mut(&ino)
-        //     .unwrap()
-        //     .fetch_add(len as u64, Ordering::SeqCst);
-        // self.requested_read
-        //     .lock()
-        //     .await
-        //     .get_mut(&ino)
-        //     .unwrap()
-        //     .fetch_add(buf.len() as u64, Ordering::SeqCst);
-
-        // if buf.len() != len {
-        //     error!(
-        //         "size mismatch in read(), size {size} offset {offset} buf_len {} len {len}",
-        //         buf.len()
-        //     );
-        //     let lock = self.write_handles.read().await;
-        //     lock.iter().for_each(|(_, lock2)| {
-        //         call_async(async {
-        //             let ctx = lock2.lock().await;
-        //             if ctx.ino == ino && size != ctx.attr.size {
-        //                 error!("trying to read from a file which is not commited yet, ctx size {} actual size {size}", ctx.attr.size);
-        //             }
-        //         });
-        //     });
-        // }
+    /// Add `delta` to the links of the inode and return how many are left.
+    ///
+    /// A file has one for each hard link, a directory has two, `.` and its entry in the parent, and one for the
+    /// `..` of each subdirectory. When none are left the inode is not written, the caller removes it.
+    async fn change_nlink(&self, ino: u64, delta: i32) -> FsResult<u32> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
 
-        Ok(len)
+        let mut attr = self.get_inode_from_storage(ino).await?;
+        attr.nlink = attr.nlink.saturating_add_signed(delta);
+        if attr.kind == FileType::Directory {
+            // volumes from before subdirectories were counted have fewer, until they are repaired
+            attr.nlink = attr.nlink.max(2);
+        }
+        if attr.nlink > 0 {
+            attr.ctime = SystemTime::now();
+            self.write_inode_to_storage(&attr).await?;
+        }
+        Ok(attr.nlink)
     }
 
     #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::too_many_lines)]
-    pub async fn release(&self, handle: u64) -> FsResult<()> {
-        if handle == 0 {
-            // in the case of directory or if the file was crated
-            // without being opened we don't use a handle
-            return Ok(());
+    #[allow(clippy::missing_errors_doc)]
+    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
         }
-        let mut valid_fh = false;
-
-        // read
-        let ctx = { self.read_handles.write().await.remove(&handle) };
-        if let Some(ctx) = ctx {
-            let ctx = ctx.lock().await;
-
-            {
-                let mut opened_files_for_read = self.opened_files_for_read.write().await;
-                opened_files_for_read
-                    .get_mut(&ctx.ino)
-                    .expect("handle is missing")
-                    .remove(&handle);
-                if opened_files_for_read
-                    .get(&ctx.ino)
-                    .expect("handle is missing")
-                    .is_empty()
-                {
-                    opened_files_for_read.remove(&ctx.ino);
-                }
-            }
-
-            // write attr only here to avoid serializing it multiple times while reading
-            // it will merge time fields with existing data because it might got change while we kept the handle
-            let set_attr: SetFileAttr = ctx.attr.clone().into();
-            let ino = ctx.ino;
-            drop(ctx);
-            self.set_attr(ino, set_attr).await?;
-
-            valid_fh = true;
+        if !self.is_dir(parent) {
+            return Err(FsError::InvalidInodeType);
         }
+        let hash = self.hash_file_name(name);
+        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
+        Ok(self.storage.is_file(&hash_path))
+    }
 
-        // write
-        let ctx = { self.write_handles.write().await.remove(&handle) };
-        if let Some(ctx) = ctx {
-            if self.read_only {
-                return Err(FsError::ReadOnly);
-            }
-            let mut ctx = ctx.lock().await;
-
-            let mut writer = ctx.writer.take().unwrap();
-            let lock = self
-                .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
-            let write_guard = lock.write().await;
-            let file = writer.finish()?;
-            file.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-            // write attr only here to avoid serializing it multiple times while writing
-            // it will merge time fields with existing data because it might got change while we kept the handle
-            let ino = ctx.ino;
-            let attr = ctx.attr.clone();
-            drop(ctx);
-            self.set_attr(ino, attr.into()).await?;
-            let attr = self.get_attr(ino).await?;
-            {
-                let write_size = self
-                    .sizes_write
-                    .lock()
-                    .await
-                    .get(&ino)
-                    .unwrap()
-                    .load(Ordering::SeqCst);
-                info!("written for {ino} {write_size}");
-                if attr.size != write_size {
-                    // error!("size mismatch write {} {}", write_size, attr.size);
-                }
-                let requested_read = self
-                    .requested_read
-                    .lock()
-                    .await
-                    .get(&ino)
-                    .unwrap()
-                    .load(Ordering::SeqCst);
-                let read = self
-                    .sizes_read
-                    .lock()
-                    .await
-                    .get(&ino)
-                    .unwrap()
-                    .load(Ordering::SeqCst);
-                if requested_read != read {
-                    error!(
-                        "size mismatch read, size {} requested {} read {}",
-                        attr.size, requested_read, read
-                    );
-                }
-            }
-            self.sizes_write.lock().await.remove(&ino);
-            self.sizes_read.lock().await.remove(&ino);
-            self.requested_read.lock().await.remove(&ino);
-            drop(write_guard);
-            self.opened_files_for_write.write().await.remove(&ino);
-            self.reset_handles(ino, Some(handle), true).await?;
-
-            valid_fh = true;
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
         }
-
-        if !valid_fh {
-            return Err(FsError::InvalidFileHandle);
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        if !self.storage.is_dir(&ls_dir) {
+            return Err(FsError::InvalidInodeType);
         }
-        Ok(())
-    }
 
-    /// Check if a file is opened for reading with this handle.
-    pub async fn is_read_handle(&self, fh: u64) -> bool {
-        self.read_handles.read().await.contains_key(&fh)
+        let iter = self.dir_entry_paths(&ls_dir)?;
+        self.touch_atime(ino).await?;
+        Ok(self.create_directory_entry_iterator(iter).await)
     }
 
-    /// Check if a file is opened for writing with this handle.
-    pub async fn is_write_handle(&self, fh: u64) -> bool {
-        self.write_handles.read().await.contains_key(&fh)
+    /// Like [`EncryptedFs::read_dir`], sorted by name with `.` and `..` first.
+    ///
+    /// All the names are decrypted before it returns, [`EncryptedFs::read_dir`] is cheaper if the order doesn't matter.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir_sorted(&self, ino: u64) -> FsResult<Vec<DirectoryEntry>> {
+        let entries = self.sorted_dir_entries(ino).await?;
+        self.touch_atime(ino).await?;
+        Ok(entries)
     }
 
-    /// Writes the contents of `buf` to the file with `ino` starting at `offset`.
-    ///
-    /// If we write outside file size, we fill up with zeros until the `offset`.
-    /// If the file is not opened for writing,
-    /// it will return an error of type [FsError::InvalidFileHandle].
-    #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
-    pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
-        }
-        if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
+    async fn sorted_dir_entries(&self, ino: u64) -> FsResult<Vec<DirectoryEntry>> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
         }
-        if !self.is_file(ino) {
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        if !self.storage.is_dir(&ls_dir) {
             return Err(FsError::InvalidInodeType);
         }
+
+        let mut res = vec![];
+        for entry in self
+            .create_directory_entry_iterator(self.dir_entry_paths(&ls_dir)?)
+            .await
         {
-            if !self.write_handles.read().await.contains_key(&handle) {
-                return Err(FsError::InvalidFileHandle);
+            match entry {
+                Ok(entry) => res.push(entry),
+                // removed after we listed it
+                Err(FsError::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
             }
         }
-        {
-            let guard = self.write_handles.read().await;
-            let ctx = guard.get(&handle).unwrap().lock().await;
-            if ctx.ino != ino {
-                return Err(FsError::InvalidFileHandle);
+        res.sort_by(|a, b| cmp_entry_names(&a.name, &b.name));
+        Ok(res)
+    }
+
+    /// Reads at most `limit` entries of the directory starting from `offset`, and if there are more after them.
+    ///
+    /// Only the entries of the page are read from the listing, so huge directories can be read without keeping
+    /// all of them in memory. The order is the one of the listing in the data dir, which is stable while the
+    /// directory isn't changed, or by name with [`FsConfig::sorted_dirs`]. Entries added or removed between
+    /// pages may be skipped or returned twice, like with `readdir(3)`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir_paged(
+        &self,
+        ino: u64,
+        offset: usize,
+        limit: usize,
+    ) -> FsResult<(Vec<DirectoryEntry>, bool)> {
+        if self.sorted_dirs {
+            let mut entries = self.sorted_dir_entries(ino).await?;
+            if offset == 0 {
+                self.touch_atime(ino).await?;
             }
+            let has_more = entries.len() > offset.saturating_add(limit);
+            entries.truncate(offset.saturating_add(limit));
+            return Ok((entries.split_off(offset.min(entries.len())), has_more));
         }
-        if buf.is_empty() {
-            // no-op
-            return Ok(0);
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
         }
-
-        let lock = self
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        if !self.storage.is_dir(&ls_dir) {
+            return Err(FsError::InvalidInodeType);
+        }
+
+        // one more to know if there are others after this page
+        let mut page: Vec<_> = self
+            .dir_entry_paths(&ls_dir)?
+            .skip(offset)
+            .take(limit.saturating_add(1))
+            .collect();
+        let has_more = page.len() > limit;
+        page.truncate(limit);
+        if offset == 0 {
+            self.touch_atime(ino).await?;
+        }
+        let mut res = Vec::with_capacity(page.len());
+        for entry in self.create_directory_entry_iterator(page).await {
+            match entry {
+                Ok(entry) => res.push(entry),
+                // removed after we listed it
+                Err(FsError::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok((res, has_more))
+    }
+
+    /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
+    pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        if !self.storage.is_dir(&ls_dir) {
+            return Err(FsError::InvalidInodeType);
+        }
+
+        let iter = self.dir_entry_paths(&ls_dir)?;
+        self.touch_atime(ino).await?;
+        let mut iter = self.create_directory_entry_plus_iterator(iter).await;
+        if self.sorted_dirs {
+            // errors last
+            iter.0.make_contiguous().sort_by(|a, b| match (a, b) {
+                (Ok(a), Ok(b)) => cmp_entry_names(&a.name, &b.name),
+                (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+                (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+                (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+            });
+        }
+        Ok(iter)
+    }
+
+    /// The paths of the entries in an `ls` dir of the data dir.
+    fn dir_entry_paths(&self, ls_dir: &Path) -> FsResult<impl Iterator<Item = PathBuf>> {
+        let ls_dir = ls_dir.to_path_buf();
+        Ok(self
+            .storage
+            .read_dir(&ls_dir)?
+            .into_iter()
+            .map(move |name| ls_dir.join(name)))
+    }
+
+    async fn create_directory_entry_plus(&self, path: PathBuf) -> FsResult<DirectoryEntryPlus> {
+        let entry = self.create_directory_entry(path).await?;
+        let lock = self.serialize_inode_locks.clone();
+        let lock_ino = lock.get_or_insert_with(entry.ino, || RwLock::new(false));
+        let _ino_guard = lock_ino.read();
+        let attr = self.get_inode_from_cache_or_storage(entry.ino).await?;
+        Ok(DirectoryEntryPlus {
+            ino: entry.ino,
+            name: entry.name,
+            kind: entry.kind,
+            attr,
+        })
+    }
+
+    async fn create_directory_entry_plus_iterator(
+        &self,
+        read_dir: impl IntoIterator<Item = PathBuf>,
+    ) -> DirectoryEntryPlusIterator {
+        if self.serialized {
+            let mut res = VecDeque::new();
+            for entry in read_dir {
+                res.push_back(self.create_directory_entry_plus(entry).await);
+            }
+            return DirectoryEntryPlusIterator(res);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let futures: Vec<_> = read_dir
+            .into_iter()
+            .map(|entry| {
+                let fs = self.self_arc();
+                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry_plus(entry).await })
+            })
+            .collect();
+
+        // do these futures in parallel and return them
+        let mut res = VecDeque::with_capacity(futures.len());
+        for f in futures {
+            res.push_back(f.await.unwrap());
+        }
+        DirectoryEntryPlusIterator(res)
+    }
+
+    async fn create_directory_entry(&self, path: PathBuf) -> FsResult<DirectoryEntry> {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let name = {
+            if name == "$." {
+                SecretString::new(Box::new(".".into()))
+            } else if name == "$.." {
+                SecretString::from_str("..").unwrap()
+            } else {
+                // try from cache
+                let lock = self.dir_entries_name_cache().await?;
+                let name_cached = if let Some(lock) = &lock {
+                    let name_cached = lock.lock().await.get(&name).cloned();
+                    self.observe_cache(CacheKind::DirEntryName, name_cached.is_some());
+                    name_cached
+                } else {
+                    None
+                };
+                if let Some(name_cached) = name_cached {
+                    name_cached
+                } else {
+                    // the name is encrypted with the same cipher as the entry
+                    let cipher = self.ciphers.cipher_for(&path);
+                    if let Ok(decrypted_name) =
+                        crypto::decrypt_file_name(&name, cipher, &*self.key().await?).map_err(
+                            |err| {
+                                error!(err = %err, "decrypting file name");
+                                err
+                            },
+                        )
+                    {
+                        if let Some(lock) = lock {
+                            lock.lock().await.put(name.clone(), decrypted_name.clone());
+                        }
+                        decrypted_name
+                    } else {
+                        return Err(FsError::InvalidInput("invalid file name"));
+                    }
+                }
+            }
+        };
+
+        self.validate_filename(&name)?;
+
+        let file_path = path.to_str().unwrap().to_owned();
+        // try from cache
+        if let Some(lock) = self.dir_entries_meta_cache().await? {
+            let cached = lock.lock().await.get(&file_path).copied();
+            self.observe_cache(CacheKind::DirEntryMeta, cached.is_some());
+            if let Some((ino, kind)) = cached {
+                return Ok(DirectoryEntry { ino, name, kind });
+            }
+        }
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(file_path.clone(), || RwLock::new(false));
+        let guard = lock.read().await;
+        let (file, cipher) = self.ciphers.open(&path)?;
+        let res: bincode::Result<(u64, FileType)> = bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &*self.key().await?),
+            bincode_util::METADATA_LIMIT,
+        );
+        drop(guard);
+        if let Err(e) = res {
+            error!(err = %e, "deserializing directory entry");
+            return Err(e.into());
+        }
+        let (ino, kind): (u64, FileType) = res.unwrap();
+        // add to cache
+        if let Some(lock) = self.dir_entries_meta_cache().await? {
+            lock.lock().await.put(file_path, (ino, kind));
+        }
+        Ok(DirectoryEntry { ino, name, kind })
+    }
+
+    async fn dir_entries_name_cache(
+        &self,
+    ) -> FsResult<Option<Arc<Mutex<LruCache<String, SecretString>>>>> {
+        match &self.dir_entries_name_cache {
+            Some(cache) => Ok(Some(cache.get().await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn dir_entries_meta_cache(&self) -> FsResult<Option<Arc<Mutex<DirEntryMetaCache>>>> {
+        match &self.dir_entries_meta_cache {
+            Some(cache) => Ok(Some(cache.get().await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn attr_cache(&self) -> FsResult<Option<Arc<RwLock<LruCache<u64, FileAttr>>>>> {
+        match &self.attr_cache {
+            Some(cache) => Ok(Some(cache.get().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Drop all from the metadata caches and the [`BlockCache`], so everything is read again from the data dir.
+    ///
+    /// Useful after the data dir was changed by someone else, like another process sharing it read-only, or to
+    /// free memory. Operations in progress keep working, they just miss the cache.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn clear_caches(&self) -> FsResult<()> {
+        if let Some(lock) = self.attr_cache().await? {
+            lock.write().await.clear();
+        }
+        if let Some(lock) = self.dir_entries_name_cache().await? {
+            lock.lock().await.clear();
+        }
+        if let Some(lock) = self.dir_entries_meta_cache().await? {
+            lock.lock().await.clear();
+        }
+        if let Some(cache) = self.block_cache() {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Drop from the caches the attributes and content of an inode, and the entries of the directory if it's one,
+    /// so they are read again from the data dir, see [`EncryptedFs::clear_caches`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn invalidate_inode(&self, ino: u64) -> FsResult<()> {
+        if let Some(lock) = self.attr_cache().await? {
+            lock.write().await.pop(&ino);
+        }
+        if let Some(lock) = self.dir_entries_meta_cache().await? {
+            let ls_dir = self.contents_path(ino).join(LS_DIR);
+            let mut cache = lock.lock().await;
+            let stale: Vec<String> = cache
+                .iter()
+                .filter(|(path, (entry_ino, _))| {
+                    *entry_ino == ino || Path::new(path).starts_with(&ls_dir)
+                })
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in stale {
+                cache.pop(&path);
+            }
+        }
+        if let Some(cache) = self.block_cache() {
+            cache.invalidate(ino);
+        }
+        Ok(())
+    }
+
+    async fn create_directory_entry_iterator(
+        &self,
+        read_dir: impl IntoIterator<Item = PathBuf>,
+    ) -> DirectoryEntryIterator {
+        if self.serialized {
+            let mut res = VecDeque::new();
+            for entry in read_dir {
+                res.push_back(self.create_directory_entry(entry).await);
+            }
+            return DirectoryEntryIterator(res);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let futures: Vec<_> = read_dir
+            .into_iter()
+            .map(|entry| {
+                let fs = self.self_arc();
+                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry(entry).await })
+            })
+            .collect();
+
+        // do these futures in parallel and return them
+        let mut res = VecDeque::with_capacity(futures.len());
+        for f in futures {
+            res.push_back(f.await.unwrap());
+        }
+        DirectoryEntryIterator(res)
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    async fn get_inode_from_storage(&self, ino: u64) -> FsResult<FileAttr> {
+        let lock = self
+            .serialize_inode_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read();
+
+        if !self.inodes.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        self.inodes.read(ino, &*self.key().await?)
+    }
+
+    async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
+        let Some(lock) = self.attr_cache().await? else {
+            return self.get_inode_from_storage(ino).await;
+        };
+        let mut guard = lock.write().await;
+        let attr = guard.get(&ino);
+        self.observe_cache(CacheKind::Attr, attr.is_some());
+        if let Some(attr) = attr {
+            Ok(*attr)
+        } else {
+            drop(guard);
+            let attr = self.get_inode_from_storage(ino).await?;
+            let mut guard = lock.write().await;
+            guard.put(ino, attr);
+            Ok(attr)
+        }
+    }
+
+    /// Get metadata
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+
+        // merge time info with any open read handles
+        let open_reads = { self.opened_files_for_read.read().await.contains_key(&ino) };
+        if open_reads {
+            let fhs = self.opened_files_for_read.read().await.get(&ino).cloned();
+            if let Some(fhs) = fhs {
+                for fh in fhs {
+                    if let Some(ctx) = self.read_handle(fh).await {
+                        let set_atr: SetFileAttr = ctx.lock().await.attr.clone().into();
+                        merge_attr(&mut attr, &set_atr, false, false);
+                    }
+                }
+            }
+        }
+
+        // merge time info and size with any open write handles
+        let open_writes = { self.opened_files_for_write.read().await.contains_key(&ino) };
+        if open_writes {
+            let fh = self.opened_files_for_write.read().await.get(&ino).copied();
+            if let Some(fh) = fh {
+                if let Some(ctx) = self.write_handle(fh).await {
+                    let ctx = ctx.lock().await;
+                    merge_attr(&mut attr, &ctx.attr.clone().into(), false, false);
+                }
+            }
+        }
+
+        // merge times not yet written
+        if let Some(pending) = self.pending_times.lock().await.get(&ino) {
+            merge_attr(&mut attr, pending, false, false);
+        }
+
+        let cipher = self.ciphers.cipher_for(&self.contents_path(ino));
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            attr.blksize = cipher.recommended_io_size() as u32;
+        }
+        if matches!(attr.kind, FileType::RegularFile | FileType::Symlink) {
+            attr.blocks = match self.sparse_blocks(ino)? {
+                Some(blocks) => blocks,
+                None => cipher.ciphertext_len(attr.size).div_ceil(512),
+            };
+        }
+
+        Ok(attr)
+    }
+
+    /// Set metadata
+    pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if self.try_defer_times(ino, &set_attr).await? {
+            return Ok(());
+        }
+        self.set_attr2(ino, set_attr, false, false).await
+    }
+
+    /// Set metadata, but unlike [`EncryptedFs::set_attr`] the times are set as they are, even if they are older
+    /// than the existing ones.
+    ///
+    /// Useful to restore times from backups or to fix times which are in the future after the system clock
+    /// jumped backward.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_attr_overwrite(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.set_attr2(ino, set_attr, false, true).await
+    }
+
+    async fn set_attr2(
+        &self,
+        ino: u64,
+        set_attr: SetFileAttr,
+        overwrite_size: bool,
+        overwrite_times: bool,
+    ) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        // pending times are written together with this change
+        let pending = self.pending_times.lock().await.remove(&ino);
+        let mut attr = self.get_attr(ino).await?;
+        if let Some(pending) = pending {
+            merge_attr(&mut attr, &pending, false, false);
+        }
+        let now = SystemTime::now();
+        if !overwrite_times {
+            warn_on_clock_skew(&attr, &set_attr, now);
+        }
+        merge_attr(&mut attr, &set_attr, overwrite_size, overwrite_times);
+        // like on Linux, only accessing it doesn't change the inode
+        if (!overwrite_times || set_attr.ctime.is_none()) && !is_atime_only(&set_attr) {
+            attr.ctime = now;
+        }
+        if !overwrite_times || set_attr.atime.is_none() {
+            attr.atime = now;
+        }
+
+        self.write_inode_to_storage(&attr).await?;
+        if overwrite_times {
+            // open handles keep their own times which are merged on get_attr, bring them in sync
+            // so newer times from them don't hide the ones we just set
+            self.overwrite_handles_times(&attr).await;
+        }
+
+        Ok(())
+    }
+
+    async fn overwrite_handles_times(&self, attr: &FileAttr) {
+        let fhs = self
+            .opened_files_for_read
+            .read()
+            .await
+            .get(&attr.ino)
+            .cloned();
+        if let Some(fhs) = fhs {
+            for fh in fhs {
+                if let Some(ctx) = self.read_handle(fh).await {
+                    ctx.lock().await.attr = (*attr).into();
+                }
+            }
+        }
+        let fh = self
+            .opened_files_for_write
+            .read()
+            .await
+            .get(&attr.ino)
+            .copied();
+        if let Some(fh) = fh {
+            if let Some(ctx) = self.write_handle(fh).await {
+                let mut ctx = ctx.lock().await;
+                ctx.attr.atime = attr.atime;
+                ctx.attr.mtime = attr.mtime;
+                ctx.attr.ctime = attr.ctime;
+                ctx.attr.crtime = attr.crtime;
+            }
+        }
+    }
+
+    async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
+        let lock = self
+            .serialize_inode_locks
+            .get_or_insert_with(attr.ino, || RwLock::new(false));
+        let guard = lock.write().await;
+        self.inodes.write(attr, &*self.key().await?)?;
+        drop(guard);
+        // update cache also
+        if let Some(lock) = self.attr_cache().await? {
+            let mut guard = lock.write().await;
+            guard.put(attr.ino, *attr);
+        }
+        Ok(())
+    }
+
+    /// Check if the user with `uid` and `gid` can access `ino` with `mask`, like `access(2)`, see [`access_allowed`].
+    ///
+    /// Operations don't check permissions themselves, as they don't know the user, who serves the filesystem to
+    /// other users needs to check them before, like the FUSE mount on Linux does. Asking for write on a read-only
+    /// filesystem fails with [`FsError::ReadOnly`], else it fails with [`FsError::AccessDenied`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn check_access(&self, ino: u64, uid: u32, gid: u32, mask: u32) -> FsResult<()> {
+        let attr = self.get_attr(ino).await?;
+        if mask & 0o2 != 0 && self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if access_allowed(attr.uid, attr.gid, attr.perm, uid, gid, mask) {
+            Ok(())
+        } else {
+            Err(FsError::AccessDenied)
+        }
+    }
+
+    /// Tokio [`AsyncRead`](tokio::io::AsyncRead) and [`AsyncSeek`](tokio::io::AsyncSeek) over `handle`, opened for
+    /// read with [`EncryptedFs::open`], it starts at the beginning of the file. See [`async_io`].
+    pub fn async_reader(&self, ino: u64, handle: u64) -> FileReader {
+        FileReader::new(self.self_arc(), ino, handle)
+    }
+
+    /// Tokio [`AsyncWrite`](tokio::io::AsyncWrite) and [`AsyncSeek`](tokio::io::AsyncSeek) over `handle`, opened for
+    /// write with [`EncryptedFs::open`], it starts at the beginning of the file. See [`async_io`].
+    pub fn async_writer(&self, ino: u64, handle: u64) -> FileWriter {
+        FileWriter::new(self.self_arc(), ino, handle)
+    }
+
+    /// Read the contents from an `offset`.
+    ///
+    /// If we try to read outside of file size, we return zero bytes.
+    /// A read which straddles the end of the file returns only the bytes of the file, and `buf` is filled with zeros
+    /// after them up to the end of the 4 KiB page holding the end of the file, so the tail of the last page of a
+    /// mmap'd file reads as zeros. The rest of `buf` is left as it is. The length returned is still the one of the
+    /// bytes read, as FUSE takes it as the end of the file.
+    /// If the file is not opened for read, it will return an error of type [FsError::InvalidFileHandle].
+    #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let start = Instant::now();
+        let res = self.read_unobserved(ino, offset, buf, handle).await;
+        let Some(observer) = &self.observer else {
+            return res;
+        };
+        match &res {
+            Ok(len) => observer.on_read(ino, *len, start.elapsed()),
+            Err(err) if err.is_decrypt_failure() => observer.on_decrypt_failure(ino),
+            Err(_) => {}
+        }
+        res
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read_unobserved(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let Some(ctx) = self.read_handle(handle).await else {
+            return Err(FsError::InvalidFileHandle);
+        };
+
+        let size = self.get_attr(ino).await?.size;
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        // opened for read and write, see what was written with the same handle
+        if let Some(write_ctx) = self.write_handle(handle).await {
+            let write_guard = lock.write().await;
+            self.flush_own_writer(ino, handle, &write_ctx, &ctx).await?;
+            drop(write_guard);
+        }
+        let _read_guard = lock.read().await;
+        // blocks removed from the end of the file, not while it's written as the size is ahead of the content
+        let expected_size = if self.opened_files_for_write.read().await.contains_key(&ino) {
+            None
+        } else {
+            Some(self.get_inode_from_cache_or_storage(ino).await?.size)
+        };
+
+        let mut ctx = ctx.lock().await;
+
+        if ctx.ino != ino {
+            return Err(FsError::InvalidFileHandle);
+        }
+        if self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+
+        // keep within the max file size the cipher can handle
+        #[allow(clippy::cast_possible_truncation)]
+        let max_plaintext_len = self
+            .ciphers
+            .cipher_for(&self.contents_path(ino))
+            .max_plaintext_len();
+        let buf = if offset + buf.len() as u64 > max_plaintext_len as u64 {
+            warn!("reading past the max file size of the cipher, truncating");
+            buf.split_at_mut(max_plaintext_len.saturating_sub(offset as usize))
+                .0
+        } else {
+            buf
+        };
+
+        if let Some(cache) = self.block_cache() {
+            self.stop_readahead(&mut ctx).await?;
+            let len = self.read_cached(&cache, &mut ctx, offset, buf).await?;
+            self.touch_handle_atime(&mut ctx.attr);
+            zero_last_page(buf, offset, len, size);
+            return Ok(len);
+        }
+
+        let len = if let Some(blocks) = self.readahead_blocks() {
+            self.read_ahead(&mut ctx, offset, buf, blocks.get()).await?
+        } else {
+            self.stop_readahead(&mut ctx).await?;
+            self.read_direct(&mut ctx, offset, buf).await?
+        };
+        if let Some(size) = expected_size {
+            if len < buf.len() && offset + (len as u64) < size {
+                error!(ino, offset, size, "content is shorter than the file size");
+                buf[..len].zeroize();
+                return Err(FsError::IntegrityCheckFailed);
+            }
+        }
+        if len == 0 {
+            // we would need to seek after filesize
+            return Ok(0);
+        }
+
+        self.touch_handle_atime(&mut ctx.attr);
+        drop(ctx);
+        zero_last_page(buf, offset, len, size);
+
+        Ok(len)
+    }
+
+    /// Read and decrypt from the reader of the handle.
+    async fn read_direct(
+        &self,
+        ctx: &mut ReadHandleContext,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FsResult<usize> {
+        let mut reader = self.take_reader(ctx).await?;
+        let buf_len = buf.len();
+        let (reader, res) = self
+            .run_crypto(move || {
+                let res = (|| {
+                    reader.seek(SeekFrom::Start(offset)).map_err(|err| {
+                        error!(err = %err, "seeking");
+                        err
+                    })?;
+                    let pos = reader.stream_position().map_err(|err| {
+                        error!(err = %err, "getting position");
+                        err
+                    })?;
+                    if pos != offset {
+                        // we would need to seek after filesize
+                        return Ok(None);
+                    }
+                    let mut data = vec![0; buf_len];
+                    let len = stream_util::read(&mut reader, &mut data).map_err(|err| {
+                        error!(err = %err, "reading");
+                        err
+                    })?;
+                    Ok::<_, io::Error>(Some((data, len)))
+                })();
+                (reader, res)
+            })
+            .await?;
+        ctx.reader = Some(reader);
+        let (mut data, len) = res?.unwrap_or_default();
+        buf[..len].copy_from_slice(&data[..len]);
+        data.zeroize();
+        Ok(len)
+    }
+
+    /// Read through what was decrypted ahead, see [`FsConfig::readahead_blocks`].
+    async fn read_ahead(
+        &self,
+        ctx: &mut ReadHandleContext,
+        offset: u64,
+        buf: &mut [u8],
+        blocks: usize,
+    ) -> FsResult<usize> {
+        let sequential = offset == ctx.readahead.next_offset;
+        if !sequential {
+            // random access, it would be wasted work
+            self.stop_readahead(ctx).await?;
+        } else if ctx
+            .readahead
+            .task
+            .as_ref()
+            .is_some_and(task::JoinHandle::is_finished)
+        {
+            self.collect_readahead(ctx).await?;
+        }
+        let mut read = ctx.readahead.buf.copy_to(offset, buf);
+        if read < buf.len() && ctx.readahead.task.is_some() {
+            // the rest might be in flight
+            self.collect_readahead(ctx).await?;
+            read += ctx
+                .readahead
+                .buf
+                .copy_to(offset + read as u64, &mut buf[read..]);
+        }
+        if read < buf.len() {
+            read += self
+                .read_direct(ctx, offset + read as u64, &mut buf[read..])
+                .await?;
+        }
+        let end = offset + read as u64;
+        ctx.readahead.next_offset = end;
+        ctx.readahead.buf.consume(end);
+
+        // keep at least half of it ahead, unless we reached the end of the file
+        let len = blocks * BLOCK_SIZE;
+        if sequential
+            && read == buf.len()
+            && ctx.readahead.task.is_none()
+            && ctx.readahead.buf.data.len() < len / 2
+        {
+            let Some(mut reader) = ctx.reader.take() else {
+                return Ok(read);
+            };
+            let start = ctx.readahead.buf.end();
+            let len = len - ctx.readahead.buf.data.len();
+            ctx.readahead.task = Some(task::spawn_blocking(move || {
+                let res = (|| {
+                    let mut buf = ReadaheadBuf {
+                        start,
+                        data: vec![0; len],
+                    };
+                    if reader.seek(SeekFrom::Start(start))? == start {
+                        let len = stream_util::read(&mut reader, &mut buf.data)?;
+                        buf.data.truncate(len);
+                    } else {
+                        // after filesize
+                        buf.data.clear();
+                    }
+                    Ok::<_, io::Error>(buf)
+                })();
+                (reader, res)
+            }));
+        }
+
+        Ok(read)
+    }
+
+    /// Take the reader of the handle, to give back when done. If it was lost, like when a read was cancelled or
+    /// its task failed while decrypting, a new one is opened, so the handle can still be used.
+    async fn take_reader(
+        &self,
+        ctx: &mut ReadHandleContext,
+    ) -> FsResult<Box<dyn CryptoReadSeek<Box<dyn StorageFile>>>> {
+        if let Some(reader) = ctx.reader.take() {
+            return Ok(reader);
+        }
+        warn!(
+            ino = ctx.ino,
+            "reader of the handle was lost, opening it again"
+        );
+        Ok(Box::new(self.create_content_read(ctx.ino).await?))
+    }
+
+    /// Wait for the read ahead in flight, if any, and take back the reader of the handle.
+    async fn collect_readahead(&self, ctx: &mut ReadHandleContext) -> FsResult<()> {
+        let Some(task) = ctx.readahead.task.take() else {
+            return Ok(());
+        };
+        let (reader, res) = task.await?;
+        if ctx.reader.is_some() {
+            // the handle was reset meanwhile, what was read might be stale
+            return Ok(());
+        }
+        ctx.reader = Some(reader);
+        match res {
+            Ok(buf) => ctx.readahead.buf.append(buf),
+            // it's read again when needed, and the error reported then
+            Err(err) => debug!(err = %err, "reading ahead"),
+        }
+        Ok(())
+    }
+
+    /// Wait for the read ahead in flight and drop all that was read ahead.
+    async fn stop_readahead(&self, ctx: &mut ReadHandleContext) -> FsResult<()> {
+        self.collect_readahead(ctx).await?;
+        ctx.readahead.buf = ReadaheadBuf::default();
+        Ok(())
+    }
+
+    /// Stream the content of a file from the start, in chunks of up to [`BLOCK_SIZE`] bytes.
+    ///
+    /// The next chunk is read and decrypted only when the stream is polled, so a slow consumer
+    /// applies backpressure. It ends at the file size. Errors are yielded as items and end the stream.
+    /// > ⚠️ **Warning**
+    /// > The chunks are plain [`Bytes`] and are not zeroized on drop.
+    pub fn read_stream(&self, ino: u64, handle: u64) -> impl Stream<Item = FsResult<Bytes>> + '_ {
+        stream::unfold(Some(0_u64), move |offset| async move {
+            let offset = offset?;
+            let res = async {
+                let size = self.get_attr(ino).await?.size;
+                if offset >= size {
+                    return Ok(None);
+                }
+                #[allow(clippy::cast_possible_truncation)]
+                let len = (size - offset).min(BLOCK_SIZE as u64) as usize;
+                let mut buf = vec![0; len];
+                let mut read = 0;
+                while read < len {
+                    let n = self
+                        .read(ino, offset + read as u64, &mut buf[read..], handle)
+                        .await?;
+                    if n == 0 {
+                        break;
+                    }
+                    read += n;
+                }
+                buf.truncate(read);
+                Ok::<_, FsError>((read > 0).then_some(buf))
+            }
+            .await;
+            match res {
+                Ok(Some(buf)) => {
+                    let next = offset + buf.len() as u64;
+                    Some((Ok(Bytes::from(buf)), Some(next)))
+                }
+                Ok(None) => None,
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
+
+    /// Read through the [`BlockCache`], whole blocks are decrypted and cached on miss.
+    async fn read_cached(
+        &self,
+        cache: &BlockCache,
+        ctx: &mut ReadHandleContext,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FsResult<usize> {
+        let ino = ctx.ino;
+        let mut read = 0;
+        while read < buf.len() {
+            let pos = offset + read as u64;
+            let block = pos / BLOCK_SIZE as u64;
+            #[allow(clippy::cast_possible_truncation)]
+            let block_offset = (pos % BLOCK_SIZE as u64) as usize;
+            // take it before reading, so we don't cache stale data if a write invalidates it meanwhile
+            let generation = cache.generation(ino);
+            let cached = cache.get(ino, block, generation)?;
+            self.observe_cache(CacheKind::Block, cached.is_some());
+            let mut data = if let Some(data) = cached {
+                data
+            } else {
+                let mut reader = self.take_reader(ctx).await?;
+                let (reader, res) = self
+                    .run_crypto(move || {
+                        let res = (|| {
+                            let start = block * BLOCK_SIZE as u64;
+                            if reader.seek(SeekFrom::Start(start))? != start {
+                                // after filesize
+                                return Ok(vec![]);
+                            }
+                            let mut data = vec![0; BLOCK_SIZE];
+                            let len = stream_util::read(&mut reader, &mut data)?;
+                            data.truncate(len);
+                            Ok::<_, io::Error>(data)
+                        })();
+                        (reader, res)
+                    })
+                    .await?;
+                ctx.reader = Some(reader);
+                let data = res.map_err(|err| {
+                    error!(err = %err, "reading block");
+                    err
+                })?;
+                if !data.is_empty() {
+                    cache.put(ino, block, generation, &data)?;
+                }
+                data
+            };
+            let len = data
+                .len()
+                .saturating_sub(block_offset)
+                .min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&data[block_offset..block_offset + len]);
+            let eof = data.len() < BLOCK_SIZE;
+            data.zeroize();
+            read += len;
+            if eof {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::too_many_lines)]
+    pub async fn release(&self, handle: u64) -> FsResult<()> {
+        if handle == 0 {
+            // in the case of directory or if the file was crated
+            // without being opened we don't use a handle
+            return Ok(());
+        }
+        let mut valid_fh = false;
+        let mut released_ino = None;
+
+        // append
+        let ino = { self.append_handles.write().await.remove(&handle) };
+        if let Some(ino) = ino {
+            valid_fh = true;
+            released_ino = Some(ino);
+        }
+
+        // read
+        let ctx = { self.read_handles.write().await.remove(&handle) };
+        if let Some(ctx) = ctx {
+            let mut ctx = ctx.lock().await;
+            self.stop_readahead(&mut ctx).await?;
+
+            {
+                let mut opened_files_for_read = self.opened_files_for_read.write().await;
+                opened_files_for_read
+                    .get_mut(&ctx.ino)
+                    .expect("handle is missing")
+                    .remove(&handle);
+                if opened_files_for_read
+                    .get(&ctx.ino)
+                    .expect("handle is missing")
+                    .is_empty()
+                {
+                    opened_files_for_read.remove(&ctx.ino);
+                }
+            }
+
+            // write attr only here to avoid serializing it multiple times while reading
+            // it will merge time fields with existing data because it might got change while we kept the handle
+            // only atime, the size could be stale if someone wrote meanwhile
+            let atime = ctx.attr.atime;
+            let ino = ctx.ino;
+            drop(ctx);
+            self.save_handle_atime(ino, atime).await?;
+
+            valid_fh = true;
+            released_ino = Some(ino);
+        }
+
+        // write
+        let ctx = { self.write_handles.write().await.remove(&handle) };
+        if let Some(ctx) = ctx {
+            if self.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            let mut ctx = ctx.lock().await;
+
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ctx.ino, || RwLock::new(false));
+            let write_guard = lock.write().await;
+            let mut writer = self.take_writer(&mut ctx).await?;
+            let file = writer.finish()?;
+            file.sync_all()?;
+            self.storage
+                .sync_dir(self.contents_path(ctx.ino).parent().unwrap())?;
+            // write attr only here to avoid serializing it multiple times while writing
+            // it will merge time fields with existing data because it might got change while we kept the handle
+            let ino = ctx.ino;
+            let attr = ctx.attr.clone();
+            drop(ctx);
+            self.set_attr(ino, attr.into()).await?;
+            self.commit_journal(ino).await?;
+            if let Err(err) = self.dedup_content(ino).await {
+                warn!(err = %err, ino, "cannot share the content");
+            }
+            drop(write_guard);
+            self.opened_files_for_write.write().await.remove(&ino);
+            self.write_slot_released.notify_waiters();
+            self.reset_handles(ino, Some(handle), true).await?;
+
+            valid_fh = true;
+            released_ino = Some(ino);
+        }
+
+        if !valid_fh {
+            return Err(FsError::InvalidFileHandle);
+        }
+        if let Some(ino) = released_ino {
+            self.observe(|observer| observer.on_handle_released(ino));
+            if !self.is_opened(ino).await && self.orphans.lock().await.remove(&ino) {
+                self.remove_inode_storage(ino).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The handles which are not released yet, as `(handle, ino, write)`, sorted by handle.
+    ///
+    /// Meant for tooling, like finding which handle keeps a file opened for write when opening it fails with
+    /// [`FsError::AlreadyOpenForWrite`]. Handles opened for read and write are listed once, as write.
+    pub async fn open_handles(&self) -> Vec<(u64, u64, bool)> {
+        let mut handles: Vec<_> = self
+            .opened_files_for_write
+            .read()
+            .await
+            .iter()
+            .map(|(ino, handle)| (*handle, *ino, true))
+            .collect();
+        handles.extend(
+            self.append_handles
+                .read()
+                .await
+                .iter()
+                .map(|(handle, ino)| (*handle, *ino, true)),
+        );
+        for (ino, fhs) in self.opened_files_for_read.read().await.iter() {
+            for handle in fhs {
+                if !handles.iter().any(|(fh, _, _)| fh == handle) {
+                    handles.push((*handle, *ino, false));
+                }
+            }
+        }
+        handles.sort_unstable();
+        handles
+    }
+
+    /// Release a handle its owner didn't, like the ones leaked at shutdown, see [`EncryptedFs::open_handles`].
+    ///
+    /// Handles opened for write are flushed first. If it's released meanwhile with [`EncryptedFs::release`] only one
+    /// of them releases it, the other fails with [`FsError::InvalidFileHandle`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn force_release(&self, handle: u64) -> FsResult<()> {
+        warn!(handle, "force releasing handle");
+        if self.write_handles.read().await.contains_key(&handle) {
+            self.flush(handle).await?;
+        }
+        self.release(handle).await
+    }
+
+    /// Mark the file as opened for write with `handle`, if it's already opened wait up to
+    /// [`FsConfig::open_write_timeout`] for it to be released.
+    async fn take_write_slot(&self, ino: u64, handle: u64) -> FsResult<()> {
+        let timeout = self.open_write_timeout;
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        loop {
+            let notified = self.write_slot_released.notified();
+            tokio::pin!(notified);
+            // register before checking, so we don't miss a release in between
+            notified.as_mut().enable();
+            if let Entry::Vacant(entry) = self.opened_files_for_write.write().await.entry(ino) {
+                entry.insert(handle);
+                return Ok(());
+            }
+            let Some(deadline) = deadline else {
+                return Err(FsError::AlreadyOpenForWrite);
+            };
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Err(FsError::AlreadyOpenForWrite);
+            }
+        }
+    }
+
+    async fn is_opened(&self, ino: u64) -> bool {
+        self.opened_files_for_read.read().await.contains_key(&ino)
+            || self.opened_files_for_write.read().await.contains_key(&ino)
+            || self.append_handles.read().await.values().any(|i| *i == ino)
+    }
+
+    /// Remove the inode and its content of a file which is not in any directory anymore.
+    /// If it's still opened it's kept until the last handle is released.
+    async fn remove_inode_when_closed(&self, ino: u64) -> FsResult<()> {
+        if self.is_opened(ino).await {
+            self.orphans.lock().await.insert(ino);
+            return Ok(());
+        }
+        self.remove_inode_storage(ino).await
+    }
+
+    async fn remove_inode_storage(&self, ino: u64) -> FsResult<()> {
+        let shred = self.shred;
+        {
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            if self.quota.usage().is_some() {
+                // it might be missing or broken on a corrupted volume, it's removed anyway
+                if let Ok(attr) = self.inodes.read(ino, &*self.key().await?) {
+                    if attr.kind == FileType::RegularFile {
+                        self.quota.release(attr.size);
+                    }
+                }
+            }
+            if shred {
+                self.inodes.shred(ino)?;
+            } else {
+                self.inodes.remove(ino)?;
+            }
+        }
+        let contents_path = self.contents_path(ino);
+        if self.storage.is_dir(&contents_path) {
+            self.storage.remove_dir_all(&contents_path)?;
+        } else if self.storage.exists(&contents_path) {
+            self.release_content_ref(&contents_path).await?;
+            // it might be missing on a corrupted volume, see [`EncryptedFs::repair`]
+            self.storage.remove_file(&contents_path)?;
+        }
+        if let Some(cache) = self.block_cache() {
+            cache.invalidate(ino);
+        }
+        let transform_path = self.content_transform_path(ino);
+        if self.storage.exists(&transform_path) {
+            self.storage.remove_file(&transform_path)?;
+        }
+        self.holes.lock().unwrap().remove(&ino);
+        let holes_path = self.content_holes_path(ino);
+        if self.storage.exists(&holes_path) {
+            self.storage.remove_file(&holes_path)?;
+        }
+        let xattr_path = self.xattr_path(ino);
+        if self.storage.exists(&xattr_path) {
+            self.storage.remove_file(&xattr_path)?;
+        }
+        self.journals.lock().unwrap().remove(&ino);
+        let wal_path = self.data_dir.join(WAL_DIR).join(ino.to_string());
+        if self.storage.exists(&wal_path) {
+            if shred {
+                wal::shred(&*self.storage, &wal_path)?;
+            } else {
+                self.storage.remove_dir_all(&wal_path)?;
+            }
+        }
+        self.pending_times.lock().await.remove(&ino);
+        if let Some(cache) = self.attr_cache().await? {
+            if shred {
+                // don't keep the key in memory
+                cache.write().await.pop(&ino);
+            } else {
+                cache.write().await.demote(&ino);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if a file is opened for reading with this handle.
+    pub async fn is_read_handle(&self, fh: u64) -> bool {
+        self.read_handles.read().await.contains_key(&fh)
+    }
+
+    /// Check if a file is opened for writing with this handle, appending included.
+    pub async fn is_write_handle(&self, fh: u64) -> bool {
+        self.write_handles.read().await.contains_key(&fh)
+            || self.append_handles.read().await.contains_key(&fh)
+    }
+
+    /// Writes the contents of `buf` to the file with `ino` starting at `offset`.
+    ///
+    /// If we write outside file size, we fill up with zeros until the `offset`.
+    /// If the file is not opened for writing,
+    /// it will return an error of type [FsError::InvalidFileHandle].
+    #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
+    pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let Some(observer) = &self.observer else {
+            return self.write_unobserved(ino, offset, buf, handle).await;
+        };
+        let start = Instant::now();
+        let res = self.write_unobserved(ino, offset, buf, handle).await;
+        match &res {
+            Ok(len) => observer.on_write(ino, *len, start.elapsed()),
+            Err(err) if err.is_decrypt_failure() => observer.on_decrypt_failure(ino),
+            Err(_) => {}
+        }
+        res
+    }
+
+    async fn write_unobserved(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &[u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let append_ino = self.append_handles.read().await.get(&handle).copied();
+        if let Some(append_ino) = append_ino {
+            if append_ino != ino {
+                return Err(FsError::InvalidFileHandle);
+            }
+            return self.append(ino, buf).await;
+        }
+        let Some(ctx) = self.write_handle(handle).await else {
+            return Err(FsError::InvalidFileHandle);
+        };
+        if ctx.lock().await.ino != ino {
+            return Err(FsError::InvalidFileHandle);
+        }
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.is_immutable() {
+            return Err(FsError::NotPermitted);
+        }
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let write_guard = lock.write().await;
+
+        let mut ctx = ctx.lock().await;
+        // always write at the end, like O_APPEND
+        let offset = if attr.is_append_only() {
+            ctx.attr.size
+        } else {
+            offset
+        };
+
+        // write new data
+        let max_plaintext_len = self
+            .ciphers
+            .cipher_for(&self.contents_path(ino))
+            .max_plaintext_len();
+        if offset > max_plaintext_len as u64 {
+            return Err(FsError::MaxFilesizeExceeded(max_plaintext_len));
+        }
+        // keep within the max file size the cipher can handle
+        #[allow(clippy::cast_possible_truncation)]
+        let data = if offset + buf.len() as u64 > max_plaintext_len as u64 {
+            warn!("writing past the max file size of the cipher, truncating");
+            &buf[..(max_plaintext_len - offset as usize)]
+        } else {
+            buf
+        };
+        // taken before writing, so concurrent writes to other files can't go past the quota together
+        let size = ctx.attr.size;
+        let reservation = self
+            .quota
+            .reserve((offset + data.len() as u64).saturating_sub(size))?;
+        let mut data = data.to_vec();
+        let mut writer = self.take_writer(&mut ctx).await?;
+        let res = self
+            .run_crypto(move || {
+                let res = (|| {
+                    let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
+                        error!(err = %err, "seeking");
+                        err
+                    })?;
+                    if offset != pos {
+                        // we could not seek to the desired position
+                        return Ok(None);
+                    }
+                    let len = writer.write(&data).map_err(|err| {
+                        error!(err = %err, "writing");
+                        err
+                    })?;
+                    Ok::<_, io::Error>(Some((writer.stream_position()?, len)))
+                })();
+                data.zeroize();
+                (writer, res)
+            })
+            .await
+            .and_then(|(writer, res)| {
+                ctx.writer = Some(writer);
+                Ok(res?)
+            });
+        let res = match res {
+            Ok(res) => res,
+            // if the task failed the writer is lost with it, revert makes a new one
+            Err(err) => {
+                self.revert_writer(ino, &mut ctx).await;
+                drop(ctx);
+                drop(write_guard);
+                self.reset_handles(ino, Some(handle), false).await?;
+                return Err(err);
+            }
+        };
+        let Some((pos, len)) = res else {
+            return Ok(0);
+        };
+        reservation.keep(pos.saturating_sub(size));
+
+        if pos > ctx.attr.size {
+            // if we write pass file size set the new size
+            debug!("setting new file size {}", pos);
+            ctx.attr.size = pos;
+        }
+        let now = SystemTime::now();
+        ctx.attr.mtime = now;
+        ctx.attr.ctime = now;
+        ctx.attr.atime = now;
+        ctx.dirty = true;
+        drop(ctx);
+
+        drop(write_guard);
+        self.reset_handles(ino, Some(handle), true).await?;
+
+        Ok(len)
+    }
+
+    /// Write `buf` at the end of the file, for handles from [`EncryptedFs::open_append`].
+    async fn append(&self, ino: u64, buf: &[u8]) -> FsResult<usize> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.is_immutable() {
+            return Err(FsError::NotPermitted);
+        }
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+
+        // the writer might have data not yet in storage, the end of file is after it
+        self.flush_and_reset_writers(ino).await?;
+        let offset = self.get_attr(ino).await?.size;
+
+        let max_plaintext_len = self
+            .ciphers
+            .cipher_for(&self.contents_path(ino))
+            .max_plaintext_len();
+        if offset > max_plaintext_len as u64 {
+            return Err(FsError::MaxFilesizeExceeded(max_plaintext_len));
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let mut data = if offset + buf.len() as u64 > max_plaintext_len as u64 {
+            warn!("writing past the max file size of the cipher, truncating");
+            buf[..(max_plaintext_len - offset as usize)].to_vec()
+        } else {
+            buf.to_vec()
+        };
+        let len = data.len();
+        let reservation = self.quota.reserve(len as u64)?;
+        let mut writer = self
+            .create_content_write_seek(ino, self.open_content_journaled(ino).await?)
+            .await?;
+        let file = self
+            .run_crypto(move || {
+                let res = (|| {
+                    writer.seek(SeekFrom::Start(offset))?;
+                    writer.write_all(&data)?;
+                    writer.finish()
+                })();
+                data.zeroize();
+                res
+            })
+            .await?
+            .map_err(|err| {
+                error!(err = %err, "appending");
+                err
+            })?;
+        file.sync_all()?;
+        self.storage
+            .sync_dir(self.contents_path(ino).parent().unwrap())?;
+
+        let now = SystemTime::now();
+        self.set_attr(
+            ino,
+            SetFileAttr::default()
+                .with_size(offset + len as u64)
+                .with_mtime(now)
+                .with_ctime(now),
+        )
+        .await?;
+        self.commit_journal(ino).await?;
+        reservation.keep(len as u64);
+        // readers and the writer see the new end of file
+        self.reset_handles(ino, None, true).await?;
+
+        Ok(len)
+    }
+
+    /// Flush the data to the underlying storage.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn flush(&self, handle: u64) -> FsResult<()> {
+        self.fsync(handle, false).await
+    }
+
+    /// Flush the data to the underlying storage, like `fsync(2)`.
+    ///
+    /// With `datasync` only the content and the size are synced, like `fdatasync(2)`, the parent directory and the
+    /// pending times are left for later.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn fsync(&self, handle: u64, datasync: bool) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if handle == 0 {
+            // in the case of directory or if the file was crated without being opened we don't use a handle
+            return Ok(());
+        }
+        if self.append_handles.read().await.contains_key(&handle) {
+            // appends are synced when written
+            return Ok(());
+        }
+        let mut flushed_ino = None;
+        if let Some(ctx) = self.read_handle(handle).await {
+            flushed_ino = Some(ctx.lock().await.ino);
+        }
+        let mut valid_fh = flushed_ino.is_some();
+        if let Some(ctx) = self.write_handle(handle).await {
+            // same order as in `write` and `read`, the lock of the inode first and then the handle
+            let ino = ctx.lock().await.ino;
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let write_guard = lock.write().await;
+            let mut ctx = ctx.lock().await;
+            let res = (|| {
+                ctx.writer
+                    .as_mut()
+                    .ok_or(FsError::Other("writer is missing"))?
+                    .flush()?;
+                let file = self.storage.open(&self.contents_path(ctx.ino))?;
+                if datasync {
+                    file.sync_data()?;
+                } else {
+                    file.sync_all()?;
+                    self.storage
+                        .sync_dir(self.contents_path(ctx.ino).parent().unwrap())?;
+                }
+                Ok::<_, FsError>(())
+            })();
+            if let Err(err) = res {
+                let ino = ctx.ino;
+                self.revert_writer(ino, &mut ctx).await;
+                drop(ctx);
+                drop(write_guard);
+                self.reset_handles(ino, Some(handle), false).await?;
+                return Err(err);
+            }
+            let ino = ctx.ino;
+            let set_attr: SetFileAttr = ctx.attr.clone().into();
+            drop(ctx);
+            // the content and the size are synced together
+            self.set_attr(ino, set_attr).await?;
+            self.commit_journal(ino).await?;
+            drop(write_guard);
+            self.reset_handles(ino, Some(handle), true).await?;
+            flushed_ino = Some(ino);
+            valid_fh = true;
+        }
+        if let Some(ino) = flushed_ino.filter(|_| !datasync) {
+            self.flush_inode_times(ino).await?;
+        }
+
+        if !valid_fh {
+            return Err(FsError::InvalidFileHandle);
+        }
+
+        Ok(())
+    }
+
+    /// Flush all opened writers, write the pending times and sync everything in the data dir to disk.
+    ///
+    /// When it returns, all the changes made before the call are durable, like after umount.
+    /// Useful before taking a snapshot or a backup of the data dir while mounted.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn sync_all(&self) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.flush_writers().await?;
+        self.flush_times().await?;
+        self.storage.sync_dir_all(&self.data_dir)?;
+        Ok(())
+    }
+
+    /// Flush and commit the writers of all files opened for write, they stay opened.
+    async fn flush_writers(&self) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let inodes: Vec<u64> = self
+            .opened_files_for_write
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect();
+        for ino in inodes {
+            // `flush_and_reset_writers` needs the caller to hold the write lock
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            self.flush_and_reset_writers(ino).await?;
+        }
+        Ok(())
+    }
+
+    /// Helpful when we want to copy just some portions of the file.
+    pub async fn copy_file_range(
+        &self,
+        file_range_req: &CopyFileRangeReq,
+        size: usize,
+    ) -> FsResult<usize> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if self.is_dir(file_range_req.src_ino) || self.is_dir(file_range_req.dest_ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+
+        // make what was written through the source's writer visible to our read
+        {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(file_range_req.src_ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            self.flush_and_reset_writers(file_range_req.src_ino).await?;
+        }
+
+        let mut buf = vec![0; size];
+        let len = self
+            .read(
+                file_range_req.src_ino,
+                file_range_req.src_offset,
+                &mut buf,
+                file_range_req.src_fh,
+            )
+            .await?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut copied = 0;
+        while copied < len {
+            let written = self
+                .write(
+                    file_range_req.dest_ino,
+                    file_range_req.dest_offset + copied as u64,
+                    &buf[copied..len],
+                    file_range_req.dest_fh,
+                )
+                .await?;
+            if written == 0 {
+                error!(copied, len, "Failed to copy all read bytes");
+                buf.zeroize();
+                return Err(FsError::Other("Failed to copy all read bytes"));
+            }
+            copied += written;
+        }
+        buf.zeroize();
+        Ok(copied)
+    }
+
+    /// Copy the regular file `src_name` to a new file `dst_name`, both in `parent`, like `cp -p`.
+    ///
+    /// The content is decrypted and encrypted again block by block, under the same key but with new nonces.
+    /// Unlike [`EncryptedFs::copy_file_range`] it doesn't go through handles opened by the caller, what was
+    /// written to the source is flushed and the writes to it wait until the copy is done.
+    /// The copy gets the `perm`, `uid`, `gid`, `flags`, `atime` and `mtime` of the source.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn copy_file(
+        &self,
+        parent: u64,
+        src_name: &SecretString,
+        dst_name: &SecretString,
+    ) -> FsResult<FileAttr> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let src = self
+            .find_by_name(parent, src_name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        if src.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(src.ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        self.flush_and_reset_writers(src.ino).await?;
+        // the size might have changed with the flush
+        let src = self.get_attr(src.ino).await?;
+        let reservation = self.quota.reserve(src.size)?;
+
+        let create_attr = CreateFileAttr {
+            kind: FileType::RegularFile,
+            perm: src.perm,
+            uid: src.uid,
+            gid: src.gid,
+            rdev: src.rdev,
+            flags: src.flags,
+        };
+        let (_, mut attr) = self
+            .create(parent, dst_name, create_attr, false, false)
+            .await?;
+
+        let file = self.storage.open_write(&self.contents_path(attr.ino))?;
+        let mut reader = self.create_content_read(src.ino).await?;
+        let mut writer = self.create_content_write(attr.ino, file).await?;
+        stream_util::copy_exact(&mut reader, &mut writer, src.size)?;
+        let file = writer.finish()?;
+        file.sync_all()?;
+        attr.size = src.size;
+        attr.atime = src.atime;
+        attr.mtime = src.mtime;
+        attr.content_mac = Some(self.content_mac(&self.data_dir, &attr, &*self.key().await?)?);
+        self.write_inode_to_storage(&attr).await?;
+        reservation.keep(src.size);
+        {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(attr.ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            if let Err(err) = self.dedup_content(attr.ino).await {
+                warn!(err = %err, ino = attr.ino, "cannot share the content");
+            }
+        }
+        self.get_attr(attr.ino).await
+    }
+
+    /// Open a file. We can open multiple times for read but only one to write at a time.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if write && self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        if self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind == FileType::Symlink {
+            // it's followed by the kernel, the target is read with `read_link`
+            return Err(FsError::InvalidInodeType);
+        }
+        if write && attr.is_immutable() {
+            return Err(FsError::NotPermitted);
+        }
+
+        let mut handle: Option<u64> = None;
+        if read {
+            handle = Some(self.next_handle());
+            self.do_with_read_handle(
+                *handle.as_ref().unwrap(),
+                ReadHandleContextOperation::Create { ino },
+            )
+            .await?;
+        }
+        if write {
+            if handle.is_none() {
+                handle = Some(self.next_handle());
+            }
+            let fh = *handle.as_ref().expect("handle is missing");
+            let mut res = self.take_write_slot(ino, fh).await;
+            if res.is_ok() {
+                res = self
+                    .do_with_write_handle(fh, WriteHandleContextOperation::Create { ino })
+                    .await;
+                if res.is_err() {
+                    self.opened_files_for_write.write().await.remove(&ino);
+                    self.write_slot_released.notify_waiters();
+                }
+            }
+            if res.is_err() && read {
+                // on error remove the read handle if it was added above
+                self.read_handles.write().await.remove(&fh);
+                let mut opened_files_for_read = self.opened_files_for_read.write().await;
+                if let Some(set) = opened_files_for_read.get_mut(&ino) {
+                    set.remove(&fh);
+                    if set.is_empty() {
+                        opened_files_for_read.remove(&ino);
+                    }
+                }
+            }
+            res?;
+        }
+        let fh = handle.unwrap();
+        self.observe(|observer| observer.on_handle_opened(ino));
+        Ok(fh)
+    }
+
+    /// Open a file to append to it, like `O_APPEND` writes always go to the end of the file,
+    /// whatever offset is passed to [`EncryptedFs::write`].
+    ///
+    /// Unlike [`EncryptedFs::open`] for write it doesn't take the single write slot, so it can be
+    /// opened by multiple appenders alongside one other writer. The appends are serialized, each one is
+    /// written and synced as a whole before the next, which makes it slower than a regular writer.
+    /// The order between concurrent appenders is not guaranteed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_append(&self, ino: u64) -> FsResult<u64> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind == FileType::Symlink {
+            return Err(FsError::InvalidInodeType);
+        }
+        if attr.is_immutable() {
+            return Err(FsError::NotPermitted);
+        }
+        let fh = self.next_handle();
+        self.append_handles.write().await.insert(fh, ino);
+        self.observe(|observer| observer.on_handle_opened(ino));
+        Ok(fh)
+    }
+
+    /// Truncates or extends the underlying file, updating the size of this file to become size.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::too_many_lines)]
+    pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        info!("truncate {ino} to {size}");
+        let attr = self.get_attr(ino).await?;
+        if matches!(attr.kind, FileType::Directory) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if attr.is_immutable() || attr.is_append_only() {
+            return Err(FsError::NotPermitted);
+        }
+
+        if size == attr.size {
+            // no-op
+            return Ok(());
+        }
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+
+        // flush writers
+        self.flush_and_reset_writers(ino).await?;
+        let reservation = self.quota.reserve(size.saturating_sub(attr.size))?;
+
+        let file_path = self.contents_path(ino);
+        // a shared content is replaced or copied before it's changed in place
+        self.release_content_ref(&file_path).await?;
+        if size == 0 {
+            debug!("truncate to zero");
+            // replace with an empty file, the old one is kept by the journal until we commit
+            self.journal(ino).before_replace(&*self.key().await?)?;
+            self.storage.create_atomic(&file_path)?.commit()?;
+        } else if self.file_content_transform(ino).await?.is_none() {
+            debug!(
+                "truncate in place size to {}",
+                size.to_formatted_string(&Locale::en)
+            );
+            self.set_len_in_place(ino, attr.size, size).await?;
+        } else {
+            debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
+
+            self.journal(ino).before_replace(&*self.key().await?)?;
+            let mut file = self.storage.create_atomic(&file_path)?;
+            {
+                // have a new scope, so we drop the reader before moving new content files
+                let mut reader = self.create_content_read(ino).await?;
+
+                let mut writer = self.create_content_write(ino, file).await?;
+
+                let len = if size > attr.size {
+                    // increase size, copy existing data until existing size
+                    attr.size
+                } else {
+                    // decrease size, copy existing data until new size
+                    size
+                };
+                stream_util::copy_exact(&mut reader, &mut writer, len)?;
+                if size > attr.size {
+                    // increase size, seek to new size will write zeros
+                    stream_util::fill_zeros(&mut writer, size - attr.size)?;
+                }
+                file = writer.finish()?;
+            }
+            file.commit()?;
+        }
+        self.storage.sync_dir(file_path.parent().unwrap())?;
+
+        let now = SystemTime::now();
+        let set_attr = SetFileAttr::default()
+            .with_size(size)
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.set_attr2(ino, set_attr, true, false).await?;
+        self.commit_journal(ino).await?;
+        reservation.keep(size.saturating_sub(attr.size));
+        self.quota.release(attr.size.saturating_sub(size));
+
+        let attr = self.get_inode_from_storage(ino).await?;
+        println!("attr 1: {:?}", attr.size);
+        let attr = self.get_attr(ino).await?;
+        println!("attr 1: {:?}", attr.size);
+
+        // reset handles because the file has changed
+        self.reset_handles(attr.ino, None, false).await?;
+
+        let attr = self.get_inode_from_storage(ino).await?;
+        println!("attr 2: {:?}", attr.size);
+        let attr = self.get_attr(ino).await?;
+        println!("attr 2: {:?}", attr.size);
+
+        if size != attr.size {
+            error!("error truncating file expected {size} actual {}", attr.size);
+        }
+
+        Ok(())
+    }
+
+    /// Allocate space for `len` bytes starting at `offset`, like `fallocate(2)`, so later writes in that range
+    /// don't fail because the disk is full.
+    ///
+    /// If the range ends after the end of the file, the file is extended with zeros like with
+    /// [`EncryptedFs::set_len`]. The zeros are encrypted, so they take space on disk, the file never has holes.
+    /// With [`FALLOC_FL_KEEP_SIZE`] the size is kept, the space is only reserved in the data dir where supported.
+    /// Other modes, like punching holes, fail with [`FsError::NotSupported`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn fallocate(&self, ino: u64, offset: u64, len: u64, mode: u32) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if mode & !FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(FsError::NotSupported("fallocate mode"));
+        }
+        if len == 0 {
+            return Err(FsError::InvalidInput("len must be greater than 0"));
+        }
+        let end = offset
+            .checked_add(len)
+            .ok_or(FsError::InvalidInput("offset + len overflows"))?;
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        if attr.is_immutable() {
+            return Err(FsError::NotPermitted);
+        }
+        let path = self.contents_path(ino);
+        let cipher = self.ciphers.cipher_for(&path);
+        if end > cipher.max_plaintext_len() as u64 {
+            return Err(FsError::MaxFilesizeExceeded(cipher.max_plaintext_len()));
+        }
+
+        if mode & FALLOC_FL_KEEP_SIZE != 0 {
+            if self.file_content_transform(ino).await?.is_some() {
+                // we don't know how much the blocks take on disk
+                return Ok(());
+            }
+            self.storage
+                .open_write(&path)?
+                .reserve(cipher.ciphertext_len(end))?;
+            return Ok(());
+        }
+        if end <= attr.size {
+            // the blocks are already on disk
+            return Ok(());
+        }
+        self.set_len(ino, end).await?;
+        if self.sparse {
+            // the zeros might be left as holes, the space still needs to be reserved
+            self.storage
+                .open_write(&path)?
+                .reserve(cipher.ciphertext_len(end))?;
+        }
+        Ok(())
+    }
+
+    /// Without a [`ContentTransform`] all the blocks but the last one have the same length on disk, so we can
+    /// remove or add blocks at the end without touching the rest of the file, only the last block is re-encrypted.
+    async fn set_len_in_place(&self, ino: u64, old_size: u64, size: u64) -> FsResult<()> {
+        let path = self.contents_path(ino);
+        let block_len = BLOCK_SIZE as u64;
+        let mut file = self.open_content_journaled(ino).await?;
+        if size < old_size {
+            let last_block = size / block_len;
+            // the part of the last block we keep
+            #[allow(clippy::cast_possible_truncation)]
+            let mut tail = vec![0; (size % block_len) as usize];
+            if !tail.is_empty() {
+                let mut reader = self.create_content_read(ino).await?;
+                reader.seek(SeekFrom::Start(last_block * block_len))?;
+                reader.read_exact(&mut tail)?;
+            }
+            let cipher = self.ciphers.cipher_for(&path);
+            file.set_len(cipher.ciphertext_len(last_block * block_len))?;
+            let res = if tail.is_empty() {
+                file.sync_all()
+            } else {
+                let mut writer = self.create_content_write_seek(ino, file).await?;
+                writer
+                    .seek(SeekFrom::Start(last_block * block_len))
+                    .and_then(|_| writer.write_all(&tail))
+                    .and_then(|()| writer.finish())
+                    .and_then(|file| file.sync_all())
+            };
+            tail.zeroize();
+            res?;
+        } else {
+            // only the zeros are written, seeking after the end writes them or leaves holes, see
+            // [`FsConfig::sparse`]
+            let mut writer = self.create_content_write_seek(ino, file).await?;
+            writer.seek(SeekFrom::Start(size))?;
+            writer.finish()?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// This will write any dirty data to the file from all writers and reset them.
+    /// Timestamps and size will be updated to the storage.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    /// > That is because we want to make sure caller is holding a lock while all writers flush and we can't
+    /// > lock here also as we would end-up in a deadlock.
+    async fn flush_and_reset_writers(&self, ino: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let handle = self.opened_files_for_write.read().await.get(&ino).copied();
+        if let Some(handle) = handle {
+            if let Some(lock) = self.write_handle(handle).await {
+                let mut ctx = lock.lock().await;
+
+                let mut writer = self.take_writer(&mut ctx).await?;
+                let file = writer.finish()?;
+                file.sync_all()?;
+                self.storage
+                    .sync_dir(self.contents_path(ctx.ino).parent().unwrap())?;
+                let set_attr: SetFileAttr = ctx.attr.clone().into();
+                drop(ctx);
+                self.set_attr(ino, set_attr).await?;
+                self.commit_journal(ino).await?;
+                self.reset_handles(ino, Some(handle), true).await?;
+                let mut ctx = lock.lock().await;
+                let writer = self
+                    .create_content_write_seek(ino, self.open_content_journaled(ino).await?)
+                    .await?;
+                ctx.writer = Some(Box::new(writer));
+                ctx.dirty = false;
+                let attr = self.get_inode_from_storage(ino).await?;
+                ctx.attr = attr.into();
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn rename(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if !self.exists(new_parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(new_parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if !self.exists_by_name(parent, name)? {
+            return Err(FsError::NotFound("name not found"));
+        }
+        if *new_name.expose_secret() == "." || *new_name.expose_secret() == ".." {
+            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+        }
+        self.validate_filename(new_name)?;
+
+        if parent == new_parent && name.expose_secret() == new_name.expose_secret() {
+            // no-op
+            return Ok(());
+        }
+        // only the case changes, on a case-insensitive volume it's the same entry
+        let same_entry = parent == new_parent && self.same_name(name, new_name);
+
+        let attr = self
+            .find_by_name(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        if attr.is_immutable() || attr.is_append_only() {
+            return Err(FsError::NotPermitted);
+        }
+        if attr.kind == FileType::Directory && parent != new_parent {
+            // the new parent must not be in the subtree we move, that would detach it from root in a cycle
+            let parent_name = SecretString::from_str("..").unwrap();
+            let mut ino = new_parent;
+            while ino != ROOT_INODE {
+                if ino == attr.ino {
+                    return Err(FsError::InvalidInput("cannot move directory into itself"));
+                }
+                ino = self
+                    .find_by_name(ino, &parent_name)
+                    .await?
+                    .ok_or(FsError::NotFound("parent not found"))?
+                    .ino;
+            }
+        }
+
+        // Only overwrite an existing directory if it's empty
+        let replaced = if same_entry {
+            None
+        } else {
+            self.find_by_name(new_parent, new_name).await.ok().flatten()
+        };
+        if let Some(new_attr) = &replaced {
+            if new_attr.ino == attr.ino {
+                // both names are hard links to the same file, like rename(2) nothing to do
+                return Ok(());
+            }
+            if new_attr.is_immutable() || new_attr.is_append_only() {
+                return Err(FsError::NotPermitted);
+            }
+            if new_attr.kind == FileType::Directory && !self.is_empty(new_attr.ino)? {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        // remove from parent contents
+        self.remove_directory_entry(parent, name).await?;
+        // remove from new_parent contents, if exists
+        if self.exists_by_name(new_parent, new_name)? {
+            self.remove_directory_entry(new_parent, new_name).await?;
+        }
+        // add to new parent contents
+        self.insert_directory_entry(
+            new_parent,
+            &DirectoryEntry {
+                ino: attr.ino,
+                name: new_name.clone(),
+                kind: attr.kind,
+            },
+        )
+        .await?;
+
+        if attr.kind == FileType::Directory {
+            // add the parent link to the new directory
+            self.insert_directory_entry(
+                attr.ino,
+                &DirectoryEntry {
+                    ino: new_parent,
+                    name: SecretBox::new(Box::new("$..".to_owned())),
+                    kind: FileType::Directory,
+                },
+            )
+            .await?;
+        }
+
+        // the `..` of a directory links to its parent
+        if attr.kind == FileType::Directory && parent != new_parent {
+            self.change_nlink(parent, -1).await?;
+            self.change_nlink(new_parent, 1).await?;
+        }
+        if replaced
+            .as_ref()
+            .is_some_and(|replaced| replaced.kind == FileType::Directory)
+        {
+            self.change_nlink(new_parent, -1).await?;
+        }
+
+        // like editors saving to a temp file and renaming it over the original, handles opened on the
+        // replaced file keep reading the old content until they are released
+        if let Some(replaced) = replaced {
+            // a replaced file might still have other hard links
+            if replaced.kind == FileType::Directory
+                || self.change_nlink(replaced.ino, -1).await? == 0
+            {
+                self.remove_inode_when_closed(replaced.ino).await?;
+            }
+        }
+
+        let now = SystemTime::now();
+        let set_attr = SetFileAttr::default()
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.set_attr(parent, set_attr).await?;
+
+        let set_attr = SetFileAttr::default()
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.set_attr(new_parent, set_attr).await?;
+
+        let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
+        self.set_attr(attr.ino, set_attr).await?;
+
+        Ok(())
+    }
+
+    /// Take a snapshot of all files and directories, returns its id.
+    ///
+    /// The inodes are copied, on Unix the contents of files are hard links to the current ones, so it takes little
+    /// space until the files are changed, a file is copied the first time it's written after. Writes are blocked
+    /// while it's taken, so each file is as it was at some point, but files and directories created or removed
+    /// meanwhile might be partially included.
+    ///
+    /// It can be opened read-only with [`EncryptedFs::new_readonly_at`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn snapshot(&self) -> FsResult<u64> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let _migration_guard = self.cipher_migration_lock.read().await;
+        if self.ciphers.migrating_to().is_some() {
+            return Err(FsError::Other("cipher migration in progress"));
+        }
+        // block writes to all files, always in the same order so two snapshots don't deadlock
+        let mut inos = self.inodes.inos()?;
+        inos.sort_unstable();
+        let locks = inos
+            .iter()
+            .map(|ino| {
+                self.read_write_locks
+                    .get_or_insert_with(*ino, || RwLock::new(false))
+            })
+            .collect::<Vec<_>>();
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in &locks {
+            guards.push(lock.write().await);
+        }
+        let opened = self
+            .opened_files_for_write
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        for ino in opened {
+            self.flush_and_reset_writers(ino).await?;
+        }
+        self.flush_times().await?;
+
+        let snapshots_dir = self.data_dir.join(SNAPSHOTS_DIR);
+        self.storage.create_dir_all(&snapshots_dir)?;
+        let id = self.snapshots().await?.last().map_or(1, |id| id + 1);
+        // copy to a temp dir first, so we don't end up with partial snapshots
+        let tmp_dir = snapshots_dir.join(format!(".{id}"));
+        if self.storage.exists(&tmp_dir) {
+            self.storage.remove_dir_all(&tmp_dir)?;
+        }
+        self.inodes.copy_to(&tmp_dir)?;
+        link_contents(
+            &*self.storage,
+            &self.data_dir.join(CONTENTS_DIR),
+            &tmp_dir.join(CONTENTS_DIR),
+        )?;
+        if self.storage.is_dir(&self.data_dir.join(XATTR_DIR)) {
+            copy_dir_content(
+                &*self.storage,
+                &self.data_dir.join(XATTR_DIR),
+                &tmp_dir.join(XATTR_DIR),
+            )?;
+        }
+        self.storage
+            .rename(&tmp_dir, &snapshots_dir.join(id.to_string()))?;
+        self.storage.sync_dir(&snapshots_dir)?;
+
+        // the opened writers need to copy the contents we linked before they change them, which happens when they
+        // are opened again, see `unshare_content`
+        let opened = self
+            .opened_files_for_write
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        for ino in opened {
+            if inos.binary_search(&ino).is_ok() {
+                self.flush_and_reset_writers(ino).await?;
+            } else {
+                // created after we took the locks
+                let lock = self
+                    .read_write_locks
+                    .get_or_insert_with(ino, || RwLock::new(false));
+                let _guard = lock.write().await;
+                self.flush_and_reset_writers(ino).await?;
+            }
+        }
+        drop(guards);
+
+        Ok(id)
+    }
+
+    /// Ids of existing snapshots, sorted ascending.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn snapshots(&self) -> FsResult<Vec<u64>> {
+        let snapshots_dir = self.data_dir.join(SNAPSHOTS_DIR);
+        if !self.storage.is_dir(&snapshots_dir) {
+            return Ok(vec![]);
+        }
+        let mut ids = self
+            .storage
+            .read_dir(&snapshots_dir)?
+            .iter()
+            .filter_map(|name| name.to_str()?.parse::<u64>().ok())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Revert the content and metadata of a file to the ones from a snapshot taken with [`EncryptedFs::snapshot`].
+    ///
+    /// The rest of the files are not affected. The file needs to still exist.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn restore_file(&self, ino: u64, snapshot_id: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let _migration_guard = self.cipher_migration_lock.read().await;
+        if self.ciphers.migrating_to().is_some() {
+            return Err(FsError::Other("cipher migration in progress"));
+        }
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let snapshot_dir = self
+            .data_dir
+            .join(SNAPSHOTS_DIR)
+            .join(snapshot_id.to_string());
+        if !self.storage.is_dir(&snapshot_dir) {
+            return Err(FsError::NotFound("snapshot not found"));
+        }
+        let snapshot_contents = snapshot_dir.join(CONTENTS_DIR).join(ino.to_string());
+        if !self.storage.is_file(&snapshot_contents) {
+            return Err(FsError::NotFound("file not found in snapshot"));
+        }
+        let mut attr =
+            match InodeStore::read_from(&snapshot_dir, ino, &self.ciphers, &*self.key().await?) {
+                Err(FsError::InodeNotFound) => {
+                    return Err(FsError::NotFound("file not found in snapshot"))
+                }
+                res => res?,
+            };
+
+        let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
-        let write_guard = lock.write().await;
+        let _write_guard = lock.write().await;
 
-        let guard = self.write_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        // flush writers
+        self.flush_and_reset_writers(ino).await?;
+        let size = self.get_attr(ino).await?.size;
+        let reservation = self.quota.reserve(attr.size.saturating_sub(size))?;
 
-        // write new data
-        let (pos, len) = {
-            if offset > self.cipher.max_plaintext_len() as u64 {
-                return Err(FsError::MaxFilesizeExceeded(
-                    self.cipher.max_plaintext_len(),
-                ));
+        let file_path = self.contents_path(ino);
+        self.release_content_ref(&file_path).await?;
+        let mut file = self.storage.create_atomic(&file_path)?;
+        io::copy(&mut self.storage.open(&snapshot_contents)?, &mut file)?;
+        file.commit()?;
+        // the transform used to write the content in the snapshot
+        let transform_path = self.content_transform_path(ino);
+        let snapshot_transform_path = snapshot_dir
+            .join(CONTENTS_DIR)
+            .join(transform_path.file_name().unwrap());
+        if self.storage.is_file(&snapshot_transform_path) {
+            self.storage
+                .copy(&snapshot_transform_path, &transform_path)?;
+        } else if self.storage.exists(&transform_path) {
+            self.storage.remove_file(&transform_path)?;
+        }
+        // and the holes in it, the writers were flushed so the current ones are saved
+        self.holes.lock().unwrap().remove(&ino);
+        let holes_path = self.content_holes_path(ino);
+        let snapshot_holes_path = snapshot_dir
+            .join(CONTENTS_DIR)
+            .join(holes_path.file_name().unwrap());
+        if self.storage.is_file(&snapshot_holes_path) {
+            self.storage.copy(&snapshot_holes_path, &holes_path)?;
+        } else if self.storage.exists(&holes_path) {
+            self.storage.remove_file(&holes_path)?;
+        }
+        self.storage.sync_dir(file_path.parent().unwrap())?;
+        {
+            let lock = self
+                .serialize_xattr_locks
+                .get_or_insert_with(ino, || Mutex::new(false));
+            let _guard = lock.lock().await;
+            let xattr_path = self.xattr_path(ino);
+            let snapshot_xattr_path = snapshot_dir.join(XATTR_DIR).join(ino.to_string());
+            if self.storage.is_file(&snapshot_xattr_path) {
+                self.storage
+                    .create_dir_all(&self.data_dir.join(XATTR_DIR))?;
+                let mut file = self.storage.create_atomic(&xattr_path)?;
+                io::copy(&mut self.storage.open(&snapshot_xattr_path)?, &mut file)?;
+                file.commit()?;
+            } else if self.storage.exists(&xattr_path) {
+                self.storage.remove_file(&xattr_path)?;
             }
-            let writer = ctx.writer.as_mut().unwrap();
-            let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
-                error!(err = %err, "seeking");
-                err
-            })?;
-            if offset != pos {
-                // we could not seek to the desired position
-                return Ok(0);
+        }
+
+        // keep current links, the restored file is still in the same directories
+        attr.nlink = self.get_inode_from_storage(ino).await?.nlink;
+        attr.ctime = SystemTime::now();
+        self.write_inode_to_storage(&attr).await?;
+        reservation.keep(attr.size.saturating_sub(size));
+        self.quota.release(size.saturating_sub(attr.size));
+
+        // reset handles because the file has changed
+        self.reset_handles(ino, None, false).await?;
+
+        Ok(())
+    }
+
+    /// Set the extended attribute `name` of an inode, `mode` says what to do if it already exists.
+    ///
+    /// Both names and values are encrypted, see [`XATTR_NAME_MAX_LEN`] and [`XATTR_VALUE_MAX_LEN`] for the limits.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &SecretString,
+        value: &[u8],
+        mode: XattrMode,
+    ) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let name_len = name.expose_secret().len();
+        if name_len == 0 || name_len > XATTR_NAME_MAX_LEN {
+            return Err(FsError::InvalidInput("invalid extended attribute name"));
+        }
+        if value.len() > XATTR_VALUE_MAX_LEN {
+            return Err(FsError::XattrTooBig);
+        }
+        if self.get_attr(ino).await?.is_immutable() {
+            return Err(FsError::NotPermitted);
+        }
+        {
+            let lock = self
+                .serialize_xattr_locks
+                .get_or_insert_with(ino, || Mutex::new(false));
+            let _guard = lock.lock().await;
+            let mut xattrs = self.read_xattrs(ino).await?;
+            let exists = xattrs.contains_key(&*name.expose_secret());
+            match mode {
+                XattrMode::Create if exists => return Err(FsError::AlreadyExists),
+                XattrMode::Replace if !exists => return Err(FsError::XattrNotFound),
+                _ => {}
             }
-            // keep block size to max the cipher can handle
-            #[allow(clippy::cast_possible_truncation)]
-            let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
-                warn!("writing more than max block size, truncating");
-                &buf[..(self.cipher.max_plaintext_len() - offset as usize)]
-            } else {
-                buf
-            };
-            let len = writer.write(buf).map_err(|err| {
-                error!(err = %err, "writing");
-                err
-            })?;
-            (writer.stream_position()?, len)
+            xattrs.insert(name.expose_secret().to_string(), value.to_vec());
+            self.write_xattrs(ino, &xattrs).await?;
+        }
+        self.set_attr(ino, SetFileAttr::default().with_ctime(SystemTime::now()))
+            .await
+    }
+
+    /// The value of the extended attribute `name` of an inode.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_xattr(&self, ino: u64, name: &SecretString) -> FsResult<SecretVec<u8>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        let mut xattrs = self.read_xattrs(ino).await?;
+        xattrs
+            .remove(&*name.expose_secret())
+            .map(|value| SecretVec::new(Box::new(value)))
+            .ok_or(FsError::XattrNotFound)
+    }
+
+    /// Names of the extended attributes of an inode, sorted.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn list_xattr(&self, ino: u64) -> FsResult<Vec<SecretString>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        Ok(self
+            .read_xattrs(ino)
+            .await?
+            .into_keys()
+            .map(|name| SecretString::new(Box::new(name)))
+            .collect())
+    }
+
+    /// Remove the extended attribute `name` of an inode.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_xattr(&self, ino: u64, name: &SecretString) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if self.get_attr(ino).await?.is_immutable() {
+            return Err(FsError::NotPermitted);
+        }
+        {
+            let lock = self
+                .serialize_xattr_locks
+                .get_or_insert_with(ino, || Mutex::new(false));
+            let _guard = lock.lock().await;
+            let mut xattrs = self.read_xattrs(ino).await?;
+            if xattrs.remove(&*name.expose_secret()).is_none() {
+                return Err(FsError::XattrNotFound);
+            }
+            self.write_xattrs(ino, &xattrs).await?;
+        }
+        self.set_attr(ino, SetFileAttr::default().with_ctime(SystemTime::now()))
+            .await
+    }
+
+    async fn read_xattrs(&self, ino: u64) -> FsResult<BTreeMap<String, Vec<u8>>> {
+        let path = self.xattr_path(ino);
+        if !self.storage.is_file(&path) {
+            return Ok(BTreeMap::new());
+        }
+        let (file, cipher) = self.ciphers.open(&path)?;
+        let mut padded: Vec<u8> = bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &*self.key().await?),
+            bincode_util::XATTR_LIMIT + XATTR_PADDING as u64,
+        )?;
+        // the padding is ignored as trailing bytes
+        let xattrs = bincode_util::deserialize_from(padded.as_slice(), bincode_util::XATTR_LIMIT);
+        padded.zeroize();
+        Ok(xattrs?)
+    }
+
+    async fn write_xattrs(&self, ino: u64, xattrs: &BTreeMap<String, Vec<u8>>) -> FsResult<()> {
+        let path = self.xattr_path(ino);
+        if xattrs.is_empty() {
+            if self.storage.exists(&path) {
+                self.storage.remove_file(&path)?;
+            }
+            return Ok(());
+        }
+        let mut padded = bincode::serialize(xattrs)?;
+        if padded.len() as u64 > bincode_util::XATTR_LIMIT {
+            padded.zeroize();
+            return Err(FsError::XattrTooBig);
+        }
+        padded.resize(padded.len().next_multiple_of(XATTR_PADDING), 0);
+        self.storage
+            .create_dir_all(&self.data_dir.join(XATTR_DIR))?;
+        let res = atomic_serialize_encrypt_into(
+            &*self.storage,
+            &path,
+            &padded,
+            self.ciphers.for_write(&path)?,
+            &*self.key().await?,
+        );
+        padded.zeroize();
+        res
+    }
+
+    /// Create a crypto writer using internal encryption info.
+    pub async fn create_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
+        &self,
+        file: W,
+    ) -> FsResult<impl CryptoWrite<W>> {
+        Ok(crypto::create_write(
+            file,
+            self.ciphers.cipher(),
+            &*self.key().await?,
+        ))
+    }
+
+    /// Create a crypto writer with seek using internal encryption info.
+    pub async fn create_write_seek<W: Write + Seek + Read + Send + Sync + 'static>(
+        &self,
+        file: W,
+    ) -> FsResult<impl CryptoWriteSeek<W>> {
+        Ok(crypto::create_write_seek(
+            file,
+            self.ciphers.cipher(),
+            &*self.key().await?,
+        ))
+    }
+
+    /// Create a crypto reader using internal encryption info.
+    pub async fn create_read<R: Read + Send + Sync>(
+        &self,
+        reader: R,
+    ) -> FsResult<impl CryptoRead<R>> {
+        Ok(crypto::create_read(
+            reader,
+            self.ciphers.cipher(),
+            &*self.key().await?,
+        ))
+    }
+
+    /// Create a crypto reader with seek using internal encryption info.
+    pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
+        &self,
+        reader: R,
+    ) -> FsResult<impl CryptoReadSeek<R>> {
+        Ok(crypto::create_read_seek(
+            reader,
+            self.ciphers.cipher(),
+            &*self.key().await?,
+        ))
+    }
+
+    /// Open the content of a file for read, using the cipher and [`ContentTransform`] the file was created with.
+    async fn create_content_read(
+        &self,
+        ino: u64,
+    ) -> FsResult<impl CryptoReadSeek<Box<dyn StorageFile>>> {
+        let (file, cipher) = self.ciphers.open(&self.contents_path(ino))?;
+        Ok(crypto::create_read_seek_with_transform(
+            file,
+            cipher,
+            &self.file_key(ino).await?,
+            self.file_content_transform(ino).await?,
+            Some(self.holes(ino).await?),
+        ))
+    }
+
+    /// Create a crypto writer for the content of a file, using the [`ContentTransform`] the file was created with.
+    async fn create_content_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
+        &self,
+        ino: u64,
+        file: W,
+    ) -> FsResult<impl CryptoWrite<W>> {
+        Ok(crypto::create_write_with_transform(
+            file,
+            self.ciphers.for_write(&self.contents_path(ino))?,
+            &self.file_key(ino).await?,
+            self.file_content_transform(ino).await?,
+            self.nonce_counter(),
+        ))
+    }
+
+    /// Create a crypto writer with seek for the content of a file, using the [`ContentTransform`] the file was created with.
+    async fn create_content_write_seek<W: Write + Seek + Read + Send + Sync + 'static>(
+        &self,
+        ino: u64,
+        file: W,
+    ) -> FsResult<impl CryptoWriteSeek<W>> {
+        Ok(crypto::create_write_seek_with_transform(
+            file,
+            self.ciphers.for_write(&self.contents_path(ino))?,
+            &self.file_key(ino).await?,
+            self.file_content_transform(ino).await?,
+            self.nonce_counter(),
+            Some(self.holes(ino).await?),
+            self.sparse,
+        ))
+    }
+
+    /// The key the content of a file is encrypted with, see [`FileAttr::file_key`].
+    async fn file_key(&self, ino: u64) -> FsResult<SecretVec<u8>> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        Ok(content_key(attr.file_key, &*self.key().await?))
+    }
+
+    /// The [`HoleMap`] of the content of a file, shared by all its readers and writers.
+    #[allow(clippy::missing_panics_doc)]
+    async fn holes(&self, ino: u64) -> FsResult<Arc<HoleMap>> {
+        if let Some(holes) = self.holes.lock().unwrap().get(&ino) {
+            return Ok(holes.clone());
+        }
+        let holes = match self.read_holes(ino).await? {
+            Some(ranges) => {
+                let attr = self.get_inode_from_cache_or_storage(ino).await?;
+                if attr.holes_mac != Some(holes_mac(ino, &ranges)) {
+                    // without it the zeroed blocks are refused, like when it's missing
+                    return Err(FsError::Other("hole map doesn't match the inode"));
+                }
+                HoleMap::from_ranges(&ranges)
+            }
+            None => HoleMap::default(),
         };
+        // another task might have loaded it in the meantime
+        Ok(self
+            .holes
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_insert_with(|| Arc::new(holes))
+            .clone())
+    }
 
-        // let size = ctx.attr.size;
-        if pos > ctx.attr.size {
-            // if we write pass file size set the new size
-            debug!("setting new file size {}", pos);
-            ctx.attr.size = pos;
+    /// The ranges of the [`HoleMap`] saved for a file, if it has one.
+    async fn read_holes(&self, ino: u64) -> FsResult<Option<Vec<(u64, u64)>>> {
+        let path = self.content_holes_path(ino);
+        if !self.storage.exists(&path) {
+            return Ok(None);
         }
-        let now = SystemTime::now();
-        ctx.attr.mtime = now;
-        ctx.attr.ctime = now;
-        ctx.attr.atime = now;
-        drop(ctx);
+        let (file, cipher) = self.ciphers.open(&path)?;
+        Ok(Some(bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &*self.key().await?),
+            bincode_util::HOLES_LIMIT,
+        )?))
+    }
 
-        drop(write_guard);
-        self.reset_handles(ino, Some(handle), true).await?;
+    /// Save the [`HoleMap`] of a file if blocks were added to it or removed, and its MAC in the inode.
+    ///
+    /// It needs to be saved before the content is committed, else the holes can't be read after a crash. The
+    /// [`Journal`] keeps the previous one, so a rollback restores it together with the content and the inode.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_locks.get(ino)`.
+    #[allow(clippy::missing_panics_doc)]
+    async fn save_holes(&self, ino: u64) -> FsResult<()> {
+        let Some(holes) = self.holes.lock().unwrap().get(&ino).cloned() else {
+            return Ok(());
+        };
+        if !holes.take_dirty() {
+            return Ok(());
+        }
+        let path = self.content_holes_path(ino);
+        let res = async {
+            let ranges = holes.ranges();
+            atomic_serialize_encrypt_into(
+                &*self.storage,
+                &path,
+                &ranges,
+                self.ciphers.for_write(&path)?,
+                &*self.key().await?,
+            )?;
+            self.update_holes_mac(ino, Some(holes_mac(ino, &ranges)))
+                .await
+        }
+        .await;
+        if res.is_err() {
+            holes.set_dirty();
+        }
+        res
+    }
+
+    /// Blocks of 512 bytes the content of a file with holes takes on disk, `None` if it has no holes.
+    #[allow(clippy::missing_panics_doc)]
+    fn sparse_blocks(&self, ino: u64) -> FsResult<Option<u64>> {
+        let has_holes = self
+            .holes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .is_some_and(|holes| !holes.is_empty())
+            || self.storage.exists(&self.content_holes_path(ino));
+        if !has_holes {
+            return Ok(None);
+        }
+        Ok(self.storage.metadata(&self.contents_path(ino))?.blocks)
+    }
 
-        self.sizes_write
+    /// The [`Journal`] of the content of a file, shared by all its writers.
+    #[allow(clippy::missing_panics_doc)]
+    fn journal(&self, ino: u64) -> Arc<Journal> {
+        self.journals
             .lock()
-            .await
-            .get_mut(&ino)
             .unwrap()
-            .fetch_add(len as u64, Ordering::SeqCst);
-        if buf.len() != len {
-            // error!(
-            //     "size mismatch in write(), size {size} offset {offset} buf_len {} len {len}",
-            //     buf.len()
-            // );
-        }
-        // warn!(
-        //     "written uncommited for {ino} size {}",
-        //     self.sizes_write
-        //         .lock()
-        //         .await
-        //         .get(&ino)
-        //         .unwrap()
-        //         .load(Ordering::SeqCst)
-        // );
+            .entry(ino)
+            .or_insert_with(|| {
+                let contents_path = self.contents_path(ino);
+                let chunk_len = self
+                    .ciphers
+                    .cipher_for(&contents_path)
+                    .ciphertext_len(BLOCK_SIZE as u64);
+                Arc::new(Journal::new(
+                    self.data_dir.join(WAL_DIR).join(ino.to_string()),
+                    contents_path,
+                    self.inodes.clone(),
+                    ino,
+                    chunk_len,
+                    self.ciphers.clone(),
+                ))
+            })
+            .clone()
+    }
 
-        Ok(len)
+    /// Open the content of a file for write, what it had at the last commit is saved in its [`Journal`]
+    /// before it's overwritten.
+    async fn open_content_journaled(&self, ino: u64) -> FsResult<JournaledFile> {
+        self.unshare_content(ino).await?;
+        let file = self.storage.open_write(&self.contents_path(ino))?;
+        Ok(JournaledFile::new(
+            file,
+            self.journal(ino),
+            self.key().await?,
+        ))
     }
 
-    /// Flush the data to the underlying storage.
+    /// The content and the inode of the file are synced, the version from the last commit is not needed anymore.
     #[allow(clippy::missing_panics_doc)]
-    pub async fn flush(&self, handle: u64) -> FsResult<()> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
+    async fn commit_journal(&self, ino: u64) -> FsResult<()> {
+        self.save_holes(ino).await?;
+        // saved before the commit, so a rollback restores it together with the content
+        self.update_content_mac(ino).await?;
+        let journal = self.journals.lock().unwrap().get(&ino).cloned();
+        if let Some(journal) = journal {
+            journal.commit()?;
         }
-        if handle == 0 {
-            // in the case of directory or if the file was crated without being opened we don't use a handle
+        Ok(())
+    }
+
+    /// MAC of the content of the file with `attr` in `root`, which is the data dir or a snapshot.
+    fn content_mac(&self, root: &Path, attr: &FileAttr, key: &SecretVec<u8>) -> FsResult<[u8; 32]> {
+        let contents_dir = root.join(CONTENTS_DIR);
+        let path = contents_dir.join(attr.ino.to_string());
+        let cipher = self.ciphers.cipher_for(&path);
+        let mut block_len = cipher.ciphertext_len(BLOCK_SIZE as u64);
+        if self
+            .storage
+            .exists(&contents_dir.join(format!("{}{CONTENT_TRANSFORM_SUFFIX}", attr.ino)))
+        {
+            block_len += FRAME_HEADER_LEN as u64;
+        }
+        Ok(crypto::content_mac(
+            &mut self.storage.open(&path)?,
+            attr.ino,
+            attr.size,
+            block_len,
+            cipher.tag_len(),
+            &content_key(attr.file_key, key),
+        )?)
+    }
+
+    /// Save the MAC of the current content of a regular file in its inode.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_locks.get(ino)`.
+    async fn update_content_mac(&self, ino: u64) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind != FileType::RegularFile {
             return Ok(());
         }
-        let lock = self.read_handles.read().await;
-        let mut valid_fh = lock.get(&handle).is_some();
-        let lock = self.write_handles.read().await;
-        if let Some(ctx) = lock.get(&handle) {
-            let mut ctx = ctx.lock().await;
-            let lock = self
-                .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
-            let write_guard = lock.write().await;
-            ctx.writer.as_mut().expect("writer is missing").flush()?;
-            File::open(self.contents_path(ctx.ino))?.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-            drop(write_guard);
-            let ino = ctx.ino;
-            drop(ctx);
-            self.reset_handles(ino, Some(handle), true).await?;
-            valid_fh = true;
+        let mac = self.content_mac(&self.data_dir, &attr, &*self.key().await?)?;
+        if attr.content_mac == Some(mac) {
+            return Ok(());
         }
+        attr.content_mac = Some(mac);
+        self.write_inode_to_storage(&attr).await
+    }
 
-        if !valid_fh {
-            return Err(FsError::InvalidFileHandle);
+    /// Save `mac`, of the [`HoleMap`] of a file, in its inode.
+    async fn update_holes_mac(&self, ino: u64, mac: Option<[u8; 32]>) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.holes_mac == mac {
+            return Ok(());
         }
+        attr.holes_mac = mac;
+        self.write_inode_to_storage(&attr).await
+    }
 
+    /// Share the content of a file with the files which have the same content, see [`FsConfig::dedup`].
+    ///
+    /// The content needs to be committed. Handles are not reset, the plaintext is the same.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_locks.get(ino)`.
+    async fn dedup_content(&self, ino: u64) -> FsResult<()> {
+        if !cfg!(unix) || !self.dedup || self.ciphers.migrating_to().is_some() {
+            return Ok(());
+        }
+        let path = self.contents_path(ino);
+        if self.storage.metadata(&path)?.len == 0
+            || self.storage.exists(&self.content_holes_path(ino))
+        {
+            // holes are read only with the map of the file they belong to
+            return Ok(());
+        }
+        let key = self.key().await?;
+        let hash = crypto::hash_reader(&mut self.create_content_read(ino).await?)?;
+        let name = crypto::content_ref_name(
+            &hash,
+            self.ciphers.cipher_for(&path),
+            self.file_content_transform_id(ino).await?,
+            &key,
+        );
+        let refs_dir = self.data_dir.join(CONTENTS_REFS_DIR);
+        let ref_path = refs_dir.join(&name);
+
+        let _guard = self.content_refs_lock.lock().await;
+        match self.storage.metadata(&ref_path) {
+            Ok(ref_metadata) if self.storage.metadata(&path)?.is_same_file(&ref_metadata) => {}
+            Ok(_) => {
+                let ref_key = self.content_ref_key(&ref_path, &key)?;
+                // replace our copy with a link to the shared one, the journal brings ours and the inode back if we
+                // crash
+                self.journal(ino).before_replace(&key)?;
+                let tmp = refs_dir.join(format!(".{:016x}", crypto::create_rng().next_u64()));
+                self.storage.hard_link(&ref_path, &tmp)?;
+                self.storage.rename(&tmp, &path)?;
+                self.storage.sync_dir(path.parent().unwrap())?;
+                // it's encrypted with the key of the file which shared it first
+                let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+                attr.file_key = ref_key;
+                self.write_inode_to_storage(&attr).await?;
+                // saves the MAC of the shared content, it has other tags
+                self.commit_journal(ino).await?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.storage.create_dir_all(&refs_dir)?;
+                // the key first, so a shared content always has it
+                let key_path = refs_dir.join(format!("{name}{CONTENT_REF_KEY_SUFFIX}"));
+                let file_key = self.get_inode_from_cache_or_storage(ino).await?.file_key;
+                atomic_serialize_encrypt_into(
+                    &*self.storage,
+                    &key_path,
+                    &file_key,
+                    self.ciphers.for_write(&key_path)?,
+                    &key,
+                )?;
+                self.storage.hard_link(&path, &ref_path)?;
+                self.storage.sync_dir(&refs_dir)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
         Ok(())
     }
 
-    /// Helpful when we want to copy just some portions of the file.
-    pub async fn copy_file_range(
-        &self,
-        file_range_req: &CopyFileRangeReq,
-        size: usize,
-    ) -> FsResult<usize> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
+    /// The [`FileKey`] a shared content is encrypted with, `None` if it uses the volume key.
+    fn content_ref_key(&self, ref_path: &Path, key: &SecretVec<u8>) -> FsResult<Option<FileKey>> {
+        let mut key_path = ref_path.as_os_str().to_owned();
+        key_path.push(CONTENT_REF_KEY_SUFFIX);
+        let key_path = PathBuf::from(key_path);
+        if !self.storage.exists(&key_path) {
+            // shared before files had their own key
+            return Ok(None);
         }
-        if self.is_dir(file_range_req.src_ino) || self.is_dir(file_range_req.dest_ino) {
-            return Err(FsError::InvalidInodeType);
+        let (file, cipher) = self.ciphers.open(&key_path)?;
+        Ok(bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, key),
+            bincode_util::SMALL_LIMIT,
+        )?)
+    }
+
+    /// Remove a shared content and its key.
+    ///
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with lock on `self.content_refs_lock`.
+    fn remove_content_ref(&self, ref_path: &Path) -> FsResult<()> {
+        self.storage.remove_file(ref_path)?;
+        let mut key_path = ref_path.as_os_str().to_owned();
+        key_path.push(CONTENT_REF_KEY_SUFFIX);
+        if let Err(err) = self.storage.remove_file(Path::new(&key_path)) {
+            // shared before files had their own key
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
         }
+        self.storage.sync_dir(ref_path.parent().unwrap())?;
+        Ok(())
+    }
 
-        let mut buf = vec![0; size];
-        let len = self
-            .read(
-                file_range_req.src_ino,
-                file_range_req.src_offset,
-                &mut buf,
-                file_range_req.src_fh,
-            )
-            .await?;
-        if len == 0 {
-            return Ok(0);
+    /// The shared content in `contents-refs` which `path` is a link to, if any.
+    ///
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with lock on `self.content_refs_lock`.
+    fn content_ref(&self, path: &Path) -> FsResult<Option<PathBuf>> {
+        let refs_dir = self.data_dir.join(CONTENTS_REFS_DIR);
+        let metadata = match self.storage.metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        // the journal also keeps links to contents, so we need to look for it
+        if metadata.hard_links < 2 || !self.storage.is_dir(&refs_dir) {
+            return Ok(None);
         }
-        let mut copied = 0;
-        while copied < size {
-            let len = self
-                .write(
-                    file_range_req.dest_ino,
-                    file_range_req.dest_offset,
-                    &buf[copied..len],
-                    file_range_req.dest_fh,
-                )
-                .await?;
-            if len == 0 && copied < size {
-                error!(len, "Failed to copy all read bytes");
-                return Err(FsError::Other("Failed to copy all read bytes"));
+        for name in self.storage.read_dir(&refs_dir)? {
+            let ref_path = refs_dir.join(name);
+            if metadata.is_same_file(&self.storage.metadata(&ref_path)?) {
+                return Ok(Some(ref_path));
             }
-            copied += len;
         }
-        Ok(len)
+        Ok(None)
     }
 
-    /// Open a file. We can open multiple times for read but only one to write at a time.
-    #[allow(clippy::missing_panics_doc)]
-    pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
-        if write && self.read_only {
-            return Err(FsError::ReadOnly);
+    /// Make a copy of the content of a file only for it if it's shared, with other files or snapshots, before it's
+    /// changed in place.
+    async fn unshare_content(&self, ino: u64) -> FsResult<()> {
+        let path = self.contents_path(ino);
+        let _guard = self.content_refs_lock.lock().await;
+        let links = match self.storage.metadata(&path) {
+            Ok(metadata) => metadata.hard_links,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if links < 2 {
+            return Ok(());
         }
-        if !read && !write {
-            return Err(FsError::InvalidInput(
-                "read and write cannot be false at the same time",
-            ));
+        if let Some(ref_path) = self.content_ref(&path)? {
+            if links <= 2 {
+                // no other file uses it
+                self.remove_content_ref(&ref_path)?;
+                return Ok(());
+            }
         }
-        if self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+        let mut file = self.storage.create_atomic(&path)?;
+        io::copy(&mut self.storage.open(&path)?, &mut file)?;
+        file.commit()?;
+        Ok(())
+    }
+
+    /// Before `path` is removed or replaced, remove the shared content it links to if no other file uses it.
+    async fn release_content_ref(&self, path: &Path) -> FsResult<()> {
+        let _guard = self.content_refs_lock.lock().await;
+        if let Some(ref_path) = self.content_ref(path)? {
+            if self.storage.metadata(path)?.hard_links <= 2 {
+                self.remove_content_ref(&ref_path)?;
+            }
         }
+        Ok(())
+    }
 
-        let mut handle: Option<u64> = None;
-        if read {
-            handle = Some(self.next_handle());
-            self.do_with_read_handle(
-                *handle.as_ref().unwrap(),
-                ReadHandleContextOperation::Create { ino },
-            )
-            .await?;
+    /// Remove the shared contents no file uses anymore, like after a cipher migration which gives each file its
+    /// own copy, and the leftovers of an interrupted [`EncryptedFs::dedup_content`].
+    async fn gc_content_refs(&self) -> FsResult<()> {
+        let refs_dir = self.data_dir.join(CONTENTS_REFS_DIR);
+        if !self.storage.is_dir(&refs_dir) {
+            return Ok(());
         }
-        if write {
-            if self.opened_files_for_write.read().await.contains_key(&ino) {
-                return Err(FsError::AlreadyOpenForWrite);
+        let _guard = self.content_refs_lock.lock().await;
+        for name in self.storage.read_dir(&refs_dir)? {
+            let path = refs_dir.join(&name);
+            let name = name.to_string_lossy().to_string();
+            if name.ends_with(CONTENT_REF_KEY_SUFFIX) {
+                continue;
             }
-            if handle.is_none() {
-                handle = Some(self.next_handle());
+            if name.starts_with('.') || self.storage.metadata(&path)?.hard_links < 2 {
+                self.storage.remove_file(&path)?;
             }
-            let res = self
-                .do_with_write_handle(
-                    *handle.as_ref().expect("handle is missing"),
-                    WriteHandleContextOperation::Create { ino },
-                )
-                .await;
-            if res.is_err() && read {
-                // on error remove the read handle if it was added above,
-                // remove the read handle if it was added above
-                self.read_handles
-                    .write()
-                    .await
-                    .remove(handle.as_ref().unwrap());
+        }
+        // the keys of the ones removed above, or before an interruption
+        for name in self.storage.read_dir(&refs_dir)? {
+            let path = refs_dir.join(&name);
+            let name = name.to_string_lossy().to_string();
+            if let Some(ref_name) = name.strip_suffix(CONTENT_REF_KEY_SUFFIX) {
+                if !self.storage.exists(&refs_dir.join(ref_name)) {
+                    self.storage.remove_file(&path)?;
+                }
             }
-            res?;
         }
-        let fh = handle.unwrap();
-        self.sizes_write
-            .lock()
-            .await
-            .entry(ino)
-            .or_insert(AtomicU64::new(0));
-        self.sizes_read
-            .lock()
-            .await
-            .entry(ino)
-            .or_insert(AtomicU64::new(0));
-        self.requested_read
-            .lock()
-            .await
-            .entry(ino)
-            .or_insert(AtomicU64::new(0));
-        Ok(fh)
+        self.storage.sync_dir(&refs_dir)?;
+        Ok(())
     }
 
-    /// Truncates or extends the underlying file, updating the size of this file to become size.
-    #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::too_many_lines)]
-    pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
-        }
-        info!("truncate {ino} to {size}");
-        let attr = self.get_attr(ino).await?;
-        if matches!(attr.kind, FileType::Directory) {
-            return Err(FsError::InvalidInodeType);
+    /// Forget all the shared contents, the files which share one keep sharing it but new files don't find it
+    /// anymore. Writing to one of them still makes a copy first.
+    async fn forget_content_refs(&self) -> FsResult<()> {
+        let refs_dir = self.data_dir.join(CONTENTS_REFS_DIR);
+        let _guard = self.content_refs_lock.lock().await;
+        if self.storage.is_dir(&refs_dir) {
+            self.storage.remove_dir_all(&refs_dir)?;
+            self.storage.sync_dir(&self.data_dir)?;
         }
+        Ok(())
+    }
 
-        if size == attr.size {
-            // no-op
+    /// Write the data kept in the buffer of the writer to the file and recreate the writer and the reader of the same
+    /// handle over it, so reads see it. It's not committed, that is left for [`EncryptedFs::flush`].
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_locks.get(ino)`.
+    async fn flush_own_writer(
+        &self,
+        ino: u64,
+        handle: u64,
+        write_ctx: &Mutex<WriteHandleContext>,
+        read_ctx: &Mutex<ReadHandleContext>,
+    ) -> FsResult<()> {
+        let mut write_ctx = write_ctx.lock().await;
+        if !write_ctx.dirty || write_ctx.ino != ino {
             return Ok(());
         }
+        // the last block is written only on finish, if not full
+        let res = write_ctx
+            .writer
+            .as_mut()
+            .ok_or(FsError::Other("writer is missing"))?
+            .finish();
+        if let Err(err) = res {
+            self.revert_writer(ino, &mut write_ctx).await;
+            drop(write_ctx);
+            self.reset_handles(ino, Some(handle), false).await?;
+            return Err(err.into());
+        }
+        // continue with the same journal
+        let writer = self
+            .create_content_write_seek(ino, self.open_content_journaled(ino).await?)
+            .await?;
+        write_ctx.writer = Some(Box::new(writer));
+        write_ctx.dirty = false;
+        drop(write_ctx);
+        let reader = self.create_content_read(ino).await?;
+        read_ctx.lock().await.set_reader(Box::new(reader));
+        if let Some(cache) = self.block_cache() {
+            cache.invalidate(ino);
+        }
+        Ok(())
+    }
 
-        let lock = self
-            .read_write_locks
-            .get_or_insert_with(ino, || RwLock::new(false));
-        let _write_guard = lock.write().await;
-
-        // flush writers
-        self.flush_and_reset_writers(ino).await?;
+    /// Take the writer of the handle, to give back when done. If it was lost, like when a write was cancelled while
+    /// encrypting, the content is brought back to the last commit with [`EncryptedFs::revert_writer`], which makes
+    /// a new one.
+    async fn take_writer(
+        &self,
+        ctx: &mut WriteHandleContext,
+    ) -> FsResult<Box<dyn CryptoWriteSeek<JournaledFile>>> {
+        if ctx.writer.is_none() {
+            warn!(
+                ino = ctx.ino,
+                "writer of the handle was lost, reverting to the last commit"
+            );
+            self.revert_writer(ctx.ino, ctx).await;
+        }
+        ctx.writer.take().ok_or(FsError::Other("writer is missing"))
+    }
 
-        let file_path = self.contents_path(ino);
-        if size == 0 {
-            debug!("truncate to zero");
-            // truncate to zero
-            let file = File::create(&file_path)?;
-            file.set_len(0)?;
-            file.sync_all()?;
-        } else {
-            debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
+    /// After a failed write or flush the writer can't be trusted, the block it holds might be already encrypted
+    /// in place, so bring the content back to the last commit and start a new writer from there.
+    /// This way the size never counts data which didn't make it to disk, like when the disk is full.
+    async fn revert_writer(&self, ino: u64, ctx: &mut WriteHandleContext) {
+        ctx.writer = None;
+        ctx.dirty = false;
+        let res = async {
+            let journal = self.journals.lock().unwrap().get(&ino).cloned();
+            if let Some(journal) = journal {
+                journal.revert(&*self.key().await?)?;
+                // the hole map was brought back too, the inode is kept so it needs its MAC
+                self.holes.lock().unwrap().remove(&ino);
+                let mac = self
+                    .read_holes(ino)
+                    .await?
+                    .map(|ranges| holes_mac(ino, &ranges));
+                self.update_holes_mac(ino, mac).await?;
+            }
+            let writer = self
+                .create_content_write_seek(ino, self.open_content_journaled(ino).await?)
+                .await?;
+            ctx.writer = Some(Box::new(writer));
+            let size = ctx.attr.size;
+            ctx.attr = self.get_inode_from_storage(ino).await?.into();
+            // what was written since the last flush is lost
+            self.quota.release(size.saturating_sub(ctx.attr.size));
+            Ok::<_, FsError>(())
+        }
+        .await;
+        if let Err(err) = res {
+            // next writes fail until the file is opened again, the journal is rolled back on the next start
+            error!(err = %err, ino, "cannot revert writer after a failed write");
+        }
+    }
 
-            let mut file = fs_util::open_atomic_write(&file_path)?;
+    /// Roll back the files with changes not committed before a crash or power loss.
+    async fn rollback_journals(&self) -> FsResult<()> {
+        let wal_dir = self.data_dir.join(WAL_DIR);
+        if !self.storage.is_dir(&wal_dir) {
+            return Ok(());
+        }
+        let mut dirs = vec![];
+        for name in self.storage.read_dir(&wal_dir)? {
+            let path = wal_dir.join(name);
+            if let Some(ino) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<u64>().ok())
             {
-                // have a new scope, so we drop the reader before moving new content files
-                let mut reader = self.create_read(File::open(file_path.as_path())?).await?;
-
-                let mut writer = self.create_write(file).await?;
-
-                let len = if size > attr.size {
-                    // increase size, copy existing data until existing size
-                    attr.size
-                } else {
-                    // decrease size, copy existing data until new size
-                    size
-                };
-                stream_util::copy_exact(&mut reader, &mut writer, len)?;
-                if size > attr.size {
-                    // increase size, seek to new size will write zeros
-                    stream_util::fill_zeros(&mut writer, size - attr.size)?;
-                }
-                file = writer.finish()?;
+                dirs.push((ino, path));
             }
-            file.commit()?;
         }
-        File::open(file_path.parent().unwrap())?.sync_all()?;
-
-        let now = SystemTime::now();
-        let set_attr = SetFileAttr::default()
-            .with_size(size)
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr2(ino, set_attr, true).await?;
+        if dirs.is_empty() {
+            return Ok(());
+        }
+        if self.read_only {
+            warn!(
+                files = dirs.len(),
+                "some files have changes not committed before a crash, open read-write to roll them back"
+            );
+            return Ok(());
+        }
+        let key = self.key().await?;
+        for (ino, dir) in dirs {
+            warn!(ino, "rolling back changes not committed before a crash");
+            wal::rollback(
+                &dir,
+                &self.contents_path(ino),
+                &self.inodes,
+                ino,
+                &self.ciphers,
+                &key,
+            )?;
+        }
+        Ok(())
+    }
 
-        let attr = self.get_inode_from_storage(ino).await?;
-        println!("attr 1: {:?}", attr.size);
-        let attr = self.get_attr(ino).await?;
-        println!("attr 1: {:?}", attr.size);
+    /// The [`ContentTransform`] the file was created with, if any.
+    async fn file_content_transform(
+        &self,
+        ino: u64,
+    ) -> FsResult<Option<Arc<dyn ContentTransform>>> {
+        let Some(id) = self.file_content_transform_id(ino).await? else {
+            return Ok(None);
+        };
+        self.content_transforms
+            .iter()
+            .find(|transform| transform.id() == id)
+            .cloned()
+            .map(Some)
+            .ok_or(FsError::Other(
+                "file was created with a content transform which is not set",
+            ))
+    }
 
-        // reset handles because the file has changed
-        self.reset_handles(attr.ino, None, false).await?;
+    async fn file_content_transform_id(&self, ino: u64) -> FsResult<Option<u8>> {
+        let path = self.content_transform_path(ino);
+        if !self.storage.exists(&path) {
+            return Ok(None);
+        }
+        let (file, cipher) = self.ciphers.open(&path)?;
+        Ok(Some(bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &*self.key().await?),
+            bincode_util::SMALL_LIMIT,
+        )?))
+    }
 
-        let attr = self.get_inode_from_storage(ino).await?;
-        println!("attr 2: {:?}", attr.size);
+    /// Details about how a file is stored, like the cipher, size on disk and the content transform.
+    ///
+    /// Useful for tooling and debugging.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn file_info(&self, ino: u64) -> FsResult<FileInfo> {
         let attr = self.get_attr(ino).await?;
-        println!("attr 2: {:?}", attr.size);
-
-        if size != attr.size {
-            error!("error truncating file expected {size} actual {}", attr.size);
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        let content_transform = self.file_content_transform_id(ino).await?;
+        let path = self.contents_path(ino);
+        let metadata = self.storage.metadata(&path)?;
+        let cipher = self.ciphers.cipher_for(&path);
+        let mut block_len = BLOCK_SIZE + cipher.per_block_overhead();
+        if content_transform.is_some() {
+            block_len += FRAME_HEADER_LEN;
         }
+        Ok(FileInfo {
+            ino,
+            cipher,
+            format_version: CONTENT_FORMAT_VERSION,
+            content_transform,
+            size: attr.size,
+            disk_size: metadata.len,
+            blocks: metadata.len.div_ceil(block_len as u64),
+            sparse: metadata.is_sparse(),
+        })
+    }
 
+    /// Check the content of a regular file against the MAC kept in its inode.
+    ///
+    /// Each block is authenticated when it's read, but that doesn't detect if blocks were removed from the end
+    /// of the file or replaced with the ones at the same position in another file. If the content was changed
+    /// like that outside the filesystem it fails with [`FsError::IntegrityCheckFailed`].
+    ///
+    /// The MAC is updated on flush and release, so a file opened for write fails with [`FsError::AlreadyOpenForWrite`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn verify_file(&self, ino: u64) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _read_guard = lock.read().await;
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        if self.opened_files_for_write.read().await.contains_key(&ino) {
+            return Err(FsError::AlreadyOpenForWrite);
+        }
+        let mac = self.content_mac(&self.data_dir, &attr, &*self.key().await?)?;
+        if attr.content_mac != Some(mac) {
+            error!(ino, "content doesn't match its MAC");
+            return Err(FsError::IntegrityCheckFailed);
+        }
         Ok(())
     }
 
-    /// This will write any dirty data to the file from all writers and reset them.
-    /// Timestamps and size will be updated to the storage.
-    /// > ⚠️ **Warning**
-    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
-    /// > That is because we want to make sure caller is holding a lock while all writers flush and we can't
-    /// > lock here also as we would end-up in a deadlock.
-    async fn flush_and_reset_writers(&self, ino: u64) -> FsResult<()> {
+    /// Re-encrypt all the data with another cipher, while the filesystem is in use.
+    ///
+    /// Files are migrated one by one, while migrating each file is read with the cipher it's encrypted with
+    /// and new files are created with the new cipher. If it's interrupted, like on a crash, the migration is
+    /// resumed by calling it again with the same cipher. Snapshots can't be created or restored until it's finished.
+    ///
+    /// The encryption key stays the same, so the ciphers need to have the same key length.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn migrate_cipher(&self, cipher: Cipher) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        let opened_files_for_write_guard = self.opened_files_for_write.read().await;
-        let handle = opened_files_for_write_guard.get(&ino);
-        if let Some(handle) = handle {
-            let write_handles_guard = self.write_handles.write().await;
-            let ctx = write_handles_guard.get(handle);
-            if let Some(lock) = ctx {
-                let mut ctx = lock.lock().await;
-
-                let mut writer = ctx.writer.take().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-                let handle = *handle;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
-                drop(ctx);
-                drop(opened_files_for_write_guard);
-                drop(write_handles_guard);
-                self.set_attr(ino, set_attr).await?;
-                self.reset_handles(ino, Some(handle), true).await?;
-                let write_handles_guard = self.write_handles.write().await;
-                let mut ctx = write_handles_guard.get(&handle).unwrap().lock().await;
-                let writer = self
-                    .create_write_seek(
-                        OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(self.contents_path(ino))?,
-                    )
-                    .await?;
-                ctx.writer = Some(Box::new(writer));
-                let attr = self.get_inode_from_storage(ino).await?;
-                ctx.attr = attr.into();
+        let _guard = self.cipher_migration_lock.write().await;
+        if self.ciphers.migrating_to().is_none() && self.ciphers.cipher() == cipher {
+            return Ok(());
+        }
+        if self.inodes.backend() == InodeBackend::Db {
+            return Err(FsError::NotSupported(
+                "cipher migration with the inode db backend",
+            ));
+        }
+        info!(from = %self.ciphers.cipher(), to = %cipher, "migrating cipher");
+        self.ciphers.start(cipher)?;
+        let key = self.key().await?;
+        self.migrate_data_dir(&self.data_dir, true, &key).await?;
+        let snapshots_dir = self.data_dir.join(SNAPSHOTS_DIR);
+        if self.storage.is_dir(&snapshots_dir) {
+            for name in self.storage.read_dir(&snapshots_dir)? {
+                let path = snapshots_dir.join(&name);
+                // skip partial snapshots, they are removed on the next snapshot
+                if self.storage.is_dir(&path) && !name.to_string_lossy().starts_with('.') {
+                    self.migrate_data_dir(&path, false, &key).await?;
+                }
             }
         }
+        if let Some(key_file) = &self.key_file {
+            key_file.migrate()?;
+        }
+        self.ciphers.finish()?;
+        // each file has its own copy now
+        self.gc_content_refs().await?;
+        info!(%cipher, "cipher migration finished");
         Ok(())
     }
 
-    #[allow(clippy::missing_panics_doc)]
-    pub async fn rename(
-        &self,
-        parent: u64,
-        name: &SecretString,
-        new_parent: u64,
-        new_name: &SecretString,
-    ) -> FsResult<()> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
+    /// Migrate the `inodes`, `xattr` and `contents` in `root`, which is the data dir or a snapshot.
+    ///
+    /// For the data dir (`live`) we lock each file and reset the handles of opened files.
+    async fn migrate_data_dir(&self, root: &Path, live: bool, key: &SecretVec<u8>) -> FsResult<()> {
+        let inodes_dir = root.join(INODES_DIR);
+        for name in self.storage.read_dir(&inodes_dir)? {
+            let path = inodes_dir.join(name);
+            let Some(ino) = path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .and_then(|name| name.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let rw_lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _rw_guard = if live {
+                // the journal keeps a copy of the inode, commit it before the inode changes the cipher
+                let guard = rw_lock.write().await;
+                self.flush_and_reset_writers(ino).await?;
+                Some(guard)
+            } else {
+                None
+            };
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = if live { Some(lock.write().await) } else { None };
+            self.migrate_file(&path, key, false)?;
         }
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+
+        let xattr_dir = root.join(XATTR_DIR);
+        if self.storage.is_dir(&xattr_dir) {
+            for name in self.storage.read_dir(&xattr_dir)? {
+                let path = xattr_dir.join(name);
+                let Some(ino) = path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .and_then(|name| name.parse::<u64>().ok())
+                else {
+                    continue;
+                };
+                let lock = self
+                    .serialize_xattr_locks
+                    .get_or_insert_with(ino, || Mutex::new(false));
+                let _guard = if live { Some(lock.lock().await) } else { None };
+                self.migrate_file(&path, key, false)?;
+            }
         }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+
+        let contents_dir = root.join(CONTENTS_DIR);
+        for name in self.storage.read_dir(&contents_dir)? {
+            let path = contents_dir.join(name);
+            if self.storage.is_dir(&path) {
+                self.migrate_dir_entries(&path, key).await?;
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let side_file = name
+                .strip_suffix(CONTENT_TRANSFORM_SUFFIX)
+                .or_else(|| name.strip_suffix(CONTENT_HOLES_SUFFIX));
+            let (ino, is_side_file) = match side_file {
+                Some(ino) => (ino.parse::<u64>(), true),
+                None => (name.parse::<u64>(), false),
+            };
+            let Ok(ino) = ino else {
+                continue;
+            };
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = if live { Some(lock.write().await) } else { None };
+            if is_side_file {
+                self.migrate_file(&path, key, false)?;
+                continue;
+            }
+            if live {
+                self.flush_and_reset_writers(ino).await?;
+            }
+            let framed = self
+                .storage
+                .exists(&contents_dir.join(format!("{ino}{CONTENT_TRANSFORM_SUFFIX}")));
+            let content_key = content_key(self.stored_file_key(root, ino, key)?, key);
+            if self.migrate_file(&path, &content_key, framed)? && live {
+                // reset handles because the file has changed
+                self.reset_handles(ino, None, false).await?;
+            }
+            // the tags changed, also when it was migrated before an interruption
+            if live {
+                self.update_content_mac(ino).await?;
+            } else {
+                self.update_snapshot_content_mac(root, ino, key)?;
+            }
         }
-        if !self.exists(new_parent) {
-            return Err(FsError::InodeNotFound);
+        Ok(())
+    }
+
+    /// The [`FileKey`] in the inode of `ino` in `root`, which is the data dir or a snapshot.
+    fn stored_file_key(
+        &self,
+        root: &Path,
+        ino: u64,
+        key: &SecretVec<u8>,
+    ) -> FsResult<Option<FileKey>> {
+        match InodeStore::read_from(root, ino, &self.ciphers, key) {
+            Ok(attr) => Ok(attr.file_key),
+            // orphaned content, it's removed by a repair
+            Err(FsError::InodeNotFound) => Ok(None),
+            Err(err) => Err(err),
         }
-        if !self.is_dir(new_parent) {
-            return Err(FsError::InvalidInodeType);
+    }
+
+    /// Save the MAC of the content of a file in `root` in its inode, after the content was migrated or re-encrypted
+    /// with a new key. `root` is a snapshot, or the data dir while nothing else changes it.
+    fn update_snapshot_content_mac(
+        &self,
+        root: &Path,
+        ino: u64,
+        key: &SecretVec<u8>,
+    ) -> FsResult<()> {
+        let path = root.join(INODES_DIR).join(ino.to_string());
+        if !self.storage.is_file(&path) {
+            return Ok(());
         }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
+        let (file, cipher) = self.ciphers.open(&path)?;
+        let mut attr = inode_store::deserialize_inode(crypto::create_read(file, cipher, key))?;
+        if attr.kind != FileType::RegularFile {
+            return Ok(());
         }
-        self.validate_filename(new_name)?;
+        attr.content_mac = Some(self.content_mac(root, &attr, key)?);
+        atomic_serialize_encrypt_into(
+            &*self.storage,
+            &path,
+            &inode_store::InodeRecord::new(&attr),
+            self.ciphers.for_write(&path)?,
+            key,
+        )?;
+        Ok(())
+    }
 
-        if parent == new_parent && name.expose_secret() == new_name.expose_secret() {
-            // no-op
+    /// Migrate the `ls` and `hash` entries of a directory.
+    ///
+    /// The names of `ls` entries are encrypted, so the entries are moved to the name encrypted with the new
+    /// cipher and the `hash` entry pointing to it is updated.
+    async fn migrate_dir_entries(&self, dir: &Path, key: &SecretVec<u8>) -> FsResult<()> {
+        let Some(to) = self.ciphers.migrating_to() else {
             return Ok(());
+        };
+        for name in self.storage.read_dir(&dir.join(LS_DIR))? {
+            let ls_path = dir.join(LS_DIR).join(&name);
+            let name = name.to_string_lossy().to_string();
+            if name == "$." || name == "$.." {
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(ls_path.to_str().unwrap().to_owned(), || {
+                        RwLock::new(false)
+                    });
+                let _guard = lock.write().await;
+                self.migrate_file(&ls_path, key, false)?;
+                continue;
+            }
+            let from = self.ciphers.cipher_for(&ls_path);
+            if from == to {
+                continue;
+            }
+            let Ok(plain_name) = crypto::decrypt_file_name(&name, from, key) else {
+                warn!(path = %ls_path.display(), "cannot decrypt directory entry name, skipping");
+                continue;
+            };
+            let hash_path = dir.join(HASH_DIR).join(self.hash_file_name(&plain_name));
+            // same order as in `remove_directory_entry`, HASH then LS
+            let hash_lock = self
+                .serialize_dir_entries_hash_locks
+                .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
+                    RwLock::new(false)
+                });
+            let _hash_guard = hash_lock.write().await;
+            let ls_lock = self
+                .serialize_dir_entries_ls_locks
+                .get_or_insert_with(ls_path.to_str().unwrap().to_owned(), || RwLock::new(false));
+            let _ls_guard = ls_lock.write().await;
+            if !self.storage.exists(&ls_path) || self.ciphers.cipher_for(&ls_path) == to {
+                // removed or migrated meanwhile
+                continue;
+            }
+            let (file, from) = self.ciphers.open(&ls_path)?;
+            let (ino, kind): (u64, FileType) = bincode_util::deserialize_from(
+                crypto::create_read(file, from, key),
+                bincode_util::METADATA_LIMIT,
+            )?;
+            let mut new_name = None;
+            if self.storage.exists(&hash_path) {
+                let (file, cipher) = self.ciphers.open(&hash_path)?;
+                let (_, _, hash_ls_name): (u64, FileType, String) = bincode_util::deserialize_from(
+                    crypto::create_read(file, cipher, key),
+                    bincode_util::METADATA_LIMIT,
+                )?;
+                if hash_ls_name != name {
+                    if self.storage.exists(&dir.join(LS_DIR).join(&hash_ls_name)) {
+                        // stale entry, the one in hash is used
+                        self.storage.remove_file(&ls_path)?;
+                        continue;
+                    }
+                    // interrupted after the hash entry was updated
+                    new_name = Some(hash_ls_name);
+                }
+            }
+            let new_name = match new_name {
+                Some(new_name) => new_name,
+                None => crypto::encrypt_file_name(&plain_name, to, key, self.name_padding())?,
+            };
+            // update hash first, so we know the new name if we're interrupted
+            let tmp = self.ciphers.tmp_path();
+            atomic_serialize_encrypt_into(
+                &*self.storage,
+                &tmp,
+                &(ino, kind, new_name.clone()),
+                to,
+                key,
+            )?;
+            if self.storage.exists(&hash_path) {
+                self.ciphers.replace(&hash_path, &tmp)?;
+            } else {
+                self.ciphers.create(&hash_path, &tmp)?;
+            }
+            let tmp = self.ciphers.tmp_path();
+            atomic_serialize_encrypt_into(&*self.storage, &tmp, &(ino, kind), to, key)?;
+            self.ciphers
+                .create(&dir.join(LS_DIR).join(new_name), &tmp)?;
+            self.storage.remove_file(&ls_path)?;
+        }
+        // the rest, like `$.` and `$..`
+        for name in self.storage.read_dir(&dir.join(HASH_DIR))? {
+            let hash_path = dir.join(HASH_DIR).join(name);
+            let lock = self
+                .serialize_dir_entries_hash_locks
+                .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
+                    RwLock::new(false)
+                });
+            let _guard = lock.write().await;
+            self.migrate_file(&hash_path, key, false)?;
         }
+        Ok(())
+    }
 
-        // Only overwrite an existing directory if it's empty
-        if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
-            if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
-                return Err(FsError::NotEmpty);
-            }
+    /// Re-encrypt a file with the cipher we migrate to, if it's not already.
+    ///
+    /// Returns `false` if there was nothing to do.
+    fn migrate_file(&self, path: &Path, key: &SecretVec<u8>, framed: bool) -> FsResult<bool> {
+        let Some(to) = self.ciphers.migrating_to() else {
+            return Ok(false);
+        };
+        if !self.storage.exists(path) {
+            return Ok(false);
         }
+        let (mut file, from) = self.ciphers.open(path)?;
+        if from == to {
+            return Ok(false);
+        }
+        let tmp = self.ciphers.tmp_path();
+        let mut tmp_file = self.storage.create(&tmp)?;
+        crypto::reencrypt(&mut file, &mut tmp_file, from, to, key, framed)?;
+        tmp_file.sync_all()?;
+        self.ciphers.replace(path, &tmp)
+    }
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        // remove from parent contents
-        self.remove_directory_entry(parent, name).await?;
-        // remove from new_parent contents, if exists
-        if self.exists_by_name(new_parent, new_name)? {
-            self.remove_directory_entry(new_parent, new_name).await?;
+    /// Re-encrypt the filesystem in `data_dir` from `old_cipher` to `new_cipher`, while it's not mounted.
+    ///
+    /// The inodes, directory entries and contents are re-encrypted with [`EncryptedFs::migrate_cipher`], each
+    /// file into a temp file which atomically replaces it, tracked in a journal in the data dir. If it's
+    /// interrupted, calling it again with the same ciphers finishes it, until then the filesystem can still be
+    /// mounted with `old_cipher` and is readable. After it finished calling it again does nothing.
+    ///
+    /// The encryption key stays the same, so the ciphers need to have the same key length.
+    pub async fn change_cipher(
+        data_dir: &Path,
+        password: SecretString,
+        old_cipher: Cipher,
+        new_cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(&FileStorage, data_dir, false, true)?;
+        let ciphers = match CipherTags::load(data_dir, old_cipher) {
+            // finished before
+            Err(err @ FsError::CipherMismatch { .. }) => {
+                CipherTags::load(data_dir, new_cipher).map_err(|_| err)?
+            }
+            res => res?,
+        };
+        // check the password before we start
+        read_key(data_dir, &password, &ciphers)?;
+        let cipher = ciphers.cipher();
+        if cipher == new_cipher && ciphers.migrating_to().is_none() {
+            return Ok(());
         }
-        // add to new parent contents
-        self.insert_directory_entry(
-            new_parent,
-            &DirectoryEntry {
-                ino: attr.ino,
-                name: new_name.clone(),
-                kind: attr.kind,
-            },
+        drop(ciphers);
+
+        let fs = Self::new(
+            data_dir.to_path_buf(),
+            Box::new(FixedPasswordProvider(password)),
+            cipher,
+            false,
         )
         .await?;
+        fs.migrate_cipher(new_cipher).await
+    }
 
-        if attr.kind == FileType::Directory {
-            // add the parent link to the new directory
-            self.insert_directory_entry(
-                attr.ino,
-                &DirectoryEntry {
-                    ino: new_parent,
-                    name: SecretBox::new(Box::new("$..".to_owned())),
-                    kind: FileType::Directory,
-                },
-            )
-            .await?;
+    /// Replace the random key the data is encrypted with by a new one, like security policies ask periodically.
+    ///
+    /// Unlike [`EncryptedFs::passwd`], which only re-encrypts the key, the metadata is re-encrypted with the new key.
+    /// The contents of files are encrypted with their own [`FileKey`], kept in the inode, so they stay as they are,
+    /// only the ones created before files had their own key are re-encrypted. The new key is encrypted with the password we already have, it's not
+    /// asked again. Each file is re-encrypted into a temp file which atomically replaces it, if it's interrupted, like
+    /// on a crash, it's finished when the volume is opened read-write again, until then it can't be opened read-only.
+    ///
+    /// Meanwhile the other operations fail with [`FsError::KeyRotationInProgress`], also after it failed, until it's
+    /// called again. Opened files would keep using the old key, so they need to be closed before, else it fails with
+    /// [`FsError::AlreadyOpenForWrite`].
+    /// A recovery phrase exported before doesn't work after, export a new one with
+    /// [`EncryptedFs::export_recovery_phrase`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn rotate_data_key(&self) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
         }
+        let Some(key_file) = &self.key_file else {
+            return Err(FsError::NotSupported(
+                "key rotation with an external key provider",
+            ));
+        };
+        if self.inodes.backend() == InodeBackend::Db {
+            return Err(FsError::NotSupported(
+                "key rotation with the inode db backend",
+            ));
+        }
+        let _migration_guard = self.cipher_migration_lock.write().await;
+        if self.ciphers.migrating_to().is_some() {
+            return Err(FsError::Other("cipher migration in progress"));
+        }
+        // waits for the ops which are getting the key, the next ones fail
+        *self.key_rotation.write().await = true;
+        let res = self.rotate_data_key_blocked(key_file).await;
+        // if it failed after it started some files use the new key, keep failing until it's called again
+        *self.key_rotation.write().await = self.storage.exists(&key_file.rotation_path());
+        res
+    }
 
-        let now = SystemTime::now();
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(parent, set_attr).await?;
-
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(new_parent, set_attr).await?;
-
-        let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
-        self.set_attr(attr.ino, set_attr).await?;
+    /// The part of [`EncryptedFs::rotate_data_key`] which runs while getting the key fails.
+    async fn rotate_data_key_blocked(&self, key_file: &KeyProvider) -> FsResult<()> {
+        if !self.opened_files_for_read.read().await.is_empty()
+            || !self.opened_files_for_write.read().await.is_empty()
+        {
+            return Err(FsError::AlreadyOpenForWrite);
+        }
+        // wait for the ops which got the key before to finish, the key is kept only while it's used
+        let old_key = loop {
+            let key = self.key.get().await?;
+            self.key.clear().await;
+            if Arc::strong_count(&key) == 1 {
+                break key;
+            }
+            drop(key);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
 
+        let rotation_path = key_file.rotation_path();
+        let new_key = if self.storage.exists(&rotation_path) {
+            key_file.unwrap(&rotation_path)?
+        } else {
+            let mut key = vec![0; self.ciphers.cipher().key_len()];
+            crypto::create_rng().fill_bytes(&mut key);
+            let key = SecretBox::new(Box::new(key));
+            key_file.wrap(&rotation_path, &key)?;
+            key
+        };
+        // same key if we crashed after `key.enc` was replaced
+        if *old_key.expose_secret() != *new_key.expose_secret() {
+            info!("rotating the data key");
+            self.rekey_data_dir(&self.data_dir, &old_key, &new_key)?;
+            let snapshots_dir = self.data_dir.join(SNAPSHOTS_DIR);
+            if self.storage.is_dir(&snapshots_dir) {
+                for name in self.storage.read_dir(&snapshots_dir)? {
+                    let path = snapshots_dir.join(&name);
+                    // skip partial snapshots, they are removed on the next snapshot
+                    if self.storage.is_dir(&path) && !name.to_string_lossy().starts_with('.') {
+                        self.rekey_data_dir(&path, &old_key, &new_key)?;
+                    }
+                }
+            }
+            key_file.wrap(&key_file.key_path, &new_key)?;
+        }
+        self.storage.remove_file(&rotation_path)?;
+        self.storage.sync_dir(rotation_path.parent().unwrap())?;
+        drop(old_key);
+        // next time it's read from `key.enc`
+        self.key.clear().await;
+        // the MACs are in the cached inodes
+        self.clear_caches().await?;
+        // the names of the shared contents and their keys depend on the old key, the files keep sharing them
+        self.forget_content_refs().await?;
+        info!("data key rotated");
         Ok(())
     }
 
-    /// Create a crypto writer using internal encryption info.
-    pub async fn create_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
-        &self,
-        file: W,
-    ) -> FsResult<impl CryptoWrite<W>> {
-        Ok(crypto::create_write(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
-    }
-
-    /// Create a crypto writer with seek using internal encryption info.
-    pub async fn create_write_seek<W: Write + Seek + Read + Send + Sync + 'static>(
+    /// Re-encrypt the `inodes`, `xattr` and `contents` in `root`, which is the data dir or a snapshot, with
+    /// `new_key`. Files already re-encrypted before an interruption are skipped.
+    fn rekey_data_dir(
         &self,
-        file: W,
-    ) -> FsResult<impl CryptoWriteSeek<W>> {
-        Ok(crypto::create_write_seek(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        root: &Path,
+        old_key: &SecretVec<u8>,
+        new_key: &SecretVec<u8>,
+    ) -> FsResult<()> {
+        for dir in [INODES_DIR, XATTR_DIR] {
+            let dir = root.join(dir);
+            if !self.storage.is_dir(&dir) {
+                continue;
+            }
+            for name in self.storage.read_dir(&dir)? {
+                let path = dir.join(name);
+                if path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .and_then(|name| name.parse::<u64>().ok())
+                    .is_some()
+                {
+                    self.rekey_file(&path, old_key, new_key, false)?;
+                }
+            }
+        }
+
+        let contents_dir = root.join(CONTENTS_DIR);
+        for name in self.storage.read_dir(&contents_dir)? {
+            let path = contents_dir.join(name);
+            if self.storage.is_dir(&path) {
+                self.rekey_dir_entries(&path, old_key, new_key)?;
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let side_file = name
+                .strip_suffix(CONTENT_TRANSFORM_SUFFIX)
+                .or_else(|| name.strip_suffix(CONTENT_HOLES_SUFFIX));
+            if let Some(ino) = side_file {
+                if ino.parse::<u64>().is_ok() {
+                    self.rekey_file(&path, old_key, new_key, false)?;
+                }
+                continue;
+            }
+            let Ok(ino) = name.parse::<u64>() else {
+                continue;
+            };
+            // the inode with its key was re-encrypted above, the content doesn't use the volume key
+            if self.stored_file_key(root, ino, new_key)?.is_some() {
+                continue;
+            }
+            let framed = self
+                .storage
+                .exists(&contents_dir.join(format!("{ino}{CONTENT_TRANSFORM_SUFFIX}")));
+            self.rekey_file(&path, old_key, new_key, framed)?;
+            // the MAC is keyed, the inode was re-encrypted above
+            self.update_snapshot_content_mac(root, ino, new_key)?;
+        }
+        Ok(())
     }
 
-    /// Create a crypto reader using internal encryption info.
-    pub async fn create_read<R: Read + Send + Sync>(
+    /// Re-encrypt the `ls` and `hash` entries of a directory with `new_key`.
+    ///
+    /// The names of `ls` entries are encrypted, so like in [`EncryptedFs::migrate_dir_entries`] the entries are
+    /// moved to the name encrypted with the new key and the `hash` entry pointing to it is updated first.
+    fn rekey_dir_entries(
         &self,
-        reader: R,
-    ) -> FsResult<impl CryptoRead<R>> {
-        Ok(crypto::create_read(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        dir: &Path,
+        old_key: &SecretVec<u8>,
+        new_key: &SecretVec<u8>,
+    ) -> FsResult<()> {
+        for name in self.storage.read_dir(&dir.join(LS_DIR))? {
+            let ls_path = dir.join(LS_DIR).join(&name);
+            let name = name.to_string_lossy().to_string();
+            if name == "$." || name == "$.." {
+                self.rekey_file(&ls_path, old_key, new_key, false)?;
+                continue;
+            }
+            let cipher = self.ciphers.cipher_for(&ls_path);
+            let Ok(plain_name) = crypto::decrypt_file_name(&name, cipher, old_key) else {
+                if crypto::decrypt_file_name(&name, cipher, new_key).is_err() {
+                    warn!(path = %ls_path.display(), "cannot decrypt directory entry name, skipping");
+                }
+                continue;
+            };
+            let hash_path = dir.join(HASH_DIR).join(self.hash_file_name(&plain_name));
+            let (file, cipher) = self.ciphers.open(&ls_path)?;
+            let (ino, kind): (u64, FileType) = bincode_util::deserialize_from(
+                crypto::create_read(file, cipher, old_key),
+                bincode_util::METADATA_LIMIT,
+            )?;
+            let mut new_name = None;
+            if self.storage.exists(&hash_path) {
+                // interrupted after the hash entry was updated
+                let (file, cipher) = self.ciphers.open(&hash_path)?;
+                let hash: bincode::Result<(u64, FileType, String)> = bincode_util::deserialize_from(
+                    crypto::create_read(file, cipher, new_key),
+                    bincode_util::METADATA_LIMIT,
+                );
+                if let Ok((_, _, hash_ls_name)) = hash {
+                    new_name = Some(hash_ls_name);
+                }
+            }
+            let new_name = match new_name {
+                Some(new_name) => new_name,
+                None => {
+                    crypto::encrypt_file_name(&plain_name, cipher, new_key, self.name_padding())?
+                }
+            };
+            // update hash first, so we know the new name if we're interrupted
+            atomic_serialize_encrypt_into(
+                &*self.storage,
+                &hash_path,
+                &(ino, kind, new_name.clone()),
+                cipher,
+                new_key,
+            )?;
+            atomic_serialize_encrypt_into(
+                &*self.storage,
+                &dir.join(LS_DIR).join(new_name),
+                &(ino, kind),
+                cipher,
+                new_key,
+            )?;
+            self.storage.remove_file(&ls_path)?;
+        }
+        // the rest, like `$.` and `$..`, the ones updated above are skipped
+        for name in self.storage.read_dir(&dir.join(HASH_DIR))? {
+            self.rekey_file(&dir.join(HASH_DIR).join(name), old_key, new_key, false)?;
+        }
+        Ok(())
     }
 
-    /// Create a crypto reader with seek using internal encryption info.
-    pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
+    /// Re-encrypt a file with `new_key`, if it's not already.
+    fn rekey_file(
         &self,
-        reader: R,
-    ) -> FsResult<impl CryptoReadSeek<R>> {
-        Ok(crypto::create_read_seek(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        path: &Path,
+        old_key: &SecretVec<u8>,
+        new_key: &SecretVec<u8>,
+        framed: bool,
+    ) -> FsResult<()> {
+        let (mut file, cipher) = self.ciphers.open(path)?;
+        let mut tmp = self.storage.create_atomic(path)?;
+        match crypto::rekey(&mut file, &mut tmp, cipher, old_key, new_key, framed) {
+            Ok(()) => {
+                tmp.commit()?;
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                // re-encrypted before an interruption
+                let (mut file, cipher) = self.ciphers.open(path)?;
+                crypto::rekey(&mut file, &mut io::sink(), cipher, new_key, new_key, framed)
+                    .map_err(|_| FsError::from(err))
+            }
+            Err(err) => Err(err.into()),
+        }
     }
 
     /// Change the password of the filesystem used to access the encryption key.
+    ///
+    /// The data is encrypted with a random key, kept in `security/key.enc` encrypted with a key derived from
+    /// the password. So only that file is rewritten, atomically, the data stays as it is.
+    ///
+    /// The new password needs at least [`DEFAULT_MIN_PASSWORD_LEN`] characters.
     pub async fn passwd(
         data_dir: &Path,
         old_password: SecretString,
         new_password: SecretString,
         cipher: Cipher,
     ) -> FsResult<()> {
-        check_structure(data_dir, false).await?;
-        // decrypt key
-        let salt: Vec<u8> = bincode::deserialize_from(File::open(
-            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-        )?)?;
-        let initial_key = crypto::derive_key(&old_password, cipher, &salt)?;
-        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
-        let reader = crypto::create_read(File::open(enc_file)?, cipher, &initial_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        let key = SecretBox::new(Box::new(key));
+        check_structure(&FileStorage, data_dir, false, true)?;
+        check_password_strength(&new_password, DEFAULT_MIN_PASSWORD_LEN)?;
+        let ciphers = CipherTags::load(data_dir, cipher)?;
+        let (key, cipher) = read_key(data_dir, &old_password, &ciphers)?;
         // encrypt it with a new key derived from new password
-        let new_key = crypto::derive_key(&new_password, cipher, &salt)?;
-        crypto::atomic_serialize_encrypt_into(
-            &data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            &*key.expose_secret(),
+        let salt = read_salt(
+            &FileStorage,
+            &data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+        )?;
+        let kdf_params = read_kdf_params(
+            &FileStorage,
+            &data_dir.join(SECURITY_DIR).join(KDF_PARAMS_FILENAME),
+        )?;
+        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+        wrap_key(
+            &FileStorage,
+            &enc_file,
+            &key,
+            &new_password,
+            &salt,
+            &kdf_params,
+            cipher,
+        )
+    }
+
+    /// Export the encryption key of the filesystem, wrapped with the public key of an escrow service,
+    /// see [`crypto::escrow`].
+    ///
+    /// Whoever has the escrow secret key can then restore access to the data with [`EncryptedFs::import_escrow`],
+    /// the key is only in plaintext in memory.
+    pub async fn export_escrow(
+        data_dir: &Path,
+        password: SecretString,
+        escrow_public_key: &[u8],
+        cipher: Cipher,
+    ) -> FsResult<Vec<u8>> {
+        check_structure(&FileStorage, data_dir, false, true)?;
+        let ciphers = CipherTags::load(data_dir, cipher)?;
+        let (key, _) = read_key(data_dir, &password, &ciphers)?;
+        Ok(escrow::wrap(&key, escrow_public_key)?)
+    }
+
+    /// Restore access to the filesystem from the key exported with [`EncryptedFs::export_escrow`],
+    /// it will be encrypted with `new_password` which replaces the current one.
+    pub async fn import_escrow(
+        data_dir: &Path,
+        escrow: &[u8],
+        escrow_secret_key: &SecretVec<u8>,
+        new_password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(&FileStorage, data_dir, false, true)?;
+        check_password_strength(&new_password, DEFAULT_MIN_PASSWORD_LEN)?;
+        let key = escrow::unwrap(escrow, escrow_secret_key)?;
+        let ciphers = CipherTags::load(data_dir, cipher)?;
+        if key.expose_secret().len() != ciphers.cipher().key_len() {
+            return Err(FsError::InvalidInput("escrow is not for this filesystem"));
+        }
+        let salt = read_salt(
+            &FileStorage,
+            &data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+        )?;
+        let kdf_params = read_kdf_params(
+            &FileStorage,
+            &data_dir.join(SECURITY_DIR).join(KDF_PARAMS_FILENAME),
+        )?;
+        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+        let cipher = ciphers.for_write(&enc_file)?;
+        wrap_key(
+            &FileStorage,
+            &enc_file,
+            &key,
+            &new_password,
+            &salt,
+            &kdf_params,
             cipher,
-            &new_key,
+        )
+    }
+
+    /// Export the encryption key of the filesystem as a recovery phrase of 24 words, see [`crypto::recovery`].
+    ///
+    /// If the password is lost, the phrase gives access to the data again with [`EncryptedFs::recover_with_phrase`].
+    /// Anyone who has it can read the data, so keep it somewhere safe. It doesn't change when the password changes.
+    pub async fn export_recovery_phrase(&self) -> FsResult<SecretString> {
+        let key = self.key().await?;
+        Ok(crypto::recovery::to_phrase(&key)?)
+    }
+
+    /// Restore access to the filesystem from the phrase exported with [`EncryptedFs::export_recovery_phrase`],
+    /// the key will be encrypted with `new_password` which replaces the current one.
+    ///
+    /// Returns [`FsError::InvalidRecoveryPhrase`] if the phrase has a typo or is not for this filesystem.
+    pub async fn recover_with_phrase(
+        data_dir: &Path,
+        phrase: &SecretString,
+        new_password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(&FileStorage, data_dir, false, true)?;
+        check_password_strength(&new_password, DEFAULT_MIN_PASSWORD_LEN)?;
+        let key = crypto::recovery::from_phrase(phrase).map_err(|err| {
+            warn!(err = %err);
+            FsError::InvalidRecoveryPhrase
+        })?;
+        let ciphers = Arc::new(CipherTags::load(data_dir, cipher)?);
+        if key.expose_secret().len() != ciphers.cipher().key_len() {
+            return Err(FsError::InvalidRecoveryPhrase);
+        }
+        // the checksum doesn't catch all typos, make sure it's the key before we replace it
+        let root = InodeStore::read_from(data_dir, ROOT_INODE, &ciphers, &key);
+        if root.is_err() {
+            return Err(FsError::InvalidRecoveryPhrase);
+        }
+        let salt = read_salt(
+            &FileStorage,
+            &data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
         )?;
-        Ok(())
+        let kdf_params = read_kdf_params(
+            &FileStorage,
+            &data_dir.join(SECURITY_DIR).join(KDF_PARAMS_FILENAME),
+        )?;
+        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+        let cipher = ciphers.for_write(&enc_file)?;
+        wrap_key(
+            &FileStorage,
+            &enc_file,
+            &key,
+            &new_password,
+            &salt,
+            &kdf_params,
+            cipher,
+        )
+    }
+
+    async fn read_handle(&self, handle: u64) -> Option<Arc<Mutex<ReadHandleContext>>> {
+        self.read_handles.read().await.get(&handle).cloned()
+    }
+
+    async fn write_handle(&self, handle: u64) -> Option<Arc<Mutex<WriteHandleContext>>> {
+        self.write_handles.read().await.get(&handle).cloned()
+    }
+
+    /// The key the data is encrypted with, while [`EncryptedFs::rotate_data_key`] runs it fails with
+    /// [`FsError::KeyRotationInProgress`].
+    async fn key(&self) -> FsResult<Arc<SecretVec<u8>>> {
+        let rotating = self.key_rotation.read().await;
+        if *rotating {
+            return Err(FsError::KeyRotationInProgress);
+        }
+        self.key.get().await
+    }
+
+    fn self_arc(&self) -> Arc<Self> {
+        self.self_weak
+            .get()
+            .and_then(Weak::upgrade)
+            .expect("filesystem is dropped")
     }
 
     fn next_handle(&self) -> u64 {
@@ -2177,40 +7401,49 @@ impl EncryptedFs {
         skip_write_fh: Option<u64>,
         save_attr: bool,
     ) -> FsResult<()> {
-        let path = self.contents_path(ino);
+        if let Some(cache) = self.block_cache() {
+            cache.invalidate(ino);
+        }
 
         // read
-        let lock = self.opened_files_for_read.read().await;
-        if let Some(set) = lock.get(&ino) {
-            for handle in set.iter().filter(|h| skip_write_fh != Some(**h)) {
-                let guard = self.read_handles.read().await;
-                let ctx = guard.get(handle).unwrap().lock().await;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
+        // the reader of a read-write handle is recreated too, else it keeps seeing the content
+        // from before the writes made through the same handle
+        let set = self.opened_files_for_read.read().await.get(&ino).cloned();
+        if let Some(set) = set {
+            for handle in &set {
+                let Some(lock) = self.read_handle(*handle).await else {
+                    // released meanwhile
+                    continue;
+                };
+                let ctx = lock.lock().await;
+                // readers only change atime, their size could be stale and would truncate the
+                // data just written
+                let atime = ctx.attr.atime;
                 drop(ctx);
-                self.set_attr(ino, set_attr).await?;
+                self.save_handle_atime(ino, atime).await?;
                 let attr = self.get_inode_from_storage(ino).await?;
-                let mut ctx = guard.get(handle).unwrap().lock().await;
-                let reader = self.create_read_seek(File::open(&path)?).await?;
-                ctx.reader = Some(Box::new(reader));
+                let mut ctx = lock.lock().await;
+                let reader = self.create_content_read(ino).await?;
+                ctx.set_reader(Box::new(reader));
                 ctx.attr = attr.into();
             }
         }
 
         // write
-        let lock = self.opened_files_for_write.read().await;
-        if let Some(fh) = lock.get(&ino) {
+        let fh = self.opened_files_for_write.read().await.get(&ino).copied();
+        if let Some(fh) = fh {
             if let Some(handle) = skip_write_fh {
-                if *fh == handle {
+                if fh == handle {
                     return Ok(());
                 }
             }
-            let lock = self.write_handles.read().await;
-            if let Some(lock) = lock.get(fh) {
+            if let Some(lock) = self.write_handle(fh).await {
                 let mut ctx = lock.lock().await;
-                let writer = ctx.writer.as_mut().unwrap();
+                let mut writer = self.take_writer(&mut ctx).await?;
                 let file = writer.finish()?;
                 file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                self.storage
+                    .sync_dir(self.contents_path(ctx.ino).parent().unwrap())?;
                 let set_attr: Option<SetFileAttr> = if save_attr {
                     Some(ctx.attr.clone().into())
                 } else {
@@ -2220,11 +7453,13 @@ impl EncryptedFs {
                 if let Some(set_attr) = set_attr {
                     self.set_attr(ino, set_attr).await?;
                 }
+                self.commit_journal(ino).await?;
                 let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
+                    .create_content_write_seek(ino, self.open_content_journaled(ino).await?)
                     .await?;
                 let mut ctx = lock.lock().await;
                 ctx.writer = Some(Box::new(writer));
+                ctx.dirty = false;
                 let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
             }
@@ -2239,21 +7474,21 @@ impl EncryptedFs {
         op: ReadHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
         let attr = self.get_inode_from_storage(ino).await?;
         match op {
             ReadHandleContextOperation::Create { ino } => {
                 let attr: TimesFileAttr = attr.into();
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let reader = self.create_content_read(ino).await?;
                 let ctx = ReadHandleContext {
                     ino,
                     attr,
                     reader: Some(Box::new(reader)),
+                    readahead: Readahead::default(),
                 };
                 self.read_handles
                     .write()
                     .await
-                    .insert(handle, Mutex::new(ctx));
+                    .insert(handle, Arc::new(Mutex::new(ctx)));
                 self.opened_files_for_read
                     .write()
                     .await
@@ -2271,22 +7506,27 @@ impl EncryptedFs {
         op: WriteHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
         match op {
-            WriteHandleContextOperation::Create { ino } => {
+            WriteHandleContextOperation::Create { .. } => {
+                // so a snapshot doesn't link the content after we unshared it
+                let lock = self
+                    .read_write_locks
+                    .get_or_insert_with(ino, || RwLock::new(false));
+                let _guard = lock.write().await;
                 let attr = self.get_attr(ino).await?.into();
                 let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
+                    .create_content_write_seek(ino, self.open_content_journaled(ino).await?)
                     .await?;
                 let ctx = WriteHandleContext {
                     ino,
                     attr,
                     writer: Some(Box::new(writer)),
+                    dirty: false,
                 };
                 self.write_handles
                     .write()
                     .await
-                    .insert(handle, Mutex::new(ctx));
+                    .insert(handle, Arc::new(Mutex::new(ctx)));
                 self.opened_files_for_write
                     .write()
                     .await
@@ -2318,9 +7558,11 @@ impl EncryptedFs {
             self.write_inode_to_storage(&attr).await?;
 
             // create in contents directory
-            fs::create_dir(self.contents_path(attr.ino))?;
-            fs::create_dir(self.contents_path(attr.ino).join(LS_DIR))?;
-            fs::create_dir(self.contents_path(attr.ino).join(HASH_DIR))?;
+            self.storage.create_dir(&self.contents_path(attr.ino))?;
+            self.storage
+                .create_dir(&self.contents_path(attr.ino).join(LS_DIR))?;
+            self.storage
+                .create_dir(&self.contents_path(attr.ino).join(HASH_DIR))?;
 
             // add "." entry
             self.insert_directory_entry(
@@ -2343,25 +7585,24 @@ impl EncryptedFs {
         entry: &DirectoryEntry,
     ) -> FsResult<()> {
         let parent_path = self.contents_path(ino_contents_dir);
-        let encrypted_name =
-            crypto::encrypt_file_name(&entry.name, self.cipher, &*self.key.get().await?)?;
+        let key = self.key().await?;
+        // the name is used only here, the rest needs the encrypted name and the hash, so we don't clone it
+        // while migrating the cipher new entries are created with the new one
+        let (ls_path, ls_cipher) = self.ciphers.for_new_path(|cipher| {
+            let name = crypto::encrypt_file_name(&entry.name, cipher, &key, self.name_padding())?;
+            Ok(parent_path.join(LS_DIR).join(name))
+        })?;
+        let encrypted_name = ls_path.file_name().unwrap().to_str().unwrap().to_owned();
+        let hash_path = parent_path
+            .join(HASH_DIR)
+            .join(self.hash_file_name(&entry.name));
+        let (ino, kind) = (entry.ino, entry.kind);
         // add to LS directory
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
-        let parent_path_clone = parent_path.clone();
-        let encrypted_name_clone = encrypted_name.clone();
-        let entry_clone = entry.clone();
+        let self_clone = self.self_arc();
+        let ls_key = key.clone();
         // spawn a task to do concurrently with adding to HASH directory
         let h = tokio::spawn(async move {
-            let file_path = parent_path_clone
-                .join(LS_DIR)
-                .join(encrypted_name_clone.clone());
+            let file_path = ls_path;
             let lock = self_clone
                 .serialize_dir_entries_ls_locks
                 .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
@@ -2369,100 +7610,174 @@ impl EncryptedFs {
                 });
             let _guard = lock.write().await;
             // write inode and file type
-            let entry = (entry_clone.ino, entry_clone.kind);
-            crypto::atomic_serialize_encrypt_into(
+            let entry = (ino, kind);
+            atomic_serialize_encrypt_into(
+                &*self_clone.storage,
                 &file_path,
                 &entry,
-                self_clone.cipher,
-                &*self_clone.key.get().await?,
+                ls_cipher,
+                &ls_key,
             )?;
+            // entry might be overwritten, like `$..` on rename, keep the cache in sync
+            if let Some(cache) = self_clone.dir_entries_meta_cache().await? {
+                cache
+                    .lock()
+                    .await
+                    .put(file_path.to_str().unwrap().to_owned(), entry);
+            }
             Ok::<(), FsError>(())
         });
         // add to HASH directory
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
-        let entry_hash = entry.clone();
-        tokio::spawn(async move {
-            let name = crypto::hash_file_name(&entry_hash.name);
-            let file_path = parent_path.join(HASH_DIR).join(name);
-            let lock = self_clone
+        {
+            let lock = self
                 .serialize_dir_entries_hash_locks
-                .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
+                .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
                     RwLock::new(false)
                 });
             let _guard = lock.write().await;
             // write inode and file type
             // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
-            let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
-            crypto::atomic_serialize_encrypt_into(
-                &file_path,
+            let entry = (ino, kind, encrypted_name);
+            atomic_serialize_encrypt_into(
+                &*self.storage,
+                &hash_path,
                 &entry,
-                self_clone.cipher,
-                &*self_clone.key.get().await?,
+                self.ciphers.for_write(&hash_path)?,
+                &key,
             )?;
-            Ok::<(), FsError>(())
-        })
-        .await??;
+        }
         h.await??;
         Ok(())
     }
 
     fn ino_file(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(INODES_DIR).join(ino.to_string())
+        self.inodes.path(ino)
+    }
+
+    fn xattr_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(XATTR_DIR).join(ino.to_string())
     }
 
     fn contents_path(&self, ino: u64) -> PathBuf {
         self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
     }
 
+    fn content_transform_path(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(CONTENTS_DIR)
+            .join(format!("{ino}{CONTENT_TRANSFORM_SUFFIX}"))
+    }
+
+    fn content_holes_path(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(CONTENTS_DIR)
+            .join(format!("{ino}{CONTENT_HOLES_SUFFIX}"))
+    }
+
     async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         let parent_path = self.contents_path(parent);
         // remove from HASH
-        let name = crypto::hash_file_name(name);
+        let name = self.hash_file_name(name);
         let path = parent_path.join(HASH_DIR).join(name);
         let lock = self
             .serialize_dir_entries_hash_locks
             .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
-        let guard = lock.write().await;
-        let (_, _, name): (u64, FileType, String) =
-            bincode::deserialize_from(crypto::create_read(
-                File::open(path.clone())?,
-                self.cipher,
-                &*self.key.get().await?,
-            ))?;
-        fs::remove_file(path)?;
-        drop(guard);
-        // remove from LS
+        let _guard = lock.write().await;
+        let (file, cipher) = self.ciphers.open(&path)?;
+        let (_, _, name): (u64, FileType, String) = bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, &*self.key().await?),
+            bincode_util::METADATA_LIMIT,
+        )?;
+        self.storage.remove_file(&path)?;
+        // remove from LS, keep holding the HASH lock so a cipher migration doesn't rename the entry meanwhile
         let path = parent_path.join(LS_DIR).join(name);
         let lock = self
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
         let _guard = lock.write().await;
-        fs::remove_file(path)?;
+        self.storage.remove_file(&path)?;
         Ok(())
     }
 
-    fn generate_next_inode(&self) -> u64 {
-        loop {
-            let ino = crypto::create_rng().next_u64();
+    /// Unique also between concurrent calls, without looking at which inodes exist, see [`InoCounter`].
+    fn generate_next_inode(&self) -> FsResult<u64> {
+        Ok(self.ino_counter.next()?)
+    }
+}
 
-            if ino <= ROOT_INODE {
-                continue;
-            }
-            if self.exists(ino) {
-                continue;
-            }
+/// Result of [`EncryptedFs::check`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Directories with a missing or wrong `$.` entry.
+    pub wrong_self_entries: Vec<u64>,
+    /// Directories with a missing or wrong `$..` entry.
+    pub wrong_parent_entries: Vec<u64>,
+    /// If the problems were repaired, this doesn't happen in read-only mode.
+    pub repaired: bool,
+}
 
-            return ino;
-        }
+impl CheckReport {
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.wrong_self_entries.is_empty() && self.wrong_parent_entries.is_empty()
+    }
+}
+
+/// Result of [`EncryptedFs::verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Inodes not listed in any directory reachable from root.
+    pub orphaned_inodes: Vec<u64>,
+    /// Inodes which have `contents` but no inode file.
+    pub orphaned_contents: Vec<u64>,
+    /// Inodes whose `contents` is missing or doesn't match their kind.
+    pub invalid_contents: Vec<u64>,
+    /// Directory entries which point to a missing inode or miss their `ls` or `hash` counterpart.
+    pub dangling_entries: Vec<DanglingEntry>,
+    /// Inode and directory entry files which couldn't be decrypted.
+    pub undecryptable: Vec<PathBuf>,
+    /// Directories with a missing or wrong `$.` entry.
+    pub wrong_self_entries: Vec<u64>,
+    /// Directories with a missing or wrong `$..` entry, with the parent it should point to.
+    pub wrong_parent_entries: Vec<(u64, u64)>,
+    /// Directories whose `nlink` doesn't match their subdirectories, with the right one.
+    pub wrong_nlinks: Vec<(u64, u32)>,
+}
+
+impl VerifyReport {
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_inodes.is_empty()
+            && self.orphaned_contents.is_empty()
+            && self.invalid_contents.is_empty()
+            && self.dangling_entries.is_empty()
+            && self.undecryptable.is_empty()
+            && self.wrong_self_entries.is_empty()
+            && self.wrong_parent_entries.is_empty()
+            && self.wrong_nlinks.is_empty()
     }
 }
+
+/// A directory entry reported by [`EncryptedFs::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DanglingEntry {
+    /// The directory which has the entry.
+    pub dir: u64,
+    /// Path of the `ls` or `hash` file of the entry.
+    pub path: PathBuf,
+    pub reason: DanglingReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DanglingReason {
+    /// The `ls` entry points to an inode which doesn't exist.
+    MissingInode,
+    /// The `ls` entry has no `hash` counterpart, so it can't be found by name.
+    MissingHash,
+    /// The `hash` entry has no `ls` counterpart, so it's not listed.
+    MissingLs,
+}
+
 pub struct CopyFileRangeReq {
     src_ino: u64,
     src_offset: u64,
@@ -2494,93 +7809,285 @@ impl CopyFileRangeReq {
     }
 }
 
+/// Decrypt the key of an existing filesystem, returns it with the cipher it's encrypted with.
+fn read_key(
+    data_dir: &Path,
+    password: &SecretString,
+    ciphers: &CipherTags,
+) -> FsResult<(SecretVec<u8>, Cipher)> {
+    let storage = ciphers.storage();
+    let salt = read_salt(
+        &**storage,
+        &data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+    )?;
+    let kdf_params = read_kdf_params(
+        &**storage,
+        &data_dir.join(SECURITY_DIR).join(KDF_PARAMS_FILENAME),
+    )?;
+    unwrap_key(
+        &data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
+        password,
+        &salt,
+        &kdf_params,
+        ciphers,
+    )
+}
+
+/// Salt of the key, it's read before we can check the password.
+fn read_salt(storage: &dyn Storage, path: &Path) -> FsResult<Vec<u8>> {
+    Ok(bincode_util::deserialize_from(
+        storage.open(path)?,
+        bincode_util::SALT_LIMIT,
+    )?)
+}
+
+/// Rejects empty passwords and the ones shorter than `min_len` characters.
+fn check_password_strength(password: &SecretString, min_len: usize) -> FsResult<()> {
+    let len = password.expose_secret().chars().count();
+    if len == 0 || len < min_len {
+        return Err(FsError::WeakPassword {
+            min_len: min_len.max(1),
+        });
+    }
+    Ok(())
+}
+
+/// The [`VolumeFormat`] kept in `path`, `None` if it's not saved yet.
+fn read_format(storage: &dyn Storage, path: &Path) -> FsResult<Option<VolumeFormat>> {
+    if !storage.exists(path) {
+        return Ok(None);
+    }
+    Ok(Some(bincode_util::deserialize_from(
+        storage.open(path)?,
+        bincode_util::SMALL_LIMIT,
+    )?))
+}
+
+fn save_format(storage: &dyn Storage, path: &Path, format: VolumeFormat) -> FsResult<()> {
+    storage.write_atomic(path, &bincode::serialize(&format)?)?;
+    Ok(())
+}
+
+/// The [`KdfParams`] saved for the volume, [`KdfParams::LEGACY`] if it was created before we saved them.
+fn read_kdf_params(storage: &dyn Storage, path: &Path) -> FsResult<KdfParams> {
+    if storage.exists(path) {
+        let params: KdfParams =
+            bincode_util::deserialize_from(storage.open(path)?, bincode_util::SMALL_LIMIT)?;
+        // it's read before we can check the password, don't trust it
+        params.validate()?;
+        Ok(params)
+    } else {
+        Ok(KdfParams::LEGACY)
+    }
+}
+
+/// Returns the key the data is encrypted with.
+///
+/// It's a random key, created with the volume and kept in `key_path` encrypted with a key derived from the password,
+/// see [`wrap_key`]. This way changing the password only re-encrypts that file.
+///
+/// `kdf_defaults` are used and saved only for new volumes, existing ones always use the params saved with them.
+/// The same for `min_password_len`, see [`check_password_strength`].
 fn read_or_create_key(
     key_path: &PathBuf,
     salt_path: &PathBuf,
+    kdf_params_path: &Path,
     password: &SecretString,
-    cipher: Cipher,
+    ciphers: &CipherTags,
+    kdf_defaults: KdfParams,
+    min_password_len: usize,
 ) -> FsResult<SecretVec<u8>> {
-    let salt = if salt_path.exists() {
-        bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?
+    let storage = ciphers.storage();
+    if !storage.exists(key_path) {
+        // check before we create anything
+        check_password_strength(password, min_password_len)?;
+    }
+    let kdf_params = if !storage.exists(kdf_params_path) && !storage.exists(key_path) {
+        let mut file = storage.create(kdf_params_path)?;
+        bincode::serialize_into(&mut file, &kdf_defaults)?;
+        file.flush()?;
+        file.sync_all()?;
+        storage.sync_dir(
+            kdf_params_path
+                .parent()
+                .expect("oops, we don't have a parent"),
+        )?;
+        kdf_defaults
+    } else {
+        read_kdf_params(&**storage, kdf_params_path)?
+    };
+    let salt = if storage.exists(salt_path) {
+        read_salt(&**storage, salt_path).map_err(|_| FsError::InvalidPassword)?
     } else {
         let mut salt = vec![0; 16];
         crypto::create_rng().fill_bytes(&mut salt);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(salt_path)?;
+        let mut file = storage.create(salt_path)?;
         bincode::serialize_into(&mut file, &salt)?;
         file.flush()?;
         file.sync_all()?;
-        File::open(salt_path.parent().expect("oops, we don't have a parent"))?.sync_all()?;
+        storage.sync_dir(salt_path.parent().expect("oops, we don't have a parent"))?;
         salt
     };
-    // derive key from password
-    let derived_key = crypto::derive_key(password, cipher, &salt)?;
-    if key_path.exists() {
-        // read key
-        let reader = crypto::create_read(File::open(key_path)?, cipher, &derived_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        Ok(SecretBox::new(Box::new(key)))
+    if storage.exists(key_path) {
+        let (key, _) = unwrap_key(key_path, password, &salt, &kdf_params, ciphers)?;
+        Ok(key)
     } else {
-        // first time, create a random key and encrypt it with the derived key from password
-        let mut key: Vec<u8> = vec![];
-        let key_len = cipher.key_len();
-        key.resize(key_len, 0);
+        // first time, create a random data key
+        let cipher = ciphers.for_write(key_path)?;
+        let mut key = vec![0; cipher.key_len()];
         crypto::create_rng().fill_bytes(&mut key);
-        let mut writer = crypto::create_write(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(key_path)?,
+        let key = SecretBox::new(Box::new(key));
+        wrap_key(
+            &**storage,
+            key_path,
+            &key,
+            password,
+            &salt,
+            &kdf_params,
             cipher,
-            &derived_key,
-        );
-        bincode::serialize_into(&mut writer, &key)?;
-        let file = writer.finish()?;
-        file.sync_all()?;
-        File::open(key_path.parent().unwrap())?.sync_all()?;
-        Ok(SecretBox::new(Box::new(key)))
+        )?;
+        Ok(key)
+    }
+}
+
+/// Decrypt the data key from `key_path` with the key derived from `password`, returns it with the cipher
+/// it's encrypted with.
+fn unwrap_key(
+    key_path: &Path,
+    password: &SecretString,
+    salt: &[u8],
+    kdf_params: &KdfParams,
+    ciphers: &CipherTags,
+) -> FsResult<(SecretVec<u8>, Cipher)> {
+    let (file, cipher) = ciphers.open(key_path)?;
+    let derived_key = crypto::derive_key(password, cipher, salt, kdf_params)?;
+    let reader = crypto::create_read(file, cipher, &derived_key);
+    let key: Vec<u8> =
+        bincode_util::deserialize_from(reader, bincode_util::KEY_LIMIT).map_err(|_| {
+            find_key_cipher(
+                &**ciphers.storage(),
+                key_path,
+                password,
+                salt,
+                kdf_params,
+                cipher,
+            )
+            .map_or(FsError::InvalidPassword, |volume| FsError::CipherMismatch {
+                volume,
+                requested: cipher,
+            })
+        })?;
+    Ok((SecretBox::new(Box::new(key)), cipher))
+}
+
+/// Volumes created before we saved their cipher can't tell a wrong cipher from a wrong password, when the key
+/// doesn't decrypt we try the other ciphers, so we don't report a good password as invalid.
+fn find_key_cipher(
+    storage: &dyn Storage,
+    key_path: &Path,
+    password: &SecretString,
+    salt: &[u8],
+    kdf_params: &KdfParams,
+    requested: Cipher,
+) -> Option<Cipher> {
+    if storage.exists(&key_path.with_file_name(CIPHER_FILENAME)) {
+        // already checked by `CipherTags::load`
+        return None;
     }
+    Cipher::iter()
+        .filter(|cipher| *cipher != requested)
+        .find(|cipher| {
+            let Ok(derived_key) = crypto::derive_key(password, *cipher, salt, kdf_params) else {
+                return false;
+            };
+            let Ok(file) = storage.open(key_path) else {
+                return false;
+            };
+            let reader = crypto::create_read(file, *cipher, &derived_key);
+            bincode_util::deserialize_from::<_, Vec<u8>>(reader, bincode_util::KEY_LIMIT).is_ok()
+        })
+}
+
+/// Encrypt the data key with the key derived from `password` and save it in `key_path`.
+///
+/// The file is replaced atomically, so if we crash while writing it the previous password still works.
+fn wrap_key(
+    storage: &dyn Storage,
+    key_path: &Path,
+    key: &SecretVec<u8>,
+    password: &SecretString,
+    salt: &[u8],
+    kdf_params: &KdfParams,
+    cipher: Cipher,
+) -> FsResult<()> {
+    let derived_key = crypto::derive_key(password, cipher, salt, kdf_params)?;
+    atomic_serialize_encrypt_into(
+        storage,
+        key_path,
+        &*key.expose_secret(),
+        cipher,
+        &derived_key,
+    )
 }
 
-async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
-    if data_dir.exists() {
-        check_structure(data_dir, true).await?;
+/// Like [`crypto::atomic_serialize_encrypt_into`], for a file kept in `storage`.
+fn atomic_serialize_encrypt_into<T: Serialize + ?Sized>(
+    storage: &dyn Storage,
+    path: &Path,
+    value: &T,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    crypto::serialize_encrypt_into(storage.create_atomic(path)?, value, cipher, key)?.commit()?;
+    Ok(())
+}
+
+fn ensure_structure_created(
+    storage: &dyn Storage,
+    data_dir: &Path,
+    key_file: bool,
+) -> FsResult<()> {
+    if storage.exists(data_dir) {
+        check_structure(storage, data_dir, true, key_file)?;
     } else {
-        fs::create_dir_all(data_dir)?;
+        storage.create_dir_all(data_dir)?;
     }
 
     // create directories
-    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
+    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR, XATTR_DIR, WAL_DIR];
     for dir in dirs {
         let path = data_dir.join(dir);
-        if !path.exists() {
-            fs::create_dir_all(path)?;
+        if !storage.exists(&path) {
+            storage.create_dir_all(&path)?;
         }
     }
 
     Ok(())
 }
 
-async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
-    if !data_dir.exists() || !data_dir.is_dir() {
+/// `key_file` checks there is a key encrypted with the password, volumes with the key from
+/// [`EncryptedFs::new_with_key_provider`] don't have it.
+fn check_structure(
+    storage: &dyn Storage,
+    data_dir: &Path,
+    ignore_empty: bool,
+    key_file: bool,
+) -> FsResult<()> {
+    if !storage.is_dir(data_dir) {
         return Err(FsError::InvalidDataDirStructure);
     }
-    let mut vec = ReadDirStream::new(tokio::fs::read_dir(data_dir).await?)
-        .try_collect::<Vec<_>>()
-        .await?
+    let mut vec = storage
+        .read_dir(data_dir)?
         .iter()
-        .map(|dir| dir.file_name().to_string_lossy().to_string())
+        .map(|name| name.to_string_lossy().to_string())
         .collect::<Vec<String>>();
     if vec.is_empty() && ignore_empty {
         return Ok(());
     }
+    // optional
+    vec.retain(|name| {
+        name != SNAPSHOTS_DIR && name != XATTR_DIR && name != WAL_DIR && name != CONTENTS_REFS_DIR
+    });
     if vec.len() != 3 {
         return Err(FsError::InvalidDataDirStructure);
     }
@@ -2589,11 +8096,9 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     let mut vec2 = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
     vec2.sort_unstable();
     if vec != vec2
-        || !data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file()
-        || !data_dir
-            .join(SECURITY_DIR)
-            .join(KEY_SALT_FILENAME)
-            .is_file()
+        || key_file
+            && (!storage.is_file(&data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME))
+                || !storage.is_file(&data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME)))
     {
         return Err(FsError::InvalidDataDirStructure);
     }
@@ -2601,7 +8106,85 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     Ok(())
 }
 
-fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
+/// Fail with [`FsError::UnsupportedDataDirFs`] if the filesystem of the data dir doesn't allow the characters of
+/// encrypted names, base64 with `|` instead of `/`, see [`crypto::encrypt_file_name`].
+///
+/// Case-insensitive filesystems, like the default on macOS, are fine. The hashes of names are lowercase hex, and the
+/// chance of two encrypted names in a directory differing only in case is negligible, as they look random.
+fn check_names_supported(storage: &dyn Storage, data_dir: &Path) -> FsResult<()> {
+    let name = format!("name-probe-{:016x}|+Aa", rand::random::<u64>());
+    if !storage.can_create(&data_dir.join(CONTENTS_DIR), &name)? {
+        return Err(FsError::UnsupportedDataDirFs);
+    }
+    Ok(())
+}
+
+/// Logs a warning if a time we want to set is older than the existing one, while the existing one is in the future.
+///
+/// That usually means the system clock jumped backward (NTP correction) and the new times will be ignored
+/// by [`merge_attr`], [`EncryptedFs::set_attr_overwrite`] can be used to fix them.
+fn warn_on_clock_skew(attr: &FileAttr, set_attr: &SetFileAttr, now: SystemTime) {
+    let times = [
+        ("atime", attr.atime, set_attr.atime),
+        ("mtime", attr.mtime, set_attr.mtime),
+        ("ctime", attr.ctime, set_attr.ctime),
+        ("crtime", attr.crtime, set_attr.crtime),
+    ];
+    for (name, existing, new) in times {
+        let Some(new) = new else {
+            continue;
+        };
+        if existing.duration_since(new).unwrap_or_default() > CLOCK_SKEW_THRESHOLD
+            && existing.duration_since(now).unwrap_or_default() > CLOCK_SKEW_THRESHOLD
+        {
+            warn!(
+                ino = attr.ino,
+                "possible clock skew, {name} {:?} is in the future and newer than {:?}, keeping the existing one",
+                existing,
+                new
+            );
+        }
+    }
+}
+
+/// Order of entries by name, with `.` and `..` first.
+fn cmp_entry_names(a: &SecretString, b: &SecretString) -> std::cmp::Ordering {
+    let rank = |name: &str| match name {
+        "." => 0,
+        ".." => 1,
+        _ => 2,
+    };
+    let (a, b) = (a.expose_secret(), b.expose_secret());
+    rank(&a)
+        .cmp(&rank(&b))
+        .then_with(|| a.as_str().cmp(b.as_str()))
+}
+
+/// Only `atime` is changed, like after a read.
+const fn is_atime_only(set_attr: &SetFileAttr) -> bool {
+    is_times_only(set_attr)
+        && set_attr.atime.is_some()
+        && set_attr.mtime.is_none()
+        && set_attr.ctime.is_none()
+        && set_attr.crtime.is_none()
+}
+
+/// Only times are changed, see [`FsConfig::times_write_back`].
+const fn is_times_only(set_attr: &SetFileAttr) -> bool {
+    set_attr.size.is_none()
+        && set_attr.perm.is_none()
+        && set_attr.uid.is_none()
+        && set_attr.gid.is_none()
+        && set_attr.rdev.is_none()
+        && set_attr.flags.is_none()
+}
+
+fn merge_attr(
+    attr: &mut FileAttr,
+    set_attr: &SetFileAttr,
+    overwrite_size: bool,
+    overwrite_times: bool,
+) {
     if let Some(size) = set_attr.size {
         if overwrite_size {
             attr.size = size;
@@ -2609,17 +8192,24 @@ fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool)
             attr.size = attr.size.max(size);
         }
     }
+    let merge_time = |existing: SystemTime, new: SystemTime| {
+        if overwrite_times {
+            new
+        } else {
+            existing.max(new)
+        }
+    };
     if let Some(atime) = set_attr.atime {
-        attr.atime = attr.atime.max(atime);
+        attr.atime = merge_time(attr.atime, atime);
     }
     if let Some(mtime) = set_attr.mtime {
-        attr.mtime = attr.mtime.max(mtime);
+        attr.mtime = merge_time(attr.mtime, mtime);
     }
     if let Some(ctime) = set_attr.ctime {
-        attr.ctime = attr.ctime.max(ctime);
+        attr.ctime = merge_time(attr.ctime, ctime);
     }
     if let Some(crtime) = set_attr.crtime {
-        attr.crtime = attr.crtime.max(crtime);
+        attr.crtime = merge_time(attr.crtime, crtime);
     }
     if let Some(perm) = set_attr.perm {
         attr.perm = perm;
@@ -2635,6 +8225,40 @@ fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool)
     }
 }
 
+/// Copy the contents dir `src` to `dst` for a snapshot, the contents of files are hard links on Unix.
+///
+/// They are changed in place, but they are copied first while they are shared, see `unshare_content`.
+/// Directories and the side files like the transforms are copied.
+fn link_contents(storage: &dyn Storage, src: &Path, dst: &Path) -> io::Result<()> {
+    storage.create_dir_all(dst)?;
+    for name in storage.read_dir(src)? {
+        let path = src.join(&name);
+        let dst = dst.join(&name);
+        if storage.is_dir(&path) {
+            copy_dir_content(storage, &path, &dst)?;
+        } else if cfg!(unix) && name.to_string_lossy().parse::<u64>().is_ok() {
+            storage.hard_link(&path, &dst)?;
+        } else {
+            storage.copy(&path, &dst)?;
+        }
+    }
+    Ok(())
+}
+
+/// Like [`crate::fs_util::copy_dir_content`], for a dir kept in `storage`.
+fn copy_dir_content(storage: &dyn Storage, src: &Path, dst: &Path) -> io::Result<()> {
+    storage.create_dir_all(dst)?;
+    for name in storage.read_dir(src)? {
+        let path = src.join(&name);
+        if storage.is_dir(&path) {
+            copy_dir_content(storage, &path, &dst.join(&name))?;
+        } else {
+            storage.copy(&path, &dst.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
 pub async fn write_all_string_to_fs(
     fs: &EncryptedFs,
     ino: u64,
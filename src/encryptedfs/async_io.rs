@@ -0,0 +1,212 @@
+//! Tokio [`AsyncRead`], [`AsyncWrite`] and [`AsyncSeek`] over a handle opened with [`EncryptedFs::open`], like to
+//! `tokio::io::copy` a file into a socket.
+//!
+//! Each call is a [`EncryptedFs::read`] or [`EncryptedFs::write`] at the current position, which do the crypto off
+//! the runtime when [`FsConfig::crypto_threads`](crate::encryptedfs::FsConfig::crypto_threads) is set. The lock of
+//! the file is held only while one of them runs, so other handles of the file are not blocked between calls.
+
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = FsResult<T>> + Send>>;
+
+enum State {
+    Idle,
+    Read(BoxFuture<Vec<u8>>),
+    Write(BoxFuture<usize>),
+    Flush(BoxFuture<()>),
+    Seek(BoxFuture<u64>),
+}
+
+/// Reads a file from a handle opened for read, see the [module docs](self).
+///
+/// It ends at the size of the file, what's written after it's created with other handles is visible to it.
+pub struct FileReader {
+    inner: Inner,
+}
+
+/// Writes a file from a handle opened for write, see the [module docs](self).
+///
+/// Data is durable only after [`AsyncWriteExt::flush`](tokio::io::AsyncWriteExt::flush) or
+/// [`AsyncWriteExt::shutdown`](tokio::io::AsyncWriteExt::shutdown), dropping it doesn't release the handle.
+pub struct FileWriter {
+    inner: Inner,
+}
+
+struct Inner {
+    fs: Arc<EncryptedFs>,
+    ino: u64,
+    handle: u64,
+    pos: u64,
+    state: State,
+}
+
+impl FileReader {
+    pub(crate) const fn new(fs: Arc<EncryptedFs>, ino: u64, handle: u64) -> Self {
+        Self {
+            inner: Inner::new(fs, ino, handle),
+        }
+    }
+}
+
+impl FileWriter {
+    pub(crate) const fn new(fs: Arc<EncryptedFs>, ino: u64, handle: u64) -> Self {
+        Self {
+            inner: Inner::new(fs, ino, handle),
+        }
+    }
+}
+
+impl Inner {
+    const fn new(fs: Arc<EncryptedFs>, ino: u64, handle: u64) -> Self {
+        Self {
+            fs,
+            ino,
+            handle,
+            pos: 0,
+            state: State::Idle,
+        }
+    }
+
+    fn start_seek(&mut self, position: SeekFrom) -> io::Result<()> {
+        if !matches!(self.state, State::Idle) {
+            return Err(io::Error::other("other operation is pending"));
+        }
+        let (fs, ino, pos) = (self.fs.clone(), self.ino, self.pos);
+        self.state = State::Seek(Box::pin(async move {
+            let (base, offset) = match position {
+                SeekFrom::Start(offset) => return Ok(offset),
+                SeekFrom::Current(offset) => (pos, offset),
+                SeekFrom::End(offset) => (fs.get_attr(ino).await?.size, offset),
+            };
+            base.checked_add_signed(offset).ok_or(FsError::InvalidInput(
+                "invalid seek to a negative or overflowing position",
+            ))
+        }));
+        Ok(())
+    }
+
+    fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        match &mut self.state {
+            State::Idle => Poll::Ready(Ok(self.pos)),
+            State::Seek(fut) => {
+                let res = ready!(fut.as_mut().poll(cx));
+                self.state = State::Idle;
+                self.pos = res.map_err(to_io_error)?;
+                Poll::Ready(Ok(self.pos))
+            }
+            _ => Poll::Ready(Err(io::Error::other("other operation is pending"))),
+        }
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let inner = &mut self.inner;
+        if matches!(inner.state, State::Idle) {
+            let (fs, ino, handle, pos) = (inner.fs.clone(), inner.ino, inner.handle, inner.pos);
+            let len = buf.remaining().min(BLOCK_SIZE);
+            inner.state = State::Read(Box::pin(async move {
+                let mut data = vec![0; len];
+                let len = fs.read(ino, pos, &mut data, handle).await?;
+                data.truncate(len);
+                Ok(data)
+            }));
+        }
+        let State::Read(fut) = &mut inner.state else {
+            return Poll::Ready(Err(io::Error::other("other operation is pending")));
+        };
+        let res = ready!(fut.as_mut().poll(cx));
+        inner.state = State::Idle;
+        // nothing read means we are at the end of the file
+        let data = res.map_err(to_io_error)?;
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        inner.pos += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.inner.start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        self.inner.poll_complete(cx)
+    }
+}
+
+impl AsyncWrite for FileWriter {
+    /// The data is copied on the first call, if it returns [`Poll::Pending`] the next call must be with the same
+    /// `buf`, like the [`AsyncWrite`] contract asks.
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let inner = &mut self.inner;
+        if matches!(inner.state, State::Idle) {
+            let (fs, ino, handle, pos) = (inner.fs.clone(), inner.ino, inner.handle, inner.pos);
+            let data = buf.to_vec();
+            inner.state = State::Write(Box::pin(
+                async move { fs.write(ino, pos, &data, handle).await },
+            ));
+        }
+        let State::Write(fut) = &mut inner.state else {
+            return Poll::Ready(Err(io::Error::other("other operation is pending")));
+        };
+        let res = ready!(fut.as_mut().poll(cx));
+        inner.state = State::Idle;
+        let len = res.map_err(to_io_error)?;
+        inner.pos += len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let inner = &mut self.inner;
+        if matches!(inner.state, State::Idle) {
+            let (fs, handle) = (inner.fs.clone(), inner.handle);
+            inner.state = State::Flush(Box::pin(async move { fs.flush(handle).await }));
+        }
+        let State::Flush(fut) = &mut inner.state else {
+            return Poll::Ready(Err(io::Error::other("other operation is pending")));
+        };
+        let res = ready!(fut.as_mut().poll(cx));
+        inner.state = State::Idle;
+        Poll::Ready(res.map_err(to_io_error))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for FileWriter {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.inner.start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        self.inner.poll_complete(cx)
+    }
+}
+
+fn to_io_error(err: FsError) -> io::Error {
+    match err {
+        FsError::Io { source, .. } => source,
+        err => io::Error::other(err),
+    }
+}
@@ -1,15 +1,30 @@
 #[allow(unused_imports)]
+use std::num::NonZeroUsize;
+#[allow(unused_imports)]
+use std::path::Path;
+#[allow(unused_imports)]
 use std::str::FromStr;
 #[allow(unused_imports)]
+use std::sync::Arc;
+#[allow(unused_imports)]
+use std::time::Duration;
+#[allow(unused_imports)]
 use test::{black_box, Bencher};
 
 #[allow(unused_imports)]
 use rand::Rng;
 #[allow(unused_imports)]
 use shush_rs::SecretString;
+#[allow(unused_imports)]
+use tokio::task::JoinSet;
 
 #[allow(unused_imports)]
-use crate::encryptedfs::{DirectoryEntry, DirectoryEntryPlus, FileType, ROOT_INODE};
+use crate::block_cache::BlockCache;
+#[allow(unused_imports)]
+use crate::encryptedfs::{
+    write_all_bytes_to_fs, DirectoryEntry, DirectoryEntryPlus, FileType, FsConfig, SetFileAttr,
+    ROOT_INODE,
+};
 #[allow(unused_imports)]
 use crate::test_common::{create_attr, get_fs};
 #[allow(unused_imports)]
@@ -44,6 +59,34 @@ fn bench_create(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn bench_create_many(b: &mut Bencher) {
+    test_common::bench("bench_create_many", 1, false, async {
+        let fs = get_fs().await;
+
+        let mut i = 1;
+        let i = &mut i;
+        b.iter(|| {
+            black_box({
+                async_util::call_async(async {
+                    let entries = (0..100)
+                        .map(|j| {
+                            (
+                                SecretString::from_str(&format!("test-file-{i}-{j}")).unwrap(),
+                                create_attr(FileType::RegularFile),
+                                b"test-42".to_vec(),
+                            )
+                        })
+                        .collect();
+                    let _ = fs.create_many(ROOT_INODE, entries).await.unwrap();
+                });
+                *i += 1;
+                *i
+            })
+        });
+    });
+}
+
 #[bench]
 fn bench_exists_by_name(b: &mut Bencher) {
     test_common::bench("exists_by_name", 1, false, async {
@@ -161,3 +204,312 @@ fn bench_read_dir_plus(b: &mut Bencher) {
         });
     });
 }
+
+#[bench]
+fn bench_concurrent_read(b: &mut Bencher) {
+    bench_concurrent_read_with_crypto_threads("bench_concurrent_read", None, b);
+}
+
+#[bench]
+fn bench_concurrent_read_crypto_threads(b: &mut Bencher) {
+    bench_concurrent_read_with_crypto_threads(
+        "bench_concurrent_read_crypto_threads",
+        NonZeroUsize::new(4),
+        b,
+    );
+}
+
+#[allow(dead_code)]
+fn bench_concurrent_read_with_crypto_threads(
+    key: &'static str,
+    threads: Option<NonZeroUsize>,
+    b: &mut Bencher,
+) {
+    let config = FsConfig {
+        crypto_threads: threads,
+        ..FsConfig::default()
+    };
+    test_common::bench_with_config(key, 4, false, config, async {
+        let fs = get_fs().await;
+
+        let mut inos = vec![];
+        for i in 0..8 {
+            let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "test-42".repeat(128 * 1024);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            inos.push(attr.ino);
+        }
+
+        b.iter(|| {
+            async_util::call_async(async {
+                let mut join_set = JoinSet::new();
+                for ino in inos.clone() {
+                    let fs = fs.clone();
+                    join_set.spawn(async move {
+                        black_box(test_common::read_to_string(ino, &fs).await)
+                    });
+                }
+                while let Some(res) = join_set.join_next().await {
+                    res.unwrap();
+                }
+            });
+            black_box(());
+        });
+    });
+}
+
+#[bench]
+fn bench_read_small_file(b: &mut Bencher) {
+    bench_read_small_file_with_times_write_back("bench_read_small_file", None, b);
+}
+
+#[bench]
+fn bench_read_small_file_times_write_back(b: &mut Bencher) {
+    bench_read_small_file_with_times_write_back(
+        "bench_read_small_file_times_write_back",
+        Some(Duration::from_secs(1)),
+        b,
+    );
+}
+
+/// Each read releases the handle which updates `atime`, with write-back enabled the inode is not rewritten each time.
+#[allow(dead_code)]
+fn bench_read_small_file_with_times_write_back(
+    key: &'static str,
+    interval: Option<Duration>,
+    b: &mut Bencher,
+) {
+    let config = FsConfig {
+        times_write_back: interval,
+        ..FsConfig::default()
+    };
+    test_common::bench_with_config(key, 1, false, config, async {
+        let fs = get_fs().await;
+
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+
+        b.iter(|| {
+            async_util::call_async(async {
+                black_box(test_common::read_to_string(attr.ino, &fs).await);
+            });
+            black_box(());
+        });
+    });
+}
+
+#[bench]
+fn bench_read_file(b: &mut Bencher) {
+    bench_read_file_with_block_cache("bench_read_file", false, b);
+}
+
+#[bench]
+fn bench_read_file_block_cache(b: &mut Bencher) {
+    bench_read_file_with_block_cache("bench_read_file_block_cache", true, b);
+}
+
+/// Reads the same file repeatedly, with the block cache only the first read decrypts the blocks.
+#[allow(dead_code)]
+fn bench_read_file_with_block_cache(key: &'static str, block_cache: bool, b: &mut Bencher) {
+    let config = FsConfig {
+        block_cache: block_cache
+            .then(|| Arc::new(BlockCache::new(Path::new("/dev/shm"), 16 * 1024 * 1024).unwrap())),
+        ..FsConfig::default()
+    };
+    test_common::bench_with_config(key, 1, false, config, async {
+        let fs = get_fs().await;
+
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        let data = "test-42".repeat(128 * 1024);
+        write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+
+        b.iter(|| {
+            async_util::call_async(async {
+                black_box(test_common::read_to_string(attr.ino, &fs).await);
+            });
+            black_box(());
+        });
+    });
+}
+
+/// Random 4 KiB reads in a big file with the same handle, each one decrypts only the blocks it reads from,
+/// not the file from the start.
+#[bench]
+fn bench_read_random(b: &mut Bencher) {
+    test_common::bench("bench_read_random", 1, false, async {
+        let fs = get_fs().await;
+
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        let data = "test-42".repeat(1024 * 1024);
+        write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+
+        let fh = fs.open(attr.ino, true, false).await.unwrap();
+        let mut buf = vec![0; 4096];
+        let max_offset = (data.len() - buf.len()) as u64;
+        let mut rng = rand::thread_rng();
+        b.iter(|| {
+            async_util::call_async(async {
+                for _ in 0..1000 {
+                    let offset = rng.gen_range(0..max_offset);
+                    black_box(fs.read(attr.ino, offset, &mut buf, fh).await.unwrap());
+                }
+            });
+            black_box(());
+        });
+        fs.release(fh).await.unwrap();
+    });
+}
+
+/// Shrinks a big file by one byte each time, only the last block is re-encrypted.
+#[bench]
+fn bench_set_len_shrink(b: &mut Bencher) {
+    test_common::bench("bench_set_len_shrink", 1, false, async {
+        let fs = get_fs().await;
+
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        let data = "test-42".repeat(1024 * 1024);
+        write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+
+        let mut size = data.len() as u64;
+        b.iter(|| {
+            size -= 1;
+            async_util::call_async(async {
+                fs.set_len(attr.ino, size).await.unwrap();
+            });
+            black_box(size)
+        });
+    });
+}
+
+#[bench]
+fn bench_concurrent_ops_1_task(b: &mut Bencher) {
+    bench_concurrent_ops_with_tasks("bench_concurrent_ops_1_task", 1, b);
+}
+
+#[bench]
+fn bench_concurrent_ops_8_tasks(b: &mut Bencher) {
+    bench_concurrent_ops_with_tasks("bench_concurrent_ops_8_tasks", 8, b);
+}
+
+/// Each task writes, reads and updates the attributes of its own file, as they don't share any inode
+/// the time per iteration should stay close to the one with 1 task, as long as there are enough cores.
+#[allow(dead_code)]
+fn bench_concurrent_ops_with_tasks(key: &'static str, tasks: usize, b: &mut Bencher) {
+    test_common::bench(key, tasks, false, async {
+        let fs = get_fs().await;
+
+        let mut files = vec![];
+        for i in 0..tasks {
+            let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            files.push((attr.ino, fh));
+        }
+        let data = Arc::new("test-42".repeat(16 * 1024));
+
+        b.iter(|| {
+            async_util::call_async(async {
+                let mut join_set = JoinSet::new();
+                for (ino, fh) in files.clone() {
+                    let fs = fs.clone();
+                    let data = data.clone();
+                    join_set.spawn(async move {
+                        write_all_bytes_to_fs(&fs, ino, 0, data.as_bytes(), fh)
+                            .await
+                            .unwrap();
+                        fs.flush(fh).await.unwrap();
+                        black_box(test_common::read_to_string(ino, &fs).await);
+                        fs.set_attr(
+                            ino,
+                            SetFileAttr::default().with_mtime(std::time::SystemTime::now()),
+                        )
+                        .await
+                        .unwrap();
+                    });
+                }
+                while let Some(res) = join_set.join_next().await {
+                    res.unwrap();
+                }
+            });
+            black_box(());
+        });
+    });
+}
@@ -0,0 +1,516 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rand_core::RngCore;
+
+use crate::bincode_util;
+use crate::crypto::{self, Cipher};
+use crate::encryptedfs::storage::{FileStorage, Storage, StorageFile};
+use crate::encryptedfs::{
+    FsError, FsResult, CIPHER_FILENAME, CIPHER_MIGRATION_FILENAME,
+    CIPHER_MIGRATION_JOURNAL_FILENAME, SECURITY_DIR,
+};
+
+/// Suffix of the temp files keeping the re-encrypted data until it replaces the original file.
+pub(crate) const MIGRATING_SUFFIX: &str = ".migrating";
+
+struct Migration {
+    to: Cipher,
+    // relative to data dir
+    migrated: HashSet<PathBuf>,
+    journal: Box<dyn StorageFile>,
+}
+
+impl Migration {
+    /// Tag the file as using the new cipher, `tmp` is the file which will replace it.
+    fn tag(&mut self, data_dir: &Path, path: &Path, tmp: Option<&Path>) -> FsResult<()> {
+        let path = relative(data_dir, path)?;
+        let tmp = tmp.map(|tmp| relative(data_dir, tmp)).transpose()?;
+        let tmp = tmp.as_ref().map_or("-", |tmp| tmp.to_str().unwrap());
+        writeln!(self.journal, "{tmp} {}", path.to_str().unwrap())?;
+        self.journal.sync_data()?;
+        self.migrated.insert(path);
+        Ok(())
+    }
+}
+
+struct Inner {
+    cipher: Cipher,
+    migration: Option<Migration>,
+}
+
+impl Inner {
+    fn cipher_for(&self, data_dir: &Path, path: &Path) -> Cipher {
+        match &self.migration {
+            Some(migration)
+                if path
+                    .strip_prefix(data_dir)
+                    .is_ok_and(|path| migration.migrated.contains(path)) =>
+            {
+                migration.to
+            }
+            _ => self.cipher,
+        }
+    }
+}
+
+/// Keeps track of the cipher each file is encrypted with.
+///
+/// Normally all files use the volume cipher, kept in `security/cipher`. While migrating to another one with
+/// [`EncryptedFs::migrate_cipher`](super::EncryptedFs::migrate_cipher) the files already using the new cipher
+/// are tagged in a journal, new files are created directly with the new cipher.
+///
+/// A file is re-encrypted into a temp file, which replaces the original one after it's tagged.
+/// If we crash in between, the replace is finished on [`CipherTags::load`].
+///
+/// Its files, and the ones opened with [`CipherTags::open`], are kept in a [`Storage`].
+pub(crate) struct CipherTags {
+    data_dir: PathBuf,
+    storage: Arc<dyn Storage>,
+    inner: RwLock<Inner>,
+}
+
+impl CipherTags {
+    /// `cipher` is used for volumes which don't have `security/cipher` yet.
+    ///
+    /// For existing volumes it needs to be the volume cipher, or the one we are migrating to,
+    /// else it returns [`FsError::CipherMismatch`].
+    pub(crate) fn load(data_dir: &Path, cipher: Cipher) -> FsResult<Self> {
+        Self::load_with(Arc::new(FileStorage), data_dir, cipher)
+    }
+
+    /// Like [`CipherTags::load`], with the files kept in `storage`.
+    pub(crate) fn load_with(
+        storage: Arc<dyn Storage>,
+        data_dir: &Path,
+        cipher: Cipher,
+    ) -> FsResult<Self> {
+        let requested = cipher;
+        let security_dir = data_dir.join(SECURITY_DIR);
+        let cipher_path = security_dir.join(CIPHER_FILENAME);
+        let cipher = if storage.exists(&cipher_path) {
+            bincode_util::deserialize_from(storage.open(&cipher_path)?, bincode_util::SMALL_LIMIT)?
+        } else {
+            cipher
+        };
+
+        let migration_path = security_dir.join(CIPHER_MIGRATION_FILENAME);
+        let migration = if storage.exists(&migration_path) {
+            let to: Cipher = bincode_util::deserialize_from(
+                storage.open(&migration_path)?,
+                bincode_util::SMALL_LIMIT,
+            )?;
+            let journal_path = security_dir.join(CIPHER_MIGRATION_JOURNAL_FILENAME);
+            let mut migrated = HashSet::new();
+            let content = storage.read(&journal_path)?;
+            // the last line might not be completely written
+            let complete = content
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |pos| pos + 1);
+            for line in String::from_utf8_lossy(&content[..complete]).lines() {
+                let Some((tmp, path)) = line.split_once(' ') else {
+                    continue;
+                };
+                if tmp != "-" && storage.exists(&data_dir.join(tmp)) {
+                    // crashed after tagging it, finish the replace
+                    let path = data_dir.join(path);
+                    storage.rename(&data_dir.join(tmp), &path)?;
+                    storage.sync_dir(path.parent().unwrap())?;
+                }
+                migrated.insert(PathBuf::from(path));
+            }
+            let journal = storage.open_append(&journal_path, false)?;
+            if complete < content.len() {
+                journal.set_len(complete as u64)?;
+            }
+            Some(Migration {
+                to,
+                migrated,
+                journal,
+            })
+        } else {
+            None
+        };
+        if requested != cipher && migration.as_ref().map(|m| m.to) != Some(requested) {
+            return Err(FsError::CipherMismatch {
+                volume: cipher,
+                requested,
+            });
+        }
+        // left from a crash before they were tagged
+        for name in storage.read_dir(&security_dir)? {
+            if name
+                .to_str()
+                .unwrap_or_default()
+                .ends_with(MIGRATING_SUFFIX)
+            {
+                storage.remove_file(&security_dir.join(name))?;
+            }
+        }
+
+        Ok(Self {
+            data_dir: data_dir.to_path_buf(),
+            storage,
+            inner: RwLock::new(Inner { cipher, migration }),
+        })
+    }
+
+    /// Temp file where we write the re-encrypted data of a file.
+    ///
+    /// They are kept in the security dir, so they don't show up in the listing of directories.
+    pub(crate) fn tmp_path(&self) -> PathBuf {
+        self.data_dir.join(SECURITY_DIR).join(format!(
+            "{:016x}{MIGRATING_SUFFIX}",
+            crypto::create_rng().next_u64()
+        ))
+    }
+
+    pub(crate) fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// Cipher of the volume, while migrating it's the one we migrate from.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn cipher(&self) -> Cipher {
+        self.inner.read().unwrap().cipher
+    }
+
+    /// Cipher we migrate to, if a migration is in progress.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn migrating_to(&self) -> Option<Cipher> {
+        self.inner
+            .read()
+            .unwrap()
+            .migration
+            .as_ref()
+            .map(|migration| migration.to)
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn cipher_for(&self, path: &Path) -> Cipher {
+        self.inner.read().unwrap().cipher_for(&self.data_dir, path)
+    }
+
+    /// Open the file for read together with the cipher it's encrypted with.
+    ///
+    /// The opened file keeps its content even if a migration replaces it after, so they always match.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn open(&self, path: &Path) -> FsResult<(Box<dyn StorageFile>, Cipher)> {
+        let inner = self.inner.read().unwrap();
+        Ok((
+            self.storage.open(path)?,
+            inner.cipher_for(&self.data_dir, path),
+        ))
+    }
+
+    /// Cipher to write the file with, if it doesn't exist and we're migrating it's created with the new cipher.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn for_write(&self, path: &Path) -> FsResult<Cipher> {
+        {
+            // not migrating most of the time, don't block other files
+            let inner = self.inner.read().unwrap();
+            if inner.migration.is_none() {
+                return Ok(inner.cipher);
+            }
+        }
+        let mut inner = self.inner.write().unwrap();
+        if let Some(migration) = inner.migration.as_mut() {
+            if !self.storage.exists(path) {
+                migration.tag(&self.data_dir, path, None)?;
+                return Ok(migration.to);
+            }
+        }
+        Ok(inner.cipher_for(&self.data_dir, path))
+    }
+
+    /// Like [`CipherTags::for_write`], for files whose name depends on the cipher, like the encrypted names
+    /// of directory entries. `path` builds the path for the cipher we would use.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn for_new_path(
+        &self,
+        path: impl FnOnce(Cipher) -> FsResult<PathBuf>,
+    ) -> FsResult<(PathBuf, Cipher)> {
+        {
+            // keep it while building the path, so a migration doesn't start meanwhile
+            let inner = self.inner.read().unwrap();
+            if inner.migration.is_none() {
+                let cipher = inner.cipher;
+                return Ok((path(cipher)?, cipher));
+            }
+        }
+        let mut inner = self.inner.write().unwrap();
+        let Some(to) = inner.migration.as_ref().map(|migration| migration.to) else {
+            let cipher = inner.cipher;
+            return Ok((path(cipher)?, cipher));
+        };
+        let path = path(to)?;
+        if self.storage.exists(&path) {
+            // names which are not encrypted, like `$.`
+            let cipher = inner.cipher_for(&self.data_dir, &path);
+            return Ok((path, cipher));
+        }
+        if let Some(migration) = inner.migration.as_mut() {
+            migration.tag(&self.data_dir, &path, None)?;
+        }
+        Ok((path, to))
+    }
+
+    /// Start migrating to `to`, or continue if we were already migrating to it.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn start(&self, to: Cipher) -> FsResult<()> {
+        let mut inner = self.inner.write().unwrap();
+        match &inner.migration {
+            Some(migration) if migration.to == to => return Ok(()),
+            Some(_) => {
+                return Err(FsError::InvalidInput(
+                    "another cipher migration is in progress",
+                ))
+            }
+            None => {}
+        }
+        if inner.cipher.key_len() != to.key_len() {
+            return Err(FsError::InvalidInput(
+                "ciphers need to have the same key length",
+            ));
+        }
+        let security_dir = self.data_dir.join(SECURITY_DIR);
+        let journal_path = security_dir.join(CIPHER_MIGRATION_JOURNAL_FILENAME);
+        if self.storage.exists(&journal_path) {
+            self.storage.remove_file(&journal_path)?;
+        }
+        let journal = self.storage.open_append(&journal_path, true)?;
+        journal.sync_all()?;
+        // from now on the migration is resumed after a restart
+        save_cipher(
+            &*self.storage,
+            &security_dir.join(CIPHER_MIGRATION_FILENAME),
+            to,
+        )?;
+        inner.migration = Some(Migration {
+            to,
+            migrated: HashSet::new(),
+            journal,
+        });
+        Ok(())
+    }
+
+    /// Replace `path` with `tmp`, which has the same content encrypted with the cipher we migrate to.
+    ///
+    /// Returns `false` if `path` doesn't exist anymore, `tmp` is removed in that case.
+    pub(crate) fn replace(&self, path: &Path, tmp: &Path) -> FsResult<bool> {
+        self.commit(path, tmp, true)
+    }
+
+    /// Move `tmp`, encrypted with the cipher we migrate to, to `path` which doesn't exist yet.
+    pub(crate) fn create(&self, path: &Path, tmp: &Path) -> FsResult<()> {
+        self.commit(path, tmp, false).map(|_| ())
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    fn commit(&self, path: &Path, tmp: &Path, replace: bool) -> FsResult<bool> {
+        let mut inner = self.inner.write().unwrap();
+        let migration = inner
+            .migration
+            .as_mut()
+            .ok_or(FsError::Other("no cipher migration in progress"))?;
+        if replace && !self.storage.exists(path) {
+            self.storage.remove_file(tmp)?;
+            return Ok(false);
+        }
+        migration.tag(&self.data_dir, path, Some(tmp))?;
+        self.storage.rename(tmp, path)?;
+        self.storage.sync_dir(path.parent().unwrap())?;
+        Ok(true)
+    }
+
+    /// Make the cipher we migrated to the volume cipher, all files need to be migrated by now.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn finish(&self) -> FsResult<()> {
+        let mut inner = self.inner.write().unwrap();
+        let Some(to) = inner.migration.as_ref().map(|migration| migration.to) else {
+            return Ok(());
+        };
+        let security_dir = self.data_dir.join(SECURITY_DIR);
+        save_cipher(&*self.storage, &security_dir.join(CIPHER_FILENAME), to)?;
+        inner.cipher = to;
+        inner.migration = None;
+        self.storage
+            .remove_file(&security_dir.join(CIPHER_MIGRATION_FILENAME))?;
+        self.storage
+            .remove_file(&security_dir.join(CIPHER_MIGRATION_JOURNAL_FILENAME))?;
+        self.storage.sync_dir(&security_dir)?;
+        Ok(())
+    }
+
+    /// Keep the volume cipher in `security/cipher` if it's not there yet.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn save(&self) -> FsResult<()> {
+        let path = self.data_dir.join(SECURITY_DIR).join(CIPHER_FILENAME);
+        if !self.storage.exists(&path) {
+            save_cipher(&*self.storage, &path, self.inner.read().unwrap().cipher)?;
+        }
+        Ok(())
+    }
+}
+
+fn relative(data_dir: &Path, path: &Path) -> FsResult<PathBuf> {
+    Ok(path
+        .strip_prefix(data_dir)
+        .map_err(|_| FsError::InvalidInput("path is not in data dir"))?
+        .to_path_buf())
+}
+
+fn save_cipher(storage: &dyn Storage, path: &Path, cipher: Cipher) -> FsResult<()> {
+    storage.write_atomic(path, &bincode::serialize(&cipher)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Read;
+
+    use super::*;
+    use crate::encryptedfs::storage::MemStorage;
+
+    fn setup() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_path_buf();
+        fs::create_dir_all(data_dir.join(SECURITY_DIR)).unwrap();
+        fs::create_dir_all(data_dir.join("inodes")).unwrap();
+        (dir, data_dir)
+    }
+
+    #[test]
+    fn test_tags() {
+        let (_dir, data_dir) = setup();
+        let old = data_dir.join("inodes").join("1");
+        let new = data_dir.join("inodes").join("2");
+        fs::write(&old, "old").unwrap();
+
+        let tags = CipherTags::load(&data_dir, Cipher::ChaCha20Poly1305).unwrap();
+        assert_eq!(tags.migrating_to(), None);
+        assert_eq!(tags.for_write(&new).unwrap(), Cipher::ChaCha20Poly1305);
+
+        tags.start(Cipher::Aes256Gcm).unwrap();
+        assert!(tags.start(Cipher::ChaCha20Poly1305).is_err());
+        // existing files keep their cipher until replaced, new ones use the new cipher
+        assert_eq!(tags.for_write(&old).unwrap(), Cipher::ChaCha20Poly1305);
+        assert_eq!(tags.for_write(&new).unwrap(), Cipher::Aes256Gcm);
+        let tmp = tags.tmp_path();
+        fs::write(&tmp, "new").unwrap();
+        assert!(tags.replace(&old, &tmp).unwrap());
+        assert_eq!(fs::read_to_string(&old).unwrap(), "new");
+        let (_, cipher) = tags.open(&old).unwrap();
+        assert_eq!(cipher, Cipher::Aes256Gcm);
+
+        tags.finish().unwrap();
+        assert_eq!(tags.cipher(), Cipher::Aes256Gcm);
+        assert_eq!(tags.migrating_to(), None);
+        // the volume cipher is kept, opening with the old one is refused
+        assert!(matches!(
+            CipherTags::load(&data_dir, Cipher::ChaCha20Poly1305),
+            Err(FsError::CipherMismatch {
+                volume: Cipher::Aes256Gcm,
+                requested: Cipher::ChaCha20Poly1305
+            })
+        ));
+        let tags = CipherTags::load(&data_dir, Cipher::Aes256Gcm).unwrap();
+        assert_eq!(tags.cipher(), Cipher::Aes256Gcm);
+    }
+
+    #[test]
+    fn test_tags_in_mem_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let data_dir = Path::new("/data");
+        storage
+            .create_dir_all(&data_dir.join(SECURITY_DIR))
+            .unwrap();
+        storage.create_dir_all(&data_dir.join("inodes")).unwrap();
+        let path = data_dir.join("inodes").join("1");
+        storage.write_atomic(&path, b"old").unwrap();
+
+        let tags =
+            CipherTags::load_with(storage.clone(), data_dir, Cipher::ChaCha20Poly1305).unwrap();
+        tags.save().unwrap();
+        tags.start(Cipher::Aes256Gcm).unwrap();
+        let tmp = tags.tmp_path();
+        storage.write_atomic(&tmp, b"new").unwrap();
+        assert!(tags.replace(&path, &tmp).unwrap());
+        let (mut file, cipher) = tags.open(&path).unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!((data.as_str(), cipher), ("new", Cipher::Aes256Gcm));
+        drop(tags);
+
+        // resumed from what's kept in the storage, nothing is on disk
+        assert!(!data_dir.join(SECURITY_DIR).exists());
+        let tags =
+            CipherTags::load_with(storage.clone(), data_dir, Cipher::ChaCha20Poly1305).unwrap();
+        assert_eq!(tags.migrating_to(), Some(Cipher::Aes256Gcm));
+        assert_eq!(tags.cipher_for(&path), Cipher::Aes256Gcm);
+        tags.finish().unwrap();
+        let tags = CipherTags::load_with(storage, data_dir, Cipher::Aes256Gcm).unwrap();
+        assert_eq!(tags.cipher(), Cipher::Aes256Gcm);
+        assert_eq!(tags.migrating_to(), None);
+    }
+
+    #[test]
+    fn test_resume() {
+        let (_dir, data_dir) = setup();
+        let path = data_dir.join("inodes").join("1");
+        fs::write(&path, "old").unwrap();
+
+        let tags = CipherTags::load(&data_dir, Cipher::ChaCha20Poly1305).unwrap();
+        tags.start(Cipher::Aes256Gcm).unwrap();
+        // crash after tagging, before replacing it
+        let tmp = tags.tmp_path();
+        fs::write(&tmp, "new").unwrap();
+        tags.inner
+            .write()
+            .unwrap()
+            .migration
+            .as_mut()
+            .unwrap()
+            .tag(&data_dir, &path, Some(&tmp))
+            .unwrap();
+        drop(tags);
+        // and while writing the next entry
+        OpenOptions::new()
+            .append(true)
+            .open(
+                data_dir
+                    .join(SECURITY_DIR)
+                    .join(CIPHER_MIGRATION_JOURNAL_FILENAME),
+            )
+            .unwrap()
+            .write_all(b"- inodes/")
+            .unwrap();
+
+        let tags = CipherTags::load(&data_dir, Cipher::ChaCha20Poly1305).unwrap();
+        assert_eq!(tags.migrating_to(), Some(Cipher::Aes256Gcm));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!tmp.exists());
+        // not tagged, so it's removed
+        let stale = tags.tmp_path();
+        fs::write(&stale, "new").unwrap();
+        drop(tags);
+        let tags = CipherTags::load(&data_dir, Cipher::ChaCha20Poly1305).unwrap();
+        assert!(!stale.exists());
+        assert_eq!(tags.cipher_for(&path), Cipher::Aes256Gcm);
+        assert_eq!(
+            tags.cipher_for(&data_dir.join("inodes").join("2")),
+            Cipher::ChaCha20Poly1305
+        );
+        let journal = fs::read_to_string(
+            data_dir
+                .join(SECURITY_DIR)
+                .join(CIPHER_MIGRATION_JOURNAL_FILENAME),
+        )
+        .unwrap();
+        assert!(journal.ends_with('\n'));
+    }
+}
@@ -0,0 +1,127 @@
+//! Numbers of new inodes, unique without checking which ones exist and still not guessable.
+//!
+//! The high 32 bits are a counter kept in `security/ino_counter`, the low 32 bits are random. The counter makes
+//! them unique, also between concurrent calls, the random part keeps them from being guessed from the order files
+//! were created in. Volumes created before it have fully random numbers, a new number matches one of those only if
+//! both the counter and the random part match, which is as likely as two random numbers matching.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::encryptedfs::storage::Storage;
+
+/// How many values we reserve at once, so we don't write the counter for each new inode.
+const RESERVE: u64 = 1 << 10;
+const RANDOM_BITS: u32 = 32;
+const MAX: u64 = 1 << (64 - RANDOM_BITS);
+
+/// Shared by all the creates of a volume, see the [module docs](self).
+///
+/// Like [`NonceCounter`](crate::crypto::nonce::NonceCounter), we persist the end of a range of reserved values before
+/// using them, so after a crash we continue after it and never hand out a number twice.
+pub(crate) struct InoCounter {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    next: u64,
+    reserved: u64,
+    rng: ChaCha20Rng,
+}
+
+impl InoCounter {
+    /// Open the counter kept in `path` in `storage`, it's created on the first [`InoCounter::next`] if it doesn't
+    /// exist.
+    pub(crate) fn open(storage: Arc<dyn Storage>, path: &Path) -> io::Result<Self> {
+        let reserved = match storage.read(path) {
+            Ok(bytes) => u64::from_le_bytes(bytes.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid inode counter")
+            })?),
+            // start from 1, so the numbers are never the root or 0
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 1,
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            storage,
+            path: path.to_path_buf(),
+            state: Mutex::new(State {
+                next: reserved,
+                reserved,
+                rng: ChaCha20Rng::from_entropy(),
+            }),
+        })
+    }
+
+    /// Number for a new inode, never returned before for this volume.
+    #[allow(clippy::missing_panics_doc)]
+    pub(crate) fn next(&self) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.next == state.reserved {
+            let reserved = state.reserved + RESERVE;
+            if reserved > MAX {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "inode counter exhausted",
+                ));
+            }
+            self.storage
+                .write_atomic(&self.path, &reserved.to_le_bytes())?;
+            state.reserved = reserved;
+        }
+        let counter = state.next;
+        state.next += 1;
+        Ok(counter << RANDOM_BITS | u64::from(state.rng.next_u32()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use super::*;
+    use crate::encryptedfs::storage::FileStorage;
+
+    #[test]
+    fn test_next() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ino_counter");
+        let counter = InoCounter::open(Arc::new(FileStorage), &path).unwrap();
+        let mut last = counter.next().unwrap();
+        assert_eq!(1, last >> RANDOM_BITS);
+        assert!(path.exists());
+        for _ in 0..RESERVE * 2 {
+            let ino = counter.next().unwrap();
+            assert!(ino > last);
+            last = ino;
+        }
+    }
+
+    #[test]
+    fn test_next_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ino_counter");
+        let counter = InoCounter::open(Arc::new(FileStorage), &path).unwrap();
+        let mut inos = HashSet::new();
+        for _ in 0..10 {
+            inos.insert(counter.next().unwrap());
+        }
+        // nothing is written on drop, like when the process is killed
+        drop(counter);
+
+        let counter = InoCounter::open(Arc::new(FileStorage), &path).unwrap();
+        let ino = counter.next().unwrap();
+        assert!(inos
+            .iter()
+            .all(|prev| ino >> RANDOM_BITS > prev >> RANDOM_BITS));
+
+        fs::write(&path, [0; 3]).unwrap();
+        assert!(InoCounter::open(Arc::new(FileStorage), &path).is_err());
+    }
+}
@@ -0,0 +1,832 @@
+//! Storage of the inodes, one file per inode or all of them in one file, see [`InodeBackend`].
+//!
+//! With [`InodeBackend::Db`] each change of an inode is appended to `inodes/db` as a record: the `ino` and the
+//! length of the payload as little-endian `u64` and `u32`, a checksum of them and of the payload, then the payload,
+//! which is the inode encrypted like in `inodes/<ino>`. A record with an empty payload marks a removed inode.
+//! An in-memory index keeps where the last record of each inode is, it's built when the file is opened by reading
+//! all the records and checking their checksums, so opening takes longer as the file grows, until it's compacted.
+//! If we crash while appending, the records from the first one with a wrong checksum are dropped. When old records take more than the live ones the file is compacted, the live records are copied
+//! to a temp file which then replaces it.
+//!
+//! The files are kept in the [`Storage`] of the [`CipherTags`].
+//!
+//! An inode is saved as an [`InodeRecord`], the format version followed by the [`FileAttr`]. Inodes written before
+//! the version was added are the bare fields of [`FileAttrV0`], those are read too and get the new fields empty.
+
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use shush_rs::{SecretVec, Zeroize};
+use tracing::{error, warn};
+
+use crate::encryptedfs::cipher_tags::CipherTags;
+use crate::encryptedfs::storage::{Storage, StorageFile};
+use crate::encryptedfs::{FileAttr, FileType, FsError, FsResult, InodeBackend, INODES_DIR};
+use crate::{bincode_util, crypto};
+
+/// The file keeping all inodes with [`InodeBackend::Db`], in `inodes/`.
+pub(crate) const INODE_DB_FILENAME: &str = "db";
+/// Where the live records are copied while compacting.
+const COMPACT_FILENAME: &str = "db.compact";
+const CHECKSUM_LEN: usize = 8;
+/// `ino`, length of the payload and checksum.
+const HEADER_LEN: u64 = 8 + 4 + CHECKSUM_LEN as u64;
+/// Don't compact while the old records take less than this.
+const COMPACT_MIN_DEAD_LEN: u64 = 1024 * 1024;
+/// Version of the [`InodeRecord`] we write. When fields are added to [`FileAttr`] bump it and keep the previous
+/// struct to read the older records.
+const INODE_VERSION: u16 = 1;
+/// Length of a serialized [`FileAttrV0`], all its fields have a fixed size.
+const INODE_V0_LEN: usize = 102;
+
+/// How an inode is saved, the version tells which struct follows.
+#[derive(Serialize)]
+pub(crate) struct InodeRecord<'a> {
+    version: u16,
+    attr: &'a FileAttr,
+}
+
+impl<'a> InodeRecord<'a> {
+    pub(crate) const fn new(attr: &'a FileAttr) -> Self {
+        Self {
+            version: INODE_VERSION,
+            attr,
+        }
+    }
+}
+
+/// The inode as it was saved before [`InodeRecord`], without a version. It has a fixed length, which is shorter than
+/// any versioned record, that's how they are told apart.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FileAttrV0 {
+    pub(crate) ino: u64,
+    pub(crate) size: u64,
+    pub(crate) blocks: u64,
+    pub(crate) atime: SystemTime,
+    pub(crate) mtime: SystemTime,
+    pub(crate) ctime: SystemTime,
+    pub(crate) crtime: SystemTime,
+    pub(crate) kind: FileType,
+    pub(crate) perm: u16,
+    pub(crate) nlink: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) rdev: u32,
+    pub(crate) blksize: u32,
+    pub(crate) flags: u32,
+}
+
+#[cfg(test)]
+impl From<FileAttr> for FileAttrV0 {
+    fn from(attr: FileAttr) -> Self {
+        Self {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            crtime: attr.crtime,
+            kind: attr.kind,
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
+            blksize: attr.blksize,
+            flags: attr.flags,
+        }
+    }
+}
+
+impl From<FileAttrV0> for FileAttr {
+    fn from(attr: FileAttrV0) -> Self {
+        Self {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            crtime: attr.crtime,
+            kind: attr.kind,
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
+            blksize: attr.blksize,
+            flags: attr.flags,
+            content_mac: None,
+            file_key: None,
+            holes_mac: None,
+        }
+    }
+}
+
+/// Read an inode saved as an [`InodeRecord`] or as a [`FileAttrV0`].
+pub(crate) fn deserialize_inode(reader: impl Read) -> bincode::Result<FileAttr> {
+    let mut data = vec![];
+    reader
+        .take(bincode_util::METADATA_LIMIT + 1)
+        .read_to_end(&mut data)?;
+    let res = deserialize_inode_data(&data);
+    // it has the key of the file
+    data.zeroize();
+    res
+}
+
+fn deserialize_inode_data(data: &[u8]) -> bincode::Result<FileAttr> {
+    if data.len() as u64 > bincode_util::METADATA_LIMIT {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+    if data.len() == INODE_V0_LEN {
+        let attr: FileAttrV0 = bincode_util::deserialize_from(data, bincode_util::METADATA_LIMIT)?;
+        return Ok(attr.into());
+    }
+    let version: u16 = bincode_util::deserialize_from(data, bincode_util::METADATA_LIMIT)?;
+    if version != INODE_VERSION {
+        return Err(Box::new(bincode::ErrorKind::Custom(format!(
+            "unknown inode format version {version}"
+        ))));
+    }
+    let (_, attr): (u16, FileAttr) =
+        bincode_util::deserialize_from(data, bincode_util::METADATA_LIMIT)?;
+    Ok(attr)
+}
+
+/// The inodes of a data dir, or of a snapshot.
+pub(crate) enum InodeStore {
+    Files {
+        dir: PathBuf,
+        ciphers: Arc<CipherTags>,
+        // held for write while the inodes are copied, so the copy doesn't see only part of the changes
+        lock: RwLock<()>,
+    },
+    Db(InodeDb),
+}
+
+impl InodeStore {
+    /// Open the inodes of `root`, the backend of existing ones is detected, `backend` is used for new ones.
+    ///
+    /// Fails with [`FsError::InvalidInput`] if `backend` is set and the existing inodes use the other one.
+    pub(crate) fn open(
+        root: &Path,
+        backend: Option<InodeBackend>,
+        ciphers: Arc<CipherTags>,
+        read_only: bool,
+    ) -> FsResult<Self> {
+        let dir = root.join(INODES_DIR);
+        let storage = ciphers.storage();
+        let existing = if storage.exists(&dir.join(INODE_DB_FILENAME)) {
+            Some(InodeBackend::Db)
+        } else if storage.exists(&dir) && !storage.read_dir(&dir)?.is_empty() {
+            Some(InodeBackend::Files)
+        } else {
+            None
+        };
+        if let (Some(existing), Some(backend)) = (existing, backend) {
+            if existing != backend {
+                return Err(FsError::InvalidInput(
+                    "the data dir uses another inode backend",
+                ));
+            }
+        }
+        Ok(match existing.or(backend).unwrap_or_default() {
+            InodeBackend::Files => Self::Files {
+                dir,
+                ciphers,
+                lock: RwLock::default(),
+            },
+            InodeBackend::Db => Self::Db(InodeDb::open(&dir, ciphers, read_only)?),
+        })
+    }
+
+    /// Read one inode from the data dir or the snapshot in `root`, without keeping it opened.
+    pub(crate) fn read_from(
+        root: &Path,
+        ino: u64,
+        ciphers: &Arc<CipherTags>,
+        key: &SecretVec<u8>,
+    ) -> FsResult<FileAttr> {
+        Self::open(root, None, ciphers.clone(), true)?.read(ino, key)
+    }
+
+    pub(crate) const fn backend(&self) -> InodeBackend {
+        match self {
+            Self::Files { .. } => InodeBackend::Files,
+            Self::Db(_) => InodeBackend::Db,
+        }
+    }
+
+    fn storage(&self) -> &Arc<dyn Storage> {
+        match self {
+            Self::Files { ciphers, .. } => ciphers.storage(),
+            Self::Db(db) => db.ciphers.storage(),
+        }
+    }
+
+    /// The file which keeps the inode, for reports.
+    pub(crate) fn path(&self, ino: u64) -> PathBuf {
+        match self {
+            Self::Files { dir, .. } => dir.join(ino.to_string()),
+            Self::Db(db) => db.path.clone(),
+        }
+    }
+
+    pub(crate) fn exists(&self, ino: u64) -> bool {
+        match self {
+            Self::Files { .. } => self.storage().exists(&self.path(ino)),
+            Self::Db(db) => db.inner.lock().unwrap().index.contains_key(&ino),
+        }
+    }
+
+    /// All stored inodes, in no particular order.
+    pub(crate) fn inos(&self) -> io::Result<Vec<u64>> {
+        match self {
+            Self::Files { dir, ciphers, .. } => Ok(ciphers
+                .storage()
+                .read_dir(dir)?
+                .iter()
+                // skip temp files
+                .filter_map(|name| name.to_string_lossy().parse::<u64>().ok())
+                .collect()),
+            Self::Db(db) => Ok(db.inner.lock().unwrap().index.keys().copied().collect()),
+        }
+    }
+
+    /// Fails with [`FsError::InodeNotFound`] if it's not stored.
+    pub(crate) fn read(&self, ino: u64, key: &SecretVec<u8>) -> FsResult<FileAttr> {
+        match self {
+            Self::Files { ciphers, .. } => {
+                let path = self.path(ino);
+                if !ciphers.storage().exists(&path) {
+                    return Err(FsError::InodeNotFound);
+                }
+                let (file, cipher) = ciphers.open(&path).map_err(|err| {
+                    error!(err = %err, "opening file");
+                    FsError::InodeNotFound
+                })?;
+                Ok(deserialize_inode(crypto::create_read(file, cipher, key))?)
+            }
+            Self::Db(db) => db.read(ino, key),
+        }
+    }
+
+    /// Save the inode, it's synced to disk when this returns.
+    pub(crate) fn write(&self, attr: &FileAttr, key: &SecretVec<u8>) -> FsResult<()> {
+        match self {
+            Self::Files { ciphers, lock, .. } => {
+                let _guard = lock.read().unwrap();
+                let path = self.path(attr.ino);
+                let data = crypto::serialize_encrypt_into(
+                    Cursor::new(vec![]),
+                    &InodeRecord::new(attr),
+                    ciphers.for_write(&path)?,
+                    key,
+                )?;
+                ciphers.storage().write_atomic(&path, data.get_ref())?;
+                Ok(())
+            }
+            Self::Db(db) => db.write(attr, key, true),
+        }
+    }
+
+    /// Save a new inode without syncing it, call [`InodeStore::sync`] after all are written. It returns the file
+    /// which needs to be synced too, if any.
+    pub(crate) fn write_new_unsynced(
+        &self,
+        attr: &FileAttr,
+        key: &SecretVec<u8>,
+    ) -> FsResult<Option<PathBuf>> {
+        match self {
+            Self::Files { ciphers, lock, .. } => {
+                let _guard = lock.read().unwrap();
+                let path = self.path(attr.ino);
+                crypto::serialize_encrypt_into(
+                    ciphers.storage().create_new(&path)?,
+                    &InodeRecord::new(attr),
+                    ciphers.for_write(&path)?,
+                    key,
+                )?;
+                Ok(Some(path))
+            }
+            Self::Db(db) => {
+                db.write(attr, key, false)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Sync to disk what [`InodeStore::write_new_unsynced`] wrote.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        match self {
+            Self::Files { .. } => Ok(()),
+            Self::Db(db) => db.inner.lock().unwrap().file.sync_data(),
+        }
+    }
+
+    pub(crate) fn remove(&self, ino: u64) -> io::Result<()> {
+        match self {
+            Self::Files { lock, .. } => {
+                let _guard = lock.read().unwrap();
+                self.storage().remove_file(&self.path(ino))
+            }
+            Self::Db(db) => db.remove(ino),
+        }
+    }
+
+    /// Like [`InodeStore::remove`], but the file of the inode is overwritten with zeros before, see
+    /// [`Storage::shred`]. The db keeps the old records until it's compacted, so it's not supported there.
+    pub(crate) fn shred(&self, ino: u64) -> io::Result<()> {
+        match self {
+            Self::Files { lock, .. } => {
+                let _guard = lock.read().unwrap();
+                self.storage().shred(&self.path(ino))
+            }
+            Self::Db(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot shred inodes in the db",
+            )),
+        }
+    }
+
+    /// Copy the inode as it's stored to `dst`, like for the [`Journal`](super::wal::Journal). It's synced when
+    /// this returns.
+    pub(crate) fn save_copy(&self, ino: u64, dst: &Path) -> io::Result<()> {
+        let data = match self {
+            Self::Files { .. } => self.storage().read(&self.path(ino))?,
+            Self::Db(db) => db.payload(ino)?,
+        };
+        let mut file = self.storage().create(dst)?;
+        file.write_all(&data)?;
+        file.sync_all()
+    }
+
+    /// Copy all the inodes to the inodes dir of `root`, like for a snapshot. It's atomic, changes made meanwhile
+    /// are either all included or none.
+    pub(crate) fn copy_to(&self, root: &Path) -> io::Result<()> {
+        let dst = root.join(INODES_DIR);
+        match self {
+            Self::Files { dir, lock, .. } => {
+                let _guard = lock.write().unwrap();
+                let storage = self.storage();
+                storage.create_dir_all(&dst)?;
+                for name in storage.read_dir(dir)? {
+                    storage.copy(&dir.join(&name), &dst.join(name))?;
+                }
+                Ok(())
+            }
+            Self::Db(db) => {
+                // appends and compaction hold it
+                let _guard = db.inner.lock().unwrap();
+                let storage = self.storage();
+                storage.create_dir_all(&dst)?;
+                storage.copy(&db.path, &dst.join(INODE_DB_FILENAME))
+            }
+        }
+    }
+
+    /// Bring back the inode from a copy made with [`InodeStore::save_copy`], `src` is consumed.
+    pub(crate) fn restore_copy(&self, ino: u64, src: &Path) -> io::Result<()> {
+        match self {
+            Self::Files { lock, .. } => {
+                let _guard = lock.read().unwrap();
+                self.storage()
+                    .write_atomic(&self.path(ino), &self.storage().read(src)?)?;
+                self.storage().remove_file(src)
+            }
+            Self::Db(db) => {
+                db.append(ino, &self.storage().read(src)?, true)?;
+                self.storage().remove_file(src)
+            }
+        }
+    }
+}
+
+struct Inner {
+    file: Box<dyn StorageFile>,
+    len: u64,
+    // offset of the last record of each inode, and the length of its payload
+    index: HashMap<u64, (u64, u32)>,
+    // length of the records which are not the last one of their inode, and of the removed ones
+    dead_len: u64,
+}
+
+/// All inodes in one file, see the [module docs](self).
+pub(crate) struct InodeDb {
+    path: PathBuf,
+    ciphers: Arc<CipherTags>,
+    read_only: bool,
+    inner: Mutex<Inner>,
+}
+
+impl InodeDb {
+    fn open(dir: &Path, ciphers: Arc<CipherTags>, read_only: bool) -> FsResult<Self> {
+        let storage = ciphers.storage().clone();
+        let path = dir.join(INODE_DB_FILENAME);
+        let compact_path = dir.join(COMPACT_FILENAME);
+        if !read_only && storage.exists(&compact_path) {
+            // we crashed while compacting, the db is still whole
+            storage.remove_file(&compact_path)?;
+        }
+        let mut file = if read_only {
+            storage.open(&path)?
+        } else {
+            storage.open_append(&path, true)?
+        };
+        let file_len = file.len()?;
+
+        let mut index = HashMap::new();
+        let mut dead_len = 0;
+        let mut len = 0;
+        let mut reader = BufReader::new(&mut file);
+        let mut payload = vec![];
+        while len + HEADER_LEN <= file_len {
+            let mut header = [0; HEADER_LEN as usize];
+            reader.read_exact(&mut header)?;
+            let (ino, payload_len, checksum) = parse_header(&header);
+            let record_len = HEADER_LEN + u64::from(payload_len);
+            if u64::from(payload_len) > bincode_util::METADATA_LIMIT || len + record_len > file_len
+            {
+                break;
+            }
+            payload.resize(payload_len as usize, 0);
+            reader.read_exact(&mut payload)?;
+            if checksum != record_checksum(ino, payload_len, &payload) {
+                break;
+            }
+            let replaced = if payload.is_empty() {
+                dead_len += record_len;
+                index.remove(&ino)
+            } else {
+                index.insert(ino, (len, payload_len))
+            };
+            if let Some((_, replaced_len)) = replaced {
+                dead_len += HEADER_LEN + u64::from(replaced_len);
+            }
+            len += record_len;
+        }
+        drop(reader);
+        if len < file_len {
+            warn!(
+                torn = file_len - len,
+                "inode db has a torn write from a crash, it's dropped"
+            );
+            if !read_only {
+                file.set_len(len)?;
+                file.sync_all()?;
+            }
+        }
+
+        let db = Self {
+            path,
+            ciphers,
+            read_only,
+            inner: Mutex::new(Inner {
+                file,
+                len,
+                index,
+                dead_len,
+            }),
+        };
+        if !read_only {
+            db.compact_if_needed(&mut db.inner.lock().unwrap())?;
+        }
+        Ok(db)
+    }
+
+    fn read(&self, ino: u64, key: &SecretVec<u8>) -> FsResult<FileAttr> {
+        let payload = self.payload(ino).map_err(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                FsError::InodeNotFound
+            } else {
+                err.into()
+            }
+        })?;
+        let attr = deserialize_inode(crypto::create_read(
+            Cursor::new(payload),
+            self.ciphers.cipher_for(&self.path),
+            key,
+        ))?;
+        if attr.ino != ino {
+            // a payload moved from another record
+            return Err(FsError::Other("inode record doesn't match the inode"));
+        }
+        Ok(attr)
+    }
+
+    fn write(&self, attr: &FileAttr, key: &SecretVec<u8>, sync: bool) -> FsResult<()> {
+        let cipher = self.ciphers.for_write(&self.path)?;
+        let payload = crypto::serialize_encrypt_into(
+            Cursor::new(vec![]),
+            &InodeRecord::new(attr),
+            cipher,
+            key,
+        )?;
+        self.append(attr.ino, payload.get_ref(), sync)?;
+        Ok(())
+    }
+
+    fn remove(&self, ino: u64) -> io::Result<()> {
+        if !self.inner.lock().unwrap().index.contains_key(&ino) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "inode not found"));
+        }
+        self.append(ino, &[], true)
+    }
+
+    /// The encrypted inode, fails with [`io::ErrorKind::NotFound`] if it's not stored.
+    fn payload(&self, ino: u64) -> io::Result<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let (offset, len) = inner
+            .index
+            .get(&ino)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "inode not found"))?;
+        let mut payload = vec![0; len as usize];
+        inner.file.seek(SeekFrom::Start(offset + HEADER_LEN))?;
+        inner.file.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Append a record, an empty `payload` removes the inode.
+    fn append(&self, ino: u64, payload: &[u8], sync: bool) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "inode db is opened read-only",
+            ));
+        }
+        let payload_len = u32::try_from(payload.len())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&ino.to_le_bytes());
+        record.extend_from_slice(&payload_len.to_le_bytes());
+        record.extend_from_slice(&record_checksum(ino, payload_len, payload));
+        record.extend_from_slice(payload);
+
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let offset = inner.len;
+        let res = inner.file.write_all(&record).and_then(|()| {
+            if sync {
+                inner.file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(err) = res {
+            // don't leave a partial record before the next ones
+            if let Err(err) = inner.file.set_len(offset) {
+                error!(err = %err, "cannot remove partial record from inode db");
+            }
+            return Err(err);
+        }
+        inner.len += record.len() as u64;
+        let replaced = if payload.is_empty() {
+            inner.dead_len += record.len() as u64;
+            inner.index.remove(&ino)
+        } else {
+            inner.index.insert(ino, (offset, payload_len))
+        };
+        if let Some((_, replaced_len)) = replaced {
+            inner.dead_len += HEADER_LEN + u64::from(replaced_len);
+        }
+        if let Err(err) = self.compact_if_needed(inner) {
+            // the record is saved, it's retried on the next append
+            warn!(err = %err, "cannot compact inode db");
+        }
+        Ok(())
+    }
+
+    /// Copy the live records to a new file when old ones take more space than them.
+    fn compact_if_needed(&self, inner: &mut Inner) -> io::Result<()> {
+        if inner.dead_len < COMPACT_MIN_DEAD_LEN || inner.dead_len < inner.len - inner.dead_len {
+            return Ok(());
+        }
+        let storage = self.ciphers.storage();
+        let compact_path = self.path.with_file_name(COMPACT_FILENAME);
+        let mut records = inner
+            .index
+            .iter()
+            .map(|(ino, (offset, len))| (*offset, *ino, *len))
+            .collect::<Vec<_>>();
+        records.sort_unstable();
+        let mut writer = BufWriter::new(storage.create(&compact_path)?);
+        let mut index = HashMap::with_capacity(records.len());
+        let mut len = 0;
+        let mut record = vec![];
+        for (offset, ino, payload_len) in records {
+            record.resize((HEADER_LEN + u64::from(payload_len)) as usize, 0);
+            inner.file.seek(SeekFrom::Start(offset))?;
+            inner.file.read_exact(&mut record)?;
+            writer.write_all(&record)?;
+            index.insert(ino, (len, payload_len));
+            len += record.len() as u64;
+        }
+        let file = writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        storage.rename(&compact_path, &self.path)?;
+        storage.sync_dir(self.path.parent().unwrap())?;
+        inner.file = storage.open_append(&self.path, false)?;
+        inner.len = len;
+        inner.index = index;
+        inner.dead_len = 0;
+        Ok(())
+    }
+}
+
+fn parse_header(header: &[u8; HEADER_LEN as usize]) -> (u64, u32, [u8; CHECKSUM_LEN]) {
+    let (ino, rest) = header.split_at(8);
+    let (len, checksum) = rest.split_at(4);
+    (
+        u64::from_le_bytes(ino.try_into().unwrap()),
+        u32::from_le_bytes(len.try_into().unwrap()),
+        checksum.try_into().unwrap(),
+    )
+}
+
+/// Catches torn writes, the payload is authenticated by the cipher anyway.
+fn record_checksum(ino: u64, payload_len: u32, payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&ino.to_le_bytes());
+    hasher.update(&payload_len.to_le_bytes());
+    hasher.update(payload);
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&hasher.finalize().as_bytes()[..CHECKSUM_LEN]);
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use shush_rs::SecretVec;
+
+    use super::{FileAttrV0, InodeStore, COMPACT_MIN_DEAD_LEN, INODE_DB_FILENAME, INODE_V0_LEN};
+    use crate::crypto;
+    use crate::crypto::Cipher;
+    use crate::encryptedfs::cipher_tags::CipherTags;
+    use crate::encryptedfs::storage::{MemStorage, Storage};
+    use crate::encryptedfs::{FileAttr, FileType, FsError, InodeBackend, INODES_DIR, SECURITY_DIR};
+    use crate::test_common::create_attr;
+
+    fn attr(ino: u64, size: u64) -> FileAttr {
+        let mut attr: FileAttr = create_attr(FileType::RegularFile).into();
+        attr.ino = ino;
+        attr.size = size;
+        attr
+    }
+
+    #[test]
+    fn test_read_inode_without_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data_dir = tmp.path();
+        fs::create_dir_all(data_dir.join(SECURITY_DIR)).unwrap();
+        fs::create_dir_all(data_dir.join(INODES_DIR)).unwrap();
+        let ciphers = Arc::new(CipherTags::load(data_dir, Cipher::ChaCha20Poly1305).unwrap());
+        let key = SecretVec::new(Box::new(vec![42_u8; 32]));
+        let store =
+            InodeStore::open(data_dir, Some(InodeBackend::Files), ciphers.clone(), false).unwrap();
+
+        // like it was saved before the inode had a version
+        let expected = attr(2, 42);
+        let old = FileAttrV0::from(expected);
+        assert_eq!(bincode::serialize(&old).unwrap().len(), INODE_V0_LEN);
+        let path = data_dir.join(INODES_DIR).join("2");
+        crypto::serialize_encrypt_into(
+            fs::File::create(&path).unwrap(),
+            &old,
+            ciphers.for_write(&path).unwrap(),
+            &key,
+        )
+        .unwrap();
+
+        let read = store.read(2, &key).unwrap();
+        assert_eq!(read.size, 42);
+        assert_eq!(read.mtime, expected.mtime);
+        assert_eq!(read.flags, expected.flags);
+        assert!(read.content_mac.is_none());
+        assert!(read.file_key.is_none());
+        assert!(read.holes_mac.is_none());
+
+        // it's saved with the version after it's changed
+        let mut changed = read;
+        changed.content_mac = Some([1; 32]);
+        store.write(&changed, &key).unwrap();
+        assert_eq!(store.read(2, &key).unwrap().content_mac, Some([1; 32]));
+    }
+
+    #[test]
+    fn test_inode_db() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data_dir = tmp.path();
+        fs::create_dir_all(data_dir.join(SECURITY_DIR)).unwrap();
+        fs::create_dir_all(data_dir.join(INODES_DIR)).unwrap();
+        let ciphers = Arc::new(CipherTags::load(data_dir, Cipher::ChaCha20Poly1305).unwrap());
+        let key = SecretVec::new(Box::new(vec![42_u8; 32]));
+        let open =
+            || InodeStore::open(data_dir, Some(InodeBackend::Db), ciphers.clone(), false).unwrap();
+
+        let store = open();
+        assert_eq!(store.backend(), InodeBackend::Db);
+        store.write(&attr(2, 1), &key).unwrap();
+        store.write(&attr(3, 1), &key).unwrap();
+        store.write(&attr(2, 42), &key).unwrap();
+        store.remove(3).unwrap();
+        assert!(store.exists(2));
+        assert!(!store.exists(3));
+        assert_eq!(store.read(2, &key).unwrap().size, 42);
+        assert!(matches!(store.read(3, &key), Err(FsError::InodeNotFound)));
+        drop(store);
+
+        // the index is rebuilt from the file
+        let store = open();
+        assert_eq!(store.inos().unwrap(), vec![2]);
+        assert_eq!(store.read(2, &key).unwrap().size, 42);
+        // the backend is detected
+        assert!(matches!(
+            InodeStore::open(data_dir, Some(InodeBackend::Files), ciphers.clone(), false),
+            Err(FsError::InvalidInput(_))
+        ));
+        assert_eq!(
+            InodeStore::open(data_dir, None, ciphers.clone(), false)
+                .unwrap()
+                .backend(),
+            InodeBackend::Db
+        );
+
+        // a torn record from a crash is dropped, the previous one is kept
+        store.write(&attr(2, 37), &key).unwrap();
+        drop(store);
+        let db_path = data_dir.join(INODES_DIR).join(INODE_DB_FILENAME);
+        let len = fs::metadata(&db_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&db_path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let store = open();
+        assert_eq!(store.read(2, &key).unwrap().size, 42);
+
+        // compacted when it's mostly old records
+        let InodeStore::Db(db) = &store else {
+            unreachable!()
+        };
+        for size in 0..20_000 {
+            db.write(&attr(2, size), &key, false).unwrap();
+        }
+        assert!(fs::metadata(&db_path).unwrap().len() < COMPACT_MIN_DEAD_LEN * 2);
+        assert_eq!(store.read(2, &key).unwrap().size, 19_999);
+        drop(store);
+        assert_eq!(open().read(2, &key).unwrap().size, 19_999);
+    }
+
+    #[test]
+    fn test_inodes_in_mem_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let key = SecretVec::new(Box::new(vec![42_u8; 32]));
+        for (data_dir, backend) in [
+            (Path::new("/files"), InodeBackend::Files),
+            (Path::new("/db"), InodeBackend::Db),
+        ] {
+            storage
+                .create_dir_all(&data_dir.join(SECURITY_DIR))
+                .unwrap();
+            storage.create_dir_all(&data_dir.join(INODES_DIR)).unwrap();
+            let ciphers = Arc::new(
+                CipherTags::load_with(storage.clone(), data_dir, Cipher::ChaCha20Poly1305).unwrap(),
+            );
+            let store = InodeStore::open(data_dir, Some(backend), ciphers.clone(), false).unwrap();
+            store.write(&attr(2, 1), &key).unwrap();
+            store.write_new_unsynced(&attr(3, 1), &key).unwrap();
+            store.sync().unwrap();
+            store.write(&attr(2, 42), &key).unwrap();
+            store.remove(3).unwrap();
+            drop(store);
+
+            let store = InodeStore::open(data_dir, None, ciphers.clone(), false).unwrap();
+            assert_eq!(store.backend(), backend);
+            assert_eq!(store.inos().unwrap(), vec![2]);
+            assert_eq!(store.read(2, &key).unwrap().size, 42);
+            let snapshot = data_dir.join("snapshot");
+            store.copy_to(&snapshot).unwrap();
+            assert_eq!(
+                InodeStore::read_from(&snapshot, 2, &ciphers, &key)
+                    .unwrap()
+                    .size,
+                42
+            );
+            assert!(!data_dir.exists());
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Limit of the total size of the files in a volume, see
+//! [`FsConfig::quota`](crate::encryptedfs::FsConfig::quota).
+//!
+//! The space is taken before a file grows and given back when it shrinks or is removed. Taking it is a single
+//! atomic update, so concurrent writes can't go past the limit together.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::encryptedfs::{FsError, FsResult};
+
+// there is no limit
+const UNLIMITED: u64 = u64::MAX;
+
+pub(crate) struct Quota {
+    limit: AtomicU64,
+    used: AtomicU64,
+}
+
+/// Space taken from the [`Quota`] for a change not done yet, it's given back on drop if the change fails.
+pub(crate) struct Reservation<'a> {
+    quota: &'a Quota,
+    len: u64,
+}
+
+impl Quota {
+    pub(crate) const fn new() -> Self {
+        Self {
+            limit: AtomicU64::new(UNLIMITED),
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the limit and how much of it the files already use.
+    pub(crate) fn set(&self, limit: Option<u64>, used: u64) {
+        self.used.store(used, Ordering::SeqCst);
+        self.limit
+            .store(limit.unwrap_or(UNLIMITED), Ordering::SeqCst);
+    }
+
+    /// The limit and how much of it is used, `None` without a limit.
+    pub(crate) fn usage(&self) -> Option<(u64, u64)> {
+        let limit = self.limit.load(Ordering::SeqCst);
+        (limit != UNLIMITED).then(|| (limit, self.used.load(Ordering::SeqCst)))
+    }
+
+    /// Take `len` bytes, fails with [`FsError::QuotaExceeded`] if the files would use more than the limit.
+    pub(crate) fn reserve(&self, len: u64) -> FsResult<Reservation<'_>> {
+        let limit = self.limit.load(Ordering::SeqCst);
+        if limit == UNLIMITED || len == 0 {
+            return Ok(Reservation {
+                quota: self,
+                len: 0,
+            });
+        }
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(len).filter(|used| *used <= limit)
+            })
+            .map_err(|_| FsError::QuotaExceeded)?;
+        Ok(Reservation { quota: self, len })
+    }
+
+    /// Give back `len` bytes, after a file shrinks or is removed.
+    pub(crate) fn release(&self, len: u64) {
+        if len == 0 || self.limit.load(Ordering::SeqCst) == UNLIMITED {
+            return;
+        }
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(len))
+            });
+    }
+}
+
+impl Reservation<'_> {
+    /// The change is done and the file grew by `len`, what's left of the reservation is given back.
+    pub(crate) fn keep(mut self, len: u64) {
+        self.quota.release(self.len.saturating_sub(len));
+        self.len = 0;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.quota.release(self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let quota = Quota::new();
+        assert!(quota.usage().is_none());
+        // without a limit nothing is counted
+        quota.reserve(u64::MAX).unwrap().keep(u64::MAX);
+        assert!(quota.usage().is_none());
+
+        quota.set(Some(100), 10);
+        assert_eq!(Some((100, 10)), quota.usage());
+        let reservation = quota.reserve(60).unwrap();
+        assert!(matches!(quota.reserve(31), Err(FsError::QuotaExceeded)));
+        drop(reservation);
+        assert_eq!(Some((100, 10)), quota.usage());
+
+        quota.reserve(60).unwrap().keep(20);
+        assert_eq!(Some((100, 30)), quota.usage());
+        quota.reserve(70).unwrap().keep(70);
+        assert_eq!(Some((100, 100)), quota.usage());
+        assert!(matches!(quota.reserve(1), Err(FsError::QuotaExceeded)));
+
+        quota.release(40);
+        assert_eq!(Some((100, 60)), quota.usage());
+        quota.release(1000);
+        assert_eq!(Some((100, 0)), quota.usage());
+    }
+}
@@ -0,0 +1,836 @@
+//! Where the files of a data dir are kept, so they can live somewhere else than the local filesystem, like in
+//! memory for tests or for scratch space which never reaches a disk.
+//!
+//! [`FileStorage`] keeps them on the local filesystem, that's the default. [`MemStorage`] keeps them in memory.
+//! Paths are the same for both, under the data dir. All the files of the data dir go through it, the key, the
+//! inodes, the contents, the directory entries, the journals and the counters, see
+//! [`FsConfig::storage`](super::FsConfig::storage).
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use atomic_write_file::AtomicWriteFile;
+
+use crate::fs_util;
+
+/// A file opened from a [`Storage`], like [`File`].
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::module_name_repetitions)]
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    fn len(&self) -> io::Result<u64>;
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    fn sync_data(&self) -> io::Result<()>;
+
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Reserve space for the first `len` bytes without changing the length, see [`fs_util::reserve`].
+    /// It's best effort, by default nothing is reserved.
+    fn reserve(&self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Ok(())
+    }
+}
+
+/// A file from [`Storage::create_atomic`], what's written replaces the file only on [`AtomicStorageFile::commit`].
+#[allow(clippy::module_name_repetitions)]
+pub trait AtomicStorageFile: Read + Write + Seek + Send + Sync {
+    /// Replace the file with what was written, readers see either the old or the new content.
+    /// It's durable when this returns. If it's dropped without a commit the file is left as it was.
+    #[allow(clippy::missing_errors_doc)]
+    fn commit(self: Box<Self>) -> io::Result<()>;
+}
+
+/// What [`Storage::metadata`] tells about a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub len: u64,
+    /// Blocks of 512 bytes it takes, `None` where it's not known, see [`fs_util::allocated_blocks`].
+    pub blocks: Option<u64>,
+    /// Number of hard links to it.
+    pub hard_links: u64,
+    /// The same for all the hard links to the file, `None` where it's not known.
+    pub id: Option<(u64, u64)>,
+}
+
+impl Metadata {
+    /// If the file has holes, so it takes less space than its length, see [`fs_util::is_sparse`].
+    #[must_use]
+    pub fn is_sparse(&self) -> bool {
+        self.blocks.is_some_and(|blocks| blocks * 512 < self.len)
+    }
+
+    /// If both are hard links to the same file, always `false` where it's not known.
+    #[must_use]
+    pub fn is_same_file(&self, other: &Self) -> bool {
+        self.id.is_some() && self.id == other.id
+    }
+}
+
+/// Files and directories of a data dir, see the [module docs](self).
+///
+/// Like on the local filesystem, what's written is durable only after it's synced, and [`Storage::rename`] is
+/// durable only after the directory is synced with [`Storage::sync_dir`].
+#[allow(clippy::missing_errors_doc)]
+pub trait Storage: Send + Sync {
+    /// Open an existing file for read.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open an existing file for read and write, it's not truncated.
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open a file for read and append, it's created if it doesn't exist and `create` is set.
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Create a file for write, truncated if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Create a file for write, fails with [`io::ErrorKind::AlreadyExists`] if it exists.
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Write a new content for the file, it starts empty and replaces the file on
+    /// [`AtomicStorageFile::commit`], see [`Storage::write_atomic`].
+    fn create_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicStorageFile>>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Replace the content of the file with `data` at once, readers see either the old or the new content.
+    /// It's durable when this returns.
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = self.create_atomic(path)?;
+        file.write_all(data)?;
+        file.commit()
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Rename a file or a directory, a file at `to` is replaced.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Make `to` another name of the file at `from`, both see the same content.
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Overwrite the file with zeros, then remove it, see [`fs_util::shred`].
+    fn shred(&self, path: &Path) -> io::Result<()>;
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// If there is a file or a directory at `path`.
+    fn exists(&self, path: &Path) -> bool;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Names of the files and directories in `dir`.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<OsString>>;
+
+    /// Create a directory, its parent needs to exist.
+    fn create_dir(&self, dir: &Path) -> io::Result<()>;
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Remove the directory with all it has.
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Make the changes of the entries of `dir` durable, like new, renamed or removed files.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Make the files and directories in `dir`, and it, durable, see [`fs_util::sync_dir_all`].
+    fn sync_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Make `paths`, which are all inside `dir`, durable with as few syncs as possible, see
+    /// [`fs_util::sync_paths`].
+    fn sync_paths(&self, dir: &Path, paths: &[PathBuf]) -> io::Result<()>;
+
+    /// If a file named `name` can be created in `dir`, some filesystems don't allow some characters in names,
+    /// see [`fs_util::can_create`].
+    fn can_create(&self, dir: &Path, name: &str) -> io::Result<bool>;
+}
+
+/// Keeps the files on the local filesystem.
+#[allow(clippy::module_name_repetitions)]
+pub struct FileStorage;
+
+impl StorageFile for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        Self::set_len(self, len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Self::sync_data(self)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        Self::sync_all(self)
+    }
+
+    fn reserve(&self, len: u64) -> io::Result<()> {
+        fs_util::reserve(self, len)
+    }
+}
+
+/// A file from [`FileStorage::create_atomic`], written to a temp file next to it.
+struct AtomicFile {
+    file: AtomicWriteFile,
+    path: PathBuf,
+}
+
+impl Read for AtomicFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl AtomicStorageFile for AtomicFile {
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        let Self { file, path } = *self;
+        file.commit()?;
+        FileStorage.sync_dir(
+            path.parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+        )
+    }
+}
+
+impl Storage for FileStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(
+            OpenOptions::new().read(true).write(true).open(path)?,
+        ))
+    }
+
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(create)
+                .open(path)?,
+        ))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::create_new(path)?))
+    }
+
+    fn create_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicStorageFile>> {
+        Ok(Box::new(AtomicFile {
+            file: fs_util::open_atomic_write(path)?,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to).map(|_| ())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn shred(&self, path: &Path) -> io::Result<()> {
+        fs_util::shred(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(Metadata {
+            len: metadata.len(),
+            blocks: fs_util::allocated_blocks(&metadata),
+            hard_links: fs_util::hard_links(&metadata),
+            id: fs_util::file_id(&metadata),
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect()
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::remove_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    fn sync_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs_util::sync_dir_all(dir)
+    }
+
+    fn sync_paths(&self, dir: &Path, paths: &[PathBuf]) -> io::Result<()> {
+        fs_util::sync_paths(dir, paths)
+    }
+
+    fn can_create(&self, dir: &Path, name: &str) -> io::Result<bool> {
+        fs_util::can_create(dir, name)
+    }
+}
+
+type Content = Arc<Mutex<Vec<u8>>>;
+
+#[derive(Default)]
+struct MemInner {
+    // hard links share the content
+    files: HashMap<PathBuf, Content>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl MemInner {
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        if path.parent().is_some_and(|dir| self.dirs.contains(dir)) {
+            Ok(())
+        } else {
+            Err(not_found(path))
+        }
+    }
+
+    fn insert(&mut self, path: &Path, content: Content) -> io::Result<()> {
+        self.check_parent(path)?;
+        if self.dirs.contains(path) {
+            return Err(already_exists(path));
+        }
+        self.files.insert(path.to_path_buf(), content);
+        Ok(())
+    }
+}
+
+/// Keeps the files in memory, they are lost when it's dropped. Everything is durable right away.
+#[derive(Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct MemStorage {
+    inner: Arc<Mutex<MemInner>>,
+}
+
+/// A file opened from [`MemStorage`]. Like on Unix, it keeps the content it was opened with even if the file is
+/// replaced or removed after.
+struct MemFile {
+    content: Content,
+    pos: u64,
+    append: bool,
+}
+
+impl MemFile {
+    const fn new(content: Content, append: bool) -> Self {
+        Self {
+            content,
+            pos: 0,
+            append,
+        }
+    }
+}
+
+/// A file from [`MemStorage::create_atomic`], its content is added to the storage on commit.
+struct MemAtomicFile {
+    file: MemFile,
+    path: PathBuf,
+    inner: Arc<Mutex<MemInner>>,
+}
+
+impl MemStorage {
+    fn get(&self, path: &Path) -> io::Result<Content> {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn insert(&self, path: &Path, data: Vec<u8>, truncate: bool) -> io::Result<Content> {
+        let mut inner = self.inner.lock().unwrap();
+        if let (false, Some(content)) = (truncate, inner.files.get(path)) {
+            return Ok(content.clone());
+        }
+        let content = Arc::new(Mutex::new(data));
+        inner.insert(path, content.clone())?;
+        Ok(content)
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(MemFile::new(self.get(path)?, false)))
+    }
+
+    fn open_write(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.open(path)
+    }
+
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        if create {
+            self.insert(path, vec![], false)?;
+        }
+        Ok(Box::new(MemFile::new(self.get(path)?, true)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(MemFile::new(
+            self.insert(path, vec![], true)?,
+            false,
+        )))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        if self.exists(path) {
+            return Err(already_exists(path));
+        }
+        self.create(path)
+    }
+
+    fn create_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicStorageFile>> {
+        self.inner.lock().unwrap().check_parent(path)?;
+        Ok(Box::new(MemAtomicFile {
+            file: MemFile::new(Arc::default(), false),
+            path: path.to_path_buf(),
+            inner: self.inner.clone(),
+        }))
+    }
+
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        // a new content, so the opened files keep the old one
+        self.insert(path, data.to_vec(), true).map(|_| ())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let data = self.read(from)?;
+        self.insert(to, data, true).map(|_| ())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_parent(to)?;
+        if inner.dirs.contains(from) {
+            if inner.files.contains_key(to) || inner.dirs.contains(to) {
+                return Err(already_exists(to));
+            }
+            // move all it has
+            let moved = |path: &Path| to.join(path.strip_prefix(from).unwrap());
+            let dirs: Vec<_> = inner
+                .dirs
+                .iter()
+                .filter(|path| path.starts_with(from))
+                .cloned()
+                .collect();
+            for dir in dirs {
+                inner.dirs.remove(&dir);
+                inner.dirs.insert(moved(&dir));
+            }
+            let files: Vec<_> = inner
+                .files
+                .keys()
+                .filter(|path| path.starts_with(from))
+                .cloned()
+                .collect();
+            for path in files {
+                let content = inner.files.remove(&path).unwrap();
+                inner.files.insert(moved(&path), content);
+            }
+            return Ok(());
+        }
+        if inner.dirs.contains(to) {
+            return Err(already_exists(to));
+        }
+        let content = inner.files.remove(from).ok_or_else(|| not_found(from))?;
+        inner.insert(to, content)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.files.contains_key(to) {
+            return Err(already_exists(to));
+        }
+        let content = inner
+            .files
+            .get(from)
+            .cloned()
+            .ok_or_else(|| not_found(from))?;
+        inner.insert(to, content)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn shred(&self, path: &Path) -> io::Result<()> {
+        let content = self
+            .inner
+            .lock()
+            .unwrap()
+            .files
+            .remove(path)
+            .ok_or_else(|| not_found(path))?;
+        content.lock().unwrap().fill(0);
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let inner = self.inner.lock().unwrap();
+        let content = inner.files.get(path).ok_or_else(|| not_found(path))?;
+        let hard_links = inner
+            .files
+            .values()
+            .filter(|other| Arc::ptr_eq(other, content))
+            .count();
+        let len = content.lock().unwrap().len() as u64;
+        Ok(Metadata {
+            len,
+            blocks: None,
+            hard_links: hard_links as u64,
+            // the address of the content is the same for all its hard links
+            id: Some((0, Arc::as_ptr(content) as usize as u64)),
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.files.contains_key(path) || inner.dirs.contains(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.inner.lock().unwrap().files.contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.lock().unwrap().dirs.contains(path)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<OsString>> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(inner
+            .files
+            .keys()
+            .chain(inner.dirs.iter())
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name().map(ToOwned::to_owned))
+            .collect())
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_parent(dir)?;
+        if inner.files.contains_key(dir) || !inner.dirs.insert(dir.to_path_buf()) {
+            return Err(already_exists(dir));
+        }
+        Ok(())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        for dir in dir.ancestors() {
+            inner.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        inner.dirs.retain(|path| !path.starts_with(dir));
+        inner.files.retain(|path, _| !path.starts_with(dir));
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        if !self.is_dir(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(())
+    }
+
+    fn sync_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.sync_dir(dir)
+    }
+
+    fn sync_paths(&self, _dir: &Path, _paths: &[PathBuf]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn can_create(&self, _dir: &Path, _name: &str) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let content = self.content.lock().unwrap();
+        let start = usize::try_from(self.pos).map_or(content.len(), |pos| pos.min(content.len()));
+        let len = buf.len().min(content.len() - start);
+        buf[..len].copy_from_slice(&content[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut content = self.content.lock().unwrap();
+        if self.append {
+            self.pos = content.len() as u64;
+        }
+        let start = usize::try_from(self.pos)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        if content.len() < start + buf.len() {
+            // like on disk, a gap before it is filled with zeros
+            content.resize(start + buf.len(), 0);
+        }
+        content[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.len()?, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageFile for MemFile {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.content.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let len =
+            usize::try_from(len).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.content.lock().unwrap().resize(len, 0);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MemAtomicFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for MemAtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemAtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl AtomicStorageFile for MemAtomicFile {
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(&self.path, self.file.content)
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem_storage() {
+        let storage = MemStorage::default();
+        let dir = Path::new("/data/inodes");
+        let path = dir.join("1");
+        assert!(storage.create(&path).is_err());
+        storage.create_dir_all(dir).unwrap();
+        assert!(storage.exists(Path::new("/data")));
+
+        let mut file = storage.create_new(&path).unwrap();
+        file.write_all(b"test-42").unwrap();
+        assert!(storage.create_new(&path).is_err());
+        assert_eq!(storage.read(&path).unwrap(), b"test-42");
+        assert_eq!(storage.read_dir(dir).unwrap(), vec![OsString::from("1")]);
+
+        // appends go at the end, wherever we seeked
+        let mut file = storage.open_append(&path, false).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b"-more").unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "-42-more");
+        file.set_len(4).unwrap();
+        assert_eq!(storage.read(&path).unwrap(), b"test");
+
+        // opened files keep the content they were opened with
+        let mut old = storage.open(&path).unwrap();
+        storage.write_atomic(&path, b"new").unwrap();
+        let mut data = String::new();
+        old.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "test");
+        assert_eq!(storage.read(&path).unwrap(), b"new");
+
+        let other = dir.join("2");
+        storage.copy(&path, &other).unwrap();
+        storage.remove_file(&path).unwrap();
+        assert!(!storage.exists(&path));
+        storage.rename(&other, &path).unwrap();
+        assert_eq!(storage.read(&path).unwrap(), b"new");
+        storage.shred(&path).unwrap();
+        assert!(storage.read_dir(dir).unwrap().is_empty());
+        assert_eq!(
+            storage.open(&path).err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // hard links share the content until one is replaced
+        storage.write_atomic(&path, b"shared").unwrap();
+        storage.hard_link(&path, &other).unwrap();
+        let metadata = storage.metadata(&path).unwrap();
+        assert_eq!(metadata.hard_links, 2);
+        assert!(metadata.is_same_file(&storage.metadata(&other).unwrap()));
+        storage.open_write(&other).unwrap().write_all(b"S").unwrap();
+        assert_eq!(storage.read(&path).unwrap(), b"Shared");
+        let mut file = storage.create_atomic(&other).unwrap();
+        file.write_all(b"new").unwrap();
+        assert_eq!(storage.read(&other).unwrap(), b"Shared");
+        file.commit().unwrap();
+        assert_eq!(storage.read(&other).unwrap(), b"new");
+        assert_eq!(storage.metadata(&path).unwrap().hard_links, 1);
+
+        // directories are moved and removed with all they have
+        let moved = Path::new("/data/moved");
+        storage.rename(dir, moved).unwrap();
+        assert!(!storage.is_dir(dir));
+        assert!(storage.is_file(&moved.join("1")));
+        assert!(storage.create_dir(moved).is_err());
+        storage.remove_dir_all(moved).unwrap();
+        assert!(!storage.exists(&moved.join("2")));
+        assert!(storage.read_dir(Path::new("/data")).unwrap().is_empty());
+    }
+}
@@ -1,25 +1,57 @@
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
 use std::str::FromStr;
 use std::string::ToString;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use shush_rs::{ExposeSecret, SecretString};
+use futures_util::{StreamExt, TryStreamExt};
+use rand::Rng;
+use rand_chacha::rand_core::RngCore;
+use shush_rs::{ExposeSecret, SecretString, SecretVec};
+use tokio::task::JoinSet;
 use tracing_test::traced_test;
 
-use crate::crypto::Cipher;
+use crate::block_cache::BlockCache;
+use crate::crypto::nonce::NonceStrategy;
+use crate::crypto::transform::{XorTransform, FRAME_HEADER_LEN, XOR_TRANSFORM_ID};
+use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+use crate::crypto::{Cipher, KdfParams};
+use crate::encryptedfs::check_names_supported;
+use crate::encryptedfs::cipher_tags::CipherTags;
+use crate::encryptedfs::inode_store::{FileAttrV0, INODE_DB_FILENAME};
+use crate::encryptedfs::storage::{FileStorage, MemStorage, Storage};
 use crate::encryptedfs::write_all_bytes_to_fs;
+use crate::encryptedfs::CIPHER_FILENAME;
+use crate::encryptedfs::CONTENTS_REFS_DIR;
+use crate::encryptedfs::CONTENT_REF_KEY_SUFFIX;
+use crate::encryptedfs::FORMAT_FILENAME;
 use crate::encryptedfs::INODES_DIR;
+use crate::encryptedfs::INO_COUNTER_FILENAME;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
+use crate::encryptedfs::NONCE_COUNTER_FILENAME;
 use crate::encryptedfs::SECURITY_DIR;
-use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR};
+use crate::encryptedfs::WAL_DIR;
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsError, FsResult, SetFileAttr,
-    CONTENTS_DIR, ROOT_INODE,
+    read_kdf_params, read_or_create_key, AtimePolicy, XattrMode, DEFAULT_MIN_PASSWORD_LEN,
+    KDF_PARAMS_FILENAME, XATTR_DIR, XATTR_VALUE_MAX_LEN,
 };
+use crate::encryptedfs::{
+    CacheConfig, CacheKind, DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsConfig,
+    FsError, FsObserver, FsResult, SetFileAttr, VolumeFormat, CONTENTS_DIR, CONTENT_FORMAT_VERSION,
+    CONTENT_TRANSFORM_SUFFIX, FS_APPEND_FL, FS_IMMUTABLE_FL, ROOT_INODE,
+};
+use crate::encryptedfs::{CopyFileRangeReq, DanglingReason, FALLOC_FL_KEEP_SIZE, HASH_DIR, LS_DIR};
+use crate::expire_value::ValueProvider;
 use crate::test_common::run_test;
+use crate::test_common::run_test_with_config;
 use crate::test_common::TestSetup;
 use crate::test_common::{create_attr, get_fs, PasswordProviderImpl};
-use crate::{crypto, test_common};
+use crate::{crypto, fs_util, test_common};
 
 static ROOT_INODE_STR: &str = "1";
 
@@ -171,6 +203,54 @@ async fn test_write() {
     .await;
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_read_past_eof() {
+    run_test(
+        TestSetup {
+            key: "test_read_past_eof",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+
+            // like the kernel reading the last page of the file
+            let mut buf = vec![0xff; 4096];
+            assert_eq!(5, fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+            assert_eq!(b"test-", &buf[..5]);
+            assert!(buf[5..].iter().all(|b| *b == 0));
+
+            // only up to the end of the last page
+            buf.fill(0xff);
+            assert_eq!(2, fs.read(attr.ino, 3, &mut buf, fh).await.unwrap());
+            assert_eq!(b"t-", &buf[..2]);
+            assert!(buf[2..4093].iter().all(|b| *b == 0));
+            assert!(buf[4093..].iter().all(|b| *b == 0xff));
+
+            // not straddling the end of the file
+            buf.fill(0xff);
+            assert_eq!(0, fs.read(attr.ino, 42, &mut buf, fh).await.unwrap());
+            assert!(buf.iter().all(|b| *b == 0xff));
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -532,195 +612,172 @@ async fn test_copy_file_range() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_read_dir() {
+async fn test_copy_file_range_from_active_writer() {
     run_test(
         TestSetup {
-            key: "test_read_dir",
+            key: "test_copy_file_range_from_active_writer",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            // file and directory in root
-            let test_file = SecretString::from_str("test-file").unwrap();
-            let (_fh, file_attr) = fs
+            let test_file_1 = SecretString::from_str("test-file-1").unwrap();
+            let (fh, attr_1) = fs
                 .create(
                     ROOT_INODE,
-                    &test_file,
+                    &test_file_1,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    true,
+                    true,
                 )
                 .await
                 .unwrap();
+            // multiple blocks and a partial last one
+            #[allow(clippy::cast_possible_truncation)]
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 42).map(|i| (i % 251) as u8).collect();
+            write_all_bytes_to_fs(&fs, attr_1.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            // flushed but the writer is still the active write handle
+            fs.flush(fh).await.unwrap();
 
-            let test_dir = SecretString::from_str("test-dir").unwrap();
-            let (_fh, dir_attr) = fs
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            let (fh2, attr_2) = fs
                 .create(
                     ROOT_INODE,
-                    &test_dir,
-                    create_attr(FileType::Directory),
-                    false,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
                     false,
+                    true,
                 )
                 .await
                 .unwrap();
-            let mut entries: Vec<FsResult<DirectoryEntry>> =
-                fs.read_dir(dir_attr.ino).await.unwrap().collect();
-            entries.sort_by(|a, b| {
-                a.as_ref()
-                    .unwrap()
-                    .name
-                    .expose_secret()
-                    .cmp(&*b.as_ref().unwrap().name.expose_secret())
-            });
-            let entries: Vec<DirectoryEntry> = entries.into_iter().map(Result::unwrap).collect();
-            assert_eq!(entries.len(), 2);
+            test_common::copy_all_file_range(
+                &fs,
+                attr_1.ino,
+                0,
+                attr_2.ino,
+                0,
+                data.len(),
+                fh,
+                fh2,
+            )
+            .await;
+            fs.flush(fh2).await.unwrap();
+            fs.release(fh2).await.unwrap();
+            fs.release(fh).await.unwrap();
+
             assert_eq!(
-                vec![
-                    DirectoryEntry {
-                        ino: dir_attr.ino,
-                        name: SecretString::from_str(".").unwrap(),
-                        kind: FileType::Directory,
-                    },
-                    DirectoryEntry {
-                        ino: ROOT_INODE,
-                        name: SecretString::from_str("..").unwrap(),
-                        kind: FileType::Directory,
-                    },
-                ],
-                entries
+                fs.get_attr(attr_1.ino).await.unwrap().size,
+                data.len() as u64
+            );
+            assert_eq!(
+                fs.get_attr(attr_2.ino).await.unwrap().size,
+                data.len() as u64
             );
+            let mut buf = vec![0; data.len()];
+            let fh = fs.open(attr_2.ino, true, false).await.unwrap();
+            test_common::read_exact(&fs, attr_2.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(data, buf);
+        },
+    )
+    .await;
+}
 
-            let mut entries: Vec<FsResult<DirectoryEntry>> =
-                fs.read_dir(ROOT_INODE).await.unwrap().collect();
-            entries.sort_by(|a, b| {
-                a.as_ref()
-                    .unwrap()
-                    .name
-                    .expose_secret()
-                    .cmp(&*b.as_ref().unwrap().name.expose_secret())
-            });
-            let entries: Vec<DirectoryEntry> = entries.into_iter().map(Result::unwrap).collect();
-            let mut sample = vec![
-                DirectoryEntry {
-                    ino: ROOT_INODE,
-                    name: SecretString::from_str(".").unwrap(),
-                    kind: FileType::Directory,
-                },
-                DirectoryEntry {
-                    ino: file_attr.ino,
-                    name: test_file.clone(),
-                    kind: FileType::RegularFile,
-                },
-                DirectoryEntry {
-                    ino: dir_attr.ino,
-                    name: test_dir.clone(),
-                    kind: FileType::Directory,
-                },
-            ];
-            sample.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            assert_eq!(entries.len(), 3);
-            assert_eq!(sample, entries);
+#[tokio::test]
+#[traced_test]
+async fn test_copy_file() {
+    run_test(
+        TestSetup {
+            key: "test_copy_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
 
-            // file and directory in another directory
-            let parent = dir_attr.ino;
-            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
-            let (_fh, file_attr) = fs
-                .create(
-                    parent,
-                    &test_file_2,
-                    create_attr(FileType::RegularFile),
-                    false,
-                    false,
-                )
+            let src_name = SecretString::from_str("test-file.pdf").unwrap();
+            let mut attr = create_attr(FileType::RegularFile);
+            attr.perm = 0o640;
+            let (fh, src) = fs
+                .create(ROOT_INODE, &src_name, attr, false, true)
                 .await
                 .unwrap();
-
-            let test_file_3 = SecretString::from_str("test_file_3").unwrap();
-            let (_fh, file_attr_2) = fs
-                .create(
-                    parent,
-                    &test_file_3,
-                    create_attr(FileType::RegularFile),
-                    false,
-                    false,
-                )
+            // binary content, multiple blocks and a partial last one
+            let mut data = vec![0; BLOCK_SIZE * 5 + 42];
+            crypto::create_rng().fill_bytes(&mut data);
+            write_all_bytes_to_fs(&fs, src.ino, 0, &data, fh)
                 .await
                 .unwrap();
 
-            let test_dir_2 = SecretString::from_str("test_dir_2").unwrap();
-            let (_fh, dir_attr) = fs
-                .create(
-                    parent,
-                    &test_dir_2,
-                    create_attr(FileType::Directory),
-                    false,
-                    false,
-                )
+            // what is in the writer of the source is copied too
+            let dst_name = SecretString::from_str("test-file-copy.pdf").unwrap();
+            let dst = fs
+                .copy_file(ROOT_INODE, &src_name, &dst_name)
                 .await
                 .unwrap();
-            let mut entries: Vec<FsResult<DirectoryEntry>> =
-                fs.read_dir(dir_attr.ino).await.unwrap().collect();
-            entries.sort_by(|a, b| {
-                a.as_ref()
-                    .unwrap()
-                    .name
-                    .expose_secret()
-                    .cmp(&*b.as_ref().unwrap().name.expose_secret())
-            });
-            let entries: Vec<DirectoryEntry> = entries.into_iter().map(Result::unwrap).collect();
-            assert_eq!(entries.len(), 2);
+            fs.release(fh).await.unwrap();
+            assert_ne!(src.ino, dst.ino);
+            assert_eq!(dst.size, data.len() as u64);
+            assert_eq!(dst.perm, 0o640);
+            let src = fs.get_attr(src.ino).await.unwrap();
             assert_eq!(
-                vec![
-                    DirectoryEntry {
-                        ino: dir_attr.ino,
-                        name: SecretString::from_str(".").unwrap(),
-                        kind: FileType::Directory,
-                    },
-                    DirectoryEntry {
-                        ino: parent,
-                        name: SecretString::from_str("..").unwrap(),
-                        kind: FileType::Directory,
-                    },
-                ],
-                entries
+                (dst.uid, dst.gid, dst.flags, dst.atime, dst.mtime),
+                (src.uid, src.gid, src.flags, src.atime, src.mtime)
+            );
+            assert_eq!(
+                fs.find_by_name(ROOT_INODE, &dst_name)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                dst.ino
             );
 
-            let iter = fs.read_dir(parent).await.unwrap();
-            let mut entries: Vec<DirectoryEntry> = iter.map(Result::unwrap).collect();
-            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            let mut sample = vec![
-                DirectoryEntry {
-                    ino: parent,
-                    name: SecretString::from_str(".").unwrap(),
-                    kind: FileType::Directory,
-                },
-                DirectoryEntry {
-                    ino: ROOT_INODE,
-                    name: SecretString::from_str("..").unwrap(),
-                    kind: FileType::Directory,
-                },
-                DirectoryEntry {
-                    ino: file_attr.ino,
-                    name: test_file_2.clone(),
-                    kind: FileType::RegularFile,
-                },
-                DirectoryEntry {
-                    ino: file_attr_2.ino,
-                    name: test_file_3.clone(),
-                    kind: FileType::RegularFile,
-                },
-                DirectoryEntry {
-                    ino: dir_attr.ino,
-                    name: test_dir_2.clone(),
-                    kind: FileType::Directory,
-                },
-            ];
-            sample.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            assert_eq!(entries.len(), 5);
-            assert_eq!(sample, entries);
+            let mut buf = vec![0; data.len()];
+            let fh = fs.open(dst.ino, true, false).await.unwrap();
+            test_common::read_exact(&fs, dst.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(data, buf);
+            fs.verify_file(dst.ino).await.unwrap();
+            // the content is encrypted with new nonces
+            assert_ne!(
+                std::fs::read(fs.contents_path(src.ino)).unwrap(),
+                std::fs::read(fs.contents_path(dst.ino)).unwrap()
+            );
+
+            assert!(matches!(
+                fs.copy_file(ROOT_INODE, &src_name, &dst_name).await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.copy_file(
+                    ROOT_INODE,
+                    &SecretString::from_str("missing").unwrap(),
+                    &SecretString::from_str("missing-copy").unwrap(),
+                )
+                .await,
+                Err(FsError::NotFound(_))
+            ));
+            let dir_name = SecretString::from_str("test-dir").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &dir_name,
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                fs.copy_file(
+                    ROOT_INODE,
+                    &dir_name,
+                    &SecretString::from_str("test-dir-copy").unwrap(),
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
         },
     )
     .await;
@@ -729,10 +786,352 @@ async fn test_read_dir() {
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
-async fn test_read_dir_plus() {
-    run_test(
+async fn test_dedup() {
+    run_test_with_config(
         TestSetup {
-            key: "test_read_dir_plus",
+            key: "test_dedup",
+            read_only: false,
+        },
+        FsConfig {
+            dedup: true,
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+            let refs = |fs: &EncryptedFs| {
+                std::fs::read_dir(fs.data_dir.join(CONTENTS_REFS_DIR))
+                    .unwrap()
+                    .filter(|entry| {
+                        !entry
+                            .as_ref()
+                            .unwrap()
+                            .file_name()
+                            .to_string_lossy()
+                            .ends_with(CONTENT_REF_KEY_SUFFIX)
+                    })
+                    .count()
+            };
+            let links = |fs: &EncryptedFs, ino: u64| {
+                fs_util::hard_links(&std::fs::metadata(fs.contents_path(ino)).unwrap())
+            };
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            let create_file = |name: &'static str, data: String| {
+                let fs = fs.clone();
+                async move {
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str(name).unwrap(),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                        .await
+                        .unwrap();
+                    fs.release(fh).await.unwrap();
+                    attr.ino
+                }
+            };
+
+            let ino1 = create_file("test-file-1", data.clone()).await;
+            assert_eq!(refs(&fs), 1);
+            assert_eq!(links(&fs, ino1), 2);
+            let ino2 = create_file("test-file-2", data.clone()).await;
+            assert_eq!(refs(&fs), 1);
+            assert_eq!(links(&fs, ino2), 3);
+            // the shared content is encrypted with the key of the first one
+            assert_eq!(
+                fs.get_attr(ino1).await.unwrap().file_key,
+                fs.get_attr(ino2).await.unwrap().file_key
+            );
+            let ino3 = create_file("test-file-3", "42-test".repeat(BLOCK_SIZE / 3)).await;
+            assert_eq!(refs(&fs), 2);
+            assert_eq!(links(&fs, ino3), 2);
+            for ino in [ino1, ino2] {
+                assert_eq!(data, test_common::read_to_string(ino, &fs).await);
+                fs.verify_file(ino).await.unwrap();
+            }
+
+            // writing makes a copy first
+            let fh = fs.open(ino2, false, true).await.unwrap();
+            assert_eq!(links(&fs, ino2), 1);
+            assert_eq!(links(&fs, ino1), 2);
+            write_all_bytes_to_fs(&fs, ino2, 0, b"TEST-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(data, test_common::read_to_string(ino1, &fs).await);
+            assert_eq!(
+                format!("TEST-42{}", &data[7..]),
+                test_common::read_to_string(ino2, &fs).await
+            );
+            assert_eq!(refs(&fs), 3);
+
+            // removed with the last file using it
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file-1").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(refs(&fs), 2);
+            fs.set_len(ino3, 0).await.unwrap();
+            assert_eq!(refs(&fs), 1);
+
+            let copy = fs
+                .copy_file(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-2").unwrap(),
+                    &SecretString::from_str("test-file-2-copy").unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(refs(&fs), 1);
+            assert_eq!(links(&fs, copy.ino), 3);
+            assert_eq!(
+                test_common::read_to_string(ino2, &fs).await,
+                test_common::read_to_string(copy.ino, &fs).await
+            );
+            fs.verify_file(copy.ino).await.unwrap();
+
+            // each file has its own copy after changing the cipher
+            fs.migrate_cipher(Cipher::Aes256Gcm).await.unwrap();
+            assert_eq!(refs(&fs), 0);
+            assert_eq!(links(&fs, copy.ino), 1);
+            assert_eq!(
+                test_common::read_to_string(ino2, &fs).await,
+                test_common::read_to_string(copy.ino, &fs).await
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_shred() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_shred",
+            read_only: false,
+        },
+        FsConfig {
+            shred: true,
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // a link sees what's left in the blocks of the inode after it's removed
+            let link = fs.data_dir.join("inode-link");
+            std::fs::hard_link(fs.ino_file(attr.ino), &link).unwrap();
+            let len = std::fs::metadata(&link).unwrap().len();
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+            assert!(!fs.ino_file(attr.ino).exists());
+            assert_eq!(vec![0; len as usize], std::fs::read(&link).unwrap());
+            std::fs::remove_file(link).unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_quota() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_quota",
+            read_only: false,
+        },
+        FsConfig {
+            quota: Some(100),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert_eq!(Some((100, 0)), fs.quota_usage());
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &[42; 10], fh)
+                .await
+                .unwrap();
+            assert_eq!(Some((100, 10)), fs.quota_usage());
+            assert!(matches!(
+                fs.write(attr.ino, 10, &[42; 91], fh).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert_eq!(Some((100, 10)), fs.quota_usage());
+            // overwriting doesn't take more space
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &[42; 100], fh)
+                .await
+                .unwrap();
+            assert_eq!(Some((100, 100)), fs.quota_usage());
+            fs.release(fh).await.unwrap();
+
+            fs.set_len(attr.ino, 50).await.unwrap();
+            assert_eq!(Some((100, 50)), fs.quota_usage());
+            assert!(matches!(
+                fs.set_len(attr.ino, 101).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert_eq!(50, fs.get_attr(attr.ino).await.unwrap().size);
+
+            let name2 = SecretString::from_str("test-file-2").unwrap();
+            let (fh, attr2) = fs
+                .create(
+                    ROOT_INODE,
+                    &name2,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.write(attr2.ino, 0, &[42; 51], fh).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            write_all_bytes_to_fs(&fs, attr2.ino, 0, &[42; 50], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(Some((100, 100)), fs.quota_usage());
+
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+            assert_eq!(Some((100, 50)), fs.quota_usage());
+            // counted from the files when opened
+            let open_with = |quota| {
+                EncryptedFs::new_with_config(
+                    fs.data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsConfig {
+                        quota,
+                        ..FsConfig::default()
+                    },
+                )
+            };
+            let fs2 = open_with(Some(60)).await.unwrap();
+            assert_eq!(Some((60, 50)), fs2.quota_usage());
+            let fs2 = open_with(None).await.unwrap();
+            assert!(fs2.quota_usage().is_none());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_self_test() {
+    run_test(
+        TestSetup {
+            key: "test_self_test",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            fs.self_test().await.unwrap();
+            assert!(fs.is_empty(ROOT_INODE).unwrap());
+
+            // the file is removed also when it fails
+            let fs = EncryptedFs::new_with_config(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsConfig {
+                    quota: Some(10),
+                    ..FsConfig::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert!(matches!(fs.self_test().await, Err(FsError::QuotaExceeded)));
+            assert!(fs.is_empty(ROOT_INODE).unwrap());
+            assert_eq!(Some((10, 0)), fs.quota_usage());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_stream() {
+    run_test(
+        TestSetup {
+            key: "test_read_stream",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 42).map(|i| (i % 251) as u8).collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let chunks: Vec<_> = fs.read_stream(attr.ino, fh).try_collect().await.unwrap();
+            assert_eq!(chunks.len(), 3);
+            assert!(chunks.iter().all(|c| c.len() <= BLOCK_SIZE));
+            assert_eq!(data, chunks.concat());
+            fs.release(fh).await.unwrap();
+
+            // errors are yielded and end the stream
+            let items: Vec<_> = fs.read_stream(attr.ino, u64::MAX).collect().await;
+            assert_eq!(items.len(), 1);
+            assert!(matches!(items[0], Err(FsError::InvalidFileHandle)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_read_dir() {
+    run_test(
+        TestSetup {
+            key: "test_read_dir",
             read_only: false,
         },
         async {
@@ -762,8 +1161,8 @@ async fn test_read_dir_plus() {
                 )
                 .await
                 .unwrap();
-            let mut entries: Vec<FsResult<DirectoryEntryPlus>> =
-                fs.read_dir_plus(dir_attr.ino).await.unwrap().collect();
+            let mut entries: Vec<FsResult<DirectoryEntry>> =
+                fs.read_dir(dir_attr.ino).await.unwrap().collect();
             entries.sort_by(|a, b| {
                 a.as_ref()
                     .unwrap()
@@ -771,32 +1170,26 @@ async fn test_read_dir_plus() {
                     .expose_secret()
                     .cmp(&*b.as_ref().unwrap().name.expose_secret())
             });
-            let entries: Vec<DirectoryEntryPlus> =
-                entries.into_iter().map(Result::unwrap).collect();
+            let entries: Vec<DirectoryEntry> = entries.into_iter().map(Result::unwrap).collect();
             assert_eq!(entries.len(), 2);
-            let attr_root = fs.get_attr(ROOT_INODE).await.unwrap();
-            // reload it as atime is changed on read_dir*()
-            let dir_attr = fs.get_attr(dir_attr.ino).await.unwrap();
             assert_eq!(
                 vec![
-                    DirectoryEntryPlus {
+                    DirectoryEntry {
                         ino: dir_attr.ino,
                         name: SecretString::from_str(".").unwrap(),
                         kind: FileType::Directory,
-                        attr: dir_attr,
                     },
-                    DirectoryEntryPlus {
+                    DirectoryEntry {
                         ino: ROOT_INODE,
                         name: SecretString::from_str("..").unwrap(),
                         kind: FileType::Directory,
-                        attr: attr_root,
                     },
                 ],
                 entries
             );
 
-            let mut entries: Vec<FsResult<DirectoryEntryPlus>> =
-                fs.read_dir_plus(ROOT_INODE).await.unwrap().collect();
+            let mut entries: Vec<FsResult<DirectoryEntry>> =
+                fs.read_dir(ROOT_INODE).await.unwrap().collect();
             entries.sort_by(|a, b| {
                 a.as_ref()
                     .unwrap()
@@ -804,28 +1197,22 @@ async fn test_read_dir_plus() {
                     .expose_secret()
                     .cmp(&*b.as_ref().unwrap().name.expose_secret())
             });
-            let entries: Vec<DirectoryEntryPlus> =
-                entries.into_iter().map(Result::unwrap).collect();
-            // reload it as atime is changed on read_dir*()
-            let attr_root = fs.get_attr(ROOT_INODE).await.unwrap();
+            let entries: Vec<DirectoryEntry> = entries.into_iter().map(Result::unwrap).collect();
             let mut sample = vec![
-                DirectoryEntryPlus {
+                DirectoryEntry {
                     ino: ROOT_INODE,
                     name: SecretString::from_str(".").unwrap(),
                     kind: FileType::Directory,
-                    attr: attr_root,
                 },
-                DirectoryEntryPlus {
+                DirectoryEntry {
                     ino: file_attr.ino,
                     name: test_file.clone(),
                     kind: FileType::RegularFile,
-                    attr: file_attr,
                 },
-                DirectoryEntryPlus {
+                DirectoryEntry {
                     ino: dir_attr.ino,
                     name: test_dir.clone(),
                     kind: FileType::Directory,
-                    attr: dir_attr,
                 },
             ];
             sample.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
@@ -834,7 +1221,6 @@ async fn test_read_dir_plus() {
 
             // file and directory in another directory
             let parent = dir_attr.ino;
-            let attr_parent = dir_attr;
             let test_file_2 = SecretString::from_str("test-file-2").unwrap();
             let (_fh, file_attr) = fs
                 .create(
@@ -847,7 +1233,19 @@ async fn test_read_dir_plus() {
                 .await
                 .unwrap();
 
-            let test_dir_2 = SecretString::from_str("test-dir-2").unwrap();
+            let test_file_3 = SecretString::from_str("test_file_3").unwrap();
+            let (_fh, file_attr_2) = fs
+                .create(
+                    parent,
+                    &test_file_3,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let test_dir_2 = SecretString::from_str("test_dir_2").unwrap();
             let (_fh, dir_attr) = fs
                 .create(
                     parent,
@@ -858,9 +1256,8 @@ async fn test_read_dir_plus() {
                 )
                 .await
                 .unwrap();
-            let attr_parent = fs.get_attr(attr_parent.ino).await.unwrap();
-            let mut entries: Vec<FsResult<DirectoryEntryPlus>> =
-                fs.read_dir_plus(dir_attr.ino).await.unwrap().collect();
+            let mut entries: Vec<FsResult<DirectoryEntry>> =
+                fs.read_dir(dir_attr.ino).await.unwrap().collect();
             entries.sort_by(|a, b| {
                 a.as_ref()
                     .unwrap()
@@ -868,62 +1265,56 @@ async fn test_read_dir_plus() {
                     .expose_secret()
                     .cmp(&*b.as_ref().unwrap().name.expose_secret())
             });
-            let entries: Vec<DirectoryEntryPlus> =
-                entries.into_iter().map(Result::unwrap).collect();
-            // reload it as atime is changed on read_dir*()
-            let dir_attr = fs.get_attr(dir_attr.ino).await.unwrap();
+            let entries: Vec<DirectoryEntry> = entries.into_iter().map(Result::unwrap).collect();
             assert_eq!(entries.len(), 2);
             assert_eq!(
                 vec![
-                    DirectoryEntryPlus {
+                    DirectoryEntry {
                         ino: dir_attr.ino,
                         name: SecretString::from_str(".").unwrap(),
                         kind: FileType::Directory,
-                        attr: dir_attr,
                     },
-                    DirectoryEntryPlus {
+                    DirectoryEntry {
                         ino: parent,
                         name: SecretString::from_str("..").unwrap(),
                         kind: FileType::Directory,
-                        attr: attr_parent,
                     },
                 ],
                 entries
             );
 
-            let iter = fs.read_dir_plus(parent).await.unwrap();
-            let mut entries: Vec<DirectoryEntryPlus> = iter.map(Result::unwrap).collect();
+            let iter = fs.read_dir(parent).await.unwrap();
+            let mut entries: Vec<DirectoryEntry> = iter.map(Result::unwrap).collect();
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            // reload it as atime is changed on read_dir*()
-            let attr_parent = fs.get_attr(attr_parent.ino).await.unwrap();
             let mut sample = vec![
-                DirectoryEntryPlus {
+                DirectoryEntry {
                     ino: parent,
                     name: SecretString::from_str(".").unwrap(),
                     kind: FileType::Directory,
-                    attr: attr_parent,
                 },
-                DirectoryEntryPlus {
+                DirectoryEntry {
                     ino: ROOT_INODE,
                     name: SecretString::from_str("..").unwrap(),
                     kind: FileType::Directory,
-                    attr: attr_root,
                 },
-                DirectoryEntryPlus {
+                DirectoryEntry {
                     ino: file_attr.ino,
                     name: test_file_2.clone(),
                     kind: FileType::RegularFile,
-                    attr: file_attr,
                 },
-                DirectoryEntryPlus {
+                DirectoryEntry {
+                    ino: file_attr_2.ino,
+                    name: test_file_3.clone(),
+                    kind: FileType::RegularFile,
+                },
+                DirectoryEntry {
                     ino: dir_attr.ino,
                     name: test_dir_2.clone(),
                     kind: FileType::Directory,
-                    attr: dir_attr,
                 },
             ];
             sample.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            assert_eq!(entries.len(), 4);
+            assert_eq!(entries.len(), 5);
             assert_eq!(sample, entries);
         },
     )
@@ -932,118 +1323,73 @@ async fn test_read_dir_plus() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_find_by_name() {
-    run_test(
+async fn test_name_padding() {
+    run_test_with_config(
         TestSetup {
-            key: "test_find_by_name",
+            key: "test_name_padding",
             read_only: false,
         },
-        async {
-            let fs = get_fs().await;
-
-            let test_file = SecretString::from_str("test-file").unwrap();
-            let (_fh, file_attr) = fs
-                .create(
-                    ROOT_INODE,
-                    &test_file,
-                    create_attr(FileType::RegularFile),
-                    false,
-                    false,
-                )
-                .await
-                .unwrap();
-
-            assert_eq!(
-                Some(file_attr),
-                fs.find_by_name(ROOT_INODE, &test_file).await.unwrap()
-            );
-            assert_eq!(
-                None,
-                fs.find_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
-                    .await
-                    .unwrap()
-            );
-        },
-    )
-    .await;
-}
-
-#[tokio::test]
-#[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_exists_by_name() {
-    run_test(
-        TestSetup {
-            key: "test_exists_by_name",
-            read_only: false,
+        FsConfig {
+            format: Some(VolumeFormat {
+                name_padding: NonZeroUsize::new(32),
+                ..VolumeFormat::default()
+            }),
+            ..FsConfig::default()
         },
         async {
             let fs = get_fs().await;
-
-            for file in ["test-file", "test--file"] {
-                let test_file = SecretString::from_str(file).unwrap();
-                let _ = fs
+            let mut inos = vec![];
+            for name in ["a", "longer-name.txt"] {
+                let name = SecretString::from_str(name).unwrap();
+                let (_, attr) = fs
                     .create(
                         ROOT_INODE,
-                        &test_file,
+                        &name,
                         create_attr(FileType::RegularFile),
                         false,
                         false,
                     )
                     .await
                     .unwrap();
-
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-                assert!(
-                    !(fs.exists_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
-                        .unwrap())
-                );
-            }
-        },
-    )
-    .await;
-}
-
-#[tokio::test]
-#[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_remove_dir() {
-    run_test(
-        TestSetup {
-            key: "test_remove_dir",
-            read_only: false,
-        },
-        async {
-            let fs = get_fs().await;
-            for dir in ["test-dir", "test-dir_", "test-dir-"] {
-                let test_dir = SecretString::from_str(dir).unwrap();
-                let _ = fs
-                    .create(
-                        ROOT_INODE,
-                        &test_dir,
-                        create_attr(FileType::Directory),
-                        false,
-                        false,
-                    )
-                    .await
-                    .unwrap();
-
-                assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
-                fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
-                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
+                inos.push(attr.ino);
+                // lookup by hash of the plaintext name still works
                 assert_eq!(
-                    0,
-                    fs.read_dir(ROOT_INODE)
+                    attr.ino,
+                    fs.find_by_name(ROOT_INODE, &name)
                         .await
                         .unwrap()
-                        .filter(|entry| {
-                            entry.as_ref().unwrap().name.expose_secret() == test_dir.expose_secret()
-                        })
-                        .count()
+                        .unwrap()
+                        .ino
                 );
             }
+            let name_lens: std::collections::HashSet<_> =
+                std::fs::read_dir(fs.contents_path(ROOT_INODE).join(LS_DIR))
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().len())
+                    .collect();
+            // `$.` and both names in the same bucket
+            assert_eq!(1, name_lens.len());
+
+            let mut names: Vec<_> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect();
+            names.sort();
+            assert_eq!(vec![".", "a", "longer-name.txt"], names);
+
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str("nul\0").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
         },
     )
     .await;
@@ -1051,45 +1397,90 @@ async fn test_remove_dir() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_remove_file() {
+async fn test_read_dir_paged() {
     run_test(
         TestSetup {
-            key: "test_remove_file",
+            key: "test_read_dir_paged",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            for dir in ["test-dir", "test-dir_", "test-dir-clear"] {
-                let test_file = SecretString::from_str(dir).unwrap();
-                let _ = fs
-                    .create(
-                        ROOT_INODE,
-                        &test_file,
-                        create_attr(FileType::RegularFile),
-                        false,
-                        false,
-                    )
-                    .await
-                    .unwrap();
+            for i in 0..10 {
+                let name = SecretString::from_str(&format!("file-{i}")).unwrap();
+                fs.create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            let names = |page: &[DirectoryEntry]| {
+                page.iter()
+                    .map(|e| e.name.expose_secret().to_string())
+                    .collect::<Vec<_>>()
+            };
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-                fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
-                assert_eq!(
-                    0,
-                    fs.read_dir(ROOT_INODE)
-                        .await
-                        .unwrap()
-                        .filter(|entry| {
-                            entry.as_ref().unwrap().name.expose_secret()
-                                == test_file.expose_secret()
-                        })
-                        .count()
-                );
+            // pages are in the same order as the whole listing, with `.`
+            let (all, has_more) = fs.read_dir_paged(ROOT_INODE, 0, 100).await.unwrap();
+            assert!(!has_more);
+            assert_eq!(11, all.len());
+            let mut paged = vec![];
+            let mut offset = 0;
+            loop {
+                let (page, has_more) = fs.read_dir_paged(ROOT_INODE, offset, 5).await.unwrap();
+                offset += page.len();
+                paged.extend(names(&page));
+                if !has_more {
+                    break;
+                }
+                assert_eq!(5, page.len());
+            }
+            assert_eq!(names(&all), paged);
+
+            // offset past the end
+            let (page, has_more) = fs.read_dir_paged(ROOT_INODE, 100, 5).await.unwrap();
+            assert!(page.is_empty());
+            assert!(!has_more);
+
+            // changes between pages don't fail the listing
+            let (first, _) = fs.read_dir_paged(ROOT_INODE, 0, 5).await.unwrap();
+            for i in 0..5 {
+                let name = SecretString::from_str(&format!("file-{i}")).unwrap();
+                fs.remove_file(ROOT_INODE, &name).await.unwrap();
             }
+            let name = SecretString::from_str("file-new").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &name,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            let (rest, has_more) = fs
+                .read_dir_paged(ROOT_INODE, first.len(), 100)
+                .await
+                .unwrap();
+            assert!(!has_more);
+            assert!(rest.len() <= 2);
+
+            assert!(matches!(
+                fs.read_dir_paged(
+                    all.iter()
+                        .find(|e| e.kind == FileType::RegularFile)
+                        .unwrap()
+                        .ino,
+                    0,
+                    5
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
         },
     )
     .await;
@@ -1097,92 +1488,79 @@ async fn test_remove_file() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_find_by_name_exists_by_name100files() {
-    run_test(
+async fn test_read_dir_sorted() {
+    run_test_with_config(
         TestSetup {
-            key: "test_find_by_name_exists_by_name_many_files",
+            key: "test_read_dir_sorted",
             read_only: false,
         },
+        FsConfig {
+            sorted_dirs: true,
+            ..FsConfig::default()
+        },
         async {
             let fs = get_fs().await;
 
-            for i in 0..100 {
-                let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
-                let _ = fs
-                    .create(
-                        ROOT_INODE,
-                        &test_file,
-                        create_attr(FileType::RegularFile),
-                        false,
-                        false,
-                    )
-                    .await
-                    .unwrap();
-            }
-
-            let special_test_file = SecretString::from_str("test-_file").unwrap();
-            let _ = fs
+            let (_, dir) = fs
                 .create(
                     ROOT_INODE,
-                    &special_test_file,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut names = (0..20).map(|i| format!("file-{i:02}")).collect::<Vec<_>>();
+            rand::seq::SliceRandom::shuffle(&mut names[..], &mut rand::thread_rng());
+            for name in &names {
+                fs.create(
+                    dir.ino,
+                    &SecretString::from_str(name).unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
                     false,
                 )
                 .await
                 .unwrap();
+            }
+            names.sort();
+            let mut expected = vec![".".to_string(), "..".to_string()];
+            expected.extend(names);
+            let to_names = |entries: &[DirectoryEntry]| {
+                entries
+                    .iter()
+                    .map(|e| e.name.expose_secret().to_string())
+                    .collect::<Vec<_>>()
+            };
 
-            let test_file = SecretString::from_str("test-file-42").unwrap();
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-            assert!(fs
-                .find_by_name(ROOT_INODE, &test_file)
-                .await
-                .unwrap()
-                .is_some());
+            let entries = fs.read_dir_sorted(dir.ino).await.unwrap();
+            assert_eq!(expected, to_names(&entries));
 
-            assert!(fs.exists_by_name(ROOT_INODE, &special_test_file).unwrap());
-            assert!(fs
-                .find_by_name(ROOT_INODE, &special_test_file)
+            // the pages and the listing with attrs follow the same order
+            let mut paged = vec![];
+            let mut offset = 0;
+            loop {
+                let (page, has_more) = fs.read_dir_paged(dir.ino, offset, 6).await.unwrap();
+                offset += page.len();
+                paged.extend(to_names(&page));
+                if !has_more {
+                    break;
+                }
+            }
+            assert_eq!(expected, paged);
+            let plus = fs
+                .read_dir_plus(dir.ino)
                 .await
                 .unwrap()
-                .is_some());
-        },
-    )
-    .await;
-}
-
-#[tokio::test]
-#[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_create_structure_and_root() {
-    run_test(
-        TestSetup {
-            key: "test_sample",
-            read_only: false,
-        },
-        async {
-            let fs = get_fs().await;
-
-            assert!(fs.exists(ROOT_INODE));
-            assert!(fs.is_dir(ROOT_INODE));
-
-            assert!(fs.data_dir.join(INODES_DIR).is_dir());
-            assert!(fs.data_dir.join(CONTENTS_DIR).is_dir());
-            assert!(fs.data_dir.join(SECURITY_DIR).is_dir());
-            assert!(fs
-                .data_dir
-                .join(SECURITY_DIR)
-                .join(KEY_ENC_FILENAME)
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(SECURITY_DIR)
-                .join(KEY_SALT_FILENAME)
-                .is_file());
+                .map(|e| e.unwrap().name.expose_secret().to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(expected, plus);
 
-            assert!(fs.data_dir.join(INODES_DIR).join(ROOT_INODE_STR).is_file());
-            assert!(fs.data_dir.join(CONTENTS_DIR).join(ROOT_INODE_STR).is_dir());
+            assert!(matches!(
+                fs.read_dir_sorted(entries[2].ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
         },
     )
     .await;
@@ -1191,68 +1569,30 @@ async fn test_create_structure_and_root() {
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
-async fn test_create() {
+async fn test_read_dir_plus() {
     run_test(
         TestSetup {
-            key: "test_create",
+            key: "test_read_dir_plus",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            // file in root
+            // file and directory in root
             let test_file = SecretString::from_str("test-file").unwrap();
-            let (fh, attr) = fs
+            let (_fh, file_attr) = fs
                 .create(
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
+                    false,
                     false,
                 )
                 .await
                 .unwrap();
-            assert_ne!(fh, 0);
-            assert_ne!(attr.ino, 0);
-            assert!(fs
-                .data_dir
-                .join(INODES_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(ROOT_INODE_STR)
-                .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_file))
-                .is_file());
-            assert!(fs.exists(attr.ino));
-            assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
-            let mut entries: Vec<DirectoryEntryPlus> = fs
-                .read_dir_plus(ROOT_INODE)
-                .await
-                .unwrap()
-                .map(Result::unwrap)
-                .collect();
-            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            assert_eq!(attr, entries[1].attr);
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
-            assert_eq!(
-                attr,
-                fs.find_by_name(ROOT_INODE, &test_file)
-                    .await
-                    .unwrap()
-                    .unwrap()
-            );
 
-            // directory in root
             let test_dir = SecretString::from_str("test-dir").unwrap();
-            let (_fh, attr) = fs
+            let (_fh, dir_attr) = fs
                 .create(
                     ROOT_INODE,
                     &test_dir,
@@ -1262,49 +1602,93 @@ async fn test_create() {
                 )
                 .await
                 .unwrap();
-            assert_ne!(attr.ino, 0);
-            assert!(fs
-                .data_dir
-                .join(INODES_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(attr.ino.to_string())
-                .is_dir());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(ROOT_INODE_STR)
-                .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_dir))
-                .is_file());
-            assert!(fs.exists(attr.ino));
-            assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
-            assert!(fs.is_dir(attr.ino));
-            let mut entries: Vec<DirectoryEntryPlus> = fs
-                .read_dir_plus(ROOT_INODE)
-                .await
-                .unwrap()
-                .map(Result::unwrap)
-                .collect();
-            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            assert_eq!(ROOT_INODE, entries[0].attr.ino);
-            assert_eq!(attr, entries[1].attr);
-            assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
-            assert_eq!(
-                attr,
-                fs.find_by_name(ROOT_INODE, &test_dir)
-                    .await
-                    .unwrap()
+            let mut entries: Vec<FsResult<DirectoryEntryPlus>> =
+                fs.read_dir_plus(dir_attr.ino).await.unwrap().collect();
+            entries.sort_by(|a, b| {
+                a.as_ref()
                     .unwrap()
+                    .name
+                    .expose_secret()
+                    .cmp(&*b.as_ref().unwrap().name.expose_secret())
+            });
+            let entries: Vec<DirectoryEntryPlus> =
+                entries.into_iter().map(Result::unwrap).collect();
+            assert_eq!(entries.len(), 2);
+            let attr_root = fs.get_attr(ROOT_INODE).await.unwrap();
+            // reload it as atime is changed on read_dir*()
+            let dir_attr = fs.get_attr(dir_attr.ino).await.unwrap();
+            assert_eq!(
+                vec![
+                    DirectoryEntryPlus {
+                        ino: dir_attr.ino,
+                        name: SecretString::from_str(".").unwrap(),
+                        kind: FileType::Directory,
+                        attr: dir_attr,
+                    },
+                    DirectoryEntryPlus {
+                        ino: ROOT_INODE,
+                        name: SecretString::from_str("..").unwrap(),
+                        kind: FileType::Directory,
+                        attr: attr_root,
+                    },
+                ],
+                entries
             );
 
-            // directory in another directory
-            let parent = attr.ino;
+            let mut entries: Vec<FsResult<DirectoryEntryPlus>> =
+                fs.read_dir_plus(ROOT_INODE).await.unwrap().collect();
+            entries.sort_by(|a, b| {
+                a.as_ref()
+                    .unwrap()
+                    .name
+                    .expose_secret()
+                    .cmp(&*b.as_ref().unwrap().name.expose_secret())
+            });
+            let entries: Vec<DirectoryEntryPlus> =
+                entries.into_iter().map(Result::unwrap).collect();
+            // reload it as atime is changed on read_dir*()
+            let attr_root = fs.get_attr(ROOT_INODE).await.unwrap();
+            let mut sample = vec![
+                DirectoryEntryPlus {
+                    ino: ROOT_INODE,
+                    name: SecretString::from_str(".").unwrap(),
+                    kind: FileType::Directory,
+                    attr: attr_root,
+                },
+                DirectoryEntryPlus {
+                    ino: file_attr.ino,
+                    name: test_file.clone(),
+                    kind: FileType::RegularFile,
+                    attr: file_attr,
+                },
+                DirectoryEntryPlus {
+                    ino: dir_attr.ino,
+                    name: test_dir.clone(),
+                    kind: FileType::Directory,
+                    attr: dir_attr,
+                },
+            ];
+            sample.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
+            assert_eq!(entries.len(), 3);
+            assert_eq!(sample, entries);
+
+            // file and directory in another directory
+            let parent = dir_attr.ino;
+            let attr_parent = dir_attr;
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            let (_fh, file_attr) = fs
+                .create(
+                    parent,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
             let test_dir_2 = SecretString::from_str("test-dir-2").unwrap();
-            let (_fh, attr) = fs
+            let (_fh, dir_attr) = fs
                 .create(
                     parent,
                     &test_dir_2,
@@ -1314,66 +1698,4231 @@ async fn test_create() {
                 )
                 .await
                 .unwrap();
-            assert!(fs
-                .data_dir
-                .join(INODES_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(attr.ino.to_string())
-                .is_dir());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(parent.to_string())
-                .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_dir_2))
-                .is_file());
-            assert!(fs.exists(attr.ino));
-            assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
-            assert!(fs.is_dir(attr.ino));
-            let mut entries: Vec<DirectoryEntryPlus> = fs
-                .read_dir_plus(parent)
-                .await
-                .unwrap()
-                .map(Result::unwrap)
-                .collect();
-            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
-            assert_eq!(attr, entries[2].attr);
-            assert_eq!(parent, entries[0].attr.ino);
-            assert!(fs.exists_by_name(parent, &test_dir_2).unwrap());
+            let attr_parent = fs.get_attr(attr_parent.ino).await.unwrap();
+            let mut entries: Vec<FsResult<DirectoryEntryPlus>> =
+                fs.read_dir_plus(dir_attr.ino).await.unwrap().collect();
+            entries.sort_by(|a, b| {
+                a.as_ref()
+                    .unwrap()
+                    .name
+                    .expose_secret()
+                    .cmp(&*b.as_ref().unwrap().name.expose_secret())
+            });
+            let entries: Vec<DirectoryEntryPlus> =
+                entries.into_iter().map(Result::unwrap).collect();
+            // reload it as atime is changed on read_dir*()
+            let dir_attr = fs.get_attr(dir_attr.ino).await.unwrap();
+            assert_eq!(entries.len(), 2);
             assert_eq!(
-                attr,
-                fs.find_by_name(parent, &test_dir_2).await.unwrap().unwrap()
+                vec![
+                    DirectoryEntryPlus {
+                        ino: dir_attr.ino,
+                        name: SecretString::from_str(".").unwrap(),
+                        kind: FileType::Directory,
+                        attr: dir_attr,
+                    },
+                    DirectoryEntryPlus {
+                        ino: parent,
+                        name: SecretString::from_str("..").unwrap(),
+                        kind: FileType::Directory,
+                        attr: attr_parent,
+                    },
+                ],
+                entries
             );
 
-            // existing file
-            assert!(matches!(
-                fs.create(
+            let iter = fs.read_dir_plus(parent).await.unwrap();
+            let mut entries: Vec<DirectoryEntryPlus> = iter.map(Result::unwrap).collect();
+            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
+            // reload it as atime is changed on read_dir*()
+            let attr_parent = fs.get_attr(attr_parent.ino).await.unwrap();
+            let mut sample = vec![
+                DirectoryEntryPlus {
+                    ino: parent,
+                    name: SecretString::from_str(".").unwrap(),
+                    kind: FileType::Directory,
+                    attr: attr_parent,
+                },
+                DirectoryEntryPlus {
+                    ino: ROOT_INODE,
+                    name: SecretString::from_str("..").unwrap(),
+                    kind: FileType::Directory,
+                    attr: attr_root,
+                },
+                DirectoryEntryPlus {
+                    ino: file_attr.ino,
+                    name: test_file_2.clone(),
+                    kind: FileType::RegularFile,
+                    attr: file_attr,
+                },
+                DirectoryEntryPlus {
+                    ino: dir_attr.ino,
+                    name: test_dir_2.clone(),
+                    kind: FileType::Directory,
+                    attr: dir_attr,
+                },
+            ];
+            sample.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
+            assert_eq!(entries.len(), 4);
+            assert_eq!(sample, entries);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_find_by_name() {
+    run_test(
+        TestSetup {
+            key: "test_find_by_name",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_fh, file_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                Some(file_attr),
+                fs.find_by_name(ROOT_INODE, &test_file).await.unwrap()
+            );
+            assert_eq!(
+                None,
+                fs.find_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                    .await
+                    .unwrap()
+            );
+
+            // root's parent is the root itself
+            let root_attr = fs.get_attr(ROOT_INODE).await.unwrap();
+            assert_eq!(
+                Some(root_attr),
+                fs.find_by_name(ROOT_INODE, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+            );
+            // but for other directories it's the real parent
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(ROOT_INODE),
+                fs.find_by_name(dir_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .map(|attr| attr.ino)
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_exists_by_name() {
+    run_test(
+        TestSetup {
+            key: "test_exists_by_name",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for file in ["test-file", "test--file"] {
+                let test_file = SecretString::from_str(file).unwrap();
+                let _ = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_file,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+
+                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(
+                    !(fs.exists_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                        .unwrap())
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_disk_usage() {
+    run_test(
+        TestSetup {
+            key: "test_disk_usage",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            assert_eq!((0, 0), fs.disk_usage(ROOT_INODE).await.unwrap());
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut files = vec![];
+            for (parent, name, len) in [(ROOT_INODE, "file1", 10), (dir_attr.ino, "file2", 20)] {
+                let name = SecretString::from_str(name).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        parent,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &vec![42; len], fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                files.push(attr.ino);
+            }
+            // counted once
+            let test_link = SecretString::from_str("test-link").unwrap();
+            fs.link(files[1], ROOT_INODE, &test_link).await.unwrap();
+
+            let (logical, physical) = fs.disk_usage(ROOT_INODE).await.unwrap();
+            assert_eq!(30, logical);
+            assert!(physical > logical);
+            let (logical, physical_dir) = fs.disk_usage(dir_attr.ino).await.unwrap();
+            assert_eq!(20, logical);
+            assert!(physical_dir > logical && physical_dir < physical);
+            assert_eq!(10, fs.disk_usage(files[0]).await.unwrap().0);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_remove_dir() {
+    run_test(
+        TestSetup {
+            key: "test_remove_dir",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            for dir in ["test-dir", "test-dir_", "test-dir-"] {
+                let test_dir = SecretString::from_str(dir).unwrap();
+                let (_, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_dir,
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+
+                assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                assert!(fs.is_empty(attr.ino).unwrap());
+                let child = SecretString::from_str("child").unwrap();
+                fs.create(
+                    attr.ino,
+                    &child,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+                assert!(!fs.is_empty(attr.ino).unwrap());
+                assert!(matches!(
+                    fs.remove_dir(ROOT_INODE, &test_dir).await,
+                    Err(FsError::NotEmpty)
+                ));
+                fs.remove_file(attr.ino, &child).await.unwrap();
+                assert!(fs.is_empty(attr.ino).unwrap());
+                fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
+                assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
+                assert_eq!(
+                    0,
+                    fs.read_dir(ROOT_INODE)
+                        .await
+                        .unwrap()
+                        .filter(|entry| {
+                            entry.as_ref().unwrap().name.expose_secret() == test_dir.expose_secret()
+                        })
+                        .count()
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_remove_file() {
+    run_test(
+        TestSetup {
+            key: "test_remove_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for dir in ["test-dir", "test-dir_", "test-dir-clear"] {
+                let test_file = SecretString::from_str(dir).unwrap();
+                let _ = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_file,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+
+                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+                assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
+                assert_eq!(
+                    0,
+                    fs.read_dir(ROOT_INODE)
+                        .await
+                        .unwrap()
+                        .filter(|entry| {
+                            entry.as_ref().unwrap().name.expose_secret()
+                                == test_file.expose_secret()
+                        })
+                        .count()
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_append() {
+    run_test(
+        TestSetup {
+            key: "test_open_append",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // not flushed yet, appends go after it
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"abc", fh)
+                .await
+                .unwrap();
+
+            let fh_a = fs.open_append(attr.ino).await.unwrap();
+            let fh_b = fs.open_append(attr.ino).await.unwrap();
+            assert!(fs.is_write_handle(fh_a).await);
+            // the offset is ignored
+            assert_eq!(fs.write(attr.ino, 0, b"-1", fh_a).await.unwrap(), 2);
+            assert_eq!(fs.write(attr.ino, 1, b"-2", fh_b).await.unwrap(), 2);
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 7);
+
+            // the single writer keeps working alongside
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"X", fh)
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.open(attr.ino, false, true).await,
+                Err(FsError::AlreadyOpenForWrite)
+            ));
+
+            fs.flush(fh_a).await.unwrap();
+            fs.release(fh_a).await.unwrap();
+            fs.release(fh_b).await.unwrap();
+            assert!(matches!(
+                fs.write(attr.ino, 0, b"-3", fh_a).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "Xbc-1-2");
+
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.open_append(dir_attr.ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_link() {
+    run_test(
+        TestSetup {
+            key: "test_link",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let test_link = SecretString::from_str("test-link").unwrap();
+            let link_attr = fs.link(attr.ino, dir_attr.ino, &test_link).await.unwrap();
+            assert_eq!(link_attr.ino, attr.ino);
+            assert_eq!(link_attr.nlink, 2);
+            let found = fs.find_by_name(dir_attr.ino, &test_link).await.unwrap();
+            assert_eq!(found.unwrap().ino, attr.ino);
+            assert!(matches!(
+                fs.link(attr.ino, dir_attr.ino, &test_link).await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.link(dir_attr.ino, ROOT_INODE, &test_link).await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            // renaming a link over another name of the same file is a no-op
+            fs.rename(dir_attr.ino, &test_link, ROOT_INODE, &test_file)
+                .await
+                .unwrap();
+            assert!(fs.exists_by_name(dir_attr.ino, &test_link).unwrap());
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().nlink, 2);
+
+            // the content is kept while a link remains
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().nlink, 1);
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42");
+
+            fs.remove_file(dir_attr.ino, &test_link).await.unwrap();
+            assert!(!fs.exists(attr.ino));
+            assert!(!fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(attr.ino.to_string())
+                .exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lookup_path() {
+    run_test(
+        TestSetup {
+            key: "test_lookup_path",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, file_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            for path in ["", "/", ".", "/./", "..", "test-dir/.."] {
+                assert_eq!(fs.lookup_path(path).await.unwrap().ino, ROOT_INODE);
+            }
+            for path in ["test-dir", "/test-dir/", "./test-dir"] {
+                assert_eq!(fs.lookup_path(path).await.unwrap().ino, dir_attr.ino);
+            }
+            for path in [
+                "test-dir/test-file",
+                "/test-dir//test-file",
+                "/test-dir/../test-dir/test-file",
+            ] {
+                assert_eq!(fs.lookup_path(path).await.unwrap().ino, file_attr.ino);
+            }
+            assert!(matches!(
+                fs.lookup_path("/test-dir/42").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.lookup_path("/42/test-file").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.lookup_path("test-dir/test-file/..").await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            let (parent, name) = fs.lookup_path_parent("/test-dir/test-file").await.unwrap();
+            assert_eq!(parent, dir_attr.ino);
+            assert_eq!(*name.expose_secret(), "test-file");
+            // the leaf doesn't need to exist
+            let (parent, name) = fs.lookup_path_parent("test-dir/42/").await.unwrap();
+            assert_eq!(parent, dir_attr.ino);
+            assert_eq!(*name.expose_secret(), "42");
+            let (parent, name) = fs.lookup_path_parent("test-dir").await.unwrap();
+            assert_eq!(parent, ROOT_INODE);
+            assert_eq!(*name.expose_secret(), "test-dir");
+            for path in ["/", "", "test-dir/..", "test-dir/."] {
+                assert!(matches!(
+                    fs.lookup_path_parent(path).await,
+                    Err(FsError::InvalidInput(_))
+                ));
+            }
+            assert!(matches!(
+                fs.lookup_path_parent("/42/test-file").await,
+                Err(FsError::NotFound(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_find_by_name_exists_by_name100files() {
+    run_test(
+        TestSetup {
+            key: "test_find_by_name_exists_by_name_many_files",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for i in 0..100 {
+                let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let _ = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_file,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let special_test_file = SecretString::from_str("test-_file").unwrap();
+            let _ = fs
+                .create(
+                    ROOT_INODE,
+                    &special_test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let test_file = SecretString::from_str("test-file-42").unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs
+                .find_by_name(ROOT_INODE, &test_file)
+                .await
+                .unwrap()
+                .is_some());
+
+            assert!(fs.exists_by_name(ROOT_INODE, &special_test_file).unwrap());
+            assert!(fs
+                .find_by_name(ROOT_INODE, &special_test_file)
+                .await
+                .unwrap()
+                .is_some());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_create_structure_and_root() {
+    run_test(
+        TestSetup {
+            key: "test_sample",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            assert!(fs.exists(ROOT_INODE));
+            assert!(fs.is_dir(ROOT_INODE));
+
+            assert!(fs.data_dir.join(INODES_DIR).is_dir());
+            assert!(fs.data_dir.join(CONTENTS_DIR).is_dir());
+            assert!(fs.data_dir.join(SECURITY_DIR).is_dir());
+            assert!(fs
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(KEY_ENC_FILENAME)
+                .is_file());
+            assert!(fs
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(KEY_SALT_FILENAME)
+                .is_file());
+
+            assert!(fs.data_dir.join(INODES_DIR).join(ROOT_INODE_STR).is_file());
+            assert!(fs.data_dir.join(CONTENTS_DIR).join(ROOT_INODE_STR).is_dir());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_create() {
+    run_test(
+        TestSetup {
+            key: "test_create",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // file in root
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_ne!(fh, 0);
+            assert_ne!(attr.ino, 0);
+            assert!(fs
+                .data_dir
+                .join(INODES_DIR)
+                .join(attr.ino.to_string())
+                .is_file());
+            assert!(fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(attr.ino.to_string())
+                .is_file());
+            assert!(fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(ROOT_INODE_STR)
+                .join(HASH_DIR)
+                .join(crypto::hash_file_name(&test_file))
+                .is_file());
+            assert!(fs.exists(attr.ino));
+            assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
+            let mut entries: Vec<DirectoryEntryPlus> = fs
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
+            assert_eq!(attr, entries[1].attr);
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert_eq!(
+                attr,
+                fs.find_by_name(ROOT_INODE, &test_file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            );
+
+            // directory in root
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_ne!(attr.ino, 0);
+            assert!(fs
+                .data_dir
+                .join(INODES_DIR)
+                .join(attr.ino.to_string())
+                .is_file());
+            assert!(fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(attr.ino.to_string())
+                .is_dir());
+            assert!(fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(ROOT_INODE_STR)
+                .join(HASH_DIR)
+                .join(crypto::hash_file_name(&test_dir))
+                .is_file());
+            assert!(fs.exists(attr.ino));
+            assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
+            assert!(fs.is_dir(attr.ino));
+            let mut entries: Vec<DirectoryEntryPlus> = fs
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
+            assert_eq!(ROOT_INODE, entries[0].attr.ino);
+            assert_eq!(attr, entries[1].attr);
+            assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+            assert_eq!(
+                attr,
+                fs.find_by_name(ROOT_INODE, &test_dir)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            );
+
+            // directory in another directory
+            let parent = attr.ino;
+            let test_dir_2 = SecretString::from_str("test-dir-2").unwrap();
+            let (_fh, attr) = fs
+                .create(
+                    parent,
+                    &test_dir_2,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(fs
+                .data_dir
+                .join(INODES_DIR)
+                .join(attr.ino.to_string())
+                .is_file());
+            assert!(fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(attr.ino.to_string())
+                .is_dir());
+            assert!(fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(parent.to_string())
+                .join(HASH_DIR)
+                .join(crypto::hash_file_name(&test_dir_2))
+                .is_file());
+            assert!(fs.exists(attr.ino));
+            assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
+            assert!(fs.is_dir(attr.ino));
+            let mut entries: Vec<DirectoryEntryPlus> = fs
+                .read_dir_plus(parent)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
+            assert_eq!(attr, entries[2].attr);
+            assert_eq!(parent, entries[0].attr.ino);
+            assert!(fs.exists_by_name(parent, &test_dir_2).unwrap());
+            assert_eq!(
+                attr,
+                fs.find_by_name(parent, &test_dir_2).await.unwrap().unwrap()
+            );
+
+            // existing file
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // existing directory
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_create_unique_inos() {
+    run_test(
+        TestSetup {
+            key: "test_create_unique_inos",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let mut join_set = JoinSet::new();
+            for i in 0..32 {
+                let fs = fs.clone();
+                join_set.spawn(async move {
+                    let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                    let (_, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &name,
+                            create_attr(FileType::RegularFile),
+                            false,
+                            false,
+                        )
+                        .await
+                        .unwrap();
+                    attr.ino
+                });
+            }
+            let mut inos = std::collections::HashSet::new();
+            while let Some(ino) = join_set.join_next().await {
+                let ino = ino.unwrap();
+                assert!(ino > ROOT_INODE);
+                assert!(inos.insert(ino));
+            }
+            assert!(fs
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(INO_COUNTER_FILENAME)
+                .is_file());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_rename() {
+    run_test(
+        TestSetup {
+            key: "test_rename",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // new file in same directory
+            let new_parent = ROOT_INODE;
+            let file_1 = SecretString::from_str("file-1").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let file_1_new = SecretString::from_str("file-1-new").unwrap();
+            fs.rename(ROOT_INODE, &file_1, new_parent, &file_1_new)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_1_new).unwrap());
+            let new_attr = fs
+                .find_by_name(new_parent, &file_1_new)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(fs.is_file(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1_new.expose_secret())
+                    .count(),
+                1
+            );
+
+            // new directory in same directory
+            let new_parent = ROOT_INODE;
+            let dir_1 = SecretString::from_str("dir-1").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_1,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let dir_1_new = SecretString::from_str("dir-1-new").unwrap();
+            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1_new)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_1_new).unwrap());
+            let new_attr = fs
+                .find_by_name(new_parent, &dir_1_new)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(fs.is_dir(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_1_new.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_parent
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_attr.ino
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
+                    .count(),
+                1
+            );
+
+            let dir_new_parent = SecretString::from_str("dir-new-parent").unwrap();
+            let (_, new_parent_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_new_parent,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // new file to another directory
+            let new_parent = new_parent_attr.ino;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let file_2 = SecretString::from_str("file-2").unwrap();
+            fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
+            assert!(fs.is_file(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| {
+                        let file_new = "file-new";
+                        *entry.as_ref().unwrap().name.expose_secret() == file_new
+                    })
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_2.expose_secret())
+                    .count(),
+                1
+            );
+
+            // new directory to another directory
+            let new_parent = new_parent_attr.ino;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_1,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let dir_2 = SecretString::from_str("dir-_2").unwrap();
+            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
+            assert!(fs.is_dir(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_2.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_2.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_parent
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_attr.ino
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
+                    .count(),
+                1
+            );
+
+            // file to existing file in same directory
+            let file_1 = SecretString::from_str("file-1").unwrap();
+            let file_2 = SecretString::from_str("file--2").unwrap();
+            let new_parent = ROOT_INODE;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, _attr_2) = fs
+                .create(
+                    new_parent,
+                    &file_2,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
+            assert!(fs.is_file(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_2.expose_secret())
+                    .count(),
+                1
+            );
+
+            // directory to existing directory in same directory
+            let new_parent = ROOT_INODE;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_1,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, _attr_2) = fs
+                .create(
+                    new_parent,
+                    &dir_2,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
+            assert!(fs.is_dir(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_2.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_parent
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_attr.ino
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
+                    .count(),
+                1
+            );
+
+            // file to existing file in another directory
+            let new_parent = new_parent_attr.ino;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, _attr_2) = fs
+                .create(
+                    new_parent,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &file_1, new_parent, &file_1)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
+            assert!(fs.is_file(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1.expose_secret())
+                    .count(),
+                1
+            );
+
+            // directory to existing directory in another directory
+            let new_parent = new_parent_attr.ino;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_1,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, _attr_2) = fs
+                .create(
+                    new_parent,
+                    &dir_1,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
+            assert!(fs.is_dir(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_1.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_parent
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_attr.ino
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
+                    .count(),
+                1
+            );
+
+            // overwriting directory with file
+            let new_parent = ROOT_INODE;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, _attr_2) = fs
+                .create(
+                    new_parent,
+                    &dir_1,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &file_1, new_parent, &dir_1)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
+            assert!(fs.is_file(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_1.expose_secret())
+                    .count(),
+                1
+            );
+
+            // overwriting file with directory
+            let new_parent = ROOT_INODE;
+            let dir_3 = SecretString::from_str("dir-3").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_3,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, _attr_2) = fs
+                .create(
+                    new_parent,
+                    &file_1,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &dir_3, new_parent, &file_1)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
+            assert!(fs.is_dir(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_3.expose_secret())
+                    .count(),
+                0
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_1.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_parent
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_attr.ino
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
+                    .count(),
+                1
+            );
+
+            // overwriting non-empty directory
+            let new_parent = ROOT_INODE;
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_3,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let _ = new_parent_attr;
+            let name_2 = dir_new_parent;
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &dir_3, new_parent, &name_2).await,
+                Err(FsError::NotEmpty)
+            ));
+            assert!(fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
+            assert!(fs.exists_by_name(new_parent, &name_2).unwrap());
+            let attr_3 = fs.find_by_name(ROOT_INODE, &dir_3).await.unwrap().unwrap();
+            assert!(fs.is_dir(attr_3.ino));
+            let attr_2 = fs.find_by_name(new_parent, &name_2).await.unwrap().unwrap();
+            assert!(fs.is_dir(attr_2.ino));
+            let new_attr = fs.find_by_name(new_parent, &dir_3).await.unwrap().unwrap();
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            let new_attr_2 = fs.find_by_name(new_parent, &name_2).await.unwrap().unwrap();
+            assert_eq!(new_attr_2.ino, attr_2.ino);
+            assert_eq!(new_attr_2.kind, attr_2.kind);
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_3.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == name_2.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr_2.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_parent
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr_2.ino, &SecretString::from_str(".").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_attr_2.ino
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
+                    .count(),
+                1
+            );
+
+            // same file in same directory
+            let new_parent = ROOT_INODE;
+            let file_3 = SecretString::from_str("file-3").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_3,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &file_3, new_parent, &file_3)
+                .await
+                .unwrap();
+            assert!(fs.exists_by_name(new_parent, &file_3).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &file_3).await.unwrap().unwrap();
+            assert!(fs.is_file(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_3.expose_secret())
+                    .count(),
+                1
+            );
+
+            // same directory in same directory
+            let new_parent = ROOT_INODE;
+            let dir_5 = SecretString::from_str("dir-5").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_5,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(ROOT_INODE, &dir_5, new_parent, &dir_5)
+                .await
+                .unwrap();
+            assert!(fs.exists_by_name(new_parent, &dir_5).unwrap());
+            let new_attr = fs.find_by_name(new_parent, &dir_5).await.unwrap().unwrap();
+            assert!(fs.is_dir(new_attr.ino));
+            assert_eq!(new_attr.ino, attr.ino);
+            assert_eq!(new_attr.kind, attr.kind);
+            assert_eq!(
+                fs.read_dir(new_parent)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == dir_5.expose_secret())
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_parent
+            );
+            assert_eq!(
+                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                new_attr.ino
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
+                    .count(),
+                1
+            );
+            assert_eq!(
+                fs.read_dir(new_attr.ino)
+                    .await
+                    .unwrap()
+                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
+                    .count(),
+                1
+            );
+
+            // invalid nodes and name
+            let invalid = SecretString::from_str("invalid").unwrap();
+            assert!(matches!(
+                fs.rename(0, &invalid, 0, &invalid).await,
+                Err(FsError::InodeNotFound)
+            ));
+            let existing_file = SecretString::from_str("existing-file").unwrap();
+            let (_, attr_file) = fs
+                .create(
+                    ROOT_INODE,
+                    &existing_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.rename(attr_file.ino, &invalid, 0, &invalid).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &invalid, ROOT_INODE, &invalid).await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &existing_file, 0, &invalid).await,
+                Err(FsError::InodeNotFound)
+            ));
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &existing_file, attr_file.ino, &invalid)
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_into_own_subtree() {
+    run_test(
+        TestSetup {
+            key: "test_rename_into_own_subtree",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let a = SecretString::from_str("a").unwrap();
+            let b = SecretString::from_str("b").unwrap();
+            let c = SecretString::from_str("c").unwrap();
+            let (_, a_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &a,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, b_attr) = fs
+                .create(
+                    a_attr.ino,
+                    &b,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, c_attr) = fs
+                .create(
+                    b_attr.ino,
+                    &c,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // `/a` into `/a/b`, `/a/b/c` and itself
+            for new_parent in [b_attr.ino, c_attr.ino, a_attr.ino] {
+                assert!(matches!(
+                    fs.rename(ROOT_INODE, &a, new_parent, &a).await,
+                    Err(FsError::InvalidInput(_))
+                ));
+            }
+            assert!(fs.exists_by_name(ROOT_INODE, &a).unwrap());
+            assert_eq!(list_names(&fs, b_attr.ino).await, vec![".", "..", "c"]);
+
+            // moving across directories still works, also down the tree of another directory
+            fs.rename(b_attr.ino, &c, ROOT_INODE, &c).await.unwrap();
+            fs.rename(ROOT_INODE, &c, b_attr.ino, &c).await.unwrap();
+            fs.rename(a_attr.ino, &b, ROOT_INODE, &b).await.unwrap();
+            assert_eq!(
+                fs.find_by_name(b_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                ROOT_INODE
+            );
+            fs.rename(ROOT_INODE, &a, c_attr.ino, &a).await.unwrap();
+            assert_eq!(list_names(&fs, c_attr.ino).await, vec![".", "..", "a"]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_nlink() {
+    run_test(
+        TestSetup {
+            key: "test_dir_nlink",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let create = |parent: u64, name: &str, kind: FileType| {
+                let fs = fs.clone();
+                let name = SecretString::from_str(name).unwrap();
+                async move {
+                    fs.create(parent, &name, create_attr(kind), false, false)
+                        .await
+                        .unwrap()
+                        .1
+                }
+            };
+            let nlink = |ino: u64| {
+                let fs = fs.clone();
+                async move { fs.get_attr(ino).await.unwrap().nlink }
+            };
+
+            let dir = create(ROOT_INODE, "dir", FileType::Directory).await;
+            assert_eq!(2, dir.nlink);
+            assert_eq!(3, nlink(ROOT_INODE).await);
+            let n = 5;
+            for i in 0..n {
+                create(dir.ino, &format!("dir-{i}"), FileType::Directory).await;
+            }
+            // files don't link to the directory
+            create(dir.ino, "file", FileType::RegularFile).await;
+            assert_eq!(n + 2, nlink(dir.ino).await);
+
+            fs.remove_dir(dir.ino, &SecretString::from_str("dir-0").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(n + 1, nlink(dir.ino).await);
+
+            // moved to another directory
+            fs.rename(
+                dir.ino,
+                &SecretString::from_str("dir-1").unwrap(),
+                ROOT_INODE,
+                &SecretString::from_str("dir-1").unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(n, nlink(dir.ino).await);
+            assert_eq!(4, nlink(ROOT_INODE).await);
+            // renamed in the same directory
+            fs.rename(
+                dir.ino,
+                &SecretString::from_str("dir-2").unwrap(),
+                dir.ino,
+                &SecretString::from_str("dir-2-renamed").unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(n, nlink(dir.ino).await);
+            // over an empty directory, which is removed
+            fs.rename(
+                dir.ino,
+                &SecretString::from_str("dir-3").unwrap(),
+                dir.ino,
+                &SecretString::from_str("dir-4").unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(n - 1, nlink(dir.ino).await);
+            // over an empty directory in another one
+            fs.rename(
+                dir.ino,
+                &SecretString::from_str("dir-4").unwrap(),
+                ROOT_INODE,
+                &SecretString::from_str("dir-1").unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(n - 2, nlink(dir.ino).await);
+            assert_eq!(4, nlink(ROOT_INODE).await);
+
+            assert!(fs.verify().await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_over_opened_file() {
+    run_test(
+        TestSetup {
+            key: "test_rename_over_opened_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let file = SecretString::from_str("file").unwrap();
+            let tmp_file = SecretString::from_str("file.tmp").unwrap();
+
+            let (fh, old_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, old_attr.ino, 0, b"test-37", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let read_fh = fs.open(old_attr.ino, true, false).await.unwrap();
+
+            // like editors save, write a temp file and rename it over the original
+            let (fh, new_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &tmp_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, new_attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.rename(ROOT_INODE, &tmp_file, ROOT_INODE, &file)
+                .await
+                .unwrap();
+
+            assert!(!fs.exists_by_name(ROOT_INODE, &tmp_file).unwrap());
+            let attr = fs.find_by_name(ROOT_INODE, &file).await.unwrap().unwrap();
+            assert_eq!(attr.ino, new_attr.ino);
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+
+            // the opened handle still reads the replaced content
+            let mut buf = [0; 7];
+            fs.read(old_attr.ino, 0, &mut buf, read_fh).await.unwrap();
+            assert_eq!(&buf, b"test-37");
+            assert!(fs.exists(old_attr.ino));
+            // and it's removed after it's released
+            fs.release(read_fh).await.unwrap();
+            assert!(!fs.exists(old_attr.ino));
+            assert!(!fs.contents_path(old_attr.ino).exists());
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+
+            // without opened handles the replaced file is removed right away
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &tmp_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(fh, 0);
+            fs.rename(ROOT_INODE, &tmp_file, ROOT_INODE, &file)
+                .await
+                .unwrap();
+            assert!(!fs.exists(new_attr.ino));
+            assert_eq!(
+                fs.find_by_name(ROOT_INODE, &file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                attr.ino
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open() {
+    run_test(
+        TestSetup {
+            key: "test_open",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            // single read
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert_ne!(fh, 0);
+            // multiple read
+            let fh_2 = fs.open(attr.ino, true, false).await.unwrap();
+            assert_ne!(fh_2, 0);
+            // write and read
+            let _ = fs.open(attr.ino, false, true).await.unwrap();
+            // ensure cannot open multiple write
+            assert!(matches!(
+                fs.open(attr.ino, false, true).await,
+                Err(FsError::AlreadyOpenForWrite)
+            ));
+        },
+    )
+    .await;
+}
+
+// #[tokio::test]
+// #[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn _test_sample() {
+    run_test(
+        TestSetup {
+            key: "test_sample",
+            read_only: false,
+        },
+        async {
+            let _ = get_fs().await;
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_read_only_create() {
+    run_test(
+        TestSetup {
+            key: "test_read_only_create",
+            read_only: true,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // Check creating a file in a read only fs
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let create_file_result = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    false,
+                )
+                .await;
+            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_read_only_write() {
+    run_test(
+        TestSetup {
+            key: "read_only_test_write",
+            read_only: false,
+        },
+        async {
+            let fs_rw = get_fs().await;
+            let data_dir = fs_rw.data_dir.clone();
+            let cipher = Cipher::ChaCha20Poly1305;
+            let file1 = SecretString::from_str("file1").unwrap();
+            let file_dest = SecretString::from_str("file_dest").unwrap();
+            let dir1 = SecretString::from_str("dir1").unwrap();
+            let data = "Hello, world!";
+
+            let (fh, attr) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &file1,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating file.");
+            let (fh_dest, attr_dest) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &file_dest,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating file.");
+            let (_, _) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &dir1,
+                    create_attr(FileType::Directory),
+                    false,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating dir.");
+
+            // Create a succesful write on the file
+            crate::encryptedfs::write_all_string_to_fs(&fs_rw, attr.ino, 0, data, fh)
+                .await
+                .unwrap();
+            fs_rw.flush(fh).await.unwrap();
+            fs_rw.release(fh).await.unwrap();
+            drop(fs_rw);
+            let fs_ro = EncryptedFs::new(data_dir, Box::new(PasswordProviderImpl {}), cipher, true)
+                .await
+                .expect("test_read_only_write: Error creating rw fs.");
+            let fh = fs_ro
+                .open(attr.ino, true, false)
+                .await
+                .expect("read_only_test_create: Error opening file in ro.");
+
+            // Test a succesful reading the file in rw mode
+            let mut buf = vec![0; data.len()];
+            fs_ro.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(data, String::from_utf8(buf).unwrap());
+
+            // Test creating a file
+            let file2 = SecretString::from_str("file2").unwrap();
+            let create_file_result = fs_ro
+                .create(
+                    ROOT_INODE,
+                    &file2,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await;
+            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+            // Test renaming the file
+            let new_file = SecretString::from_str("file1").unwrap();
+            let rename_result = fs_ro
+                .rename(ROOT_INODE, &file1, ROOT_INODE, &new_file)
+                .await;
+            assert!(matches!(rename_result, Err(FsError::ReadOnly)));
+            // Test removing a file
+            let remove_file_result = fs_ro.remove_file(ROOT_INODE, &file1).await;
+            assert!(matches!(remove_file_result, Err(FsError::ReadOnly)));
+            // Test copy file range
+            let file_range_req = CopyFileRangeReq::builder()
+                .src_ino(attr.ino)
+                .src_offset(0)
+                .dest_ino(attr_dest.ino)
+                .dest_offset(0)
+                .src_fh(fh)
+                .dest_fh(fh_dest)
+                .build();
+            let copy_file_range_result = fs_ro.copy_file_range(&file_range_req, data.len()).await;
+            assert!(matches!(copy_file_range_result, Err(FsError::ReadOnly)));
+            // Test removing a dir
+            let remove_dir_result = fs_ro.remove_dir(ROOT_INODE, &dir1).await;
+            assert!(matches!(remove_dir_result, Err(FsError::ReadOnly)));
+            // Test changing the length of the file
+            let set_len_result = fs_ro.set_len(attr.ino, 55).await;
+            assert!(matches!(set_len_result, Err(FsError::ReadOnly)));
+            // Test setting attr of a file
+            let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
+            let set_attr_result = fs_ro.set_attr(attr.ino, set_attr).await;
+            assert!(matches!(set_attr_result, Err(FsError::ReadOnly)));
+            // Test writing to file with Read Only enabled.
+            let write_all_strings_result =
+                crate::encryptedfs::write_all_string_to_fs(&fs_ro, attr.ino, 0, data, fh).await;
+            assert!(matches!(write_all_strings_result, Err(FsError::ReadOnly)));
+            // Test flushing data to file
+            let flush_result = fs_ro.flush(fh).await;
+            assert!(matches!(flush_result, Err(FsError::ReadOnly)));
+        },
+    )
+    .await;
+}
+
+/// Config of a volume with the content of files transformed.
+fn transform_config() -> FsConfig {
+    FsConfig {
+        format: Some(VolumeFormat {
+            content_transform: Some(XOR_TRANSFORM_ID),
+            ..VolumeFormat::default()
+        }),
+        content_transforms: vec![Arc::new(XorTransform)],
+        ..FsConfig::default()
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_content_transform() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_content_transform",
+            read_only: false,
+        },
+        transform_config(),
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert!(fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(format!("{}{CONTENT_TRANSFORM_SUFFIX}", attr.ino))
+                .is_file());
+            let data = "test-42".repeat(1000);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
+
+            fs.set_len(attr.ino, 5).await.unwrap();
+            assert_eq!("test-", test_common::read_to_string(attr.ino, &fs).await);
+
+            // the data dir can't be opened without the transform it was created with
+            assert!(matches!(
+                EncryptedFs::new(
+                    fs.data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    true,
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(format!("{}{CONTENT_TRANSFORM_SUFFIX}", attr.ino))
+                .exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_clock_skew() {
+    run_test(
+        TestSetup {
+            key: "test_clock_skew",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // times set before the clock jumped backward
+            let future = SystemTime::now() + Duration::from_secs(60 * 60);
+            fs.set_attr(attr.ino, SetFileAttr::default().with_mtime(future))
+                .await
+                .unwrap();
+            assert_eq!(future, fs.get_attr(attr.ino).await.unwrap().mtime);
+
+            // after the clock jumped backward new times are ignored
+            let now = SystemTime::now();
+            fs.set_attr(attr.ino, SetFileAttr::default().with_mtime(now))
+                .await
+                .unwrap();
+            assert_eq!(future, fs.get_attr(attr.ino).await.unwrap().mtime);
+            assert!(logs_contain("possible clock skew"));
+
+            // overwrite restores correct times
+            fs.set_attr_overwrite(
+                attr.ino,
+                SetFileAttr::default().with_mtime(now).with_atime(now),
+            )
+            .await
+            .unwrap();
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(now, attr.mtime);
+            assert_eq!(now, attr.atime);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_names_not_logged() {
+    run_test(
+        TestSetup {
+            key: "test_names_not_logged",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = "plaintext-secret-name";
+            let new_name = "plaintext-secret-new-name";
+            let file = SecretString::from_str(name).unwrap();
+            let new_file = SecretString::from_str(new_name).unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(fs.find_by_name(ROOT_INODE, &file).await.unwrap().is_some());
+            fs.rename(ROOT_INODE, &file, ROOT_INODE, &new_file)
+                .await
+                .unwrap();
+            for entry in fs.read_dir_plus(ROOT_INODE).await.unwrap() {
+                tracing::debug!(?entry, "entry");
+            }
+            let err = fs.remove_file(ROOT_INODE, &file).await.unwrap_err();
+            tracing::error!(%err, ?file, "remove");
+            fs.remove_file(ROOT_INODE, &new_file).await.unwrap();
+
+            assert!(logs_contain("entry"));
+            assert!(!logs_contain(name));
+            assert!(!logs_contain(new_name));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_restore_file() {
+    run_test(
+        TestSetup {
+            key: "test_restore_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let other_file = SecretString::from_str("other-file").unwrap();
+            let (fh2, attr2) = fs
+                .create(
+                    ROOT_INODE,
+                    &other_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr2.ino, 0, b"other-42", fh2)
+                .await
+                .unwrap();
+            fs.flush(fh2).await.unwrap();
+
+            let snapshot_attr = fs.get_attr(attr.ino).await.unwrap();
+            let snapshot_id = fs.snapshot().await.unwrap();
+            assert_eq!(vec![snapshot_id], fs.snapshots().await.unwrap());
+
+            // modify after snapshot
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"modified-37", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr2.ino, 0, b"other-37", fh2)
+                .await
+                .unwrap();
+            fs.flush(fh2).await.unwrap();
+            fs.release(fh2).await.unwrap();
+            assert_eq!(
+                "modified-37",
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+
+            fs.restore_file(attr.ino, snapshot_id).await.unwrap();
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+            let restored_attr = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(snapshot_attr.size, restored_attr.size);
+            assert_eq!(snapshot_attr.mtime, restored_attr.mtime);
+            // other files are not affected
+            assert_eq!(
+                "other-37",
+                test_common::read_to_string(attr2.ino, &fs).await
+            );
+
+            assert!(matches!(
+                fs.restore_file(attr.ino, snapshot_id + 1).await,
+                Err(FsError::NotFound(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_new_readonly_at() {
+    run_test(
+        TestSetup {
+            key: "test_new_readonly_at",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+
+            // still opened for write
+            let snapshot_id = fs.snapshot().await.unwrap();
+            #[cfg(unix)]
+            assert_eq!(
+                2,
+                fs_util::hard_links(&std::fs::metadata(fs.contents_path(attr.ino)).unwrap())
+            );
+
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"modified-37", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                "modified-37",
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+            // the content was copied before it was changed
+            #[cfg(unix)]
+            assert_eq!(
+                1,
+                fs_util::hard_links(&std::fs::metadata(fs.contents_path(attr.ino)).unwrap())
+            );
+
+            let snapshot_fs = EncryptedFs::new_readonly_at(
+                fs.data_dir.clone(),
+                snapshot_id,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                FsConfig::default(),
+            )
+            .await
+            .unwrap();
+            let snapshot_attr = snapshot_fs
+                .find_by_name(ROOT_INODE, &test_file)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(7, snapshot_attr.size);
+            assert_eq!(
+                "test-42",
+                test_common::read_to_string(snapshot_attr.ino, &snapshot_fs).await
+            );
+            assert!(matches!(
+                snapshot_fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str("new-file").unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await,
+                Err(FsError::ReadOnly)
+            ));
+
+            assert!(matches!(
+                EncryptedFs::new_readonly_at(
+                    fs.data_dir.clone(),
+                    snapshot_id + 1,
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    FsConfig::default(),
+                )
+                .await,
+                Err(FsError::NotFound(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_crypto_threads() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_crypto_threads",
+            read_only: false,
+        },
+        FsConfig {
+            crypto_threads: NonZeroUsize::new(2),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+
+            // more concurrent operations than threads, to make sure waiting for the pool doesn't deadlock
+            let mut join_set = JoinSet::new();
+            for i in 0..8 {
+                let fs = fs.clone();
+                join_set.spawn(async move {
+                    let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &name,
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    let data = format!("test-{i}-").repeat(100);
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                        .await
+                        .unwrap();
+                    fs.flush(fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+                });
+            }
+            tokio::time::timeout(Duration::from_secs(60), async {
+                while let Some(res) = join_set.join_next().await {
+                    res.unwrap();
+                }
+            })
+            .await
+            .expect("crypto pool deadlocked");
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lost_reader_and_writer() {
+    run_test(
+        TestSetup {
+            key: "test_lost_reader_and_writer",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+
+            // like when the task encrypting it failed or the write was cancelled
+            fs.write_handle(fh).await.unwrap().lock().await.writer = None;
+            write_all_bytes_to_fs(&fs, attr.ino, 7, b"-more", fh)
+                .await
+                .unwrap();
+            fs.write_handle(fh).await.unwrap().lock().await.writer = None;
+            // what was not flushed is lost, the rest is still there
+            fs.release(fh).await.unwrap();
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 7];
+            fs.read_handle(fh).await.unwrap().lock().await.reader = None;
+            assert_eq!(7, fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+            assert_eq!(b"test-42", &buf);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_check_special_entries() {
+    run_test(
+        TestSetup {
+            key: "test_check_special_entries",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (_, dir1) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir1").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, dir2) = fs
+                .create(
+                    dir1.ino,
+                    &SecretString::from_str("dir2").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(fs.check().await.unwrap().is_clean());
+
+            // corrupt `$..` of dir2 to point to root instead of dir1
+            fs.insert_directory_entry(
+                dir2.ino,
+                &DirectoryEntry {
+                    ino: ROOT_INODE,
+                    name: SecretString::from_str("$..").unwrap(),
+                    kind: FileType::Directory,
+                },
+            )
+            .await
+            .unwrap();
+            let parent = SecretString::from_str("..").unwrap();
+            assert_eq!(
+                ROOT_INODE,
+                fs.find_by_name(dir2.ino, &parent)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            // and remove `$.` of dir1
+            std::fs::remove_file(fs.contents_path(dir1.ino).join(LS_DIR).join("$.")).unwrap();
+
+            let report = fs.check().await.unwrap();
+            assert_eq!(vec![dir1.ino], report.wrong_self_entries);
+            assert_eq!(vec![dir2.ino], report.wrong_parent_entries);
+            assert!(report.repaired);
+
+            assert_eq!(
+                dir1.ino,
+                fs.find_by_name(dir2.ino, &parent)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert!(fs.read_dir(dir1.ino).await.unwrap().any(|entry| entry
+                .unwrap()
+                .name
+                .expose_secret()
+                == "."));
+            assert!(fs.check().await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_check_names_supported() {
+    run_test(
+        TestSetup {
+            key: "test_check_names_supported",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let contents = fs.data_dir.join(CONTENTS_DIR);
+            let count = || std::fs::read_dir(&contents).unwrap().count();
+            let before = count();
+            check_names_supported(&*fs.storage, &fs.data_dir).unwrap();
+            // the probe is removed
+            assert_eq!(before, count());
+
+            // an existing file is kept as it is
+            let name = "test|+Aa";
+            std::fs::write(contents.join(name), b"test-42").unwrap();
+            assert!(fs_util::can_create(&contents, name).unwrap());
+            assert_eq!(
+                b"test-42".to_vec(),
+                std::fs::read(contents.join(name)).unwrap()
+            );
+            std::fs::remove_file(contents.join(name)).unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_verify_and_repair() {
+    run_test(
+        TestSetup {
+            key: "test_verify_and_repair",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let create = |parent: u64, name: &str, kind: FileType| {
+                let fs = fs.clone();
+                let name = SecretString::from_str(name).unwrap();
+                async move {
+                    fs.create(parent, &name, create_attr(kind), false, false)
+                        .await
+                        .unwrap()
+                        .1
+                }
+            };
+            let dir1 = create(ROOT_INODE, "dir1", FileType::Directory).await;
+            let dir2 = create(dir1.ino, "dir2", FileType::Directory).await;
+            let file1 = create(dir1.ino, "file1", FileType::RegularFile).await;
+            let file2 = create(ROOT_INODE, "file2", FileType::RegularFile).await;
+            let file3 = create(dir1.ino, "file3", FileType::RegularFile).await;
+            let file4 = create(ROOT_INODE, "file4", FileType::RegularFile).await;
+            let fh = fs.open(file1.ino, false, true).await.unwrap();
+            crate::encryptedfs::write_all_string_to_fs(&fs, file1.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.link(
+                file1.ino,
+                ROOT_INODE,
+                &SecretString::from_str("link1").unwrap(),
+            )
+            .await
+            .unwrap();
+            fs.create_symlink(
+                dir2.ino,
+                &SecretString::from_str("symlink").unwrap(),
+                &SecretString::from_str("../file1").unwrap(),
+            )
+            .await
+            .unwrap();
+            assert!(fs.verify().await.unwrap().is_clean());
+
+            // `file2` can't be found by name anymore
+            let file2_name = SecretString::from_str("file2").unwrap();
+            std::fs::remove_file(
+                fs.contents_path(ROOT_INODE)
+                    .join(HASH_DIR)
+                    .join(crypto::hash_file_name(&file2_name)),
+            )
+            .unwrap();
+            // `file3` entry points to nothing
+            std::fs::remove_file(fs.ino_file(file3.ino)).unwrap();
+            // `file4` is not listed anymore
+            fs.remove_directory_entry(ROOT_INODE, &SecretString::from_str("file4").unwrap())
+                .await
+                .unwrap();
+            // `$..` of dir2 points to root instead of dir1
+            fs.insert_directory_entry(
+                dir2.ino,
+                &DirectoryEntry {
+                    ino: ROOT_INODE,
+                    name: SecretString::from_str("$..").unwrap(),
+                    kind: FileType::Directory,
+                },
+            )
+            .await
+            .unwrap();
+            // `dir1` doesn't count the link from `..` of `dir2`
+            let mut attr = fs.get_inode_from_storage(dir1.ino).await.unwrap();
+            attr.nlink = 2;
+            fs.write_inode_to_storage(&attr).await.unwrap();
+            // garbage inode
+            let garbage = fs.ino_file(42);
+            std::fs::write(&garbage, b"garbage").unwrap();
+
+            let report = fs.verify().await.unwrap();
+            // nothing is changed
+            assert_eq!(report, fs.verify().await.unwrap());
+            assert_eq!(vec![file4.ino], report.orphaned_inodes);
+            assert_eq!(vec![file3.ino], report.orphaned_contents);
+            assert!(report.invalid_contents.is_empty());
+            assert_eq!(vec![garbage.clone()], report.undecryptable);
+            assert!(report.wrong_self_entries.is_empty());
+            assert_eq!(vec![(dir2.ino, dir1.ino)], report.wrong_parent_entries);
+            assert_eq!(vec![(dir1.ino, 3)], report.wrong_nlinks);
+            let mut dangling: Vec<_> = report
+                .dangling_entries
+                .iter()
+                .map(|entry| (entry.dir, entry.reason))
+                .collect();
+            dangling.sort_by_key(|(dir, _)| *dir == ROOT_INODE);
+            assert_eq!(
+                vec![
+                    (dir1.ino, DanglingReason::MissingInode),
+                    (ROOT_INODE, DanglingReason::MissingHash)
+                ],
+                dangling
+            );
+
+            fs.repair(&report).await.unwrap();
+            let report = fs.verify().await.unwrap();
+            // undecryptable files are left as they are
+            assert_eq!(vec![garbage.clone()], report.undecryptable);
+            std::fs::remove_file(&garbage).unwrap();
+            assert!(fs.verify().await.unwrap().is_clean());
+
+            assert_eq!(
+                file2.ino,
+                fs.find_by_name(ROOT_INODE, &file2_name)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert!(!fs
+                .exists_by_name(dir1.ino, &SecretString::from_str("file3").unwrap())
+                .unwrap());
+            assert!(!fs.exists(file4.ino));
+            assert_eq!(
+                dir1.ino,
+                fs.find_by_name(dir2.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!("test-42", test_common::read_to_string(file1.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_times_write_back() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_times_write_back",
+            read_only: false,
+        },
+        FsConfig {
+            times_write_back: Some(Duration::from_secs(60 * 60)),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let ino_file = fs.ino_file(attr.ino);
+            let stored = std::fs::read(&ino_file).unwrap();
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+
+            // only times changed, inode is not rewritten but get_attr sees them
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+            fs.set_attr(
+                attr.ino,
+                SetFileAttr::default().with_mtime(SystemTime::now()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(stored, std::fs::read(&ino_file).unwrap());
+            let pending = fs.get_attr(attr.ino).await.unwrap();
+            assert!(pending.atime > attr.atime);
+            assert!(pending.mtime > attr.mtime);
+
+            fs.flush_times().await.unwrap();
+            assert_ne!(stored, std::fs::read(&ino_file).unwrap());
+            assert_eq!(pending, fs.get_inode_from_storage(attr.ino).await.unwrap());
+
+            // other changes are written right away together with pending times
+            fs.set_attr(
+                attr.ino,
+                SetFileAttr::default().with_mtime(SystemTime::now()),
+            )
+            .await
+            .unwrap();
+            let pending = fs.get_attr(attr.ino).await.unwrap();
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            let stored = fs.get_inode_from_storage(attr.ino).await.unwrap();
+            assert_eq!(stored.perm, 0o600);
+            assert!(stored.mtime >= pending.mtime);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_check_access() {
+    run_test(
+        TestSetup {
+            key: "test_check_access",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_perm(0o640)
+                    .with_uid(1000)
+                    .with_gid(100),
+            )
+            .await
+            .unwrap();
+
+            // owner
+            fs.check_access(attr.ino, 1000, 100, 0o6).await.unwrap();
+            assert!(matches!(
+                fs.check_access(attr.ino, 1000, 100, 0o1).await,
+                Err(FsError::AccessDenied)
+            ));
+            // group
+            fs.check_access(attr.ino, 1001, 100, 0o4).await.unwrap();
+            assert!(matches!(
+                fs.check_access(attr.ino, 1001, 100, 0o2).await,
+                Err(FsError::AccessDenied)
+            ));
+            // other
+            fs.check_access(attr.ino, 1001, 101, 0).await.unwrap();
+            assert!(matches!(
+                fs.check_access(attr.ino, 1001, 101, 0o4).await,
+                Err(FsError::AccessDenied)
+            ));
+            // root can read and write, but execute only if some execute bit is set
+            fs.check_access(attr.ino, 0, 0, 0o6).await.unwrap();
+            assert!(matches!(
+                fs.check_access(attr.ino, 0, 0, 0o1).await,
+                Err(FsError::AccessDenied)
+            ));
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o641))
+                .await
+                .unwrap();
+            fs.check_access(attr.ino, 0, 0, 0o1).await.unwrap();
+
+            assert!(matches!(
+                fs.check_access(42_u64 << 32, 1000, 100, 0).await,
+                Err(FsError::InodeNotFound)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_immutable_flag() {
+    run_test(
+        TestSetup {
+            key: "test_immutable_flag",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            fs.set_attr(attr.ino, SetFileAttr::default().with_flags(FS_IMMUTABLE_FL))
+                .await
+                .unwrap();
+            assert!(fs.get_attr(attr.ino).await.unwrap().is_immutable());
+
+            // handles opened before still cannot write
+            assert!(matches!(
+                fs.write(attr.ino, 0, b"test-37", fh).await,
+                Err(FsError::NotPermitted)
+            ));
+            fs.release(fh).await.unwrap();
+            assert!(matches!(
+                fs.open(attr.ino, false, true).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.set_len(attr.ino, 0).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.remove_file(ROOT_INODE, &name).await,
+                Err(FsError::NotPermitted)
+            ));
+            let new_name = SecretString::from_str("test-file-2").unwrap();
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &name, ROOT_INODE, &new_name).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+
+            // clearing the flag allows changes again
+            fs.set_attr(attr.ino, SetFileAttr::default().with_flags(0))
+                .await
+                .unwrap();
+            fs.set_len(attr.ino, 0).await.unwrap();
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_append_only_flag() {
+    run_test(
+        TestSetup {
+            key: "test_append_only_flag",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_attr(attr.ino, SetFileAttr::default().with_flags(FS_APPEND_FL))
+                .await
+                .unwrap();
+
+            // writes go at the end whatever the offset
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"-37", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                "test-42-37",
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+
+            assert!(matches!(
+                fs.set_len(attr.ino, 0).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.set_len(attr.ino, 100).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.remove_file(ROOT_INODE, &name).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 10);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_metadata_snapshot() {
+    run_test(
+        TestSetup {
+            key: "test_metadata_snapshot",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut inos = vec![];
+            for i in 0..2 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        dir_attr.ino,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+
+            let snapshot = fs.metadata_snapshot().await.unwrap();
+            assert_eq!(
+                snapshot.iter().map(|(ino, _)| *ino).collect::<Vec<_>>(),
+                vec![ROOT_INODE, dir_attr.ino, inos[0], inos[1]]
+            );
+            for (ino, attr) in &snapshot {
+                assert_eq!(attr.ino, *ino);
+                assert_eq!(attr.size, fs.get_attr(*ino).await.unwrap().size);
+            }
+
+            let fh = fs.open(inos[1], false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, inos[1], 7, b"-test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.remove_file(
+                dir_attr.ino,
+                &SecretString::from_str("test-file-0").unwrap(),
+            )
+            .await
+            .unwrap();
+
+            let snapshot2 = fs.metadata_snapshot().await.unwrap();
+            assert_eq!(
+                snapshot2.iter().map(|(ino, _)| *ino).collect::<Vec<_>>(),
+                vec![ROOT_INODE, dir_attr.ino, inos[1]]
+            );
+            let (_, attr) = snapshot2.last().unwrap();
+            assert_eq!(attr.size, 15);
+            assert_ne!(
+                (attr.size, attr.mtime),
+                (snapshot[3].1.size, snapshot[3].1.mtime)
+            );
+        },
+    )
+    .await;
+}
+
+#[derive(Default)]
+struct CountingObserver {
+    reads: AtomicU64,
+    read_bytes: AtomicU64,
+    writes: AtomicU64,
+    written_bytes: AtomicU64,
+    attr_hits: AtomicU64,
+    attr_misses: AtomicU64,
+    decrypt_failures: AtomicU64,
+    opened_handles: AtomicU64,
+}
+
+impl FsObserver for CountingObserver {
+    fn on_read(&self, _ino: u64, len: usize, _duration: Duration) {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.read_bytes.fetch_add(len as u64, Ordering::SeqCst);
+    }
+
+    fn on_write(&self, _ino: u64, len: usize, _duration: Duration) {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.written_bytes.fetch_add(len as u64, Ordering::SeqCst);
+    }
+
+    fn on_cache_hit(&self, kind: CacheKind) {
+        if kind == CacheKind::Attr {
+            self.attr_hits.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn on_cache_miss(&self, kind: CacheKind) {
+        if kind == CacheKind::Attr {
+            self.attr_misses.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn on_decrypt_failure(&self, _ino: u64) {
+        self.decrypt_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_handle_opened(&self, _ino: u64) {
+        self.opened_handles.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_handle_released(&self, _ino: u64) {
+        self.opened_handles.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_observer() {
+    let observer = Arc::new(CountingObserver::default());
+    run_test_with_config(
+        TestSetup {
+            key: "test_observer",
+            read_only: false,
+        },
+        FsConfig {
+            observer: Some(observer.clone()),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert_eq!(observer.opened_handles.load(Ordering::SeqCst), 1);
+            let data = vec![42; BLOCK_SIZE * 2];
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(observer.opened_handles.load(Ordering::SeqCst), 0);
+            assert!(observer.writes.load(Ordering::SeqCst) > 0);
+            assert_eq!(
+                observer.written_bytes.load(Ordering::SeqCst),
+                data.len() as u64
+            );
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; BLOCK_SIZE];
+            assert_eq!(
+                fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(),
+                BLOCK_SIZE
+            );
+            assert_eq!(observer.reads.load(Ordering::SeqCst), 1);
+            assert_eq!(
+                observer.read_bytes.load(Ordering::SeqCst),
+                BLOCK_SIZE as u64
+            );
+            assert!(observer.attr_hits.load(Ordering::SeqCst) > 0);
+            assert!(observer.attr_misses.load(Ordering::SeqCst) > 0);
+
+            // change the tag of the last block
+            let path = fs.contents_path(attr.ino);
+            let mut content = std::fs::read(&path).unwrap();
+            *content.last_mut().unwrap() ^= 1;
+            std::fs::write(&path, &content).unwrap();
+            assert!(fs
+                .read(attr.ino, BLOCK_SIZE as u64, &mut buf, fh)
+                .await
+                .is_err());
+            assert_eq!(observer.decrypt_failures.load(Ordering::SeqCst), 1);
+            assert_eq!(observer.reads.load(Ordering::SeqCst), 1);
+            fs.release(fh).await.unwrap();
+            assert_eq!(observer.opened_handles.load(Ordering::SeqCst), 0);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_clear_caches() {
+    run_test(
+        TestSetup {
+            key: "test_clear_caches",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (fh, _) = fs
+                .create(
+                    dir.ino,
+                    &SecretString::from_str("test-child").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // another instance sharing the data dir read-only keeps seeing the cached attributes
+            let fs2 = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            let perm = fs2.get_attr(attr.ino).await.unwrap().perm;
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            assert_eq!(fs2.get_attr(attr.ino).await.unwrap().perm, perm);
+            fs2.invalidate_inode(attr.ino).await.unwrap();
+            assert_eq!(fs2.get_attr(attr.ino).await.unwrap().perm, 0o600);
+
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o640))
+                .await
+                .unwrap();
+            assert_eq!(fs2.get_attr(attr.ino).await.unwrap().perm, 0o600);
+            fs2.clear_caches().await.unwrap();
+            assert_eq!(fs2.get_attr(attr.ino).await.unwrap().perm, 0o640);
+
+            // entries are read again after the directory is invalidated
+            assert_eq!(
+                list_names(&fs2, dir.ino).await,
+                vec![".", "..", "test-child"]
+            );
+            fs2.invalidate_inode(dir.ino).await.unwrap();
+            assert_eq!(
+                list_names(&fs2, dir.ino).await,
+                vec![".", "..", "test-child"]
+            );
+
+            // clearing while other operations use the caches
+            let mut join_set = JoinSet::new();
+            for _ in 0..4 {
+                let fs2 = fs2.clone();
+                join_set.spawn(async move {
+                    for _ in 0..50 {
+                        assert_eq!(fs2.get_attr(attr.ino).await.unwrap().perm, 0o640);
+                        assert_eq!(list_names(&fs2, dir.ino).await.len(), 3);
+                    }
+                });
+            }
+            for _ in 0..50 {
+                fs2.clear_caches().await.unwrap();
+                fs2.invalidate_inode(dir.ino).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            while let Some(res) = join_set.join_next().await {
+                res.unwrap();
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_file_info() {
+    run_test(
+        TestSetup {
+            key: "test_file_info",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data = vec![42_u8; BLOCK_SIZE * 2 + 100];
+            let block_len = ring::aead::NONCE_LEN + BLOCK_SIZE + fs.ciphers.cipher().tag_len();
+
+            let transformed_dir = tempfile::tempdir().unwrap();
+            let transformed_fs = EncryptedFs::new_with_config(
+                transformed_dir.path().join("data"),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                transform_config(),
+            )
+            .await
+            .unwrap();
+            let mut inos = vec![];
+            for fs in [&fs, &transformed_fs] {
+                let name = SecretString::from_str("test-file").unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+
+            let info = fs.file_info(inos[0]).await.unwrap();
+            assert_eq!(info.ino, inos[0]);
+            assert_eq!(info.cipher, fs.ciphers.cipher());
+            assert_eq!(info.format_version, CONTENT_FORMAT_VERSION);
+            assert_eq!(info.content_transform, None);
+            assert_eq!(info.size, data.len() as u64);
+            assert_eq!(info.blocks, 3);
+            assert_eq!(
+                info.disk_size,
+                (block_len * 2 + block_len - BLOCK_SIZE + 100) as u64
+            );
+            assert!(!info.sparse);
+
+            let info = transformed_fs.file_info(inos[1]).await.unwrap();
+            assert_eq!(info.content_transform, Some(XOR_TRANSFORM_ID));
+            assert_eq!(info.size, data.len() as u64);
+            assert_eq!(info.blocks, 3);
+            // each block has the frame header
+            let block_len = block_len + FRAME_HEADER_LEN;
+            assert_eq!(
+                info.disk_size,
+                (block_len * 2 + block_len - BLOCK_SIZE + 100) as u64
+            );
+
+            assert!(matches!(
+                fs.file_info(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_block_cache() {
+    if !Path::new("/dev/shm").is_dir() {
+        return;
+    }
+    let cache = Arc::new(BlockCache::new(Path::new("/dev/shm"), 4 * BLOCK_SIZE).unwrap());
+    run_test_with_config(
+        TestSetup {
+            key: "test_block_cache",
+            read_only: false,
+        },
+        FsConfig {
+            block_cache: Some(cache.clone()),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "test-42".repeat(BLOCK_SIZE / 4);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // first read decrypts and caches the blocks, the second one is served from cache
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+            let stats = cache.stats();
+            assert_eq!(stats.hits, 0);
+            assert!(stats.misses > 0);
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+            assert!(cache.stats().hits > 0);
+            assert_eq!(cache.stats().misses, stats.misses);
+
+            // read spanning two blocks
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 7];
+            let offset = BLOCK_SIZE as u64 - 3;
+            let len = fs.read(attr.ino, offset, &mut buf, fh).await.unwrap();
+            assert_eq!(len, 7);
+            #[allow(clippy::cast_possible_truncation)]
+            let offset = offset as usize;
+            assert_eq!(&buf, &data.as_bytes()[offset..offset + 7]);
+            fs.release(fh).await.unwrap();
+
+            // write invalidates the cached blocks
+            let generation = cache.generation(attr.ino);
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"TEST-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(cache.generation(attr.ino) > generation);
+            let stats = cache.stats();
+            let new_data = format!("TEST-42{}", &data[7..]);
+            assert_eq!(new_data, test_common::read_to_string(attr.ino, &fs).await);
+            assert!(cache.stats().misses > stats.misses);
+        },
+    )
+    .await;
+}
+
+/// If the handle has something read ahead, or is reading ahead.
+async fn reading_ahead(fs: &EncryptedFs, fh: u64) -> bool {
+    let ctx = fs.read_handle(fh).await.unwrap();
+    let ctx = ctx.lock().await;
+    ctx.readahead.task.is_some() || !ctx.readahead.buf.data.is_empty()
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::cast_possible_truncation)]
+async fn test_readahead() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_readahead",
+            read_only: false,
+        },
+        FsConfig {
+            readahead_blocks: NonZeroUsize::new(4),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: String = (0..BLOCK_SIZE * 3).map(|i| format!("{i},")).collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let read_at = |offset: usize, len: usize| {
+                let fs = fs.clone();
+                async move {
+                    let mut buf = vec![0; len];
+                    let len = fs
+                        .read(attr.ino, offset as u64, &mut buf, fh)
+                        .await
+                        .unwrap();
+                    String::from_utf8(buf[..len].to_vec()).unwrap()
+                }
+            };
+
+            // sequential reads, in chunks not aligned to the blocks
+            let mut read = String::new();
+            while read.len() < data.len() {
+                let chunk = read_at(read.len(), 37).await;
+                assert!(!chunk.is_empty());
+                read.push_str(&chunk);
+            }
+            assert_eq!(data, read);
+            assert!(read_at(data.len(), 37).await.is_empty());
+
+            // random reads don't read ahead
+            for offset in [data.len() / 2, 42, data.len() - 100, 7] {
+                assert_eq!(&data[offset..offset + 50], read_at(offset, 50).await);
+                assert!(!reading_ahead(&fs, fh).await);
+            }
+            // sequential again
+            assert_eq!(&data[57..100], read_at(57, 43).await);
+            assert!(reading_ahead(&fs, fh).await);
+
+            // what was read ahead is dropped on write
+            let fh_write = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 100, b"TEST-42", fh_write)
+                .await
+                .unwrap();
+            fs.flush(fh_write).await.unwrap();
+            fs.release(fh_write).await.unwrap();
+            assert_eq!("TEST-42", read_at(100, 7).await);
+            assert_eq!(&data[107..200], read_at(107, 93).await);
+
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+async fn list_names(fs: &EncryptedFs, ino: u64) -> Vec<String> {
+    let mut names: Vec<String> = fs
+        .read_dir(ino)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_migrate_cipher() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_migrate_cipher",
+            read_only: false,
+        },
+        transform_config(),
+        async {
+            let fs = get_fs().await;
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut inos = vec![];
+            for i in 0..2 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        dir_attr.ino,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+            // keep a file opened while migrating
+            let read_fh = fs.open(inos[0], true, false).await.unwrap();
+            let write_fh = fs.open(inos[1], false, true).await.unwrap();
+            let names = list_names(&fs, dir_attr.ino).await;
+
+            let migrate = {
+                let fs = fs.clone();
+                tokio::spawn(async move { fs.migrate_cipher(Cipher::Aes256Gcm).await })
+            };
+            while !migrate.is_finished() {
+                assert_eq!(names, list_names(&fs, dir_attr.ino).await);
+                assert_eq!(data, test_common::read_to_string(inos[0], &fs).await);
+            }
+            migrate.await.unwrap().unwrap();
+            assert_eq!(fs.ciphers.cipher(), Cipher::Aes256Gcm);
+            assert!(fs.snapshot().await.is_ok());
+
+            assert_eq!(names, list_names(&fs, dir_attr.ino).await);
+            assert_eq!(
+                fs.find_by_name(
+                    dir_attr.ino,
+                    &SecretString::from_str("test-file-1").unwrap()
+                )
+                .await
+                .unwrap()
+                .unwrap()
+                .ino,
+                inos[1]
+            );
+            for ino in &inos {
+                assert_eq!(data, test_common::read_to_string(*ino, &fs).await);
+                assert_eq!(fs.file_info(*ino).await.unwrap().cipher, Cipher::Aes256Gcm);
+            }
+            // opened handles continue with the new cipher
+            let mut buf = vec![0; 7];
+            fs.read(inos[0], 0, &mut buf, read_fh).await.unwrap();
+            assert_eq!(&buf, &data.as_bytes()[..7]);
+            fs.release(read_fh).await.unwrap();
+            write_all_bytes_to_fs(&fs, inos[1], 0, b"TEST-42", write_fh)
+                .await
+                .unwrap();
+            fs.flush(write_fh).await.unwrap();
+            fs.release(write_fh).await.unwrap();
+            assert_eq!(
+                format!("TEST-42{}", &data[7..]),
+                test_common::read_to_string(inos[1], &fs).await
+            );
+
+            // new files use the new cipher
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-new").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                fs.file_info(attr.ino).await.unwrap().cipher,
+                Cipher::Aes256Gcm
+            );
+
+            // already migrated
+            fs.migrate_cipher(Cipher::Aes256Gcm).await.unwrap();
+
+            // the volume keeps the cipher it was migrated to
+            let data_dir = fs.data_dir.clone();
+            assert!(matches!(
+                EncryptedFs::new_with_config(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    transform_config(),
+                )
+                .await,
+                Err(FsError::CipherMismatch { .. })
+            ));
+            let fs = EncryptedFs::new_with_config(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::Aes256Gcm,
+                false,
+                transform_config(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(fs.ciphers.cipher(), Cipher::Aes256Gcm);
+            assert_eq!(names, list_names(&fs, dir_attr.ino).await);
+            assert_eq!(
+                format!("TEST-42{}", &data[7..]),
+                test_common::read_to_string(inos[1], &fs).await
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_migrate_cipher_resume() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_cipher_resume",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            let mut inos = vec![];
+            for i in 0..2 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+
+            // interrupted after migrating some of the files
+            fs.ciphers.start(Cipher::Aes256Gcm).unwrap();
+            let key = fs.key.get().await.unwrap();
+            assert!(fs.migrate_file(&fs.ino_file(inos[0]), &key, false).unwrap());
+            assert!(fs
+                .migrate_file(&fs.contents_path(inos[0]), &key, false)
+                .unwrap());
+            assert!(matches!(
+                fs.snapshot().await,
+                Err(FsError::Other("cipher migration in progress"))
+            ));
+
+            let data_dir = fs.data_dir.clone();
+            let fs = EncryptedFs::new(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fs.ciphers.migrating_to(), Some(Cipher::Aes256Gcm));
+            // files are read with the cipher each one uses
+            for ino in &inos {
+                assert_eq!(data, test_common::read_to_string(*ino, &fs).await);
+            }
+            assert_eq!(
+                fs.file_info(inos[0]).await.unwrap().cipher,
+                Cipher::Aes256Gcm
+            );
+            assert_eq!(
+                fs.file_info(inos[1]).await.unwrap().cipher,
+                Cipher::ChaCha20Poly1305
+            );
+            assert!(matches!(
+                fs.migrate_cipher(Cipher::ChaCha20Poly1305).await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            fs.migrate_cipher(Cipher::Aes256Gcm).await.unwrap();
+            assert_eq!(fs.ciphers.migrating_to(), None);
+            for ino in &inos {
+                assert_eq!(data, test_common::read_to_string(*ino, &fs).await);
+                assert_eq!(fs.file_info(*ino).await.unwrap().cipher, Cipher::Aes256Gcm);
+            }
+            assert_eq!(
+                list_names(&fs, ROOT_INODE).await,
+                vec![".", "test-file-0", "test-file-1"]
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_rotate_data_key() {
+    run_test(
+        TestSetup {
+            key: "test_rotate_data_key",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+
+            let (_, dir_attr) = fs
+                .create(
                     ROOT_INODE,
-                    &test_file,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &name,
                     create_attr(FileType::RegularFile),
                     false,
-                    false
+                    true,
                 )
-                .await,
-                Err(FsError::AlreadyExists)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let xattr_name = SecretString::from_str("user.test").unwrap();
+            fs.set_xattr(attr.ino, &xattr_name, b"test-42", XattrMode::Set)
+                .await
+                .unwrap();
+            let snapshot_id = fs.snapshot().await.unwrap();
+            let names = list_names(&fs, dir_attr.ino).await;
+            let old_key = fs.key.get().await.unwrap().expose_secret().to_vec();
+
+            // opened files would keep the old key
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert!(matches!(
+                fs.rotate_data_key().await,
+                Err(FsError::AlreadyOpenForWrite)
             ));
+            fs.release(fh).await.unwrap();
 
-            // existing directory
+            // other ops fail while it runs
+            *fs.key_rotation.write().await = true;
             assert!(matches!(
-                fs.create(
-                    ROOT_INODE,
-                    &test_dir,
-                    create_attr(FileType::Directory),
-                    false,
-                    false
+                fs.open(attr.ino, true, false).await,
+                Err(FsError::KeyRotationInProgress)
+            ));
+            *fs.key_rotation.write().await = false;
+
+            fs.rotate_data_key().await.unwrap();
+            assert_ne!(
+                old_key,
+                fs.key.get().await.unwrap().expose_secret().to_vec()
+            );
+            assert_eq!(names, list_names(&fs, dir_attr.ino).await);
+            assert_eq!(
+                fs.find_by_name(dir_attr.ino, &name)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                attr.ino
+            );
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(
+                *fs.get_xattr(attr.ino, &xattr_name)
+                    .await
+                    .unwrap()
+                    .expose_secret(),
+                b"test-42"
+            );
+            fs.verify_file(attr.ino).await.unwrap();
+
+            // snapshots use the new key too
+            let data_dir = fs.data_dir.clone();
+            let snapshot = EncryptedFs::new_readonly_at(
+                data_dir.clone(),
+                snapshot_id,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                FsConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(data, test_common::read_to_string(attr.ino, &snapshot).await);
+            drop(snapshot);
+
+            // the new key is kept with the same password
+            let fs = EncryptedFs::new(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(names, list_names(&fs, dir_attr.ino).await);
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rotate_data_key_resume() {
+    run_test(
+        TestSetup {
+            key: "test_rotate_data_key_resume",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            let mut inos = vec![];
+            for i in 0..2 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+
+            // interrupted after re-encrypting some of the files
+            let key_file = fs.key_file.as_ref().unwrap();
+            let rotation_path = key_file.rotation_path();
+            let old_key = fs.key.get().await.unwrap();
+            let new_key = SecretVec::new(Box::new(vec![42_u8; old_key.expose_secret().len()]));
+            key_file.wrap(&rotation_path, &new_key).unwrap();
+            fs.rekey_file(&fs.ino_file(inos[0]), &old_key, &new_key, false)
+                .unwrap();
+            // already done
+            fs.rekey_file(&fs.ino_file(inos[0]), &old_key, &new_key, false)
+                .unwrap();
+            drop(old_key);
+
+            let data_dir = fs.data_dir.clone();
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    true,
                 )
                 .await,
-                Err(FsError::AlreadyExists)
+                Err(FsError::KeyRotationInProgress)
             ));
+            // finished when opened read-write
+            let fs = EncryptedFs::new(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(!rotation_path.exists());
+            assert_eq!(
+                *new_key.expose_secret(),
+                *fs.key.get().await.unwrap().expose_secret()
+            );
+            for ino in &inos {
+                assert_eq!(data, test_common::read_to_string(*ino, &fs).await);
+                fs.verify_file(*ino).await.unwrap();
+            }
+            assert_eq!(
+                list_names(&fs, ROOT_INODE).await,
+                vec![".", "test-file-0", "test-file-1"]
+            );
         },
     )
     .await;
@@ -1381,892 +5930,2826 @@ async fn test_create() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_rename() {
+async fn test_file_key() {
     run_test(
         TestSetup {
-            key: "test_rename",
+            key: "test_file_key",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            let mut inos = vec![];
+            for i in 0..2 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+            let file_keys = [
+                fs.get_attr(inos[0]).await.unwrap().file_key,
+                fs.get_attr(inos[1]).await.unwrap().file_key,
+            ];
+            assert!(file_keys[0].is_some());
+            assert_ne!(file_keys[0], file_keys[1]);
+            assert_eq!("Some(FileKey(..))", format!("{:?}", file_keys[0]));
 
-            // new file in same directory
-            let new_parent = ROOT_INODE;
-            let file_1 = SecretString::from_str("file-1").unwrap();
-            let (_, attr) = fs
+            // only the inodes are re-encrypted, the contents stay as they are
+            let content = std::fs::read(fs.contents_path(inos[0])).unwrap();
+            let inode = std::fs::read(fs.ino_file(inos[0])).unwrap();
+            fs.rotate_data_key().await.unwrap();
+            assert_eq!(content, std::fs::read(fs.contents_path(inos[0])).unwrap());
+            assert_ne!(inode, std::fs::read(fs.ino_file(inos[0])).unwrap());
+            assert_eq!(file_keys[0], fs.get_attr(inos[0]).await.unwrap().file_key);
+            for ino in &inos {
+                assert_eq!(data, test_common::read_to_string(*ino, &fs).await);
+                fs.verify_file(*ino).await.unwrap();
+            }
+
+            // and they are re-encrypted with their key when the cipher changes
+            fs.migrate_cipher(Cipher::Aes256Gcm).await.unwrap();
+            assert_ne!(content, std::fs::read(fs.contents_path(inos[0])).unwrap());
+            for ino in &inos {
+                assert_eq!(data, test_common::read_to_string(*ino, &fs).await);
+                fs.verify_file(*ino).await.unwrap();
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_file_without_file_key() {
+    run_test(
+        TestSetup {
+            key: "test_file_without_file_key",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_1,
+                    &name,
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            let file_1_new = SecretString::from_str("file-1-new").unwrap();
-            fs.rename(ROOT_INODE, &file_1, new_parent, &file_1_new)
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1_new).unwrap());
-            let new_attr = fs
-                .find_by_name(new_parent, &file_1_new)
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // save it like before each file had its own key, the content is encrypted with the volume key and
+            // the inode has no version and no file key
+            let key = fs.key().await.unwrap();
+            let path = fs.contents_path(attr.ino);
+            let mut writer = crypto::create_write(
+                std::fs::File::create(&path).unwrap(),
+                fs.ciphers.for_write(&path).unwrap(),
+                &key,
+            );
+            writer.write_all(data.as_bytes()).unwrap();
+            writer.finish().unwrap();
+            let path = fs.ino_file(attr.ino);
+            let old = FileAttrV0::from(fs.get_attr(attr.ino).await.unwrap());
+            crypto::serialize_encrypt_into(
+                std::fs::File::create(&path).unwrap(),
+                &old,
+                fs.ciphers.for_write(&path).unwrap(),
+                &key,
+            )
+            .unwrap();
+            fs.clear_caches().await.unwrap();
+
+            assert!(fs.get_attr(attr.ino).await.unwrap().file_key.is_none());
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+            // it keeps using the volume key when it's changed
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"TEST", fh)
                 .await
-                .unwrap()
                 .unwrap();
-            assert!(fs.is_file(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.clear_caches().await.unwrap();
+            assert!(fs.get_attr(attr.ino).await.unwrap().file_key.is_none());
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1.expose_secret())
-                    .count(),
-                0
+                format!("TEST{}", &data[4..]),
+                test_common::read_to_string(attr.ino, &fs).await
             );
-            assert_eq!(
-                fs.read_dir(new_parent)
+            fs.verify_file(attr.ino).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_change_cipher() {
+    run_test(
+        TestSetup {
+            key: "test_change_cipher",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            let mut inos = vec![];
+            for i in 0..2 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
                     .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1_new.expose_secret())
-                    .count(),
-                1
-            );
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+            let data_dir = fs.data_dir.clone();
+            let password = || SecretString::from_str("password").unwrap();
+
+            assert!(matches!(
+                EncryptedFs::change_cipher(
+                    &data_dir,
+                    SecretString::from_str("wrong-password").unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                    Cipher::Aes256Gcm,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            assert_eq!(fs.ciphers.migrating_to(), None);
+
+            // interrupted after migrating a file
+            fs.ciphers.start(Cipher::Aes256Gcm).unwrap();
+            let key = fs.key.get().await.unwrap();
+            assert!(fs.migrate_file(&fs.ino_file(inos[0]), &key, false).unwrap());
+            drop(fs);
+
+            EncryptedFs::change_cipher(
+                &data_dir,
+                password(),
+                Cipher::ChaCha20Poly1305,
+                Cipher::Aes256Gcm,
+            )
+            .await
+            .unwrap();
+            // finished, nothing to do
+            EncryptedFs::change_cipher(
+                &data_dir,
+                password(),
+                Cipher::ChaCha20Poly1305,
+                Cipher::Aes256Gcm,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                EncryptedFs::change_cipher(
+                    &data_dir,
+                    password(),
+                    Cipher::Aes256GcmSiv,
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::CipherMismatch { .. })
+            ));
+
+            let fs = EncryptedFs::new(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::Aes256Gcm,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fs.ciphers.cipher(), Cipher::Aes256Gcm);
+            assert_eq!(fs.ciphers.migrating_to(), None);
+            for ino in &inos {
+                assert_eq!(data, test_common::read_to_string(*ino, &fs).await);
+                assert_eq!(fs.file_info(*ino).await.unwrap().cipher, Cipher::Aes256Gcm);
+                fs.verify_file(*ino).await.unwrap();
+            }
+        },
+    )
+    .await;
+}
+
+/// The race behind "error reading after copy": the source of a copy is read through another handle while it's
+/// still written, so each copy flushes its writer and resets the reader in the middle of the reads. Serialized,
+/// the tasks switch only when they await, so the interleaving is the same on each run.
+#[tokio::test]
+#[traced_test]
+async fn test_serialized_read_while_copy_from_active_writer() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fs = EncryptedFs::new_serialized(
+        data_dir.path().join("data"),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let (fh, src) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file-1").unwrap(),
+            create_attr(FileType::RegularFile),
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+    let (fh2, dest) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file-2").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let read_fh = fs.open(src.ino, true, false).await.unwrap();
+    // a partial block at the end
+    #[allow(clippy::cast_possible_truncation)]
+    let data: Arc<Vec<u8>> = Arc::new((0..BLOCK_SIZE * 4 + 42).map(|i| (i % 251) as u8).collect());
+    // how much was written and copied so far
+    let copied = Arc::new(AtomicUsize::new(0));
+
+    let mut join_set = JoinSet::new();
+    {
+        let (fs, data, copied) = (fs.clone(), data.clone(), copied.clone());
+        join_set.spawn(async move {
+            for chunk in data.chunks(BLOCK_SIZE) {
+                let offset = copied.load(Ordering::SeqCst);
+                // not flushed, the copy needs to flush it first
+                write_all_bytes_to_fs(&fs, src.ino, offset as u64, chunk, fh)
+                    .await
+                    .unwrap();
+                test_common::copy_all_file_range(
+                    &fs,
+                    src.ino,
+                    offset as u64,
+                    dest.ino,
+                    offset as u64,
+                    chunk.len(),
+                    fh,
+                    fh2,
+                )
+                .await;
+                copied.store(offset + chunk.len(), Ordering::SeqCst);
+                tokio::task::yield_now().await;
+            }
+        });
+    }
+    {
+        let (fs, data, copied) = (fs.clone(), data.clone(), copied.clone());
+        join_set.spawn(async move {
+            loop {
+                let len = copied.load(Ordering::SeqCst);
+                // what was copied is flushed, so it must read back the same while the copy goes on
+                let mut buf = vec![0; len];
+                test_common::read_exact(&fs, src.ino, 0, &mut buf, read_fh).await;
+                assert_eq!(&data[..len], &buf[..]);
+                if len == data.len() {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        });
+    }
+    while let Some(res) = join_set.join_next().await {
+        res.unwrap();
+    }
+
+    fs.release(read_fh).await.unwrap();
+    fs.flush(fh2).await.unwrap();
+    fs.release(fh2).await.unwrap();
+    fs.release(fh).await.unwrap();
+    let mut buf = vec![0; data.len()];
+    let fh = fs.open(dest.ino, true, false).await.unwrap();
+    test_common::read_exact(&fs, dest.ino, 0, &mut buf, fh).await;
+    fs.release(fh).await.unwrap();
+    assert_eq!(*data, buf);
+}
+
+struct StaticKeyProvider(Vec<u8>);
+
+#[async_trait::async_trait]
+impl ValueProvider<SecretVec<u8>, FsError> for StaticKeyProvider {
+    async fn provide(&self) -> FsResult<SecretVec<u8>> {
+        Ok(SecretVec::new(Box::new(self.0.clone())))
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_new_with_key_provider() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = vec![7; cipher.key_len()];
+    let new_fs = |key: Vec<u8>| {
+        EncryptedFs::new_with_key_provider(
+            data_dir.clone(),
+            Box::new(StaticKeyProvider(key)),
+            cipher,
+            false,
+            FsConfig::default(),
+        )
+    };
+
+    let fs = new_fs(key.clone()).await.unwrap();
+    let name = SecretString::from_str("test-file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    drop(fs);
+    // the key is never stored in the data dir
+    assert!(!data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).exists());
+
+    let fs = new_fs(key).await.unwrap();
+    let attr = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+    drop(fs);
+
+    assert!(matches!(
+        new_fs(vec![7; 16]).await,
+        Err(FsError::InvalidInput(_))
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_aes256gcmsiv() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fs = EncryptedFs::new(
+        data_dir.path().join("data"),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::Aes256GcmSiv,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let mut expected = vec![b'a'; BLOCK_SIZE * 3];
+    write_all_bytes_to_fs(&fs, attr.ino, 0, &expected, fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    // rewrite the same region, each version needs to be readable
+    for i in 0..10 {
+        let data = vec![b'0' + i; BLOCK_SIZE];
+        let offset = BLOCK_SIZE / 2;
+        write_all_bytes_to_fs(&fs, attr.ino, offset as u64, &data, fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        expected[offset..offset + BLOCK_SIZE].copy_from_slice(&data);
+        assert_eq!(
+            String::from_utf8(expected.clone()).unwrap(),
+            test_common::read_to_string(attr.ino, &fs).await
+        );
+    }
+    fs.release(fh).await.unwrap();
+
+    fs.set_len(attr.ino, BLOCK_SIZE as u64 + 42).await.unwrap();
+    expected.truncate(BLOCK_SIZE + 42);
+    assert_eq!(
+        String::from_utf8(expected).unwrap(),
+        test_common::read_to_string(attr.ino, &fs).await
+    );
+    fs.verify_file(attr.ino).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_inode_db_backend() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let new_fs = |backend| {
+        EncryptedFs::new_with_config(
+            data_dir.clone(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsConfig {
+                inode_backend: Some(backend),
+                ..FsConfig::default()
+            },
+        )
+    };
+    let fs = new_fs(InodeBackend::Db).await.unwrap();
+    assert!(data_dir.join(INODES_DIR).join(INODE_DB_FILENAME).is_file());
+
+    let (_, dir_attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-dir").unwrap(),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (fh, attr) = fs
+        .create(
+            dir_attr.ino,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let created = fs
+        .create_many(
+            ROOT_INODE,
+            (0..3)
+                .map(|i| {
+                    (
+                        SecretString::from_str(&format!("test-file-{i}")).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        b"test-37".to_vec(),
+                    )
+                })
+                .collect(),
+        )
+        .await
+        .unwrap();
+    fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file-0").unwrap())
+        .await
+        .unwrap();
+    assert_eq!(fs.count_inodes().unwrap(), 5);
+    assert!(!fs.exists(created[0].ino));
+    assert!(fs.verify().await.unwrap().is_clean());
+    // only the db is in the inodes dir
+    assert_eq!(
+        std::fs::read_dir(data_dir.join(INODES_DIR))
+            .unwrap()
+            .count(),
+        1
+    );
+    assert!(matches!(
+        fs.migrate_cipher(Cipher::Aes256Gcm).await,
+        Err(FsError::NotSupported(_))
+    ));
+    drop(fs);
+
+    // the backend is detected
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(fs.count_inodes().unwrap(), 5);
+    assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42");
+    assert_eq!(
+        test_common::read_to_string(created[1].ino, &fs).await,
+        "test-37"
+    );
+    assert_eq!(
+        fs.lookup_path("/test-dir/test-file").await.unwrap().ino,
+        attr.ino
+    );
+    drop(fs);
+    assert!(matches!(
+        new_fs(InodeBackend::Files).await,
+        Err(FsError::InvalidInput(_))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_serialized_needs_current_thread() {
+    let data_dir = tempfile::tempdir().unwrap();
+    assert!(matches!(
+        EncryptedFs::new_serialized(
+            data_dir.path().join("data"),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+        )
+        .await,
+        Err(FsError::InvalidInput(_))
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_nonce_strategy() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_nonce_strategy",
+            read_only: false,
+        },
+        FsConfig {
+            format: Some(VolumeFormat {
+                nonce_strategy: NonceStrategy::Counter,
+                ..VolumeFormat::default()
+            }),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
 
-            // new directory in same directory
-            let new_parent = ROOT_INODE;
-            let dir_1 = SecretString::from_str("dir-1").unwrap();
-            let (_, attr) = fs
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &dir_1,
-                    create_attr(FileType::Directory),
-                    false,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
+                    true,
                 )
                 .await
                 .unwrap();
-            let dir_1_new = SecretString::from_str("dir-1-new").unwrap();
-            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1_new)
-                .await
-                .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1_new).unwrap());
-            let new_attr = fs
-                .find_by_name(new_parent, &dir_1_new)
+            let data = "test-42".repeat(BLOCK_SIZE / 4);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
                 .await
-                .unwrap()
                 .unwrap();
-            assert!(fs.is_dir(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_1.expose_secret())
-                    .count(),
-                0
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_1_new.expose_secret())
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_parent
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_attr.ino
-            );
-            assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
-                    .count(),
-                1
-            );
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(fs
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(NONCE_COUNTER_FILENAME)
+                .is_file());
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
 
-            let dir_new_parent = SecretString::from_str("dir-new-parent").unwrap();
-            let (_, new_parent_attr) = fs
-                .create(
-                    ROOT_INODE,
-                    &dir_new_parent,
-                    create_attr(FileType::Directory),
-                    false,
+            // the strategy is kept in the data dir
+            assert!(fs
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(FORMAT_FILENAME)
+                .is_file());
+            let fs2 = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fs2.format.nonce_strategy, NonceStrategy::Counter);
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs2).await);
+
+            // and it can't be changed
+            assert!(matches!(
+                EncryptedFs::new_with_config(
+                    fs.data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
                     false,
+                    FsConfig {
+                        format: Some(VolumeFormat::default()),
+                        ..FsConfig::default()
+                    },
                 )
-                .await
-                .unwrap();
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+        },
+    )
+    .await;
+}
 
-            // new file to another directory
-            let new_parent = new_parent_attr.ino;
-            let (_, attr) = fs
+#[tokio::test]
+#[traced_test]
+async fn test_case_insensitive() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_case_insensitive",
+            read_only: false,
+        },
+        FsConfig {
+            format: Some(VolumeFormat {
+                case_insensitive: true,
+                ..VolumeFormat::default()
+            }),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("Test-File").unwrap();
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_1,
+                    &test_file,
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            let file_2 = SecretString::from_str("file-2").unwrap();
-            fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
-                .await
-                .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1.expose_secret())
-                    .count(),
-                0
-            );
+            fs.release(fh).await.unwrap();
+
+            // any case finds the same entry
+            let lower = SecretString::from_str("test-file").unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &lower).unwrap());
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &lower)
                     .await
                     .unwrap()
-                    .filter(|entry| {
-                        let file_new = "file-new";
-                        *entry.as_ref().unwrap().name.expose_secret() == file_new
-                    })
-                    .count(),
-                0
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
                     .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_2.expose_secret())
-                    .count(),
-                1
+                    .ino
             );
-
-            // new directory to another directory
-            let new_parent = new_parent_attr.ino;
-            let (_, attr) = fs
-                .create(
+            assert!(matches!(
+                fs.create(
                     ROOT_INODE,
-                    &dir_1,
-                    create_attr(FileType::Directory),
+                    &SecretString::from_str("TEST-FILE").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
                     false,
                 )
-                .await
-                .unwrap();
-            let dir_2 = SecretString::from_str("dir-_2").unwrap();
-            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
-                .await
-                .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_1.expose_secret())
-                    .count(),
-                0
-            );
-            assert_eq!(
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // the name keeps the case it was created with
+            let names = |fs: Arc<EncryptedFs>| async move {
                 fs.read_dir(ROOT_INODE)
                     .await
                     .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_2.expose_secret())
-                    .count(),
-                0
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_2.expose_secret())
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_parent
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_attr.ino
-            );
+                    .map(|entry| entry.unwrap().name.expose_secret().clone())
+                    .filter(|name| name != "." && name != "..")
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(vec!["Test-File".to_owned()], names(fs.clone()).await);
+
+            // renaming to another case only changes the listed name
+            fs.rename(ROOT_INODE, &test_file, ROOT_INODE, &lower)
+                .await
+                .unwrap();
+            assert_eq!(vec!["test-file".to_owned()], names(fs.clone()).await);
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &test_file)
                     .await
                     .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
                     .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
-                    .count(),
-                1
+                    .ino
             );
 
-            // file to existing file in same directory
-            let file_1 = SecretString::from_str("file-1").unwrap();
-            let file_2 = SecretString::from_str("file--2").unwrap();
-            let new_parent = ROOT_INODE;
-            let (_, attr) = fs
+            // the setting is kept in the data dir
+            let fs2 = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            assert!(fs2.volume_format().case_insensitive);
+            assert!(fs2.exists_by_name(ROOT_INODE, &test_file).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fsync() {
+    run_test(
+        TestSetup {
+            key: "test_fsync",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_1,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            let (_, _attr_2) = fs
+            let data = "test-".repeat(BLOCK_SIZE / 2);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+
+            // only the data, the content and the size are visible to a new instance
+            fs.fsync(fh, true).await.unwrap();
+            let fs2 = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                fs2.get_attr(attr.ino).await.unwrap().size,
+                data.len() as u64
+            );
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs2).await);
+            drop(fs2);
+
+            // the handle keeps working after both kinds of sync
+            write_all_bytes_to_fs(&fs, attr.ino, data.len() as u64, b"-end", fh)
+                .await
+                .unwrap();
+            fs.fsync(fh, false).await.unwrap();
+            assert_eq!(
+                format!("{data}-end"),
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+
+            // directories don't have handles
+            fs.fsync(0, true).await.unwrap();
+            assert!(matches!(
+                fs.fsync(fh + 42, true).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sync_all() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_sync_all",
+            read_only: false,
+        },
+        FsConfig {
+            times_write_back: Some(Duration::from_secs(60 * 60)),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+
+            // several files with writes not flushed yet
+            let mut files = vec![];
+            for i in 0..3 {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(&format!("test-file-{i}")).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                let data = format!("test-{i}").repeat(BLOCK_SIZE / 3);
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                files.push((attr.ino, fh, data));
+            }
+            // pending times
+            let mtime = SystemTime::now() - Duration::from_secs(60);
+            fs.set_attr(ROOT_INODE, SetFileAttr::default().with_mtime(mtime))
+                .await
+                .unwrap();
+
+            fs.sync_all().await.unwrap();
+
+            // the handles are still opened, a new instance sees all from the data dir
+            let fs2 = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            for (ino, _, data) in &files {
+                assert_eq!(fs2.get_attr(*ino).await.unwrap().size, data.len() as u64);
+                assert_eq!(*data, test_common::read_to_string(*ino, &fs2).await);
+            }
+            assert_eq!(fs2.get_attr(ROOT_INODE).await.unwrap().mtime, mtime);
+            drop(fs2);
+
+            // handles keep working after sync
+            let (ino, fh, data) = &files[1];
+            write_all_bytes_to_fs(&fs, *ino, data.len() as u64, b"-end", *fh)
+                .await
+                .unwrap();
+            fs.flush(*fh).await.unwrap();
+            assert_eq!(
+                format!("{data}-end"),
+                test_common::read_to_string(*ino, &fs).await
+            );
+            for (_, fh, _) in files {
+                fs.release(fh).await.unwrap();
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::cast_precision_loss)]
+async fn test_storage_overhead() {
+    run_test(
+        TestSetup {
+            key: "test_storage_overhead",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let cipher = fs.ciphers.cipher();
+
+            let (fh, attr) = fs
                 .create(
-                    new_parent,
-                    &file_2,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
+            let data = vec![42; 2 * BLOCK_SIZE + 7];
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let info = fs.file_info(attr.ino).await.unwrap();
+            assert_eq!(info.disk_size, cipher.ciphertext_len(data.len() as u64));
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1.expose_secret())
-                    .count(),
-                0
+                info.disk_size,
+                data.len() as u64 + 3 * cipher.per_block_overhead() as u64
             );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_2.expose_secret())
-                    .count(),
-                1
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(attr.blocks, info.disk_size.div_ceil(512));
+
+            let block_len = BLOCK_SIZE + cipher.per_block_overhead();
+            assert!(
+                (fs.storage_overhead_ratio() - block_len as f64 / BLOCK_SIZE as f64).abs()
+                    < f64::EPSILON
             );
+            // framed blocks have a header
+            let transformed_dir = tempfile::tempdir().unwrap();
+            let transformed_fs = EncryptedFs::new_with_config(
+                transformed_dir.path().join("data"),
+                Box::new(PasswordProviderImpl {}),
+                cipher,
+                false,
+                transform_config(),
+            )
+            .await
+            .unwrap();
+            assert!(
+                (transformed_fs.storage_overhead_ratio()
+                    - (block_len + FRAME_HEADER_LEN) as f64 / BLOCK_SIZE as f64)
+                    .abs()
+                    < f64::EPSILON
+            );
+        },
+    )
+    .await;
+}
 
-            // directory to existing directory in same directory
-            let new_parent = ROOT_INODE;
-            let (_, attr) = fs
+#[tokio::test]
+#[traced_test]
+async fn test_blksize() {
+    run_test(
+        TestSetup {
+            key: "test_blksize",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let block_len = fs.ciphers.cipher().block_len();
+            assert_eq!(block_len, BLOCK_SIZE);
+            assert_eq!(fs.io_block_size(), block_len);
+
+            let (_, file) = fs
                 .create(
                     ROOT_INODE,
-                    &dir_1,
-                    create_attr(FileType::Directory),
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
                     false,
                 )
                 .await
                 .unwrap();
-            let (_, _attr_2) = fs
+            let (_, dir) = fs
                 .create(
-                    new_parent,
-                    &dir_2,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
                     create_attr(FileType::Directory),
                     false,
                     false,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
-                .await
-                .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_1.expose_secret())
-                    .count(),
-                0
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_2.expose_secret())
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_parent
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_attr.ino
-            );
-            assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
-                    .count(),
-                1
-            );
+            assert_eq!(file.blksize as usize, block_len);
+            for ino in [ROOT_INODE, file.ino, dir.ino] {
+                let attr = fs.get_attr(ino).await.unwrap();
+                assert_ne!(attr.blksize, 0);
+                assert_eq!(attr.blksize as usize, block_len);
+            }
+        },
+    )
+    .await;
+}
 
-            // file to existing file in another directory
-            let new_parent = new_parent_attr.ino;
-            let (_, attr) = fs
+#[test]
+#[traced_test]
+fn test_kdf_params_persisted() {
+    fn open_key(
+        data_dir: &Path,
+        password: &SecretString,
+        ciphers: &CipherTags,
+        defaults: KdfParams,
+    ) -> FsResult<shush_rs::SecretVec<u8>> {
+        let security_dir = data_dir.join(SECURITY_DIR);
+        read_or_create_key(
+            &security_dir.join(KEY_ENC_FILENAME),
+            &security_dir.join(KEY_SALT_FILENAME),
+            &security_dir.join(KDF_PARAMS_FILENAME),
+            password,
+            ciphers,
+            defaults,
+            DEFAULT_MIN_PASSWORD_LEN,
+        )
+    }
+    let password = SecretString::from_str("password").unwrap();
+    let create_volume = |defaults: KdfParams| {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join(SECURITY_DIR)).unwrap();
+        let ciphers = CipherTags::load(dir.path(), Cipher::ChaCha20Poly1305).unwrap();
+        let key = open_key(dir.path(), &password, &ciphers, defaults).unwrap();
+        (dir, ciphers, key)
+    };
+    let old_defaults = KdfParams {
+        m_cost: 8 * 1024,
+        t_cost: 1,
+        p_cost: 1,
+    };
+    // like after an upgrade of argon2
+    let new_defaults = KdfParams {
+        m_cost: 16 * 1024,
+        t_cost: 3,
+        p_cost: 2,
+    };
+
+    let (dir, ciphers, key) = create_volume(old_defaults);
+    let kdf_params_path = dir.path().join(SECURITY_DIR).join(KDF_PARAMS_FILENAME);
+    assert_eq!(
+        read_kdf_params(&FileStorage, &kdf_params_path).unwrap(),
+        old_defaults
+    );
+    let key2 = open_key(dir.path(), &password, &ciphers, new_defaults).unwrap();
+    assert_eq!(key.expose_secret(), key2.expose_secret());
+    assert_eq!(
+        read_kdf_params(&FileStorage, &kdf_params_path).unwrap(),
+        old_defaults
+    );
+    assert!(matches!(
+        open_key(
+            dir.path(),
+            &SecretString::from_str("wrong").unwrap(),
+            &ciphers,
+            new_defaults
+        ),
+        Err(FsError::InvalidPassword)
+    ));
+
+    // volumes created before the params were saved use the ones argon2 had as defaults then
+    let (dir, ciphers, key) = create_volume(KdfParams::LEGACY);
+    let kdf_params_path = dir.path().join(SECURITY_DIR).join(KDF_PARAMS_FILENAME);
+    std::fs::remove_file(&kdf_params_path).unwrap();
+    let key2 = open_key(dir.path(), &password, &ciphers, new_defaults).unwrap();
+    assert_eq!(key.expose_secret(), key2.expose_secret());
+    assert!(!kdf_params_path.exists());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_weak_password() {
+    struct Provider {
+        password: &'static str,
+        min_len: usize,
+    }
+    impl crate::encryptedfs::PasswordProvider for Provider {
+        fn get_password(&self) -> Option<SecretString> {
+            Some(SecretString::from_str(self.password).unwrap())
+        }
+
+        fn min_password_len(&self) -> usize {
+            self.min_len
+        }
+    }
+    let new_fs = |data_dir: &Path, password: &'static str, min_len: usize| {
+        EncryptedFs::new(
+            data_dir.to_path_buf(),
+            Box::new(Provider { password, min_len }),
+            Cipher::ChaCha20Poly1305,
+            false,
+        )
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let key_path = dir.path().join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+
+    assert!(matches!(
+        new_fs(dir.path(), "", DEFAULT_MIN_PASSWORD_LEN).await,
+        Err(FsError::WeakPassword { .. })
+    ));
+    // an empty password is rejected even if no minimum is set
+    assert!(matches!(
+        new_fs(dir.path(), "", 0).await,
+        Err(FsError::WeakPassword { min_len: 1 })
+    ));
+    assert!(matches!(
+        new_fs(dir.path(), "short", DEFAULT_MIN_PASSWORD_LEN).await,
+        Err(FsError::WeakPassword {
+            min_len: DEFAULT_MIN_PASSWORD_LEN
+        })
+    ));
+    assert!(!key_path.exists());
+
+    new_fs(dir.path(), "short", 1).await.unwrap();
+    assert!(key_path.exists());
+    // the minimum only applies when the volume is created
+    new_fs(dir.path(), "short", DEFAULT_MIN_PASSWORD_LEN)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_escrow() {
+    run_test(
+        TestSetup {
+            key: "test_escrow",
+            read_only: false,
+        },
+        async {
+            struct NewPasswordProvider {}
+            impl crate::encryptedfs::PasswordProvider for NewPasswordProvider {
+                fn get_password(&self) -> Option<SecretString> {
+                    Some(SecretString::from_str("new-password").unwrap())
+                }
+            }
+
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_1,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            let (_, _attr_2) = fs
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let (escrow_secret, escrow_public) = crypto::escrow::generate_keypair();
+            assert!(matches!(
+                EncryptedFs::export_escrow(
+                    &data_dir,
+                    SecretString::from_str("wrong-password").unwrap(),
+                    &escrow_public,
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            let escrow = EncryptedFs::export_escrow(
+                &data_dir,
+                SecretString::from_str("password").unwrap(),
+                &escrow_public,
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+
+            // the password is lost, recover with the escrow secret key
+            let (other_secret, _) = crypto::escrow::generate_keypair();
+            assert!(EncryptedFs::import_escrow(
+                &data_dir,
+                &escrow,
+                &other_secret,
+                SecretString::from_str("new-password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .is_err());
+            EncryptedFs::import_escrow(
+                &data_dir,
+                &escrow,
+                &escrow_secret,
+                SecretString::from_str("new-password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+
+            let fs2 = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(NewPasswordProvider {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs2).await);
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir,
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[traced_test]
+#[allow(clippy::cast_possible_truncation)]
+async fn test_concurrent_writes_size() {
+    run_test(
+        TestSetup {
+            key: "test_concurrent_writes_size",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
                 .create(
-                    new_parent,
-                    &file_1,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &file_1, new_parent, &file_1)
-                .await
+
+            // interleaved writes on the same handle, at offsets inside, across and after the end
+            let writes: Vec<Vec<(u64, usize)>> = (0..8)
+                .map(|_| {
+                    (0..20)
+                        .map(|_| {
+                            let mut rng = rand::thread_rng();
+                            (
+                                rng.gen_range(0..(BLOCK_SIZE * 8) as u64),
+                                rng.gen_range(1..BLOCK_SIZE * 2),
+                            )
+                        })
+                        .collect()
+                })
+                .collect();
+            let expected_size = writes
+                .iter()
+                .flatten()
+                .map(|(offset, len)| offset + *len as u64)
+                .max()
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
+
+            let mut join_set = JoinSet::new();
+            for (i, writes) in writes.into_iter().enumerate() {
+                let fs = fs.clone();
+                join_set.spawn(async move {
+                    for (offset, len) in writes {
+                        let data = vec![b'a' + i as u8; len];
+                        write_all_bytes_to_fs(&fs, attr.ino, offset, &data, fh)
+                            .await
+                            .unwrap();
+                        // the size never goes back
+                        assert!(fs.get_attr(attr.ino).await.unwrap().size >= offset + len as u64);
+                    }
+                });
+            }
+            while let Some(res) = join_set.join_next().await {
+                res.unwrap();
+            }
+
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, expected_size);
+            fs.flush(fh).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, expected_size);
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, expected_size);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1.expose_secret())
-                    .count(),
-                0
+                test_common::read_to_string(attr.ino, &fs).await.len() as u64,
+                expected_size
             );
-            assert_eq!(
-                fs.read_dir(new_parent)
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_concurrent_distinct_inodes() {
+    run_test(
+        TestSetup {
+            key: "test_concurrent_distinct_inodes",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let mut join_set = JoinSet::new();
+            for i in 0..16 {
+                let fs = fs.clone();
+                join_set.spawn(async move {
+                    // each task works in its own directory, on its own files
+                    let dir = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str(&format!("dir-{i}")).unwrap(),
+                            create_attr(FileType::Directory),
+                            false,
+                            false,
+                        )
+                        .await
+                        .unwrap()
+                        .1;
+                    let (fh, attr) = fs
+                        .create(
+                            dir.ino,
+                            &SecretString::from_str("file").unwrap(),
+                            create_attr(FileType::RegularFile),
+                            true,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    let mut data = String::new();
+                    for j in 0..20 {
+                        let chunk = format!("{i}-{j}").repeat(BLOCK_SIZE / 4);
+                        write_all_bytes_to_fs(
+                            &fs,
+                            attr.ino,
+                            data.len() as u64,
+                            chunk.as_bytes(),
+                            fh,
+                        )
+                        .await
+                        .unwrap();
+                        data.push_str(&chunk);
+                        if j % 5 == 0 {
+                            fs.flush(fh).await.unwrap();
+                        }
+                        // other handles opened and released meanwhile
+                        let fh2 = fs.open(attr.ino, true, false).await.unwrap();
+                        let mut buf = vec![0; chunk.len()];
+                        fs.read(attr.ino, (data.len() - chunk.len()) as u64, &mut buf, fh2)
+                            .await
+                            .unwrap();
+                        assert_eq!(chunk.as_bytes(), buf);
+                        fs.release(fh2).await.unwrap();
+                        fs.set_attr(
+                            dir.ino,
+                            SetFileAttr::default().with_mtime(SystemTime::now()),
+                        )
+                        .await
+                        .unwrap();
+                        fs.create(
+                            dir.ino,
+                            &SecretString::from_str(&format!("file-{j}")).unwrap(),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            false,
+                        )
+                        .await
+                        .unwrap();
+                    }
+                    fs.flush(fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    (dir.ino, attr.ino, data)
+                });
+            }
+            let res = tokio::time::timeout(Duration::from_secs(120), async {
+                let mut res = vec![];
+                while let Some(r) = join_set.join_next().await {
+                    res.push(r.unwrap());
+                }
+                res
+            })
+            .await
+            .expect("operations on distinct inodes blocked each other");
+
+            for (dir, ino, data) in res {
+                assert_eq!(data, test_common::read_to_string(ino, &fs).await);
+                let files = fs
+                    .read_dir(dir)
                     .await
                     .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1.expose_secret())
-                    .count(),
-                1
-            );
+                    .filter(|entry| {
+                        entry
+                            .as_ref()
+                            .unwrap()
+                            .name
+                            .expose_secret()
+                            .starts_with("file")
+                    })
+                    .count();
+                assert_eq!(files, 1 + 20);
+            }
+        },
+    )
+    .await;
+}
 
-            // directory to existing directory in another directory
-            let new_parent = new_parent_attr.ino;
-            let (_, attr) = fs
+#[tokio::test]
+#[traced_test]
+async fn test_corrupted_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    let new_fs = || {
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+        )
+    };
+    let fs = new_fs().await.unwrap();
+    let (_, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let ino_file = fs.ino_file(attr.ino);
+    drop(fs);
+    let security_dir = data_dir.join(SECURITY_DIR);
+
+    // both are read before the password is checked, a huge length or cost must not be used
+    let salt_path = security_dir.join(KEY_SALT_FILENAME);
+    let salt = std::fs::read(&salt_path).unwrap();
+    std::fs::write(&salt_path, [0xff; 8]).unwrap();
+    assert!(matches!(new_fs().await, Err(FsError::InvalidPassword)));
+    std::fs::write(&salt_path, salt).unwrap();
+
+    let kdf_params_path = security_dir.join(KDF_PARAMS_FILENAME);
+    let kdf_params = std::fs::read(&kdf_params_path).unwrap();
+    std::fs::write(
+        &kdf_params_path,
+        bincode::serialize(&KdfParams {
+            m_cost: u32::MAX,
+            ..KdfParams::LEGACY
+        })
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(new_fs().await.is_err());
+    std::fs::write(&kdf_params_path, [0xff; 3]).unwrap();
+    assert!(new_fs().await.is_err());
+    std::fs::write(&kdf_params_path, kdf_params).unwrap();
+
+    let fs = new_fs().await.unwrap();
+    fs.get_attr(attr.ino).await.unwrap();
+    std::fs::write(&ino_file, [0xff; 512]).unwrap();
+    fs.attr_cache()
+        .await
+        .unwrap()
+        .unwrap()
+        .write()
+        .await
+        .clear();
+    assert!(fs.get_attr(attr.ino).await.is_err());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_write_timeout() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_open_write_timeout",
+            read_only: false,
+        },
+        FsConfig {
+            open_write_timeout: Some(Duration::from_secs(1)),
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &dir_1,
-                    create_attr(FileType::Directory),
-                    false,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
+                    true,
                 )
                 .await
                 .unwrap();
-            let (_, _attr_2) = fs
+
+            // fails after the timeout
+            assert!(matches!(
+                fs.open(attr.ino, true, true).await,
+                Err(FsError::AlreadyOpenForWrite)
+            ));
+
+            // the other writer releases it meanwhile
+            let fs2 = fs.clone();
+            let release = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                fs2.release(fh).await.unwrap();
+            });
+            let fh2 = fs.open(attr.ino, false, true).await.unwrap();
+            release.await.unwrap();
+            assert!(fs.is_write_handle(fh2).await);
+            assert!(!fs.is_write_handle(fh).await);
+            fs.release(fh2).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_force_release() {
+    run_test(
+        TestSetup {
+            key: "test_force_release",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
                 .create(
-                    new_parent,
-                    &dir_1,
-                    create_attr(FileType::Directory),
-                    false,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
+                    true,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1)
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_1.expose_secret())
-                    .count(),
-                0
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_1.expose_secret())
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_parent
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_attr.ino
-            );
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
             assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
-                    .count(),
-                1
+                fs.open_handles().await,
+                vec![(fh, attr.ino, true), (read_fh, attr.ino, false)]
             );
-            assert_eq!(
-                fs.read_dir(new_attr.ino)
+
+            // leaked writer, what it wrote is kept
+            fs.force_release(fh).await.unwrap();
+            assert_eq!(fs.open_handles().await, vec![(read_fh, attr.ino, false)]);
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // released only once
+            let (res1, res2) = tokio::join!(fs.force_release(read_fh), fs.release(read_fh));
+            assert!(res1.is_ok() != res2.is_ok());
+            assert!(matches!(res1.and(res2), Err(FsError::InvalidFileHandle)));
+            assert!(fs.open_handles().await.is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_symlink() {
+    run_test(
+        TestSetup {
+            key: "test_symlink",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            for (name, target) in [("relative", "../a/b"), ("absolute", "/abs/path")] {
+                let attr = fs
+                    .create_symlink(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        &SecretString::from_str(target).unwrap(),
+                    )
                     .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
-                    .count(),
-                1
-            );
+                    .unwrap();
+                assert_eq!(attr.kind, FileType::Symlink);
+                assert_eq!(attr.size, target.len() as u64);
+                // target doesn't exist, but we can still read the link
+                assert_eq!(
+                    *fs.read_link(attr.ino).await.unwrap().expose_secret(),
+                    target
+                );
+                let attr = fs.get_attr(attr.ino).await.unwrap();
+                assert_eq!(attr.kind, FileType::Symlink);
+                assert_eq!(attr.size, target.len() as u64);
+                assert!(matches!(
+                    fs.open(attr.ino, true, false).await,
+                    Err(FsError::InvalidInodeType)
+                ));
+            }
 
-            // overwriting directory with file
-            let new_parent = ROOT_INODE;
-            let (_, attr) = fs
-                .create(
+            let entry = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|entry| *entry.name.expose_secret() == "relative")
+                .unwrap();
+            assert_eq!(entry.kind, FileType::Symlink);
+
+            assert!(matches!(
+                fs.create_symlink(
                     ROOT_INODE,
-                    &file_1,
-                    create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    &SecretString::from_str("empty").unwrap(),
+                    &SecretString::from_str("").unwrap(),
                 )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.read_link(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("relative").unwrap())
                 .await
                 .unwrap();
-            let (_, _attr_2) = fs
+            assert!(!fs
+                .exists_by_name(ROOT_INODE, &SecretString::from_str("relative").unwrap())
+                .unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_aes_256_gcm_volume() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let fs = EncryptedFs::new_with_config(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::Aes256Gcm,
+        false,
+        FsConfig {
+            format: Some(VolumeFormat {
+                nonce_strategy: NonceStrategy::Counter,
+                ..VolumeFormat::default()
+            }),
+            ..FsConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(Cipher::Aes256Gcm.key_len(), 32);
+    assert_eq!(Cipher::Aes256Gcm.per_block_overhead(), 12 + 16);
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data = "test-42".repeat(BLOCK_SIZE);
+    write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    // each rewrite of the content, like on set_len, uses new nonces
+    let block_len = BLOCK_SIZE + Cipher::Aes256Gcm.per_block_overhead();
+    let mut nonces = std::collections::HashSet::new();
+    let mut collect_nonces = |fs: &EncryptedFs, len: u64| {
+        let encrypted = std::fs::read(fs.contents_path(attr.ino)).unwrap();
+        assert_eq!(
+            encrypted.len() as u64,
+            Cipher::Aes256Gcm.ciphertext_len(len)
+        );
+        for block in encrypted.chunks(block_len) {
+            assert!(nonces.insert(block[..ring::aead::NONCE_LEN].to_vec()));
+        }
+    };
+    collect_nonces(&fs, data.len() as u64);
+    let len = (BLOCK_SIZE * 3 + 42) as u64;
+    fs.set_len(attr.ino, len).await.unwrap();
+    collect_nonces(&fs, len);
+    let len = (BLOCK_SIZE * 5) as u64;
+    fs.set_len(attr.ino, len).await.unwrap();
+    collect_nonces(&fs, len);
+    let data = format!(
+        "{}{}",
+        &data[..BLOCK_SIZE * 3 + 42],
+        "\0".repeat(BLOCK_SIZE * 2 - 42)
+    );
+    assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+    drop(fs);
+
+    // opens only with the cipher it was created with
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+        )
+        .await,
+        Err(FsError::CipherMismatch {
+            volume: Cipher::Aes256Gcm,
+            requested: Cipher::ChaCha20Poly1305
+        })
+    ));
+    let fs = EncryptedFs::new(
+        data_dir,
+        Box::new(PasswordProviderImpl {}),
+        Cipher::Aes256Gcm,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(fs.ciphers.cipher(), Cipher::Aes256Gcm);
+    assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_cipher_mismatch_without_saved_cipher() {
+    struct WrongPasswordProvider {}
+    impl crate::encryptedfs::PasswordProvider for WrongPasswordProvider {
+        fn get_password(&self) -> Option<SecretString> {
+            Some(SecretString::from_str("wrong-password").unwrap())
+        }
+    }
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::Aes256Gcm,
+        false,
+    )
+    .await
+    .unwrap();
+    drop(fs);
+    // like volumes created before we saved the cipher
+    std::fs::remove_file(data_dir.join(SECURITY_DIR).join(CIPHER_FILENAME)).unwrap();
+
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+        )
+        .await,
+        Err(FsError::CipherMismatch {
+            volume: Cipher::Aes256Gcm,
+            requested: Cipher::ChaCha20Poly1305
+        })
+    ));
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(WrongPasswordProvider {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+    let fs = EncryptedFs::new(
+        data_dir,
+        Box::new(PasswordProviderImpl {}),
+        Cipher::Aes256Gcm,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(fs.ciphers.cipher(), Cipher::Aes256Gcm);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_passwd() {
+    run_test(
+        TestSetup {
+            key: "test_passwd",
+            read_only: false,
+        },
+        async {
+            struct NewPasswordProvider {}
+            impl crate::encryptedfs::PasswordProvider for NewPasswordProvider {
+                fn get_password(&self) -> Option<SecretString> {
+                    Some(SecretString::from_str("new-password").unwrap())
+                }
+            }
+
+            fn read_all(dir: &Path, files: &mut Vec<(std::path::PathBuf, Vec<u8>)>) {
+                for entry in std::fs::read_dir(dir).unwrap() {
+                    let path = entry.unwrap().path();
+                    if path.is_dir() {
+                        read_all(&path, files);
+                    } else {
+                        let bytes = std::fs::read(&path).unwrap();
+                        files.push((path, bytes));
+                    }
+                }
+                files.sort();
+            }
+
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let (fh, attr) = fs
                 .create(
-                    new_parent,
-                    &dir_1,
-                    create_attr(FileType::Directory),
-                    false,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
+                    true,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &file_1, new_parent, &dir_1)
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1.expose_secret())
-                    .count(),
-                0
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_1.expose_secret())
-                    .count(),
-                1
-            );
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            drop(fs);
 
-            // overwriting file with directory
-            let new_parent = ROOT_INODE;
-            let dir_3 = SecretString::from_str("dir-3").unwrap();
-            let (_, attr) = fs
-                .create(
-                    ROOT_INODE,
-                    &dir_3,
-                    create_attr(FileType::Directory),
-                    false,
+            let key_path = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+            let mut before = vec![];
+            read_all(&data_dir, &mut before);
+
+            assert!(matches!(
+                EncryptedFs::passwd(
+                    &data_dir,
+                    SecretString::from_str("wrong-password").unwrap(),
+                    SecretString::from_str("new-password").unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            EncryptedFs::passwd(
+                &data_dir,
+                SecretString::from_str("password").unwrap(),
+                SecretString::from_str("new-password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+
+            // only the key file is rewritten, the data stays the same
+            let mut after = vec![];
+            read_all(&data_dir, &mut after);
+            assert_eq!(before.len(), after.len());
+            for ((path, bytes), (path2, bytes2)) in before.iter().zip(after.iter()) {
+                assert_eq!(path, path2);
+                if *path == key_path {
+                    assert_ne!(bytes, bytes2);
+                } else {
+                    assert_eq!(bytes, bytes2);
+                }
+            }
+
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
                     false,
                 )
-                .await
-                .unwrap();
-            let (_, _attr_2) = fs
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            let fs = EncryptedFs::new(
+                data_dir,
+                Box::new(NewPasswordProvider {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_recovery_phrase() {
+    run_test(
+        TestSetup {
+            key: "test_recovery_phrase",
+            read_only: false,
+        },
+        async {
+            struct NewPasswordProvider {}
+            impl crate::encryptedfs::PasswordProvider for NewPasswordProvider {
+                fn get_password(&self) -> Option<SecretString> {
+                    Some(SecretString::from_str("new-password").unwrap())
+                }
+            }
+
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let (fh, attr) = fs
                 .create(
-                    new_parent,
-                    &file_1,
-                    create_attr(FileType::Directory),
-                    false,
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
+                    true,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &dir_3, new_parent, &file_1)
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_3.expose_secret())
-                    .count(),
-                0
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_1.expose_secret())
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_parent
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_attr.ino
-            );
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let phrase = fs.export_recovery_phrase().await.unwrap();
+            let words: Vec<String> = phrase
+                .expose_secret()
+                .split(' ')
+                .map(ToString::to_string)
+                .collect();
+            assert_eq!(words.len(), 24);
+            // derived from the key, not the password
+            assert!(!phrase.expose_secret().contains("password"));
             assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
-                    .count(),
-                1
+                *phrase.expose_secret(),
+                *fs.export_recovery_phrase().await.unwrap().expose_secret()
             );
+
+            // the password is lost, recover with the phrase
+            let mut swapped = words.clone();
+            let other_word = words.iter().position(|w| *w != words[0]).unwrap();
+            swapped.swap(0, other_word);
+            assert!(matches!(
+                EncryptedFs::recover_with_phrase(
+                    &data_dir,
+                    &SecretString::from_str(&swapped.join(" ")).unwrap(),
+                    SecretString::from_str("new-password").unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::InvalidRecoveryPhrase)
+            ));
+            // valid phrase, but for another key
+            let other =
+                crypto::recovery::to_phrase(&shush_rs::SecretVec::new(Box::new(vec![42; 32])))
+                    .unwrap();
+            assert!(matches!(
+                EncryptedFs::recover_with_phrase(
+                    &data_dir,
+                    &other,
+                    SecretString::from_str("new-password").unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::InvalidRecoveryPhrase)
+            ));
+            EncryptedFs::recover_with_phrase(
+                &data_dir,
+                &phrase,
+                SecretString::from_str("new-password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+
+            let fs2 = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(NewPasswordProvider {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs2).await);
+            // the phrase stays the same
             assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
-                    .count(),
-                1
+                *phrase.expose_secret(),
+                *fs2.export_recovery_phrase().await.unwrap().expose_secret()
             );
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir,
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+        },
+    )
+    .await;
+}
 
-            // overwriting non-empty directory
-            let new_parent = ROOT_INODE;
+#[tokio::test]
+#[traced_test]
+async fn test_xattr() {
+    run_test(
+        TestSetup {
+            key: "test_xattr",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
             let (_, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &dir_3,
-                    create_attr(FileType::Directory),
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
                     false,
                 )
                 .await
                 .unwrap();
-            let _ = new_parent_attr;
-            let name_2 = dir_new_parent;
+            let name = SecretString::from_str("user.xdg.origin.url").unwrap();
+            let name2 = SecretString::from_str("user.test").unwrap();
+            assert!(fs.list_xattr(attr.ino).await.unwrap().is_empty());
             assert!(matches!(
-                fs.rename(ROOT_INODE, &dir_3, new_parent, &name_2).await,
-                Err(FsError::NotEmpty)
+                fs.get_xattr(attr.ino, &name).await,
+                Err(FsError::XattrNotFound)
             ));
-            assert!(fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-            assert!(fs.exists_by_name(new_parent, &name_2).unwrap());
-            let attr_3 = fs.find_by_name(ROOT_INODE, &dir_3).await.unwrap().unwrap();
-            assert!(fs.is_dir(attr_3.ino));
-            let attr_2 = fs.find_by_name(new_parent, &name_2).await.unwrap().unwrap();
-            assert!(fs.is_dir(attr_2.ino));
-            let new_attr = fs.find_by_name(new_parent, &dir_3).await.unwrap().unwrap();
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
-            let new_attr_2 = fs.find_by_name(new_parent, &name_2).await.unwrap().unwrap();
-            assert_eq!(new_attr_2.ino, attr_2.ino);
-            assert_eq!(new_attr_2.kind, attr_2.kind);
-            assert_eq!(
-                fs.read_dir(ROOT_INODE)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_3.expose_secret())
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == name_2.expose_secret())
-                    .count(),
-                1
-            );
-            assert_eq!(
-                fs.find_by_name(new_attr_2.ino, &SecretString::from_str("..").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_parent
-            );
+
+            fs.set_xattr(attr.ino, &name, b"https://test-42", XattrMode::Set)
+                .await
+                .unwrap();
             assert_eq!(
-                fs.find_by_name(new_attr_2.ino, &SecretString::from_str(".").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_attr_2.ino
+                *fs.get_xattr(attr.ino, &name).await.unwrap().expose_secret(),
+                b"https://test-42"
             );
+            let xattr_path = fs.data_dir.join(XATTR_DIR).join(attr.ino.to_string());
+            let len = std::fs::metadata(&xattr_path).unwrap().len();
+            let raw = std::fs::read(&xattr_path).unwrap();
+            assert!(!raw.windows(9).any(|w| w == b"user.xdg."));
+            assert!(!raw.windows(7).any(|w| w == b"test-42"));
+
+            assert!(matches!(
+                fs.set_xattr(attr.ino, &name, b"test", XattrMode::Create)
+                    .await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.set_xattr(attr.ino, &name2, b"test", XattrMode::Replace)
+                    .await,
+                Err(FsError::XattrNotFound)
+            ));
+            fs.set_xattr(attr.ino, &name2, b"", XattrMode::Create)
+                .await
+                .unwrap();
+            fs.set_xattr(attr.ino, &name2, b"test-43", XattrMode::Replace)
+                .await
+                .unwrap();
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                *fs.get_xattr(attr.ino, &name2)
                     .await
                     .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
-                    .count(),
-                1
+                    .expose_secret(),
+                b"test-43"
             );
+            let names: Vec<String> = fs
+                .list_xattr(attr.ino)
+                .await
+                .unwrap()
+                .iter()
+                .map(|name| name.expose_secret().to_string())
+                .collect();
+            assert_eq!(names, vec!["user.test", "user.xdg.origin.url"]);
+            // padded, the size doesn't tell how many there are
+            assert_eq!(std::fs::metadata(&xattr_path).unwrap().len(), len);
+
+            assert!(matches!(
+                fs.set_xattr(
+                    attr.ino,
+                    &name,
+                    &vec![0; XATTR_VALUE_MAX_LEN + 1],
+                    XattrMode::Set
+                )
+                .await,
+                Err(FsError::XattrTooBig)
+            ));
+            assert!(matches!(
+                fs.set_xattr(
+                    attr.ino,
+                    &SecretString::from_str("").unwrap(),
+                    b"test",
+                    XattrMode::Set
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            fs.remove_xattr(attr.ino, &name2).await.unwrap();
+            assert!(matches!(
+                fs.remove_xattr(attr.ino, &name2).await,
+                Err(FsError::XattrNotFound)
+            ));
+            assert_eq!(fs.list_xattr(attr.ino).await.unwrap().len(), 1);
+
+            // directories have them too
+            fs.set_xattr(ROOT_INODE, &name, b"test-42", XattrMode::Set)
+                .await
+                .unwrap();
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                *fs.get_xattr(ROOT_INODE, &name)
                     .await
                     .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
-                    .count(),
-                1
+                    .expose_secret(),
+                b"test-42"
             );
 
-            // same file in same directory
-            let new_parent = ROOT_INODE;
-            let file_3 = SecretString::from_str("file-3").unwrap();
-            let (_, attr) = fs
+            // removed with the file
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file").unwrap())
+                .await
+                .unwrap();
+            assert!(!xattr_path.exists());
+            assert!(matches!(
+                fs.get_xattr(attr.ino, &name).await,
+                Err(FsError::InodeNotFound)
+            ));
+        },
+    )
+    .await;
+}
+
+/// Content is stored in blocks sealed each with its own nonce, a write in the middle of a file
+/// only rewrites the blocks it touches.
+#[tokio::test]
+#[traced_test]
+async fn test_random_write_rewrites_only_touched_blocks() {
+    run_test(
+        TestSetup {
+            key: "test_random_write_rewrites_only_touched_blocks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_3,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &file_3, new_parent, &file_3)
+            let blocks = 10;
+            let data = "0123456789".repeat(BLOCK_SIZE);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
-            assert!(fs.exists_by_name(new_parent, &file_3).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &file_3).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let before = std::fs::read(fs.contents_path(attr.ino)).unwrap();
+
+            // a reader opened before the write
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 10];
+            fs.read(attr.ino, 0, &mut buf, read_fh).await.unwrap();
+
+            // partial write over the boundary between blocks 4 and 5
+            let offset = (BLOCK_SIZE * 5 - 3) as u64;
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, offset, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let after = std::fs::read(fs.contents_path(attr.ino)).unwrap();
+            assert_eq!(before.len(), after.len());
+            let block_len = BLOCK_SIZE + Cipher::ChaCha20Poly1305.per_block_overhead();
+            for (i, (old, new)) in before
+                .chunks(block_len)
+                .zip(after.chunks(block_len))
+                .enumerate()
+            {
+                if i == 4 || i == 5 {
+                    assert_ne!(old, new, "block {i} should be rewritten");
+                } else {
+                    assert_eq!(old, new, "block {i} should not change");
+                }
+            }
+            assert_eq!(before.len(), block_len * blocks);
+
+            let mut expected = data.into_bytes();
+            expected[offset as usize..offset as usize + 7].copy_from_slice(b"test-42");
+            let mut buf = vec![0; 20];
+            fs.read(attr.ino, offset - 5, &mut buf, read_fh)
+                .await
+                .unwrap();
+            assert_eq!(buf, &expected[offset as usize - 5..offset as usize + 15]);
+            fs.release(read_fh).await.unwrap();
             assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == file_3.expose_secret())
-                    .count(),
-                1
+                String::from_utf8(expected).unwrap(),
+                test_common::read_to_string(attr.ino, &fs).await
             );
+        },
+    )
+    .await;
+}
 
-            // same directory in same directory
-            let new_parent = ROOT_INODE;
-            let dir_5 = SecretString::from_str("dir-5").unwrap();
-            let (_, attr) = fs
+#[tokio::test]
+#[traced_test]
+async fn test_set_len_in_place() {
+    run_test(
+        TestSetup {
+            key: "test_set_len_in_place",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &dir_5,
-                    create_attr(FileType::Directory),
-                    false,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
                     false,
+                    true,
                 )
                 .await
                 .unwrap();
-            fs.rename(ROOT_INODE, &dir_5, new_parent, &dir_5)
+            let data = "0123456789".repeat(BLOCK_SIZE);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
-            assert!(fs.exists_by_name(new_parent, &dir_5).unwrap());
-            let new_attr = fs.find_by_name(new_parent, &dir_5).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
-            assert_eq!(new_attr.ino, attr.ino);
-            assert_eq!(new_attr.kind, attr.kind);
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let block_len = BLOCK_SIZE + Cipher::ChaCha20Poly1305.per_block_overhead();
+            let before = std::fs::read(fs.contents_path(attr.ino)).unwrap();
+
+            // shrink inside a block, the blocks before it are kept as they are
+            let size = BLOCK_SIZE * 3 + 42;
+            fs.set_len(attr.ino, size as u64).await.unwrap();
+            let after = std::fs::read(fs.contents_path(attr.ino)).unwrap();
             assert_eq!(
-                fs.read_dir(new_parent)
-                    .await
-                    .unwrap()
-                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
-                        == dir_5.expose_secret())
-                    .count(),
-                1
+                after.len() as u64,
+                Cipher::ChaCha20Poly1305.ciphertext_len(size as u64)
             );
+            assert_eq!(before[..block_len * 3], after[..block_len * 3]);
             assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str("..").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_parent
+                &data[..size],
+                test_common::read_to_string(attr.ino, &fs).await
             );
+
+            // shrink to a block boundary
+            let size = BLOCK_SIZE * 2;
+            fs.set_len(attr.ino, size as u64).await.unwrap();
+            let after = std::fs::read(fs.contents_path(attr.ino)).unwrap();
+            assert_eq!(before[..block_len * 2], after);
             assert_eq!(
-                fs.find_by_name(new_attr.ino, &SecretString::from_str(".").unwrap())
-                    .await
-                    .unwrap()
-                    .unwrap()
-                    .ino,
-                new_attr.ino
+                &data[..size],
+                test_common::read_to_string(attr.ino, &fs).await
             );
+
+            // grow, only the zeros are written
+            let size = BLOCK_SIZE * 4 + 7;
+            fs.set_len(attr.ino, size as u64).await.unwrap();
+            let after = std::fs::read(fs.contents_path(attr.ino)).unwrap();
+            assert_eq!(before[..block_len * 2], after[..block_len * 2]);
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, size as u64);
             assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
-                    .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
-                    .count(),
-                1
+                format!(
+                    "{}{}",
+                    &data[..BLOCK_SIZE * 2],
+                    "\0".repeat(BLOCK_SIZE * 2 + 7)
+                ),
+                test_common::read_to_string(attr.ino, &fs).await
             );
-            assert_eq!(
-                fs.read_dir(new_attr.ino)
-                    .await
+
+            // to a size inside the first block
+            fs.set_len(attr.ino, 1).await.unwrap();
+            assert_eq!(&data[..1], test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sparse() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_sparse",
+            read_only: false,
+        },
+        FsConfig {
+            sparse: true,
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let offset = 10 * 1024 * 1024 * 1024;
+            fs.write(attr.ino, offset, b"x", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(fs.content_holes_path(attr.ino).exists());
+
+            let disk_blocks = || {
+                fs_util::allocated_blocks(&std::fs::metadata(fs.contents_path(attr.ino)).unwrap())
                     .unwrap()
-                    .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
-                    .count(),
-                1
-            );
+            };
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(attr.size, offset + 1);
+            assert!(disk_blocks() < 64);
+            assert_eq!(attr.blocks, disk_blocks());
+
+            let read = |offset: u64| {
+                let fs = fs.clone();
+                async move {
+                    let fh = fs.open(attr.ino, true, false).await.unwrap();
+                    let mut buf = [1; 10];
+                    let len = fs.read(attr.ino, offset, &mut buf, fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    buf[..len].to_vec()
+                }
+            };
+            let mut expected = vec![0; 9];
+            expected.push(b'x');
+            assert_eq!(read(offset - 9).await, expected);
+            assert_eq!(read(offset / 2).await, vec![0; 10]);
+            // the holes are kept in the data dir, with their hash in the inode
+            fs.holes.lock().unwrap().clear();
+            fs.clear_caches().await.unwrap();
+            assert!(fs.get_attr(attr.ino).await.unwrap().holes_mac.is_some());
+            assert_eq!(read(offset - 9).await, expected);
+
+            // extending leaves holes too
+            fs.set_len(attr.ino, offset * 2).await.unwrap();
+            assert!(disk_blocks() < 64);
+            assert_eq!(read(offset * 2 - 10).await, vec![0; 10]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sparse_written_hole() {
+    run_test_with_config(
+        TestSetup {
+            key: "test_sparse_written_hole",
+            read_only: false,
+        },
+        FsConfig {
+            sparse: true,
+            ..FsConfig::default()
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // blocks 0 and 1 are left as holes
+            let offset = BLOCK_SIZE as u64 * 3;
+            fs.write(attr.ino, offset, b"x", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let holes_path = fs.content_holes_path(attr.ino);
+            let old_holes = std::fs::read(&holes_path).unwrap();
+
+            // block 1 is not a hole anymore
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let data = vec![b'y'; BLOCK_SIZE];
+            write_all_bytes_to_fs(&fs, attr.ino, BLOCK_SIZE as u64, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.holes(attr.ino).await.unwrap().ranges(), vec![(0, 1)]);
+
+            let read = |offset: u64| {
+                let fs = fs.clone();
+                async move {
+                    // loaded again from the data dir
+                    fs.holes.lock().unwrap().clear();
+                    let fh = fs.open(attr.ino, true, false).await?;
+                    let mut buf = vec![1; BLOCK_SIZE];
+                    let res = fs.read(attr.ino, offset, &mut buf, fh).await;
+                    fs.release(fh).await?;
+                    Ok::<_, FsError>(buf[..res?].to_vec())
+                }
+            };
+            assert_eq!(read(0).await.unwrap(), vec![0; BLOCK_SIZE]);
+            assert_eq!(read(BLOCK_SIZE as u64).await.unwrap(), data);
+
+            // zeroed on disk, like a hole
+            let contents_path = fs.contents_path(attr.ino);
+            let block_len = fs
+                .ciphers
+                .cipher_for(&contents_path)
+                .ciphertext_len(BLOCK_SIZE as u64);
+            let mut file = OpenOptions::new().write(true).open(&contents_path).unwrap();
+            file.seek(SeekFrom::Start(block_len)).unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            file.write_all(&vec![0; block_len as usize]).unwrap();
+            file.sync_all().unwrap();
+            assert!(read(BLOCK_SIZE as u64).await.is_err());
+
+            // the old map, which still has it as a hole, doesn't match the inode
+            std::fs::write(&holes_path, old_holes).unwrap();
+            assert!(read(BLOCK_SIZE as u64).await.is_err());
+            assert!(read(0).await.is_err());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_wal_rollback() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data = "a".repeat(BLOCK_SIZE * 3 + 42);
+    write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    // committed
+    let wal_path = data_dir.join(WAL_DIR).join(attr.ino.to_string());
+    assert!(!wal_path.exists());
+
+    // a crash while writing leaves some of the new blocks on disk
+    let fh = fs.open(attr.ino, false, true).await.unwrap();
+    let new_data = "b".repeat(BLOCK_SIZE * 5);
+    write_all_bytes_to_fs(&fs, attr.ino, 0, new_data.as_bytes(), fh)
+        .await
+        .unwrap();
+    assert!(wal_path.exists());
+    assert_ne!(
+        std::fs::read(fs.contents_path(attr.ino)).unwrap().len() as u64,
+        Cipher::ChaCha20Poly1305.ciphertext_len(data.len() as u64)
+    );
+    drop(fs);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(!wal_path.exists());
+    assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, data.len() as u64);
+    assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+
+    // committed changes are kept
+    let fh = fs.open(attr.ino, false, true).await.unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, new_data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    assert!(!wal_path.exists());
+    fs.set_len(attr.ino, 0).await.unwrap();
+    assert!(!wal_path.exists());
+    fs.release(fh).await.unwrap();
+    drop(fs);
+
+    let fs = EncryptedFs::new(
+        data_dir,
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 0);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_cache_config() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let fs = EncryptedFs::new_with_config(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        FsConfig {
+            cache: CacheConfig {
+                attr_capacity: 100_000,
+                ..CacheConfig::default()
+            },
+            ..FsConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let ino_file = fs.ino_file(attr.ino);
+    let ino_data = std::fs::read(&ino_file).unwrap();
+    // served from cache, storage is not read again
+    std::fs::write(&ino_file, [0xff; 512]).unwrap();
+    for _ in 0..10 {
+        assert_eq!(fs.get_attr(attr.ino).await.unwrap().ino, attr.ino);
+    }
+    std::fs::write(&ino_file, ino_data).unwrap();
+    drop(fs);
+
+    let fs = EncryptedFs::new_with_config(
+        data_dir,
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        FsConfig {
+            cache: CacheConfig::disabled(),
+            ..FsConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(fs.get_attr(attr.ino).await.unwrap().ino, attr.ino);
+    let names: Vec<_> = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().to_string())
+        .collect();
+    assert!(names.contains(&"test-file".to_string()));
+    // every lookup goes to storage
+    std::fs::write(&ino_file, [0xff; 512]).unwrap();
+    assert!(fs.get_attr(attr.ino).await.is_err());
+}
+
+#[test]
+fn test_no_space_error() {
+    let err: FsError = std::io::Error::from(std::io::ErrorKind::StorageFull).into();
+    assert!(matches!(err, FsError::NoSpace));
+    let err: FsError = std::io::Error::from(std::io::ErrorKind::QuotaExceeded).into();
+    assert!(matches!(err, FsError::NoSpace));
+    // wrapped by the crypto writer
+    let err: FsError =
+        crypto::Error::from(std::io::Error::from(std::io::ErrorKind::StorageFull)).into();
+    assert!(matches!(err, FsError::NoSpace));
+    let err: FsError = std::io::Error::other(FsError::from(std::io::Error::from(
+        std::io::ErrorKind::StorageFull,
+    )))
+    .into();
+    assert!(matches!(err, FsError::NoSpace));
+    let err: FsError = std::io::Error::from(std::io::ErrorKind::PermissionDenied).into();
+    assert!(matches!(err, FsError::Io { .. }));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_auto_flush() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let fs = EncryptedFs::new_with_config(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        FsConfig {
+            auto_flush: Some(Duration::from_millis(50)),
+            ..FsConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data = "test-42";
+    write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // the file is still opened, a new instance sees the data
+    let wal_path = data_dir.join(WAL_DIR).join(attr.ino.to_string());
+    assert!(!wal_path.exists());
+    let fs2 = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fs2.get_attr(attr.ino).await.unwrap().size,
+        data.len() as u64
+    );
+    assert_eq!(data, test_common::read_to_string(attr.ino, &fs2).await);
+    drop(fs2);
+
+    // we can keep writing with the same handle
+    write_all_bytes_to_fs(&fs, attr.ino, data.len() as u64, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!(
+        data.repeat(2),
+        test_common::read_to_string(attr.ino, &fs).await
+    );
+
+    // the task doesn't keep it alive
+    let weak = Arc::downgrade(&fs);
+    drop(fs);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(weak.upgrade().is_none());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_mem_storage() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+    let new_fs = || {
+        EncryptedFs::new_with_config(
+            data_dir.clone(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsConfig {
+                storage: Some(storage.clone()),
+                ..FsConfig::default()
+            },
+        )
+    };
+
+    let fs = new_fs().await.unwrap();
+    let name = SecretString::from_str("test-file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+    drop(fs);
+
+    // nothing reached the disk, a new instance with the same storage sees the file
+    assert!(!data_dir.exists());
+    let fs = new_fs().await.unwrap();
+    let found = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+    assert_eq!(attr.ino, found.ino);
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+    assert!(!data_dir.exists());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_own_writes() {
+    run_test(
+        TestSetup {
+            key: "test_read_own_writes",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert_eq!(fs.write(attr.ino, 0, b"abc", fh).await.unwrap(), 3);
+            let mut buf = [0; 3];
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 3);
+            assert_eq!(&buf, b"abc");
+
+            // overwrite in the middle and keep writing after reading
+            assert_eq!(fs.write(attr.ino, 1, b"x", fh).await.unwrap(), 1);
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 3);
+            assert_eq!(&buf, b"axc");
+            assert_eq!(fs.write(attr.ino, 3, b"def", fh).await.unwrap(), 3);
+            let mut buf = [0; 6];
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 6);
+            assert_eq!(&buf, b"axcdef");
+
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!("axcdef", test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 6);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fallocate() {
+    run_test(
+        TestSetup {
+            key: "test_fallocate",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
 
-            // invalid nodes and name
-            let invalid = SecretString::from_str("invalid").unwrap();
-            assert!(matches!(
-                fs.rename(0, &invalid, 0, &invalid).await,
-                Err(FsError::InodeNotFound)
-            ));
-            let existing_file = SecretString::from_str("existing-file").unwrap();
-            let (_, attr_file) = fs
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &existing_file,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"abc", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // extend with zeros
+            fs.fallocate(attr.ino, 2, 8, 0).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 10);
+            assert_eq!(
+                "abc\0\0\0\0\0\0\0",
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+            // inside the file nothing changes
+            fs.fallocate(attr.ino, 0, 5, 0).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 10);
+
+            // only reserve
+            let ciphertext_len = std::fs::metadata(fs.contents_path(attr.ino)).unwrap().len();
+            fs.fallocate(attr.ino, 0, BLOCK_SIZE as u64 * 4, FALLOC_FL_KEEP_SIZE)
+                .await
+                .unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 10);
+            assert_eq!(
+                std::fs::metadata(fs.contents_path(attr.ino)).unwrap().len(),
+                ciphertext_len
+            );
+            assert_eq!(
+                "abc\0\0\0\0\0\0\0",
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+
+            // punch hole
             assert!(matches!(
-                fs.rename(attr_file.ino, &invalid, 0, &invalid).await,
-                Err(FsError::InvalidInodeType)
-            ));
-            assert!(matches!(
-                fs.rename(ROOT_INODE, &invalid, ROOT_INODE, &invalid).await,
-                Err(FsError::NotFound(_))
+                fs.fallocate(attr.ino, 0, 5, 0x02 | FALLOC_FL_KEEP_SIZE)
+                    .await,
+                Err(FsError::NotSupported(_))
             ));
             assert!(matches!(
-                fs.rename(ROOT_INODE, &existing_file, 0, &invalid).await,
-                Err(FsError::InodeNotFound)
+                fs.fallocate(attr.ino, 0, 0, 0).await,
+                Err(FsError::InvalidInput(_))
             ));
             assert!(matches!(
-                fs.rename(ROOT_INODE, &existing_file, attr_file.ino, &invalid)
-                    .await,
+                fs.fallocate(ROOT_INODE, 0, 5, 0).await,
                 Err(FsError::InvalidInodeType)
             ));
         },
@@ -2276,55 +8759,166 @@ async fn test_rename() {
 
 #[tokio::test]
 #[traced_test]
-async fn test_open() {
+async fn test_atime_policy() {
     run_test(
         TestSetup {
-            key: "test_open",
+            key: "test_atime_policy",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            let test_file = SecretString::from_str("test-file").unwrap();
-            let (_fh, attr) = fs
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &test_file,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     false,
-                    false,
+                    true,
                 )
                 .await
                 .unwrap();
-            // single read
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
-            assert_ne!(fh, 0);
-            // multiple read
-            let fh_2 = fs.open(attr.ino, true, false).await.unwrap();
-            assert_ne!(fh_2, 0);
-            // write and read
-            let _ = fs.open(attr.ino, false, true).await.unwrap();
-            // ensure cannot open multiple write
-            assert!(matches!(
-                fs.open(attr.ino, false, true).await,
-                Err(FsError::AlreadyOpenForWrite)
-            ));
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let ino_file = fs.ino_file(attr.ino);
+            let modified = || std::fs::metadata(&ino_file).unwrap().modified().unwrap();
+            let open_with = |atime_policy| {
+                EncryptedFs::new_with_config(
+                    fs.data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsConfig {
+                        atime_policy,
+                        ..FsConfig::default()
+                    },
+                )
+            };
+            let read = |fs: Arc<EncryptedFs>, times: usize| async move {
+                let fh = fs.open(attr.ino, true, false).await.unwrap();
+                let mut buf = [0; 7];
+                for _ in 0..times {
+                    fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+                }
+                fs.release(fh).await.unwrap();
+                fs.read_dir(ROOT_INODE).await.unwrap().for_each(drop);
+            };
+
+            // the inode is not touched at all
+            let never_fs = open_with(AtimePolicy::Never).await.unwrap();
+            let before = modified();
+            let attr = never_fs.get_attr(attr.ino).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            read(never_fs.clone(), 1000).await;
+            assert_eq!(before, modified());
+            let attr2 = never_fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(attr.atime, attr2.atime);
+
+            // first read after the change updates it, next ones don't
+            let relatime_fs = open_with(AtimePolicy::Relatime).await.unwrap();
+            read(relatime_fs.clone(), 1).await;
+            let attr3 = relatime_fs.get_attr(attr.ino).await.unwrap();
+            assert!(attr3.atime > attr.atime);
+            assert!(attr3.atime > attr3.mtime);
+            // ctime is not changed by reads
+            assert_eq!(attr3.ctime, attr.ctime);
+            let before = modified();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            read(relatime_fs.clone(), 10).await;
+            assert_eq!(before, modified());
+
+            // every read updates it
+            let always_fs = open_with(AtimePolicy::Always).await.unwrap();
+            read(always_fs.clone(), 1).await;
+            assert!(always_fs.get_attr(attr.ino).await.unwrap().atime > attr3.atime);
         },
     )
     .await;
 }
 
-// #[tokio::test]
-// #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn _test_sample() {
+#[tokio::test]
+#[traced_test]
+async fn test_create_many() {
     run_test(
         TestSetup {
-            key: "test_sample",
+            key: "test_create_many",
             read_only: false,
         },
         async {
-            let _ = get_fs().await;
+            let fs = get_fs().await;
+
+            let entries: Vec<_> = (0..100)
+                .map(|i| {
+                    (
+                        SecretString::from_str(&format!("test-file-{i}")).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        format!("test-{i}").into_bytes(),
+                    )
+                })
+                .collect();
+            let parent_mtime = fs.get_attr(ROOT_INODE).await.unwrap().mtime;
+            let attrs = fs.create_many(ROOT_INODE, entries).await.unwrap();
+            assert_eq!(attrs.len(), 100);
+            assert!(fs.get_attr(ROOT_INODE).await.unwrap().mtime > parent_mtime);
+            for (i, attr) in attrs.iter().enumerate() {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let found = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+                assert_eq!(found, *attr);
+                assert_eq!(attr.size, format!("test-{i}").len() as u64);
+                assert_eq!(
+                    format!("test-{i}"),
+                    test_common::read_to_string(attr.ino, &fs).await
+                );
+            }
+            assert_eq!(
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry
+                        .as_ref()
+                        .unwrap()
+                        .name
+                        .expose_secret()
+                        .starts_with("test-file-"))
+                    .count(),
+                100
+            );
+
+            // nothing is created if any of the names is not valid
+            let entries = vec![
+                (
+                    SecretString::from_str("new-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    vec![],
+                ),
+                (
+                    SecretString::from_str("test-file-42").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    vec![],
+                ),
+            ];
+            assert!(matches!(
+                fs.create_many(ROOT_INODE, entries).await,
+                Err(FsError::AlreadyExists)
+            ));
+            let new_file = SecretString::from_str("new-file").unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &new_file).unwrap());
+            let entries = vec![
+                (new_file.clone(), create_attr(FileType::RegularFile), vec![]),
+                (new_file.clone(), create_attr(FileType::RegularFile), vec![]),
+            ];
+            assert!(matches!(
+                fs.create_many(ROOT_INODE, entries).await,
+                Err(FsError::AlreadyExists)
+            ));
+            let entries = vec![(new_file.clone(), create_attr(FileType::Directory), vec![])];
+            assert!(matches!(
+                fs.create_many(ROOT_INODE, entries).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(!fs.exists_by_name(ROOT_INODE, &new_file).unwrap());
         },
     )
     .await;
@@ -2332,28 +8926,116 @@ async fn _test_sample() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_read_only_create() {
+async fn test_verify_file() {
     run_test(
         TestSetup {
-            key: "test_read_only_create",
-            read_only: true,
+            key: "test_verify_file",
+            read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            // Check creating a file in a read only fs
-            let test_file = SecretString::from_str("test-file").unwrap();
-            let create_file_result = fs
+            let (_, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &test_file,
+                    &SecretString::from_str("test-file-empty").unwrap(),
                     create_attr(FileType::RegularFile),
-                    true,
+                    false,
                     false,
                 )
-                .await;
-            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+                .await
+                .unwrap();
+            fs.verify_file(attr.ino).await.unwrap();
+
+            let mut files = vec![];
+            for (name, byte) in [("test-file-1", b'a'), ("test-file-2", b'b')] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                let data = vec![byte; BLOCK_SIZE * 3 + BLOCK_SIZE / 2];
+                let mut offset = 0;
+                while offset < data.len() {
+                    offset += fs
+                        .write(attr.ino, offset as u64, &data[offset..], fh)
+                        .await
+                        .unwrap();
+                }
+                // the MAC is updated on release
+                assert!(matches!(
+                    fs.verify_file(attr.ino).await,
+                    Err(FsError::AlreadyOpenForWrite)
+                ));
+                fs.release(fh).await.unwrap();
+                fs.verify_file(attr.ino).await.unwrap();
+                files.push(attr.ino);
+            }
+            let (ino, other) = (files[0], files[1]);
+            let path = fs.contents_path(ino);
+            let block_len = fs
+                .ciphers
+                .cipher_for(&path)
+                .ciphertext_len(BLOCK_SIZE as u64) as usize;
+            let content = std::fs::read(&path).unwrap();
+
+            // block from the same position in another file
+            let mut spliced = content.clone();
+            spliced[block_len..block_len * 2].copy_from_slice(
+                &std::fs::read(fs.contents_path(other)).unwrap()[block_len..block_len * 2],
+            );
+            std::fs::write(&path, &spliced).unwrap();
+            assert!(matches!(
+                fs.verify_file(ino).await,
+                Err(FsError::IntegrityCheckFailed)
+            ));
+            std::fs::write(&path, &content).unwrap();
+            fs.verify_file(ino).await.unwrap();
+
+            // last blocks removed
+            std::fs::write(&path, &content[..block_len * 2]).unwrap();
+            assert!(matches!(
+                fs.verify_file(ino).await,
+                Err(FsError::IntegrityCheckFailed)
+            ));
+            let fh = fs.open(ino, true, false).await.unwrap();
+            let mut buf = vec![0; BLOCK_SIZE];
+            assert!(matches!(
+                fs.read(ino, BLOCK_SIZE as u64 * 3, &mut buf, fh).await,
+                Err(FsError::IntegrityCheckFailed)
+            ));
+            fs.release(fh).await.unwrap();
+            std::fs::write(&path, &content).unwrap();
+            fs.verify_file(ino).await.unwrap();
+
+            // set_len keeps it in sync, in place and when replacing the content
+            for size in [BLOCK_SIZE as u64 + 42, BLOCK_SIZE as u64 * 5, 0, 42] {
+                fs.set_len(ino, size).await.unwrap();
+                fs.verify_file(ino).await.unwrap();
+            }
+
+            let attrs = fs
+                .create_many(
+                    ROOT_INODE,
+                    vec![(
+                        SecretString::from_str("test-file-3").unwrap(),
+                        create_attr(FileType::RegularFile),
+                        vec![b'c'; BLOCK_SIZE * 2],
+                    )],
+                )
+                .await
+                .unwrap();
+            fs.verify_file(attrs[0].ino).await.unwrap();
+
+            assert!(matches!(
+                fs.verify_file(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
         },
     )
     .await;
@@ -2361,122 +9043,53 @@ async fn test_read_only_create() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_read_only_write() {
+async fn test_async_reader_writer() {
     run_test(
         TestSetup {
-            key: "read_only_test_write",
+            key: "test_async_reader_writer",
             read_only: false,
         },
         async {
-            let fs_rw = get_fs().await;
-            let data_dir = fs_rw.data_dir.clone();
-            let cipher = Cipher::ChaCha20Poly1305;
-            let file1 = SecretString::from_str("file1").unwrap();
-            let file_dest = SecretString::from_str("file_dest").unwrap();
-            let dir1 = SecretString::from_str("dir1").unwrap();
-            let data = "Hello, world!";
+            use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
-            let (fh, attr) = fs_rw
-                .create(
-                    ROOT_INODE,
-                    &file1,
-                    create_attr(FileType::RegularFile),
-                    true,
-                    true,
-                )
-                .await
-                .expect("read_only_test_create: Error creating file.");
-            let (fh_dest, attr_dest) = fs_rw
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_dest,
+                    &SecretString::from_str("test-file").unwrap(),
                     create_attr(FileType::RegularFile),
                     true,
                     true,
                 )
-                .await
-                .expect("read_only_test_create: Error creating file.");
-            let (_, _) = fs_rw
-                .create(
-                    ROOT_INODE,
-                    &dir1,
-                    create_attr(FileType::Directory),
-                    false,
-                    true,
-                )
-                .await
-                .expect("read_only_test_create: Error creating dir.");
-
-            // Create a succesful write on the file
-            crate::encryptedfs::write_all_string_to_fs(&fs_rw, attr.ino, 0, data, fh)
                 .await
                 .unwrap();
-            fs_rw.flush(fh).await.unwrap();
-            fs_rw.release(fh).await.unwrap();
-            drop(fs_rw);
-            let fs_ro = EncryptedFs::new(data_dir, Box::new(PasswordProviderImpl {}), cipher, true)
-                .await
-                .expect("test_read_only_write: Error creating rw fs.");
-            let fh = fs_ro
-                .open(attr.ino, true, false)
+            let data = "test-42".repeat(BLOCK_SIZE / 3);
+            let mut writer = fs.async_writer(attr.ino, fh);
+            tokio::io::copy(&mut data.as_bytes(), &mut writer)
                 .await
-                .expect("read_only_test_create: Error opening file in ro.");
+                .unwrap();
+            writer.seek(std::io::SeekFrom::Start(4)).await.unwrap();
+            writer.write_all(b"_").await.unwrap();
+            writer.shutdown().await.unwrap();
+            let data = format!("test_{}", &data[5..]);
+            assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
 
-            // Test a succesful reading the file in rw mode
-            let mut buf = vec![0; data.len()];
-            fs_ro.read(attr.ino, 0, &mut buf, fh).await.unwrap();
-            assert_eq!(data, String::from_utf8(buf).unwrap());
+            // ends at the size of the file
+            let mut reader = fs.async_reader(attr.ino, fh);
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(data.as_bytes(), &buf);
+            assert_eq!(0, reader.read(&mut [0; 10]).await.unwrap());
 
-            // Test creating a file
-            let file2 = SecretString::from_str("file2").unwrap();
-            let create_file_result = fs_ro
-                .create(
-                    ROOT_INODE,
-                    &file2,
-                    create_attr(FileType::RegularFile),
-                    true,
-                    true,
-                )
-                .await;
-            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
-            // Test renaming the file
-            let new_file = SecretString::from_str("file1").unwrap();
-            let rename_result = fs_ro
-                .rename(ROOT_INODE, &file1, ROOT_INODE, &new_file)
-                .await;
-            assert!(matches!(rename_result, Err(FsError::ReadOnly)));
-            // Test removing a file
-            let remove_file_result = fs_ro.remove_file(ROOT_INODE, &file1).await;
-            assert!(matches!(remove_file_result, Err(FsError::ReadOnly)));
-            // Test copy file range
-            let file_range_req = CopyFileRangeReq::builder()
-                .src_ino(attr.ino)
-                .src_offset(0)
-                .dest_ino(attr_dest.ino)
-                .dest_offset(0)
-                .src_fh(fh)
-                .dest_fh(fh_dest)
-                .build();
-            let copy_file_range_result = fs_ro.copy_file_range(&file_range_req, data.len()).await;
-            assert!(matches!(copy_file_range_result, Err(FsError::ReadOnly)));
-            // Test removing a dir
-            let remove_dir_result = fs_ro.remove_dir(ROOT_INODE, &dir1).await;
-            assert!(matches!(remove_dir_result, Err(FsError::ReadOnly)));
-            // Test changing the length of the file
-            let set_len_result = fs_ro.set_len(attr.ino, 55).await;
-            assert!(matches!(set_len_result, Err(FsError::ReadOnly)));
-            // Test setting attr of a file
-            let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-            let set_attr_result = fs_ro.set_attr(attr.ino, set_attr).await;
-            assert!(matches!(set_attr_result, Err(FsError::ReadOnly)));
-            // Test writing to file with Read Only enabled.
-            let write_all_strings_result =
-                crate::encryptedfs::write_all_string_to_fs(&fs_ro, attr.ino, 0, data, fh).await;
-            assert!(matches!(write_all_strings_result, Err(FsError::ReadOnly)));
-            // Test flushing data to file
-            let flush_result = fs_ro.flush(fh).await;
-            assert!(matches!(flush_result, Err(FsError::ReadOnly)));
+            let pos = reader.seek(std::io::SeekFrom::End(-2)).await.unwrap();
+            assert_eq!(data.len() as u64 - 2, pos);
+            let mut buf = String::new();
+            reader.read_to_string(&mut buf).await.unwrap();
+            assert_eq!(&data[data.len() - 2..], buf);
+            reader.seek(std::io::SeekFrom::Start(0)).await.unwrap();
+            assert!(reader.seek(std::io::SeekFrom::Current(-1)).await.is_err());
+            fs.release(fh).await.unwrap();
         },
     )
     .await;
@@ -0,0 +1,416 @@
+//! Rollback journal for the content of files, so a crash or power loss while writing leaves a file at
+//! the version from its last commit, never a torn mix of old and new blocks.
+//!
+//! On the first change after a commit the inode, the [`HoleMap`](crate::crypto::holes::HoleMap) and the length of
+//! the content are saved in `wal/<ino>`.
+//! Before a range of the content which existed at that time is overwritten, its ciphertext is copied to
+//! `wal/<ino>/<chunk>`, changes which replace the whole file keep a hard link to the old one instead.
+//! After the new content and inode are synced the journal is removed, that's the commit.
+//! Journals left by a crash are rolled back when the filesystem is opened.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use shush_rs::SecretVec;
+
+use crate::bincode_util;
+use crate::crypto;
+use crate::encryptedfs::cipher_tags::CipherTags;
+use crate::encryptedfs::inode_store::InodeStore;
+use crate::encryptedfs::storage::{Storage, StorageFile};
+use crate::encryptedfs::{FsResult, CONTENT_HOLES_SUFFIX};
+
+/// The inode as it was at the last commit.
+const INODE_FILENAME: &str = "inode";
+/// The hole map at the last commit, as it's on disk, empty if the file had none.
+const HOLES_FILENAME: &str = "holes";
+/// The length of the content at the last commit and the length of the chunks, encrypted.
+/// Written last when the journal starts, without it the journal is incomplete and nothing was changed.
+const META_FILENAME: &str = "meta";
+/// Hard link to the content at the last commit, for changes which replace the file.
+const CONTENTS_FILENAME: &str = "contents";
+
+#[derive(Default)]
+struct State {
+    // `None` until the first change after a commit
+    len: Option<u64>,
+    saved: HashSet<u64>,
+    replaced: bool,
+}
+
+/// Journal of the content of one file, shared by all the writers of the file.
+pub(crate) struct Journal {
+    dir: PathBuf,
+    contents_path: PathBuf,
+    inodes: Arc<InodeStore>,
+    ino: u64,
+    chunk_len: u64,
+    ciphers: Arc<CipherTags>,
+    state: Mutex<State>,
+}
+
+impl Journal {
+    /// `chunk_len` is the length on disk of a block, so a block is saved at most once.
+    pub(crate) fn new(
+        dir: PathBuf,
+        contents_path: PathBuf,
+        inodes: Arc<InodeStore>,
+        ino: u64,
+        chunk_len: u64,
+        ciphers: Arc<CipherTags>,
+    ) -> Self {
+        Self {
+            dir,
+            contents_path,
+            inodes,
+            ino,
+            chunk_len,
+            ciphers,
+            state: Mutex::default(),
+        }
+    }
+
+    fn start(&self, state: &mut State, key: &SecretVec<u8>) -> io::Result<()> {
+        if state.len.is_some() {
+            return Ok(());
+        }
+        let storage = self.ciphers.storage();
+        // leftovers of a start which didn't finish
+        if storage.exists(&self.dir) {
+            storage.remove_dir_all(&self.dir)?;
+        }
+        storage.create_dir_all(&self.dir)?;
+        let len = storage.metadata(&self.contents_path)?.len;
+        self.inodes
+            .save_copy(self.ino, &self.dir.join(INODE_FILENAME))?;
+        let holes_copy = self.dir.join(HOLES_FILENAME);
+        let holes_path = holes_path(&self.contents_path);
+        if storage.exists(&holes_path) {
+            storage.copy(&holes_path, &holes_copy)?;
+            storage.open(&holes_copy)?.sync_all()?;
+        } else {
+            storage.create(&holes_copy)?.sync_all()?;
+        }
+        let meta_path = self.dir.join(META_FILENAME);
+        let cipher = self
+            .ciphers
+            .for_write(&meta_path)
+            .map_err(io::Error::other)?;
+        crypto::serialize_encrypt_into(
+            storage.create_atomic(&meta_path)?,
+            &(len, self.chunk_len),
+            cipher,
+            key,
+        )
+        .map_err(io::Error::other)?
+        .commit()?;
+        storage.sync_dir(self.dir.parent().unwrap())?;
+        state.len = Some(len);
+        Ok(())
+    }
+
+    /// Save what `[offset, offset + len)` of `file` had at the last commit, before it's overwritten.
+    pub(crate) fn before_write(
+        &self,
+        file: &mut dyn StorageFile,
+        offset: u64,
+        len: u64,
+        key: &SecretVec<u8>,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.start(&mut state, key)?;
+        let committed_len = state.len.unwrap();
+        if state.replaced || len == 0 || offset >= committed_len {
+            // the old content is kept whole or there is nothing to keep
+            return Ok(());
+        }
+        let end = (offset + len).min(committed_len);
+        let storage = self.ciphers.storage();
+        let mut saved_any = false;
+        for chunk in offset / self.chunk_len..=(end - 1) / self.chunk_len {
+            if state.saved.contains(&chunk) {
+                continue;
+            }
+            let start = chunk * self.chunk_len;
+            #[allow(clippy::cast_possible_truncation)]
+            let mut buf = vec![0; self.chunk_len.min(committed_len - start) as usize];
+            let pos = file.stream_position()?;
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf)?;
+            file.seek(SeekFrom::Start(pos))?;
+            // ciphertext, it's already encrypted
+            let tmp = self.dir.join(format!("{chunk}.tmp"));
+            let mut copy = storage.create(&tmp)?;
+            copy.write_all(&buf)?;
+            copy.sync_all()?;
+            storage.rename(&tmp, &self.dir.join(chunk.to_string()))?;
+            state.saved.insert(chunk);
+            saved_any = true;
+        }
+        if saved_any {
+            storage.sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Keep the whole content as it was at the last commit, before the file is replaced with a new one.
+    pub(crate) fn before_replace(&self, key: &SecretVec<u8>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.start(&mut state, key)?;
+        if !state.replaced {
+            // the chunks saved so far are applied over it on rollback
+            let storage = self.ciphers.storage();
+            storage.hard_link(&self.contents_path, &self.dir.join(CONTENTS_FILENAME))?;
+            storage.sync_dir(&self.dir)?;
+            state.replaced = true;
+        }
+        Ok(())
+    }
+
+    /// The content and the inode are synced, forget the old version.
+    pub(crate) fn commit(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.len.is_none() {
+            return Ok(());
+        }
+        let storage = self.ciphers.storage();
+        storage.remove_dir_all(&self.dir)?;
+        storage.sync_dir(self.dir.parent().unwrap())?;
+        *state = State::default();
+        Ok(())
+    }
+
+    /// Bring the content back to the last commit while the file is opened, like after a failed write.
+    ///
+    /// The inode is kept, only the content is changed between commits, other changes of the inode are not lost.
+    pub(crate) fn revert(&self, key: &SecretVec<u8>) -> FsResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.len.is_none() {
+            return Ok(());
+        }
+        rollback_contents(&self.dir, &self.contents_path, &self.ciphers, key)?;
+        let storage = self.ciphers.storage();
+        storage.remove_dir_all(&self.dir)?;
+        storage.sync_dir(self.dir.parent().unwrap())?;
+        *state = State::default();
+        Ok(())
+    }
+}
+
+/// Remove the journal in `dir` of a removed file, the copy of the inode is shredded first, see
+/// [`FsConfig::shred`](super::FsConfig::shred).
+pub(crate) fn shred(storage: &dyn Storage, dir: &Path) -> io::Result<()> {
+    let ino_copy = dir.join(INODE_FILENAME);
+    if storage.exists(&ino_copy) {
+        storage.shred(&ino_copy)?;
+    }
+    storage.remove_dir_all(dir)
+}
+
+/// Bring the content and the inode back to the last commit, from the journal in `dir` left by a crash.
+pub(crate) fn rollback(
+    dir: &Path,
+    contents_path: &Path,
+    inodes: &InodeStore,
+    ino: u64,
+    ciphers: &CipherTags,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    if rollback_contents(dir, contents_path, ciphers, key)? {
+        let ino_copy = dir.join(INODE_FILENAME);
+        if ciphers.storage().exists(&ino_copy) {
+            inodes.restore_copy(ino, &ino_copy)?;
+        }
+    }
+    ciphers.storage().remove_dir_all(dir)?;
+    ciphers.storage().sync_dir(dir.parent().unwrap())?;
+    Ok(())
+}
+
+/// Bring only the content back to the last commit, returns `false` if the journal is incomplete and
+/// nothing was changed.
+fn rollback_contents(
+    dir: &Path,
+    contents_path: &Path,
+    ciphers: &CipherTags,
+    key: &SecretVec<u8>,
+) -> FsResult<bool> {
+    let storage = ciphers.storage();
+    let meta_path = dir.join(META_FILENAME);
+    if storage.exists(&meta_path) {
+        let (file, cipher) = ciphers.open(&meta_path)?;
+        let (len, chunk_len): (u64, u64) = bincode_util::deserialize_from(
+            crypto::create_read(file, cipher, key),
+            bincode_util::METADATA_LIMIT,
+        )?;
+        let whole = dir.join(CONTENTS_FILENAME);
+        if storage.exists(&whole) {
+            storage.rename(&whole, contents_path)?;
+        }
+        let mut file = storage.open_write(contents_path)?;
+        for name in storage.read_dir(dir)? {
+            let Some(chunk) = name.to_str().and_then(|name| name.parse::<u64>().ok()) else {
+                continue;
+            };
+            file.seek(SeekFrom::Start(chunk * chunk_len))?;
+            io::copy(&mut storage.open(&dir.join(name))?, &mut file)?;
+        }
+        file.set_len(len)?;
+        file.sync_all()?;
+        // missing in journals from before the hole maps were kept
+        let holes_copy = dir.join(HOLES_FILENAME);
+        if storage.exists(&holes_copy) {
+            let holes_path = holes_path(contents_path);
+            if storage.metadata(&holes_copy)?.len > 0 {
+                storage.rename(&holes_copy, &holes_path)?;
+            } else if storage.exists(&holes_path) {
+                storage.remove_file(&holes_path)?;
+            }
+        }
+        storage.sync_dir(contents_path.parent().unwrap())?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// The file next to the content which keeps its hole map.
+fn holes_path(contents_path: &Path) -> PathBuf {
+    let mut name = contents_path.file_name().unwrap_or_default().to_os_string();
+    name.push(CONTENT_HOLES_SUFFIX);
+    contents_path.with_file_name(name)
+}
+
+/// The content file of a writer, which saves the old content in the [`Journal`] before overwriting it.
+pub(crate) struct JournaledFile {
+    file: Box<dyn StorageFile>,
+    journal: Arc<Journal>,
+    key: Arc<SecretVec<u8>>,
+}
+
+impl JournaledFile {
+    pub(crate) fn new(
+        file: Box<dyn StorageFile>,
+        journal: Arc<Journal>,
+        key: Arc<SecretVec<u8>>,
+    ) -> Self {
+        Self { file, journal, key }
+    }
+
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Like [`StorageFile::set_len`], a shrink saves the content which is cut.
+    pub(crate) fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let file_len = self.file.len()?;
+        if len < file_len {
+            self.journal
+                .before_write(&mut *self.file, len, file_len - len, &self.key)?;
+        }
+        self.file.set_len(len)
+    }
+}
+
+impl Write for JournaledFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pos = self.file.stream_position()?;
+        self.journal
+            .before_write(&mut *self.file, pos, buf.len() as u64, &self.key)?;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Read for JournaledFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for JournaledFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    use shush_rs::SecretVec;
+
+    use super::{rollback, Journal, JournaledFile};
+    use crate::crypto::Cipher;
+    use crate::encryptedfs::cipher_tags::CipherTags;
+    use crate::encryptedfs::inode_store::InodeStore;
+    use crate::encryptedfs::{INODES_DIR, SECURITY_DIR};
+
+    #[test]
+    fn test_rollback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data_dir = tmp.path();
+        fs::create_dir_all(data_dir.join(SECURITY_DIR)).unwrap();
+        let ciphers = Arc::new(CipherTags::load(data_dir, Cipher::ChaCha20Poly1305).unwrap());
+        let key = Arc::new(SecretVec::new(Box::new(vec![42_u8; 32])));
+        let contents_path = data_dir.join("contents");
+        let inodes = Arc::new(InodeStore::open(data_dir, None, ciphers.clone(), false).unwrap());
+        let ino_path = data_dir.join(INODES_DIR).join("1");
+        let dir = data_dir.join("wal").join("1");
+        fs::create_dir_all(data_dir.join("wal")).unwrap();
+        fs::create_dir_all(data_dir.join(INODES_DIR)).unwrap();
+        fs::write(&contents_path, b"0123456789").unwrap();
+        fs::write(&ino_path, b"old").unwrap();
+
+        let journal = Arc::new(Journal::new(
+            dir.clone(),
+            contents_path.clone(),
+            inodes.clone(),
+            1,
+            4,
+            ciphers.clone(),
+        ));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&contents_path)
+            .unwrap();
+        let mut file = JournaledFile::new(Box::new(file), journal.clone(), key.clone());
+        file.seek(SeekFrom::Start(5)).unwrap();
+        file.write_all(b"abcdefgh").unwrap();
+        fs::write(&ino_path, b"new").unwrap();
+        assert_eq!(fs::read(&contents_path).unwrap(), b"01234abcdefgh");
+
+        // crash before commit
+        rollback(&dir, &contents_path, &inodes, 1, &ciphers, &key).unwrap();
+        assert_eq!(fs::read(&contents_path).unwrap(), b"0123456789");
+        assert_eq!(fs::read(&ino_path).unwrap(), b"old");
+        assert!(!dir.exists());
+
+        // committed changes stay
+        let journal = Arc::new(Journal::new(
+            dir.clone(),
+            contents_path.clone(),
+            inodes.clone(),
+            1,
+            4,
+            ciphers,
+        ));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&contents_path)
+            .unwrap();
+        let mut file = JournaledFile::new(Box::new(file), journal.clone(), key);
+        file.set_len(2).unwrap();
+        journal.commit().unwrap();
+        assert!(!dir.exists());
+        assert_eq!(fs::read(&contents_path).unwrap(), b"01");
+    }
+}
@@ -15,6 +15,24 @@ pub trait ValueProvider<T, E: Error + Send + Sync + 'static>: Send + Sync + 'sta
     async fn provide(&self) -> Result<T, E>;
 }
 
+#[async_trait]
+impl<T: Send + 'static, E: Error + Send + Sync + 'static> ValueProvider<T, E>
+    for Box<dyn ValueProvider<T, E>>
+{
+    async fn provide(&self) -> Result<T, E> {
+        (**self).provide().await
+    }
+}
+
+#[async_trait]
+impl<T: Send + 'static, E: Error + Send + Sync + 'static, P: ValueProvider<T, E>>
+    ValueProvider<T, E> for Arc<P>
+{
+    async fn provide(&self) -> Result<T, E> {
+        (**self).provide().await
+    }
+}
+
 /// It keeps the value in memory while it's being used and while there are strong references to it.
 ///
 /// After the specified `duration` it will remove it from internal cache and just keep it while there are strong references to it, after which it will be zeroized and dropped from memory.  
@@ -55,6 +73,7 @@ impl<
         s
     }
 
+    /// If the provider fails the error is returned and nothing is cached, so the next call asks it again.
     pub async fn get(&self) -> Result<Arc<T>, E> {
         if let Some(value) = self.get_from_ref_or_cache().await {
             return Ok(value);
@@ -69,6 +88,11 @@ impl<
         Ok(v)
     }
 
+    /// The provider used to create the value.
+    pub const fn provider(&self) -> &P {
+        &self.provider
+    }
+
     async fn get_from_ref_or_cache(&self) -> Option<Arc<T>> {
         let lock = self.weak.read().await;
         if let Some(ref weak) = *lock {
@@ -117,6 +141,33 @@ mod tests {
         }
     }
 
+    struct FailingProvider {
+        called: Arc<AtomicUsize>,
+    }
+    #[async_trait]
+    impl ValueProvider<String, std::io::Error> for FailingProvider {
+        async fn provide(&self) -> Result<String, std::io::Error> {
+            if self.called.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(std::io::Error::other("unavailable"));
+            }
+            Ok("test".to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_provider_error_not_cached() {
+        let called = Arc::new(AtomicUsize::new(0));
+        // a transient error of the provider doesn't stick
+        let provider: Box<dyn ValueProvider<String, std::io::Error>> = Box::new(FailingProvider {
+            called: called.clone(),
+        });
+        let expire_value = ExpireValue::new(provider, Duration::from_secs(1));
+        assert!(expire_value.get().await.is_err());
+        assert_eq!(*expire_value.get().await.unwrap(), "test");
+        assert_eq!(*expire_value.get().await.unwrap(), "test");
+        assert_eq!(called.load(Ordering::SeqCst), 2);
+    }
+
     #[tokio::test]
     async fn test_expire_value() {
         let called = Arc::new(AtomicUsize::new(0));
@@ -2,7 +2,7 @@
 use atomic_write_file::unix::OpenOptionsExt;
 use atomic_write_file::AtomicWriteFile;
 use futures_util::TryStreamExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 use tokio_stream::wrappers::ReadDirStream;
 
@@ -36,6 +36,204 @@ pub async fn rename_dir_content(src: &Path, dst: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Recursively copies the content of a directory to another.
+/// It will create destination directory if it doesn't exist.
+pub fn copy_dir_content(src: &Path, dst: &Path) -> io::Result<()> {
+    if !src.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "source directory does not exist",
+        ));
+    }
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.join(entry.file_name());
+        if entry.path().is_dir() {
+            copy_dir_content(&entry.path(), &dst)?;
+        } else {
+            fs::copy(entry.path(), dst)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively syncs to disk all files and directories in a directory, including it.
+/// Entries removed while we walk are skipped.
+pub fn sync_dir_all(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let res = if entry.file_type()?.is_dir() {
+            sync_dir_all(&entry.path())
+        } else {
+            fs::File::open(entry.path()).and_then(|file| file.sync_all())
+        };
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+    }
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Sync to disk the files and directories in `paths`, which are all inside `dir`, with as few syncs as possible.
+///
+/// On Linux the whole filesystem `dir` is on is synced at once, like `syncfs(2)`, which is much faster than
+/// syncing many small files one by one. Elsewhere each path and its parent are synced.
+pub fn sync_paths(dir: &Path, paths: &[PathBuf]) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let _ = paths;
+        let dir = fs::File::open(dir)?;
+        if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = dir;
+        let mut parents = std::collections::BTreeSet::new();
+        for path in paths {
+            fs::File::open(path)?.sync_all()?;
+            if let Some(parent) = path.parent() {
+                parents.insert(parent);
+            }
+        }
+        for parent in parents {
+            fs::File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// If the file has holes, so it uses less space on disk than its length.
+pub fn is_sparse(metadata: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // `blocks` is always in 512 bytes units
+        metadata.blocks() * 512 < metadata.len()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// Blocks of 512 bytes the file takes on disk, `None` where the OS doesn't tell.
+pub fn allocated_blocks(metadata: &fs::Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.blocks())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Number of hard links to the file, always `1` where the OS doesn't tell.
+pub fn hard_links(metadata: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        1
+    }
+}
+
+/// If both are hard links to the same file, always `false` where the OS doesn't tell.
+pub fn is_same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    file_id(a).is_some_and(|id| file_id(b) == Some(id))
+}
+
+/// The device and the inode of the file, the same for all its hard links, `None` where the OS doesn't tell.
+pub fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Reserve space on disk for the first `len` bytes of the file without changing its length, like
+/// `fallocate(2)` with `FALLOC_FL_KEEP_SIZE`.
+///
+/// It's best effort, where the filesystem or the OS doesn't support it nothing is reserved.
+pub fn reserve(file: &fs::File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let len =
+            i64::try_from(len).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, len);
+        Ok(())
+    }
+}
+
+/// Overwrite the file with zeros and sync it before removing it, so what it had is not left in the blocks it used.
+///
+/// It's best effort, filesystems which write the changes to new blocks, like copy-on-write ones or SSDs which remap
+/// them, might still keep the old content.
+pub fn shred(path: &Path) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    file.write_all(&vec![0; usize::try_from(len).unwrap_or_default()])?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+/// If a file named `name` can be created in `dir`, by creating and removing it. Filesystems like FAT, exFAT
+/// and NTFS don't allow some characters in names.
+pub fn can_create(dir: &Path, name: &str) -> io::Result<bool> {
+    let path = dir.join(name);
+    match fs::File::create_new(&path) {
+        Ok(_) => {
+            fs::remove_file(path)?;
+            Ok(true)
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(true),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::InvalidInput | io::ErrorKind::InvalidFilename
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
 pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     let mut opt = AtomicWriteFile::options();
     opt.read(true);
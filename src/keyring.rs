@@ -1,57 +1,95 @@
-use std::str::FromStr;
+//! Keep the password in the keyring of the OS, with the [keyring](https://crates.io/crates/keyring) crate.
+//!
+//! Store it once with [`store_password`], then give [`KeyringPasswordProvider`] to
+//! [`EncryptedFs::new`](crate::encryptedfs::EncryptedFs::new) or
+//! [`mount::create_mount_point`](crate::mount::create_mount_point), so it's read from the keyring each time the key
+//! needs to be derived again, instead of keeping it in memory.
 
-use keyring::Entry;
+use ::keyring::Entry;
 use shush_rs::{ExposeSecret, SecretString};
+use tracing::error;
 
-#[allow(dead_code)]
-const KEYRING_SERVICE: &str = "rencfs";
-#[allow(dead_code)]
-const KEYRING_USER: &str = "rencfs";
+use crate::encryptedfs::{FsResult, PasswordProvider};
 
-#[allow(dead_code)]
-pub(crate) fn save(password: &SecretString, suffix: &str) -> Result<(), keyring::Error> {
-    let entry = Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}"))?;
-    entry.set_password(&password.expose_secret())
+/// Gets the password from the entry `account` of `service` in the keyring, see the [module docs](self).
+pub struct KeyringPasswordProvider {
+    service: String,
+    account: String,
 }
 
-#[allow(dead_code)]
-pub(crate) fn remove(suffix: &str) -> Result<(), keyring::Error> {
-    let entry = Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}"))?;
-    entry.delete_password()
+impl KeyringPasswordProvider {
+    #[must_use]
+    pub fn new(service: &str, account: &str) -> Self {
+        Self {
+            service: service.to_owned(),
+            account: account.to_owned(),
+        }
+    }
+}
+
+impl PasswordProvider for KeyringPasswordProvider {
+    /// `None` if there is no such entry or the keyring can't be used, so opening the volume fails with
+    /// [`FsError::InvalidPassword`](crate::encryptedfs::FsError::InvalidPassword).
+    fn get_password(&self) -> Option<SecretString> {
+        match Entry::new(&self.service, &self.account).and_then(|entry| entry.get_password()) {
+            // moved, not copied, so there is no other copy of it left in memory
+            Ok(password) => Some(SecretString::new(Box::new(password))),
+            Err(::keyring::Error::NoEntry) => None,
+            Err(err) => {
+                error!(err = %err, "cannot get password from keyring");
+                None
+            }
+        }
+    }
 }
 
-#[allow(dead_code)]
-pub(crate) fn get(suffix: &str) -> Result<SecretString, keyring::Error> {
-    let entry = Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}"))?;
-    Ok(SecretString::from_str(&entry.get_password()?).unwrap())
+/// Save `password` in the entry `account` of `service`, it replaces what the entry had.
+#[allow(clippy::missing_errors_doc)]
+pub fn store_password(service: &str, account: &str, password: &SecretString) -> FsResult<()> {
+    Entry::new(service, account)?.set_password(&password.expose_secret())?;
+    Ok(())
+}
+
+/// Remove the entry `account` of `service`, it's fine if it doesn't exist.
+#[allow(clippy::missing_errors_doc)]
+pub fn delete_password(service: &str, account: &str) -> FsResult<()> {
+    match Entry::new(service, account)?.delete_password() {
+        Ok(()) | Err(::keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use super::*;
 
-    #[test]
-    fn test_save() {
-        let password = SecretString::from_str("password").unwrap();
-        assert!(save(&password, "test1").is_ok());
-        remove("test1").unwrap();
-    }
+    const SERVICE: &str = "rencfs";
 
     #[test]
-    fn test_get() {
+    fn test_store_password() {
         let password = SecretString::from_str("password").unwrap();
-        save(&password, "test2").unwrap();
+        store_password(SERVICE, "rencfs.test1", &password).unwrap();
         assert_eq!(
-            get("test2").unwrap().expose_secret(),
+            KeyringPasswordProvider::new(SERVICE, "rencfs.test1")
+                .get_password()
+                .unwrap()
+                .expose_secret(),
             password.expose_secret()
         );
-        remove("test2").unwrap();
+        delete_password(SERVICE, "rencfs.test1").unwrap();
     }
 
     #[test]
-    fn test_remove() {
+    fn test_delete_password() {
         let password = SecretString::from_str("password").unwrap();
-        save(&password, "test3").unwrap();
-        assert!(remove("test3").is_ok());
+        store_password(SERVICE, "rencfs.test2", &password).unwrap();
+        delete_password(SERVICE, "rencfs.test2").unwrap();
+        assert!(KeyringPasswordProvider::new(SERVICE, "rencfs.test2")
+            .get_password()
+            .is_none());
+        // already removed
+        delete_password(SERVICE, "rencfs.test2").unwrap();
     }
 }
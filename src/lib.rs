@@ -31,6 +31,7 @@
 //! use rencfs::encryptedfs::PasswordProvider;
 //! use rencfs::mount::create_mount_point;
 //! use rencfs::mount::MountPoint;
+//! use rencfs::mount::MountOptions;
 //!
 //! /// This will mount and expose the mount point until you press `Enter`, then it will umount and close the program.
 //! #[tokio::main]
@@ -47,8 +48,8 @@
 //! struct PasswordProviderImpl {}
 //!     impl PasswordProvider for PasswordProviderImpl {
 //!         fn get_password(&self) -> Option<SecretString> {
-//!             // placeholder password, use some secure way to get the password like with [keyring](https://crates.io/crates/keyring) crate
-//!             Some(SecretString::new(Box::new(String::from("pass42"))))
+//!             // placeholder password, use some secure way to get the password like `rencfs::keyring::KeyringPasswordProvider`
+//!             Some(SecretString::new(Box::new(String::from("super-secret-42"))))
 //!         }
 //!     }
 //!     let mount_point = create_mount_point(
@@ -59,6 +60,7 @@
 //!         false,
 //!         false,
 //!         false,
+//!         MountOptions::default().with_create_mount_point_dir(0o755),
 //!     );
 //!     let handle = mount_point.mount().await?;
 //!     let mut buffer = String::new();
@@ -97,8 +99,8 @@
 //! struct PasswordProviderImpl {}
 //! impl PasswordProvider for PasswordProviderImpl {
 //!     fn get_password(&self) -> Option<SecretString> {
-//!         // placeholder password, use some secure way to get the password like with [keyring](https://crates.io/crates/keyring) crate
-//!         Some(SecretString::new(Box::new(String::from("pass42"))))
+//!         // placeholder password, use some secure way to get the password like `rencfs::keyring::KeyringPasswordProvider`
+//!         Some(SecretString::new(Box::new(String::from("super-secret-42"))))
 //!     }
 //! }
 //!
@@ -232,6 +234,8 @@
 //! We also expose a Writer and Reader in encrypted format, which implements [`std::io::Write`], [`std::io::Read`] and [`std::io::Seek`].
 //! You can wrap any [`std::io::Write`] and [`std::io::Read`], like a file, to write and read encrypted content.
 //! This is using [ring](https://crates.io/crates/ring) crate to handle encryption.
+//! To encrypt or decrypt a whole file there are [`crypto::encrypt_file`] and [`crypto::decrypt_file`], which replace
+//! the destination atomically.
 //!
 //! ### Example
 //! ```no_run
@@ -239,8 +243,6 @@
 //! use rand_core::RngCore;
 //! use std::env::args;
 //! use std::fs::File;
-//! use std::io;
-//! use std::io::Write;
 //! use std::path::Path;
 //! use std::sync::Arc;
 //!
@@ -248,7 +250,6 @@
 //! use tracing::info;
 //!
 //! use rencfs::crypto;
-//! use rencfs::crypto::write::CryptoWrite;
 //! use rencfs::crypto::Cipher;
 //!
 //! fn main() -> Result<()> {
@@ -270,15 +271,12 @@
 //!         std::fs::remove_file(&out)?;
 //!     }
 //!
-//!     let mut file = File::open(path_in.clone())?;
-//!     let mut writer = crypto::create_write(File::create(out.clone())?, cipher, &key);
 //!     info!("encrypt file");
-//!     io::copy(&mut file, &mut writer).unwrap();
-//!     writer.finish()?;
+//!     let (_, hash1) = crypto::encrypt_file(Path::new(&path_in), &out, cipher, &key)?;
 //!
+//!     // or stream it yourself
 //!     let mut reader = crypto::create_read(File::open(out)?, cipher, &key);
 //!     info!("read file and compare hash to original one");
-//!     let hash1 = crypto::hash_reader(&mut File::open(path_in)?)?;
 //!     let hash2 = crypto::hash_reader(&mut reader)?;
 //!     assert_eq!(hash1, hash2);
 //!
@@ -291,10 +289,13 @@ use std::sync::LazyLock;
 
 pub mod arc_hashmap;
 pub mod async_util;
+pub mod bincode_util;
+pub mod block_cache;
 pub mod crypto;
 pub mod encryptedfs;
 pub mod expire_value;
 pub mod fs_util;
+pub mod keyring;
 pub mod log;
 pub mod mount;
 pub mod stream_util;
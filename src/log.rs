@@ -1,5 +1,7 @@
-use crate::is_debug;
-use std::io;
+use crate::{crypto, is_debug};
+use shush_rs::{ExposeSecret, SecretString};
+use std::ffi::OsStr;
+use std::{fmt, io};
 use tracing::level_filters::LevelFilter;
 use tracing::Level;
 use tracing_appender::non_blocking::WorkerGuard;
@@ -30,3 +32,83 @@ pub fn log_init(level: Level) -> WorkerGuard {
 
     guard
 }
+
+/// Wraps a file name, so it can be logged without leaking the plaintext.
+///
+/// It keeps only a short hash of the name, which is printed as `<redacted:HASH>` by both [`fmt::Display`] and
+/// [`fmt::Debug`]. That is enough to correlate log lines about the same name.
+/// Use it for any name which gets to logs, like `#[instrument(fields(name = %RedactedName::from(name)))]`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RedactedName(String);
+
+impl RedactedName {
+    #[must_use]
+    pub fn new(name: &[u8]) -> Self {
+        Self(hex::encode(&crypto::hash(name)[..4]))
+    }
+}
+
+impl From<&str> for RedactedName {
+    fn from(name: &str) -> Self {
+        Self::new(name.as_bytes())
+    }
+}
+
+impl From<&OsStr> for RedactedName {
+    fn from(name: &OsStr) -> Self {
+        Self::new(name.as_encoded_bytes())
+    }
+}
+
+impl From<&SecretString> for RedactedName {
+    fn from(name: &SecretString) -> Self {
+        Self::new(name.expose_secret().as_bytes())
+    }
+}
+
+impl fmt::Display for RedactedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted:{}>", self.0)
+    }
+}
+
+impl fmt::Debug for RedactedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::encryptedfs::{DirectoryEntry, FileType};
+
+    #[test]
+    fn test_redacted_name() {
+        let name = "my-secret-file.txt";
+        let redacted = RedactedName::from(name);
+        assert!(!redacted.to_string().contains(name));
+        assert!(!format!("{redacted:?}").contains(name));
+        assert!(redacted.to_string().starts_with("<redacted:"));
+        assert_eq!(redacted, RedactedName::from(OsStr::new(name)));
+        assert_eq!(
+            redacted,
+            RedactedName::from(&SecretString::from_str(name).unwrap())
+        );
+        assert_ne!(redacted, RedactedName::from("other-file.txt"));
+    }
+
+    #[test]
+    fn test_directory_entry_debug_is_redacted() {
+        let name = "my-secret-file.txt";
+        let entry = DirectoryEntry {
+            ino: 42,
+            name: SecretString::from_str(name).unwrap(),
+            kind: FileType::RegularFile,
+        };
+        assert!(!format!("{entry:?}").contains(name));
+        assert!(!format!("{entry:#?}").contains(name));
+    }
+}
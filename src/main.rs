@@ -1,13 +1,11 @@
 use anyhow::Result;
 
-mod keyring;
-
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 mod run;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    #[cfg(target_os = "windows")]
     {
         eprintln!("he he, not yet ready for this platform, but soon my friend, soon :)");
         eprintln!("Bye!");
@@ -20,6 +18,6 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     run::run().await
 }
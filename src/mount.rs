@@ -1,25 +1,57 @@
+use crate::block_cache::BlockCache;
+use crate::crypto::nonce::NonceStrategy;
+use crate::crypto::write::BLOCK_SIZE;
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsResult, PasswordProvider};
+use crate::encryptedfs::{
+    AtimePolicy, CacheConfig, EncryptedFs, FsConfig, FsError, FsResult, PasswordProvider,
+    VolumeFormat,
+};
 use async_trait::async_trait;
 use futures_util::FutureExt;
 use std::future::Future;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 use std::{io, process};
 
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
+use linux::mount_overlay;
+#[cfg(target_os = "linux")]
 use linux::MountHandleInnerImpl;
 #[cfg(target_os = "linux")]
 use linux::MountPointImpl;
 
-#[cfg(not(target_os = "linux"))]
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+use macos::mount_overlay;
+#[cfg(target_os = "macos")]
+use macos::MountHandleInnerImpl;
+#[cfg(target_os = "macos")]
+use macos::MountPointImpl;
+
+#[cfg(target_os = "windows")]
+mod windows;
+// `self::` as there is also the `windows` crate
+#[cfg(target_os = "windows")]
+use self::windows::mount_overlay;
+#[cfg(target_os = "windows")]
+use self::windows::MountHandleInnerImpl;
+#[cfg(target_os = "windows")]
+use self::windows::MountPointImpl;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
 mod dummy;
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+use dummy::mount_overlay;
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
 use dummy::MountHandleInnerImpl;
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
 use dummy::MountPointImpl;
 
 #[async_trait]
@@ -35,19 +67,370 @@ pub trait MountPoint {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        options: MountOptions,
     ) -> Self
     where
         Self: Sized;
     async fn mount(mut self) -> FsResult<MountHandle>;
 }
 
+/// Extra options used when mounting the filesystem.
+///
+/// All of them are optional, when not set we keep the defaults of the FUSE implementation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct MountOptions {
+    /// Max number of requests the kernel keeps in the background queue (async reads, writeback, etc.).
+    /// Higher values help throughput on machines with many cores, lower values keep memory usage down.
+    ///
+    /// Only on Linux. It's set after mount in `/sys/fs/fuse/connections`, as `fuse3` doesn't let us set it on init,
+    /// which needs root, else the mount fails.
+    pub max_background: Option<u16>,
+    /// Number of background requests after which the kernel considers the filesystem congested
+    /// and starts to throttle new async requests. Must not be greater than `max_background`.
+    ///
+    /// Set like [`MountOptions::max_background`], it needs root too.
+    pub congestion_threshold: Option<u16>,
+    /// Run encryption and decryption on a dedicated pool with this many threads,
+    /// see [`FsConfig::crypto_threads`](crate::encryptedfs::FsConfig::crypto_threads).
+    pub crypto_threads: Option<NonZeroUsize>,
+    /// Write times-only inode updates at most once in this interval,
+    /// see [`FsConfig::times_write_back`](crate::encryptedfs::FsConfig::times_write_back).
+    pub times_write_back: Option<Duration>,
+    /// Flush the files opened for write at this interval,
+    /// see [`FsConfig::auto_flush`](crate::encryptedfs::FsConfig::auto_flush).
+    pub auto_flush: Option<Duration>,
+    /// If the mount point is already a rencfs mount, umount it first instead of failing with
+    /// [`FsError::AlreadyMounted`]. Useful to recover after a crash or when retrying a mount.
+    pub umount_first: bool,
+    /// Create the mount point directory, and its parents, with these permissions if it doesn't exist,
+    /// like `0o755`. It's removed on umount if we created it.
+    /// When `None` the mount point must already be a directory.
+    pub create_mount_point_dir: Option<u32>,
+    /// Keep decrypted blocks in this directory, it must be on `tmpfs` or `ramfs`,
+    /// see [`BlockCache`](crate::block_cache::BlockCache).
+    pub block_cache_dir: Option<PathBuf>,
+    /// Max bytes kept in [`MountOptions::block_cache_dir`].
+    pub block_cache_size: usize,
+    /// Decrypt this many blocks ahead of sequential reads,
+    /// see [`FsConfig::readahead_blocks`](crate::encryptedfs::FsConfig::readahead_blocks).
+    pub readahead_blocks: Option<NonZeroUsize>,
+    /// How long the kernel caches name lookups before asking us again, 1 second if not set.
+    ///
+    /// Longer ones assume the mount is the only writer, changes made with another [`EncryptedFs`] on the same data
+    /// dir, or directly in it, are not seen through the mount until they expire.
+    pub entry_timeout: Option<Duration>,
+    /// How long the kernel caches file attributes before asking us again, 1 second if not set.
+    ///
+    /// Like [`MountOptions::entry_timeout`], longer ones assume the mount is the only writer.
+    pub attr_timeout: Option<Duration>,
+    /// When reads update `atime`,
+    /// see [`FsConfig::atime_policy`](crate::encryptedfs::FsConfig::atime_policy).
+    pub atime_policy: AtimePolicy,
+    /// Wait this long for a file opened for write to be released before failing to open it for write again,
+    /// see [`FsConfig::open_write_timeout`](crate::encryptedfs::FsConfig::open_write_timeout).
+    pub open_write_timeout: Option<Duration>,
+    /// How new data dirs are laid out, like the name padding and the nonce strategy. Existing ones keep theirs,
+    /// see [`FsConfig::format`](crate::encryptedfs::FsConfig::format).
+    ///
+    /// When not set on Windows, new data dirs are case-insensitive, see
+    /// [`VolumeFormat::case_insensitive`](crate::encryptedfs::VolumeFormat::case_insensitive).
+    pub format: Option<VolumeFormat>,
+    /// Store identical file contents only once,
+    /// see [`FsConfig::dedup`](crate::encryptedfs::FsConfig::dedup).
+    pub dedup: bool,
+    /// Overwrite the inodes of removed files,
+    /// see [`FsConfig::shred`](crate::encryptedfs::FsConfig::shred).
+    pub shred: bool,
+    /// Max total size of the files in bytes,
+    /// see [`FsConfig::quota`](crate::encryptedfs::FsConfig::quota).
+    pub quota: Option<u64>,
+    /// List directories sorted by name,
+    /// see [`FsConfig::sorted_dirs`](crate::encryptedfs::FsConfig::sorted_dirs).
+    pub sorted_dirs: bool,
+    /// Leave the zeros as holes when files are extended,
+    /// see [`FsConfig::sparse`](crate::encryptedfs::FsConfig::sparse).
+    pub sparse: bool,
+    /// Sizes and TTLs of the metadata caches,
+    /// see [`FsConfig::cache`](crate::encryptedfs::FsConfig::cache).
+    pub cache_config: CacheConfig,
+    /// Let the kernel check the permissions with the mode, owner and group of files, the `default_permissions`
+    /// FUSE option. Always on on macOS.
+    pub default_permissions: bool,
+    /// Show all files as owned by this user, what's stored doesn't change. Useful to serve a volume to another
+    /// user together with `allow_other`. Linux only.
+    pub uid: Option<u32>,
+    /// Show all files as owned by this group, like [`MountOptions::uid`]. Linux only.
+    pub gid: Option<u32>,
+    /// Let the kernel cache writes and send them to us in bigger chunks, aligned to the block size, the
+    /// `writeback_cache` FUSE option. Much fewer writes reach the encryption, but written data is kept only in the
+    /// kernel until it flushes it, and changes made directly in the data dir might be overwritten. Linux only.
+    pub write_back_cache: bool,
+    /// Mount this snapshot instead of the current files, always read-only,
+    /// see [`EncryptedFs::new_readonly_at`](crate::encryptedfs::EncryptedFs::new_readonly_at).
+    pub snapshot: Option<u64>,
+    /// Before mounting, write a file and read it back to check the cipher and the key work, the mount fails if
+    /// they don't, see [`EncryptedFs::self_test`](crate::encryptedfs::EncryptedFs::self_test). Skipped when
+    /// read-only.
+    pub self_test: bool,
+}
+
+impl MountOptions {
+    #[must_use]
+    pub const fn with_max_background(mut self, max_background: u16) -> Self {
+        self.max_background = Some(max_background);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_congestion_threshold(mut self, congestion_threshold: u16) -> Self {
+        self.congestion_threshold = Some(congestion_threshold);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_crypto_threads(mut self, crypto_threads: NonZeroUsize) -> Self {
+        self.crypto_threads = Some(crypto_threads);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_create_mount_point_dir(mut self, mode: u32) -> Self {
+        self.create_mount_point_dir = Some(mode);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_umount_first(mut self, umount_first: bool) -> Self {
+        self.umount_first = umount_first;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_times_write_back(mut self, interval: Duration) -> Self {
+        self.times_write_back = Some(interval);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_auto_flush(mut self, interval: Duration) -> Self {
+        self.auto_flush = Some(interval);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_entry_timeout(mut self, timeout: Duration) -> Self {
+        self.entry_timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_attr_timeout(mut self, timeout: Duration) -> Self {
+        self.attr_timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_open_write_timeout(mut self, timeout: Duration) -> Self {
+        self.open_write_timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub fn with_name_padding(mut self, padding: NonZeroUsize) -> Self {
+        self.format
+            .get_or_insert_with(Default::default)
+            .name_padding = Some(padding);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_readahead_blocks(mut self, blocks: NonZeroUsize) -> Self {
+        self.readahead_blocks = Some(blocks);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_write_back_cache(mut self, write_back_cache: bool) -> Self {
+        self.write_back_cache = write_back_cache;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_shred(mut self, shred: bool) -> Self {
+        self.shred = shred;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_quota(mut self, quota: u64) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_sorted_dirs(mut self, sorted_dirs: bool) -> Self {
+        self.sorted_dirs = sorted_dirs;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_snapshot(mut self, snapshot_id: u64) -> Self {
+        self.snapshot = Some(snapshot_id);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_self_test(mut self, self_test: bool) -> Self {
+        self.self_test = self_test;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_cache_config(mut self, cache_config: CacheConfig) -> Self {
+        self.cache_config = cache_config;
+        self
+    }
+
+    #[must_use]
+    pub fn with_nonce_strategy(mut self, strategy: NonceStrategy) -> Self {
+        self.format
+            .get_or_insert_with(Default::default)
+            .nonce_strategy = strategy;
+        self
+    }
+
+    #[must_use]
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.format
+            .get_or_insert_with(Default::default)
+            .case_insensitive = case_insensitive;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_atime_policy(mut self, policy: AtimePolicy) -> Self {
+        self.atime_policy = policy;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_default_permissions(mut self, default_permissions: bool) -> Self {
+        self.default_permissions = default_permissions;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    #[must_use]
+    pub fn with_block_cache(mut self, dir: impl Into<PathBuf>, max_size: usize) -> Self {
+        self.block_cache_dir = Some(dir.into());
+        self.block_cache_size = max_size;
+        self
+    }
+
+    /// Check the values are accepted by the kernel.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn validate(&self) -> FsResult<()> {
+        if self.max_background == Some(0) {
+            return Err(FsError::InvalidInput(
+                "max_background must be greater than 0",
+            ));
+        }
+        if self.congestion_threshold == Some(0) {
+            return Err(FsError::InvalidInput(
+                "congestion_threshold must be greater than 0",
+            ));
+        }
+        if let (Some(max_background), Some(congestion_threshold)) =
+            (self.max_background, self.congestion_threshold)
+        {
+            if congestion_threshold > max_background {
+                return Err(FsError::InvalidInput(
+                    "congestion_threshold cannot be greater than max_background",
+                ));
+            }
+        }
+        if self.times_write_back == Some(Duration::ZERO) {
+            return Err(FsError::InvalidInput(
+                "times_write_back must be greater than 0",
+            ));
+        }
+        if self.auto_flush == Some(Duration::ZERO) {
+            return Err(FsError::InvalidInput("auto_flush must be greater than 0"));
+        }
+        if self.block_cache_dir.is_some() && self.block_cache_size < BLOCK_SIZE {
+            return Err(FsError::InvalidInput(
+                "block_cache_size must be at least one block",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The [`FsConfig`] to open the filesystem with, it creates the [`BlockCache`] if we have one.
+    pub(crate) fn fs_config(&self) -> FsResult<FsConfig> {
+        let block_cache = match &self.block_cache_dir {
+            Some(dir) => Some(Arc::new(BlockCache::new(dir, self.block_cache_size)?)),
+            None => None,
+        };
+        Ok(FsConfig {
+            cache: self.cache_config,
+            format: self.format,
+            crypto_threads: self.crypto_threads,
+            open_write_timeout: self.open_write_timeout,
+            dedup: self.dedup,
+            shred: self.shred,
+            sorted_dirs: self.sorted_dirs,
+            sparse: self.sparse,
+            times_write_back: self.times_write_back,
+            auto_flush: self.auto_flush,
+            atime_policy: self.atime_policy,
+            block_cache,
+            readahead_blocks: self.readahead_blocks,
+            quota: self.quota,
+            ..FsConfig::default()
+        })
+    }
+}
+
+/// Handle of a mounted filesystem, awaiting it waits until it's umounted.
+///
+/// If it's dropped without [`MountHandle::umount`], like after a panic, it umounts with [`umount`], blocking the
+/// current thread, so it doesn't leave a stale mount.
 #[allow(clippy::module_name_repetitions)]
 pub struct MountHandle {
-    inner: MountHandleInnerImpl,
+    // taken when it's umounted or the session ended, so drop doesn't umount again
+    inner: Option<MountHandleInnerImpl>,
+    mountpoint: PathBuf,
 }
 impl MountHandle {
-    pub async fn umount(self) -> io::Result<()> {
-        self.inner.unmount().await
+    pub async fn umount(mut self) -> io::Result<()> {
+        match self.inner.take() {
+            Some(inner) => inner.unmount().await,
+            None => Ok(()),
+        }
     }
 }
 
@@ -55,7 +438,24 @@ impl Future for MountHandle {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.inner.poll_unpin(cx)
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let res = ready!(inner.poll_unpin(cx));
+        self.inner = None;
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if self.inner.is_none() {
+            return;
+        }
+        tracing::warn!(mountpoint = %self.mountpoint.display(), "mount handle dropped without umount, umounting it");
+        if let Err(err) = umount(&self.mountpoint.to_string_lossy()) {
+            tracing::warn!(err = %err, "cannot umount");
+        }
     }
 }
 
@@ -71,9 +471,15 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 /// **`cipher`** The encryption algorithm to use.
 /// Currently, it supports these ciphers [`Cipher`]
 ///
-/// **`allow_root`** allow root to access the file system  
-/// **`allow_other`** allow other users to access the file system  
-/// **`read_only`** Set FUSE filesystem read-only mount option, default is disabled.
+/// The three bools come one after the other, in this order, take care not to mix them:
+///
+/// **`allow_root`** allow root to access the file system, the `allow_root` FUSE option  
+/// **`allow_other`** allow other users to access the file system, the `allow_other` FUSE option.
+/// For non-root users both need `user_allow_other` in `/etc/fuse.conf`, on Linux mounting fails with
+/// [`FsError::InvalidInput`](crate::encryptedfs::FsError::InvalidInput) if it's missing  
+/// **`read_only`** mount it read-only (`MS_RDONLY`), the volume is also opened read-only and all ops which
+/// would change it fail with `EROFS`. Useful to safely look into a backup of the data dir.  
+/// **`options`** extra [`MountOptions`], like FUSE queue tuning
 #[must_use]
 #[allow(clippy::fn_params_excessive_bools)]
 #[allow(clippy::too_long_first_doc_paragraph)]
@@ -85,6 +491,7 @@ pub fn create_mount_point(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: MountOptions,
 ) -> impl MountPoint {
     MountPointImpl::new(
         mountpoint.to_path_buf(),
@@ -94,9 +501,74 @@ pub fn create_mount_point(
         allow_root,
         allow_other,
         read_only,
+        options,
     )
 }
 
+/// Mount several volumes under one read-only mount point, each one is a directory in the root
+/// named after it, like `mnt/work` and `mnt/personal`.
+///
+/// Operations are sent to the volume of the top-level directory, the volumes keep their own data dir and key.
+/// They need to be opened read-only, see [`EncryptedFs::new`].
+///
+/// **`mountpoint`** where it wil mount the filesystem
+/// **`volumes`** the name of the directory and the volume shown in it
+/// **`allow_root`** allow root to access the file system  
+/// **`allow_other`** allow other users to access the file system  
+/// **`options`** extra [`MountOptions`], the ones about encryption are ignored as the volumes are already created
+#[must_use]
+pub fn create_overlay_mount_point(
+    mountpoint: &Path,
+    volumes: Vec<(String, Arc<EncryptedFs>)>,
+    allow_root: bool,
+    allow_other: bool,
+    options: MountOptions,
+) -> OverlayMountPoint {
+    OverlayMountPoint {
+        mountpoint: mountpoint.to_path_buf(),
+        volumes,
+        allow_root,
+        allow_other,
+        options,
+    }
+}
+
+/// Read-only mount of several volumes, see [`create_overlay_mount_point`].
+pub struct OverlayMountPoint {
+    mountpoint: PathBuf,
+    volumes: Vec<(String, Arc<EncryptedFs>)>,
+    allow_root: bool,
+    allow_other: bool,
+    options: MountOptions,
+}
+
+impl OverlayMountPoint {
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn mount(self) -> FsResult<MountHandle> {
+        self.options.validate()?;
+        validate_overlay_volumes(&self.volumes)?;
+        mount_overlay(self).await
+    }
+}
+
+fn validate_overlay_volumes(volumes: &[(String, Arc<EncryptedFs>)]) -> FsResult<()> {
+    if volumes.is_empty() {
+        return Err(FsError::InvalidInput("no volumes to mount"));
+    }
+    for (i, (name, fs)) in volumes.iter().enumerate() {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidInput("invalid volume name"));
+        }
+        if volumes[..i].iter().any(|(other, _)| other == name) {
+            return Err(FsError::InvalidInput("duplicate volume name"));
+        }
+        if !fs.is_read_only() {
+            return Err(FsError::InvalidInput("volumes must be opened read-only"));
+        }
+    }
+    Ok(())
+}
+
 pub fn umount(mountpoint: &str) -> io::Result<()> {
     // try normal umount
     if process::Command::new("umount")
@@ -133,3 +605,87 @@ pub fn umount(mountpoint: &str) -> io::Result<()> {
         ))
     }
 }
+
+/// Create the mount point directory if it's missing and [`MountOptions::create_mount_point_dir`] is set.
+/// Returns `true` if we created it.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(in crate::mount) async fn prepare_mount_point_dir(
+    mountpoint: &Path,
+    options: &MountOptions,
+) -> FsResult<bool> {
+    if mountpoint.exists() {
+        if !mountpoint.is_dir() {
+            return Err(FsError::InvalidInput("mount point is not a directory"));
+        }
+        return Ok(false);
+    }
+    let Some(mode) = options.create_mount_point_dir else {
+        return Err(FsError::InvalidInput("mount point does not exist"));
+    };
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(mode)
+        .create(mountpoint)
+        .await?;
+    Ok(true)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(in crate::mount) async fn remove_mount_point_dir(mountpoint: &Path) {
+    if let Err(err) = tokio::fs::remove_dir(mountpoint).await {
+        tracing::warn!(err = %err, "cannot remove mount point directory we created");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_options_validate() {
+        assert!(MountOptions::default().validate().is_ok());
+
+        let options = MountOptions::default()
+            .with_max_background(64)
+            .with_congestion_threshold(48);
+        assert_eq!(options.max_background, Some(64));
+        assert_eq!(options.congestion_threshold, Some(48));
+        assert!(options.validate().is_ok());
+
+        assert!(matches!(
+            MountOptions::default().with_max_background(0).validate(),
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            MountOptions::default()
+                .with_congestion_threshold(0)
+                .validate(),
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            MountOptions::default()
+                .with_max_background(8)
+                .with_congestion_threshold(9)
+                .validate(),
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            MountOptions::default()
+                .with_times_write_back(Duration::ZERO)
+                .validate(),
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            MountOptions::default()
+                .with_auto_flush(Duration::ZERO)
+                .validate(),
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            MountOptions::default()
+                .with_block_cache("/dev/shm", BLOCK_SIZE - 1)
+                .validate(),
+            Err(FsError::InvalidInput(_))
+        ));
+    }
+}
@@ -9,7 +9,7 @@ use tracing::error;
 use crate::crypto::Cipher;
 use crate::encryptedfs::{FsError, FsResult, PasswordProvider};
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::{MountHandleInner, MountOptions, MountPoint, OverlayMountPoint};
 
 #[allow(clippy::struct_excessive_bools)]
 #[allow(dead_code)]
@@ -21,6 +21,7 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: MountOptions,
 }
 
 #[async_trait]
@@ -33,6 +34,7 @@ impl MountPoint for MountPointImpl {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        options: MountOptions,
     ) -> Self {
         Self {
             mountpoint,
@@ -42,6 +44,7 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            options,
         }
     }
 
@@ -50,6 +53,13 @@ impl MountPoint for MountPointImpl {
     }
 }
 
+#[allow(clippy::unused_async)]
+pub(in crate::mount) async fn mount_overlay(
+    _mount_point: OverlayMountPoint,
+) -> FsResult<mount::MountHandle> {
+    Err(FsError::Other("Dummy implementation"))
+}
+
 pub(in crate::mount) struct MountHandleInnerImpl {}
 
 impl Future for MountHandleInnerImpl {
@@ -6,7 +6,8 @@ use std::io::{BufRead, BufReader};
 use std::iter::Skip;
 use std::num::NonZeroU32;
 use std::os::raw::c_int;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -19,25 +20,36 @@ use bytes::Bytes;
 use fuse3::raw::prelude::{
     DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
     ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+    ReplyXAttr,
 };
 use fuse3::raw::{Filesystem, MountHandle, Request, Session};
-use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
+use fuse3::{Errno, Inode, Result, SetAttr, Timestamp};
 use futures_util::stream::Iter;
 use futures_util::{stream, FutureExt};
-use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use libc::{
+    E2BIG, EACCES, EDQUOT, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENODEV,
+    ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EOPNOTSUPP, EPERM, ERANGE, EROFS,
+};
 use shush_rs::{ExposeSecret, SecretString};
 use tracing::{debug, error, instrument, trace, warn};
 use tracing::{info, Level};
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult,
-    PasswordProvider, SetFileAttr,
+    access_allowed, CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError,
+    FsResult, PasswordProvider, SetFileAttr, XattrMode, XATTR_NAME_MAX_LEN,
 };
+use crate::log::RedactedName;
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::{
+    prepare_mount_point_dir, remove_mount_point_dir, MountHandleInner, MountOptions, MountPoint,
+};
 
-const TTL: Duration = Duration::from_secs(1);
+mod overlay;
+pub(super) use overlay::mount_overlay;
+
+/// How long the kernel caches entries and attributes if not set in [`MountOptions`].
+const DEFAULT_TTL: Duration = Duration::from_secs(1);
 const STATFS: ReplyStatFs = ReplyStatFs {
     blocks: 1,
     bfree: 0,
@@ -51,45 +63,43 @@ const STATFS: ReplyStatFs = ReplyStatFs {
 
 const FMODE_EXEC: i32 = 0x20;
 
+/// Max entries we read for each `readdir`, the kernel asks again from where its buffer got full.
+const READDIR_PAGE_SIZE: usize = 1024;
+
+/// The FUSE kernel module exposes the tuning of each connection in this directory, in a subdirectory named after the
+/// minor number of the device of the mount.
+const FUSE_CONNECTIONS_DIR: &str = "/sys/fs/fuse/connections";
+
 // const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
 
-pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator, u64);
+/// A page of [`EncryptedFs::read_dir_paged`] and the offset of the next entry.
+pub struct DirectoryEntryIterator(std::vec::IntoIter<crate::encryptedfs::DirectoryEntry>, u64);
 
 impl Iterator for DirectoryEntryIterator {
     type Item = Result<DirectoryEntry>;
 
     #[instrument(name = "DirectoryEntryIterator::next", skip(self))]
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
-                Some(Ok(DirectoryEntry {
-                    inode: entry.ino,
-                    kind,
-                    name: OsString::from(&*entry.name.expose_secret()),
-                    #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
-                }))
-            }
-            Some(Err(FsError::Io { source, .. })) => {
-                error!(err = %source);
-                Some(Err(source.into()))
-            }
-            Some(Err(err)) => {
-                error!(err = %err);
-                Some(Err(EIO.into()))
-            }
-            None => None,
-        }
+        let entry = self.0.next()?;
+        self.1 += 1;
+        Some(Ok(DirectoryEntry {
+            inode: entry.ino,
+            kind: to_fuse_kind(entry.kind),
+            name: OsString::from(&*entry.name.expose_secret()),
+            #[allow(clippy::cast_possible_wrap)]
+            offset: self.1 as i64,
+        }))
     }
 }
 
-pub struct DirectoryEntryPlusIterator(crate::encryptedfs::DirectoryEntryPlusIterator, u64);
+/// Also keeps the offset of the next entry, the entry and attr TTLs and the owner files are shown with.
+pub struct DirectoryEntryPlusIterator(
+    crate::encryptedfs::DirectoryEntryPlusIterator,
+    u64,
+    Duration,
+    Duration,
+    Owner,
+);
 
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = Result<DirectoryEntryPlus>;
@@ -97,11 +107,7 @@ impl Iterator for DirectoryEntryPlusIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
+                let kind = to_fuse_kind(entry.kind);
                 self.1 += 1;
                 Some(Ok(DirectoryEntryPlus {
                     inode: entry.ino,
@@ -110,9 +116,9 @@ impl Iterator for DirectoryEntryPlusIterator {
                     name: OsString::from(&*entry.name.expose_secret()),
                     #[allow(clippy::cast_possible_wrap)]
                     offset: self.1 as i64,
-                    attr: entry.attr.into(),
-                    entry_ttl: TTL,
-                    attr_ttl: TTL,
+                    attr: self.4.map_attr(entry.attr).into(),
+                    entry_ttl: self.2,
+                    attr_ttl: self.3,
                 }))
             }
             Some(Err(FsError::Io { source, .. })) => {
@@ -128,8 +134,31 @@ impl Iterator for DirectoryEntryPlusIterator {
     }
 }
 
+/// Owner all files are shown with, see [`MountOptions::uid`] and [`MountOptions::gid`].
+#[derive(Debug, Clone, Copy)]
+struct Owner {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl Owner {
+    const fn map_attr(self, mut attr: FileAttr) -> FileAttr {
+        if let Some(uid) = self.uid {
+            attr.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            attr.gid = gid;
+        }
+        attr
+    }
+}
+
 struct EncryptedFsFuse3 {
     fs: Arc<EncryptedFs>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    owner: Owner,
+    write_back_cache: bool,
 }
 
 impl EncryptedFsFuse3 {
@@ -138,22 +167,92 @@ impl EncryptedFsFuse3 {
         password_provider: Box<dyn PasswordProvider>,
         cipher: Cipher,
         read_only: bool,
+        options: &MountOptions,
     ) -> FsResult<Self> {
-        Ok(Self {
-            fs: EncryptedFs::new(data_dir, password_provider, cipher, read_only).await?,
-        })
+        let config = options.fs_config()?;
+        let fs = if let Some(snapshot_id) = options.snapshot {
+            EncryptedFs::new_readonly_at(data_dir, snapshot_id, password_provider, cipher, config)
+                .await?
+        } else {
+            EncryptedFs::new_with_config(data_dir, password_provider, cipher, read_only, config)
+                .await?
+        };
+        Ok(Self::from_fs(fs, options))
+    }
+
+    fn from_fs(fs: Arc<EncryptedFs>, options: &MountOptions) -> Self {
+        Self {
+            fs,
+            entry_ttl: options.entry_timeout.unwrap_or(DEFAULT_TTL),
+            attr_ttl: options.attr_timeout.unwrap_or(DEFAULT_TTL),
+            owner: Owner {
+                uid: options.uid,
+                gid: options.gid,
+            },
+            write_back_cache: options.write_back_cache,
+        }
     }
 
     fn get_fs(&self) -> Arc<EncryptedFs> {
         self.fs.clone()
     }
 
+    /// Attributes as we show them, with the owner from [`MountOptions`], permissions are checked with these too.
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        Ok(self.owner.map_attr(self.get_fs().get_attr(ino).await?))
+    }
+
+    /// Ops which change the filesystem fail early with `EROFS` if it's read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.fs.is_read_only() {
+            return Err(EROFS.into());
+        }
+        Ok(())
+    }
+
     #[allow(clippy::cast_possible_truncation)]
     const fn creation_mode(&self, mode: u32) -> u16 {
         (mode & !(libc::S_ISUID | libc::S_ISGID)) as u16
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    /// The name of an extended attribute, if `req` has `access_mask` on the inode.
+    ///
+    /// Attributes in the `trusted` and `security` namespaces can only be changed by root.
+    async fn xattr_name(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        access_mask: i32,
+    ) -> Result<SecretString> {
+        let Some(name) = name.to_str() else {
+            return Err(EINVAL.into());
+        };
+        if name.is_empty() || name.len() > XATTR_NAME_MAX_LEN {
+            return Err(ERANGE.into());
+        }
+        if access_mask == libc::W_OK
+            && req.uid != 0
+            && (name.starts_with("trusted.") || name.starts_with("security."))
+        {
+            return Err(EPERM.into());
+        }
+        self.check_xattr_access(req, inode, access_mask).await?;
+        Ok(SecretString::from_str(name).unwrap())
+    }
+
+    async fn check_xattr_access(&self, req: Request, inode: Inode, access_mask: i32) -> Result<()> {
+        let attr = self.get_attr(inode).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(ENOENT)
+        })?;
+        if !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+            return Err(EACCES.into());
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn create_nod(
         &self,
         parent: u64,
@@ -163,7 +262,7 @@ impl EncryptedFsFuse3 {
         read: bool,
         write: bool,
     ) -> std::result::Result<(u64, FileAttr), c_int> {
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        let parent_attr = match self.get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT);
@@ -210,6 +309,8 @@ impl EncryptedFsFuse3 {
                 error!(err = %err);
                 match err {
                     FsError::AlreadyExists => EEXIST,
+                    FsError::NoSpace => ENOSPC,
+                    FsError::QuotaExceeded => EDQUOT,
                     FsError::Io { source, .. } => {
                         if source.to_string().to_lowercase().contains("too long") {
                             ENAMETOOLONG
@@ -224,6 +325,53 @@ impl EncryptedFsFuse3 {
     }
 }
 
+/// Stats of the filesystem keeping the data dir, with the space scaled down by
+/// [`EncryptedFs::storage_overhead_ratio`], so `df` shows how much content still fits.
+#[allow(clippy::unnecessary_cast)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+fn statfs_data_dir(fs: &EncryptedFs) -> io::Result<ReplyStatFs> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(fs.data_dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ratio = fs.storage_overhead_ratio();
+    let content = |blocks: u64| (blocks as f64 / ratio) as u64;
+    // each inode needs two files in the data dir, the metadata and the content
+    let ffree = stat.f_ffree as u64 / 2;
+    let files = fs.count_inodes().map_err(io::Error::other)? + ffree;
+    let (mut blocks, mut bfree, mut bavail) = (
+        content(stat.f_blocks as u64),
+        content(stat.f_bfree as u64),
+        content(stat.f_bavail as u64),
+    );
+    // with a quota we look like a disk of that size
+    if let Some((quota, used)) = fs.quota_usage() {
+        let frsize = (stat.f_frsize as u64).max(1);
+        let free = quota.saturating_sub(used) / frsize;
+        blocks = blocks.min(quota / frsize);
+        bfree = bfree.min(free);
+        bavail = bavail.min(free);
+    }
+    Ok(ReplyStatFs {
+        blocks,
+        bfree,
+        bavail,
+        files,
+        ffree,
+        // reads and writes of whole blocks are the most efficient
+        bsize: fs.io_block_size() as u32,
+        namelen: STATFS.namelen,
+        frsize: stat.f_frsize as u32,
+    })
+}
+
 #[allow(clippy::cast_possible_truncation)]
 const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
     if parent.perm & libc::S_ISGID as u16 != 0 {
@@ -242,11 +390,7 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: to_fuse_kind(from.kind),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
@@ -270,18 +414,28 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self))]
     async fn destroy(&self, req: Request) {
         trace!("");
+
+        // fuse3 doesn't dispatch FUSE_SYNCFS, so `syncfs(2)` on the mount point doesn't reach us,
+        // make sure all is on disk at least on umount
+        if let Err(err) = self.get_fs().sync_all().await {
+            error!(err = %err, "syncing all");
+        }
+        // drops the cached plaintext
+        if let Err(err) = self.get_fs().clear_caches().await {
+            error!(err = %err, "clearing caches");
+        }
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG), ret(level = Level::DEBUG))]
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::DEBUG), ret(level = Level::DEBUG))]
     async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
         trace!("");
 
         // if name.len() > MAX_NAME_LENGTH as usize {
-        //     warn!(name = %name.to_str().unwrap(), "name too long");
+        //     warn!(name = %RedactedName::from(name), "name too long");
         //     return Err(ENAMETOOLONG.into());
         // }
 
-        match self.get_fs().get_attr(parent).await {
+        match self.get_attr(parent).await {
             Err(err) => {
                 error!(parent, err = %err, "not found");
                 return Err(ENOENT.into());
@@ -319,8 +473,8 @@ impl Filesystem for EncryptedFsFuse3 {
         };
 
         Ok(ReplyEntry {
-            ttl: TTL,
-            attr: attr.into(),
+            ttl: self.entry_ttl,
+            attr: self.owner.map_attr(attr).into(),
             generation: 0,
         })
     }
@@ -340,18 +494,240 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyAttr> {
         trace!("");
 
-        match self.get_fs().get_attr(inode).await {
+        match self.get_attr(inode).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
             }
             Ok(attr) => Ok(ReplyAttr {
-                ttl: TTL,
-                attr: attr.into(),
+                ttl: self.attr_ttl,
+                attr: self.owner.map_attr(attr).into(),
             }),
         }
     }
 
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn readlink(&self, _req: Request, inode: Inode) -> Result<ReplyData> {
+        trace!("");
+
+        match self.get_fs().read_link(inode).await {
+            Err(FsError::InvalidInodeType) => Err(EINVAL.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+            Ok(target) => Ok(ReplyData {
+                data: Bytes::copy_from_slice(target.expose_secret().as_bytes()),
+            }),
+        }
+    }
+
+    #[instrument(skip(self, name, value), fields(name = %RedactedName::from(name), len = value.len()), err(level = Level::DEBUG))]
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        self.check_writable()?;
+
+        let name = self.xattr_name(req, inode, name, libc::W_OK).await?;
+        #[allow(clippy::cast_possible_wrap)]
+        let mode = match flags as i32 {
+            0 => XattrMode::Set,
+            libc::XATTR_CREATE => XattrMode::Create,
+            libc::XATTR_REPLACE => XattrMode::Replace,
+            _ => return Err(EINVAL.into()),
+        };
+        self.get_fs()
+            .set_xattr(inode, &name, value, mode)
+            .await
+            .map_err(xattr_errno)
+    }
+
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::DEBUG))]
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        trace!("");
+
+        let name = self.xattr_name(req, inode, name, libc::R_OK).await?;
+        let value = self
+            .get_fs()
+            .get_xattr(inode, &name)
+            .await
+            .map_err(xattr_errno)?;
+        let value = value.expose_secret();
+        xattr_reply(&value, size)
+    }
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        trace!("");
+
+        self.check_xattr_access(req, inode, libc::R_OK).await?;
+        let names = self.get_fs().list_xattr(inode).await.map_err(xattr_errno)?;
+        // each name is null terminated
+        let mut buf = vec![];
+        for name in names {
+            buf.extend_from_slice(name.expose_secret().as_bytes());
+            buf.push(0);
+        }
+        xattr_reply(&buf, size)
+    }
+
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::DEBUG))]
+    async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        trace!("");
+
+        self.check_writable()?;
+
+        let name = self.xattr_name(req, inode, name, libc::W_OK).await?;
+        self.get_fs()
+            .remove_xattr(inode, &name)
+            .await
+            .map_err(xattr_errno)
+    }
+
+    #[instrument(skip(self, name, link), fields(name = %RedactedName::from(name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        self.check_writable()?;
+
+        let parent_attr = match self.get_attr(parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+        let Some(target) = link.to_str() else {
+            return Err(EINVAL.into());
+        };
+
+        let attr = self
+            .get_fs()
+            .create_symlink(
+                parent,
+                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                &SecretString::from_str(target).unwrap(),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::InvalidInput(_) => EINVAL,
+                    FsError::NoSpace => ENOSPC,
+                    _ => EIO,
+                }
+            })?;
+        self.get_fs()
+            .set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_uid(req.uid)
+                    .with_gid(creation_gid(&parent_attr, req.gid)),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                Errno::from(EIO)
+            })?;
+        let attr = self.get_attr(attr.ino).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(EIO)
+        })?;
+        Ok(ReplyEntry {
+            ttl: self.entry_ttl,
+            attr: self.owner.map_attr(attr).into(),
+            generation: 0,
+        })
+    }
+
+    #[instrument(skip(self, new_name), fields(new_name = %RedactedName::from(new_name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        self.check_writable()?;
+
+        let parent_attr = match self.get_attr(new_parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        let attr = self
+            .get_fs()
+            .link(
+                inode,
+                new_parent,
+                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::InodeNotFound => ENOENT,
+                    // hard links to directories are not allowed
+                    FsError::InvalidInodeType | FsError::NotPermitted => EPERM,
+                    FsError::InvalidInput(_) => EINVAL,
+                    FsError::NoSpace => ENOSPC,
+                    _ => EIO,
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: self.entry_ttl,
+            attr: self.owner.map_attr(attr).into(),
+            generation: 0,
+        })
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     #[allow(clippy::cast_possible_truncation)]
     async fn setattr(
@@ -362,9 +738,11 @@ impl Filesystem for EncryptedFsFuse3 {
         set_attr: SetAttr,
     ) -> Result<ReplyAttr> {
         trace!("");
+
+        self.check_writable()?;
         debug!("{set_attr:#?}");
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+        let attr = self.get_attr(inode).await.map_err(|err| {
             error!(err = %err);
             Errno::from(ENOENT)
         })?;
@@ -393,9 +771,8 @@ impl Filesystem for EncryptedFsFuse3 {
                     Errno::from(EIO)
                 })?;
             return Ok(ReplyAttr {
-                ttl: TTL,
+                ttl: self.attr_ttl,
                 attr: self
-                    .get_fs()
                     .get_attr(inode)
                     .await
                     .map_err(|_err| Errno::from(ENOENT))?
@@ -454,9 +831,8 @@ impl Filesystem for EncryptedFsFuse3 {
                     Errno::from(EIO)
                 })?;
             return Ok(ReplyAttr {
-                ttl: TTL,
+                ttl: self.attr_ttl,
                 attr: self
-                    .get_fs()
                     .get_attr(inode)
                     .await
                     .map_err(|_err| Errno::from(ENOENT))?
@@ -469,7 +845,12 @@ impl Filesystem for EncryptedFsFuse3 {
 
             self.get_fs().set_len(inode, size).await.map_err(|err| {
                 error!(err = %err);
-                Errno::from(EIO)
+                match err {
+                    FsError::NotPermitted => Errno::from(EPERM),
+                    FsError::NoSpace => Errno::from(ENOSPC),
+                    FsError::QuotaExceeded => Errno::from(EDQUOT),
+                    _ => Errno::from(EIO),
+                }
             })?;
             set_attr2 = set_attr2.with_size(size);
 
@@ -503,18 +884,20 @@ impl Filesystem for EncryptedFsFuse3 {
             set_attr2 = set_attr2.with_ctime(SystemTime::now());
         }
 
-        self.get_fs()
-            .set_attr(inode, set_attr2)
-            .await
-            .map_err(|err| {
-                error!(err = %err);
-                Errno::from(EIO)
-            })?;
+        // times explicitly set by utimens are kept as they are, even if older, like when restoring from backups
+        let res = if set_attr.atime.is_some() || set_attr.mtime.is_some() {
+            self.get_fs().set_attr_overwrite(inode, set_attr2).await
+        } else {
+            self.get_fs().set_attr(inode, set_attr2).await
+        };
+        res.map_err(|err| {
+            error!(err = %err);
+            Errno::from(EIO)
+        })?;
 
         Ok(ReplyAttr {
-            ttl: TTL,
+            ttl: self.attr_ttl,
             attr: self
-                .get_fs()
                 .get_attr(inode)
                 .await
                 .map_err(|_err| Errno::from(ENOENT))?
@@ -522,7 +905,7 @@ impl Filesystem for EncryptedFsFuse3 {
         })
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn mknod(
         &self,
         req: Request,
@@ -532,6 +915,8 @@ impl Filesystem for EncryptedFsFuse3 {
         rdev: u32,
     ) -> Result<ReplyEntry> {
         trace!("");
+
+        self.check_writable()?;
         debug!("mode={mode:o}");
 
         let file_type = mode & libc::S_IFMT;
@@ -553,14 +938,14 @@ impl Filesystem for EncryptedFsFuse3 {
             })
             .map(|(_, attr)| {
                 Ok(ReplyEntry {
-                    ttl: TTL,
-                    attr: attr.into(),
+                    ttl: self.entry_ttl,
+                    attr: self.owner.map_attr(attr).into(),
                     generation: 0,
                 })
             })?
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn mkdir(
         &self,
         req: Request,
@@ -570,9 +955,11 @@ impl Filesystem for EncryptedFsFuse3 {
         umask: u32,
     ) -> Result<ReplyEntry> {
         trace!("");
+
+        self.check_writable()?;
         debug!("mode={mode:o}");
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        let parent_attr = match self.get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
@@ -621,17 +1008,19 @@ impl Filesystem for EncryptedFsFuse3 {
                 Errno::from(ENOENT)
             })?;
         Ok(ReplyEntry {
-            ttl: TTL,
-            attr: attr.into(),
+            ttl: self.entry_ttl,
+            attr: self.owner.map_attr(attr).into(),
             generation: 0,
         })
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        self.check_writable()?;
+
+        let parent_attr = match self.get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
@@ -686,17 +1075,22 @@ impl Filesystem for EncryptedFsFuse3 {
             .await
         {
             error!(err = %err);
-            return Err(ENOENT.into());
+            return match err {
+                FsError::NotPermitted => Err(EPERM.into()),
+                _ => Err(ENOENT.into()),
+            };
         }
 
         Ok(())
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
 
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
+        self.check_writable()?;
+
+        let Ok(parent_attr) = self.get_attr(parent).await else {
             error!(parent, "not found");
             return Err(ENOENT.into());
         };
@@ -720,7 +1114,7 @@ impl Filesystem for EncryptedFsFuse3 {
             )
             .await
         else {
-            error!(parent, name = name.to_str().unwrap());
+            error!(parent, name = %RedactedName::from(name));
             return Err(ENOENT.into());
         };
 
@@ -750,6 +1144,7 @@ impl Filesystem for EncryptedFsFuse3 {
             error!(err = %err);
             return match err {
                 FsError::NotEmpty => Err(EISDIR.into()),
+                FsError::NotPermitted => Err(EPERM.into()),
                 _ => Err(EIO.into()),
             };
         }
@@ -757,7 +1152,7 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(())
     }
 
-    #[instrument(skip(self, name, new_name), fields(name = name.to_str().unwrap(), new_name = new_name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[instrument(skip(self, name, new_name), fields(name = %RedactedName::from(name), new_name = %RedactedName::from(new_name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn rename(
         &self,
         req: Request,
@@ -768,6 +1163,8 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<()> {
         trace!("");
 
+        self.check_writable()?;
+
         let Ok(Some(attr)) = self
             .get_fs()
             .find_by_name(
@@ -778,13 +1175,13 @@ impl Filesystem for EncryptedFsFuse3 {
         else {
             error!(
                 parent,
-                name = name.to_str().unwrap(),
-                new_name = new_name.to_str().unwrap()
+                name = %RedactedName::from(name),
+                new_name = %RedactedName::from(new_name)
             );
             return Err(ENOENT.into());
         };
 
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
+        let Ok(parent_attr) = self.get_attr(parent).await else {
             error!(parent, "parent not found");
             return Err(ENOENT.into());
         };
@@ -810,7 +1207,7 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(EACCES.into());
         }
 
-        let Ok(new_parent_attr) = self.get_fs().get_attr(new_parent).await else {
+        let Ok(new_parent_attr) = self.get_attr(new_parent).await else {
             error!(new_parent, "not found");
             return Err(ENOENT.into());
         };
@@ -864,6 +1261,8 @@ impl Filesystem for EncryptedFsFuse3 {
         {
             Ok(()) => Ok(()),
             Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
+            Err(FsError::NotPermitted) => Err(EPERM.into()),
+            Err(FsError::InvalidInput(_)) => Err(EINVAL.into()),
             _ => Err(ENOENT.into()),
         }
     }
@@ -896,9 +1295,15 @@ impl Filesystem for EncryptedFsFuse3 {
 
         // let _create = flags & libc::O_CREAT as u32 != 0;
         let truncate = flags & libc::O_TRUNC as u32 != 0;
-        // let _append = flags & libc::O_APPEND as u32 != 0;
+        if write || truncate {
+            self.check_writable()?;
+        }
+        // with writeback cache the kernel reads the pages it only partially writes, even on write only handles,
+        // and it handles `O_APPEND` itself
+        let read = read || (write && self.write_back_cache);
+        let append = flags & libc::O_APPEND as u32 != 0 && !self.write_back_cache;
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+        let attr = self.get_attr(inode).await.map_err(|err| {
             error!(err = %err);
             EIO
         })?;
@@ -907,17 +1312,28 @@ impl Filesystem for EncryptedFsFuse3 {
             if truncate {
                 self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
                     error!(err = %err);
-                    EIO
+                    match err {
+                        FsError::NotPermitted => EPERM,
+                        FsError::NoSpace => ENOSPC,
+                        _ => EIO,
+                    }
                 })?;
             }
-            let fh = self
-                .get_fs()
-                .open(inode, read, write)
-                .await
-                .map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
+            // write only appenders don't take the single write slot, so multiple processes can
+            // append to the same log
+            let res = if append && write && !read {
+                self.get_fs().open_append(inode).await
+            } else {
+                self.get_fs().open(inode, read, write).await
+            };
+            let fh = res.map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::NotPermitted => EPERM,
+                    FsError::NoSpace => ENOSPC,
+                    _ => EIO,
+                }
+            })?;
             Ok(ReplyOpen { fh, flags: 0 })
         } else {
             return Err(EACCES.into());
@@ -959,6 +1375,8 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u32,
     ) -> Result<ReplyWrite> {
         trace!("");
+
+        self.check_writable()?;
         debug!(size = data.len());
 
         let len = self
@@ -969,6 +1387,10 @@ impl Filesystem for EncryptedFsFuse3 {
                 error!(err = %err);
                 match err {
                     FsError::MaxFilesizeExceeded(_) => EFBIG,
+                    FsError::NotPermitted => EPERM,
+                    // so apps say the disk is full
+                    FsError::NoSpace => ENOSPC,
+                    FsError::QuotaExceeded => EDQUOT,
                     _ => EIO,
                 }
             })?;
@@ -982,8 +1404,14 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
         trace!("");
-        warn!("implementation is a stub");
-        Ok(STATFS)
+
+        match statfs_data_dir(&self.get_fs()) {
+            Ok(statfs) => Ok(statfs),
+            Err(err) => {
+                warn!(err = %err, "cannot get stats of the data dir");
+                Ok(STATFS)
+            }
+        }
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1003,7 +1431,7 @@ impl Filesystem for EncryptedFsFuse3 {
         if flush {
             if let Err(err) = fs.flush(fh).await {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(flush_errno(&err).into());
             }
         }
 
@@ -1039,12 +1467,26 @@ impl Filesystem for EncryptedFsFuse3 {
 
         if let Err(err) = self.get_fs().flush(fh).await {
             error!(err = %err, fh);
-            return Err(EIO.into());
+            return Err(flush_errno(&err).into());
         }
 
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        match self.get_fs().fsync(fh, datasync).await {
+            // nothing to sync
+            Ok(()) | Err(FsError::ReadOnly) => Ok(()),
+            Err(err) => {
+                error!(err = %err, fh);
+                Err(flush_errno(&err).into())
+            }
+        }
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     #[allow(clippy::cast_possible_wrap)]
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
@@ -1066,7 +1508,7 @@ impl Filesystem for EncryptedFsFuse3 {
             }
         };
 
-        let attr = match self.get_fs().get_attr(inode).await {
+        let attr = match self.get_attr(inode).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
@@ -1085,7 +1527,7 @@ impl Filesystem for EncryptedFsFuse3 {
     }
 
     type DirEntryStream<'a>
-        = Iter<Skip<DirectoryEntryIterator>>
+        = Iter<DirectoryEntryIterator>
     where
         Self: 'a;
 
@@ -1099,20 +1541,24 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
         trace!("");
 
+        // the offset is the one we gave to the last entry the kernel got, so we continue after it
+        #[allow(clippy::cast_possible_truncation)]
         #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir(inode).await {
+        let offset = offset as usize;
+        let (page, _) = match self
+            .get_fs()
+            .read_dir_paged(inode, offset, READDIR_PAGE_SIZE)
+            .await
+        {
             Err(err) => {
                 error!(err = %err);
                 return Err(EIO.into());
             }
-            Ok(iter) => iter,
+            Ok(page) => page,
         };
-        let iter = DirectoryEntryIterator(iter, 0);
 
         Ok(ReplyDirectory {
-            #[allow(clippy::cast_possible_truncation)]
-            #[allow(clippy::cast_sign_loss)]
-            entries: stream::iter(iter.skip(offset as usize)),
+            entries: stream::iter(DirectoryEntryIterator(page.into_iter(), offset as u64)),
         })
     }
 
@@ -1123,11 +1569,30 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn fsyncdir(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        // entries are synced when written, and directories don't have handles
+        match self.get_fs().fsync(fh, datasync).await {
+            Ok(()) | Err(FsError::ReadOnly) => Ok(()),
+            Err(err) => {
+                error!(err = %err, fh);
+                Err(flush_errno(&err).into())
+            }
+        }
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
         trace!("");
 
-        self.get_fs().get_attr(inode).await.map_or_else(
+        #[allow(clippy::cast_possible_wrap)]
+        if mask as i32 & libc::W_OK != 0 {
+            self.check_writable()?;
+        }
+
+        self.get_attr(inode).await.map_or_else(
             |_| Err(ENOENT.into()),
             |attr| {
                 #[allow(clippy::cast_possible_wrap)]
@@ -1140,7 +1605,7 @@ impl Filesystem for EncryptedFsFuse3 {
         )
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn create(
         &self,
         req: Request,
@@ -1151,10 +1616,13 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyCreated> {
         trace!("");
 
+        self.check_writable()?;
+
         #[allow(clippy::cast_possible_wrap)]
         let (read, write) = match flags as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => (true, false),
-            libc::O_WRONLY => (false, true),
+            // the kernel might read the pages it partially writes, see `open`
+            libc::O_WRONLY => (self.write_back_cache, true),
             libc::O_RDWR => (true, true),
             // Exactly one access mode flag must be specified
             _ => {
@@ -1162,16 +1630,42 @@ impl Filesystem for EncryptedFsFuse3 {
             }
         };
 
-        let (handle, attr) = self
-            .create_nod(parent, mode, &req, name, read, write)
-            .await
-            .map_err(|err| {
+        let exclusive = flags & libc::O_EXCL as u32 != 0;
+        let (handle, attr) = match self.create_nod(parent, mode, &req, name, read, write).await {
+            Ok(res) => res,
+            // without O_EXCL we open the existing file like open(2) does
+            Err(EEXIST) if !exclusive => {
+                let attr = self
+                    .get_fs()
+                    .find_by_name(
+                        parent,
+                        &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                    )
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        EIO
+                    })?
+                    .ok_or(ENOENT)?;
+                if attr.kind == FileType::Directory {
+                    return Err(EISDIR.into());
+                }
+                let ReplyOpen { fh, .. } = self.open(req, attr.ino, flags).await?;
+                // size might have changed by O_TRUNC
+                let attr = self.get_attr(attr.ino).await.map_err(|err| {
+                    error!(err = %err);
+                    EIO
+                })?;
+                (fh, attr)
+            }
+            Err(err) => {
                 error!(err = %err);
-                Errno::from(ENOENT)
-            })?;
+                return Err(err.into());
+            }
+        };
         Ok(ReplyCreated {
-            ttl: TTL,
-            attr: attr.into(),
+            ttl: self.entry_ttl,
+            attr: self.owner.map_attr(attr).into(),
             generation: 0,
             fh: handle,
             flags: 0,
@@ -1202,7 +1696,7 @@ impl Filesystem for EncryptedFsFuse3 {
             }
             Ok(iter) => iter,
         };
-        let iter = DirectoryEntryPlusIterator(iter, 0);
+        let iter = DirectoryEntryPlusIterator(iter, 0, self.entry_ttl, self.attr_ttl, self.owner);
 
         Ok(ReplyDirectoryPlus {
             #[allow(clippy::cast_possible_truncation)]
@@ -1224,6 +1718,8 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
         trace!("");
+
+        self.check_writable()?;
         let file_range_req = CopyFileRangeReq::builder()
             .src_ino(inode)
             .src_offset(off_in)
@@ -1240,11 +1736,46 @@ impl Filesystem for EncryptedFsFuse3 {
         {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(flush_errno(&err).into());
             }
             Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
         }
     }
+
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn fallocate(
+        &self,
+        _req: Request,
+        inode: Inode,
+        _fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        self.check_writable()?;
+        self.get_fs()
+            .fallocate(inode, offset, length, mode)
+            .await
+            .map_err(|err| {
+                let errno = match err {
+                    FsError::NotSupported(_) => EOPNOTSUPP,
+                    FsError::NoSpace => ENOSPC,
+                    FsError::QuotaExceeded => EDQUOT,
+                    FsError::NotPermitted => EPERM,
+                    FsError::InvalidInodeType => ENODEV,
+                    FsError::InvalidInput(_) => EINVAL,
+                    FsError::MaxFilesizeExceeded(_) => EFBIG,
+                    FsError::InodeNotFound => ENOENT,
+                    _ => EIO,
+                };
+                if errno == EIO {
+                    error!(err = %err);
+                }
+                errno.into()
+            })
+    }
 }
 
 fn get_groups(pid: u32) -> Vec<u32> {
@@ -1277,13 +1808,21 @@ const fn clear_suid_sgid(mut perm: u16) -> u16 {
     perm
 }
 
+const fn to_fuse_kind(kind: FileType) -> fuse3::raw::prelude::FileType {
+    match kind {
+        FileType::Directory => fuse3::raw::prelude::FileType::Directory,
+        FileType::RegularFile => fuse3::raw::prelude::FileType::RegularFile,
+        FileType::Symlink => fuse3::raw::prelude::FileType::Symlink,
+    }
+}
+
 fn as_file_kind(mut mode: u32) -> FileType {
     mode &= libc::S_IFMT;
 
     if mode == libc::S_IFREG {
         FileType::RegularFile
-        // } else if mode == libc::S_IFLNK as u32 {
-        //     return FileType::Symlink;
+    } else if mode == libc::S_IFLNK {
+        FileType::Symlink
     } else if mode == libc::S_IFDIR {
         FileType::Directory
     } else {
@@ -1313,39 +1852,55 @@ const fn file_attr() -> CreateFileAttr {
     }
 }
 
+/// With `size` 0 the caller asks for the length of the buffer it needs.
+#[allow(clippy::cast_possible_truncation)]
+fn xattr_reply(data: &[u8], size: u32) -> Result<ReplyXAttr> {
+    if size == 0 {
+        Ok(ReplyXAttr::Size(data.len() as u32))
+    } else if data.len() > size as usize {
+        Err(ERANGE.into())
+    } else {
+        Ok(ReplyXAttr::Data(Bytes::copy_from_slice(data)))
+    }
+}
+
+/// Flushing writes what's left of the data, so it can find the disk full.
+const fn flush_errno(err: &FsError) -> c_int {
+    match err {
+        FsError::NoSpace => ENOSPC,
+        FsError::QuotaExceeded => EDQUOT,
+        _ => EIO,
+    }
+}
+
+fn xattr_errno(err: FsError) -> Errno {
+    match err {
+        FsError::XattrNotFound => ENODATA,
+        FsError::XattrTooBig => E2BIG,
+        FsError::AlreadyExists => EEXIST,
+        FsError::InodeNotFound => ENOENT,
+        FsError::InvalidInput(_) => EINVAL,
+        FsError::NotPermitted => EPERM,
+        FsError::ReadOnly => EROFS,
+        FsError::NoSpace => ENOSPC,
+        err => {
+            error!(err = %err);
+            EIO
+        }
+    }
+    .into()
+}
+
 fn check_access(
     #[allow(clippy::similar_names)] file_uid: u32,
     #[allow(clippy::similar_names)] file_gid: u32,
     file_mode: u16,
     uid: u32,
     gid: u32,
-    mut access_mask: i32,
+    access_mask: i32,
 ) -> bool {
-    // F_OK tests for existence of file
-    if access_mask == libc::F_OK {
-        return true;
-    }
-    let file_mode = i32::from(file_mode);
-
-    // root is allowed to read & write anything
-    if uid == 0 {
-        // root only allowed to exec if one of the X bits is set
-        access_mask &= libc::X_OK;
-        access_mask -= access_mask & (file_mode >> 6);
-        access_mask -= access_mask & (file_mode >> 3);
-        access_mask -= access_mask & file_mode;
-        return access_mask == 0;
-    }
-
-    if uid == file_uid {
-        access_mask -= access_mask & (file_mode >> 6);
-    } else if gid == file_gid {
-        access_mask -= access_mask & (file_mode >> 3);
-    } else {
-        access_mask -= access_mask & file_mode;
-    }
-
-    access_mask == 0
+    #[allow(clippy::cast_sign_loss)]
+    access_allowed(file_uid, file_gid, file_mode, uid, gid, access_mask as u32)
 }
 
 #[allow(clippy::cast_sign_loss)]
@@ -1362,6 +1917,7 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: MountOptions,
 }
 
 #[async_trait]
@@ -1374,6 +1930,7 @@ impl MountPoint for MountPointImpl {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        options: MountOptions,
     ) -> Self {
         Self {
             mountpoint,
@@ -1383,28 +1940,69 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            options,
         }
     }
 
     async fn mount(mut self) -> FsResult<mount::MountHandle> {
-        let handle = mount_fuse(
+        self.options.validate()?;
+        check_allow_other(self.allow_root, self.allow_other).await?;
+        let options = self.options.clone();
+        mount_with(
             self.mountpoint.clone(),
-            self.data_dir.clone(),
-            self.password_provider.take().unwrap(),
-            self.cipher,
-            self.allow_root,
-            self.allow_other,
-            self.read_only,
+            &options,
+            mount_fuse(
+                self.mountpoint,
+                self.data_dir,
+                self.password_provider.take().unwrap(),
+                self.cipher,
+                self.allow_root,
+                self.allow_other,
+                self.read_only,
+                self.options,
+            ),
         )
-        .await?;
-        Ok(mount::MountHandle {
-            inner: MountHandleInnerImpl { inner: handle },
-        })
+        .await
     }
 }
 
+/// Prepares the mount point, then runs `mount`.
+async fn mount_with(
+    mountpoint: PathBuf,
+    options: &MountOptions,
+    mount: impl Future<Output = FsResult<MountHandle>>,
+) -> FsResult<mount::MountHandle> {
+    // don't stack a new mount over an existing one, it would hide it
+    if is_rencfs_mount(&mountpoint).await? {
+        if !options.umount_first {
+            return Err(FsError::AlreadyMounted);
+        }
+        warn!("Mount point is already mounted, umounting it first");
+        mount::umount(mountpoint.to_str().unwrap())?;
+    }
+    let created_dir = prepare_mount_point_dir(&mountpoint, options).await?;
+    let handle = match mount.await {
+        Ok(handle) => handle,
+        Err(err) => {
+            if created_dir {
+                remove_mount_point_dir(&mountpoint).await;
+            }
+            return Err(err);
+        }
+    };
+    Ok(mount::MountHandle {
+        inner: Some(MountHandleInnerImpl {
+            inner: handle,
+            created_dir: created_dir.then_some(mountpoint.clone()),
+        }),
+        mountpoint,
+    })
+}
+
 pub(in crate::mount) struct MountHandleInnerImpl {
     inner: MountHandle,
+    // mount point directory we created, removed on umount
+    created_dir: Option<PathBuf>,
 }
 
 impl Future for MountHandleInnerImpl {
@@ -1418,11 +2016,16 @@ impl Future for MountHandleInnerImpl {
 #[async_trait]
 impl MountHandleInner for MountHandleInnerImpl {
     async fn unmount(mut self) -> io::Result<()> {
-        self.inner.unmount().await
+        self.inner.unmount().await?;
+        if let Some(dir) = self.created_dir {
+            remove_mount_point_dir(&dir).await;
+        }
+        Ok(())
     }
 }
 
 #[instrument(skip(password_provider))]
+#[allow(clippy::fn_params_excessive_bools)]
 async fn mount_fuse(
     mountpoint: PathBuf,
     data_dir: PathBuf,
@@ -1431,30 +2034,489 @@ async fn mount_fuse(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: MountOptions,
 ) -> FsResult<MountHandle> {
-    // create mount point if it doesn't exist
-    if !mountpoint.exists() {
-        fs::create_dir_all(&mountpoint).await?;
+    let read_only = read_only || options.snapshot.is_some();
+    let mount_options = fuse_mount_options(
+        read_only,
+        allow_root,
+        allow_other,
+        options.default_permissions,
+        options.write_back_cache,
+    );
+    let mount_path = OsStr::new(mountpoint.to_str().unwrap());
+
+    info!("Checking password and mounting FUSE filesystem");
+    let fs =
+        EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only, &options).await?;
+    if options.self_test && !read_only {
+        info!("Running self-test");
+        fs.get_fs().self_test().await?;
     }
-    let mut mount_options = &mut MountOptions::default();
+    let handle = Session::new(mount_options)
+        .mount_with_unprivileged(fs, mount_path)
+        .await?;
+    if let Err(err) = apply_fuse_connection_settings(&mountpoint, &options).await {
+        error!(err = %err, "cannot apply FUSE queue settings, maybe we are not root, umounting");
+        handle.unmount().await?;
+        return Err(err.into());
+    }
+
+    Ok(handle)
+}
+
+fn fuse_mount_options(
+    read_only: bool,
+    allow_root: bool,
+    allow_other: bool,
+    default_permissions: bool,
+    write_back_cache: bool,
+) -> fuse3::MountOptions {
+    let mut mount_options = &mut fuse3::MountOptions::default();
     {
         unsafe {
             mount_options = mount_options.uid(libc::getuid()).gid(libc::getgid());
         }
     }
-    let mount_options = mount_options
+    mount_options
         .read_only(read_only)
         .allow_root(allow_root)
         .allow_other(allow_other)
+        .default_permissions(default_permissions)
+        .write_back(write_back_cache)
         .fs_name("rencfs")
-        .clone();
-    let mount_path = OsStr::new(mountpoint.to_str().unwrap());
+        .clone()
+}
 
-    info!("Checking password and mounting FUSE filesystem");
-    Ok(Session::new(mount_options)
-        .mount_with_unprivileged(
-            EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only).await?,
-            mount_path,
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// `fusermount` refuses `allow_other` and `allow_root` for non-root users unless `user_allow_other` is set
+/// in `/etc/fuse.conf`, check it upfront so we can give a clear error.
+async fn check_allow_other(allow_root: bool, allow_other: bool) -> FsResult<()> {
+    if !allow_root && !allow_other {
+        return Ok(());
+    }
+    if unsafe { libc::getuid() } == 0 {
+        return Ok(());
+    }
+    let conf = fs::read_to_string(FUSE_CONF).await.unwrap_or_default();
+    if has_user_allow_other(&conf) {
+        Ok(())
+    } else {
+        Err(FsError::InvalidInput(
+            "allow_other and allow_root need user_allow_other in /etc/fuse.conf",
+        ))
+    }
+}
+
+fn has_user_allow_other(conf: &str) -> bool {
+    conf.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .any(|line| line == "user_allow_other")
+}
+
+/// Check in `/proc/self/mounts` if there is a rencfs mount on `mountpoint`.
+async fn is_rencfs_mount(mountpoint: &Path) -> FsResult<bool> {
+    let mountpoint = std::path::absolute(mountpoint)?;
+    match fs::read_to_string("/proc/self/mounts").await {
+        Ok(mounts) => Ok(has_rencfs_mount(&mounts, &mountpoint)),
+        Err(err) => {
+            warn!(err = %err, "cannot read mount table, assuming not mounted");
+            Ok(false)
+        }
+    }
+}
+
+/// Looks for a line like `rencfs /mnt/rencfs fuse rw,... 0 0` in `mounts`.
+fn has_rencfs_mount(mounts: &str, mountpoint: &Path) -> bool {
+    // space, tab, new line and backslash are escaped as octal in the mount table
+    let escaped = mountpoint
+        .to_string_lossy()
+        .replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011")
+        .replace('\n', "\\012");
+    mounts.lines().any(|line| {
+        let mut fields = line.split_whitespace();
+        matches!(
+            (fields.next(), fields.next(), fields.next()),
+            (Some("rencfs"), Some(path), Some(fs_type)) if path == escaped && fs_type.starts_with("fuse")
+        )
+    })
+}
+
+/// Settings we need to write in the connection directory from [`FUSE_CONNECTIONS_DIR`], as `(file name, value)`.
+fn fuse_connection_settings(options: &MountOptions) -> Vec<(&'static str, u16)> {
+    let mut settings = vec![];
+    if let Some(max_background) = options.max_background {
+        settings.push(("max_background", max_background));
+    }
+    if let Some(congestion_threshold) = options.congestion_threshold {
+        settings.push(("congestion_threshold", congestion_threshold));
+    }
+    settings
+}
+
+/// `fuse3` answers the `init` of the kernel with fixed queue sizes and has no way to change them, so we set them
+/// after mount in the sysfs control files of the connection, `/sys/fs/fuse/connections/<minor of the device>`.
+///
+/// Writing those needs root, if one can't be written it fails with the error of the write.
+async fn apply_fuse_connection_settings(
+    mountpoint: &Path,
+    options: &MountOptions,
+) -> io::Result<()> {
+    let settings = fuse_connection_settings(options);
+    if settings.is_empty() {
+        return Ok(());
+    }
+    let dev = fs::metadata(mountpoint).await?.dev();
+    // FUSE uses anonymous devices, the connection id is the minor number
+    let connection_dir = Path::new(FUSE_CONNECTIONS_DIR).join(libc::minor(dev).to_string());
+    for (name, value) in settings {
+        let path = connection_dir.join(name);
+        fs::write(&path, value.to_string()).await.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("cannot write {value} to {}: {err}", path.display()),
+            )
+        })?;
+        info!(name, value, "FUSE connection setting applied");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::write::BLOCK_SIZE;
+
+    #[tokio::test]
+    async fn test_prepare_mount_point_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mountpoint = tmp.path().join("mnt").join("vault");
+
+        assert!(matches!(
+            prepare_mount_point_dir(&mountpoint, &MountOptions::default()).await,
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(!mountpoint.exists());
+
+        let options = MountOptions::default().with_create_mount_point_dir(0o700);
+        assert!(prepare_mount_point_dir(&mountpoint, &options)
+            .await
+            .unwrap());
+        assert!(mountpoint.is_dir());
+        assert_eq!(
+            std::fs::metadata(&mountpoint).unwrap().mode() & 0o777,
+            0o700
+        );
+        // already exists, we didn't create it
+        assert!(!prepare_mount_point_dir(&mountpoint, &options)
+            .await
+            .unwrap());
+
+        // don't clobber files
+        let file = tmp.path().join("file");
+        std::fs::write(&file, "test-42").unwrap();
+        assert!(matches!(
+            prepare_mount_point_dir(&file, &options).await,
+            Err(FsError::InvalidInput(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "test-42");
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        use crate::encryptedfs::ROOT_INODE;
+        use futures_util::StreamExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let options = MountOptions::default()
+            .with_entry_timeout(Duration::from_secs(42))
+            .with_attr_timeout(Duration::from_secs(37));
+        let fs = EncryptedFsFuse3::new(
+            tmp.path().join("data"),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            &options,
+        )
+        .await
+        .unwrap();
+        let (_, attr) = fs
+            .get_fs()
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                crate::test_common::create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+
+        let entry = fs
+            .lookup(req, ROOT_INODE, OsStr::new("test-file"))
+            .await
+            .unwrap();
+        assert_eq!(entry.ttl, Duration::from_secs(42));
+        let reply = fs.getattr(req, attr.ino, None, 0).await.unwrap();
+        assert_eq!(reply.ttl, Duration::from_secs(37));
+        let reply = fs.readdirplus(req, ROOT_INODE, 0, 0, 0).await.unwrap();
+        let entries: Vec<_> = reply.entries.collect().await;
+        assert!(!entries.is_empty());
+        for entry in entries {
+            let entry = entry.unwrap();
+            assert_eq!(entry.entry_ttl, Duration::from_secs(42));
+            assert_eq!(entry.attr_ttl, Duration::from_secs(37));
+        }
+
+        // defaults
+        let fs = EncryptedFsFuse3::new(
+            tmp.path().join("data"),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+        let reply = fs.getattr(req, attr.ino, None, 0).await.unwrap();
+        assert_eq!(reply.ttl, DEFAULT_TTL);
+    }
+
+    #[tokio::test]
+    async fn test_write_back_cache() {
+        use crate::encryptedfs::ROOT_INODE;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let fs = EncryptedFsFuse3::new(
+            tmp.path().join("data"),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            &MountOptions::default().with_write_back_cache(true),
         )
-        .await?)
+        .await
+        .unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        #[allow(clippy::cast_sign_loss)]
+        let wronly = libc::O_WRONLY as u32;
+        let reply = fs
+            .create(req, ROOT_INODE, OsStr::new("test-file"), 0o644, wronly)
+            .await
+            .unwrap();
+        fs.write(req, reply.attr.ino, reply.fh, 0, b"test-42", 0, wronly)
+            .await
+            .unwrap();
+        // the kernel reads partially written pages with the write only handle
+        let data = fs.read(req, reply.attr.ino, reply.fh, 0, 7).await.unwrap();
+        assert_eq!(&data.data[..], b"test-42");
+        fs.release(req, reply.attr.ino, reply.fh, wronly, 0, false)
+            .await
+            .unwrap();
+
+        #[allow(clippy::cast_sign_loss)]
+        let append = (libc::O_WRONLY | libc::O_APPEND) as u32;
+        let open = fs.open(req, reply.attr.ino, append).await.unwrap();
+        let data = fs.read(req, reply.attr.ino, open.fh, 0, 7).await.unwrap();
+        assert_eq!(&data.data[..], b"test-42");
+        fs.release(req, reply.attr.ino, open.fh, append, 0, false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        use crate::encryptedfs::ROOT_INODE;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let data_dir = tmp.path().join("data");
+        let fs = EncryptedFsFuse3::new(
+            data_dir.clone(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+        let (_, attr) = fs
+            .get_fs()
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                crate::test_common::create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        drop(fs);
+
+        let fs = EncryptedFsFuse3::new(
+            data_dir,
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            true,
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let erofs = Errno::from(EROFS);
+        #[allow(clippy::cast_sign_loss)]
+        let (wronly, rdonly) = (libc::O_WRONLY as u32, libc::O_RDONLY as u32);
+        assert!(matches!(fs.open(req, attr.ino, wronly).await, Err(err) if err == erofs));
+        assert!(matches!(
+            fs.mkdir(req, ROOT_INODE, OsStr::new("test-dir"), 0o755, 0).await,
+            Err(err) if err == erofs
+        ));
+        assert!(matches!(
+            fs.unlink(req, ROOT_INODE, OsStr::new("test-file")).await,
+            Err(err) if err == erofs
+        ));
+        assert!(matches!(
+            fs.setxattr(req, attr.ino, OsStr::new("user.test"), b"test-42", 0, 0).await,
+            Err(err) if err == erofs
+        ));
+        #[allow(clippy::cast_sign_loss)]
+        let w_ok = libc::W_OK as u32;
+        assert!(matches!(fs.access(req, attr.ino, w_ok).await, Err(err) if err == erofs));
+
+        // reads still work
+        let reply = fs.open(req, attr.ino, rdonly).await.unwrap();
+        fs.release(req, attr.ino, reply.fh, rdonly, 0, false)
+            .await
+            .unwrap();
+        assert!(fs.getattr(req, attr.ino, None, 0).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_statfs() {
+        use crate::encryptedfs::ROOT_INODE;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let fs = EncryptedFsFuse3::new(
+            tmp.path().join("data"),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        assert_eq!(fs.get_fs().count_inodes().unwrap(), 1);
+        fs.get_fs()
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                crate::test_common::create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(fs.get_fs().count_inodes().unwrap(), 2);
+
+        let stat = fs.statfs(req, ROOT_INODE).await.unwrap();
+        // not the fallback of a full volume
+        assert!(stat.blocks > 1);
+        assert!(stat.bfree > 0);
+        assert!(stat.bavail > 0);
+        assert!(stat.blocks >= stat.bfree);
+        assert!(stat.bfree >= stat.bavail);
+        assert!(stat.files >= stat.ffree + 2);
+        assert_eq!(stat.bsize as usize, BLOCK_SIZE);
+        assert_ne!(stat.frsize, 0);
+    }
+
+    #[test]
+    fn test_xattr_reply() {
+        assert!(matches!(
+            xattr_reply(b"test-42", 0),
+            Ok(ReplyXAttr::Size(7))
+        ));
+        assert!(matches!(
+            xattr_reply(b"test-42", 7),
+            Ok(ReplyXAttr::Data(data)) if data.as_ref() == b"test-42"
+        ));
+        assert!(matches!(
+            xattr_reply(b"test-42", 64),
+            Ok(ReplyXAttr::Data(data)) if data.as_ref() == b"test-42"
+        ));
+        assert!(xattr_reply(b"test-42", 6).is_err());
+        assert!(matches!(xattr_reply(b"", 0), Ok(ReplyXAttr::Size(0))));
+    }
+
+    #[test]
+    fn test_has_rencfs_mount() {
+        let mounts = "proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+rencfs /tmp/rencfs/mnt fuse rw,nosuid,nodev,relatime,user_id=1000,group_id=1000 0 0
+rencfs /tmp/my\\040vault fuse.rencfs rw,nosuid,nodev,relatime 0 0
+tmpfs /tmp/other tmpfs rw 0 0
+";
+        assert!(has_rencfs_mount(mounts, Path::new("/tmp/rencfs/mnt")));
+        assert!(has_rencfs_mount(mounts, Path::new("/tmp/my vault")));
+        assert!(!has_rencfs_mount(mounts, Path::new("/tmp/rencfs")));
+        assert!(!has_rencfs_mount(mounts, Path::new("/tmp/other")));
+        assert!(!has_rencfs_mount(mounts, Path::new("/proc")));
+    }
+
+    #[test]
+    fn test_has_user_allow_other() {
+        assert!(!has_user_allow_other(""));
+        assert!(!has_user_allow_other(
+            "# Allow non-root users to specify the allow_other or allow_root mount options.
+#user_allow_other
+"
+        ));
+        assert!(has_user_allow_other(
+            "# mount_max = 1000
+  user_allow_other  # needed by rencfs
+"
+        ));
+        assert!(!has_user_allow_other("user_allow_other_not\n"));
+    }
+
+    #[test]
+    fn test_fuse_connection_settings() {
+        assert!(fuse_connection_settings(&MountOptions::default()).is_empty());
+
+        let options = MountOptions::default()
+            .with_max_background(64)
+            .with_congestion_threshold(48);
+        assert_eq!(
+            fuse_connection_settings(&options),
+            vec![("max_background", 64), ("congestion_threshold", 48)]
+        );
+
+        let options = MountOptions::default().with_congestion_threshold(9);
+        assert_eq!(
+            fuse_connection_settings(&options),
+            vec![("congestion_threshold", 9)]
+        );
+    }
 }
@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use fuse3::raw::prelude::{
+    DirectoryEntry, DirectoryEntryPlus, FileAttr, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs,
+};
+use fuse3::raw::{Filesystem, Request, Session};
+use fuse3::{Inode, Result};
+use futures_util::stream::{self, Iter};
+use futures_util::StreamExt;
+use libc::{EACCES, ENOENT};
+use tracing::{info, instrument, trace, Level};
+
+use super::{
+    apply_fuse_connection_settings, check_access, fuse_mount_options, mount_with, EncryptedFsFuse3,
+    DEFAULT_TTL, STATFS,
+};
+use crate::encryptedfs::{EncryptedFs, FsResult, ROOT_INODE};
+use crate::log::RedactedName;
+use crate::mount;
+use crate::mount::{MountOptions, OverlayMountPoint};
+
+/// Our root, it only has a directory for each volume.
+const OVERLAY_ROOT: u64 = ROOT_INODE;
+
+/// Maps our inodes to the ones in the volumes, as each volume has its own inodes they can collide.
+///
+/// Entries are never removed, the volumes are read-only so the number of inodes is bounded.
+#[derive(Default)]
+struct Inodes {
+    to_volume: HashMap<u64, (usize, u64)>,
+    to_overlay: HashMap<(usize, u64), u64>,
+    next: u64,
+}
+
+impl Inodes {
+    fn get_or_insert(&mut self, volume: usize, ino: u64) -> u64 {
+        if let Some(ino) = self.to_overlay.get(&(volume, ino)) {
+            return *ino;
+        }
+        self.next += 1;
+        self.to_overlay.insert((volume, ino), self.next);
+        self.to_volume.insert(self.next, (volume, ino));
+        self.next
+    }
+}
+
+/// Read-only view of several volumes, each one is a directory in our root.
+///
+/// Operations are sent to the volume owning the inode, with the inodes mapped with [`Inodes`].
+struct OverlayFs {
+    volumes: Vec<(OsString, EncryptedFsFuse3)>,
+    inodes: Mutex<Inodes>,
+    root_attr: FileAttr,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+}
+
+impl OverlayFs {
+    fn new(volumes: Vec<(String, Arc<EncryptedFs>)>, options: &MountOptions) -> Self {
+        let mut inodes = Inodes {
+            next: OVERLAY_ROOT,
+            ..Default::default()
+        };
+        let volumes: Vec<_> = volumes
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (name, fs))| {
+                inodes.get_or_insert(idx, ROOT_INODE);
+                (OsString::from(name), EncryptedFsFuse3::from_fs(fs, options))
+            })
+            .collect();
+        let now = SystemTime::now().into();
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let root_attr = FileAttr {
+            ino: OVERLAY_ROOT,
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            kind: fuse3::raw::prelude::FileType::Directory,
+            perm: 0o555,
+            #[allow(clippy::cast_possible_truncation)]
+            nlink: 2 + volumes.len() as u32,
+            uid,
+            gid,
+            rdev: 0,
+            blksize: 0,
+        };
+        Self {
+            volumes,
+            inodes: Mutex::new(inodes),
+            root_attr,
+            entry_ttl: options.entry_timeout.unwrap_or(DEFAULT_TTL),
+            attr_ttl: options.attr_timeout.unwrap_or(DEFAULT_TTL),
+        }
+    }
+
+    fn to_overlay(&self, volume: usize, ino: u64) -> u64 {
+        self.inodes.lock().unwrap().get_or_insert(volume, ino)
+    }
+
+    /// The volume and its inode for our `ino`.
+    fn to_volume(&self, ino: u64) -> Result<(usize, u64)> {
+        self.inodes
+            .lock()
+            .unwrap()
+            .to_volume
+            .get(&ino)
+            .copied()
+            .ok_or_else(|| ENOENT.into())
+    }
+
+    fn volume(&self, idx: usize) -> &EncryptedFsFuse3 {
+        &self.volumes[idx].1
+    }
+
+    fn map_attr(&self, volume: usize, mut attr: FileAttr) -> FileAttr {
+        attr.ino = self.to_overlay(volume, attr.ino);
+        attr
+    }
+
+    /// Inode of an entry of `parent` in the volume, `..` of the root of a volume is our root.
+    fn map_entry_ino(&self, volume: usize, parent: u64, name: &OsStr, ino: u64) -> u64 {
+        if parent == ROOT_INODE && name == ".." {
+            OVERLAY_ROOT
+        } else {
+            self.to_overlay(volume, ino)
+        }
+    }
+
+    async fn volume_root_attr(&self, req: Request, volume: usize) -> Result<FileAttr> {
+        let reply = self
+            .volume(volume)
+            .getattr(req, ROOT_INODE, None, 0)
+            .await?;
+        Ok(self.map_attr(volume, reply.attr))
+    }
+}
+
+impl Filesystem for OverlayFs {
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::INFO))]
+    async fn init(&self, req: Request) -> Result<ReplyInit> {
+        trace!("");
+
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(1024 * 1024).unwrap(),
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn destroy(&self, req: Request) {
+        trace!("");
+
+        for (_, fs) in &self.volumes {
+            fs.destroy(req).await;
+        }
+    }
+
+    #[instrument(skip(self, name), fields(name = %RedactedName::from(name)), err(level = Level::DEBUG), ret(level = Level::DEBUG))]
+    async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
+        trace!("");
+
+        if parent == OVERLAY_ROOT {
+            let volume = self
+                .volumes
+                .iter()
+                .position(|(volume, _)| volume == name)
+                .ok_or(ENOENT)?;
+            return Ok(ReplyEntry {
+                ttl: self.entry_ttl,
+                attr: self.volume_root_attr(req, volume).await?,
+                generation: 0,
+            });
+        }
+        let (volume, parent) = self.to_volume(parent)?;
+        let mut entry = self.volume(volume).lookup(req, parent, name).await?;
+        entry.attr = self.map_attr(volume, entry.attr);
+        Ok(entry)
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn getattr(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: Option<u64>,
+        flags: u32,
+    ) -> Result<ReplyAttr> {
+        trace!("");
+
+        if inode == OVERLAY_ROOT {
+            return Ok(ReplyAttr {
+                ttl: self.attr_ttl,
+                attr: self.root_attr,
+            });
+        }
+        let (volume, inode) = self.to_volume(inode)?;
+        let mut reply = self.volume(volume).getattr(req, inode, fh, flags).await?;
+        reply.attr = self.map_attr(volume, reply.attr);
+        Ok(reply)
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        trace!("");
+
+        let (volume, inode) = self.to_volume(inode)?;
+        self.volume(volume).open(req, inode, flags).await
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn read(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        trace!("");
+
+        let (volume, inode) = self.to_volume(inode)?;
+        self.volume(volume).read(req, inode, fh, offset, size).await
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
+        trace!("");
+
+        if inode == OVERLAY_ROOT {
+            return Ok(STATFS);
+        }
+        let (volume, inode) = self.to_volume(inode)?;
+        self.volume(volume).statfs(req, inode).await
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn release(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<()> {
+        trace!("");
+
+        let (volume, inode) = self.to_volume(inode)?;
+        // nothing to flush, volumes are read-only
+        self.volume(volume)
+            .release(req, inode, fh, flags, lock_owner, false)
+            .await
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        trace!("");
+
+        Ok(())
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        trace!("");
+
+        if inode == OVERLAY_ROOT {
+            return Ok(ReplyOpen { fh: 0, flags: 0 });
+        }
+        let (volume, inode) = self.to_volume(inode)?;
+        self.volume(volume).opendir(req, inode, flags).await
+    }
+
+    type DirEntryStream<'a>
+        = Iter<std::vec::IntoIter<Result<DirectoryEntry>>>
+    where
+        Self: 'a;
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn readdir(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
+        trace!("");
+
+        if inode == OVERLAY_ROOT {
+            let mut names = vec![
+                (OVERLAY_ROOT, OsString::from(".")),
+                (OVERLAY_ROOT, OsString::from("..")),
+            ];
+            for (idx, (name, _)) in self.volumes.iter().enumerate() {
+                names.push((self.to_overlay(idx, ROOT_INODE), name.clone()));
+            }
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_sign_loss)]
+            #[allow(clippy::cast_possible_wrap)]
+            let entries: Vec<_> = names
+                .into_iter()
+                .enumerate()
+                .skip(offset as usize)
+                .map(|(i, (inode, name))| {
+                    Ok(DirectoryEntry {
+                        inode,
+                        kind: fuse3::raw::prelude::FileType::Directory,
+                        name,
+                        offset: i as i64 + 1,
+                    })
+                })
+                .collect();
+            return Ok(ReplyDirectory {
+                entries: stream::iter(entries),
+            });
+        }
+        let (volume, inode) = self.to_volume(inode)?;
+        let entries: Vec<_> = self
+            .volume(volume)
+            .readdir(req, inode, fh, offset)
+            .await?
+            .entries
+            .map(|entry| {
+                entry.map(|mut entry| {
+                    entry.inode = self.map_entry_ino(volume, inode, &entry.name, entry.inode);
+                    entry
+                })
+            })
+            .collect()
+            .await;
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
+        trace!("");
+
+        Ok(())
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
+        trace!("");
+
+        if inode == OVERLAY_ROOT {
+            let attr = &self.root_attr;
+            #[allow(clippy::cast_possible_wrap)]
+            return if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
+                Ok(())
+            } else {
+                Err(EACCES.into())
+            };
+        }
+        let (volume, inode) = self.to_volume(inode)?;
+        self.volume(volume).access(req, inode, mask).await
+    }
+
+    type DirEntryPlusStream<'a>
+        = Iter<std::vec::IntoIter<Result<DirectoryEntryPlus>>>
+    where
+        Self: 'a;
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn readdirplus(
+        &self,
+        req: Request,
+        parent: u64,
+        fh: u64,
+        offset: u64,
+        lock_owner: u64,
+    ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
+        trace!("");
+
+        if parent == OVERLAY_ROOT {
+            let mut entries = vec![
+                (OsString::from("."), self.root_attr),
+                (OsString::from(".."), self.root_attr),
+            ];
+            for (idx, (name, _)) in self.volumes.iter().enumerate() {
+                entries.push((name.clone(), self.volume_root_attr(req, idx).await?));
+            }
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_possible_wrap)]
+            let entries: Vec<_> = entries
+                .into_iter()
+                .enumerate()
+                .skip(offset as usize)
+                .map(|(i, (name, attr))| {
+                    Ok(DirectoryEntryPlus {
+                        inode: attr.ino,
+                        generation: 0,
+                        kind: attr.kind,
+                        name,
+                        offset: i as i64 + 1,
+                        attr,
+                        entry_ttl: self.entry_ttl,
+                        attr_ttl: self.attr_ttl,
+                    })
+                })
+                .collect();
+            return Ok(ReplyDirectoryPlus {
+                entries: stream::iter(entries),
+            });
+        }
+        let (volume, parent) = self.to_volume(parent)?;
+        let entries: Vec<_> = self
+            .volume(volume)
+            .readdirplus(req, parent, fh, offset, lock_owner)
+            .await?
+            .entries
+            .map(|entry| {
+                entry.map(|mut entry| {
+                    entry.inode = self.map_entry_ino(volume, parent, &entry.name, entry.inode);
+                    entry.attr.ino = entry.inode;
+                    entry
+                })
+            })
+            .collect()
+            .await;
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(entries),
+        })
+    }
+}
+
+pub(in crate::mount) async fn mount_overlay(
+    mount_point: OverlayMountPoint,
+) -> FsResult<mount::MountHandle> {
+    let OverlayMountPoint {
+        mountpoint,
+        volumes,
+        allow_root,
+        allow_other,
+        options,
+    } = mount_point;
+    mount_with(mountpoint.clone(), &options, async {
+        let mount_options = fuse_mount_options(true, allow_root, allow_other);
+        info!(volumes = volumes.len(), "Mounting overlay FUSE filesystem");
+        let fs = OverlayFs::new(volumes, &options);
+        let handle = Session::new(mount_options)
+            .mount_with_unprivileged(fs, &mountpoint)
+            .await?;
+        apply_fuse_connection_settings(&mountpoint, &options).await;
+        Ok(handle)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::str::FromStr;
+
+    use shush_rs::SecretString;
+
+    use super::*;
+    use crate::crypto::Cipher;
+    use crate::encryptedfs::{write_all_bytes_to_fs, FileType};
+    use crate::test_common::{create_attr, PasswordProviderImpl};
+
+    /// Create a volume with a file `name` in root, then open it read-only.
+    async fn create_volume(data_dir: &Path, name: &str, content: &[u8]) -> Arc<EncryptedFs> {
+        let fs = EncryptedFs::new(
+            data_dir.to_path_buf(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+        )
+        .await
+        .unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str(name).unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_bytes_to_fs(&fs, attr.ino, 0, content, fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        drop(fs);
+        EncryptedFs::new(
+            data_dir.to_path_buf(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            true,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[allow(clippy::cast_sign_loss)]
+    async fn test_overlay() {
+        let tmp = tempfile::tempdir().unwrap();
+        let work = create_volume(&tmp.path().join("work"), "report.txt", b"test-42").await;
+        let personal = create_volume(&tmp.path().join("personal"), "notes.txt", b"test-43").await;
+        let fs = OverlayFs::new(
+            vec![
+                ("work".to_string(), work),
+                ("personal".to_string(), personal),
+            ],
+            &MountOptions::default(),
+        );
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+
+        let names = |entries: Vec<Result<DirectoryEntry>>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.unwrap().name)
+                .collect::<Vec<_>>()
+        };
+        let root: Vec<_> = fs
+            .readdir(req, OVERLAY_ROOT, 0, 0)
+            .await
+            .unwrap()
+            .entries
+            .collect()
+            .await;
+        assert_eq!(names(root), vec![".", "..", "work", "personal"]);
+
+        for (volume, file, content) in [
+            ("work", "report.txt", "test-42"),
+            ("personal", "notes.txt", "test-43"),
+        ] {
+            let dir = fs
+                .lookup(req, OVERLAY_ROOT, OsStr::new(volume))
+                .await
+                .unwrap();
+            let entries: Vec<_> = fs
+                .readdir(req, dir.attr.ino, 0, 0)
+                .await
+                .unwrap()
+                .entries
+                .collect()
+                .await;
+            let entries: Vec<_> = entries.into_iter().map(Result::unwrap).collect();
+            assert!(entries.iter().any(|entry| entry.name == file));
+            let parent = entries.iter().find(|entry| entry.name == "..").unwrap();
+            assert_eq!(parent.inode, OVERLAY_ROOT);
+
+            let entry = fs
+                .lookup(req, dir.attr.ino, OsStr::new(file))
+                .await
+                .unwrap();
+            assert_eq!(
+                fs.getattr(req, entry.attr.ino, None, 0)
+                    .await
+                    .unwrap()
+                    .attr
+                    .ino,
+                entry.attr.ino
+            );
+            let fh = fs
+                .open(req, entry.attr.ino, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            let data = fs.read(req, entry.attr.ino, fh, 0, 100).await.unwrap().data;
+            assert_eq!(&data[..], content.as_bytes());
+            fs.release(req, entry.attr.ino, fh, 0, 0, true)
+                .await
+                .unwrap();
+        }
+        assert!(fs
+            .lookup(req, OVERLAY_ROOT, OsStr::new("missing"))
+            .await
+            .is_err());
+    }
+}
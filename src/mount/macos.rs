@@ -0,0 +1,923 @@
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use fuser::{
+    BsdFileFlags, Config, Errno, FileHandle, FopenFlags, Generation, INodeNo, KernelConfig,
+    LockOwner, MountOption, OpenFlags, RenameFlags, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, Session,
+    SessionACL, SessionUnmounter, TimeOrNow, WriteFlags,
+};
+use futures_util::FutureExt;
+use shush_rs::{ExposeSecret, SecretString};
+use tokio::runtime::Handle;
+use tokio::task::{self, JoinHandle};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, PasswordProvider,
+    SetFileAttr, FS_APPEND_FL, FS_IMMUTABLE_FL,
+};
+use crate::log::RedactedName;
+use crate::mount;
+use crate::mount::{
+    prepare_mount_point_dir, remove_mount_point_dir, MountHandleInner, MountOptions, MountPoint,
+    OverlayMountPoint,
+};
+
+/// How long the kernel caches entries and attributes if not set in [`MountOptions`].
+const DEFAULT_TTL: Duration = Duration::from_secs(1);
+/// Max entries we read for each `readdir`, the kernel asks again from where its buffer got full.
+const READDIR_PAGE_SIZE: usize = 1024;
+
+/// `fuser` calls us from its own thread and each request is answered with the `reply` it gets,
+/// we run the operations on the tokio runtime we were mounted from and reply from there,
+/// so a slow operation doesn't block the others.
+///
+/// Permissions are checked by the kernel, we mount with `default_permissions`.
+struct EncryptedFsFuser {
+    fs: Arc<EncryptedFs>,
+    rt: Handle,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+}
+
+impl EncryptedFsFuser {
+    fn new(fs: Arc<EncryptedFs>, rt: Handle, options: &MountOptions) -> Self {
+        Self {
+            fs,
+            rt,
+            entry_ttl: options.entry_timeout.unwrap_or(DEFAULT_TTL),
+            attr_ttl: options.attr_timeout.unwrap_or(DEFAULT_TTL),
+        }
+    }
+
+    fn spawn<F>(&self, f: impl FnOnce(Arc<EncryptedFs>) -> F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.rt.spawn(f(self.fs.clone()));
+    }
+
+    fn reply_entry(
+        &self,
+        parent: INodeNo,
+        name: &OsStr,
+        create_attr: CreateFileAttr,
+        reply: ReplyEntry,
+    ) {
+        let Some(name) = secret_name(name) else {
+            reply.error(Errno::EINVAL);
+            return;
+        };
+        let ttl = self.entry_ttl;
+        self.spawn(|fs| async move {
+            match fs.create(parent.0, &name, create_attr, false, false).await {
+                Ok((_, attr)) => reply.entry(&ttl, &attr.into(), Generation(0)),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+}
+
+impl From<FileAttr> for fuser::FileAttr {
+    fn from(from: FileAttr) -> Self {
+        Self {
+            ino: INodeNo(from.ino),
+            size: from.size,
+            blocks: from.blocks,
+            atime: from.atime,
+            mtime: from.mtime,
+            ctime: from.ctime,
+            crtime: from.crtime,
+            kind: to_fuser_kind(from.kind),
+            perm: from.perm,
+            nlink: from.nlink,
+            uid: from.uid,
+            gid: from.gid,
+            rdev: from.rdev,
+            blksize: from.blksize,
+            flags: to_bsd_flags(from.flags).bits(),
+        }
+    }
+}
+
+impl fuser::Filesystem for EncryptedFsFuser {
+    #[instrument(skip(self, _req, _config))]
+    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn destroy(&mut self) {
+        // called from the session thread, not from the runtime, so we can block
+        if let Err(err) = self.rt.block_on(self.fs.sync_all()) {
+            error!(err = %err, "syncing all");
+        }
+        // drops the cached plaintext
+        if let Err(err) = self.rt.block_on(self.fs.clear_caches()) {
+            error!(err = %err, "clearing caches");
+        }
+    }
+
+    #[instrument(skip(self, _req, name), fields(name = %RedactedName::from(name)))]
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        let Some(name) = secret_name(name) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+        let ttl = self.entry_ttl;
+        self.spawn(|fs| async move {
+            match fs.find_by_name(parent.0, &name).await {
+                Ok(Some(attr)) => reply.entry(&ttl, &attr.into(), Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req))]
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        let ttl = self.attr_ttl;
+        self.spawn(|fs| async move {
+            match fs.get_attr(ino.0).await {
+                Ok(attr) => reply.attr(&ttl, &attr.into()),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req, reply))]
+    fn setattr(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        _fh: Option<FileHandle>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<BsdFileFlags>,
+        reply: ReplyAttr,
+    ) {
+        let mut set_attr = SetFileAttr::default();
+        if let Some(mode) = mode {
+            #[allow(clippy::cast_possible_truncation)]
+            let perm = (mode & 0o7777) as u16;
+            set_attr = set_attr.with_perm(perm);
+        }
+        if let Some(uid) = uid {
+            set_attr = set_attr.with_uid(uid);
+        }
+        if let Some(gid) = gid {
+            set_attr = set_attr.with_gid(gid);
+        }
+        if let Some(atime) = atime {
+            set_attr = set_attr.with_atime(system_time(atime));
+        }
+        if let Some(mtime) = mtime {
+            set_attr = set_attr.with_mtime(system_time(mtime));
+        }
+        if let Some(ctime) = ctime {
+            set_attr = set_attr.with_ctime(ctime);
+        }
+        if let Some(crtime) = crtime {
+            set_attr = set_attr.with_crtime(crtime);
+        }
+        if let Some(flags) = flags {
+            set_attr = set_attr.with_flags(from_bsd_flags(flags));
+        }
+        let ttl = self.attr_ttl;
+        self.spawn(|fs| async move {
+            let res = async {
+                if let Some(size) = size {
+                    fs.set_len(ino.0, size).await?;
+                }
+                // times explicitly set by utimes are kept as they are, even if older, like when restoring from backups
+                if atime.is_some() || mtime.is_some() {
+                    fs.set_attr_overwrite(ino.0, set_attr).await?;
+                } else {
+                    fs.set_attr(ino.0, set_attr).await?;
+                }
+                fs.get_attr(ino.0).await
+            }
+            .await;
+            match res {
+                Ok(attr) => reply.attr(&ttl, &attr.into()),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req))]
+    fn readlink(&self, _req: &Request, ino: INodeNo, reply: ReplyData) {
+        self.spawn(|fs| async move {
+            match fs.read_link(ino.0).await {
+                Ok(target) => reply.data(target.expose_secret().as_bytes()),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, req, name), fields(name = %RedactedName::from(name)))]
+    fn mknod(
+        &self,
+        req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        if mode & u32::from(libc::S_IFMT) != u32::from(libc::S_IFREG) {
+            // only regular files for now
+            reply.error(Errno::ENOSYS);
+            return;
+        }
+        self.reply_entry(
+            parent,
+            name,
+            create_attr(FileType::RegularFile, mode & !umask, req),
+            reply,
+        );
+    }
+
+    #[instrument(skip(self, req, name), fields(name = %RedactedName::from(name)))]
+    fn mkdir(
+        &self,
+        req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.reply_entry(
+            parent,
+            name,
+            create_attr(FileType::Directory, mode & !umask, req),
+            reply,
+        );
+    }
+
+    #[instrument(skip(self, _req, name), fields(name = %RedactedName::from(name)))]
+    fn unlink(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        let Some(name) = secret_name(name) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+        self.spawn(|fs| async move {
+            match fs.remove_file(parent.0, &name).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req, name), fields(name = %RedactedName::from(name)))]
+    fn rmdir(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        let Some(name) = secret_name(name) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+        self.spawn(|fs| async move {
+            match fs.remove_dir(parent.0, &name).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req, link_name, target), fields(name = %RedactedName::from(link_name)))]
+    fn symlink(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let (Some(name), Some(target)) = (secret_name(link_name), secret_name(target.as_os_str()))
+        else {
+            reply.error(Errno::EINVAL);
+            return;
+        };
+        let ttl = self.entry_ttl;
+        self.spawn(|fs| async move {
+            match fs.create_symlink(parent.0, &name, &target).await {
+                Ok(attr) => reply.entry(&ttl, &attr.into(), Generation(0)),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req, name, newname), fields(name = %RedactedName::from(name), newname = %RedactedName::from(newname)))]
+    fn rename(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        newparent: INodeNo,
+        newname: &OsStr,
+        _flags: RenameFlags,
+        reply: ReplyEmpty,
+    ) {
+        let (Some(name), Some(newname)) = (secret_name(name), secret_name(newname)) else {
+            reply.error(Errno::EINVAL);
+            return;
+        };
+        self.spawn(|fs| async move {
+            match fs.rename(parent.0, &name, newparent.0, &newname).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req, newname), fields(newname = %RedactedName::from(newname)))]
+    fn link(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        newparent: INodeNo,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let Some(newname) = secret_name(newname) else {
+            reply.error(Errno::EINVAL);
+            return;
+        };
+        let ttl = self.entry_ttl;
+        self.spawn(|fs| async move {
+            match fs.link(ino.0, newparent.0, &newname).await {
+                Ok(attr) => reply.entry(&ttl, &attr.into(), Generation(0)),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req))]
+    fn open(&self, _req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        let Some((read, write)) = access_mode(flags.0) else {
+            reply.error(Errno::EINVAL);
+            return;
+        };
+        let truncate = flags.0 & libc::O_TRUNC != 0;
+        let append = flags.0 & libc::O_APPEND != 0;
+        self.spawn(|fs| async move {
+            let res = async {
+                if truncate {
+                    fs.set_len(ino.0, 0).await?;
+                }
+                // write only appenders don't take the single write slot, so multiple processes can
+                // append to the same log
+                if append && write && !read {
+                    fs.open_append(ino.0).await
+                } else {
+                    fs.open(ino.0, read, write).await
+                }
+            }
+            .await;
+            match res {
+                Ok(fh) => reply.opened(FileHandle(fh), FopenFlags::empty()),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req, reply))]
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        self.spawn(|fs| async move {
+            let mut buf = vec![0; size as usize];
+            match fs.read(ino.0, offset, &mut buf, fh.0).await {
+                Ok(len) => reply.data(&buf[..len]),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req, data, reply), fields(len = data.len()))]
+    fn write(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        data: &[u8],
+        _write_flags: WriteFlags,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyWrite,
+    ) {
+        let data = data.to_vec();
+        self.spawn(|fs| async move {
+            match fs.write(ino.0, offset, &data, fh.0).await {
+                #[allow(clippy::cast_possible_truncation)]
+                Ok(len) => reply.written(len as u32),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    #[instrument(skip(self, _req))]
+    fn flush(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _lock_owner: LockOwner,
+        reply: ReplyEmpty,
+    ) {
+        self.spawn(|fs| async move { reply_empty(flush_handle(&fs, fh.0, false).await, reply) });
+    }
+
+    #[instrument(skip(self, _req))]
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.spawn(|fs| async move {
+            let res = async {
+                if flush {
+                    flush_handle(&fs, fh.0, false).await?;
+                }
+                fs.release(fh.0).await
+            }
+            .await;
+            reply_empty(res, reply);
+        });
+    }
+
+    #[instrument(skip(self, _req))]
+    fn fsync(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.spawn(|fs| async move { reply_empty(flush_handle(&fs, fh.0, datasync).await, reply) });
+    }
+
+    #[instrument(skip(self, _req, reply))]
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        self.spawn(|fs| async move {
+            #[allow(clippy::cast_possible_truncation)]
+            let (page, _) = match fs
+                .read_dir_paged(ino.0, offset as usize, READDIR_PAGE_SIZE)
+                .await
+            {
+                Ok(page) => page,
+                Err(err) => {
+                    reply.error(errno(&err));
+                    return;
+                }
+            };
+            // the offset of an entry is the one of the next, so the kernel continues from there
+            for (i, entry) in (offset + 1..).zip(page) {
+                if reply.add(
+                    INodeNo(entry.ino),
+                    i,
+                    to_fuser_kind(entry.kind),
+                    entry.name.expose_secret().as_str(),
+                ) {
+                    // buffer is full
+                    break;
+                }
+            }
+            reply.ok();
+        });
+    }
+
+    #[instrument(skip(self, _req))]
+    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
+        match statfs_data_dir(&self.fs) {
+            Ok((blocks, bfree, bavail, files, ffree, frsize)) => {
+                // reads and writes of whole blocks are the most efficient
+                #[allow(clippy::cast_possible_truncation)]
+                reply.statfs(
+                    blocks,
+                    bfree,
+                    bavail,
+                    files,
+                    ffree,
+                    BLOCK_SIZE as u32,
+                    u32::MAX,
+                    frsize,
+                );
+            }
+            Err(err) => {
+                warn!(err = %err, "cannot get stats of the data dir");
+                reply.statfs(1, 0, 0, 1, 0, 4096, u32::MAX, 0);
+            }
+        }
+    }
+
+    #[instrument(skip(self, req, name), fields(name = %RedactedName::from(name)))]
+    fn create(
+        &self,
+        req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let (Some(name), Some((read, write))) = (secret_name(name), access_mode(flags)) else {
+            reply.error(Errno::EINVAL);
+            return;
+        };
+        let create_attr = create_attr(FileType::RegularFile, mode & !umask, req);
+        let ttl = self.entry_ttl;
+        self.spawn(|fs| async move {
+            match fs.create(parent.0, &name, create_attr, read, write).await {
+                Ok((fh, attr)) => reply.created(
+                    &ttl,
+                    &attr.into(),
+                    Generation(0),
+                    FileHandle(fh),
+                    FopenFlags::empty(),
+                ),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+}
+
+/// Only write handles have something to flush, and a read-only volume has none of them.
+async fn flush_handle(fs: &EncryptedFs, fh: u64, datasync: bool) -> FsResult<()> {
+    if fs.is_write_handle(fh).await {
+        fs.fsync(fh, datasync).await?;
+    }
+    Ok(())
+}
+
+fn reply_empty(res: FsResult<()>, reply: ReplyEmpty) {
+    match res {
+        Ok(()) => reply.ok(),
+        Err(err) => reply.error(errno(&err)),
+    }
+}
+
+fn secret_name(name: &OsStr) -> Option<SecretString> {
+    name.to_str()
+        .map(|name| SecretString::from_str(name).unwrap())
+}
+
+/// The `(read, write)` access of the `flags` of `open(2)`.
+const fn access_mode(flags: i32) -> Option<(bool, bool)> {
+    match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => Some((true, false)),
+        libc::O_WRONLY => Some((false, true)),
+        libc::O_RDWR => Some((true, true)),
+        // exactly one access mode flag must be specified
+        _ => None,
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn create_attr(kind: FileType, mode: u32, req: &Request) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: (mode & 0o7777) as u16,
+        uid: req.uid(),
+        gid: req.gid(),
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+fn errno(err: &FsError) -> Errno {
+    match err {
+        FsError::InodeNotFound | FsError::NotFound(_) => Errno::ENOENT,
+        FsError::AlreadyExists => Errno::EEXIST,
+        FsError::NotEmpty => Errno::ENOTEMPTY,
+        FsError::InvalidInput(_) | FsError::InvalidInodeType => Errno::EINVAL,
+        FsError::InvalidFileHandle => Errno::EBADF,
+        FsError::ReadOnly => Errno::EROFS,
+        FsError::NotPermitted => Errno::EPERM,
+        FsError::AccessDenied => Errno::EACCES,
+        FsError::MaxFilesizeExceeded(_) => Errno::EFBIG,
+        FsError::NoSpace => Errno::ENOSPC,
+        FsError::QuotaExceeded => Errno::EDQUOT,
+        FsError::NotSupported(_) => Errno::ENOTSUP,
+        err => {
+            error!(err = %err);
+            Errno::EIO
+        }
+    }
+}
+
+const fn to_fuser_kind(kind: FileType) -> fuser::FileType {
+    match kind {
+        FileType::Directory => fuser::FileType::Directory,
+        FileType::RegularFile => fuser::FileType::RegularFile,
+        FileType::Symlink => fuser::FileType::Symlink,
+    }
+}
+
+fn system_time(t: TimeOrNow) -> SystemTime {
+    match t {
+        TimeOrNow::SpecificTime(t) => t,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+/// We keep the immutable and append-only flags of `chflags(2)` as [`FS_IMMUTABLE_FL`] and [`FS_APPEND_FL`],
+/// so they are enforced the same on all platforms, the others are not supported.
+fn from_bsd_flags(flags: BsdFileFlags) -> u32 {
+    let mut res = 0;
+    if flags.intersects(BsdFileFlags::UF_IMMUTABLE | BsdFileFlags::SF_IMMUTABLE) {
+        res |= FS_IMMUTABLE_FL;
+    }
+    if flags.intersects(BsdFileFlags::UF_APPEND | BsdFileFlags::SF_APPEND) {
+        res |= FS_APPEND_FL;
+    }
+    res
+}
+
+fn to_bsd_flags(flags: u32) -> BsdFileFlags {
+    let mut res = BsdFileFlags::empty();
+    if flags & FS_IMMUTABLE_FL != 0 {
+        res |= BsdFileFlags::UF_IMMUTABLE;
+    }
+    if flags & FS_APPEND_FL != 0 {
+        res |= BsdFileFlags::UF_APPEND;
+    }
+    res
+}
+
+/// `(blocks, bfree, bavail, files, ffree, frsize)` of the data dir, with the sizes of the content we can keep in it.
+#[allow(clippy::unnecessary_cast)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+fn statfs_data_dir(fs: &EncryptedFs) -> io::Result<(u64, u64, u64, u64, u64, u32)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(fs.data_dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ratio = fs.storage_overhead_ratio();
+    let content = |blocks: u64| (blocks as f64 / ratio) as u64;
+    // each inode needs two files in the data dir, the metadata and the content
+    let ffree = stat.f_ffree as u64 / 2;
+    let files = fs.count_inodes().map_err(io::Error::other)? + ffree;
+    let (mut blocks, mut bfree, mut bavail) = (
+        content(stat.f_blocks as u64),
+        content(stat.f_bfree as u64),
+        content(stat.f_bavail as u64),
+    );
+    // with a quota we look like a disk of that size
+    if let Some((quota, used)) = fs.quota_usage() {
+        let frsize = (stat.f_frsize as u64).max(1);
+        let free = quota.saturating_sub(used) / frsize;
+        blocks = blocks.min(quota / frsize);
+        bfree = bfree.min(free);
+        bavail = bavail.min(free);
+    }
+    Ok((blocks, bfree, bavail, files, ffree, stat.f_frsize as u32))
+}
+
+#[allow(clippy::struct_excessive_bools)]
+pub struct MountPointImpl {
+    mountpoint: PathBuf,
+    data_dir: PathBuf,
+    password_provider: Option<Box<dyn PasswordProvider>>,
+    cipher: Cipher,
+    allow_root: bool,
+    allow_other: bool,
+    read_only: bool,
+    options: MountOptions,
+}
+
+#[async_trait]
+impl MountPoint for MountPointImpl {
+    fn new(
+        mountpoint: PathBuf,
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        allow_root: bool,
+        allow_other: bool,
+        read_only: bool,
+        options: MountOptions,
+    ) -> Self {
+        Self {
+            mountpoint,
+            data_dir,
+            password_provider: Some(password_provider),
+            cipher,
+            allow_root,
+            allow_other,
+            read_only,
+            options,
+        }
+    }
+
+    async fn mount(mut self) -> FsResult<mount::MountHandle> {
+        self.options.validate()?;
+        let created_dir = prepare_mount_point_dir(&self.mountpoint, &self.options).await?;
+        let res = mount_fuse(
+            self.mountpoint.clone(),
+            self.data_dir,
+            self.password_provider.take().unwrap(),
+            self.cipher,
+            self.allow_root,
+            self.allow_other,
+            self.read_only,
+            self.options,
+        )
+        .await;
+        let (session, unmounter) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                if created_dir {
+                    remove_mount_point_dir(&self.mountpoint).await;
+                }
+                return Err(err);
+            }
+        };
+        Ok(mount::MountHandle {
+            inner: Some(MountHandleInnerImpl {
+                session,
+                unmounter,
+                created_dir: created_dir.then_some(self.mountpoint.clone()),
+            }),
+            mountpoint: self.mountpoint,
+        })
+    }
+}
+
+#[allow(clippy::unused_async)]
+pub(in crate::mount) async fn mount_overlay(
+    _mount_point: OverlayMountPoint,
+) -> FsResult<mount::MountHandle> {
+    Err(FsError::Other("overlay mount is not supported on macOS"))
+}
+
+pub(in crate::mount) struct MountHandleInnerImpl {
+    // runs the session until it's umounted
+    session: JoinHandle<io::Result<()>>,
+    unmounter: SessionUnmounter,
+    // mount point directory we created, removed on umount
+    created_dir: Option<PathBuf>,
+}
+
+impl Future for MountHandleInnerImpl {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.session
+            .poll_unpin(cx)
+            .map(|res| res.map_err(io::Error::other)?)
+    }
+}
+
+#[async_trait]
+impl MountHandleInner for MountHandleInnerImpl {
+    async fn unmount(mut self) -> io::Result<()> {
+        self.unmounter.unmount()?;
+        self.session.await.map_err(io::Error::other)??;
+        if let Some(dir) = self.created_dir {
+            remove_mount_point_dir(&dir).await;
+        }
+        Ok(())
+    }
+}
+
+#[instrument(skip(password_provider))]
+#[allow(clippy::fn_params_excessive_bools)]
+async fn mount_fuse(
+    mountpoint: PathBuf,
+    data_dir: PathBuf,
+    password_provider: Box<dyn PasswordProvider>,
+    cipher: Cipher,
+    allow_root: bool,
+    allow_other: bool,
+    read_only: bool,
+    options: MountOptions,
+) -> FsResult<(JoinHandle<io::Result<()>>, SessionUnmounter)> {
+    info!("Checking password and mounting FUSE filesystem");
+    let read_only = read_only || options.snapshot.is_some();
+    let config = options.fs_config()?;
+    let fs = if let Some(snapshot_id) = options.snapshot {
+        EncryptedFs::new_readonly_at(data_dir, snapshot_id, password_provider, cipher, config)
+            .await?
+    } else {
+        EncryptedFs::new_with_config(data_dir, password_provider, cipher, read_only, config).await?
+    };
+    if options.self_test && !read_only {
+        info!("Running self-test");
+        fs.self_test().await?;
+    }
+    if options.max_background.is_some() || options.congestion_threshold.is_some() {
+        debug!("FUSE queue tuning is only applied on Linux");
+    }
+
+    let fs = EncryptedFsFuser::new(fs, Handle::current(), &options);
+    let config = fuse_config(read_only, allow_root, allow_other);
+    // mounting blocks until macFUSE is ready
+    let mut session =
+        task::spawn_blocking(move || Session::new(fs, &mountpoint, &config)).await??;
+    let unmounter = session.unmount_callable();
+    let session = task::spawn_blocking(move || session.run());
+
+    Ok((session, unmounter))
+}
+
+fn fuse_config(read_only: bool, allow_root: bool, allow_other: bool) -> Config {
+    let mut config = Config::default();
+    config.mount_options = vec![
+        MountOption::FSName("rencfs".to_string()),
+        MountOption::DefaultPermissions,
+        if read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
+    ];
+    config.acl = if allow_other {
+        SessionACL::All
+    } else if allow_root {
+        SessionACL::RootAndOwner
+    } else {
+        SessionACL::Owner
+    };
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bsd_flags() {
+        let flags = BsdFileFlags::UF_IMMUTABLE | BsdFileFlags::SF_APPEND | BsdFileFlags::UF_HIDDEN;
+        assert_eq!(FS_IMMUTABLE_FL | FS_APPEND_FL, from_bsd_flags(flags));
+        assert_eq!(
+            BsdFileFlags::UF_IMMUTABLE | BsdFileFlags::UF_APPEND,
+            to_bsd_flags(FS_IMMUTABLE_FL | FS_APPEND_FL)
+        );
+        assert_eq!(0, from_bsd_flags(BsdFileFlags::UF_NODUMP));
+    }
+
+    #[test]
+    fn test_access_mode() {
+        assert_eq!(Some((true, false)), access_mode(libc::O_RDONLY));
+        assert_eq!(
+            Some((false, true)),
+            access_mode(libc::O_WRONLY | libc::O_APPEND)
+        );
+        assert_eq!(
+            Some((true, true)),
+            access_mode(libc::O_RDWR | libc::O_TRUNC)
+        );
+        assert_eq!(None, access_mode(libc::O_ACCMODE));
+    }
+}
@@ -0,0 +1,782 @@
+use std::ffi::c_void;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures_util::FutureExt;
+use shush_rs::ExposeSecret;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tokio::task::{self, JoinHandle};
+use tracing::{debug, error, info, instrument, warn};
+use widestring::U16CStr;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{
+    ERROR_ACCESS_DENIED, ERROR_ALREADY_EXISTS, ERROR_DIR_NOT_EMPTY, ERROR_DISK_FULL,
+    ERROR_FILE_NOT_FOUND, ERROR_FILE_TOO_LARGE, ERROR_HANDLE_EOF, ERROR_INVALID_HANDLE,
+    ERROR_INVALID_NAME, ERROR_INVALID_PARAMETER, ERROR_IO_DEVICE, ERROR_NOT_SUPPORTED,
+    ERROR_WRITE_PROTECT,
+};
+use windows::Win32::Storage::FileSystem::{
+    GetDiskFreeSpaceExW, FILE_ACCESS_RIGHTS, FILE_APPEND_DATA, FILE_ATTRIBUTE_ARCHIVE,
+    FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_READONLY, FILE_FLAGS_AND_ATTRIBUTES, FILE_WRITE_DATA,
+    INVALID_FILE_ATTRIBUTES,
+};
+use winfsp::filesystem::{
+    DirBuffer, DirInfo, DirMarker, FileInfo, FileSecurity, FileSystemContext, OpenFileInfo,
+    VolumeInfo, WideNameInfo,
+};
+use winfsp::host::{FileSystemHost, VolumeParams};
+use winfsp::FspError;
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, PasswordProvider,
+    SetFileAttr, VolumeFormat, FS_IMMUTABLE_FL, ROOT_INODE,
+};
+use crate::mount;
+use crate::mount::{MountHandleInner, MountOptions, MountPoint, OverlayMountPoint};
+
+/// How long Windows caches file info if not set in [`MountOptions::attr_timeout`].
+const DEFAULT_TTL: Duration = Duration::from_secs(1);
+/// Sizes are reported in sectors of this many bytes, it's the largest WinFSP supports.
+const SECTOR_SIZE: u16 = 4096;
+/// `FILE_DIRECTORY_FILE` of the create options, from `ntioapi.h`.
+const FILE_DIRECTORY_FILE: u32 = 0x0000_0001;
+/// `FspCleanupDelete` of the cleanup flags, the file was opened with delete on close or marked with `set_delete`.
+const FSP_CLEANUP_DELETE: u32 = 0x01;
+/// Offset of the Unix epoch in `FILETIME`, 100 ns intervals since 1601.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// WinFSP calls us from its own threads and waits for the result, we run the operations on the tokio runtime we
+/// were mounted from.
+///
+/// Paths come as `\dir\file` and are resolved from root on each open, names are case-insensitive if the volume is,
+/// see [`VolumeFormat::case_insensitive`], new volumes are by default. Windows has no
+/// owner and mode, files are created with `0o644` and directories with `0o755`, owned by `0:0`. The read-only
+/// attribute maps to the write bits of the mode. There are no ACLs, the access is not checked.
+struct EncryptedFsWinFsp {
+    fs: Arc<EncryptedFs>,
+    rt: Handle,
+}
+
+/// An opened file or directory.
+struct FileContext {
+    ino: u64,
+    kind: FileType,
+    // from `EncryptedFs::open`, directories have none
+    fh: Option<u64>,
+    // entries of the directory while it's enumerated
+    dir_buffer: DirBuffer,
+}
+
+impl EncryptedFsWinFsp {
+    fn open_attr(&self, attr: &FileAttr, write: bool) -> FsResult<FileContext> {
+        let fh = if attr.kind == FileType::Directory {
+            None
+        } else {
+            Some(self.rt.block_on(self.fs.open(attr.ino, true, write))?)
+        };
+        Ok(FileContext {
+            ino: attr.ino,
+            kind: attr.kind,
+            fh,
+            dir_buffer: DirBuffer::new(),
+        })
+    }
+
+    fn fill_info(&self, ino: u64, file_info: &mut FileInfo) -> winfsp::Result<()> {
+        let attr = self.rt.block_on(self.fs.get_attr(ino)).map_err(fsp_error)?;
+        set_file_info(file_info, &attr);
+        Ok(())
+    }
+}
+
+impl FileSystemContext for EncryptedFsWinFsp {
+    type FileContext = FileContext;
+
+    fn get_security_by_name(
+        &self,
+        file_name: &U16CStr,
+        _security_descriptor: Option<&mut [c_void]>,
+        _reparse_point_resolver: impl FnOnce(&U16CStr) -> Option<FileSecurity>,
+    ) -> winfsp::Result<FileSecurity> {
+        let path = path(file_name)?;
+        let attr = self
+            .rt
+            .block_on(self.fs.lookup_path(&path))
+            .map_err(fsp_error)?;
+        // without a security descriptor every access is granted
+        Ok(FileSecurity {
+            reparse: false,
+            sz_security_descriptor: 0,
+            attributes: file_attributes(&attr),
+        })
+    }
+
+    #[instrument(skip(self, file_name, file_info))]
+    fn open(
+        &self,
+        file_name: &U16CStr,
+        _create_options: u32,
+        granted_access: FILE_ACCESS_RIGHTS,
+        file_info: &mut OpenFileInfo,
+    ) -> winfsp::Result<Self::FileContext> {
+        let path = path(file_name)?;
+        let attr = self
+            .rt
+            .block_on(self.fs.lookup_path(&path))
+            .map_err(fsp_error)?;
+        let write = granted_access.0 & (FILE_WRITE_DATA.0 | FILE_APPEND_DATA.0) != 0;
+        let context = self.open_attr(&attr, write).map_err(fsp_error)?;
+        set_file_info(file_info.as_mut(), &attr);
+        Ok(context)
+    }
+
+    #[instrument(skip(self, context))]
+    fn close(&self, context: Self::FileContext) {
+        if let Some(fh) = context.fh {
+            if let Err(err) = self.rt.block_on(self.fs.release(fh)) {
+                error!(err = %err, "releasing handle");
+            }
+        }
+    }
+
+    #[instrument(skip(self, context, file_name))]
+    fn cleanup(&self, context: &Self::FileContext, file_name: Option<&U16CStr>, flags: u32) {
+        let Some(file_name) = file_name.filter(|_| flags & FSP_CLEANUP_DELETE != 0) else {
+            return;
+        };
+        let res = path(file_name).map_err(|_| FsError::InvalidInput("invalid file name"));
+        let res = res.and_then(|path| {
+            self.rt.block_on(async {
+                let (parent, name) = self.fs.lookup_path_parent(&path).await?;
+                if context.kind == FileType::Directory {
+                    self.fs.remove_dir(parent, &name).await
+                } else {
+                    self.fs.remove_file(parent, &name).await
+                }
+            })
+        });
+        if let Err(err) = res {
+            error!(err = %err, "deleting on cleanup");
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip_all)]
+    fn create(
+        &self,
+        file_name: &U16CStr,
+        create_options: u32,
+        _granted_access: FILE_ACCESS_RIGHTS,
+        file_attributes: FILE_FLAGS_AND_ATTRIBUTES,
+        _security_descriptor: Option<&[c_void]>,
+        _allocation_size: u64,
+        _extra_buffer: Option<&[u8]>,
+        _extra_buffer_is_reparse_point: bool,
+        file_info: &mut OpenFileInfo,
+    ) -> winfsp::Result<Self::FileContext> {
+        let path = path(file_name)?;
+        let directory = create_options & FILE_DIRECTORY_FILE != 0;
+        let mut perm = if directory { 0o755 } else { 0o644 };
+        if file_attributes.0 & FILE_ATTRIBUTE_READONLY.0 != 0 {
+            perm &= !0o222;
+        }
+        let create_attr = CreateFileAttr {
+            kind: if directory {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            },
+            perm,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        };
+        let (fh, attr) = self
+            .rt
+            .block_on(async {
+                let (parent, name) = self.fs.lookup_path_parent(&path).await?;
+                self.fs
+                    .create(parent, &name, create_attr, !directory, !directory)
+                    .await
+            })
+            .map_err(fsp_error)?;
+        set_file_info(file_info.as_mut(), &attr);
+        Ok(FileContext {
+            ino: attr.ino,
+            kind: attr.kind,
+            fh: (!directory).then_some(fh),
+            dir_buffer: DirBuffer::new(),
+        })
+    }
+
+    fn flush(
+        &self,
+        context: Option<&Self::FileContext>,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        // `None` flushes the whole volume
+        let Some(context) = context else {
+            return self.rt.block_on(self.fs.sync_all()).map_err(fsp_error);
+        };
+        if let Some(fh) = context.fh {
+            self.rt
+                .block_on(async {
+                    if self.fs.is_write_handle(fh).await {
+                        self.fs.fsync(fh, false).await?;
+                    }
+                    Ok(())
+                })
+                .map_err(fsp_error)?;
+        }
+        self.fill_info(context.ino, file_info)
+    }
+
+    fn get_file_info(
+        &self,
+        context: &Self::FileContext,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        self.fill_info(context.ino, file_info)
+    }
+
+    fn get_volume_info(&self, out_volume_info: &mut VolumeInfo) -> winfsp::Result<()> {
+        match volume_size(&self.fs) {
+            Ok((total_size, free_size)) => {
+                out_volume_info.total_size = total_size;
+                out_volume_info.free_size = free_size;
+            }
+            Err(err) => warn!(err = %err, "cannot get stats of the data dir"),
+        }
+        out_volume_info.set_volume_label("rencfs");
+        Ok(())
+    }
+
+    fn overwrite(
+        &self,
+        context: &Self::FileContext,
+        _file_attributes: FILE_FLAGS_AND_ATTRIBUTES,
+        _replace_file_attributes: bool,
+        _allocation_size: u64,
+        _extra_buffer: Option<&[u8]>,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        self.rt
+            .block_on(self.fs.set_len(context.ino, 0))
+            .map_err(fsp_error)?;
+        self.fill_info(context.ino, file_info)
+    }
+
+    #[instrument(skip(self, context, _pattern, buffer))]
+    fn read_directory(
+        &self,
+        context: &Self::FileContext,
+        _pattern: Option<&U16CStr>,
+        marker: DirMarker,
+        buffer: &mut [u8],
+    ) -> winfsp::Result<u32> {
+        // the entries are read once, at the start of the enumeration, then returned from the buffer
+        if let Ok(lock) = context.dir_buffer.acquire(marker.is_none(), None) {
+            let entries = self
+                .rt
+                .block_on(async {
+                    self.fs
+                        .read_dir_plus(context.ino)
+                        .await?
+                        .collect::<FsResult<Vec<_>>>()
+                })
+                .map_err(fsp_error)?;
+            for entry in entries {
+                let name = entry.name.expose_secret();
+                if context.ino == ROOT_INODE && (*name == "." || *name == "..") {
+                    continue;
+                }
+                let mut dir_info: DirInfo<255> = DirInfo::new();
+                dir_info.set_name(name.as_str())?;
+                set_file_info(dir_info.file_info_mut(), &entry.attr);
+                lock.write(&mut dir_info)?;
+            }
+        }
+        Ok(context.dir_buffer.read(marker, buffer))
+    }
+
+    #[instrument(skip(self, _context, file_name, new_file_name))]
+    fn rename(
+        &self,
+        _context: &Self::FileContext,
+        file_name: &U16CStr,
+        new_file_name: &U16CStr,
+        replace_if_exists: bool,
+    ) -> winfsp::Result<()> {
+        let (path, new_path) = (path(file_name)?, path(new_file_name)?);
+        self.rt
+            .block_on(async {
+                let (parent, name) = self.fs.lookup_path_parent(&path).await?;
+                let (new_parent, new_name) = self.fs.lookup_path_parent(&new_path).await?;
+                if !replace_if_exists
+                    && self.fs.find_by_name(new_parent, &new_name).await?.is_some()
+                {
+                    return Err(FsError::AlreadyExists);
+                }
+                self.fs.rename(parent, &name, new_parent, &new_name).await
+            })
+            .map_err(fsp_error)
+    }
+
+    #[instrument(skip(self, context, file_info))]
+    fn set_basic_info(
+        &self,
+        context: &Self::FileContext,
+        file_attributes: u32,
+        creation_time: u64,
+        last_access_time: u64,
+        last_write_time: u64,
+        last_change_time: u64,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        let ino = context.ino;
+        self.rt
+            .block_on(async {
+                let mut set_attr = SetFileAttr::default();
+                // 0 keeps the time
+                set_attr.crtime = system_time(creation_time);
+                set_attr.atime = system_time(last_access_time);
+                set_attr.mtime = system_time(last_write_time);
+                set_attr.ctime = system_time(last_change_time);
+                if file_attributes != INVALID_FILE_ATTRIBUTES {
+                    let perm = self.fs.get_attr(ino).await?.perm;
+                    set_attr.perm = Some(if file_attributes & FILE_ATTRIBUTE_READONLY.0 != 0 {
+                        perm & !0o222
+                    } else {
+                        perm | 0o200
+                    });
+                }
+                self.fs.set_attr(ino, set_attr).await
+            })
+            .map_err(fsp_error)?;
+        self.fill_info(ino, file_info)
+    }
+
+    fn set_delete(
+        &self,
+        context: &Self::FileContext,
+        _file_name: &U16CStr,
+        delete_file: bool,
+    ) -> winfsp::Result<()> {
+        // it's removed on cleanup, here we only refuse what would fail
+        if delete_file
+            && context.kind == FileType::Directory
+            && !self.fs.is_empty(context.ino).map_err(fsp_error)?
+        {
+            return Err(FspError::WIN32(ERROR_DIR_NOT_EMPTY.0));
+        }
+        Ok(())
+    }
+
+    fn set_file_size(
+        &self,
+        context: &Self::FileContext,
+        new_size: u64,
+        set_allocation_size: bool,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        self.rt
+            .block_on(async {
+                // we don't preallocate, only a smaller allocation size truncates the file
+                if !set_allocation_size || new_size < self.fs.get_attr(context.ino).await?.size {
+                    self.fs.set_len(context.ino, new_size).await?;
+                }
+                Ok(())
+            })
+            .map_err(fsp_error)?;
+        self.fill_info(context.ino, file_info)
+    }
+
+    fn read(
+        &self,
+        context: &Self::FileContext,
+        buffer: &mut [u8],
+        offset: u64,
+    ) -> winfsp::Result<u32> {
+        let fh = context.fh.ok_or(FspError::WIN32(ERROR_INVALID_HANDLE.0))?;
+        let len = self
+            .rt
+            .block_on(self.fs.read(context.ino, offset, buffer, fh))
+            .map_err(fsp_error)?;
+        if len == 0 && !buffer.is_empty() {
+            return Err(FspError::WIN32(ERROR_HANDLE_EOF.0));
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(len as u32)
+    }
+
+    fn write(
+        &self,
+        context: &Self::FileContext,
+        buffer: &[u8],
+        offset: u64,
+        write_to_eof: bool,
+        constrained_io: bool,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<u32> {
+        let fh = context.fh.ok_or(FspError::WIN32(ERROR_INVALID_HANDLE.0))?;
+        let (len, attr) = self
+            .rt
+            .block_on(async {
+                let size = self.fs.get_attr(context.ino).await?.size;
+                let offset = if write_to_eof { size } else { offset };
+                // paging IO doesn't extend the file
+                #[allow(clippy::cast_possible_truncation)]
+                let buffer = if constrained_io {
+                    &buffer[..buffer.len().min(size.saturating_sub(offset) as usize)]
+                } else {
+                    buffer
+                };
+                let len = if buffer.is_empty() {
+                    0
+                } else {
+                    self.fs.write(context.ino, offset, buffer, fh).await?
+                };
+                Ok((len, self.fs.get_attr(context.ino).await?))
+            })
+            .map_err(fsp_error)?;
+        set_file_info(file_info, &attr);
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(len as u32)
+    }
+}
+
+/// `\dir\file` as `/dir/file`, like [`EncryptedFs::lookup_path`] expects.
+fn path(file_name: &U16CStr) -> winfsp::Result<String> {
+    let path = file_name
+        .to_string()
+        .map_err(|_| FspError::WIN32(ERROR_INVALID_NAME.0))?;
+    Ok(path.replace('\\', "/"))
+}
+
+fn set_file_info(file_info: &mut FileInfo, attr: &FileAttr) {
+    file_info.file_attributes = file_attributes(attr);
+    file_info.file_size = attr.size;
+    file_info.allocation_size = attr.size.div_ceil(SECTOR_SIZE.into()) * u64::from(SECTOR_SIZE);
+    file_info.creation_time = filetime(attr.crtime);
+    file_info.last_access_time = filetime(attr.atime);
+    file_info.last_write_time = filetime(attr.mtime);
+    file_info.change_time = filetime(attr.ctime);
+    file_info.index_number = attr.ino;
+    file_info.hard_links = attr.nlink;
+}
+
+/// Symlinks are shown as regular files, we don't support reparse points yet.
+fn file_attributes(attr: &FileAttr) -> u32 {
+    let mut res = if attr.kind == FileType::Directory {
+        FILE_ATTRIBUTE_DIRECTORY.0
+    } else {
+        FILE_ATTRIBUTE_ARCHIVE.0
+    };
+    if attr.perm & 0o200 == 0 || attr.flags & FS_IMMUTABLE_FL != 0 {
+        res |= FILE_ATTRIBUTE_READONLY.0;
+    }
+    res
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn filetime(time: SystemTime) -> u64 {
+    let since_epoch = |d: Duration| (d.as_nanos() / 100) as u64;
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => FILETIME_UNIX_EPOCH + since_epoch(d),
+        Err(err) => FILETIME_UNIX_EPOCH.saturating_sub(since_epoch(err.duration())),
+    }
+}
+
+/// `None` for 0, which means the time is not changed.
+fn system_time(filetime: u64) -> Option<SystemTime> {
+    if filetime == 0 {
+        return None;
+    }
+    let nanos = |t: u64| Duration::from_nanos(t.saturating_mul(100));
+    Some(if filetime >= FILETIME_UNIX_EPOCH {
+        UNIX_EPOCH + nanos(filetime - FILETIME_UNIX_EPOCH)
+    } else {
+        UNIX_EPOCH - nanos(FILETIME_UNIX_EPOCH - filetime)
+    })
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn fsp_error(err: FsError) -> FspError {
+    let code = match err {
+        FsError::InodeNotFound | FsError::NotFound(_) => ERROR_FILE_NOT_FOUND,
+        FsError::AlreadyExists => ERROR_ALREADY_EXISTS,
+        FsError::NotEmpty => ERROR_DIR_NOT_EMPTY,
+        FsError::InvalidInput(_) | FsError::InvalidInodeType => ERROR_INVALID_PARAMETER,
+        FsError::InvalidFileHandle => ERROR_INVALID_HANDLE,
+        FsError::ReadOnly => ERROR_WRITE_PROTECT,
+        FsError::NotPermitted | FsError::AccessDenied => ERROR_ACCESS_DENIED,
+        FsError::MaxFilesizeExceeded(_) => ERROR_FILE_TOO_LARGE,
+        FsError::NoSpace | FsError::QuotaExceeded => ERROR_DISK_FULL,
+        FsError::NotSupported(_) => ERROR_NOT_SUPPORTED,
+        err => {
+            error!(err = %err);
+            ERROR_IO_DEVICE
+        }
+    };
+    FspError::WIN32(code.0)
+}
+
+/// `(total, free)` bytes of the data dir, with the sizes of the content we can keep in it.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+fn volume_size(fs: &EncryptedFs) -> io::Result<(u64, u64)> {
+    let dir: Vec<u16> = fs
+        .data_dir
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect();
+    let (mut free, mut total) = (0, 0);
+    unsafe {
+        GetDiskFreeSpaceExW(
+            PCWSTR(dir.as_ptr()),
+            Some(std::ptr::addr_of_mut!(free)),
+            Some(std::ptr::addr_of_mut!(total)),
+            None,
+        )
+    }
+    .map_err(io::Error::other)?;
+    let ratio = fs.storage_overhead_ratio();
+    let (mut total, mut free) = ((total as f64 / ratio) as u64, (free as f64 / ratio) as u64);
+    // with a quota we look like a disk of that size
+    if let Some((quota, used)) = fs.quota_usage() {
+        total = total.min(quota);
+        free = free.min(quota.saturating_sub(used));
+    }
+    Ok((total, free))
+}
+
+#[allow(clippy::struct_excessive_bools)]
+pub struct MountPointImpl {
+    mountpoint: PathBuf,
+    data_dir: PathBuf,
+    password_provider: Option<Box<dyn PasswordProvider>>,
+    cipher: Cipher,
+    allow_root: bool,
+    allow_other: bool,
+    read_only: bool,
+    options: MountOptions,
+}
+
+#[async_trait]
+impl MountPoint for MountPointImpl {
+    fn new(
+        mountpoint: PathBuf,
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        allow_root: bool,
+        allow_other: bool,
+        read_only: bool,
+        options: MountOptions,
+    ) -> Self {
+        Self {
+            mountpoint,
+            data_dir,
+            password_provider: Some(password_provider),
+            cipher,
+            allow_root,
+            allow_other,
+            read_only,
+            options,
+        }
+    }
+
+    async fn mount(mut self) -> FsResult<mount::MountHandle> {
+        self.options.validate()?;
+        if self.allow_root || self.allow_other {
+            debug!("allow_root and allow_other are not used on Windows");
+        }
+        let (stop, host) = mount_winfsp(
+            self.mountpoint.clone(),
+            self.data_dir,
+            self.password_provider.take().unwrap(),
+            self.cipher,
+            self.read_only,
+            self.options,
+        )
+        .await?;
+        Ok(mount::MountHandle {
+            inner: Some(MountHandleInnerImpl {
+                host,
+                stop: Some(stop),
+            }),
+            mountpoint: self.mountpoint,
+        })
+    }
+}
+
+#[allow(clippy::unused_async)]
+pub(in crate::mount) async fn mount_overlay(
+    _mount_point: OverlayMountPoint,
+) -> FsResult<mount::MountHandle> {
+    Err(FsError::Other("overlay mount is not supported on Windows"))
+}
+
+pub(in crate::mount) struct MountHandleInnerImpl {
+    // runs the host until it's told to stop
+    host: JoinHandle<io::Result<()>>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Future for MountHandleInnerImpl {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.host
+            .poll_unpin(cx)
+            .map(|res| res.map_err(io::Error::other)?)
+    }
+}
+
+#[async_trait]
+impl MountHandleInner for MountHandleInnerImpl {
+    async fn unmount(mut self) -> io::Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        self.host.await.map_err(io::Error::other)?
+    }
+}
+
+/// The mount point is a drive letter like `X:`, or a directory which doesn't exist yet.
+#[instrument(skip(password_provider))]
+async fn mount_winfsp(
+    mountpoint: PathBuf,
+    data_dir: PathBuf,
+    password_provider: Box<dyn PasswordProvider>,
+    cipher: Cipher,
+    read_only: bool,
+    mut options: MountOptions,
+) -> FsResult<(oneshot::Sender<()>, JoinHandle<io::Result<()>>)> {
+    info!("Checking password and mounting WinFSP filesystem");
+    let read_only = read_only || options.snapshot.is_some();
+    // new data dirs are case-insensitive like NTFS, existing ones keep their format, else the names hashed with
+    // their case would not be found anymore
+    let new_data_dir = fs::read_dir(&data_dir).map_or(true, |mut dir| dir.next().is_none());
+    if options.format.is_none() && new_data_dir {
+        options.format = Some(VolumeFormat {
+            case_insensitive: true,
+            ..VolumeFormat::default()
+        });
+    }
+    let config = options.fs_config()?;
+    let fs = if let Some(snapshot_id) = options.snapshot {
+        EncryptedFs::new_readonly_at(data_dir, snapshot_id, password_provider, cipher, config)
+            .await?
+    } else {
+        EncryptedFs::new_with_config(data_dir, password_provider, cipher, read_only, config).await?
+    };
+    if options.self_test && !read_only {
+        info!("Running self-test");
+        fs.self_test().await?;
+    }
+    if options.create_mount_point_dir.is_some() {
+        debug!("WinFSP creates the mount point itself, it must not exist");
+    }
+
+    let winfsp = EncryptedFsWinFsp {
+        fs: fs.clone(),
+        rt: Handle::current(),
+    };
+    let volume_params = volume_params(read_only, fs.volume_format().case_insensitive, &options);
+    let (started_tx, started_rx) = oneshot::channel();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    // the host is not `Send`, it lives on this thread until it's stopped
+    let host = task::spawn_blocking(move || {
+        let res = (|| {
+            winfsp::winfsp_init().map_err(io::Error::other)?;
+            let mut host = FileSystemHost::new(volume_params, winfsp).map_err(io::Error::other)?;
+            host.mount(mountpoint.as_os_str())
+                .map_err(io::Error::other)?;
+            host.start().map_err(io::Error::other)?;
+            Ok(host)
+        })();
+        let mut host = match res {
+            Ok(host) => {
+                let _ = started_tx.send(Ok(()));
+                host
+            }
+            Err(err) => {
+                let _ = started_tx.send(Err(err));
+                return Ok(());
+            }
+        };
+        let _ = stop_rx.blocking_recv();
+        host.stop();
+        host.unmount();
+        // called from this thread, not from the runtime, so we can block
+        Handle::current()
+            .block_on(fs.sync_all())
+            .map_err(io::Error::other)?;
+        // drops the cached plaintext
+        Handle::current()
+            .block_on(fs.clear_caches())
+            .map_err(io::Error::other)?;
+        Ok(())
+    });
+    started_rx.await.map_err(io::Error::other)??;
+
+    Ok((stop_tx, host))
+}
+
+fn volume_params(read_only: bool, case_insensitive: bool, options: &MountOptions) -> VolumeParams {
+    let mut params = VolumeParams::new();
+    params
+        .filesystem_name("rencfs")
+        .sector_size(SECTOR_SIZE)
+        .sectors_per_allocation_unit(1)
+        .case_sensitive_search(!case_insensitive)
+        .case_preserved_names(true)
+        .unicode_on_disk(true)
+        .persistent_acls(false)
+        .post_cleanup_when_modified_only(true)
+        .read_only_volume(read_only)
+        .file_info_timeout(
+            options
+                .attr_timeout
+                .unwrap_or(DEFAULT_TTL)
+                .as_millis()
+                .try_into()
+                .unwrap_or(u32::MAX),
+        );
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filetime() {
+        assert_eq!(FILETIME_UNIX_EPOCH, filetime(UNIX_EPOCH));
+        let time = UNIX_EPOCH + Duration::from_micros(1_234_567);
+        assert_eq!(Some(time), system_time(filetime(time)));
+        let before = UNIX_EPOCH - Duration::from_secs(42);
+        assert_eq!(Some(before), system_time(filetime(before)));
+        assert_eq!(None, system_time(0));
+    }
+
+    #[test]
+    fn test_path() {
+        let name = widestring::U16CString::from_str("\\dir\\file.txt").unwrap();
+        assert_eq!("/dir/file.txt", path(&name).unwrap());
+    }
+}
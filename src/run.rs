@@ -1,7 +1,9 @@
 use std::io::Write;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{env, io, panic, process};
 
 use anyhow::Result;
@@ -15,14 +17,18 @@ use tokio::sync::Mutex;
 use tokio::{fs, task};
 use tracing::{error, info, warn, Level};
 
-use crate::keyring;
+use rencfs::crypto::nonce::NonceStrategy;
 use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::{EncryptedFs, FsError, PasswordProvider};
-use rencfs::mount::MountPoint;
+use rencfs::encryptedfs::{AtimePolicy, EncryptedFs, FsError, PasswordProvider};
+use rencfs::keyring::{self, KeyringPasswordProvider};
+use rencfs::mount::{MountOptions, MountPoint};
 use rencfs::{log, mount};
 
 static mut PASS: Option<SecretString> = None;
 
+const KEYRING_SERVICE: &str = "rencfs";
+const KEYRING_ACCOUNT: &str = "rencfs.password";
+
 #[derive(Debug, Error)]
 enum ExitStatusError {
     #[error("exit with status {0}")]
@@ -179,6 +185,230 @@ fn get_cli_args() -> ArgMatches {
                         .requires("data-dir")
                         .help("Set FUSE filesystem read-only mount option, default is disabled.")
                 )
+                .arg(
+                    Arg::new("snapshot")
+                        .long("snapshot")
+                        .value_name("ID")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Mount the snapshot with this id, as printed by the snapshot command, instead of the current files. It's always read-only.")
+                )
+                .arg(
+                    Arg::new("self-test")
+                        .long("self-test")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Before mounting, write a file and read it back to check the encryption works, the mount fails if it doesn't.")
+                )
+                .arg(
+                    Arg::new("default-permissions")
+                        .long("default-permissions")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Let the kernel check access permissions based on the file mode and owner, useful with allow-other.")
+                )
+                .arg(
+                    Arg::new("uid")
+                        .long("uid")
+                        .value_name("UID")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Show all files as owned by this user id, by default they show with the owner they were created with.")
+                )
+                .arg(
+                    Arg::new("gid")
+                        .long("gid")
+                        .value_name("GID")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Show all files as owned by this group id, by default they show with the group they were created with.")
+                )
+                .arg(
+                    Arg::new("max-background")
+                        .long("max-background")
+                        .value_name("MAX_BACKGROUND")
+                        .value_parser(clap::value_parser!(u16))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Max number of background requests queued by FUSE. Higher values can improve throughput on servers with many cores, lower values use less memory. It's set in /sys/fs/fuse/connections after mount, which needs root, else the mount fails. Default is the kernel one.")
+                )
+                .arg(
+                    Arg::new("congestion-threshold")
+                        .long("congestion-threshold")
+                        .value_name("CONGESTION_THRESHOLD")
+                        .value_parser(clap::value_parser!(u16))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Number of background requests after which FUSE starts throttling, must not be greater than max-background. Like max-background it needs root. Default is the kernel one.")
+                )
+                .arg(
+                    Arg::new("crypto-threads")
+                        .long("crypto-threads")
+                        .value_name("CRYPTO_THREADS")
+                        .value_parser(clap::value_parser!(NonZeroUsize))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Run encryption and decryption on a dedicated pool with this many threads, so they don't block the async workers. By default they run inline.")
+                )
+                .arg(
+                    Arg::new("times-write-back")
+                        .long("times-write-back")
+                        .value_name("MILLIS")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Batch updates which change only access and modification times and write them at most once in this many milliseconds. By default they are written right away.")
+                )
+                .arg(
+                    Arg::new("auto-flush")
+                        .long("auto-flush")
+                        .value_name("MILLIS")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Flush files opened for write every this many milliseconds, so a crash loses less data when programs keep them opened. By default data is durable only after a flush or close.")
+                )
+                .arg(
+                    Arg::new("open-write-timeout")
+                        .long("open-write-timeout")
+                        .value_name("MILLIS")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("When a file is already opened for write, wait this many milliseconds for it to be closed before failing to open it for write again. By default it fails right away.")
+                )
+                .arg(
+                    Arg::new("name-padding")
+                        .long("name-padding")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(NonZeroUsize))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Pad file names to a multiple of this many bytes before encrypting them, so the names in the data dir don't leak how long the original ones are. Only used when creating a new data dir, existing ones keep the padding they were created with.")
+                )
+                .arg(
+                    Arg::new("dedup")
+                        .long("dedup")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Store files with the same content only once, checked when a file is closed after writing. Needs hard links in the data dir.")
+                )
+                .arg(
+                    Arg::new("shred")
+                        .long("shred")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Overwrite the inode of a file when it's removed, so its content can't be decrypted anymore even if the key leaks later.")
+                )
+                .arg(
+                    Arg::new("quota")
+                        .long("quota")
+                        .value_name("MIB")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Max total size of the files in MiB, writes after it fail with 'disk quota exceeded'.")
+                )
+                .arg(
+                    Arg::new("sorted-dirs")
+                        .long("sorted-dirs")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("List directories sorted by name, so the order is always the same. Slower on big directories.")
+                )
+                .arg(
+                    Arg::new("sparse")
+                        .long("sparse")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Leave the zeros as holes when a file is extended, by writing after its end or truncating it to a bigger size, so they take no space on disk.")
+                )
+                .arg(
+                    Arg::new("write-back-cache")
+                        .long("write-back-cache")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Let the kernel cache writes and send them in bigger chunks. Faster, but written data is kept in the kernel until it's flushed.")
+                )
+                .arg(
+                    Arg::new("block-cache-dir")
+                        .long("block-cache-dir")
+                        .value_name("BLOCK_CACHE_DIR")
+                        .requires("block-cache-size")
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Keep decrypted blocks in this directory to speed up repeated reads. It must be on tmpfs or ramfs, like /dev/shm.")
+                )
+                .arg(
+                    Arg::new("block-cache-size")
+                        .long("block-cache-size")
+                        .value_name("MIB")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .requires("block-cache-dir")
+                        .help("Max size of the block cache in MiB")
+                )
+                .arg(
+                    Arg::new("readahead-blocks")
+                        .long("readahead-blocks")
+                        .value_name("BLOCKS")
+                        .value_parser(clap::value_parser!(NonZeroUsize))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Decrypt this many blocks ahead of sequential reads, to speed up streaming big files. Not used with the block cache.")
+                )
+                .arg(
+                    Arg::new("entry-timeout")
+                        .long("entry-timeout")
+                        .value_name("SECS")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("How long the kernel caches name lookups. Higher values make ls and stat faster when files don't change often. Default is 1 second.")
+                )
+                .arg(
+                    Arg::new("attr-timeout")
+                        .long("attr-timeout")
+                        .value_name("SECS")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("How long the kernel caches file attributes. Default is 1 second.")
+                )
+                .arg(
+                    Arg::new("atime")
+                        .long("atime")
+                        .value_name("POLICY")
+                        .value_parser(AtimePolicy::from_str)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("When reading files and listing directories updates their access time, possible values: always, relatime, never. Each update rewrites the encrypted inode. Default is relatime.")
+                )
+                .arg(
+                    Arg::new("nonce-counter")
+                        .long("nonce-counter")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Use a counter kept in the data dir for the nonces of file blocks instead of random ones. Raises the safe amount of data written over the volume's life from about 1 PiB, but the data dir must never be restored to an older copy and written to. Only used when creating a new data dir.")
+                )
+                .arg(
+                    Arg::new("case-insensitive")
+                        .long("case-insensitive")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Treat names differing only in case as the same entry, names keep the case they were created with. Only used when creating a new data dir, on Windows new data dirs are case-insensitive by default.")
+                )
         ).subcommand(
         Command::new("passwd")
             .about("Change password for the master key used to encrypt the data")
@@ -190,6 +420,36 @@ fn get_cli_args() -> ArgMatches {
                     .value_name("DATA_DIR")
                     .help("Where to store the encrypted data"),
             )
+    ).subcommand(
+        Command::new("change-cipher")
+            .about("Re-encrypt all the data with another cipher, the filesystem must not be mounted. If interrupted, run it again to finish")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where to store the encrypted data"),
+            )
+            .arg(
+                Arg::new("new-cipher")
+                    .long("new-cipher")
+                    .required(true)
+                    .value_name("NEW_CIPHER")
+                    .value_parser(Cipher::from_str)
+                    .help("Cipher to re-encrypt with, --cipher is the current one"),
+            )
+    ).subcommand(
+        Command::new("snapshot")
+            .about("Take a snapshot of all the files, the filesystem must not be mounted. Prints its id, which can be mounted read-only with mount --snapshot")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where to store the encrypted data"),
+            )
     )
         .get_matches()
 }
@@ -208,6 +468,8 @@ async fn async_main() -> Result<()> {
     match matches.subcommand() {
         Some(("change-password", matches)) => run_change_password(cipher, matches).await?,
         Some(("mount", matches)) => run_mount(cipher, matches).await?,
+        Some(("change-cipher", matches)) => run_change_cipher(cipher, matches).await?,
+        Some(("snapshot", matches)) => run_snapshot(cipher, matches).await?,
         None => {
             error!("No subcommand provided");
             return Err(ExitStatusError::Failure(1).into());
@@ -246,6 +508,9 @@ async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()>
                 FsError::InvalidPassword => {
                     println!("Invalid old password");
                 }
+                FsError::WeakPassword { .. } => {
+                    println!("New {err}");
+                }
                 FsError::InvalidDataDirStructure => {
                     println!("Invalid structure of data directory");
                 }
@@ -260,6 +525,87 @@ async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()>
     Ok(())
 }
 
+async fn run_change_cipher(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let new_cipher = *matches.get_one::<Cipher>("new-cipher").unwrap();
+
+    // read password from stdin
+    print!("Enter password: ");
+    io::stdout().flush().unwrap();
+    let password = SecretString::from_str(&read_password().unwrap()).unwrap();
+    println!("Changing cipher to {new_cipher}...");
+    EncryptedFs::change_cipher(Path::new(&data_dir), password, cipher, new_cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::CipherMismatch { .. } => {
+                    println!("{err}");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("Cipher changed successfully");
+
+    Ok(())
+}
+
+async fn run_snapshot(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    print!("Enter password: ");
+    io::stdout().flush().unwrap();
+    let password = SecretString::from_str(&read_password().unwrap()).unwrap();
+
+    struct PasswordProviderImpl(SecretString);
+    #[allow(clippy::items_after_statements)]
+    impl PasswordProvider for PasswordProviderImpl {
+        fn get_password(&self) -> Option<SecretString> {
+            Some(self.0.clone())
+        }
+    }
+    let id = async {
+        let fs = EncryptedFs::new(
+            PathBuf::from(&data_dir),
+            Box::new(PasswordProviderImpl(password)),
+            cipher,
+            false,
+        )
+        .await?;
+        fs.snapshot().await
+    }
+    .await
+    .map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::CipherMismatch { .. } => {
+                println!("{err}");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+    println!("Snapshot {id} created");
+
+    Ok(())
+}
+
 async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
     let mountpoint: String = matches
         .get_one::<String>("mount-point")
@@ -303,7 +649,7 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
     }
     // save password in keyring
     info!("Save password in keyring");
-    let res = keyring::save(&password, "password").map_err(|err| {
+    let res = keyring::store_password(KEYRING_SERVICE, KEYRING_ACCOUNT, &password).map_err(|err| {
         warn!(err = %err);
     });
     if res.is_err() {
@@ -321,6 +667,93 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
         });
     }
 
+    let mut mount_options = MountOptions::default().with_create_mount_point_dir(0o755);
+    if let Some(max_background) = matches.get_one::<u16>("max-background") {
+        mount_options = mount_options.with_max_background(*max_background);
+    }
+    if let Some(congestion_threshold) = matches.get_one::<u16>("congestion-threshold") {
+        mount_options = mount_options.with_congestion_threshold(*congestion_threshold);
+    }
+    if let Some(crypto_threads) = matches.get_one::<NonZeroUsize>("crypto-threads") {
+        mount_options = mount_options.with_crypto_threads(*crypto_threads);
+    }
+    if let Some(times_write_back) = matches.get_one::<u64>("times-write-back") {
+        mount_options =
+            mount_options.with_times_write_back(Duration::from_millis(*times_write_back));
+    }
+    if let Some(interval) = matches.get_one::<u64>("auto-flush") {
+        mount_options = mount_options.with_auto_flush(Duration::from_millis(*interval));
+    }
+    if let Some(timeout) = matches.get_one::<u64>("open-write-timeout") {
+        mount_options = mount_options.with_open_write_timeout(Duration::from_millis(*timeout));
+    }
+    if let Some(padding) = matches.get_one::<NonZeroUsize>("name-padding") {
+        mount_options = mount_options.with_name_padding(*padding);
+    }
+    if matches.get_flag("dedup") {
+        mount_options = mount_options.with_dedup(true);
+    }
+    if matches.get_flag("shred") {
+        mount_options = mount_options.with_shred(true);
+    }
+    if let Some(quota) = matches.get_one::<u64>("quota") {
+        mount_options = mount_options.with_quota(quota.saturating_mul(1024 * 1024));
+    }
+    if matches.get_flag("sorted-dirs") {
+        mount_options = mount_options.with_sorted_dirs(true);
+    }
+    if matches.get_flag("sparse") {
+        mount_options = mount_options.with_sparse(true);
+    }
+    if matches.get_flag("write-back-cache") {
+        mount_options = mount_options.with_write_back_cache(true);
+    }
+    if let (Some(dir), Some(size)) = (
+        matches.get_one::<String>("block-cache-dir"),
+        matches.get_one::<u64>("block-cache-size"),
+    ) {
+        #[allow(clippy::cast_possible_truncation)]
+        let size = (*size * 1024 * 1024) as usize;
+        mount_options = mount_options.with_block_cache(dir, size);
+    }
+    if let Some(blocks) = matches.get_one::<NonZeroUsize>("readahead-blocks") {
+        mount_options = mount_options.with_readahead_blocks(*blocks);
+    }
+    if let Some(timeout) = matches.get_one::<u64>("entry-timeout") {
+        mount_options = mount_options.with_entry_timeout(Duration::from_secs(*timeout));
+    }
+    if let Some(timeout) = matches.get_one::<u64>("attr-timeout") {
+        mount_options = mount_options.with_attr_timeout(Duration::from_secs(*timeout));
+    }
+    if let Some(policy) = matches.get_one::<AtimePolicy>("atime") {
+        mount_options = mount_options.with_atime_policy(*policy);
+    }
+    if matches.get_flag("nonce-counter") {
+        mount_options = mount_options.with_nonce_strategy(NonceStrategy::Counter);
+    }
+    if matches.get_flag("case-insensitive") {
+        mount_options = mount_options.with_case_insensitive(true);
+    }
+    if let Some(snapshot_id) = matches.get_one::<u64>("snapshot") {
+        mount_options = mount_options.with_snapshot(*snapshot_id);
+    }
+    if matches.get_flag("self-test") {
+        mount_options = mount_options.with_self_test(true);
+    }
+    if matches.get_flag("default-permissions") {
+        mount_options = mount_options.with_default_permissions(true);
+    }
+    if let Some(uid) = matches.get_one::<u32>("uid") {
+        mount_options = mount_options.with_uid(*uid);
+    }
+    if let Some(gid) = matches.get_one::<u32>("gid") {
+        mount_options = mount_options.with_gid(*gid);
+    }
+    if let Err(err) = mount_options.validate() {
+        error!(err = %err);
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
     struct PasswordProviderImpl {}
     #[allow(clippy::items_after_statements)]
     #[allow(static_mut_refs)]
@@ -332,12 +765,7 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
                     PASS.clone()
                 } else {
                     info!("Get password from keyring");
-                    keyring::get("password")
-                        .map_err(|err| {
-                            error!(err = %err, "cannot get password from keyring");
-                            err
-                        })
-                        .ok()
+                    KeyringPasswordProvider::new(KEYRING_SERVICE, KEYRING_ACCOUNT).get_password()
                 }
             }
         }
@@ -350,6 +778,7 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
         matches.get_flag("allow-root"),
         matches.get_flag("allow-other"),
         matches.get_flag("read-only"),
+        mount_options,
     );
     let mount_handle = mount_point.mount().await.map_err(|err| {
         error!(err = %err);
@@ -411,7 +840,7 @@ fn remove_pass() {
     unsafe {
         if PASS.is_none() {
             info!("Delete password from keyring");
-            keyring::remove("password")
+            keyring::delete_password(KEYRING_SERVICE, KEYRING_ACCOUNT)
                 .map_err(|err| {
                     error!(err = %err);
                 })
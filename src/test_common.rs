@@ -12,7 +12,7 @@ use tokio::sync::Mutex;
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
+    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, FsConfig, PasswordProvider,
 };
 
 #[allow(dead_code)]
@@ -73,18 +73,19 @@ impl PasswordProvider for PasswordProviderImpl {
     }
 }
 #[allow(dead_code)]
-async fn setup(setup: TestSetup) -> SetupResult {
+async fn setup(setup: TestSetup, config: FsConfig) -> SetupResult {
     let path = TESTS_DATA_DIR.join(setup.key);
     let read_only = setup.read_only;
     let data_dir_str = path.to_str().unwrap();
     let _ = fs::remove_dir_all(data_dir_str);
     let _ = fs::create_dir_all(data_dir_str);
 
-    let fs = EncryptedFs::new(
+    let fs = EncryptedFs::new_with_config(
         Path::new(data_dir_str).to_path_buf(),
         Box::new(PasswordProviderImpl {}),
         Cipher::ChaCha20Poly1305,
         read_only,
+        config,
     )
     .await
     .unwrap();
@@ -109,13 +110,23 @@ async fn teardown() -> Result<(), io::Error> {
 #[allow(dead_code)]
 #[allow(clippy::future_not_send)]
 pub async fn run_test<T>(init: TestSetup, t: T)
+where
+    T: Future,
+{
+    run_test_with_config(init, FsConfig::default(), t).await;
+}
+
+/// Like [`run_test`], with the filesystem opened with `config`.
+#[allow(dead_code)]
+#[allow(clippy::future_not_send)]
+pub async fn run_test_with_config<T>(init: TestSetup, config: FsConfig, t: T)
 where
     T: Future,
 {
     {
         let s = SETUP_RESULT.get_or(|| Mutex::new(None));
         let mut s = s.lock().await;
-        *s = Some(setup(init).await);
+        *s = Some(setup(init, config).await);
     }
     t.await;
     teardown().await.unwrap();
@@ -156,15 +167,15 @@ pub async fn copy_all_file_range(
     dest_fh: u64,
 ) {
     let mut copied = 0;
-    let file_range_req = CopyFileRangeReq::builder()
-        .src_ino(src_ino)
-        .src_offset(src_offset)
-        .dest_ino(dest_ino)
-        .dest_offset(dest_offset)
-        .src_fh(src_fh)
-        .dest_fh(dest_fh)
-        .build();
     while copied < size {
+        let file_range_req = CopyFileRangeReq::builder()
+            .src_ino(src_ino)
+            .src_offset(src_offset + copied as u64)
+            .dest_ino(dest_ino)
+            .dest_offset(dest_offset + copied as u64)
+            .src_fh(src_fh)
+            .dest_fh(dest_fh)
+            .build();
         let len = fs
             .copy_file_range(&file_range_req, size - copied)
             .await
@@ -193,10 +204,22 @@ pub fn bench<F: Future + Send + Sync>(
     worker_threads: usize,
     read_only: bool,
     f: F,
+) {
+    bench_with_config(key, worker_threads, read_only, FsConfig::default(), f);
+}
+
+/// Like [`bench`], with the filesystem opened with `config`.
+#[allow(dead_code)]
+pub fn bench_with_config<F: Future + Send + Sync>(
+    key: &'static str,
+    worker_threads: usize,
+    read_only: bool,
+    config: FsConfig,
+    f: F,
 ) {
     block_on(
         async {
-            run_test(TestSetup { key, read_only }, f).await;
+            run_test_with_config(TestSetup { key, read_only }, config, f).await;
         },
         worker_threads,
     );
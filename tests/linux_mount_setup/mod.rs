@@ -9,7 +9,7 @@ use std::time::Duration;
 
 use rencfs::crypto::Cipher;
 use rencfs::encryptedfs::PasswordProvider;
-use rencfs::mount::{create_mount_point, MountHandle, MountPoint};
+use rencfs::mount::{create_mount_point, MountHandle, MountOptions, MountPoint};
 use shush_rs::SecretString;
 use tokio::runtime::Runtime;
 
@@ -31,6 +31,7 @@ impl TestResource {
             false,
             false,
             false,
+            MountOptions::default().with_create_mount_point_dir(0o755),
         );
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .worker_threads(1)
@@ -109,7 +110,7 @@ impl Drop for TestGuard {
 struct TestPasswordProvider {}
 impl PasswordProvider for TestPasswordProvider {
     fn get_password(&self) -> Option<SecretString> {
-        Some(SecretString::from_str("test").unwrap())
+        Some(SecretString::from_str("test-password").unwrap())
     }
 }
 
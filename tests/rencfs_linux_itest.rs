@@ -1,9 +1,12 @@
 #![cfg(target_os = "linux")]
 mod linux_mount_setup;
-use linux_mount_setup::{count_files, TestGuard, DATA_PATH, MOUNT_PATH};
+use linux_mount_setup::{count_files, get_password_provider, TestGuard, DATA_PATH, MOUNT_PATH};
+use rencfs::crypto::Cipher;
+use rencfs::encryptedfs::{EncryptedFs, FsError};
+use rencfs::mount::{create_mount_point, create_overlay_mount_point, MountOptions, MountPoint};
 use std::{
-    fs::{self, File},
-    io::{Read, Write},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
     os::unix::fs::MetadataExt,
     path::Path,
 };
@@ -147,3 +150,204 @@ fn it_create_empty_dir_check_attr() {
     let res = fs::remove_dir_all(Path::new(&test_folder));
     assert!(res.is_ok(), "failed to delete [{}]", &test_folder);
 }
+
+#[test]
+fn it_stat_parent_of_mount_root() {
+    let _guard = TestGuard::setup();
+    let res = fs::metadata(format!("{MOUNT_PATH}/.."));
+    assert!(
+        res.is_ok(),
+        "failed to stat .. of mount root [{}]",
+        res.err().unwrap()
+    );
+    // the kernel resolves `..` of the mount root to its parent in the host fs
+    let parent = fs::metadata(Path::new(&MOUNT_PATH).parent().unwrap()).unwrap();
+    let metadata = res.unwrap();
+    assert_eq!(metadata.ino(), parent.ino());
+    assert_eq!(metadata.dev(), parent.dev());
+}
+
+#[test]
+fn it_create_existing_file_with_and_without_excl() {
+    let _guard = TestGuard::setup();
+    let test_file = format!("{}{}", MOUNT_PATH, "/demo-excl.txt");
+    let path = Path::new(&test_file);
+    {
+        let mut file = File::create_new(path).unwrap();
+        file.write_all(b"test-42").unwrap();
+    }
+    // O_CREAT | O_EXCL fails on existing file
+    let res = OpenOptions::new().write(true).create_new(true).open(path);
+    assert_eq!(
+        res.err().map(|err| err.kind()),
+        Some(io::ErrorKind::AlreadyExists)
+    );
+    // O_CREAT opens the existing file and keeps the content
+    {
+        let res = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path);
+        assert!(res.is_ok(), "failed to open [{}]", res.err().unwrap());
+        let mut buf = String::new();
+        res.unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "test-42");
+    }
+    // O_CREAT | O_TRUNC opens and truncates it
+    {
+        let res = File::create(path);
+        assert!(res.is_ok(), "failed to open [{}]", res.err().unwrap());
+        assert_eq!(fs::metadata(path).unwrap().size(), 0);
+    }
+    let res = fs::remove_file(path);
+    assert!(res.is_ok(), "failed to delete [{}]", res.err().unwrap());
+}
+
+#[test]
+fn it_mount_twice_same_mount_point() {
+    let _guard = TestGuard::setup();
+    let mount_point = create_mount_point(
+        Path::new(&MOUNT_PATH),
+        Path::new(&DATA_PATH),
+        get_password_provider(),
+        Cipher::ChaCha20Poly1305,
+        false,
+        false,
+        false,
+        MountOptions::default(),
+    );
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let res = runtime.block_on(mount_point.mount());
+    assert!(
+        matches!(res, Err(FsError::AlreadyMounted)),
+        "second mount should be rejected"
+    );
+    // the first mount is still usable
+    assert!(fs::read_dir(MOUNT_PATH).is_ok());
+}
+
+#[test]
+fn it_mount_creates_mount_point_dir() {
+    let mountpoint = Path::new("/tmp/rencfs-create/mnt/vault");
+    let data_dir = Path::new("/tmp/rencfs-create/data");
+    let _ = fs::remove_dir_all("/tmp/rencfs-create");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // without the option the mount point must exist
+    let res = runtime.block_on(
+        create_mount_point(
+            mountpoint,
+            data_dir,
+            get_password_provider(),
+            Cipher::ChaCha20Poly1305,
+            false,
+            false,
+            false,
+            MountOptions::default(),
+        )
+        .mount(),
+    );
+    assert!(matches!(res, Err(FsError::InvalidInput(_))));
+    assert!(!mountpoint.exists());
+
+    let handle = runtime
+        .block_on(
+            create_mount_point(
+                mountpoint,
+                data_dir,
+                get_password_provider(),
+                Cipher::ChaCha20Poly1305,
+                false,
+                false,
+                false,
+                MountOptions::default().with_create_mount_point_dir(0o755),
+            )
+            .mount(),
+        )
+        .unwrap();
+    assert!(mountpoint.is_dir());
+    fs::write(mountpoint.join("demo.txt"), "test").unwrap();
+    runtime.block_on(handle.umount()).unwrap();
+    // we created it so it's removed on umount
+    assert!(!mountpoint.exists());
+    let _ = fs::remove_dir_all("/tmp/rencfs-create");
+}
+
+#[test]
+fn it_overlay_mount() {
+    let root = Path::new("/tmp/rencfs-overlay");
+    let mountpoint = root.join("mnt");
+    let _ = fs::remove_dir_all(root);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut volumes = vec![];
+    for name in ["work", "personal"] {
+        let data_dir = root.join("data").join(name);
+        // create a file in the volume through its own mount
+        let handle = runtime
+            .block_on(
+                create_mount_point(
+                    &root.join(name),
+                    &data_dir,
+                    get_password_provider(),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    false,
+                    false,
+                    MountOptions::default().with_create_mount_point_dir(0o755),
+                )
+                .mount(),
+            )
+            .unwrap();
+        fs::write(root.join(name).join(format!("{name}.txt")), name).unwrap();
+        runtime.block_on(handle.umount()).unwrap();
+
+        let fs = runtime
+            .block_on(EncryptedFs::new(
+                data_dir,
+                get_password_provider(),
+                Cipher::ChaCha20Poly1305,
+                true,
+            ))
+            .unwrap();
+        volumes.push((name.to_string(), fs));
+    }
+
+    let handle = runtime
+        .block_on(
+            create_overlay_mount_point(
+                &mountpoint,
+                volumes,
+                false,
+                false,
+                MountOptions::default().with_create_mount_point_dir(0o755),
+            )
+            .mount(),
+        )
+        .unwrap();
+    let mut names: Vec<_> = fs::read_dir(&mountpoint)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["personal", "work"]);
+    for name in ["work", "personal"] {
+        let file = mountpoint.join(name).join(format!("{name}.txt"));
+        assert_eq!(fs::read_to_string(&file).unwrap(), name);
+        assert!(fs::write(&file, "test").is_err());
+    }
+    runtime.block_on(handle.umount()).unwrap();
+    let _ = fs::remove_dir_all(root);
+}